@@ -42,6 +42,18 @@ const server = jayson.server({
       callback(err);
     }
   },
+  /**
+   * Protocol version, supported messages and MRENCLAVE of the server
+   */
+  getProtocolVersion: async function(args, callback) {
+    const id = generateId()
+    c[id] = callback;
+    try {
+      await socket.send(JSON.stringify({id : id, type : 'GetProtocolVersion', clientVersion: args.clientVersion}))
+    } catch (err) {
+      callback(err);
+    }
+  },
   /**
    * Get Encryption Key to encrypt inputs to enclave
    * and decrypt outputs from enclave
@@ -54,6 +66,7 @@ const server = jayson.server({
         await socket.send(JSON.stringify({
           id : id, 
           type : 'NewTaskEncryptionKey', 
+          tenant: args.tenant,
           userPubKey: args.userPubKey
         }));
       } catch (err) {
@@ -77,6 +90,37 @@ const server = jayson.server({
         await socket.send(JSON.stringify({
           id : id, 
           type : 'AddPersonalData', 
+          tenant: args.tenant,
+          input: {
+            encryptedUserId: args.encryptedUserId,
+            encryptedData: args.encryptedData,
+            userPubKey: args.userPubKey,
+            encryptedSignature: args.encryptedSignature,
+            compression: args.compression
+          }
+        }));
+      } catch (err) {
+        callback(err);
+      }
+    } else {
+      return callback({
+        code: _INVALID_PARAM,
+        message: "Invalid params"
+      });
+    }
+  },
+  /**
+   * Registers the signing key the user's later requests must be signed with
+   */
+  registerUser: async function(args, callback) {
+    const id = generateId()
+    c[id] = callback;
+    if(args.encryptedUserId && args.encryptedData && args.userPubKey) {
+      try {
+        await socket.send(JSON.stringify({
+          id : id, 
+          type : 'RegisterUser', 
+          tenant: args.tenant,
           input: {
             encryptedUserId: args.encryptedUserId,
             encryptedData: args.encryptedData,
@@ -93,6 +137,35 @@ const server = jayson.server({
       });
     }
   },
+  /**
+   * Sets the test result of the data the user already submitted, e.g. once they tested positive
+   */
+  updateUserStatus: async function(args, callback) {
+    const id = generateId()
+    c[id] = callback;
+    if(args.encryptedUserId && args.encryptedData && args.userPubKey) {
+      try {
+        await socket.send(JSON.stringify({
+          id : id, 
+          type : 'UpdateUserStatus', 
+          tenant: args.tenant,
+          input: {
+            encryptedUserId: args.encryptedUserId,
+            encryptedData: args.encryptedData,
+            userPubKey: args.userPubKey,
+            encryptedSignature: args.encryptedSignature
+          }
+        }));
+      } catch (err) {
+        callback(err);
+      }
+    } else {
+      return callback({
+        code: _INVALID_PARAM,
+        message: "Invalid params"
+      });
+    }
+  },
   /**
    * Requests if there has been a location+datetime match for the user
    */
@@ -104,9 +177,41 @@ const server = jayson.server({
         await socket.send(JSON.stringify({
           id : id, 
           type : 'FindMatch', 
+          tenant: args.tenant,
           input: {
             encryptedUserId: args.encryptedUserId,
-            userPubKey: args.userPubKey
+            userPubKey: args.userPubKey,
+            encryptedSignature: args.encryptedSignature,
+            matching: args.matching
+          }
+        }));
+      } catch (err) {
+        callback(err);
+      }
+    } else {
+      return callback({
+        code: _INVALID_PARAM,
+        message: "Invalid params"
+      });
+    }
+  },
+  /**
+   * Queues a findMatch, answered at once with the id of the job
+   */
+  submitMatchJob: async function(args, callback) {
+    const id = generateId()
+    c[id] = callback;
+    if(args.encryptedUserId && args.userPubKey) {
+      try {
+        await socket.send(JSON.stringify({
+          id : id, 
+          type : 'SubmitMatchJob', 
+          tenant: args.tenant,
+          input: {
+            encryptedUserId: args.encryptedUserId,
+            userPubKey: args.userPubKey,
+            encryptedSignature: args.encryptedSignature,
+            matching: args.matching
           }
         }));
       } catch (err) {
@@ -119,8 +224,113 @@ const server = jayson.server({
       });
     }
   },
+  /**
+   * The progress of a match job, and its results once done
+   */
+  getMatchJob: async function(args, callback) {
+    const id = generateId()
+    c[id] = callback;
+    if(args.jobId) {
+      try {
+        await socket.send(JSON.stringify({id : id, type : 'GetMatchJob', jobId: args.jobId}));
+      } catch (err) {
+        callback(err);
+      }
+    } else {
+      return callback({
+        code: _INVALID_PARAM,
+        message: "Invalid params"
+      });
+    }
+  },
+  /**
+   * A registration queued while IAS was unavailable, and its enclave report once done
+   */
+  getAttestationJob: async function(args, callback) {
+    const id = generateId()
+    c[id] = callback;
+    if(args.jobId) {
+      try {
+        await socket.send(JSON.stringify({id : id, type : 'GetAttestationJob', jobId: args.jobId}));
+      } catch (err) {
+        callback(err);
+      }
+    } else {
+      return callback({
+        code: _INVALID_PARAM,
+        message: "Invalid params"
+      });
+    }
+  },
+  /**
+   * Differentially private statistics for public health dashboards
+   */
+  getAggregates: async function(args, callback) {
+    const id = generateId()
+    c[id] = callback;
+    try {
+      await socket.send(JSON.stringify({id : id, type : 'GetAggregates'}))
+    } catch (err) {
+      callback(err);
+    }
+  },
+  /**
+   * For health authorities, the k-anonymous exposure statistics of a signed `request`, in a bundle the enclave signs
+   */
+  exportExposureStatistics: async function(args, callback) {
+    const id = generateId()
+    c[id] = callback;
+    try {
+      await socket.send(JSON.stringify({id : id, type : 'ExportExposureStatistics', tenant: args.tenant, request: args.request}))
+    } catch (err) {
+      callback(err);
+    }
+  },
+  /**
+   * The audit log of the server, from entry `from` on, with the checkpoints signed by the enclave
+   */
+  exportAuditLog: async function(args, callback) {
+    const id = generateId()
+    c[id] = callback;
+    try {
+      await socket.send(JSON.stringify({id : id, type : 'ExportAuditLog', from: args.from || 0}))
+    } catch (err) {
+      callback(err);
+    }
+  },
+  /**
+   * MRENCLAVE, MRSIGNER, ISV SVN and debug flag of the running enclave, its report and published manifest
+   */
+  getEnclaveInfo: async function(args, callback) {
+    const id = generateId()
+    c[id] = callback;
+    try {
+      await socket.send(JSON.stringify({id : id, type : 'GetEnclaveInfo'}))
+    } catch (err) {
+      callback(err);
+    }
+  },
 });
 
+/**
+ * HTTP probes for orchestrators (e.g. Kubernetes liveness and readiness probes):
+ * 200 when every check passes, 503 otherwise, with the checks as the body.
+ */
+function probe(type) {
+  return function(req, res) {
+    const id = generateId();
+    c[id] = (err, msg) => {
+      const ok = !err && msg.result && msg.result.ok;
+      res.statusCode = ok ? 200 : 503;
+      res.setHeader('Content-Type', 'application/json');
+      res.end(JSON.stringify(err ? {ok: false, error: err.message} : msg.result || msg));
+    };
+    socket.send(JSON.stringify({id : id, type : type}));
+  };
+}
+
+app.use('/healthz', probe('GetHealth'));
+app.use('/readyz', probe('GetReadiness'));
 app.use(cors({methods: ['POST']}));
 app.use(bodyParser.json({ limit: "20mb" }));
 app.use(bodyParser.urlencoded({ limit: "20mb", extended: true}));
@@ -2,12 +2,19 @@ const axios = require('axios');
 const forge = require('node-forge');
 const EthCrypto = require('eth-crypto');
 const jaysonBrowserClient = require('jayson/lib/client/browser');
-const enigma = require('enigma-js/lib/enigma-js.node');
-const web3utils = require('web3-utils');
+const fs = require('fs');
+// The envelope of the Rust client compiled to WebAssembly, see `yarn build-wasm`
+const envelope = require('safetrace-client');
 const data = require('./data.js');
 
 
 const JSON_RPC_Server='https://safetrace.enigma.co';
+// PEM of Intel's Attestation Report Signing CA, unset only against a server in simulation mode
+const IAS_ROOT_CA = process.env.IAS_ROOT_CA ? fs.readFileSync(process.env.IAS_ROOT_CA, 'utf8') : undefined;
+// MRENCLAVE (hex) of the enclave build to trust, any when unset
+const MR_ENCLAVE = process.env.MR_ENCLAVE || undefined;
+// Secret (hex) of the key registered with `registerUser`, the user's requests are then signed with it
+const SIGNING_KEY = process.env.SIGNING_KEY ? envelope.UserKey.fromSecret(process.env.SIGNING_KEY) : undefined;
 
 const callServer = function(request, callback) {
   let config = {
@@ -48,55 +55,54 @@ function getClientKeys(seed='') {
   return {privateKey, publicKey};
 }
 
-async function getEncryptionKey(publicKey) {
-  const getEncryptionKeyResult = await new Promise((resolve, reject) => {
-    client.request('newTaskEncryptionKey', {userPubKey: publicKey},
-        (err, response) => {
-          if (err) {
-            reject(err);
-            return;
-          }
-          resolve(response);
-        });
+function request(method, params) {
+  return new Promise((resolve, reject) => {
+    client.request(method, params, (err, response) => {
+      if (err) {
+        reject(err);
+        return;
+      }
+      resolve(response);
     });
-
-  const {result, id} = getEncryptionKeyResult;
-  const {taskPubKey, sig} = result;
-  // ToDo: verify signature
-  return taskPubKey;
+  });
 }
 
-function encrypt(taskPubKey, privateKey, variable){
-    // Generate derived key from enclave public encryption key and user's private key
-    const derivedKey = enigma.utils.getDerivedKey(taskPubKey, privateKey);
-    // Encrypt function and ABI-encoded args
-    return enigma.utils.encryptMessage(derivedKey, variable);
-}
+let enclave = null;
 
-function decrypt(taskPubKey, privateKey, enc_variable){
-    // Generate derived key from enclave public encryption key and user's private key
-    const derivedKey = enigma.utils.getDerivedKey(taskPubKey, privateKey);
-    // Decrypt function and ABI-encoded args
-    let outputHex = enigma.utils.decryptMessage(derivedKey, enc_variable);
-    let outputStr = enigma.utils.hexToAscii(outputHex);
-    return JSON.parse(outputStr);
+// Verifies the enclave report once, the enclave keeps its signing key
+async function getEnclave() {
+  if (!enclave) {
+    const report = await request('getEnclaveReport', {});
+    enclave = envelope.verifyReport(JSON.stringify(report), IAS_ROOT_CA, MR_ENCLAVE);
+  }
+  return enclave;
 }
 
+// A session with the enclave, for a single request: the task key must be signed by the attested enclave
+async function openSession(userKey) {
+  const enclave = await getEnclave();
+  const taskKey = await request('newTaskEncryptionKey', {userPubKey: userKey.publicKey});
+  return enclave.openSession(userKey, JSON.stringify(taskKey));
+}
 
-async function addData(userId, data){
+// `declaration` is the JSON {"issuedAt", "signature"} a health authority issued to a user who tested positive,
+// servers that trust health authorities refuse locations with testResult set without one
+async function addData(userId, data, declaration){
 
-  let {publicKey, privateKey} = getClientKeys();
+  let userKey = envelope.UserKey.fromSecret(getClientKeys().privateKey);
 
   try {
-    let taskPubKey = await getEncryptionKey(publicKey);
-    let encryptedUserId = encrypt(taskPubKey, privateKey, userId);
-    let encryptedData = encrypt(taskPubKey, privateKey, data);
+    let session = await openSession(userKey);
+    let encryptedUserId = session.encrypt(userId);
+    // Sealed with a nonce and the time, the enclave refuses it if it's sent again or too late
+    let encryptedData = session.encryptLocations(data, Math.floor(Date.now() / 1000), declaration);
 
     const addPersonalDataResult = await new Promise((resolve, reject) => {
       client.request('addPersonalData', {
         encryptedUserId: encryptedUserId,
         encryptedData: encryptedData,
-        userPubKey: publicKey},
+        encryptedSignature: SIGNING_KEY ? session.signRequest(SIGNING_KEY, encryptedUserId, encryptedData, userKey.publicKey) : undefined,
+        userPubKey: userKey.publicKey},
           (err, response) => {
             if (err) {
               reject(err);
@@ -108,29 +114,50 @@ async function addData(userId, data){
 
       const {addPersonalData} = addPersonalDataResult;
 
+      // The receipt comes from the enclave itself, the server can't forge it
+      let receipt = addPersonalData.encryptedOutput ? JSON.parse(session.decrypt(addPersonalData.encryptedOutput)) : null;
+
       if(addPersonalData.status == 0) {
         console.log('Personal data added successfully to the enclave.');
       } else {
         console.log('Something went wrong. Time to debug...')
       }
+      if(receipt) {
+        console.log('Enclave receipt:', receipt);
+      }
   } catch(err) {
     console.log(err);
     // Or throw an error
   }
 }
 
+// Registers SIGNING_KEY for the user, once: nobody without it can then submit data or query matches as them
+async function registerUser(userId){
+
+  let userKey = envelope.UserKey.fromSecret(getClientKeys().privateKey);
+
+  let session = await openSession(userKey);
+  const result = await request('registerUser', {
+    encryptedUserId: session.encrypt(userId),
+    encryptedData: session.registration(SIGNING_KEY, userKey),
+    userPubKey: userKey.publicKey,
+  });
+  return result.registerUser.status == 0;
+}
+
 async function findMatch(userId){
 
-  let {publicKey, privateKey} = getClientKeys();
+  let userKey = envelope.UserKey.fromSecret(getClientKeys().privateKey);
 
   try {
-    let taskPubKey = await getEncryptionKey(publicKey);
-    let encryptedUserId = encrypt(taskPubKey, privateKey, userId);
+    let session = await openSession(userKey);
+    let encryptedUserId = session.encrypt(userId);
 
     const findMatchResult = await new Promise((resolve, reject) => {
       client.request('findMatch', {
         encryptedUserId: encryptedUserId, 
-        userPubKey: publicKey},
+        userPubKey: userKey.publicKey,
+        encryptedSignature: SIGNING_KEY ? session.signRequest(SIGNING_KEY, encryptedUserId, '', userKey.publicKey) : undefined},
           (err, response) => {
             if (err) {
               reject(err);
@@ -143,7 +170,7 @@ async function findMatch(userId){
     if(findMatchResult.findMatch.status == 0) {
       console.log('Find Match operation successful');
 
-      let output = decrypt(taskPubKey, privateKey, findMatchResult.findMatch.encryptedOutput);
+      let output = JSON.parse(session.decrypt(findMatchResult.findMatch.encryptedOutput));
 
       if(output.length){
         console.log('Find matches:');
@@ -160,6 +187,46 @@ async function findMatch(userId){
   }
 }
 
+// Decoy queries: regular findMatch requests whose user id carries a marker keyed by the session
+// (`session.decoyUserId()`), so only the enclave recognizes them. They always come back empty and aren't
+// counted against the matching rate limit. Decoy user ids are 64 hex characters, the size of the encrypted
+// user id is visible on the wire: real user ids of that length are the ones decoys hide among.
+async function findMatchDecoy(){
+
+  let userKey = envelope.UserKey.fromSecret(getClientKeys().privateKey);
+
+  let session = await openSession(userKey);
+  let encryptedUserId = session.encrypt(session.decoyUserId());
+
+  return new Promise((resolve, reject) => {
+    client.request('findMatch', {
+      encryptedUserId: encryptedUserId,
+      userPubKey: userKey.publicKey},
+        (err, response) => {
+          if (err) {
+            reject(err);
+            return;
+          }
+          resolve(response);
+        });
+    });
+}
+
+// Sends a decoy roughly every `intervalMs`, randomized by +/- `jitter` (a fraction of the interval)
+// so the decoys don't form a recognizable pattern. Returns a function that stops the schedule.
+function scheduleDecoys(intervalMs, {jitter=0.5}={}) {
+  let timer = null;
+  let stopped = false;
+  const next = () => {
+    const delay = intervalMs * (1 - jitter + 2 * jitter * Math.random());
+    timer = setTimeout(() => {
+      findMatchDecoy().catch(() => {}).then(() => { if (!stopped) next(); });
+    }, delay);
+  };
+  next();
+  return () => { stopped = true; clearTimeout(timer); };
+}
+
 
 addData('user1', JSON.stringify(data.DataUser1));
 addData('user2', JSON.stringify(data.DataUser2));
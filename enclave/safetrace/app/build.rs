@@ -16,21 +16,49 @@
 // under the License..
 
 use std::env;
+use std::fs;
+use std::process::Command;
 
 fn main () {
 
     let sdk_dir = env::var("SGX_SDK")
                     .unwrap_or_else(|_| "/opt/intel/sgxsdk".to_string());
-    let is_sim = env::var("SGX_MODE")
-                    .unwrap_or_else(|_| "HW".to_string());
+    // The `sgx-sim` feature forces simulation mode regardless of SGX_MODE
+    let is_sim = if env::var("CARGO_FEATURE_SGX_SIM").is_ok() {
+        "SW".to_string()
+    } else {
+        env::var("SGX_MODE").unwrap_or_else(|_| "HW".to_string())
+    };
 
     println!("cargo:rustc-link-search=native=../lib");
     println!("cargo:rustc-link-lib=static=Enclave_u");
 
     println!("cargo:rustc-link-search=native={}/lib64", sdk_dir);
     match is_sim.as_ref() {
-        "SW" => println!("cargo:rustc-link-lib=dylib=sgx_urts_sim"),
-        "HW" => println!("cargo:rustc-link-lib=dylib=sgx_urts"),
-        _    => println!("cargo:rustc-link-lib=dylib=sgx_urts"), // Treat undefined as HW
+        "SW" => {
+            println!("cargo:rustc-link-lib=dylib=sgx_urts_sim");
+            println!("cargo:rustc-link-lib=dylib=sgx_uae_service_sim");
+        },
+        // sgx_uae_service provides sgx_report_attestation_status, see attestation::pib
+        _    => { // Treat undefined as HW
+            println!("cargo:rustc-link-lib=dylib=sgx_urts");
+            println!("cargo:rustc-link-lib=dylib=sgx_uae_service");
+        },
     }
+    println!("cargo:rerun-if-env-changed=SGX_MODE");
+
+    // Provenance for the enclave manifest (`safetrace-app manifest`), the enclave is built from the same tree
+    let commit = Command::new("git").args(&["rev-parse", "HEAD"]).output().ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or_default();
+    let toolchain = fs::read_to_string("../../rust-toolchain").map(|t| t.trim().to_string()).unwrap_or_default();
+    println!("cargo:rustc-env=SAFETRACE_BUILD_COMMIT={}", commit);
+    println!("cargo:rustc-env=SAFETRACE_BUILD_TOOLCHAIN={}", toolchain);
+    println!("cargo:rustc-env=SAFETRACE_BUILD_SGX_SDK={}", env::var("SGX_SDK_VERSION").unwrap_or_default());
+    println!("cargo:rerun-if-env-changed=SGX_SDK_VERSION");
+    println!("cargo:rerun-if-changed=../../rust-toolchain");
+    // A commit touches the index
+    println!("cargo:rerun-if-changed=../../../.git/HEAD");
+    println!("cargo:rerun-if-changed=../../../.git/index");
 }
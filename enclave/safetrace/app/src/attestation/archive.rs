@@ -0,0 +1,84 @@
+use failure::Error;
+pub use safetrace_client::archive::{verify_archive, verify_record, AttestationArchive, AttestationRecord, VerifiedRecord};
+use safetrace_client::bundle::ReportBundle;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+// Every report IAS signs for this server is added to `ias.archive`, when set, with the quote it's about: the
+// record an operator keeps of the attestations, and hands to an auditor. The format and its verification
+// live in safetrace-client. A path ending in `.cbor` is written as CBOR, any other as JSON.
+// `safetrace-app verify-archive` checks an archive offline.
+
+lazy_static! {
+    static ref ARCHIVE: Mutex<Option<PathBuf>> = Mutex::new(None);
+}
+
+// Where `record` adds the reports, None stops archiving.
+pub fn configure(path: Option<PathBuf>) {
+    *ARCHIVE.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = path;
+}
+
+pub fn read(path: &Path) -> Result<AttestationArchive, Error> {
+    match fs::read(path) {
+        Ok(bytes) => AttestationArchive::decode(&bytes).map_err(|e| format_err!("{}: {}", path.display(), e)),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(AttestationArchive::default()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+// Written aside then renamed, the archive is never left half written.
+fn write(path: &Path, archive: &AttestationArchive) -> Result<(), Error> {
+    let encoded = match path.extension() {
+        Some(extension) if extension == "cbor" => archive.to_cbor()?,
+        _ => archive.to_json()?.into_bytes(),
+    };
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    fs::write(&temporary, encoded)?;
+    fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+pub fn append(path: &Path, record: AttestationRecord) -> Result<(), Error> {
+    let mut archive = read(path)?;
+    archive.records.push(record);
+    write(path, &archive)
+}
+
+// Archives the report `bundle` of `quote`, with the enclave signing address (hex) where it's at hand. A report
+// that can't be archived is still used, the failure logged.
+pub fn record(quote: &str, bundle: &ReportBundle, signing_key: Option<String>) {
+    let archive = ARCHIVE.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let path = match &*archive {
+        Some(path) => path,
+        None => return,
+    };
+    if let Err(e) = AttestationRecord::new(quote, bundle, signing_key).and_then(|record| append(path, record)) {
+        warn!("Unable to archive the attestation report in {}: {}", path.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_append() {
+        let quote = "AAAAAAAA";
+        let bundle = ReportBundle { report: r#"{"id":"1","timestamp":"2020-05-10T12:00:00","isvEnclaveQuoteStatus":"OK","isvEnclaveQuoteBody":""}"#.to_string(), ..Default::default() };
+        for name in &["safetrace-archive-test.json", "safetrace-archive-test.cbor"] {
+            let path = env::temp_dir().join(name);
+            let _ = fs::remove_file(&path);
+            assert!(read(&path).unwrap().records.is_empty());
+            append(&path, AttestationRecord::new(quote, &bundle, None).unwrap()).unwrap();
+            append(&path, AttestationRecord::new(quote, &bundle, Some("07".repeat(20))).unwrap()).unwrap();
+            let archive = read(&path).unwrap();
+            assert_eq!(archive.records.len(), 2);
+            assert_eq!(archive.records[1].signing_key, Some("07".repeat(20)));
+            assert_eq!(fs::read(&path).unwrap()[0] == b'{', name.ends_with(".json"));
+            fs::remove_file(&path).unwrap();
+        }
+    }
+}
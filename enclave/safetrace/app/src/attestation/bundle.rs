@@ -0,0 +1,51 @@
+use super::{archive, cache, pib, AttestationProvider};
+use enigma_tools_u::attestation_service::service::{ASReport, ASResult};
+use failure::Error;
+pub use safetrace_client::bundle::ReportBundle;
+
+// The bundle itself and its compact encoding live in safetrace-client, which clients verify it with.
+// These are the conversions from and to what the attestation providers produce and verify.
+
+pub fn from_result(result: &ASResult) -> ReportBundle {
+    ReportBundle {
+        report: result.report_string.clone(),
+        signature: result.signature.clone(),
+        certificate: result.certificate.clone(),
+        ca: result.ca.clone(),
+    }
+}
+
+// e.g. for `AttestationProvider::verify_report`
+pub fn to_result(bundle: &ReportBundle) -> Result<ASResult, Error> {
+    let report: ASReport = serde_json::from_str(&bundle.report)?;
+    Ok(ASResult {
+        ca: bundle.ca.clone(),
+        certificate: bundle.certificate.clone(),
+        report,
+        report_string: bundle.report.clone(),
+        signature: bundle.signature.clone(),
+        validate: false,
+    })
+}
+
+// The bundle of the report `provider` gives for `quote`, the TCB guidance of the platform logged (`pib`) and
+// the report archived (`archive`) and cached (`cache`). A quote whose report is cached isn't sent again.
+pub fn request(provider: &dyn AttestationProvider, quote: String) -> Result<ReportBundle, Error> {
+    if let Some(cached) = cache::lookup(&quote) {
+        return Ok(cached.bundle);
+    }
+    let result = provider.get_report(quote.clone())?;
+    pib::check_report(&result);
+    let bundle = from_result(&result);
+    archive::record(&quote, &bundle, None);
+    cache::record(provider, &quote, &result);
+    Ok(bundle)
+}
+
+pub fn compact(bundle: &ReportBundle) -> String {
+    // Clients can still verify the separate fields if the chain isn't PEM
+    bundle.to_compact().unwrap_or_else(|e| {
+        warn!("Unable to encode the report bundle: {}", e);
+        String::new()
+    })
+}
@@ -0,0 +1,181 @@
+use super::{bundle, AttestationProvider};
+use enigma_tools_u::attestation_service::service::ASResult;
+use failure::Error;
+use hex::ToHex;
+use safetrace_client::bundle::ReportBundle;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// The IAS answers that verified, by the SHA-256 of the quote they're about, in `ias.report_cache` when set: a
+// quote attested before isn't sent to IAS again, and `GetCachedReport` serves the report, its signature and the
+// certificate chain exactly as IAS sent them, for audits and for clients checking a quote again. The file is JSON,
+// written aside then renamed like the archive, and keeps the `ias.cache_size` latest reports.
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CachedReport {
+    // Hex
+    pub quote_hash: String,
+    // Base64, as it was sent to IAS
+    pub quote: String,
+    pub bundle: ReportBundle,
+    // Seconds
+    pub cached_at: u64,
+}
+
+struct ReportCache {
+    path: PathBuf,
+    capacity: usize,
+    reports: BTreeMap<String, CachedReport>,
+}
+
+lazy_static! {
+    static ref CACHE: Mutex<Option<ReportCache>> = Mutex::new(None);
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// The key of a quote, base64 as the enclave produces it: the SHA-256 of its bytes, hex.
+pub fn quote_hash(quote: &str) -> Result<String, Error> {
+    let bytes = base64::decode(quote.trim()).map_err(|_| format_err!("the quote isn't base64"))?;
+    Ok(Sha256::digest(&bytes).to_hex())
+}
+
+fn read(path: &Path) -> Result<Vec<CachedReport>, Error> {
+    match fs::read(path) {
+        Ok(bytes) => serde_json::from_slice(&bytes).map_err(|e| format_err!("{}: {}", path.display(), e)),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn write(path: &Path, reports: &BTreeMap<String, CachedReport>) -> Result<(), Error> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    fs::write(&temporary, serde_json::to_vec(&reports.values().collect::<Vec<_>>())?)?;
+    fs::rename(&temporary, path)?;
+    Ok(())
+}
+
+impl ReportCache {
+    fn open(path: PathBuf, capacity: usize) -> Result<Self, Error> {
+        let mut cache = ReportCache { path, capacity, reports: BTreeMap::new() };
+        for report in read(&cache.path)? {
+            cache.insert(report);
+        }
+        Ok(cache)
+    }
+
+    // Past the capacity the oldest report goes.
+    fn insert(&mut self, report: CachedReport) {
+        self.reports.insert(report.quote_hash.clone(), report);
+        while self.reports.len() > self.capacity {
+            let oldest = self.reports.values().min_by_key(|report| report.cached_at).map(|report| report.quote_hash.clone());
+            match oldest {
+                Some(quote_hash) => self.reports.remove(&quote_hash),
+                None => break,
+            };
+        }
+    }
+
+    fn get(&self, quote_hash: &str) -> Option<CachedReport> {
+        self.reports.get(&quote_hash.to_lowercase()).cloned()
+    }
+
+    fn lookup(&self, quote: &str) -> Option<CachedReport> {
+        self.get(&quote_hash(quote).ok()?)
+    }
+
+    // Only the reports `provider` verifies are kept.
+    fn store(&mut self, provider: &dyn AttestationProvider, quote: &str, result: &ASResult) -> Result<(), Error> {
+        if !provider.verify_report(result).unwrap_or(false) {
+            return Ok(());
+        }
+        let quote_hash = quote_hash(quote)?;
+        self.insert(CachedReport { quote_hash, quote: quote.to_string(), bundle: bundle::from_result(result), cached_at: now() });
+        write(&self.path, &self.reports)
+    }
+}
+
+fn cache() -> MutexGuard<'static, Option<ReportCache>> {
+    CACHE.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// Loads the reports of `path`, None stops caching.
+pub fn configure(path: Option<PathBuf>, capacity: usize) -> Result<(), Error> {
+    *cache() = match path {
+        Some(path) => Some(ReportCache::open(path, capacity)?),
+        None => None,
+    };
+    Ok(())
+}
+
+pub fn get(quote_hash: &str) -> Result<CachedReport, Error> {
+    match cache().as_ref().and_then(|cache| cache.get(quote_hash)) {
+        Some(report) => Ok(report),
+        None => bail!("Unknown quote hash, or its report left the cache"),
+    }
+}
+
+// The report IAS gave for `quote` before, if it's cached.
+pub fn lookup(quote: &str) -> Option<CachedReport> {
+    cache().as_ref().and_then(|cache| cache.lookup(quote))
+}
+
+// Caches the report `provider` gave for `quote` once it verifies. A report that can't be cached is still used,
+// the failure logged.
+pub fn record(provider: &dyn AttestationProvider, quote: &str, result: &ASResult) {
+    if let Some(cache) = cache().as_mut() {
+        if let Err(e) = cache.store(provider, quote, result) {
+            warn!("Unable to cache the attestation report in {}: {}", cache.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::attestation::MockAttestationService;
+    use std::env;
+
+    const QUOTE: &str = "AgAAANoKAAAHAAYAAAAAABYB+Vw5ueowf+qruQGtw+54eaWW7MiyrIAooQw/uU3e";
+
+    #[test]
+    fn test_report_cache() {
+        let path = env::temp_dir().join("safetrace-report-cache-test.json");
+        let _ = fs::remove_file(&path);
+        let service = MockAttestationService::new().unwrap();
+        let result = service.get_report(QUOTE.to_string()).unwrap();
+        let quote_hash = quote_hash(QUOTE).unwrap();
+        assert_eq!(quote_hash.len(), 64);
+        assert!(super::quote_hash("not base64!").is_err());
+
+        let mut cache = ReportCache::open(path.clone(), 2).unwrap();
+        assert!(cache.lookup(QUOTE).is_none());
+        cache.store(&service, QUOTE, &result).unwrap();
+        let cached = cache.get(&quote_hash.to_uppercase()).unwrap();
+        assert_eq!((cached.quote.as_str(), &cached.bundle), (QUOTE, &bundle::from_result(&result)));
+
+        // Only what verifies, kept over restarts
+        let mut forged = service.get_report("AAAA".to_string()).unwrap();
+        forged.signature = "00".repeat(65);
+        cache.store(&service, "AAAA", &forged).unwrap();
+        assert!(cache.lookup("AAAA").is_none());
+        let mut cache = ReportCache::open(path.clone(), 2).unwrap();
+        assert_eq!(cache.lookup(QUOTE), Some(cached));
+
+        for (i, quote_hash) in ["01", "02", "03"].iter().enumerate() {
+            cache.insert(CachedReport { quote_hash: quote_hash.to_string(), quote: String::new(), bundle: ReportBundle::default(), cached_at: i as u64 });
+        }
+        // The older two went
+        assert_eq!(cache.reports.len(), 2);
+        assert!(cache.get(&quote_hash).is_some() && cache.get("03").is_some());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,109 @@
+use super::AttestationProvider;
+use enigma_crypto::KeyPair;
+use enigma_tools_u::attestation_service::service::{ASReport, ASResult};
+use failure::Error;
+use hex::{FromHex, ToHex};
+use serde_json::Value;
+use std::fs;
+use std::path::Path;
+
+// The fixture used when none is given, relative to this file.
+const DEFAULT_FIXTURE: &str = include_str!("../../tests/attestation/mock_ias.json");
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MockFixture {
+    signing_key: String,
+    report: Value,
+}
+
+// Behaves like IAS without the network: every quote gets the fixture report with the quote
+// body filled in, signed with the fixture key. The same quote always yields the same report
+// and signature, and only reports signed by this key pass `verify_report`.
+pub struct MockAttestationService {
+    keypair: KeyPair,
+    template: Value,
+}
+
+impl MockAttestationService {
+    pub fn new() -> Result<Self, Error> {
+        Self::from_fixture(DEFAULT_FIXTURE)
+    }
+
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Self::from_fixture(&fs::read_to_string(path)?)
+    }
+
+    pub fn from_fixture(fixture: &str) -> Result<Self, Error> {
+        let fixture: MockFixture = serde_json::from_str(fixture)?;
+        let secret: Vec<u8> = fixture.signing_key.from_hex()?;
+        if secret.len() != 32 || !fixture.report.is_object() {
+            bail!("the mock attestation fixture needs a 32 bytes signingKey and a report object");
+        }
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&secret);
+        Ok(MockAttestationService { keypair: KeyPair::from_slice(&key)?, template: fixture.report })
+    }
+
+    // Stands in for the certificate IAS returns, so a report can be tied back to its signer.
+    pub fn certificate(&self) -> String {
+        self.keypair.get_pubkey().to_hex()
+    }
+}
+
+impl AttestationProvider for MockAttestationService {
+    fn get_report(&self, quote: String) -> Result<ASResult, Error> {
+        let mut report = self.template.clone();
+        report["isvEnclaveQuoteBody"] = Value::String(quote);
+        let report_string = serde_json::to_string(&report)?;
+        let signature = self.keypair.sign(report_string.as_bytes())?;
+        Ok(ASResult {
+            ca: String::from("mock"),
+            certificate: self.certificate(),
+            report: serde_json::from_value::<ASReport>(report)?,
+            report_string,
+            signature: signature.to_hex(),
+            validate: false,
+        })
+    }
+
+    fn verify_report(&self, result: &ASResult) -> Result<bool, Error> {
+        let bytes: Vec<u8> = result.signature.from_hex()?;
+        if bytes.len() != 65 || result.certificate != self.certificate() {
+            return Ok(false);
+        }
+        let mut signature = [0u8; 65];
+        signature.copy_from_slice(&bytes);
+        match KeyPair::recover(result.report_string.as_bytes(), signature) {
+            Ok(signer) => Ok(signer[..] == self.keypair.get_pubkey()[..]),
+            Err(_) => Ok(false),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Any base64 string does, the mock never parses the quote
+    const QUOTE: &str = "AgAAANoKAAAHAAYAAAAAABYB+Vw5ueowf+qruQGtw+54eaWW7MiyrIAooQw/uU3e";
+
+    #[test]
+    fn test_mock_report_is_deterministic() {
+        let service = MockAttestationService::new().unwrap();
+        let first = service.get_report(QUOTE.to_string()).unwrap();
+        let second = service.get_report(QUOTE.to_string()).unwrap();
+        assert_eq!(first.report_string, second.report_string);
+        assert_eq!(first.signature, second.signature);
+        assert_eq!(first.report.isv_enclave_quote_body, QUOTE);
+        assert!(service.verify_report(&first).unwrap());
+    }
+
+    #[test]
+    fn test_mock_rejects_tampered_report() {
+        let service = MockAttestationService::new().unwrap();
+        let mut result = service.get_report(QUOTE.to_string()).unwrap();
+        result.report_string = result.report_string.replace("\"OK\"", "\"GROUP_OUT_OF_DATE\"");
+        assert!(!service.verify_report(&result).unwrap());
+    }
+}
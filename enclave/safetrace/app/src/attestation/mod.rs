@@ -0,0 +1,40 @@
+//! Where quotes get turned into signed attestation reports, and where those reports get checked.
+//! Production uses Intel's Attestation Service, tests can swap in the mock provider. The checks themselves are
+//! `verify`, which has no networking and is the same module clients and auditors verify stored reports with.
+//! The reports themselves can be kept in an archive, see `archive`, and the verified ones cached, see `cache`.
+
+use enigma_tools_u::attestation_service::service::ASResult;
+use failure::Error;
+
+pub mod archive;
+pub mod bundle;
+pub mod cache;
+pub mod pib;
+pub mod revocation;
+pub mod service;
+pub mod tls;
+pub use safetrace_client::{quote, verify};
+pub use self::bundle::ReportBundle;
+pub use self::pib::TcbStatus;
+pub use self::revocation::RevocationChecker;
+pub use self::quote::Quote;
+pub use self::service::{IasContact, IasService, RetryPolicy};
+pub use self::tls::TlsOptions;
+#[cfg(test)]
+pub mod mock;
+#[cfg(test)]
+pub use self::mock::MockAttestationService;
+
+pub trait AttestationProvider: Send + Sync {
+    /// Sends a base64 encoded quote for attestation and returns the signed report.
+    fn get_report(&self, quote: String) -> Result<ASResult, Error>;
+    /// Checks the signature (and the chain behind it) over a report produced by this provider.
+    fn verify_report(&self, result: &ASResult) -> Result<bool, Error>;
+    /// Whether the provider can be reached right now, used by the readiness probe.
+    fn check_reachability(&self) -> Result<(), Error> { Ok(()) }
+    /// The signature revocation list of an EPID group (8 hex digits), empty when it has none. Quotes prove their
+    /// signature isn't on it, see `esgx::equote::quote_for`.
+    fn get_sigrl(&self, _gid: &str) -> Result<Vec<u8>, Error> { Ok(Vec::new()) }
+    /// Whether the provider accepts the SPID and the credentials of this node, checked at startup.
+    fn check_credentials(&self, _spid: &str) -> Result<(), Error> { Ok(()) }
+}
@@ -0,0 +1,239 @@
+use crate::common_u::errors::AttestationServiceErr;
+use enigma_tools_u::attestation_service::service::ASResult;
+use failure::Error;
+use hex::{FromHex, ToHex};
+use serde_json::Value;
+use sgx_types::{sgx_platform_info_t, sgx_report_attestation_status, sgx_status_t, sgx_update_info_bit_t};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// The platform info blob (PIB) IAS adds to the report when the platform TCB is out of date, revoked
+// or needs some configuration. It is handed back to the platform services (`sgx_report_attestation_status`),
+// which tell us what to update: microcode, CSME firmware or the platform software.
+// Layout (IAS API, `platform_info_blob_t`), big-endian, after a 4 bytes TLV header:
+// epid group flags (1), TCB evaluation flags (2), PSE evaluation flags (2), latest equivalent TCB PSVN (18),
+// latest PSE ISVSVN (2), latest PSDA SVN (4), XEID (4), GID (4), signature (64).
+
+const TLV_HEADER: usize = 4;
+const PIB_SIZE: usize = 101;
+
+const EPID_GROUP_FLAGS: &[(u8, &str)] = &[
+    (0x01, "the EPID group is revoked"),
+    (0x02, "a performance rekey of the EPID group is available"),
+    (0x04, "the EPID group is out of date"),
+];
+
+const TCB_EVALUATION_FLAGS: &[(u16, &str)] = &[
+    (0x0001, "the CPU SVN is out of date, update the microcode (BIOS)"),
+    (0x0002, "the quoting enclave is out of date, update the platform software"),
+    (0x0004, "the provisioning certification enclave is out of date, update the platform software"),
+    (0x0008, "the platform needs additional configuration (BIOS settings)"),
+];
+
+const PSE_EVALUATION_FLAGS: &[(u16, &str)] = &[
+    (0x0001, "the platform services enclave is out of date"),
+    (0x0002, "the EPID group of the platform services hardware is revoked"),
+    (0x0004, "the platform services hardware SVN is out of date, update the CSME firmware"),
+    (0x0008, "the platform services SigRL version is out of date"),
+    (0x0010, "the platform services private key revocation list is out of date"),
+];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PlatformInfo {
+    pub epid_group_flags: u8,
+    pub tcb_evaluation_flags: u16,
+    pub pse_evaluation_flags: u16,
+    pub latest_equivalent_tcb_psvn: String,
+    pub latest_pse_isvsvn: u16,
+    pub latest_psda_svn: u32,
+    pub xeid: u32,
+    pub gid: u32,
+    // The blob without its TLV header, as the platform services want it
+    #[serde(skip)]
+    raw: Vec<u8>,
+}
+
+// What the platform services asked to update after reading the PIB.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PlatformUpdate {
+    pub microcode: bool,
+    pub csme_firmware: bool,
+    pub platform_software: bool,
+}
+
+// The TCB status of the last attestation, for the logs and the admin API.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TcbStatus {
+    pub quote_status: String,
+    pub platform: Option<PlatformInfo>,
+    // What to do about it, in plain words
+    pub guidance: Vec<String>,
+    pub update: Option<PlatformUpdate>,
+    pub checked_at: u64,
+}
+
+lazy_static! {
+    static ref LAST_STATUS: Mutex<Option<TcbStatus>> = Mutex::new(None);
+}
+
+fn pib_err(message: &str) -> Error {
+    AttestationServiceErr { message: format!("platform info blob: {}", message) }.into()
+}
+
+fn be16(bytes: &[u8]) -> u16 { (u16::from(bytes[0]) << 8) | u16::from(bytes[1]) }
+
+fn be32(bytes: &[u8]) -> u32 { (u32::from(be16(&bytes[..2])) << 16) | u32::from(be16(&bytes[2..4])) }
+
+impl PlatformInfo {
+    pub fn from_hex(blob: &str) -> Result<Self, Error> {
+        let bytes: Vec<u8> = blob.from_hex().map_err(|_| pib_err("not hex"))?;
+        if bytes.len() != TLV_HEADER + PIB_SIZE {
+            return Err(pib_err(&format!("expected {} bytes, got {}", TLV_HEADER + PIB_SIZE, bytes.len())));
+        }
+        let pib = &bytes[TLV_HEADER..];
+        Ok(PlatformInfo {
+            epid_group_flags: pib[0],
+            tcb_evaluation_flags: be16(&pib[1..3]),
+            pse_evaluation_flags: be16(&pib[3..5]),
+            latest_equivalent_tcb_psvn: pib[5..23].to_hex(),
+            latest_pse_isvsvn: be16(&pib[23..25]),
+            latest_psda_svn: be32(&pib[25..29]),
+            xeid: be32(&pib[29..33]),
+            gid: be32(&pib[33..37]),
+            raw: pib.to_vec(),
+        })
+    }
+
+    pub fn guidance(&self) -> Vec<String> {
+        let epid = EPID_GROUP_FLAGS.iter().filter(|&&(bit, _)| self.epid_group_flags & bit != 0).map(|&(_, text)| text);
+        let tcb = TCB_EVALUATION_FLAGS.iter().filter(|&&(bit, _)| self.tcb_evaluation_flags & bit != 0).map(|&(_, text)| text);
+        let pse = PSE_EVALUATION_FLAGS.iter().filter(|&&(bit, _)| self.pse_evaluation_flags & bit != 0).map(|&(_, text)| text);
+        epid.chain(tcb).chain(pse).map(String::from).collect()
+    }
+
+    // Hands the blob to the platform services, which tell what needs an update.
+    pub fn report_to_platform(&self, attestation_ok: bool) -> Result<PlatformUpdate, Error> {
+        if self.raw.len() != PIB_SIZE {
+            return Err(pib_err("the raw blob is missing"));
+        }
+        let mut platform_info = sgx_platform_info_t { platform_info: [0u8; PIB_SIZE] };
+        platform_info.platform_info.copy_from_slice(&self.raw);
+        let mut update = sgx_update_info_bit_t { ucodeUpdate: 0, csmeFwUpdate: 0, pswUpdate: 0 };
+        let status = unsafe { sgx_report_attestation_status(&platform_info, if attestation_ok { 0 } else { 1 }, &mut update) };
+        // SGX_ERROR_UPDATE_NEEDED is the expected answer when `update` has something to say
+        if status != sgx_status_t::SGX_SUCCESS && status != sgx_status_t::SGX_ERROR_UPDATE_NEEDED {
+            return Err(pib_err(&format!("sgx_report_attestation_status failed: {}", status.as_str())));
+        }
+        Ok(PlatformUpdate { microcode: update.ucodeUpdate != 0, csme_firmware: update.csmeFwUpdate != 0, platform_software: update.pswUpdate != 0 })
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn quote_guidance(quote_status: &str) -> Option<&'static str> {
+    match quote_status {
+        "OK" => None,
+        "GROUP_OUT_OF_DATE" => Some("the platform TCB is out of date, apply the updates below"),
+        "CONFIGURATION_NEEDED" => Some("the platform needs additional configuration, see below"),
+        "SW_HARDENING_NEEDED" | "CONFIGURATION_AND_SW_HARDENING_NEEDED" => Some("the platform needs software hardening (see the IAS advisories)"),
+        "GROUP_REVOKED" | "SIGNATURE_REVOKED" | "KEY_REVOKED" => Some("the platform is revoked, it can't be trusted until it's updated"),
+        "SIGRL_VERSION_MISMATCH" => Some("the SigRL used by the quote is out of date, retry the attestation"),
+        _ => Some("the quote was rejected"),
+    }
+}
+
+// Reads the quote status and the PIB of an attestation report, reports the PIB to the
+// platform services and logs what to do about it. The result is kept for `last_status`.
+pub fn check_report(result: &ASResult) -> TcbStatus {
+    let report: Value = serde_json::from_str(&result.report_string).unwrap_or_default();
+    let quote_status = report["isvEnclaveQuoteStatus"].as_str().unwrap_or_default().to_string();
+    let mut guidance: Vec<String> = quote_guidance(&quote_status).into_iter().map(String::from).collect();
+    let platform = match report["platformInfoBlob"].as_str().map(PlatformInfo::from_hex) {
+        Some(Ok(platform)) => Some(platform),
+        Some(Err(e)) => {
+            warn!("{}", e);
+            None
+        },
+        None => None,
+    };
+    let update = platform.as_ref().and_then(|platform| {
+        guidance.extend(platform.guidance());
+        match platform.report_to_platform(quote_status == "OK") {
+            Ok(update) => Some(update),
+            Err(e) => {
+                warn!("{}", e);
+                None
+            },
+        }
+    });
+    let status = TcbStatus { quote_status, platform, guidance, update, checked_at: now() };
+    status.log();
+    *LAST_STATUS.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(status.clone());
+    status
+}
+
+pub fn last_status() -> Option<TcbStatus> {
+    LAST_STATUS.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+}
+
+impl TcbStatus {
+    fn log(&self) {
+        if self.guidance.is_empty() {
+            info!(target: "security", "TCB status: {}", self.quote_status);
+            return;
+        }
+        warn!(target: "security", "TCB status: {}: {}", self.quote_status, self.guidance.join("; "));
+        if let Some(update) = &self.update {
+            warn!(target: "security", "Platform updates needed: microcode {}, CSME firmware {}, platform software {}",
+                  update.microcode, update.csme_firmware, update.platform_software);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // A GROUP_OUT_OF_DATE blob: CPU SVN and quoting enclave out of date
+    fn blob() -> String {
+        let mut pib: Vec<u8> = vec![0x15, 0x02, 0x00, 0x65];
+        pib.push(0x04);
+        pib.extend(&[0x00, 0x03]);
+        pib.extend(&[0x00, 0x00]);
+        pib.extend(&[0x0b; 18]);
+        pib.extend(&[0x00, 0x0a]);
+        pib.extend(&[0x00, 0x00, 0x00, 0x02]);
+        pib.extend(&[0x00, 0x00, 0x00, 0x00]);
+        pib.extend(&[0x00, 0x00, 0x0b, 0x69]);
+        pib.extend(&[0u8; 64][..]);
+        pib.to_hex()
+    }
+
+    #[test]
+    fn test_parse_pib() {
+        let platform = PlatformInfo::from_hex(&blob()).unwrap();
+        assert_eq!(platform.epid_group_flags, 0x04);
+        assert_eq!(platform.tcb_evaluation_flags, 0x0003);
+        assert_eq!(platform.latest_pse_isvsvn, 10);
+        assert_eq!(platform.latest_psda_svn, 2);
+        assert_eq!(platform.gid, 0x0b69);
+        assert_eq!(platform.latest_equivalent_tcb_psvn, "0b".repeat(18));
+        assert_eq!(platform.guidance().len(), 3);
+        assert!(platform.guidance()[1].contains("microcode"));
+
+        assert!(PlatformInfo::from_hex("1502").is_err());
+        assert!(PlatformInfo::from_hex(&"zz".repeat(105)).is_err());
+    }
+
+    #[test]
+    fn test_quote_guidance() {
+        assert_eq!(quote_guidance("OK"), None);
+        assert!(quote_guidance("GROUP_OUT_OF_DATE").unwrap().contains("out of date"));
+        assert!(quote_guidance("SOMETHING_NEW").is_some());
+    }
+}
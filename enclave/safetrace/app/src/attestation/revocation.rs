@@ -0,0 +1,307 @@
+use super::TlsOptions;
+use crate::common_u::errors::AttestationServiceErr;
+use crate::config::RevocationConfig;
+use crate::telemetry;
+use failure::Error;
+use openssl::hash::MessageDigest;
+use openssl::ocsp::{OcspCertId, OcspCertStatus, OcspFlag, OcspRequest, OcspResponse, OcspResponseStatus};
+use openssl::stack::Stack;
+use openssl::x509::{store::X509StoreBuilder, X509};
+use reqwest::{header::CONTENT_TYPE, Client};
+use safetrace_client::bundle::{pem_to_der, ReportBundle};
+use safetrace_client::x509::{self, Crl};
+use std::collections::BTreeMap;
+use std::fs;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Whether the certificate signing the IAS reports was revoked, once its chain checked out (`verify`). The CRL
+// of the root is downloaded, checked against the root and kept (in memory and in `revocation.crl_cache`) for
+// `crl_refresh` seconds or until its nextUpdate; an OCSP responder is asked as well when `ocsp_url` is set.
+//
+// A revoked certificate fails the report whatever the mode. One source answering is enough; when none can
+// (the CRL can't be downloaded and the one kept is past its nextUpdate, the responder is down) `soft` accepts
+// the report with a warning and `hard` fails it. Each check is counted by source and outcome, in the
+// `safetrace.revocation.checks` counter and on the `metrics` log target.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    Off,
+    Soft,
+    Hard,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Outcome {
+    Good,
+    Revoked,
+    // The check couldn't be made, and why
+    Unknown(String),
+}
+
+impl Outcome {
+    fn name(&self) -> &'static str {
+        match self {
+            Outcome::Good => "good",
+            Outcome::Revoked => "revoked",
+            Outcome::Unknown(_) => "unknown",
+        }
+    }
+}
+
+struct CachedCrl {
+    crl: Crl,
+    // Unix seconds
+    fetched_at: u64,
+}
+
+pub struct RevocationChecker {
+    mode: Mode,
+    crl_url: String,
+    crl_cache: String,
+    crl_refresh: u64,
+    ocsp_url: String,
+    timeout: Duration,
+    tls: TlsOptions,
+    crl: Mutex<Option<CachedCrl>>,
+}
+
+lazy_static! {
+    // By source and outcome, since the start
+    static ref CHECKS: Mutex<BTreeMap<(&'static str, &'static str), u64>> = Mutex::new(BTreeMap::new());
+}
+
+fn revocation_err(message: String) -> Error {
+    AttestationServiceErr { message }.into()
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn counted(source: &'static str, outcome: &Outcome) {
+    let mut checks = CHECKS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+    let count = checks.entry((source, outcome.name())).or_insert(0);
+    *count += 1;
+    info!(target: "metrics", "safetrace_revocation_checks{{source=\"{}\",outcome=\"{}\"}}={}", source, outcome.name(), count);
+    telemetry::count("safetrace.revocation.checks", vec![
+        ("safetrace.revocation.source", source.to_string()),
+        ("safetrace.revocation.outcome", outcome.name().to_string()),
+    ], 1);
+}
+
+// Whether a CRL fetched at `fetched_at` is still used at `now`.
+fn fresh(cached: &CachedCrl, now: u64, refresh: u64) -> bool {
+    now < cached.fetched_at.saturating_add(refresh) && cached.crl.next_update.map_or(true, |next| now <= next)
+}
+
+// What the outcomes of the sources make of the report.
+fn judge(mode: Mode, outcomes: &[(&'static str, Outcome)]) -> Result<(), Error> {
+    if let Some((source, _)) = outcomes.iter().find(|(_, outcome)| *outcome == Outcome::Revoked) {
+        return Err(revocation_err(format!("the IAS signing certificate is revoked ({})", source)));
+    }
+    if outcomes.is_empty() || outcomes.iter().any(|(_, outcome)| *outcome == Outcome::Good) {
+        return Ok(());
+    }
+    let reasons: Vec<String> = outcomes.iter().map(|(source, outcome)| match outcome {
+        Outcome::Unknown(reason) => format!("{}: {}", source, reason),
+        _ => source.to_string(),
+    }).collect();
+    match mode {
+        Mode::Hard => Err(revocation_err(format!("the revocation of the IAS signing certificate can't be checked ({})", reasons.join(", ")))),
+        _ => {
+            warn!(target: "security", "Accepting an IAS report without a revocation check ({})", reasons.join(", "));
+            Ok(())
+        },
+    }
+}
+
+impl RevocationChecker {
+    pub fn new(config: &RevocationConfig, tls: TlsOptions) -> Self {
+        let mode = match config.mode.as_str() {
+            "off" => Mode::Off,
+            "hard" => Mode::Hard,
+            _ => Mode::Soft,
+        };
+        RevocationChecker {
+            mode,
+            crl_url: config.crl_url.clone(),
+            crl_cache: config.crl_cache.clone(),
+            crl_refresh: config.crl_refresh,
+            ocsp_url: config.ocsp_url.clone(),
+            timeout: Duration::from_secs(config.timeout),
+            tls,
+            crl: Mutex::new(None),
+        }
+    }
+
+    pub fn off() -> Self {
+        Self::new(&RevocationConfig { mode: "off".to_string(), ..RevocationConfig::default() }, TlsOptions::default())
+    }
+
+    // The signing certificate of `bundle`, whose chain up to `root_ca` (PEM) was verified.
+    pub fn check(&self, bundle: &ReportBundle, root_ca: &str) -> Result<(), Error> {
+        if self.mode == Mode::Off {
+            return Ok(());
+        }
+        let signing = pem_to_der(&bundle.certificate)?.into_iter().next().ok_or_else(|| revocation_err("no signing certificate".to_string()))?;
+        let root = pem_to_der(root_ca)?.into_iter().next().ok_or_else(|| revocation_err("no root CA".to_string()))?;
+        let mut outcomes = Vec::new();
+        if !self.crl_url.is_empty() {
+            outcomes.push(("crl", self.check_crl(&signing, &root)));
+        }
+        if !self.ocsp_url.is_empty() {
+            outcomes.push(("ocsp", self.ask_ocsp(&signing, &root).unwrap_or_else(|e| Outcome::Unknown(e.to_string()))));
+        }
+        for (source, outcome) in &outcomes {
+            counted(source, outcome);
+        }
+        judge(self.mode, &outcomes)
+    }
+
+    fn check_crl(&self, signing: &[u8], root: &[u8]) -> Outcome {
+        let serial = match x509::serial_number(signing) {
+            Ok(serial) => serial,
+            Err(e) => return Outcome::Unknown(e.to_string()),
+        };
+        match self.current_crl(root, now()) {
+            Ok(ref crl) if crl.is_revoked(&serial) => Outcome::Revoked,
+            Ok(_) => Outcome::Good,
+            Err(reason) => Outcome::Unknown(reason),
+        }
+    }
+
+    // The CRL kept if it's fresh, else a new one. An older one still before its nextUpdate does if the download
+    // fails.
+    fn current_crl(&self, root: &[u8], now: u64) -> Result<Crl, String> {
+        let mut cached = self.crl.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if cached.is_none() {
+            *cached = self.read_cache(root);
+        }
+        if let Some(cached) = &*cached {
+            if fresh(cached, now, self.crl_refresh) {
+                return Ok(cached.crl.clone());
+            }
+        }
+        match self.download(root) {
+            Ok((crl, der)) => {
+                if !self.crl_cache.is_empty() {
+                    if let Err(e) = fs::write(&self.crl_cache, &der) {
+                        warn!("Unable to keep the CRL in {}: {}", self.crl_cache, e);
+                    }
+                }
+                *cached = Some(CachedCrl { crl: crl.clone(), fetched_at: now });
+                Ok(crl)
+            },
+            Err(e) => match &*cached {
+                Some(cached) if cached.crl.next_update.map_or(false, |next| now <= next) => {
+                    warn!("Downloading the CRL failed ({}), using the one of {}", e, cached.fetched_at);
+                    Ok(cached.crl.clone())
+                },
+                _ => Err(format!("no current CRL: {}", e)),
+            },
+        }
+    }
+
+    // The CRL of the last run, as old as the file.
+    fn read_cache(&self, root: &[u8]) -> Option<CachedCrl> {
+        if self.crl_cache.is_empty() {
+            return None;
+        }
+        let der = fs::read(&self.crl_cache).ok()?;
+        let fetched_at = fs::metadata(&self.crl_cache).and_then(|metadata| metadata.modified()).ok()?
+            .duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        match x509::verify_crl(&der, root) {
+            Ok(crl) => Some(CachedCrl { crl, fetched_at }),
+            Err(e) => {
+                warn!("Ignoring the CRL of {}: {}", self.crl_cache, e);
+                None
+            },
+        }
+    }
+
+    fn download(&self, root: &[u8]) -> Result<(Crl, Vec<u8>), Error> {
+        let client = self.tls.configure(Client::builder().timeout(self.timeout))?.build()?;
+        let mut response = client.get(self.crl_url.as_str()).send()?;
+        if !response.status().is_success() {
+            return Err(revocation_err(format!("{} answered {}", self.crl_url, response.status())));
+        }
+        let mut der = Vec::new();
+        response.copy_to(&mut der)?;
+        Ok((x509::verify_crl(&der, root)?, der))
+    }
+
+    fn ask_ocsp(&self, signing: &[u8], root: &[u8]) -> Result<Outcome, Error> {
+        let signing = X509::from_der(signing)?;
+        let root = X509::from_der(root)?;
+        let mut request = OcspRequest::new()?;
+        request.add_id(OcspCertId::from_cert(MessageDigest::sha1(), &signing, &root)?)?;
+        let client = self.tls.configure(Client::builder().timeout(self.timeout))?.build()?;
+        let mut sent = client.post(self.ocsp_url.as_str())
+            .header(CONTENT_TYPE, "application/ocsp-request")
+            .body(request.to_der()?)
+            .send()?;
+        if !sent.status().is_success() {
+            return Err(revocation_err(format!("{} answered {}", self.ocsp_url, sent.status())));
+        }
+        let mut body = Vec::new();
+        sent.copy_to(&mut body)?;
+        let response = OcspResponse::from_der(&body)?;
+        if response.status() != OcspResponseStatus::SUCCESSFUL {
+            return Err(revocation_err(format!("the OCSP responder refused the request ({:?})", response.status())));
+        }
+        // Signed by the root, or by a responder certificate it issued
+        let basic = response.basic()?;
+        let mut store = X509StoreBuilder::new()?;
+        store.add_cert(root.clone())?;
+        basic.verify(&Stack::<X509>::new()?, &store.build(), OcspFlag::empty())?;
+        let id = OcspCertId::from_cert(MessageDigest::sha1(), &signing, &root)?;
+        let status = basic.find_status(&id).ok_or_else(|| revocation_err("the OCSP response doesn't name the certificate".to_string()))?;
+        status.check_validity(300, None)?;
+        Ok(if status.status == OcspCertStatus::GOOD {
+            Outcome::Good
+        } else if status.status == OcspCertStatus::REVOKED {
+            Outcome::Revoked
+        } else {
+            Outcome::Unknown("the OCSP responder doesn't know the certificate".to_string())
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_judge() {
+        let unknown = || Outcome::Unknown("timed out".to_string());
+        assert!(judge(Mode::Soft, &[("crl", Outcome::Good)]).is_ok());
+        assert!(judge(Mode::Soft, &[("crl", Outcome::Revoked)]).is_err());
+        assert!(judge(Mode::Soft, &[("crl", unknown()), ("ocsp", Outcome::Revoked)]).is_err());
+        assert!(judge(Mode::Soft, &[("crl", unknown())]).is_ok());
+        assert!(judge(Mode::Hard, &[("crl", unknown())]).is_err());
+        // One answer is enough
+        assert!(judge(Mode::Hard, &[("crl", unknown()), ("ocsp", Outcome::Good)]).is_ok());
+        let error = judge(Mode::Hard, &[("crl", unknown()), ("ocsp", unknown())]).unwrap_err().to_string();
+        assert!(error.contains("crl: timed out, ocsp: timed out"), "{}", error);
+    }
+
+    #[test]
+    fn test_fresh() {
+        let cached = |next_update| CachedCrl { crl: Crl { this_update: 0, next_update, revoked: Vec::new() }, fetched_at: 1000 };
+        assert!(fresh(&cached(None), 1000, 3600));
+        assert!(!fresh(&cached(None), 4600, 3600));
+        // Its nextUpdate comes first
+        assert!(fresh(&cached(Some(2000)), 2000, 3600));
+        assert!(!fresh(&cached(Some(2000)), 2001, 3600));
+    }
+
+    #[test]
+    fn test_off() {
+        let checker = RevocationChecker::off();
+        assert!(checker.check(&ReportBundle::default(), "").is_ok());
+        // Nothing to check the certificate of
+        let checker = RevocationChecker::new(&RevocationConfig::default(), TlsOptions::default());
+        assert!(checker.check(&ReportBundle::default(), "").is_err());
+    }
+}
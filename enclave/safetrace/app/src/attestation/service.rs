@@ -0,0 +1,295 @@
+use super::{bundle, verify, AttestationProvider, RevocationChecker, TlsOptions};
+use crate::common_u::errors::{AttestationServiceErr, IasCredentialsErr, IasUnavailableErr};
+use crate::secrets::Secret;
+use enigma_tools_u::attestation_service::service::{ASReport, ASResult};
+use failure::Error;
+use rand::Rng;
+use reqwest::{header::RETRY_AFTER, Client, RequestBuilder, StatusCode};
+use serde_json::{json, Value};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Time the readiness probe waits for IAS.
+const REACHABILITY_TIMEOUT: Duration = Duration::from_secs(5);
+// The group of the SigRL asked for at startup. No platform is in it, IAS only has to accept who asks
+const SENTINEL_GID: &str = "00000000";
+
+// How the IAS client retries: exponential backoff with jitter, each attempt bounded by `timeout`.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub retries: u32,
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+    pub timeout: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            retries: 3,
+            base_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+impl RetryPolicy {
+    // Delay before retry number `attempt` (starting at 0): half of the exponential delay is fixed,
+    // the other half is random, so nodes restarting together don't hit IAS in lockstep.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let exp = self.base_delay.checked_mul(1 << attempt.min(16)).unwrap_or(self.max_delay);
+        let delay = exp.min(self.max_delay);
+        let half = delay / 2;
+        let jitter = rand::thread_rng().gen_range(0, half.as_millis() as u64 + 1);
+        half + Duration::from_millis(jitter)
+    }
+}
+
+// Why an attempt failed, and whether trying again can help.
+enum AttemptError {
+    // Rate limited, server side failure or network error, an `IasUnavailableErr`. IAS may tell us how long to wait.
+    Retryable(Error, Option<Duration>),
+    // The request itself is wrong (4xx) or the quote got rejected, retrying won't change anything.
+    Fatal(Error),
+}
+
+// The last time IAS was asked anything, by an attestation or the readiness probe, for the dashboard.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct IasContact {
+    // Seconds
+    pub at: u64,
+    // Whether IAS answered, a refused quote is an answer
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+lazy_static! {
+    static ref LAST_CONTACT: Mutex<Option<IasContact>> = Mutex::new(None);
+}
+
+fn contacted(error: Option<String>) {
+    let at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    *LAST_CONTACT.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(IasContact { at, ok: error.is_none(), error });
+}
+
+pub fn last_contact() -> Option<IasContact> {
+    LAST_CONTACT.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+}
+
+fn ias_err(message: String) -> Error {
+    AttestationServiceErr { message }.into()
+}
+
+fn unavailable(message: String) -> Error {
+    IasUnavailableErr { message }.into()
+}
+
+// Client for the attestation service, replaces the one in `enigma_tools_u` which retried without any delay.
+pub struct IasService {
+    url: String,
+    policy: RetryPolicy,
+    tls: TlsOptions,
+    api_key: Option<Secret>,
+    // PEM, the reports must chain up to it
+    root_ca: String,
+    revocation: RevocationChecker,
+}
+
+// Header carrying the IAS subscription key.
+const API_KEY_HEADER: &str = "Ocp-Apim-Subscription-Key";
+
+impl IasService {
+    pub fn new(url: &str) -> Self {
+        Self::with_policy(url, RetryPolicy::default())
+    }
+
+    pub fn with_policy(url: &str, policy: RetryPolicy) -> Self {
+        IasService { url: url.to_string(), policy, tls: TlsOptions::default(), api_key: None, root_ca: verify::INTEL_ROOT_CA.to_string(), revocation: RevocationChecker::off() }
+    }
+
+    pub fn with_tls(self, tls: TlsOptions) -> Self {
+        IasService { tls, ..self }
+    }
+
+    pub fn with_api_key(self, api_key: Option<Secret>) -> Self {
+        IasService { api_key, ..self }
+    }
+
+    pub fn with_root_ca(self, root_ca: String) -> Self {
+        IasService { root_ca, ..self }
+    }
+
+    pub fn with_revocation(self, revocation: RevocationChecker) -> Self {
+        IasService { revocation, ..self }
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        match self.api_key {
+            Some(ref key) => request.header(API_KEY_HEADER, key.expose()),
+            None => request,
+        }
+    }
+
+    fn build_request(quote: String) -> Value {
+        json!({ "jsonrpc": "2.0", "method": "validate", "params": { "quote": quote, "production": true }, "id": 1 })
+    }
+
+    fn sigrl_request(gid: &str) -> Value {
+        json!({ "jsonrpc": "2.0", "method": "sigrl", "params": { "gid": gid }, "id": 1 })
+    }
+
+    // The `result` of a JSON-RPC request, `what` naming it in the errors.
+    fn attempt(&self, client: &Client, request: &Value, what: &str) -> Result<Value, AttemptError> {
+        let mut res = self.authorize(client.post(self.url.as_str())).json(request).send()
+            .map_err(|e| AttemptError::Retryable(unavailable(e.to_string()), None))?;
+        let status = res.status();
+        if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() {
+            let retry_after = res.headers().get(RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse().ok())
+                .map(Duration::from_secs);
+            return Err(AttemptError::Retryable(unavailable(format!("IAS answered {}", status)), retry_after));
+        }
+        if !status.is_success() {
+            return Err(AttemptError::Fatal(ias_err(format!("IAS rejected the request: {}", status))));
+        }
+        let mut body: Value = res.json().map_err(|e| AttemptError::Retryable(unavailable(e.to_string()), None))?;
+        if body["error"].is_object() {
+            return Err(AttemptError::Fatal(ias_err(format!("Invalid {}: {}", what, body["error"]))));
+        }
+        Ok(body["result"].take())
+    }
+
+    // Sends `request` until IAS answers it, retrying with `policy`.
+    fn call(&self, request: &Value, what: &str) -> Result<Value, Error> {
+        let client = self.tls.configure(Client::builder().timeout(self.policy.timeout))?.build()?;
+        self.tls.check_pins(&self.url, self.policy.timeout)?;
+        let mut attempt = 0;
+        loop {
+            let attempted = self.attempt(&client, request, what);
+            contacted(match &attempted {
+                Err(AttemptError::Retryable(e, _)) => Some(e.to_string()),
+                _ => None,
+            });
+            match attempted {
+                Ok(result) => return Ok(result),
+                Err(AttemptError::Fatal(e)) => return Err(e),
+                Err(AttemptError::Retryable(e, _)) if attempt >= self.policy.retries => return Err(e),
+                Err(AttemptError::Retryable(e, retry_after)) => {
+                    let delay = retry_after.map_or_else(|| self.policy.backoff(attempt), |d| d.min(self.policy.max_delay));
+                    warn!("IAS request failed ({}), retrying in {:?}", e, delay);
+                    thread::sleep(delay);
+                    attempt += 1;
+                },
+            }
+        }
+    }
+
+    // IAS sends the list base64, nothing or null for a group without one.
+    fn parse_sigrl(result: &Value) -> Result<Vec<u8>, Error> {
+        match result {
+            Value::Null => Ok(Vec::new()),
+            Value::String(sigrl) => base64::decode(sigrl.trim()).map_err(|_| ias_err("the SigRL isn't base64".to_string())),
+            _ => Err(ias_err(format!("the SigRL isn't a string: {}", result))),
+        }
+    }
+
+    fn parse_result(result: &Value) -> Result<ASResult, Error> {
+        let field = |name: &str| result[name].as_str().map(String::from)
+            .ok_or_else(|| ias_err(format!("the response has no {}", name)));
+        let report_string = field("report")?;
+        let report: ASReport = serde_json::from_str(&report_string)?;
+        let validate = result["validate"].as_bool().unwrap_or_else(|| result["validate"].as_str() == Some("True"));
+        Ok(ASResult { ca: field("ca")?, certificate: field("certificate")?, report, report_string, signature: field("signature")?, validate })
+    }
+}
+
+impl AttestationProvider for IasService {
+    fn get_report(&self, quote: String) -> Result<ASResult, Error> {
+        let result = self.call(&Self::build_request(quote), "quote")?;
+        Self::parse_result(&result)
+    }
+
+    fn get_sigrl(&self, gid: &str) -> Result<Vec<u8>, Error> {
+        let result = self.call(&Self::sigrl_request(gid), "EPID group")?;
+        Self::parse_sigrl(&result)
+    }
+
+    // Against the pinned root, not the CA IAS sent along with the report, then whether the signing certificate
+    // was revoked
+    fn verify_report(&self, result: &ASResult) -> Result<bool, Error> {
+        let bundle = bundle::from_result(result);
+        match verify::verify_report(&bundle, &self.root_ca).and_then(|_| self.revocation.check(&bundle, &self.root_ca)) {
+            Ok(_) => Ok(true),
+            Err(e) => {
+                warn!("The IAS report doesn't verify: {}", e);
+                Ok(false)
+            },
+        }
+    }
+
+    // Any HTTP answer will do, we only want to know the service (or the proxy in front of it) is there.
+    fn check_reachability(&self) -> Result<(), Error> {
+        let client = self.tls.configure(Client::builder().timeout(REACHABILITY_TIMEOUT))?.build()?;
+        let sent = self.authorize(client.head(self.url.as_str())).send();
+        contacted(sent.as_ref().err().map(ToString::to_string));
+        sent?;
+        Ok(())
+    }
+
+    // IAS answers the SigRL of any group, empty or not found, unless it refuses the subscription key (401, 403),
+    // or the proxy in front of it the SPID (the same codes in a JSON-RPC error). Anything else only says IAS
+    // can't be reached now.
+    fn check_credentials(&self, spid: &str) -> Result<(), Error> {
+        let client = self.tls.configure(Client::builder().timeout(REACHABILITY_TIMEOUT))?.build()?;
+        self.tls.check_pins(&self.url, REACHABILITY_TIMEOUT)?;
+        let mut request = Self::sigrl_request(SENTINEL_GID);
+        request["params"]["spid"] = json!(spid);
+        let sent = self.authorize(client.post(self.url.as_str())).json(&request).send();
+        contacted(sent.as_ref().err().map(ToString::to_string));
+        let mut res = sent.map_err(|e| unavailable(e.to_string()))?;
+        let refused = |code: u64| code == u64::from(StatusCode::UNAUTHORIZED.as_u16()) || code == u64::from(StatusCode::FORBIDDEN.as_u16());
+        if refused(u64::from(res.status().as_u16())) {
+            return Err(IasCredentialsErr { message: format!("IAS answered {}", res.status()) }.into());
+        }
+        let body: Value = res.json().unwrap_or(Value::Null);
+        match body["error"]["code"].as_u64() {
+            Some(code) if refused(code) => Err(IasCredentialsErr { message: format!("IAS answered {}", body["error"]) }.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{IasService, RetryPolicy, SENTINEL_GID};
+    use serde_json::{json, Value};
+    use std::time::Duration;
+
+    #[test]
+    fn test_backoff_grows_and_is_capped() {
+        let policy = RetryPolicy { retries: 10, base_delay: Duration::from_millis(100), max_delay: Duration::from_secs(2), timeout: Duration::from_secs(1) };
+        for attempt in 0..10 {
+            let exp = Duration::from_millis(100 * (1 << attempt)).min(policy.max_delay);
+            let delay = policy.backoff(attempt);
+            assert!(delay >= exp / 2 && delay <= exp, "attempt {}: {:?}", attempt, delay);
+        }
+        assert!(policy.backoff(40) <= policy.max_delay);
+    }
+
+    #[test]
+    fn test_sigrl() {
+        let request = IasService::sigrl_request(SENTINEL_GID);
+        assert_eq!(request["method"], "sigrl");
+        assert_eq!(request["params"]["gid"], "00000000");
+
+        assert_eq!(IasService::parse_sigrl(&Value::Null).unwrap(), Vec::<u8>::new());
+        assert_eq!(IasService::parse_sigrl(&json!("")).unwrap(), Vec::<u8>::new());
+        assert_eq!(IasService::parse_sigrl(&json!("AAIADgAAAAEAAAAB")).unwrap(), vec![0, 2, 0, 14, 0, 0, 0, 1, 0, 0, 0, 1]);
+        assert!(IasService::parse_sigrl(&json!("not base64!")).is_err() && IasService::parse_sigrl(&json!(12)).is_err());
+    }
+}
@@ -0,0 +1,110 @@
+use crate::common_u::errors::AttestationServiceErr;
+use failure::Error;
+use hex::ToHex;
+use native_tls::TlsConnector;
+use reqwest::{Certificate, ClientBuilder, Proxy, Url};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const PEM_END: &str = "-----END CERTIFICATE-----";
+
+// How the IAS client reaches the service: an optional HTTP(S) proxy, extra trusted roots,
+// and optionally a set of pinned server certificates.
+#[derive(Debug, Clone, Default)]
+pub struct TlsOptions {
+    pub proxy: Option<String>,
+    // PEM bundle, added on top of the system roots.
+    pub ca_bundle: Option<PathBuf>,
+    // Hex SHA-256 fingerprints of the DER encoded certificates the server may present.
+    pub pins: Vec<String>,
+}
+
+fn tls_err(message: String) -> Error {
+    AttestationServiceErr { message }.into()
+}
+
+impl TlsOptions {
+    // Splits the CA bundle into the PEM certificates it holds.
+    fn ca_certificates(&self) -> Result<Vec<Vec<u8>>, Error> {
+        let path = match &self.ca_bundle {
+            Some(path) => path,
+            None => return Ok(Vec::new()),
+        };
+        let bundle = fs::read_to_string(path)?;
+        let certs: Vec<Vec<u8>> = bundle.split(PEM_END)
+            .filter(|pem| pem.contains("-----BEGIN CERTIFICATE-----"))
+            .map(|pem| format!("{}{}\n", pem.trim_start(), PEM_END).into_bytes())
+            .collect();
+        if certs.is_empty() {
+            return Err(tls_err(format!("no certificate found in {}", path.display())));
+        }
+        Ok(certs)
+    }
+
+    pub fn configure(&self, mut builder: ClientBuilder) -> Result<ClientBuilder, Error> {
+        if let Some(proxy) = &self.proxy {
+            builder = builder.proxy(Proxy::all(proxy.as_str())?);
+        }
+        for pem in self.ca_certificates()? {
+            builder = builder.add_root_certificate(Certificate::from_pem(&pem)?);
+        }
+        Ok(builder)
+    }
+
+    // reqwest doesn't expose the peer certificate, so pinning is checked with a separate handshake
+    // to the same host (through the proxy, if any) right before talking to IAS.
+    pub fn check_pins(&self, url: &str, timeout: Duration) -> Result<(), Error> {
+        if self.pins.is_empty() {
+            return Ok(());
+        }
+        let url = Url::parse(url)?;
+        let host = url.host_str().ok_or_else(|| tls_err(format!("no host in {}", url)))?;
+        let port = url.port_or_known_default().unwrap_or(443);
+
+        let stream = match &self.proxy {
+            Some(proxy) => Self::tunnel(proxy, host, port, timeout)?,
+            None => TcpStream::connect((host, port))?,
+        };
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+
+        let mut connector = TlsConnector::builder();
+        for pem in self.ca_certificates()? {
+            connector.add_root_certificate(native_tls::Certificate::from_pem(&pem)?);
+        }
+        let tls = connector.build()?.connect(host, stream).map_err(|e| tls_err(format!("TLS handshake with {} failed: {}", host, e)))?;
+        let cert = tls.peer_certificate()?.ok_or_else(|| tls_err(format!("{} presented no certificate", host)))?;
+        let fingerprint: String = Sha256::digest(&cert.to_der()?).to_hex();
+        if !self.pins.iter().any(|pin| pin.eq_ignore_ascii_case(&fingerprint)) {
+            return Err(tls_err(format!("the certificate of {} ({}) isn't pinned", host, fingerprint)));
+        }
+        Ok(())
+    }
+
+    // Opens a CONNECT tunnel to `host:port` through an HTTP proxy.
+    fn tunnel(proxy: &str, host: &str, port: u16, timeout: Duration) -> Result<TcpStream, Error> {
+        let proxy = Url::parse(proxy)?;
+        let proxy_host = proxy.host_str().ok_or_else(|| tls_err(format!("no host in {}", proxy)))?;
+        let mut stream = TcpStream::connect((proxy_host, proxy.port_or_known_default().unwrap_or(8080)))?;
+        stream.set_read_timeout(Some(timeout))?;
+        write!(stream, "CONNECT {0}:{1} HTTP/1.1\r\nHost: {0}:{1}\r\n\r\n", host, port)?;
+
+        let mut head = Vec::new();
+        let mut byte = [0u8; 1];
+        while !head.ends_with(b"\r\n\r\n") {
+            if stream.read(&mut byte)? == 0 || head.len() > 8192 {
+                return Err(tls_err("the proxy closed the tunnel".to_string()));
+            }
+            head.push(byte[0]);
+        }
+        let status = String::from_utf8_lossy(&head);
+        if status.split_whitespace().nth(1) != Some("200") {
+            return Err(tls_err(format!("the proxy refused the tunnel: {}", status.lines().next().unwrap_or_default())));
+        }
+        Ok(stream)
+    }
+}
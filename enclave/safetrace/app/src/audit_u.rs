@@ -0,0 +1,292 @@
+use crate::common_u::errors::EnclaveFailError;
+use crate::config::AuditConfig;
+use crate::esgx::equote;
+use crate::esgx::pool::EnclavePool;
+use crate::telemetry;
+use failure::Error;
+use hex::{FromHex, ToHex};
+use safetrace_client::audit::{self, AuditCheckpoint, AuditEntry, AuditKind, AuditRecord, AuditSummary, GENESIS};
+use serde_json::Value;
+use sgx_types::{sgx_enclave_id_t, sgx_status_t};
+use enigma_types::{EnclaveReturn};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+
+extern {
+    pub fn ecall_audit_checkpoint(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn,
+                                  prev_seq: u64, prev_head: &[u8; 32], digests: *const u8, digests_len: usize,
+                                  seq: *mut u64, head: &mut [u8; 32], sig: &mut [u8; 65], resumed: *mut u8) -> sgx_status_t;
+}
+
+// The audit log: one JSON record per line in `audit.path`, entries and the checkpoints the enclave signs over
+// them, the format is in `audit` of the client. Ingest, every admin operation and the
+// policies applied at startup are recorded once done. The primary enclave signs a checkpoint every
+// `audit.checkpoint_every` entries, or with the first entry after `audit.checkpoint_interval` seconds.
+// `ExportAuditLog` serves the records and what `audit::verify` makes of them.
+
+// Entries per `ExportAuditLog`, `next` tells where to continue
+pub const MAX_EXPORT: usize = 1000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditVerification {
+    pub valid: bool,
+    #[serde(flatten)]
+    pub summary: AuditSummary,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+struct State {
+    file: Option<File>,
+    // The last entry
+    seq: u64,
+    head: [u8; 32],
+    // The last checkpoint, and when it was taken
+    signed: (u64, [u8; 32]),
+    signed_at: u64,
+    // (seq, digest) of the entries after it
+    pending: Vec<(u64, [u8; 32])>,
+}
+
+pub struct AuditLog {
+    path: Option<PathBuf>,
+    checkpoint_every: usize,
+    checkpoint_interval: u64,
+    state: Mutex<State>,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn hash(value: &str) -> [u8; 32] {
+    let mut hash = [0u8; 32];
+    if let Ok(bytes) = value.from_hex() {
+        let bytes: Vec<u8> = bytes;
+        if bytes.len() == 32 {
+            hash.copy_from_slice(&bytes);
+        }
+    }
+    hash
+}
+
+fn read_records(path: &Path) -> Result<Vec<AuditRecord>, Error> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut records = Vec::new();
+    for (i, line) in content.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+        records.push(serde_json::from_str(line).map_err(|e| format_err!("{} line {}: {}", path.display(), i + 1, e))?);
+    }
+    Ok(records)
+}
+
+pub fn verification(records: &[AuditRecord]) -> AuditVerification {
+    match audit::verify(records, None) {
+        Ok(summary) => AuditVerification { valid: true, summary, error: None },
+        Err(e) => AuditVerification { valid: false, summary: AuditSummary::default(), error: Some(e.to_string()) },
+    }
+}
+
+// The checkpoint ecall, returns (seq, head, signature, resumed).
+pub fn checkpoint(eid: sgx_enclave_id_t, prev: (u64, [u8; 32]), digests: &[u8]) -> Result<(u64, [u8; 32], [u8; 65], bool), Error> {
+    let mut ret = EnclaveReturn::Success;
+    let (mut seq, mut head, mut sig, mut resumed) = (0u64, [0u8; 32], [0u8; 65], 0u8);
+    let status = telemetry::ecall("ecall_audit_checkpoint", || unsafe {
+        ecall_audit_checkpoint(eid, &mut ret as *mut EnclaveReturn, prev.0, &prev.1, digests.as_ptr(), digests.len(),
+                               &mut seq as *mut u64, &mut head, &mut sig, &mut resumed as *mut u8)
+    });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok((seq, head, sig, resumed != 0))
+}
+
+impl AuditLog {
+    // Picks up where the log at `audit.path` ends, an empty path disables the log.
+    pub fn open(config: &AuditConfig) -> Result<Self, Error> {
+        let mut state = State { file: None, seq: 0, head: GENESIS, signed: (0, GENESIS), signed_at: now(), pending: Vec::new() };
+        let path = if config.path.is_empty() { None } else { Some(PathBuf::from(&config.path)) };
+        if let Some(path) = &path {
+            let records = read_records(path)?;
+            if let Err(e) = audit::verify(&records, None) {
+                warn!(target: "security", "The audit log {} doesn't verify: {}", path.display(), e);
+            }
+            for record in &records {
+                match record {
+                    AuditRecord::Entry(entry) => {
+                        state.seq = entry.seq;
+                        state.head = hash(&entry.hash);
+                        state.pending.push((entry.seq, entry.digest()));
+                    },
+                    AuditRecord::Checkpoint(checkpoint) => {
+                        state.signed = (checkpoint.seq, hash(&checkpoint.head));
+                        state.pending.retain(|&(seq, _)| seq > checkpoint.seq);
+                    },
+                }
+            }
+            state.file = Some(OpenOptions::new().create(true).append(true).open(path)?);
+        }
+        Ok(AuditLog {
+            path,
+            checkpoint_every: config.checkpoint_every as usize,
+            checkpoint_interval: config.checkpoint_interval,
+            state: Mutex::new(state),
+        })
+    }
+
+    fn lock(&self) -> MutexGuard<State> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn append(state: &mut State, record: &AuditRecord) -> Result<(), Error> {
+        if let Some(file) = state.file.as_mut() {
+            let mut line = serde_json::to_vec(record)?;
+            line.push(b'\n');
+            file.write_all(&line)?;
+            file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    // Appends an entry. The operation already happened, a log that can't be written is reported, not fatal.
+    pub fn record(&self, kind: AuditKind, actor: &str, detail: Value) {
+        if self.path.is_none() {
+            return;
+        }
+        let mut state = self.lock();
+        let entry = AuditEntry::new(state.seq + 1, now(), kind, actor, detail, &state.head);
+        if let Err(e) = Self::append(&mut state, &AuditRecord::Entry(entry.clone())) {
+            error!(target: "security", "Writing audit entry {} failed: {}", entry.seq, e);
+        }
+        state.seq = entry.seq;
+        state.head = hash(&entry.hash);
+        state.pending.push((entry.seq, entry.digest()));
+    }
+
+    // Has the primary enclave sign the entries since the last checkpoint, if there are any. The checkpoint
+    // must follow the last entry it covers, entries wait for it. Takes a thread of the enclave: don't call it
+    // while holding one.
+    pub fn checkpoint(&self, pool: &EnclavePool) -> Result<Option<AuditCheckpoint>, Error> {
+        let mut state = self.lock();
+        if self.path.is_none() || state.pending.is_empty() {
+            return Ok(None);
+        }
+        let prev = state.signed;
+        let digests: Vec<u8> = state.pending.iter().flat_map(|(_, digest)| digest.iter().cloned()).collect();
+        let eid = pool.primary();
+        let ((seq, head, signature, resumed), signing_key) = {
+            let _thread = pool.enter(eid);
+            (checkpoint(eid, prev, &digests)?, equote::get_register_signing_address(eid)?)
+        };
+        let checkpoint = AuditCheckpoint {
+            prev_seq: prev.0,
+            prev_head: prev.1.to_hex(),
+            seq,
+            head: head.to_hex(),
+            timestamp: now(),
+            resumed,
+            signing_key: signing_key.to_hex(),
+            signature: signature.to_hex(),
+        };
+        if resumed {
+            info!(target: "security", "The enclave resumed the audit log at entry {}", prev.0);
+        }
+        Self::append(&mut state, &AuditRecord::Checkpoint(checkpoint.clone()))?;
+        state.signed = (seq, head);
+        state.signed_at = checkpoint.timestamp;
+        state.pending.clear();
+        Ok(Some(checkpoint))
+    }
+
+    // After a request, once its enclave thread is given back.
+    pub fn maybe_checkpoint(&self, pool: &EnclavePool) {
+        let due = {
+            let state = self.lock();
+            !state.pending.is_empty() &&
+                (state.pending.len() >= self.checkpoint_every || now() >= state.signed_at + self.checkpoint_interval)
+        };
+        if due {
+            if let Err(e) = self.checkpoint(pool) {
+                warn!(target: "security", "Signing an audit checkpoint failed: {}", e);
+            }
+        }
+    }
+
+    // The records from entry `from` on, at most `MAX_EXPORT` entries, and the first entry left out if any.
+    pub fn export(&self, from: u64) -> Result<(Vec<AuditRecord>, Option<u64>), Error> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok((Vec::new(), None)),
+        };
+        // No half-written line
+        let all = {
+            let _state = self.lock();
+            read_records(path)?
+        };
+        let mut records = Vec::new();
+        let mut entries = 0;
+        for record in all {
+            let seq = match &record {
+                AuditRecord::Entry(entry) => entry.seq,
+                AuditRecord::Checkpoint(checkpoint) => checkpoint.seq,
+            };
+            if seq < from {
+                continue;
+            }
+            if let AuditRecord::Entry(_) = record {
+                if entries == MAX_EXPORT {
+                    return Ok((records, Some(seq)));
+                }
+                entries += 1;
+            }
+            records.push(record);
+        }
+        Ok((records, None))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_audit_log_file() {
+        let path = std::env::temp_dir().join(format!("safetrace-audit-{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let config = AuditConfig { path: path.to_string_lossy().to_string(), ..AuditConfig::default() };
+
+        let log = AuditLog::open(&config).unwrap();
+        log.record(AuditKind::PolicyChange, "startup", json!({"retentionDays": 14}));
+        log.record(AuditKind::Ingest, "ipc", json!({"command": "AddPersonalData"}));
+        drop(log);
+
+        // Reopened, the chain goes on from the last entry
+        let log = AuditLog::open(&config).unwrap();
+        log.record(AuditKind::Deletion, "operator 0", json!({"op": "Purge", "users": 1, "records": 3}));
+        let (records, next) = log.export(0).unwrap();
+        assert_eq!((records.len(), next), (3, None));
+        let verification = verification(&records);
+        assert!(verification.valid, "{:?}", verification.error);
+        assert_eq!((verification.summary.last_seq, verification.summary.signed_seq), (3, 0));
+        assert_eq!(log.lock().pending.len(), 3);
+        assert_eq!(log.export(3).unwrap().0.len(), 1);
+
+        // An edited line breaks the chain
+        let content = fs::read_to_string(&path).unwrap().replace("\"users\":1", "\"users\":0");
+        fs::write(&path, content).unwrap();
+        assert!(!verification(&log.export(0).unwrap().0).valid);
+        fs::remove_file(&path).unwrap();
+
+        let disabled = AuditLog::open(&AuditConfig { path: String::new(), ..AuditConfig::default() }).unwrap();
+        disabled.record(AuditKind::Admin, "operator 0", json!({"op": "DumpMetrics"}));
+        assert_eq!(disabled.export(0).unwrap().0.len(), 0);
+    }
+}
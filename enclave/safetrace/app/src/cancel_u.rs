@@ -0,0 +1,143 @@
+use crate::common_u::errors::RequestTimeoutErr;
+use failure::Error;
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Request deadlines. A request runs on a thread of its own while the IPC thread waits at most until its
+// deadline: then the client gets a timeout, and the cancellation flag of the request is raised. The
+// ecalls of the request read the flag through `ocall_is_cancelled`, which runs on the thread that made
+// the ecall (see `cancel` in the enclave): their long loops stop, the worker thread is given back instead
+// of staying stuck behind a request nobody waits for. A write that was past its checks when the deadline
+// passed still completes.
+
+// The timeout of each request: the one the client asks for (`timeoutMs`), up to `max`, else `default`.
+// 0 disables the default or the bound.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Timeouts {
+    pub default: u64,
+    pub max: u64,
+}
+
+impl Timeouts {
+    pub fn for_request(&self, requested: Option<u64>) -> Option<Duration> {
+        let timeout = match requested.filter(|&ms| ms > 0) {
+            Some(ms) if self.max > 0 => ms.min(self.max),
+            Some(ms) => ms,
+            None => self.default,
+        };
+        if timeout == 0 { None } else { Some(Duration::from_millis(timeout)) }
+    }
+}
+
+#[derive(Clone)]
+pub struct Deadline {
+    at: Instant,
+    timeout: Duration,
+    cancelled: Arc<AtomicBool>,
+}
+
+thread_local! {
+    // The deadline of the request the current thread runs, read by the ocall
+    static CURRENT: RefCell<Option<Deadline>> = RefCell::new(None);
+}
+
+impl Deadline {
+    pub fn after(timeout: Duration) -> Self {
+        Deadline { at: Instant::now() + timeout, timeout, cancelled: Arc::new(AtomicBool::new(false)) }
+    }
+
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    fn remaining(&self) -> Duration {
+        let now = Instant::now();
+        if now >= self.at { Duration::from_millis(0) } else { self.at - now }
+    }
+
+    fn timeout_err(&self) -> Error {
+        let timeout_ms = self.timeout.as_secs() * 1000 + u64::from(self.timeout.subsec_millis());
+        RequestTimeoutErr { timeout_ms }.into()
+    }
+}
+
+// Runs `task` on a thread of its own, with `deadline` polled by the ecalls it makes. Returns its result,
+// or a `RequestTimeoutErr` once the deadline passed, leaving the task to wind down on its own. An ecall
+// that stopped because of the deadline fails like any other: that's a timeout too.
+pub fn run<T, F>(deadline: Deadline, task: F) -> Result<T, Error>
+where F: FnOnce() -> Result<T, Error> + Send + 'static, T: Send + 'static {
+    let (sender, receiver) = mpsc::channel();
+    let polled = deadline.clone();
+    thread::spawn(move || {
+        CURRENT.with(|current| *current.borrow_mut() = Some(polled));
+        // Nobody listens anymore after a timeout
+        let _ = sender.send(task());
+    });
+    match receiver.recv_timeout(deadline.remaining()) {
+        Ok(Err(_)) if deadline.is_cancelled() => Err(deadline.timeout_err()),
+        Ok(result) => result,
+        Err(_) => {
+            deadline.cancel();
+            Err(deadline.timeout_err())
+        },
+    }
+}
+
+// Whether the request of this thread was given up on, for the enclave loops. The flag is raised here too
+// once the deadline passed, in case the waiting side didn't get to it yet.
+#[no_mangle]
+pub extern "C" fn ocall_is_cancelled() -> u8 {
+    CURRENT.with(|current| match &*current.borrow() {
+        Some(deadline) => {
+            if Instant::now() >= deadline.at {
+                deadline.cancel();
+            }
+            deadline.is_cancelled() as u8
+        },
+        None => 0,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_request_timeouts() {
+        let timeouts = Timeouts { default: 30_000, max: 60_000 };
+        assert_eq!(timeouts.for_request(None), Some(Duration::from_millis(30_000)));
+        assert_eq!(timeouts.for_request(Some(500)), Some(Duration::from_millis(500)));
+        assert_eq!(timeouts.for_request(Some(600_000)), Some(Duration::from_millis(60_000)));
+        assert_eq!(timeouts.for_request(Some(0)), Some(Duration::from_millis(30_000)));
+        assert_eq!(Timeouts { default: 0, max: 0 }.for_request(None), None);
+        assert_eq!(Timeouts { default: 0, max: 0 }.for_request(Some(600_000)), Some(Duration::from_millis(600_000)));
+    }
+
+    #[test]
+    fn test_deadline_cancels() {
+        assert_eq!(run(Deadline::after(Duration::from_secs(5)), || Ok(ocall_is_cancelled())).unwrap(), 0);
+
+        // The task polls like an enclave loop, and stops once the caller gave up
+        let (sender, receiver) = mpsc::channel();
+        let deadline = Deadline::after(Duration::from_millis(50));
+        let e = run(deadline.clone(), move || -> Result<(), Error> {
+            while ocall_is_cancelled() == 0 {
+                thread::sleep(Duration::from_millis(5));
+            }
+            sender.send(()).unwrap();
+            Ok(())
+        }).unwrap_err();
+        assert!(e.downcast_ref::<RequestTimeoutErr>().is_some(), "{}", e);
+        assert!(deadline.is_cancelled());
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        // Outside a request nothing is cancelled
+        assert_eq!(ocall_is_cancelled(), 0);
+    }
+}
@@ -0,0 +1,136 @@
+use crate::common_u::errors::EnclaveFailError;
+use crate::esgx::chunks;
+use crate::telemetry;
+use failure::Error;
+use sgx_types::{sgx_enclave_id_t, sgx_status_t};
+use enigma_types::{EnclaveReturn};
+
+
+extern {
+    pub fn ecall_new_channel_key(
+        eid: sgx_enclave_id_t,
+        retval: *mut EnclaveReturn,
+        channel_pubkey: *mut [u8; 64usize],
+        sig: *mut [u8; 65usize],
+    ) -> sgx_status_t;
+
+    pub fn ecall_open_channel(
+        eid: sgx_enclave_id_t,
+        retval: *mut EnclaveReturn,
+        channel_pubkey: *const [u8; 64usize],
+        peer_pubkey: *const [u8; 64usize],
+        peer_sig: *const [u8; 65usize],
+        peer_address: *const [u8; 20usize],
+    ) -> sgx_status_t;
+
+    pub fn ecall_federated_begin(
+        eid: sgx_enclave_id_t,
+        retval: *mut EnclaveReturn,
+        encryptedUserId: *const u8,
+        encryptedUserId_len: usize,
+        encryptedSignature: *const u8,
+        encryptedSignature_len: usize,
+        userPubKey: *const [u8; 64usize],
+        peers: *const u8,
+        peers_len: usize,
+        serialized_ptr: *mut u64,
+    ) -> sgx_status_t;
+
+    pub fn ecall_federated_answer(
+        eid: sgx_enclave_id_t,
+        retval: *mut EnclaveReturn,
+        peer_address: *const [u8; 20usize],
+        query: *const u8,
+        query_len: usize,
+        serialized_ptr: *mut u64,
+    ) -> sgx_status_t;
+
+    pub fn ecall_federated_end(
+        eid: sgx_enclave_id_t,
+        retval: *mut EnclaveReturn,
+        userPubKey: *const [u8; 64usize],
+        peers: *const u8,
+        peers_len: usize,
+        answers_context: u64,
+        serialized_ptr: *mut u64,
+    ) -> sgx_status_t;
+}
+
+
+// Generates an ephemeral channel key inside the enclave, signed by the enclave signing key.
+pub fn new_channel_key(eid: sgx_enclave_id_t) -> Result<([u8; 64], [u8; 65]), Error> {
+    let mut channel_pubkey = [0u8; 64];
+    let mut sig = [0u8; 65];
+    let mut ret = EnclaveReturn::Success;
+
+    let status = telemetry::ecall("ecall_new_channel_key", || unsafe { ecall_new_channel_key(eid, &mut ret as *mut EnclaveReturn, &mut channel_pubkey, &mut sig) });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok((channel_pubkey, sig))
+}
+
+// Derives the session key with an attested peer. The pending `channel_pubkey` is consumed.
+pub fn open_channel(eid: sgx_enclave_id_t, channel_pubkey: &[u8; 64], peer_pubkey: &[u8; 64],
+                    peer_sig: &[u8; 65], peer_address: &[u8; 20]) -> Result<(), Error> {
+    let mut ret = EnclaveReturn::Success;
+
+    let status = telemetry::ecall("ecall_open_channel", || unsafe { ecall_open_channel(eid, &mut ret as *mut EnclaveReturn, channel_pubkey, peer_pubkey, peer_sig, peer_address) });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok(())
+}
+
+fn take_serialized(ret: EnclaveReturn, status: sgx_status_t, serialized_ptr: u64) -> Result<Box<[u8]>, Error> {
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    let box_ptr = serialized_ptr as *mut Box<[u8]>;
+    let part = unsafe { Box::from_raw(box_ptr) };
+    Ok(*part)
+}
+
+// Starts a federated query for the user, returns one encrypted query per peer (same order as `peers`).
+pub fn federated_begin(eid: sgx_enclave_id_t, encrypted_userid: &[u8], encrypted_signature: &[u8], user_pubkey: &[u8; 64],
+                       peers: &[[u8; 20]]) -> Result<Vec<Vec<u8>>, Error> {
+    let mut ret = EnclaveReturn::Success;
+    let mut serialized_ptr = 0u64;
+    let peers = peers.concat();
+
+    let status = telemetry::ecall("ecall_federated_begin", || unsafe {
+        ecall_federated_begin(eid, &mut ret as *mut EnclaveReturn, encrypted_userid.as_ptr(), encrypted_userid.len(),
+                              encrypted_signature.as_ptr(), encrypted_signature.len(), user_pubkey, peers.as_ptr(), peers.len(), &mut serialized_ptr as *mut u64)
+    });
+    let queries = take_serialized(ret, status, serialized_ptr)?;
+    Ok(serde_json::from_slice(&queries)?)
+}
+
+// Answers a federated query received from `peer` over its secure channel.
+pub fn federated_answer(eid: sgx_enclave_id_t, peer: &[u8; 20], query: &[u8]) -> Result<Box<[u8]>, Error> {
+    let mut ret = EnclaveReturn::Success;
+    let mut serialized_ptr = 0u64;
+
+    let status = telemetry::ecall("ecall_federated_answer", || unsafe {
+        ecall_federated_answer(eid, &mut ret as *mut EnclaveReturn, peer, query.as_ptr(), query.len(), &mut serialized_ptr as *mut u64)
+    });
+    take_serialized(ret, status, serialized_ptr)
+}
+
+// Combines the peers' answers (empty for unreachable peers) with the local matches,
+// returns the result encrypted for the user.
+pub fn federated_end(eid: sgx_enclave_id_t, user_pubkey: &[u8; 64], peers: &[[u8; 20]],
+                     answers: &[Vec<u8>]) -> Result<Box<[u8]>, Error> {
+    let mut ret = EnclaveReturn::Success;
+    let mut serialized_ptr = 0u64;
+    let peers = peers.concat();
+    let answers = serde_json::to_vec(answers)?;
+
+    chunks::with_upload(eid, &answers, |answers_context| {
+        let status = telemetry::ecall("ecall_federated_end", || unsafe {
+            ecall_federated_end(eid, &mut ret as *mut EnclaveReturn, user_pubkey, peers.as_ptr(), peers.len(),
+                                answers_context, &mut serialized_ptr as *mut u64)
+        });
+        take_serialized(ret, status, serialized_ptr)
+    })
+}
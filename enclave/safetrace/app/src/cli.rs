@@ -0,0 +1,254 @@
+use clap::{App, AppSettings, Arg, SubCommand};
+
+// Every subcommand shares the enclave initialisation and the configuration of the server, see `config`.
+pub fn app() -> App<'static, 'static> {
+    let production = Arg::with_name("production")
+        .long("production")
+        .help("Refuses to start unless the enclave runs in release mode");
+    let skip_selftest = Arg::with_name("skip-selftest")
+        .long("skip-selftest")
+        .help("Starts without the attestation self-test of server.selftest, e.g. in simulation mode");
+    let standalone = Arg::with_name("standalone")
+        .long("standalone")
+        .help("Runs as a single container: finds the AESM socket, reads the secrets in /run/secrets, runs the \
+               self-test and serves the health probes over HTTP");
+
+    App::new("safetrace-app")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("SafeTrace enclave host")
+        .setting(AppSettings::VersionlessSubcommands)
+        .arg(Arg::with_name("config")
+            .long("config")
+            .short("c")
+            .takes_value(true)
+            .global(true)
+            .help("TOML configuration file, defaults to $SAFETRACE_CONFIG or ./safetrace.toml"))
+        // Without a subcommand the server starts, as with `run`
+        .arg(production.clone())
+        .arg(skip_selftest.clone())
+        .arg(standalone.clone())
+        .subcommand(SubCommand::with_name("run")
+            .about("Starts the IPC server")
+            .arg(production)
+            .arg(skip_selftest)
+            .arg(standalone))
+        .subcommand(SubCommand::with_name("attest")
+            .about("Produces a quote, has it attested by IAS and verifies the report"))
+        .subcommand(SubCommand::with_name("keygen")
+            .about("Prints the enclave signing address, generating the signing key if there's none")
+            .arg(Arg::with_name("force")
+                .long("force")
+                .help("Sets the current key aside and generates a new one, clients must verify the new report")))
+        .subcommand(SubCommand::with_name("seal-backup")
+            .about("Copies the sealed files into a timestamped folder, they can only be restored on this machine")
+            .arg(Arg::with_name("dir").required(true).help("Folder receiving the backups")))
+        .subcommand(SubCommand::with_name("purge")
+            .about("Has the running server delete every stored record, the signing key is kept; the same as `admin purge`")
+            .arg(Arg::with_name("key")
+                .long("key")
+                .takes_value(true)
+                .required(true)
+                .help("File holding the operator secret key, 32 bytes hex"))
+            .arg(Arg::with_name("endpoint")
+                .long("endpoint")
+                .takes_value(true)
+                .help("Admin socket to connect to, defaults to admin.bind"))
+            .arg(Arg::with_name("tenant")
+                .long("tenant")
+                .takes_value(true)
+                .help("Only purges the records of this tenant"))
+            .arg(Arg::with_name("out")
+                .long("out")
+                .takes_value(true)
+                .help("Writes the signed request to this file for the other operators to cosign, instead of sending it"))
+            .arg(Arg::with_name("yes").long("yes").help("Confirms the deletion")))
+        .subcommand(SubCommand::with_name("fsck")
+            .about("Checks every entry of the sealed store, and reports a write that didn't complete")
+            .arg(Arg::with_name("repair")
+                .long("repair")
+                .help("Compacts the store into a single snapshot, without the entry a write left incomplete")))
+        .subcommand(SubCommand::with_name("preflight")
+            .about("Checks the platform can run the enclave: SGX in the CPU, the driver, the AESM service, launch control and EPC")
+            .arg(Arg::with_name("json").long("json").help("Prints the checks as a JSON report")))
+        .subcommand(SubCommand::with_name("status")
+            .about("Prints the enclave mode, signing address and sealed store statistics"))
+        .subcommand(SubCommand::with_name("admin")
+            .about("Sends a signed request to the admin socket of a running server")
+            .arg(Arg::with_name("key")
+                .long("key")
+                .takes_value(true)
+                .required(true)
+                .help("File holding the operator secret key, 32 bytes hex"))
+            .arg(Arg::with_name("endpoint")
+                .long("endpoint")
+                .takes_value(true)
+                .help("Admin socket to connect to, defaults to admin.bind"))
+            .arg(Arg::with_name("op")
+                .required(true)
+                .possible_values(&["purge", "rotate-keys", "metrics", "log-level", "principals", "tcb-status", "dashboard", "zones", "upgrade",
+                                   "policy", "adopt-operators", "maintenance", "drain-status", "feature", "pubkey", "cosign", "submit"])
+                .help("Operation, `pubkey` prints the public key to list in admin.operators, `cosign` adds a signature \
+                       to the request of --request and `submit` sends it"))
+            .arg(Arg::with_name("level")
+                .required_if("op", "log-level")
+                .help("Log filters for log-level, e.g. debug or warn,security=info"))
+            .arg(Arg::with_name("state")
+                .long("state")
+                .takes_value(true)
+                .possible_values(&["on", "off"])
+                .required_ifs(&[("op", "maintenance"), ("op", "feature")])
+                .help("Whether maintenance refuses the data plane requests, follow it with drain-status until drained; \
+                       whether the feature of --feature is served"))
+            .arg(Arg::with_name("feature")
+                .long("feature")
+                .takes_value(true)
+                .possible_values(&["registration", "keyExchange", "ingest", "matching", "federation"])
+                .required_if("op", "feature")
+                .help("Subsystem to switch on or off, for feature"))
+            .arg(Arg::with_name("retry-after")
+                .long("retry-after")
+                .takes_value(true)
+                .validator(|seconds| seconds.parse::<u64>().map(|_| ()).map_err(|_| format!("{} isn't a number of seconds", seconds)))
+                .help("Seconds the refused clients are told to wait, for maintenance"))
+            .arg(Arg::with_name("file")
+                .long("file")
+                .takes_value(true)
+                .required_ifs(&[("op", "zones"), ("op", "upgrade"), ("op", "policy")])
+                .help("JSON list of exclusion zones replacing the current ones, for zones; the enclave file \
+                       of enclave.files to switch to, for upgrade; the output of sign-policy, for policy"))
+            .arg(Arg::with_name("tenant")
+                .long("tenant")
+                .takes_value(true)
+                .help("Only purges the records of this tenant, the operators of a tenant must pass theirs"))
+            .arg(Arg::with_name("request")
+                .long("request")
+                .takes_value(true)
+                .required_ifs(&[("op", "cosign"), ("op", "submit")])
+                .conflicts_with("out")
+                .help("Signed request to cosign or submit, for the operations that need admin.quorum operators"))
+            .arg(Arg::with_name("out")
+                .long("out")
+                .takes_value(true)
+                .help("Writes the signed request to this file for the other operators to cosign, instead of sending it")))
+        .subcommand(SubCommand::with_name("sign-policy")
+            .about("Signs a policy document with the policy key, or prints the hash of the key to build the enclave with")
+            .arg(Arg::with_name("key")
+                .long("key")
+                .takes_value(true)
+                .required(true)
+                .help("File holding the policy secret key, 32 bytes hex"))
+            .arg(Arg::with_name("document").help("JSON policy document, see `policy` in the enclave")))
+        .subcommand(SubCommand::with_name("verify-archive")
+            .about("Checks every report of an attestation archive offline: signature, certificate chain and quote")
+            .arg(Arg::with_name("file").help("Archive to check, defaults to ias.archive"))
+            .arg(Arg::with_name("root")
+                .long("root")
+                .takes_value(true)
+                .help("PEM of the root the reports must chain up to, defaults to ias.root_ca or the Intel root")))
+        .subcommand(SubCommand::with_name("manifest")
+            .about("Writes the manifest of the enclave: its measurements and how it was built, to publish with the binary")
+            .arg(Arg::with_name("source")
+                .long("source")
+                .takes_value(true)
+                .help("Repository the enclave is built from"))
+            .arg(Arg::with_name("commit")
+                .long("commit")
+                .takes_value(true)
+                .help("Commit the enclave is built from, defaults to the one this binary was built from"))
+            .arg(Arg::with_name("output")
+                .long("output")
+                .short("o")
+                .takes_value(true)
+                .help("File to write, defaults to printing the manifest")))
+        .subcommand(SubCommand::with_name("dead-letters")
+            .about("Lists the IPC frames that failed, or sends them to the server again")
+            .arg(Arg::with_name("file")
+                .long("file")
+                .takes_value(true)
+                .help("Dead letters file, defaults to dead_letters.path"))
+            .arg(Arg::with_name("stage")
+                .long("stage")
+                .takes_value(true)
+                .possible_values(&["parse", "decode", "validation", "handler"])
+                .help("Only the frames that failed at this stage"))
+            .arg(Arg::with_name("command")
+                .long("command")
+                .takes_value(true)
+                .help("Only the frames of this command, e.g. AddPersonalData"))
+            .arg(Arg::with_name("replay")
+                .long("replay")
+                .help("Sends the frames to the server again and prints the answers"))
+            .arg(Arg::with_name("endpoint")
+                .long("endpoint")
+                .takes_value(true)
+                .requires("replay")
+                .help("IPC socket to replay to, defaults to server.bind")))
+        .subcommand(SubCommand::with_name("migrate-legacy")
+            .about("Converts the sealed files left by the enigma-core based prototypes")
+            .arg(Arg::with_name("dir").help("Folder holding the legacy files, defaults to ~/.enigma")))
+}
+
+#[cfg(test)]
+mod test {
+    use super::app;
+
+    #[test]
+    fn test_subcommands() {
+        let matches = app().get_matches_from(vec!["safetrace-app", "seal-backup", "/tmp/backups"]);
+        assert_eq!(matches.subcommand_matches("seal-backup").unwrap().value_of("dir"), Some("/tmp/backups"));
+        assert!(app().get_matches_from_safe(vec!["safetrace-app", "seal-backup"]).is_err());
+
+        let matches = app().get_matches_from(vec!["safetrace-app", "preflight", "--json"]);
+        assert!(matches.subcommand_matches("preflight").unwrap().is_present("json"));
+        let matches = app().get_matches_from(vec!["safetrace-app", "fsck", "--repair"]);
+        assert!(matches.subcommand_matches("fsck").unwrap().is_present("repair"));
+
+        let matches = app().get_matches_from(vec!["safetrace-app", "--production"]);
+        assert!(matches.subcommand_name().is_none() && matches.is_present("production"));
+        let matches = app().get_matches_from(vec!["safetrace-app", "run", "--skip-selftest"]);
+        assert!(matches.subcommand_matches("run").unwrap().is_present("skip-selftest"));
+        let matches = app().get_matches_from(vec!["safetrace-app", "run", "--standalone"]);
+        assert!(matches.subcommand_matches("run").unwrap().is_present("standalone"));
+
+        assert!(app().get_matches_from_safe(vec!["safetrace-app", "admin", "--key", "op.key", "log-level"]).is_err());
+        let matches = app().get_matches_from(vec!["safetrace-app", "admin", "--key", "op.key", "log-level", "debug"]);
+        assert_eq!(matches.subcommand_matches("admin").unwrap().value_of("level"), Some("debug"));
+
+        assert!(app().get_matches_from_safe(vec!["safetrace-app", "admin", "--key", "op.key", "maintenance"]).is_err());
+        assert!(app().get_matches_from_safe(vec!["safetrace-app", "admin", "--key", "op.key", "maintenance", "--state", "on", "--retry-after", "soon"]).is_err());
+        let matches = app().get_matches_from(vec!["safetrace-app", "admin", "--key", "op.key", "maintenance", "--state", "on", "--retry-after", "300"]);
+        assert_eq!(matches.subcommand_matches("admin").unwrap().value_of("retry-after"), Some("300"));
+        assert!(app().get_matches_from_safe(vec!["safetrace-app", "admin", "--key", "op.key", "feature", "--state", "off"]).is_err());
+        assert!(app().get_matches_from_safe(vec!["safetrace-app", "admin", "--key", "op.key", "feature", "--feature", "uploads", "--state", "off"]).is_err());
+        let matches = app().get_matches_from(vec!["safetrace-app", "admin", "--key", "op.key", "feature", "--feature", "ingest", "--state", "off"]);
+        assert_eq!(matches.subcommand_matches("admin").unwrap().value_of("feature"), Some("ingest"));
+
+        assert!(app().get_matches_from_safe(vec!["safetrace-app", "admin", "--key", "op.key", "zones"]).is_err());
+        let matches = app().get_matches_from(vec!["safetrace-app", "admin", "--key", "op.key", "zones", "--file", "zones.json"]);
+        assert_eq!(matches.subcommand_matches("admin").unwrap().value_of("file"), Some("zones.json"));
+        assert!(app().get_matches_from_safe(vec!["safetrace-app", "admin", "--key", "op.key", "upgrade"]).is_err());
+        let matches = app().get_matches_from(vec!["safetrace-app", "admin", "--key", "op.key", "upgrade", "--file", "enclave-v2.signed.so"]);
+        assert_eq!(matches.subcommand_matches("admin").unwrap().value_of("op"), Some("upgrade"));
+        assert!(app().get_matches_from_safe(vec!["safetrace-app", "admin", "--key", "op.key", "policy"]).is_err());
+        let matches = app().get_matches_from(vec!["safetrace-app", "sign-policy", "--key", "policy.key", "policy.json"]);
+        assert_eq!(matches.subcommand_matches("sign-policy").unwrap().value_of("document"), Some("policy.json"));
+        let matches = app().get_matches_from(vec!["safetrace-app", "admin", "--key", "op.key", "purge", "--tenant", "ch-ge"]);
+        assert_eq!(matches.subcommand_matches("admin").unwrap().value_of("tenant"), Some("ch-ge"));
+        assert!(app().get_matches_from_safe(vec!["safetrace-app", "purge", "--yes"]).is_err());
+        let matches = app().get_matches_from(vec!["safetrace-app", "purge", "--key", "op.key", "--yes"]);
+        assert!(matches.subcommand_matches("purge").unwrap().is_present("yes"));
+        let matches = app().get_matches_from(vec!["safetrace-app", "verify-archive", "attestations.cbor", "--root", "root.pem"]);
+        let args = matches.subcommand_matches("verify-archive").unwrap();
+        assert_eq!((args.value_of("file"), args.value_of("root")), (Some("attestations.cbor"), Some("root.pem")));
+
+        let matches = app().get_matches_from(vec!["safetrace-app", "dead-letters", "--stage", "handler", "--replay"]);
+        let args = matches.subcommand_matches("dead-letters").unwrap();
+        assert_eq!((args.value_of("stage"), args.is_present("replay")), (Some("handler"), true));
+        assert!(app().get_matches_from_safe(vec!["safetrace-app", "dead-letters", "--endpoint", "tcp://127.0.0.1:5552"]).is_err());
+
+        let matches = app().get_matches_from(vec!["safetrace-app", "manifest", "--commit", "0123abcd", "-o", "enclave.manifest.json"]);
+        let args = matches.subcommand_matches("manifest").unwrap();
+        assert_eq!((args.value_of("commit"), args.value_of("output"), args.value_of("source")),
+                   (Some("0123abcd"), Some("enclave.manifest.json"), None));
+    }
+}
@@ -2,6 +2,7 @@
 use sgx_types::*;
 use std::fmt;
 use failure::Error;
+pub use safetrace_client::errors::ErrorCode;
 
 // error while requesting to produce a quote (registration)
 #[derive(Fail, Debug)]
@@ -32,6 +33,29 @@ pub struct AttestationServiceErr {
     pub message: String,
 }
 
+// IAS (or the network to it) kept failing after the retries: a later attempt may succeed, unlike with the
+// errors above
+#[derive(Fail, Debug)]
+#[fail(display = "The attestation service is unavailable = ({})", message)]
+pub struct IasUnavailableErr {
+    pub message: String,
+}
+
+// IAS turned the SPID or the subscription key down, checked at startup
+#[derive(Fail, Debug)]
+#[fail(display = "Credentials invalid: the attestation service refused the SPID or the subscription key ({})", message)]
+pub struct IasCredentialsErr {
+    pub message: String,
+}
+
+// the boot self-test failed, the server doesn't start
+#[derive(Fail, Debug)]
+#[fail(display = "The startup self-test failed at {}: {}", step, message)]
+pub struct SelfTestErr {
+    pub step: String,
+    pub message: String,
+}
+
 #[derive(Fail, Debug)]
 #[fail(display = "Error while parsing the p2p messages, command: {}, error: {}", cmd, msg)]
 pub struct P2PErr {
@@ -83,4 +107,121 @@ impl fmt::Display for DBErrKind {
 pub struct EnclaveFailError {
     pub err: enigma_types::EnclaveReturn,
     pub status: sgx_status_t,
-}
\ No newline at end of file
+}
+
+#[derive(Fail, Debug)]
+#[fail(display = "The {:?} feature is currently disabled by the operator", feature)]
+pub struct FeatureDisabledErr {
+    pub feature: crate::networking::switches::Feature,
+}
+
+#[derive(Fail, Debug)]
+#[fail(display = "The request didn't complete within {} ms", timeout_ms)]
+pub struct RequestTimeoutErr {
+    pub timeout_ms: u64,
+}
+
+// A frame past `server.max_frame_size`, refused before it's parsed
+#[derive(Fail, Debug)]
+#[fail(display = "PayloadTooLarge: the frame is {} bytes, past the limit of {}", size, limit)]
+pub struct PayloadTooLargeErr {
+    pub size: usize,
+    pub limit: usize,
+}
+
+#[derive(Fail, Debug)]
+#[fail(display = "Unknown tenant {}", tenant)]
+pub struct UnknownTenantErr {
+    pub tenant: String,
+}
+
+// See `networking::reattest`
+#[derive(Fail, Debug)]
+#[fail(display = "ServiceDegraded: new registrations are refused, {}", reason)]
+pub struct ServiceDegradedErr {
+    pub reason: String,
+}
+
+// See `networking::planes`
+#[derive(Fail, Debug)]
+#[fail(display = "RateLimited: the {} socket takes no more requests for now, retry later", socket)]
+pub struct RateLimitedErr {
+    pub socket: &'static str,
+}
+
+// See `networking::router`
+#[derive(Fail, Debug)]
+#[fail(display = "RateLimited: {} messages of this client are already waiting, wait for their answers", queued)]
+pub struct ClientBusyErr {
+    pub queued: usize,
+}
+
+// See `safetrace_client::tags`
+#[derive(Fail, Debug)]
+#[fail(display = "Unauthenticated: the frame doesn't carry the tag of server.frame_key_path")]
+pub struct UnauthenticatedErr;
+
+// See `networking::maintenance`
+#[derive(Fail, Debug)]
+#[fail(display = "Maintenance: the node is draining for maintenance, retry in {} seconds", retry_after)]
+pub struct MaintenanceErr {
+    pub retry_after: u64,
+}
+
+#[derive(Fail, Debug)]
+#[fail(display = "{} isn't served on the {} socket", command, socket)]
+pub struct WrongSocketErr {
+    pub command: String,
+    pub socket: &'static str,
+}
+
+#[derive(Fail, Debug)]
+#[fail(display = "Invalid configuration: {}", message)]
+pub struct ConfigErr {
+    pub message: String,
+}
+
+#[derive(Fail, Debug)]
+#[fail(display = "Admin request refused: {}", message)]
+pub struct AdminAuthErr {
+    pub message: String,
+}
+
+// The stable code of an error, sent with every failure the IPC socket answers (see `ErrorCode`).
+pub fn error_code(e: &Error) -> ErrorCode {
+    if e.downcast_ref::<FeatureDisabledErr>().is_some() {
+        ErrorCode::FeatureDisabled
+    } else if e.downcast_ref::<crate::networking::deprecation::SunsetErr>().is_some() {
+        ErrorCode::MethodRetired
+    } else if e.downcast_ref::<AttestationServiceErr>().is_some() {
+        ErrorCode::AttestationFailed
+    } else if e.downcast_ref::<IasUnavailableErr>().is_some() {
+        ErrorCode::AttestationUnavailable
+    } else if e.downcast_ref::<P2PErr>().is_some() {
+        ErrorCode::PeerError
+    } else if e.downcast_ref::<RequestTimeoutErr>().is_some() {
+        ErrorCode::RequestTimeout
+    } else if e.downcast_ref::<PayloadTooLargeErr>().is_some() {
+        ErrorCode::PayloadTooLarge
+    } else if e.downcast_ref::<EnclaveFailError>().is_some() {
+        ErrorCode::EnclaveError
+    } else if e.downcast_ref::<crate::networking::validation::ValidationErr>().is_some() {
+        ErrorCode::ValidationError
+    } else if e.downcast_ref::<UnknownTenantErr>().is_some() {
+        ErrorCode::UnknownTenant
+    } else if e.downcast_ref::<ServiceDegradedErr>().is_some() {
+        ErrorCode::ServiceDegraded
+    } else if e.downcast_ref::<RateLimitedErr>().is_some() || e.downcast_ref::<ClientBusyErr>().is_some() {
+        ErrorCode::RateLimited
+    } else if e.downcast_ref::<UnauthenticatedErr>().is_some() {
+        ErrorCode::Unauthenticated
+    } else if e.downcast_ref::<MaintenanceErr>().is_some() {
+        ErrorCode::Maintenance
+    } else if e.downcast_ref::<WrongSocketErr>().is_some() {
+        ErrorCode::UnknownMethod
+    } else if e.downcast_ref::<serde_json::Error>().is_some() {
+        ErrorCode::InvalidRequest
+    } else {
+        ErrorCode::InternalError
+    }
+}
@@ -0,0 +1,1362 @@
+use crate::attestation::{verify, RetryPolicy, TlsOptions};
+use crate::cancel_u::Timeouts;
+use crate::common_u::errors::ConfigErr;
+use crate::esgx::threads::MAX_THREADS;
+use crate::networking::messages::{MatchingStrategy, Quantization};
+use crate::networking::router::UNIX_SCHEME;
+use crate::networking::switches::KillSwitches;
+use crate::padding_u::PaddingClass;
+use crate::secrets::{self, FileSecrets, Secret, SecretProvider, VaultSecrets};
+use enigma_tools_u::attestation_service::constants::ATTESTATION_SERVICE_URL;
+use safetrace_client::manifest::EnclaveManifest;
+use safetrace_client::signatures::Curve;
+use safetrace_client::tags::{self, FrameKey};
+use failure::Error;
+use hex::FromHex;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+// Read when neither `--config` nor `SAFETRACE_CONFIG` name a file, if it exists.
+const DEFAULT_CONFIG_FILE: &str = "safetrace.toml";
+// Docker and Kubernetes mount the secrets there
+const STANDALONE_SECRETS_DIR: &str = "/run/secrets";
+const STANDALONE_HEALTH_BIND: &str = "0.0.0.0:8080";
+// The smallest `server.max_frame_size`, a federation query is up to 128 kB of hex
+pub const MIN_FRAME_SIZE: usize = 256 * 1024;
+
+// Everything the app reads at startup. Every setting has a default, can be set in the TOML file
+// (see `safetrace.example.toml`) and overridden by its `SAFETRACE_*` environment variable.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // Prefer `secrets.spid_file` or a secret provider, the file and the environment are readable by more people
+    pub spid: Secret,
+    pub secrets: SecretsConfig,
+    pub server: ServerConfig,
+    pub enclave: EnclaveConfig,
+    pub ias: IasConfig,
+    pub retention: RetentionConfig,
+    pub capacity: CapacityConfig,
+    pub matching: MatchingConfig,
+    pub quantization: QuantizationConfig,
+    pub statistics: StatisticsConfig,
+    pub export: ExportConfig,
+    pub jobs: JobsConfig,
+    pub logging: LoggingConfig,
+    pub admin: AdminConfig,
+    pub audit: AuditConfig,
+    pub dead_letters: DeadLetterConfig,
+    pub telemetry: TelemetryConfig,
+    pub revocation: RevocationConfig,
+    pub lease: LeaseConfig,
+    pub precompute: PrecomputeConfig,
+    pub tenants: Vec<TenantConfig>,
+}
+
+// Where credentials come from. An explicit file wins, then Vault, then the secrets folder,
+// then the value inlined in the configuration.
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct SecretsConfig {
+    // One file per secret (`spid`, `ias_key`), e.g. docker's `/run/secrets`
+    pub dir: Option<PathBuf>,
+    pub spid_file: Option<PathBuf>,
+    pub vault: Option<VaultConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct VaultConfig {
+    pub addr: String,
+    // KV secret holding the `spid` and `ias_key` fields, e.g. `secret/data/safetrace`
+    pub path: String,
+    pub token_file: PathBuf,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    // ZMQ endpoint the IPC server binds to, `unix:///path` for a Unix domain socket, `host:port` with the `tls`
+    // transport
+    pub bind: String,
+    // Permissions of the `unix://` sockets, e.g. 0o660 for the owner and group only
+    pub socket_mode: u32,
+    // `zmq`, or `tls` for length-prefixed frames over TLS, see `networking::tcp`
+    pub transport: String,
+    // PEM certificate chain and private key of the `tls` transport
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    // File holding the key the gateway tags the frames with, hex, see `safetrace_client::tags`
+    pub frame_key_path: Option<PathBuf>,
+    // The key itself, from `frame_key_path` or a secret provider. None takes the frames untagged
+    #[serde(skip)]
+    pub frame_key: Option<Secret>,
+    // Other SafeTrace deployments queried by `FindMatchFederated`
+    pub peers: Vec<String>,
+    // Subsystems disabled at startup, they can be turned back on with the admin `SetFeatureSwitch`
+    pub disabled_features: Vec<String>,
+    // Milliseconds a request may take unless it asks for another timeout (`timeoutMs`), 0 is unlimited,
+    // see `cancel_u`
+    pub request_timeout: u64,
+    // The longest timeout a request may ask for, 0 is unbounded
+    pub max_request_timeout: u64,
+    // Bytes a frame may take, announced in `GetProtocolVersion` (`maxFrameSize`)
+    pub max_frame_size: usize,
+    // Attest the enclave before binding `bind`, and refuse to start if that fails, see `selftest_u`
+    pub selftest: bool,
+    // `host:port` serving `/healthz` and `/readyz` over plain HTTP for container probes, empty for none
+    pub health_bind: String,
+    // ZMQ endpoint of the control socket, see `networking::planes`. Empty serves everything on `bind`
+    pub control_bind: String,
+    // Frames of a message handled at once on each socket once split
+    pub control_workers: usize,
+    pub data_workers: usize,
+    // Requests per second each socket takes once split, 0 is unlimited
+    pub control_rate_limit: u64,
+    pub data_rate_limit: u64,
+    // Messages each socket handles at once, see `networking::router`
+    pub workers: usize,
+    // Messages of a client handled at once, and waiting for them; the ones past these are refused
+    pub max_in_flight: usize,
+    pub max_queued: usize,
+    // Seconds between the ZMQ heartbeats each connection gets, 0 for none
+    pub heartbeat_interval: u64,
+    // Seconds a connection may go without answering them before it's closed, announced in `Heartbeat`
+    pub idle_timeout: u64,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct EnclaveConfig {
+    // The enclave builds this node may run, the first one unless an `Upgrade` switched to another one
+    // (`esgx::general::ACTIVE_FILE`)
+    pub files: Vec<String>,
+    // Number of enclave workers sharing the sealed state, see `esgx::pool`
+    pub workers: usize,
+    // Ecalls each worker runs at once, at most the TCSNum the enclave was signed with, see `esgx::threads`
+    pub threads: usize,
+    // Release enclaves need an enclave signing key whitelisted by Intel, debug ones are refused with `--production`
+    pub release: bool,
+    // Bucket size per class of encrypted outputs, see `padding_u`
+    pub response_padding: BTreeMap<String, u64>,
+    // Real matching queries each enclave answers per minute, 0 is unlimited
+    pub match_rate_limit: u64,
+    // How old (or early) an `addPersonalData` envelope may be, in seconds, see `replay` in the enclave
+    pub replay_window: u64,
+    // Refuse the payloads of older clients, which carry no nonce
+    pub require_replay_protection: bool,
+    // How far off the devices' clocks may be, in seconds, see `clock` in the enclave. 0 turns the checks off
+    pub max_clock_skew: u64,
+    // Refuse the requests of users who didn't register a signing key, see `users` in the enclave
+    pub require_registration: bool,
+    // Public keys (64 bytes hex) of the health authorities whose declarations mark users as infected,
+    // see `authority` in the enclave, which takes them once. Empty trusts the `testResult` of the submissions
+    pub health_authorities: Vec<String>,
+    // How long a declaration of a health authority is taken after its `issuedAt`, in seconds
+    pub declaration_max_age: u64,
+    // The published manifest of the enclave build (`safetrace_client::manifest`), served by `GetEnclaveInfo`
+    pub manifest: Option<PathBuf>,
+    // Bind the sealed store to an SGX monotonic counter, so it can't be rolled back across restarts either,
+    // see `freshness` in the enclave. Needs the platform services (PSE)
+    pub monotonic_counter: bool,
+    // Writes appended to the log of the sealed store before it's compacted into a snapshot, see `sealed_log`
+    // in the enclave. 0 compacts on every write
+    pub compact_after: u64,
+    // The curve of the signing key, secp256k1 (Ethereum's `ecrecover`) or p256 (NIST P-256), see `identity` in
+    // the enclave. Each curve has its own sealed key, switching curves switches identities
+    pub signing_curve: String,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct IasConfig {
+    pub url: String,
+    // File holding the IAS subscription key, if the attestation service asks for one
+    pub key_path: Option<PathBuf>,
+    // The key itself, from `key_path` or a secret provider
+    #[serde(skip)]
+    pub key: Option<Secret>,
+    pub retries: u32,
+    // Per attempt, in seconds
+    pub timeout: u64,
+    pub proxy: Option<String>,
+    pub ca_bundle: Option<PathBuf>,
+    pub tls_pins: Vec<String>,
+    // PEM of the root the IAS reports must chain up to, instead of the Intel root pinned in `attestation::verify`
+    pub root_ca: Option<PathBuf>,
+    // JSON file keeping the registrations made while IAS is down, see `networking::attestation_jobs`.
+    // Empty fails them instead
+    pub retry_store: String,
+    // Seconds between two attempts of a waiting registration
+    pub retry_interval: u64,
+    // How long a report waits to be collected, in seconds
+    pub retry_retention: u64,
+    // Registrations waiting at once, more are refused
+    pub max_pending: usize,
+    // File every report is archived in, see `attestation::archive`. Empty keeps none
+    pub archive: String,
+    // File the verified reports are cached in by quote, see `attestation::cache`. Empty caches none
+    pub report_cache: String,
+    // Reports cached at once, the oldest go first
+    pub cache_size: usize,
+    // Seconds between two attestations of the running enclave, see `networking::reattest`. 0 doesn't attest again
+    pub reattest_interval: u64,
+    // Past this age in seconds the last attestation is stale and new registrations are refused, 0 never
+    pub max_attestation_age: u64,
+    // Ask IAS for a SigRL at startup, and refuse to start if it turns the SPID or the key down
+    pub check_credentials: bool,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetentionConfig {
+    // Records older than this are deleted by the enclave, 0 keeps them forever
+    pub days: u64,
+}
+
+// How many records (locations) the enclave stores, see `memory::set_capacity`. 0 is no limit.
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct CapacityConfig {
+    // Past it the enclave still stores, and the metrics say it's near capacity
+    pub soft_records: u64,
+    // A submission taking the store past it is refused with `CapacityExceeded`
+    pub hard_records: u64,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct MatchingConfig {
+    // The strategy of the queries that don't name one: `radius`, `grid` or `duration`
+    pub algorithm: String,
+    // Minimum time overlap of two locations, in seconds (`radius` and `grid`)
+    pub min_overlap: i32,
+    // Maximum distance between two locations, in meters (`radius` and `duration`)
+    pub distance: f64,
+    // Side of the cells, in degrees (`grid`)
+    pub cell: f64,
+    // Minimum time spent close to infected locations in all, in seconds (`duration`)
+    pub min_duration: i32,
+}
+
+// Applied by the enclave to the submitted locations before they're stored, see `params::set_quantization`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct QuantizationConfig {
+    // Coordinates are rounded to multiples of this many degrees, 0 keeps them as sent
+    pub grid: f64,
+    // `startTS` and `endTS` are widened to multiples of this many seconds, 0 keeps them as sent
+    pub time_bucket: u64,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct StatisticsConfig {
+    // Serve `GetAggregates`, the differentially private statistics of `aggregates` in the enclave
+    pub enabled: bool,
+    // Privacy budget of a release, smaller is noisier, at most 10
+    pub epsilon: f64,
+    // Geohash characters of the heatmap cells, 5 is about 5km
+    pub geohash_precision: u8,
+    // Cells with a smaller noisy count are left out
+    pub min_count: u64,
+}
+
+// The exports of `ExportExposureStatistics` for the health authorities, see `export` in the enclave.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExportConfig {
+    // Serve the exports, to the keys of `enclave.health_authorities` only
+    pub enabled: bool,
+    // A cell and day with fewer users is left out, an infected count under it is left empty
+    pub k_anonymity: u64,
+}
+
+// The asynchronous matching of `SubmitMatchJob`, see `networking::jobs`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct JobsConfig {
+    // Stored users compared per ecall, the enclave thread is released in between
+    pub step_size: u64,
+    // Jobs running at once, each one on a thread of the enclave
+    pub concurrency: usize,
+    // Jobs queued or running at once, more are refused
+    pub max_queued: usize,
+    // How long a finished job waits to be collected, in seconds
+    pub retention: u64,
+    // ZMQ endpoint of a PUB socket publishing the progress of the jobs. Empty disables it
+    pub events: String,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    // `env_logger` filters, e.g. `info` or `warn,security=info,metrics=info`
+    pub level: String,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminConfig {
+    // ZMQ endpoint of the admin socket, see `networking::admin`. Empty disables it
+    pub bind: String,
+    // Public keys (64 bytes hex) allowed to sign admin requests
+    pub operators: Vec<String>,
+    // How far the timestamp of a request may be from our clock, in seconds
+    pub max_skew: u64,
+    // Operators who must sign a `Purge`, `RotateKeys`, `Upgrade` or `SetOperators`, checked by the enclave
+    // too, see `quorum_u`
+    pub quorum: u32,
+}
+
+// A region or health authority the deployment serves apart, see `tenant_u`.
+#[derive(Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct TenantConfig {
+    // The `tenant` of the requests, up to 32 letters, digits, `-` and `_`
+    pub id: String,
+    // Replaces `retention.days` for the records of the tenant, 0 keeps them forever
+    pub retention_days: Option<u64>,
+    // Public keys (64 bytes hex) allowed to sign the admin requests of this tenant only
+    pub operators: Vec<String>,
+}
+
+// The audit log of ingest, deletions, policy changes and admin operations, see `audit_u`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuditConfig {
+    // JSON lines, appended to. Empty disables the log
+    pub path: String,
+    // Entries between two checkpoints signed by the enclave
+    pub checkpoint_every: u64,
+    // Seconds after which the next entry is checkpointed anyway
+    pub checkpoint_interval: u64,
+}
+
+// Where the IPC frames that failed go, with why, see `networking::dead_letters`. Off by default: the frames
+// hold public keys and encrypted payloads, keep them as private as the sealed files.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DeadLetterConfig {
+    // JSON lines, appended to. Empty keeps no file
+    pub path: String,
+    // Moved to `<path>.1` past this many bytes, replacing the previous one
+    pub max_file_size: u64,
+    // ZMQ PUB socket publishing the same lines, e.g. `tcp://127.0.0.1:5554`. Empty binds none
+    pub bind: String,
+    // Longer frames are cut, and flagged `truncated`
+    pub max_frame_size: usize,
+}
+
+// Traces and metrics for an OpenTelemetry collector, see `telemetry`. Off by default.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TelemetryConfig {
+    // `otlp` (OTLP/gRPC to `endpoint`) or `log` (the `telemetry` log target), empty exports nothing
+    pub exporter: String,
+    // The OTLP/gRPC receiver of the collector, without TLS
+    pub endpoint: String,
+    pub service_name: String,
+    // The share of the requests traced, 0 to 1
+    pub sampling: f64,
+    // Seconds between two exports
+    pub interval: u64,
+    // Spans kept until the next export, the next ones are dropped
+    pub max_spans: usize,
+    // Seconds an export may take
+    pub timeout: u64,
+}
+
+// The readiness lease renewed by the heartbeats of `networking::lease`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct LeaseConfig {
+    // Seconds between two heartbeats, 0 doesn't take a lease
+    pub interval: u64,
+    // Seconds a heartbeat that passed keeps the node ready
+    pub duration: u64,
+    // Written on every renewal and removed when a heartbeat fails, empty for none
+    pub file: String,
+}
+
+// The matching in the background of `networking::precompute`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct PrecomputeConfig {
+    pub enabled: bool,
+    // Users brought up to the store per ecall
+    pub step_size: u64,
+    // Percent of an enclave thread it may keep busy
+    pub max_cpu: u32,
+    // Seconds between two rounds without an ingest, for the stores other nodes write. 0 runs on ingest only
+    pub interval: u64,
+}
+
+// Whether the certificate signing the IAS reports was revoked, see `attestation::revocation`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RevocationConfig {
+    // `soft` accepts the report when the check can't be made, `hard` fails it, `off` doesn't check
+    pub mode: String,
+    // The CRL of the Intel Attestation Report Signing CA
+    pub crl_url: String,
+    // File keeping the last CRL across restarts, empty keeps it in memory only
+    pub crl_cache: String,
+    // Seconds a CRL is used for before downloading it again, unless its nextUpdate comes first
+    pub crl_refresh: u64,
+    // OCSP responder asked as well, none by default: the Intel certificates don't name one
+    pub ocsp_url: String,
+    // Seconds a download or an OCSP request may take
+    pub timeout: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            spid: Secret::from("B0335FD3BC1CCA8F804EB98A6420592D"),
+            secrets: SecretsConfig::default(),
+            server: ServerConfig::default(),
+            enclave: EnclaveConfig::default(),
+            ias: IasConfig::default(),
+            retention: RetentionConfig::default(),
+            capacity: CapacityConfig::default(),
+            matching: MatchingConfig::default(),
+            quantization: QuantizationConfig::default(),
+            statistics: StatisticsConfig::default(),
+            export: ExportConfig::default(),
+            jobs: JobsConfig::default(),
+            logging: LoggingConfig::default(),
+            admin: AdminConfig::default(),
+            audit: AuditConfig::default(),
+            dead_letters: DeadLetterConfig::default(),
+            telemetry: TelemetryConfig::default(),
+            revocation: RevocationConfig::default(),
+            lease: LeaseConfig::default(),
+            precompute: PrecomputeConfig::default(),
+            tenants: Vec::new(),
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            bind: "tcp://*:5552".to_string(),
+            socket_mode: 0o660,
+            transport: "zmq".to_string(),
+            tls_cert: None,
+            tls_key: None,
+            frame_key_path: None,
+            frame_key: None,
+            peers: Vec::new(),
+            disabled_features: Vec::new(),
+            request_timeout: 30_000,
+            max_request_timeout: 300_000,
+            max_frame_size: 1024 * 1024,
+            selftest: false,
+            health_bind: String::new(),
+            control_bind: String::new(),
+            control_workers: 1,
+            data_workers: 3,
+            control_rate_limit: 0,
+            data_rate_limit: 0,
+            workers: 4,
+            max_in_flight: 4,
+            max_queued: 64,
+            heartbeat_interval: 10,
+            idle_timeout: 60,
+        }
+    }
+}
+
+impl Default for EnclaveConfig {
+    fn default() -> Self {
+        EnclaveConfig {
+            files: vec!["enclave.signed.so".to_string()],
+            workers: 1,
+            threads: 4,
+            release: false,
+            response_padding: BTreeMap::new(),
+            match_rate_limit: 0,
+            replay_window: 300,
+            require_replay_protection: false,
+            max_clock_skew: 300,
+            require_registration: false,
+            health_authorities: Vec::new(),
+            declaration_max_age: 14 * 24 * 60 * 60,
+            manifest: None,
+            monotonic_counter: false,
+            compact_after: 64,
+            signing_curve: "secp256k1".to_string(),
+        }
+    }
+}
+
+impl Default for IasConfig {
+    fn default() -> Self {
+        let policy = RetryPolicy::default();
+        IasConfig {
+            url: ATTESTATION_SERVICE_URL.to_string(),
+            key_path: None,
+            key: None,
+            retries: policy.retries,
+            timeout: policy.timeout.as_secs(),
+            proxy: None,
+            ca_bundle: None,
+            tls_pins: Vec::new(),
+            root_ca: None,
+            retry_store: String::new(),
+            retry_interval: 60,
+            retry_retention: 24 * 60 * 60,
+            max_pending: 256,
+            archive: String::new(),
+            report_cache: String::new(),
+            cache_size: 1024,
+            reattest_interval: 0,
+            max_attestation_age: 0,
+            check_credentials: true,
+        }
+    }
+}
+
+impl Default for ExportConfig {
+    fn default() -> Self { ExportConfig { enabled: false, k_anonymity: 10 } }
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self { RetentionConfig { days: 0 } }
+}
+
+impl Default for MatchingConfig {
+    // The thresholds the enclave used before they were configurable, see `data::TOVERLAP` and `data::DISTANCE`
+    fn default() -> Self {
+        MatchingConfig { algorithm: "radius".to_string(), min_overlap: 300, distance: 10.0, cell: 0.0001, min_duration: 900 }
+    }
+}
+
+impl Default for StatisticsConfig {
+    fn default() -> Self { StatisticsConfig { enabled: false, epsilon: 1.0, geohash_precision: 5, min_count: 5 } }
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self { JobsConfig { step_size: 1000, concurrency: 1, max_queued: 64, retention: 600, events: String::new() } }
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self { LoggingConfig { level: "info".to_string() } }
+}
+
+impl Default for AdminConfig {
+    fn default() -> Self { AdminConfig { bind: String::new(), operators: Vec::new(), max_skew: 60, quorum: 1 } }
+}
+
+impl Default for AuditConfig {
+    fn default() -> Self { AuditConfig { path: "audit.log".to_string(), checkpoint_every: 100, checkpoint_interval: 3600 } }
+}
+
+impl Default for DeadLetterConfig {
+    fn default() -> Self { DeadLetterConfig { path: String::new(), max_file_size: 64 * 1024 * 1024, bind: String::new(), max_frame_size: 1024 * 1024 } }
+}
+
+impl Default for TelemetryConfig {
+    fn default() -> Self {
+        TelemetryConfig {
+            exporter: String::new(),
+            endpoint: "http://127.0.0.1:4317".to_string(),
+            service_name: "safetrace".to_string(),
+            sampling: 1.0,
+            interval: 10,
+            max_spans: 2048,
+            timeout: 10,
+        }
+    }
+}
+
+impl Default for LeaseConfig {
+    fn default() -> Self { LeaseConfig { interval: 0, duration: 30, file: String::new() } }
+}
+
+impl Default for PrecomputeConfig {
+    fn default() -> Self { PrecomputeConfig { enabled: false, step_size: 64, max_cpu: 25, interval: 60 } }
+}
+
+impl Default for RevocationConfig {
+    fn default() -> Self {
+        RevocationConfig {
+            mode: "soft".to_string(),
+            crl_url: "http://trustedservices.intel.com/content/CRL/SGX/AttestationReportSigningCA.crl".to_string(),
+            crl_cache: "ias_crl.der".to_string(),
+            crl_refresh: 24 * 60 * 60,
+            ocsp_url: String::new(),
+            timeout: 10,
+        }
+    }
+}
+
+impl DeadLetterConfig {
+    pub fn enabled(&self) -> bool {
+        !self.path.is_empty() || !self.bind.is_empty()
+    }
+}
+
+impl MatchingConfig {
+    pub fn strategy(&self) -> Result<MatchingStrategy, Error> {
+        Ok(match self.algorithm.as_str() {
+            "radius" => MatchingStrategy::Radius { min_overlap: self.min_overlap, distance: self.distance },
+            "grid" => MatchingStrategy::Grid { cell: self.cell, min_overlap: self.min_overlap },
+            "duration" => MatchingStrategy::Duration { distance: self.distance, min_duration: self.min_duration },
+            other => return Err(config_err(format!("matching.algorithm must be radius, grid or duration, not {}", other))),
+        })
+    }
+}
+
+impl QuantizationConfig {
+    // As announced by `GetProtocolVersion`, none when the locations are stored as sent.
+    pub fn active(&self) -> Option<Quantization> {
+        if self.grid == 0.0 && self.time_bucket == 0 {
+            return None;
+        }
+        Some(Quantization { grid: self.grid, time_bucket: self.time_bucket })
+    }
+}
+
+impl RetentionConfig {
+    pub fn seconds(&self) -> u64 {
+        self.days.saturating_mul(24 * 60 * 60)
+    }
+}
+
+impl TenantConfig {
+    // `None` keeps `retention.days`
+    pub fn retention_seconds(&self) -> Option<u64> {
+        self.retention_days.map(|days| days.saturating_mul(24 * 60 * 60))
+    }
+
+    pub fn operator_keys(&self) -> Result<Vec<[u8; 64]>, Error> {
+        parse_keys(&format!("tenants.{}.operators", self.id), &self.operators)
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        let valid_char = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+        if self.id.is_empty() || self.id.len() > 32 || !self.id.chars().all(valid_char) {
+            return Err(config_err(format!("tenants: {:?} must be 1 to 32 letters, digits, - or _", self.id)));
+        }
+        self.operator_keys()?;
+        Ok(())
+    }
+}
+
+fn config_err(message: String) -> Error {
+    ConfigErr { message }.into()
+}
+
+fn parse_var<T: FromStr>(name: &str, value: &str) -> Result<T, Error> {
+    value.trim().parse().map_err(|_| config_err(format!("{} has an invalid value: {}", name, value)))
+}
+
+fn parse_bool(value: &str) -> bool {
+    value != "0" && value != "false"
+}
+
+fn parse_list(value: &str) -> Vec<String> {
+    value.split(',').map(str::trim).filter(|item| !item.is_empty()).map(String::from).collect()
+}
+
+fn parse_keys(setting: &str, keys: &[String]) -> Result<Vec<[u8; 64]>, Error> {
+    keys.iter().map(|hex| {
+        let bytes: Vec<u8> = hex.from_hex()
+            .map_err(|_| config_err(format!("{}: {} isn't hex", setting, hex)))?;
+        if bytes.len() != 64 {
+            return Err(config_err(format!("{}: {} isn't a 64 bytes public key", setting, hex)));
+        }
+        let mut key = [0u8; 64];
+        key.copy_from_slice(&bytes);
+        Ok(key)
+    }).collect()
+}
+
+impl EnclaveConfig {
+    // The health authority keys, checked when the configuration is loaded.
+    pub fn health_authority_keys(&self) -> Result<Vec<[u8; 64]>, Error> {
+        parse_keys("enclave.health_authorities", &self.health_authorities)
+    }
+
+    pub fn curve(&self) -> Result<Curve, Error> {
+        Curve::parse(&self.signing_curve)
+            .ok_or_else(|| config_err(format!("enclave.signing_curve must be secp256k1 or p256, not {}", self.signing_curve)))
+    }
+
+    // The enclave file to start, see `esgx::general::active_enclave`.
+    pub fn active_file(&self) -> String {
+        crate::esgx::general::active_enclave(Path::new(crate::esgx::general::ACTIVE_FILE), &self.files)
+    }
+
+    // The manifest of `enclave.manifest`, read at startup.
+    pub fn load_manifest(&self) -> Result<Option<EnclaveManifest>, Error> {
+        let path = match &self.manifest {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        let json = fs::read_to_string(path).map_err(|e| config_err(format!("enclave.manifest {}: {}", path.display(), e)))?;
+        EnclaveManifest::from_json(&json).map(Some).map_err(|e| config_err(format!("enclave.manifest {}: {}", path.display(), e)))
+    }
+}
+
+impl AdminConfig {
+    pub fn enabled(&self) -> bool {
+        !self.bind.is_empty()
+    }
+
+    // The operator keys, checked when the configuration is loaded.
+    pub fn operator_keys(&self) -> Result<Vec<[u8; 64]>, Error> {
+        parse_keys("admin.operators", &self.operators)
+    }
+
+    fn validate(&self) -> Result<(), Error> {
+        self.operator_keys()?;
+        // Nobody could use it, and an empty list must never mean "anybody"
+        if self.enabled() && self.operators.is_empty() {
+            return Err(config_err("admin.bind is set but admin.operators is empty".to_string()));
+        }
+        // The enclave refuses payloads older than `quorum_u::ENCLAVE_MAX_SKEW`
+        if self.enabled() && (self.max_skew == 0 || self.max_skew > crate::quorum_u::ENCLAVE_MAX_SKEW) {
+            return Err(config_err("admin.max_skew must be between 1 and 300 seconds".to_string()));
+        }
+        if self.quorum == 0 || (self.enabled() && self.quorum as usize > self.operators.len()) {
+            return Err(config_err("admin.quorum must be between 1 and the number of admin.operators".to_string()));
+        }
+        Ok(())
+    }
+}
+
+impl TelemetryConfig {
+    fn validate(&self) -> Result<(), Error> {
+        if !["", "otlp", "log"].contains(&self.exporter.as_str()) {
+            return Err(config_err(format!("telemetry.exporter must be otlp, log or empty, not {}", self.exporter)));
+        }
+        if self.exporter == "otlp" && !self.endpoint.starts_with("http://") {
+            return Err(config_err("telemetry.endpoint must be an http:// URL, the export doesn't do TLS".to_string()));
+        }
+        if !self.sampling.is_finite() || self.sampling < 0.0 || self.sampling > 1.0 {
+            return Err(config_err("telemetry.sampling must be between 0 and 1".to_string()));
+        }
+        if self.interval == 0 || self.max_spans == 0 || self.timeout == 0 {
+            return Err(config_err("telemetry.interval, max_spans and timeout must be at least 1".to_string()));
+        }
+        Ok(())
+    }
+}
+
+impl RevocationConfig {
+    fn validate(&self) -> Result<(), Error> {
+        if !["off", "soft", "hard"].contains(&self.mode.as_str()) {
+            return Err(config_err(format!("revocation.mode must be off, soft or hard, not {}", self.mode)));
+        }
+        if self.mode != "off" && self.crl_url.is_empty() && self.ocsp_url.is_empty() {
+            return Err(config_err("revocation.crl_url or revocation.ocsp_url must be set, or revocation.mode off".to_string()));
+        }
+        if self.crl_refresh == 0 || self.timeout == 0 {
+            return Err(config_err("revocation.crl_refresh and timeout must be at least 1".to_string()));
+        }
+        Ok(())
+    }
+}
+
+impl Config {
+    // Loads `path`, or the file named by `SAFETRACE_CONFIG`, or `safetrace.toml` if there is one,
+    // then applies the environment overrides. Call `finish` once the logger is set up.
+    pub fn load(path: Option<&Path>) -> Result<Self, Error> {
+        let path = path.map(PathBuf::from).or_else(|| env::var("SAFETRACE_CONFIG").ok().map(PathBuf::from));
+        let mut config = match path {
+            Some(path) => Self::from_file(&path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => Self::from_file(Path::new(DEFAULT_CONFIG_FILE))?,
+            None => Config::default(),
+        };
+        config.apply_env(|name| env::var(name).ok())?;
+        Ok(config)
+    }
+
+    // `--standalone`, one container with its secrets mounted and nothing else: the secrets are read from
+    // `/run/secrets` unless another source is set, the self-test runs and the health probes are served on
+    // `STANDALONE_HEALTH_BIND` unless `server.health_bind` is set. Applied before `finish`.
+    pub fn standalone(&mut self) {
+        if self.secrets.dir.is_none() && self.secrets.vault.is_none() && Path::new(STANDALONE_SECRETS_DIR).is_dir() {
+            self.secrets.dir = Some(PathBuf::from(STANDALONE_SECRETS_DIR));
+        }
+        self.server.selftest = true;
+        if self.server.health_bind.is_empty() {
+            self.server.health_bind = STANDALONE_HEALTH_BIND.to_string();
+        }
+    }
+
+    // Fetches the secrets and checks the result.
+    pub fn finish(&mut self) -> Result<(), Error> {
+        // Child processes and crash reporters get a copy of the environment, don't leave the SPID there
+        if env::var_os("SAFETRACE_SPID").is_some() {
+            warn!(target: "security", "SAFETRACE_SPID is readable from the process environment, prefer SAFETRACE_SPID_FILE");
+            env::remove_var("SAFETRACE_SPID");
+        }
+        self.load_secrets()?;
+        self.validate()
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, Error> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| config_err(format!("unable to read {}: {}", path.display(), e)))?;
+        Self::from_toml(&content).map_err(|e| config_err(format!("{}: {}", path.display(), e)))
+    }
+
+    pub fn from_toml(content: &str) -> Result<Self, Error> {
+        Ok(toml::from_str(content)?)
+    }
+
+    // Environment variables win over the file, `var` looks them up.
+    pub fn apply_env<F: Fn(&str) -> Option<String>>(&mut self, var: F) -> Result<(), Error> {
+        if let Some(v) = var("SAFETRACE_SPID") { self.spid = Secret::from(v.trim()); }
+        if let Some(v) = var("SAFETRACE_SPID_FILE") { self.secrets.spid_file = Some(PathBuf::from(v)); }
+        if let Some(v) = var("SAFETRACE_SECRETS_DIR") { self.secrets.dir = Some(PathBuf::from(v)); }
+        if let Some(addr) = var("SAFETRACE_VAULT_ADDR") {
+            let current = self.secrets.vault.take();
+            self.secrets.vault = Some(VaultConfig {
+                addr,
+                path: var("SAFETRACE_VAULT_PATH").or_else(|| current.as_ref().map(|v| v.path.clone()))
+                    .ok_or_else(|| config_err("SAFETRACE_VAULT_ADDR needs SAFETRACE_VAULT_PATH".to_string()))?,
+                token_file: var("SAFETRACE_VAULT_TOKEN_FILE").map(PathBuf::from).or_else(|| current.map(|v| v.token_file))
+                    .ok_or_else(|| config_err("SAFETRACE_VAULT_ADDR needs SAFETRACE_VAULT_TOKEN_FILE".to_string()))?,
+            });
+        }
+        if let Some(v) = var("SAFETRACE_BIND") { self.server.bind = v.trim().to_string(); }
+        if let Some(v) = var("SAFETRACE_SOCKET_MODE") {
+            self.server.socket_mode = u32::from_str_radix(v.trim().trim_start_matches("0o"), 8)
+                .map_err(|_| config_err(format!("SAFETRACE_SOCKET_MODE has an invalid value: {}", v)))?;
+        }
+        if let Some(v) = var("SAFETRACE_TRANSPORT") { self.server.transport = v.trim().to_string(); }
+        if let Some(v) = var("SAFETRACE_TLS_CERT") { self.server.tls_cert = Some(PathBuf::from(v)); }
+        if let Some(v) = var("SAFETRACE_TLS_KEY") { self.server.tls_key = Some(PathBuf::from(v)); }
+        if let Some(v) = var("SAFETRACE_FRAME_KEY_PATH") { self.server.frame_key_path = Some(PathBuf::from(v)); }
+        if let Some(v) = var("SAFETRACE_PEERS") { self.server.peers = parse_list(&v); }
+        if let Some(v) = var("SAFETRACE_DISABLED_FEATURES") { self.server.disabled_features = parse_list(&v); }
+        if let Some(v) = var("SAFETRACE_REQUEST_TIMEOUT") { self.server.request_timeout = parse_var("SAFETRACE_REQUEST_TIMEOUT", &v)?; }
+        if let Some(v) = var("SAFETRACE_MAX_REQUEST_TIMEOUT") {
+            self.server.max_request_timeout = parse_var("SAFETRACE_MAX_REQUEST_TIMEOUT", &v)?;
+        }
+        if let Some(v) = var("SAFETRACE_MAX_FRAME_SIZE") { self.server.max_frame_size = parse_var("SAFETRACE_MAX_FRAME_SIZE", &v)?; }
+        if let Some(v) = var("SAFETRACE_SELFTEST") { self.server.selftest = parse_bool(&v); }
+        if let Some(v) = var("SAFETRACE_HEALTH_BIND") { self.server.health_bind = v.trim().to_string(); }
+        if let Some(v) = var("SAFETRACE_CONTROL_BIND") { self.server.control_bind = v.trim().to_string(); }
+        if let Some(v) = var("SAFETRACE_DATA_RATE_LIMIT") { self.server.data_rate_limit = parse_var("SAFETRACE_DATA_RATE_LIMIT", &v)?; }
+        if let Some(v) = var("SAFETRACE_ENCLAVE_FILES") { self.enclave.files = parse_list(&v); }
+        if let Some(v) = var("SAFETRACE_ENCLAVES") { self.enclave.workers = parse_var("SAFETRACE_ENCLAVES", &v)?; }
+        if let Some(v) = var("SAFETRACE_ENCLAVE_THREADS") { self.enclave.threads = parse_var("SAFETRACE_ENCLAVE_THREADS", &v)?; }
+        if let Some(v) = var("SAFETRACE_ENCLAVE_RELEASE") { self.enclave.release = parse_bool(&v); }
+        if let Some(v) = var("SAFETRACE_RESPONSE_PADDING") {
+            self.enclave.response_padding = crate::padding_u::parse_config(&v).map_err(config_err)?.into_iter()
+                .map(|(class, bucket)| (class.name().to_string(), bucket)).collect();
+        }
+        if let Some(v) = var("SAFETRACE_MATCH_RATE_LIMIT") { self.enclave.match_rate_limit = parse_var("SAFETRACE_MATCH_RATE_LIMIT", &v)?; }
+        if let Some(v) = var("SAFETRACE_REPLAY_WINDOW") { self.enclave.replay_window = parse_var("SAFETRACE_REPLAY_WINDOW", &v)?; }
+        if let Some(v) = var("SAFETRACE_REQUIRE_REPLAY_PROTECTION") { self.enclave.require_replay_protection = parse_bool(&v); }
+        if let Some(v) = var("SAFETRACE_MAX_CLOCK_SKEW") { self.enclave.max_clock_skew = parse_var("SAFETRACE_MAX_CLOCK_SKEW", &v)?; }
+        if let Some(v) = var("SAFETRACE_REQUIRE_REGISTRATION") { self.enclave.require_registration = parse_bool(&v); }
+        if let Some(v) = var("SAFETRACE_MONOTONIC_COUNTER") { self.enclave.monotonic_counter = parse_bool(&v); }
+        if let Some(v) = var("SAFETRACE_COMPACT_AFTER") { self.enclave.compact_after = parse_var("SAFETRACE_COMPACT_AFTER", &v)?; }
+        if let Some(v) = var("SAFETRACE_HEALTH_AUTHORITIES") { self.enclave.health_authorities = parse_list(&v); }
+        if let Some(v) = var("SAFETRACE_DECLARATION_MAX_AGE") { self.enclave.declaration_max_age = parse_var("SAFETRACE_DECLARATION_MAX_AGE", &v)?; }
+        if let Some(v) = var("SAFETRACE_ENCLAVE_MANIFEST") { self.enclave.manifest = Some(PathBuf::from(v)); }
+        if let Some(v) = var("SAFETRACE_ENCLAVE_SIGNING_CURVE") { self.enclave.signing_curve = v.trim().to_string(); }
+        if let Some(v) = var("SAFETRACE_IAS_URL") { self.ias.url = v.trim().to_string(); }
+        if let Some(v) = var("SAFETRACE_IAS_KEY_PATH") { self.ias.key_path = Some(PathBuf::from(v)); }
+        if let Some(v) = var("SAFETRACE_IAS_RETRIES") { self.ias.retries = parse_var("SAFETRACE_IAS_RETRIES", &v)?; }
+        if let Some(v) = var("SAFETRACE_IAS_TIMEOUT") { self.ias.timeout = parse_var("SAFETRACE_IAS_TIMEOUT", &v)?; }
+        if let Some(v) = var("SAFETRACE_IAS_PROXY") { self.ias.proxy = Some(v); }
+        if let Some(v) = var("SAFETRACE_IAS_CA_BUNDLE") { self.ias.ca_bundle = Some(PathBuf::from(v)); }
+        if let Some(v) = var("SAFETRACE_IAS_TLS_PINS") { self.ias.tls_pins = parse_list(&v); }
+        if let Some(v) = var("SAFETRACE_IAS_ROOT_CA") { self.ias.root_ca = Some(PathBuf::from(v)); }
+        if let Some(v) = var("SAFETRACE_IAS_RETRY_STORE") { self.ias.retry_store = v.trim().to_string(); }
+        if let Some(v) = var("SAFETRACE_IAS_RETRY_INTERVAL") { self.ias.retry_interval = parse_var("SAFETRACE_IAS_RETRY_INTERVAL", &v)?; }
+        if let Some(v) = var("SAFETRACE_IAS_RETRY_RETENTION") { self.ias.retry_retention = parse_var("SAFETRACE_IAS_RETRY_RETENTION", &v)?; }
+        if let Some(v) = var("SAFETRACE_IAS_MAX_PENDING") { self.ias.max_pending = parse_var("SAFETRACE_IAS_MAX_PENDING", &v)?; }
+        if let Some(v) = var("SAFETRACE_IAS_ARCHIVE") { self.ias.archive = v.trim().to_string(); }
+        if let Some(v) = var("SAFETRACE_IAS_REPORT_CACHE") { self.ias.report_cache = v.trim().to_string(); }
+        if let Some(v) = var("SAFETRACE_IAS_CACHE_SIZE") { self.ias.cache_size = parse_var("SAFETRACE_IAS_CACHE_SIZE", &v)?; }
+        if let Some(v) = var("SAFETRACE_IAS_REATTEST_INTERVAL") { self.ias.reattest_interval = parse_var("SAFETRACE_IAS_REATTEST_INTERVAL", &v)?; }
+        if let Some(v) = var("SAFETRACE_IAS_MAX_ATTESTATION_AGE") { self.ias.max_attestation_age = parse_var("SAFETRACE_IAS_MAX_ATTESTATION_AGE", &v)?; }
+        if let Some(v) = var("SAFETRACE_IAS_CHECK_CREDENTIALS") { self.ias.check_credentials = parse_bool(&v); }
+        if let Some(v) = var("SAFETRACE_TENANTS") {
+            self.tenants = parse_list(&v).into_iter().map(|id| TenantConfig { id, ..TenantConfig::default() }).collect();
+        }
+        if let Some(v) = var("SAFETRACE_RETENTION_DAYS") { self.retention.days = parse_var("SAFETRACE_RETENTION_DAYS", &v)?; }
+        if let Some(v) = var("SAFETRACE_CAPACITY_SOFT_RECORDS") { self.capacity.soft_records = parse_var("SAFETRACE_CAPACITY_SOFT_RECORDS", &v)?; }
+        if let Some(v) = var("SAFETRACE_CAPACITY_HARD_RECORDS") { self.capacity.hard_records = parse_var("SAFETRACE_CAPACITY_HARD_RECORDS", &v)?; }
+        if let Some(v) = var("SAFETRACE_MATCH_MIN_OVERLAP") { self.matching.min_overlap = parse_var("SAFETRACE_MATCH_MIN_OVERLAP", &v)?; }
+        if let Some(v) = var("SAFETRACE_MATCH_DISTANCE") { self.matching.distance = parse_var("SAFETRACE_MATCH_DISTANCE", &v)?; }
+        if let Some(v) = var("SAFETRACE_MATCH_ALGORITHM") { self.matching.algorithm = v.trim().to_string(); }
+        if let Some(v) = var("SAFETRACE_MATCH_CELL") { self.matching.cell = parse_var("SAFETRACE_MATCH_CELL", &v)?; }
+        if let Some(v) = var("SAFETRACE_MATCH_MIN_DURATION") { self.matching.min_duration = parse_var("SAFETRACE_MATCH_MIN_DURATION", &v)?; }
+        if let Some(v) = var("SAFETRACE_QUANTIZATION_GRID") { self.quantization.grid = parse_var("SAFETRACE_QUANTIZATION_GRID", &v)?; }
+        if let Some(v) = var("SAFETRACE_QUANTIZATION_TIME_BUCKET") { self.quantization.time_bucket = parse_var("SAFETRACE_QUANTIZATION_TIME_BUCKET", &v)?; }
+        if let Some(v) = var("SAFETRACE_STATISTICS") { self.statistics.enabled = parse_bool(&v); }
+        if let Some(v) = var("SAFETRACE_STATISTICS_EPSILON") { self.statistics.epsilon = parse_var("SAFETRACE_STATISTICS_EPSILON", &v)?; }
+        if let Some(v) = var("SAFETRACE_STATISTICS_PRECISION") { self.statistics.geohash_precision = parse_var("SAFETRACE_STATISTICS_PRECISION", &v)?; }
+        if let Some(v) = var("SAFETRACE_STATISTICS_MIN_COUNT") { self.statistics.min_count = parse_var("SAFETRACE_STATISTICS_MIN_COUNT", &v)?; }
+        if let Some(v) = var("SAFETRACE_EXPORT") { self.export.enabled = parse_bool(&v); }
+        if let Some(v) = var("SAFETRACE_EXPORT_K") { self.export.k_anonymity = parse_var("SAFETRACE_EXPORT_K", &v)?; }
+        if let Some(v) = var("SAFETRACE_JOBS_STEP_SIZE") { self.jobs.step_size = parse_var("SAFETRACE_JOBS_STEP_SIZE", &v)?; }
+        if let Some(v) = var("SAFETRACE_JOBS_CONCURRENCY") { self.jobs.concurrency = parse_var("SAFETRACE_JOBS_CONCURRENCY", &v)?; }
+        if let Some(v) = var("SAFETRACE_JOBS_MAX_QUEUED") { self.jobs.max_queued = parse_var("SAFETRACE_JOBS_MAX_QUEUED", &v)?; }
+        if let Some(v) = var("SAFETRACE_JOBS_RETENTION") { self.jobs.retention = parse_var("SAFETRACE_JOBS_RETENTION", &v)?; }
+        if let Some(v) = var("SAFETRACE_JOBS_EVENTS") { self.jobs.events = v.trim().to_string(); }
+        if let Some(v) = var("SAFETRACE_LOG") { self.logging.level = v.trim().to_string(); }
+        if let Some(v) = var("SAFETRACE_ADMIN_BIND") { self.admin.bind = v.trim().to_string(); }
+        if let Some(v) = var("SAFETRACE_ADMIN_OPERATORS") { self.admin.operators = parse_list(&v); }
+        if let Some(v) = var("SAFETRACE_ADMIN_MAX_SKEW") { self.admin.max_skew = parse_var("SAFETRACE_ADMIN_MAX_SKEW", &v)?; }
+        if let Some(v) = var("SAFETRACE_ADMIN_QUORUM") { self.admin.quorum = parse_var("SAFETRACE_ADMIN_QUORUM", &v)?; }
+        if let Some(v) = var("SAFETRACE_AUDIT_PATH") { self.audit.path = v.trim().to_string(); }
+        if let Some(v) = var("SAFETRACE_AUDIT_CHECKPOINT_EVERY") { self.audit.checkpoint_every = parse_var("SAFETRACE_AUDIT_CHECKPOINT_EVERY", &v)?; }
+        if let Some(v) = var("SAFETRACE_AUDIT_CHECKPOINT_INTERVAL") { self.audit.checkpoint_interval = parse_var("SAFETRACE_AUDIT_CHECKPOINT_INTERVAL", &v)?; }
+        if let Some(v) = var("SAFETRACE_DEAD_LETTERS_PATH") { self.dead_letters.path = v.trim().to_string(); }
+        if let Some(v) = var("SAFETRACE_DEAD_LETTERS_MAX_FILE_SIZE") { self.dead_letters.max_file_size = parse_var("SAFETRACE_DEAD_LETTERS_MAX_FILE_SIZE", &v)?; }
+        if let Some(v) = var("SAFETRACE_DEAD_LETTERS_BIND") { self.dead_letters.bind = v.trim().to_string(); }
+        if let Some(v) = var("SAFETRACE_DEAD_LETTERS_MAX_FRAME_SIZE") { self.dead_letters.max_frame_size = parse_var("SAFETRACE_DEAD_LETTERS_MAX_FRAME_SIZE", &v)?; }
+        if let Some(v) = var("SAFETRACE_TELEMETRY_EXPORTER") { self.telemetry.exporter = v.trim().to_string(); }
+        // The variables of the OpenTelemetry SDKs, ours win
+        if let Some(v) = var("SAFETRACE_OTLP_ENDPOINT").or_else(|| var("OTEL_EXPORTER_OTLP_ENDPOINT")) { self.telemetry.endpoint = v.trim().to_string(); }
+        if let Some(v) = var("OTEL_SERVICE_NAME") { self.telemetry.service_name = v.trim().to_string(); }
+        if let Some(v) = var("SAFETRACE_TRACE_SAMPLING") { self.telemetry.sampling = parse_var("SAFETRACE_TRACE_SAMPLING", &v)?; }
+        if let Some(v) = var("SAFETRACE_LEASE_INTERVAL") { self.lease.interval = parse_var("SAFETRACE_LEASE_INTERVAL", &v)?; }
+        if let Some(v) = var("SAFETRACE_READINESS_FILE") { self.lease.file = v.trim().to_string(); }
+        if let Some(v) = var("SAFETRACE_PRECOMPUTE") { self.precompute.enabled = parse_bool(&v); }
+        if let Some(v) = var("SAFETRACE_PRECOMPUTE_MAX_CPU") { self.precompute.max_cpu = parse_var("SAFETRACE_PRECOMPUTE_MAX_CPU", &v)?; }
+        if let Some(v) = var("SAFETRACE_REVOCATION_MODE") { self.revocation.mode = v.trim().to_string(); }
+        if let Some(v) = var("SAFETRACE_CRL_URL") { self.revocation.crl_url = v.trim().to_string(); }
+        if let Some(v) = var("SAFETRACE_OCSP_URL") { self.revocation.ocsp_url = v.trim().to_string(); }
+        Ok(())
+    }
+
+    fn secret_providers(&self) -> Result<Vec<Box<dyn SecretProvider>>, Error> {
+        let mut providers: Vec<Box<dyn SecretProvider>> = Vec::new();
+        if let Some(ref vault) = self.secrets.vault {
+            providers.push(Box::new(VaultSecrets::new(&vault.addr, &vault.path, &vault.token_file)?));
+        }
+        if let Some(ref dir) = self.secrets.dir {
+            providers.push(Box::new(FileSecrets::new(dir.clone())));
+        }
+        Ok(providers)
+    }
+
+    fn find_secret(providers: &[Box<dyn SecretProvider>], file: Option<&PathBuf>, name: &str) -> Result<Option<Secret>, Error> {
+        if let Some(path) = file {
+            return secrets::read_secret_file(path).map(Some);
+        }
+        for provider in providers {
+            if let Some(secret) = provider.get(name)? {
+                info!("Read the {} secret from {}", name, provider.name());
+                return Ok(Some(secret));
+            }
+        }
+        Ok(None)
+    }
+
+    // Replaces the inlined credentials with the ones of the secret files or providers, if any.
+    pub fn load_secrets(&mut self) -> Result<(), Error> {
+        let providers = self.secret_providers()?;
+        if let Some(spid) = Self::find_secret(&providers, self.secrets.spid_file.as_ref(), "spid")? {
+            self.spid = spid;
+        }
+        self.ias.key = Self::find_secret(&providers, self.ias.key_path.as_ref(), "ias_key")?;
+        self.server.frame_key = Self::find_secret(&providers, self.server.frame_key_path.as_ref(), "frame_key")?;
+        Ok(())
+    }
+
+    // Reports every mistake at startup rather than when the setting is first used.
+    pub fn validate(&self) -> Result<(), Error> {
+        let spid = self.spid.expose();
+        if spid.len() != 32 || !spid.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(config_err("spid must be 32 hex characters".to_string()));
+        }
+        if self.enclave.files.is_empty() || self.enclave.files.iter().any(String::is_empty) {
+            return Err(config_err("enclave.files must list at least one enclave file".to_string()));
+        }
+        if self.enclave.files.iter().enumerate().any(|(i, file)| self.enclave.files[..i].contains(file)) {
+            return Err(config_err("enclave.files lists a file twice".to_string()));
+        }
+        if self.enclave.workers == 0 {
+            return Err(config_err("enclave.workers must be at least 1".to_string()));
+        }
+        if self.enclave.threads == 0 || self.enclave.threads > MAX_THREADS {
+            return Err(config_err(format!("enclave.threads must be between 1 and {}, the TCS the enclave has", MAX_THREADS)));
+        }
+        if self.server.max_request_timeout > 0 && self.server.request_timeout > self.server.max_request_timeout {
+            return Err(config_err("server.request_timeout can't be above server.max_request_timeout".to_string()));
+        }
+        if !self.server.health_bind.is_empty() && self.server.health_bind.parse::<SocketAddr>().is_err() {
+            return Err(config_err("server.health_bind must be an address and a port, e.g. 0.0.0.0:8080".to_string()));
+        }
+        match self.server.transport.as_str() {
+            "zmq" => {},
+            "tls" => {
+                let control = &self.server.control_bind;
+                if self.server.bind.parse::<SocketAddr>().is_err() || (!control.is_empty() && control.parse::<SocketAddr>().is_err()) {
+                    return Err(config_err("server.bind and server.control_bind must be an address and a port with the tls transport, e.g. 0.0.0.0:5552".to_string()));
+                }
+                if self.server.tls_cert.is_none() || self.server.tls_key.is_none() {
+                    return Err(config_err("the tls transport needs server.tls_cert and server.tls_key".to_string()));
+                }
+            },
+            other => return Err(config_err(format!("server.transport must be zmq or tls, not {}", other))),
+        }
+        if let Some(key) = &self.server.frame_key {
+            FrameKey::from_hex(key.expose()).map_err(|_| {
+                config_err(format!("the frame key must be at least {} bytes of hex", tags::MIN_KEY_SIZE))
+            })?;
+        }
+        let unix = |bind: &str| bind.starts_with(UNIX_SCHEME);
+        if [&self.server.bind, &self.server.control_bind].iter().any(|bind| unix(bind) && !bind[UNIX_SCHEME.len()..].starts_with('/')) {
+            return Err(config_err("a unix:// socket needs an absolute path, e.g. unix:///run/safetrace/ipc.sock".to_string()));
+        }
+        // Anybody on the host could connect, as they could over TCP loopback
+        if self.server.socket_mode & !0o770 != 0 {
+            return Err(config_err(format!("server.socket_mode {:o} can only give access to the owner and the group", self.server.socket_mode)));
+        }
+        if !self.server.control_bind.is_empty() {
+            if self.server.control_bind == self.server.bind || self.server.control_bind == self.admin.bind {
+                return Err(config_err("server.control_bind must be a socket of its own".to_string()));
+            }
+            // The uploads and queries can't take every enclave thread
+            if self.server.control_workers == 0 || self.server.data_workers == 0 || self.server.data_workers >= self.enclave.threads {
+                return Err(config_err("server.control_workers must be at least 1 and server.data_workers between 1 and enclave.threads - 1".to_string()));
+            }
+        }
+        if self.server.workers == 0 || self.server.max_in_flight == 0 {
+            return Err(config_err("server.workers and server.max_in_flight must be at least 1".to_string()));
+        }
+        // ZMQ takes the time to live in tenths of a second, up to 6553.5 seconds
+        if self.server.heartbeat_interval > 0 && (self.server.idle_timeout <= self.server.heartbeat_interval || self.server.idle_timeout > 6553) {
+            return Err(config_err("server.idle_timeout must be above server.heartbeat_interval and at most 6553 seconds".to_string()));
+        }
+        if self.server.max_frame_size < MIN_FRAME_SIZE {
+            return Err(config_err(format!("server.max_frame_size must be at least {} bytes, the largest valid requests take that", MIN_FRAME_SIZE)));
+        }
+        if self.ias.retry_interval == 0 || self.ias.max_pending == 0 {
+            return Err(config_err("ias.retry_interval and ias.max_pending must be at least 1".to_string()));
+        }
+        if !self.ias.report_cache.is_empty() && self.ias.cache_size == 0 {
+            return Err(config_err("ias.cache_size must be at least 1 with ias.report_cache".to_string()));
+        }
+        // Nothing would keep the attestation fresh, or it would go stale between two attestations
+        if self.ias.max_attestation_age > 0 && self.ias.max_attestation_age <= self.ias.reattest_interval {
+            return Err(config_err("ias.max_attestation_age must be above ias.reattest_interval, and needs it".to_string()));
+        }
+        self.report_root_ca()?;
+        if self.enclave.replay_window < 60 {
+            return Err(config_err("enclave.replay_window must be at least 60 seconds".to_string()));
+        }
+        if self.enclave.max_clock_skew > 24 * 60 * 60 {
+            return Err(config_err("enclave.max_clock_skew can't be more than a day".to_string()));
+        }
+        if self.enclave.declaration_max_age == 0 {
+            return Err(config_err("enclave.declaration_max_age must be at least 1 second".to_string()));
+        }
+        if self.capacity.hard_records > 0 && self.capacity.soft_records > self.capacity.hard_records {
+            return Err(config_err("capacity.soft_records can't be above capacity.hard_records".to_string()));
+        }
+        if self.matching.min_overlap < 0 || !self.matching.distance.is_finite() || self.matching.distance <= 0.0 {
+            return Err(config_err("matching.min_overlap can't be negative and matching.distance must be positive".to_string()));
+        }
+        if let Err(message) = self.matching.strategy()?.check() {
+            return Err(config_err(format!("matching.{}: {}", self.matching.algorithm, message)));
+        }
+        self.enclave.health_authority_keys()?;
+        self.enclave.curve()?;
+        // Beyond a degree (111km) nothing would match anymore
+        if !self.quantization.grid.is_finite() || self.quantization.grid < 0.0 || self.quantization.grid > 1.0 {
+            return Err(config_err("quantization.grid must be between 0 and 1 degree".to_string()));
+        }
+        if self.quantization.time_bucket > i32::max_value() as u64 {
+            return Err(config_err("quantization.time_bucket is too large".to_string()));
+        }
+        if !self.statistics.epsilon.is_finite() || self.statistics.epsilon <= 0.0 || self.statistics.epsilon > 10.0 {
+            return Err(config_err("statistics.epsilon must be positive and at most 10".to_string()));
+        }
+        if self.statistics.geohash_precision == 0 || self.statistics.geohash_precision > 12 {
+            return Err(config_err("statistics.geohash_precision must be between 1 and 12".to_string()));
+        }
+        if self.export.enabled && (self.export.k_anonymity < 2 || self.enclave.health_authorities.is_empty()) {
+            return Err(config_err("export needs export.k_anonymity of at least 2 and enclave.health_authorities".to_string()));
+        }
+        if self.jobs.step_size == 0 || self.jobs.max_queued == 0 {
+            return Err(config_err("jobs.step_size and jobs.max_queued must be at least 1".to_string()));
+        }
+        // The IPC requests need a thread too
+        if self.jobs.concurrency == 0 || self.jobs.concurrency >= self.enclave.threads.max(2) {
+            return Err(config_err("jobs.concurrency must be at least 1 and less than enclave.threads, unless both are 1".to_string()));
+        }
+        self.response_padding()?;
+        self.switches()?;
+        crate::logging::check_filters(&self.logging.level).map_err(config_err)?;
+        self.admin.validate()?;
+        for (i, tenant) in self.tenants.iter().enumerate() {
+            tenant.validate()?;
+            if self.tenants[..i].iter().any(|other| other.id == tenant.id) {
+                return Err(config_err(format!("tenants lists {} twice", tenant.id)));
+            }
+        }
+        // The enclave seals them all, see `quorum_u`
+        if self.admin.operators.len() + self.tenants.iter().map(|tenant| tenant.operators.len()).sum::<usize>() > crate::quorum_u::MAX_OPERATORS {
+            return Err(config_err("at most 16 operators, those of admin.operators and of the tenants together".to_string()));
+        }
+        // The enclave signs at most `audit::MAX_DIGESTS` entries at once
+        if self.audit.checkpoint_every == 0 || self.audit.checkpoint_every > 100_000 {
+            return Err(config_err("audit.checkpoint_every must be between 1 and 100000".to_string()));
+        }
+        if self.dead_letters.max_file_size == 0 || self.dead_letters.max_frame_size == 0 {
+            return Err(config_err("dead_letters.max_file_size and max_frame_size must be at least 1".to_string()));
+        }
+        if !self.dead_letters.bind.is_empty() && (self.dead_letters.bind == self.server.bind || self.dead_letters.bind == self.admin.bind
+                                                  || self.dead_letters.bind == self.server.control_bind) {
+            return Err(config_err("dead_letters.bind must be a socket of its own".to_string()));
+        }
+        self.telemetry.validate()?;
+        self.revocation.validate()?;
+        if self.lease.interval > 0 && self.lease.duration <= self.lease.interval {
+            return Err(config_err("lease.duration must be above lease.interval, or the lease lapses between two heartbeats".to_string()));
+        }
+        if self.precompute.step_size == 0 || self.precompute.max_cpu == 0 || self.precompute.max_cpu > 100 {
+            return Err(config_err("precompute.step_size must be at least 1 and precompute.max_cpu between 1 and 100".to_string()));
+        }
+        Ok(())
+    }
+
+    pub fn response_padding(&self) -> Result<Vec<(PaddingClass, u64)>, Error> {
+        self.enclave.response_padding.iter()
+            .map(|(class, &bucket)| Ok((class.parse().map_err(config_err)?, bucket)))
+            .collect()
+    }
+
+    pub fn switches(&self) -> Result<KillSwitches, Error> {
+        KillSwitches::from_config(&self.server.disabled_features.join(",")).map_err(config_err)
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy { retries: self.ias.retries, timeout: Duration::from_secs(self.ias.timeout), ..RetryPolicy::default() }
+    }
+
+    pub fn timeouts(&self) -> Timeouts {
+        Timeouts { default: self.server.request_timeout, max: self.server.max_request_timeout }
+    }
+
+    // The PEM of `ias.root_ca`, or the pinned Intel root.
+    pub fn report_root_ca(&self) -> Result<String, Error> {
+        let path = match &self.ias.root_ca {
+            Some(path) => path,
+            None => return Ok(verify::INTEL_ROOT_CA.to_string()),
+        };
+        let pem = fs::read_to_string(path).map_err(|e| config_err(format!("ias.root_ca {}: {}", path.display(), e)))?;
+        match safetrace_client::bundle::pem_to_der(&pem) {
+            Ok(ref certificates) if certificates.len() == 1 => Ok(pem),
+            _ => Err(config_err(format!("ias.root_ca {} must hold a single PEM certificate", path.display()))),
+        }
+    }
+
+    pub fn tls_options(&self) -> TlsOptions {
+        TlsOptions { proxy: self.ias.proxy.clone(), ca_bundle: self.ias.ca_bundle.clone(), pins: self.ias.tls_pins.clone() }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Config, Curve};
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_example_config() {
+        let config = Config::from_toml(include_str!("../safetrace.example.toml")).unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.server.bind, "tcp://*:5552");
+        assert_eq!(config.retention.seconds(), 14 * 24 * 60 * 60);
+        assert_eq!(config.response_padding().unwrap().len(), 2);
+        assert_eq!(Config::from_toml("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn test_env_overrides() {
+        let mut config = Config::from_toml("[enclave]\nworkers = 2\n[matching]\ndistance = 25.0\n").unwrap();
+        let env: HashMap<&str, &str> = vec![
+            ("SAFETRACE_ENCLAVES", "4"),
+            ("SAFETRACE_PEERS", "tcp://10.0.0.2:5552, tcp://10.0.0.3:5552"),
+            ("SAFETRACE_RESPONSE_PADDING", "matching=1024"),
+        ].into_iter().collect();
+        config.apply_env(|name| env.get(name).map(|v| v.to_string())).unwrap();
+        assert_eq!(config.enclave.workers, 4);
+        assert_eq!(config.server.peers, vec!["tcp://10.0.0.2:5552", "tcp://10.0.0.3:5552"]);
+        assert_eq!(config.enclave.response_padding.get("matching"), Some(&1024));
+        assert_eq!(config.matching.distance, 25.0);
+        config.apply_env(|name| if name == "SAFETRACE_MATCH_ALGORITHM" { Some("duration".to_string()) } else { None }).unwrap();
+        assert_eq!(config.matching.strategy().unwrap(), MatchingStrategy::Duration { distance: 25.0, min_duration: 900 });
+
+        assert!(config.apply_env(|name| if name == "SAFETRACE_IAS_RETRIES" { Some("many".to_string()) } else { None }).is_err());
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(Config::from_toml("[server]\nbnid = \"tcp://*:5552\"\n").is_err());
+        assert!(Config::from_toml("spid = \"not hex\"\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[server]\ndisabled_features = [\"nope\"]\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[capacity]\nsoft_records = 2000\nhard_records = 1000\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[matching]\ndistance = -1.0\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[matching]\nalgorithm = \"nearest\"\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[matching]\nalgorithm = \"grid\"\ncell = 0.0\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[matching]\nalgorithm = \"duration\"\nmin_duration = 0\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[enclave]\nreplay_window = 0\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[enclave]\nreplay_window = 59\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[enclave]\nmax_clock_skew = 90000\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[enclave]\nhealth_authorities = [\"abcd\"]\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[enclave]\ndeclaration_max_age = 0\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[enclave]\nsigning_curve = \"secp256r1\"\n").unwrap().validate().is_err());
+        assert_eq!(Config::from_toml("[enclave]\nsigning_curve = \"p256\"\n").unwrap().enclave.curve().unwrap(), Curve::P256);
+        assert!(Config::from_toml("[statistics]\nepsilon = 0.0\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[statistics]\nepsilon = 10.5\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[quantization]\ngrid = -0.001\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[export]\nenabled = true\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[jobs]\nstep_size = 0\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[enclave]\nthreads = 9\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[enclave]\nthreads = 2\n[jobs]\nconcurrency = 2\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[logging]\nlevel = \"security=loud\"\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[audit]\ncheckpoint_every = 0\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[enclave]\nfiles = []\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[dead_letters]\nmax_frame_size = 0\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[dead_letters]\nbind = \"tcp://*:5552\"\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[enclave]\nfiles = [\"a.signed.so\", \"a.signed.so\"]\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[server]\nrequest_timeout = 60000\nmax_request_timeout = 10000\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[ias]\nretry_interval = 0\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[ias]\nroot_ca = \"/nonexistent/root.pem\"\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[ias]\nmax_attestation_age = 3600\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[ias]\nreport_cache = \"reports.json\"\ncache_size = 0\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[ias]\ncache_size = 0\n").unwrap().validate().is_ok());
+        assert!(Config::from_toml("[ias]\nreattest_interval = 3600\nmax_attestation_age = 3600\n").unwrap().validate().is_err());
+        assert!(Config::default().ias.check_credentials);
+        assert!(!Config::from_toml("[ias]\ncheck_credentials = false\n").unwrap().ias.check_credentials);
+        assert!(Config::from_toml("[server]\nmax_frame_size = 4096\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[server]\nhealth_bind = \"localhost\"\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[[tenants]]\nid = \"ch ge\"\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[[tenants]]\nid = \"ch-ge\"\n[[tenants]]\nid = \"ch-ge\"\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[[tenants]]\nid = \"ch-ge\"\noperators = [\"abcd\"]\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[telemetry]\nexporter = \"jaeger\"\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[telemetry]\nexporter = \"otlp\"\nendpoint = \"https://otel.example.org:4317\"\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[telemetry]\nsampling = 1.5\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[telemetry]\ninterval = 0\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[revocation]\nmode = \"strict\"\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[lease]\ninterval = 30\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[lease]\ninterval = 10\n").unwrap().validate().is_ok());
+        assert!(Config::from_toml("[precompute]\nmax_cpu = 0\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[server]\ncontrol_bind = \"tcp://*:5552\"\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[server]\ncontrol_bind = \"tcp://*:5551\"\ndata_workers = 4\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[server]\ncontrol_bind = \"tcp://*:5551\"\n").unwrap().validate().is_ok());
+        assert!(Config::from_toml("[server]\nmax_in_flight = 0\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[server]\nheartbeat_interval = 60\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[server]\nheartbeat_interval = 0\nidle_timeout = 0\n").unwrap().validate().is_ok());
+        assert!(Config::from_toml("[server]\ntransport = \"tcp\"\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[server]\nbind = \"unix://safetrace.sock\"\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[server]\nbind = \"unix:///run/safetrace.sock\"\nsocket_mode = 0o666\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[server]\nbind = \"unix:///run/safetrace.sock\"\nsocket_mode = 0o600\n").unwrap().validate().is_ok());
+        assert!(Config::from_toml("[server]\ntransport = \"tls\"\nbind = \"0.0.0.0:5552\"\n").unwrap().validate().is_err());
+        let tls = "[server]\ntransport = \"tls\"\ntls_cert = \"cert.pem\"\ntls_key = \"key.pem\"\n";
+        assert!(Config::from_toml(tls).unwrap().validate().is_err());
+        assert!(Config::from_toml(&format!("{}bind = \"0.0.0.0:5552\"\n", tls)).unwrap().validate().is_ok());
+        assert!(Config::from_toml("[precompute]\nmax_cpu = 150\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[revocation]\ncrl_url = \"\"\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[revocation]\nmode = \"off\"\ncrl_url = \"\"\n").unwrap().validate().is_ok());
+    }
+
+    #[test]
+    fn test_admin_config() {
+        let operator = "ab".repeat(64);
+        let config = Config::from_toml(&format!("[admin]\nbind = \"tcp://127.0.0.1:5553\"\noperators = [\"{}\"]\n", operator)).unwrap();
+        config.validate().unwrap();
+        let keys = config.admin.operator_keys().unwrap();
+        assert!(keys.len() == 1 && keys[0][..] == [0xab; 64][..]);
+
+        // Without operators nobody could use it
+        assert!(Config::from_toml("[admin]\nbind = \"tcp://127.0.0.1:5553\"\n").unwrap().validate().is_err());
+        // A quorum the operators can't reach
+        let unreachable = format!("[admin]\nbind = \"tcp://127.0.0.1:5553\"\noperators = [\"{}\"]\nquorum = 2\n", operator);
+        assert!(Config::from_toml(&unreachable).unwrap().validate().is_err());
+        assert!(Config::from_toml("[admin]\nquorum = 0\n").unwrap().validate().is_err());
+        assert!(Config::from_toml("[admin]\noperators = [\"abcd\"]\n").unwrap().validate().is_err());
+    }
+
+    #[test]
+    fn test_tenant_config() {
+        let config = Config::from_toml("[[tenants]]\nid = \"ch-ge\"\nretention_days = 21\n[[tenants]]\nid = \"ch-vd\"\n").unwrap();
+        config.validate().unwrap();
+        assert_eq!(config.tenants.len(), 2);
+        assert_eq!(config.tenants[0].retention_seconds(), Some(21 * 24 * 60 * 60));
+        assert_eq!(config.tenants[1].retention_seconds(), None);
+
+        let mut config = config;
+        config.apply_env(|name| if name == "SAFETRACE_TENANTS" { Some("fr-idf, be-bru".to_string()) } else { None }).unwrap();
+        let ids: Vec<&str> = config.tenants.iter().map(|tenant| tenant.id.as_str()).collect();
+        assert_eq!(ids, vec!["fr-idf", "be-bru"]);
+    }
+
+    #[test]
+    fn test_enclave_manifest() {
+        assert_eq!(Config::default().enclave.load_manifest().unwrap(), None);
+        let path = std::env::temp_dir().join(format!("safetrace-manifest-{}.json", std::process::id()));
+        let config = Config::from_toml(&format!("[enclave]\nmanifest = {:?}\n", path)).unwrap();
+        assert!(config.enclave.load_manifest().is_err());
+
+        let manifest = format!(r#"{{"version": 1, "mrEnclave": "{}", "mrSigner": "{}", "isvProdId": 0, "isvSvn": 1, "debug": false}}"#, "ab".repeat(32), "cd".repeat(32));
+        std::fs::write(&path, manifest).unwrap();
+        let manifest = config.enclave.load_manifest().unwrap().unwrap();
+        assert_eq!((manifest.isv_svn, manifest.build.commit.as_str()), (1, ""));
+        std::fs::write(&path, "{}").unwrap();
+        assert!(config.enclave.load_manifest().is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_secret_files() {
+        let dir = std::env::temp_dir().join(format!("safetrace-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("spid"), "00112233445566778899AABBCCDDEEFF\n").unwrap();
+        std::fs::write(dir.join("ias_key"), "key-from-dir").unwrap();
+
+        let mut config = Config::from_toml(&format!("[secrets]\ndir = {:?}\n", dir)).unwrap();
+        config.load_secrets().unwrap();
+        assert_eq!(config.spid.expose(), "00112233445566778899AABBCCDDEEFF");
+        assert_eq!(config.ias.key.as_ref().map(|key| key.expose()), Some("key-from-dir"));
+        assert!(config.server.frame_key.is_none());
+        config.validate().unwrap();
+
+        // Tagged frames once the key is there, a key too short is refused
+        std::fs::write(dir.join("frame_key"), "2b4d".repeat(8)).unwrap();
+        config.load_secrets().unwrap();
+        assert!(config.validate().is_err());
+        std::fs::write(dir.join("frame_key"), "2b4d".repeat(16)).unwrap();
+        config.load_secrets().unwrap();
+        assert!(config.server.frame_key.is_some());
+        config.validate().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_standalone() {
+        let mut config = Config::default();
+        config.standalone();
+        assert!(config.server.selftest);
+        assert_eq!(config.server.health_bind, "0.0.0.0:8080");
+        config.validate().unwrap();
+
+        // What the file sets wins
+        let mut config = Config::from_toml("[server]\nhealth_bind = \"127.0.0.1:9000\"\n[secrets]\ndir = \"/srv/secrets\"\n").unwrap();
+        config.standalone();
+        assert_eq!(config.server.health_bind, "127.0.0.1:9000");
+        assert_eq!(config.secrets.dir, Some(PathBuf::from("/srv/secrets")));
+    }
+}
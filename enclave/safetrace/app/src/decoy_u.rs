@@ -0,0 +1,22 @@
+use crate::common_u::errors::EnclaveFailError;
+use crate::telemetry;
+use failure::Error;
+use sgx_types::{sgx_enclave_id_t, sgx_status_t};
+use enigma_types::{EnclaveReturn};
+
+
+extern {
+    pub fn ecall_set_match_rate_limit(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, per_minute: u64) -> sgx_status_t;
+}
+
+// Caps the real `findMatch` queries an enclave answers per minute, 0 disables the limit.
+// The enclave does the accounting itself: decoy queries are only recognizable once decrypted,
+// so the host can't (and doesn't need to) tell them apart to exclude them from the limit.
+pub fn set_match_rate_limit(eid: sgx_enclave_id_t, per_minute: u64) -> Result<(), Error> {
+    let mut ret = EnclaveReturn::Success;
+    let status = telemetry::ecall("ecall_set_match_rate_limit", || unsafe { ecall_set_match_rate_limit(eid, &mut ret as *mut EnclaveReturn, per_minute) });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok(())
+}
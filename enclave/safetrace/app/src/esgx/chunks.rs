@@ -0,0 +1,87 @@
+use crate::common_u::errors::EnclaveFailError;
+use crate::telemetry;
+use failure::Error;
+use hex::FromHex;
+use std::str;
+use sgx_types::{sgx_enclave_id_t, sgx_status_t};
+use enigma_types::{EnclaveReturn};
+
+
+extern {
+    pub fn ecall_chunk_begin(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, total_len: usize, context: *mut u64) -> sgx_status_t;
+    pub fn ecall_chunk_append(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn,
+                              context: u64, chunk: *const u8, chunk_len: usize) -> sgx_status_t;
+    pub fn ecall_chunk_discard(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, context: u64) -> sgx_status_t;
+}
+
+// What each ecall copies into the enclave, see `chunks` in the enclave
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+fn check(ret: EnclaveReturn, status: sgx_status_t) -> Result<(), Error> {
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok(())
+}
+
+// Copies `data` into a new chunk context of the enclave, returns its id for the ecall that takes it.
+pub fn upload(eid: sgx_enclave_id_t, data: &[u8]) -> Result<u64, Error> {
+    let mut ret = EnclaveReturn::Success;
+    let mut context = 0u64;
+    let status = telemetry::ecall("ecall_chunk_begin", || unsafe { ecall_chunk_begin(eid, &mut ret as *mut EnclaveReturn, data.len(), &mut context as *mut u64) });
+    check(ret, status)?;
+    for chunk in data.chunks(CHUNK_SIZE) {
+        let status = telemetry::ecall("ecall_chunk_append", || unsafe { ecall_chunk_append(eid, &mut ret as *mut EnclaveReturn, context, chunk.as_ptr(), chunk.len()) });
+        if let Err(e) = check(ret, status) {
+            discard(eid, context);
+            return Err(e);
+        }
+    }
+    Ok(context)
+}
+
+// `upload` of the bytes `hex` encodes, decoded one chunk at a time: the whole payload is never held
+// decoded next to the text.
+pub fn upload_hex(eid: sgx_enclave_id_t, hex: &str) -> Result<u64, Error> {
+    if hex.len() % 2 != 0 {
+        bail!("odd number of hex digits");
+    }
+    let mut ret = EnclaveReturn::Success;
+    let mut context = 0u64;
+    let status = telemetry::ecall("ecall_chunk_begin", || unsafe { ecall_chunk_begin(eid, &mut ret as *mut EnclaveReturn, hex.len() / 2, &mut context as *mut u64) });
+    check(ret, status)?;
+    for digits in hex.as_bytes().chunks(2 * CHUNK_SIZE) {
+        let appended = str::from_utf8(digits).map_err(Error::from)
+            .and_then(|digits| digits.from_hex::<Vec<u8>>().map_err(|e| format_err!("{}", e)))
+            .and_then(|chunk| {
+                let status = telemetry::ecall("ecall_chunk_append", || unsafe { ecall_chunk_append(eid, &mut ret as *mut EnclaveReturn, context, chunk.as_ptr(), chunk.len()) });
+                check(ret, status)
+            });
+        if let Err(e) = appended {
+            discard(eid, context);
+            return Err(e);
+        }
+    }
+    Ok(context)
+}
+
+// Closes a context the ecall it was meant for didn't take, e.g. because it never ran.
+pub fn discard(eid: sgx_enclave_id_t, context: u64) {
+    let mut ret = EnclaveReturn::Success;
+    let status = telemetry::ecall("ecall_chunk_discard", || unsafe { ecall_chunk_discard(eid, &mut ret as *mut EnclaveReturn, context) });
+    if let Err(e) = check(ret, status) {
+        warn!("Discarding chunk context {} failed: {}", context, e);
+    }
+}
+
+// Uploads `data` and runs `ecall` with the context. The ecall takes the input out on its first step,
+// failed or not; the context is discarded anyway in case it didn't get that far.
+pub fn with_upload<T, F: FnOnce(u64) -> Result<T, Error>>(eid: sgx_enclave_id_t, data: &[u8], ecall: F) -> Result<T, Error> {
+    let context = upload(eid, data)?;
+    let result = ecall(context);
+    if result.is_err() {
+        discard(eid, context);
+    }
+    result
+}
+
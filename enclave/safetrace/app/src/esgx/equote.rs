@@ -1,8 +1,27 @@
 use common_u::errors;
+use enigma_tools_m::utils::EthereumAddress;
+use enigma_types::EnclaveReturn;
 use failure::Error;
+use hex::FromHex;
+use safetrace_client::signatures::Curve;
 use sgx_types::*;
 use std::str;
-use crate::ocalls_u::ecall_get_signing_address;
+use std::thread;
+use std::time::Duration;
+use crate::attestation::AttestationProvider;
+use crate::common_u::errors::{EnclaveFailError, IasUnavailableErr, ProduceQuoteErr, QuoteErr};
+use crate::esgx::general;
+use crate::keys_u::ecall_get_signing_identity;
+use crate::ocalls_u::{ecall_get_mr_enclave, ecall_get_registration_quote, ecall_get_signing_address};
+use crate::telemetry;
+
+// The quotes of the enclave for IAS. Unlike `enigma_tools_u::esgx::equote`, which quotes without a SigRL, the
+// EPID flow asks the attestation service for the signature revocation list of the platform's EPID group first
+// and hands it to the quoting enclave, which proves in the quote that its signatures aren't on it.
+
+// Quotes tried while the quoting enclave answers an empty one, and the wait between two
+const QUOTE_ATTEMPTS: usize = 18;
+const QUOTE_DELAY: Duration = Duration::from_secs(5);
 // this struct is returned during the process registration back to the surface.
 // quote: the base64 encoded quote
 // address : the clear text public key for ecdsa signing and registration
@@ -17,7 +36,7 @@ pub struct GetRegisterResult {
 //#[logfn(TRACE)]
 pub fn get_register_signing_address(eid: sgx_enclave_id_t) -> Result<[u8; 20], Error> {
     let mut address = [0u8; 20];
-    let status = unsafe { ecall_get_signing_address(eid, &mut address) };
+    let status = telemetry::ecall("ecall_get_signing_address", || unsafe { ecall_get_signing_address(eid, &mut address) });
     if status == sgx_status_t::SGX_SUCCESS {
         Ok(address)
     } else {
@@ -25,12 +44,141 @@ pub fn get_register_signing_address(eid: sgx_enclave_id_t) -> Result<[u8; 20], E
     }
 }
 
+// The signing key of the enclave: its curve, its public key (x || y) and the address the quotes carry.
+pub struct SigningIdentity {
+    pub curve: Curve,
+    pub public_key: [u8; 64],
+    pub address: [u8; 20],
+}
+
+pub fn get_signing_identity(eid: sgx_enclave_id_t) -> Result<SigningIdentity, Error> {
+    let mut ret = EnclaveReturn::Success;
+    let (mut curve, mut public_key) = (0u8, [0u8; 64]);
+    let status = telemetry::ecall("ecall_get_signing_identity", || unsafe {
+        ecall_get_signing_identity(eid, &mut ret as *mut EnclaveReturn, &mut curve as *mut u8, &mut public_key)
+    });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    let curve = if curve == Curve::P256.code() { Curve::P256 } else { Curve::Secp256k1 };
+    Ok(SigningIdentity { curve, public_key, address: public_key.address() })
+}
+
+// MRENCLAVE of the running enclave, the same value IAS reports show.
+pub fn get_mr_enclave(eid: sgx_enclave_id_t) -> Result<[u8; 32], Error> {
+    let mut mr_enclave = [0u8; 32];
+    let status = telemetry::ecall("ecall_get_mr_enclave", || unsafe { ecall_get_mr_enclave(eid, &mut mr_enclave) });
+    if status == sgx_status_t::SGX_SUCCESS {
+        Ok(mr_enclave)
+    } else {
+        Err(errors::EnclaveFailError { err: enigma_types::EnclaveReturn::SgxError, status }.into())
+    }
+}
+
+fn quote_err(status: sgx_status_t, message: &str) -> Error {
+    ProduceQuoteErr { status, message: message.to_string() }.into()
+}
+
+// The target info of the quoting enclave, and the EPID group of the platform.
+fn init_quote() -> Result<(sgx_target_info_t, sgx_epid_group_id_t), Error> {
+    let mut target_info = sgx_target_info_t::default();
+    let mut gid = sgx_epid_group_id_t::default();
+    let status = unsafe { sgx_init_quote(&mut target_info, &mut gid) };
+    if status != sgx_status_t::SGX_SUCCESS {
+        return Err(quote_err(status, "sgx_init_quote failed"));
+    }
+    Ok((target_info, gid))
+}
+
+// The EPID group as IAS names it, 8 hex digits big-endian, the SDK gives it little-endian.
+pub fn group_id(gid: &sgx_epid_group_id_t) -> String {
+    gid.iter().rev().map(|b| format!("{:02x}", b)).collect()
+}
+
+// The EPID group of the platform, see `group_id`.
+pub fn platform_group() -> Result<String, Error> {
+    init_quote().map(|(_, gid)| group_id(&gid))
+}
+
+fn parse_spid(spid: &str) -> Result<sgx_spid_t, Error> {
+    let bytes: Vec<u8> = spid.from_hex().map_err(|_| QuoteErr { message: "the SPID isn't hex".to_string() })?;
+    if bytes.len() != 16 {
+        return Err(QuoteErr { message: "the SPID must be 16 bytes".to_string() }.into());
+    }
+    let mut id = [0u8; 16];
+    id.copy_from_slice(&bytes);
+    Ok(sgx_spid_t { id })
+}
+
+// A linkable quote of the registration report of the enclave (its signing address and settings), base64. The
+// quoting enclave proves its signature isn't on `sigrl`, empty when the group has none.
+pub fn produce_quote(eid: sgx_enclave_id_t, spid: &str, sigrl: &[u8]) -> Result<String, Error> {
+    let spid = parse_spid(spid)?;
+    let (target_info, _) = init_quote()?;
+    let mut report = sgx_report_t::default();
+    let mut retval = sgx_status_t::SGX_SUCCESS;
+    let status = telemetry::ecall("ecall_get_registration_quote", || unsafe { ecall_get_registration_quote(eid, &mut retval, &target_info, &mut report) });
+    if status != sgx_status_t::SGX_SUCCESS || retval != sgx_status_t::SGX_SUCCESS {
+        let status = if status != sgx_status_t::SGX_SUCCESS { status } else { retval };
+        return Err(quote_err(status, "ecall_get_registration_quote failed"));
+    }
+    let sigrl_ptr = if sigrl.is_empty() { std::ptr::null() } else { sigrl.as_ptr() };
+    let mut quote_size = 0u32;
+    let status = unsafe { sgx_calc_quote_size(sigrl_ptr, sigrl.len() as u32, &mut quote_size) };
+    if status != sgx_status_t::SGX_SUCCESS {
+        return Err(quote_err(status, "sgx_calc_quote_size failed"));
+    }
+    let mut quote = vec![0u8; quote_size as usize];
+    let status = unsafe {
+        sgx_get_quote(&report, sgx_quote_sign_type_t::SGX_LINKABLE_SIGNATURE, &spid, std::ptr::null(), sigrl_ptr, sigrl.len() as u32,
+                      std::ptr::null_mut(), quote.as_mut_ptr() as *mut sgx_quote_t, quote_size)
+    };
+    if status != sgx_status_t::SGX_SUCCESS {
+        return Err(quote_err(status, "sgx_get_quote failed"));
+    }
+    Ok(base64::encode(&quote))
+}
+
+// `produce_quote` until the quoting enclave answers more than zeros, which it does while it's still starting.
+pub fn retry_quote(eid: sgx_enclave_id_t, spid: &str, sigrl: &[u8]) -> Result<String, Error> {
+    let mut quote = String::new();
+    for _ in 0..QUOTE_ATTEMPTS {
+        quote = produce_quote(eid, spid, sigrl)?;
+        if !quote.chars().all(|c| c == 'A') {
+            return Ok(quote);
+        }
+        thread::sleep(QUOTE_DELAY);
+    }
+    Err(QuoteErr { message: quote }.into())
+}
+
+// A quote for `provider` to attest, with the SigRL of the platform's group. Without IAS at hand the quote goes
+// without one, as it would be sent to IAS later: IAS checks the signature against the list either way.
+pub fn quote_for(eid: sgx_enclave_id_t, spid: &str, provider: &dyn AttestationProvider) -> Result<String, Error> {
+    if general::is_simulation() {
+        return retry_quote(eid, spid, &[]);
+    }
+    let group = platform_group()?;
+    let sigrl = match provider.get_sigrl(&group) {
+        Ok(sigrl) => sigrl,
+        Err(ref e) if e.downcast_ref::<IasUnavailableErr>().is_some() => {
+            warn!("Fetching the SigRL of EPID group {} failed, quoting without it: {}", group, e);
+            Vec::new()
+        },
+        Err(e) => return Err(e),
+    };
+    debug!("SigRL of EPID group {}: {} bytes", group, sigrl.len());
+    retry_quote(eid, spid, &sigrl)
+}
+
 
 #[cfg(test)]
 mod test {
     use crate::esgx::general::init_enclave_wrapper;
     use enigma_tools_u::attestation_service::{self, service::AttestationService};
-    use enigma_tools_u::esgx::equote::retry_quote;
+    use super::{group_id, parse_spid, retry_quote};
+    use crate::attestation::{AttestationProvider, MockAttestationService, Quote};
+    use safetrace_client::signatures::Curve;
 
     const SPID: &str = "B0335FD3BC1CCA8F804EB98A6420592D"; // Enigma's SPID
 
@@ -40,7 +188,7 @@ mod test {
         let enclave = init_enclave_wrapper().unwrap();
         // produce a quote
 
-        let tested_encoded_quote = match retry_quote(enclave.geteid(), &SPID, 18) {
+        let tested_encoded_quote = match retry_quote(enclave.geteid(), SPID, &[]) {
             Ok(encoded_quote) => encoded_quote,
             Err(e) => {
                 println!("[-] Produce quote Err {}, {}", e.as_fail(), e.backtrace());
@@ -57,9 +205,10 @@ mod test {
     }
 
     #[test]
+    #[cfg_attr(feature = "sgx-sim", ignore)] // needs IAS
     fn test_produce_and_verify_qoute() {
         let enclave = init_enclave_wrapper().unwrap();
-        let quote = retry_quote(enclave.geteid(), &SPID, 18).unwrap();
+        let quote = retry_quote(enclave.geteid(), SPID, &[]).unwrap();
         let service = AttestationService::new(attestation_service::constants::ATTESTATION_SERVICE_URL);
         let as_response = service.get_report(quote).unwrap();
 
@@ -67,9 +216,10 @@ mod test {
     }
 
     #[test]
+    #[cfg_attr(feature = "sgx-sim", ignore)] // needs IAS
     fn test_signing_key_against_quote() {
         let enclave = init_enclave_wrapper().unwrap();
-        let quote = retry_quote(enclave.geteid(), &SPID, 18).unwrap();
+        let quote = retry_quote(enclave.geteid(), SPID, &[]).unwrap();
         let service = AttestationService::new(attestation_service::constants::ATTESTATION_SERVICE_URL);
         let as_response = service.get_report(quote).unwrap();
         assert!(as_response.result.verify_report().unwrap());
@@ -77,4 +227,42 @@ mod test {
         let quote = as_response.get_quote().unwrap();
         assert_eq!(key, &quote.report_body.report_data[..20]);
     }
+
+    #[test]
+    fn test_produce_and_verify_quote_offline() {
+        let enclave = init_enclave_wrapper().unwrap();
+        let quote = retry_quote(enclave.geteid(), SPID, &[]).unwrap();
+        let service = MockAttestationService::new().unwrap();
+        let result = service.get_report(quote).unwrap();
+        enclave.destroy();
+        assert!(service.verify_report(&result).unwrap());
+    }
+
+    #[test]
+    fn test_signing_key_against_mock_report() {
+        let enclave = init_enclave_wrapper().unwrap();
+        let quote = retry_quote(enclave.geteid(), SPID, &[]).unwrap();
+        let service = MockAttestationService::new().unwrap();
+        let result = service.get_report(quote).unwrap();
+        let key = super::get_register_signing_address(enclave.geteid()).unwrap();
+        let quote = Quote::from_base64(&result.report.isv_enclave_quote_body).unwrap();
+        enclave.destroy();
+        assert_eq!(key, &quote.report_body.report_data[..20]);
+    }
+
+    #[test]
+    fn test_signing_identity() {
+        let enclave = init_enclave_wrapper().unwrap();
+        let identity = super::get_signing_identity(enclave.geteid()).unwrap();
+        let address = super::get_register_signing_address(enclave.geteid()).unwrap();
+        enclave.destroy();
+        assert_eq!((identity.curve, identity.address), (Curve::Secp256k1, address));
+    }
+
+    #[test]
+    fn test_group_id() {
+        assert_eq!(group_id(&[0x2b, 0x0b, 0x00, 0x00]), "00000b2b");
+        assert!(parse_spid(SPID).is_ok());
+        assert!(parse_spid("B0335FD3").is_err() && parse_spid("not hex").is_err());
+    }
 }
@@ -1,11 +1,29 @@
 use enigma_tools_u::{self, esgx::general::storage_dir};
 use sgx_types::*;
 use sgx_urts::SgxEnclave;
+use crate::esgx::pool::EnclaveFactory;
 use std::fs;
+use std::io;
+use std::path::Path;
 //use log;
 
 static ENCLAVE_FILE: &'static str = "../bin/enclave.signed.so";
 pub static ENCLAVE_DIR: &'static str = ".enigma";
+// Launch token cached between runs, in the storage directory.
+static TOKEN_FILE: &'static str = "enclave.token";
+// Files sealed by the enclave, relative to the working directory (see `data::DATAFILE` and `get_sealed_keys_wrapper`).
+pub static DATA_FILE: &'static str = "data.sealed";
+pub static KEYPAIR_FILE: &'static str = "keypair.sealed";
+// The signing key when `enclave.signing_curve` is p256, see `keys_u::keypair_file`
+pub static P256_KEYPAIR_FILE: &'static str = "keypair_p256.sealed";
+// The enclave file of `enclave.files` the last `Upgrade` switched to, the one to start next time.
+pub static ACTIVE_FILE: &'static str = "enclave.active";
+
+// True when built against the SGX simulation libraries (`--features sgx-sim`, or `SGX_MODE=SW` at compile time).
+// There is no IAS attestation in simulation mode, reports are mocked.
+pub fn is_simulation() -> bool {
+    cfg!(feature = "sgx-sim") || option_env!("SGX_MODE") == Some("SW")
+}
 
 //#[logfn(INFO)]
 pub fn init_enclave_wrapper() -> SgxResult<SgxEnclave> {
@@ -16,3 +34,64 @@ pub fn init_enclave_wrapper() -> SgxResult<SgxEnclave> {
 
     enigma_tools_u::esgx::init_enclave(&ENCLAVE_FILE)
 }
+
+// Creates the enclave from `enclave_file`, reusing the launch token saved by the previous run
+// (and saving it again if the platform handed out a new one). A missing or unreadable token is
+// not an error, the platform just issues a new one. `debug` enclaves can be inspected with a
+// debugger, so production deployments must run them in release mode.
+pub fn create_enclave(enclave_file: &str, debug: bool) -> SgxResult<SgxEnclave> {
+    let token_path = storage_dir(ENCLAVE_DIR).ok().map(|dir| dir.join(TOKEN_FILE));
+
+    let mut launch_token: sgx_launch_token_t = [0; 1024];
+    if let Some(token) = token_path.as_ref().and_then(|path| fs::read(path).ok()) {
+        if token.len() == launch_token.len() {
+            launch_token.copy_from_slice(&token);
+        } else {
+            warn!("Ignoring the cached launch token, it has the wrong size");
+        }
+    }
+    let mut launch_token_updated: i32 = 0;
+    let mut misc_attr = sgx_misc_attribute_t {secs_attr: sgx_attributes_t { flags:0, xfrm:0}, misc_select:0};
+    let enclave = SgxEnclave::create(enclave_file,
+                                     debug as i32,
+                                     &mut launch_token,
+                                     &mut launch_token_updated,
+                                     &mut misc_attr)?;
+
+    if launch_token_updated != 0 {
+        if let Some(path) = token_path {
+            let saved = path.parent().map_or(Ok(()), fs::create_dir_all).and_then(|_| fs::write(&path, &launch_token[..]));
+            if let Err(e) = saved {
+                warn!("Unable to save the launch token to {}: {}", path.display(), e);
+            }
+        }
+    }
+    // Before anything reads the signing key
+    if let Err(e) = crate::keys_u::set_signing_curve(enclave.geteid()) {
+        error!("Setting the signing curve of the enclave failed: {}", e);
+        return Err(sgx_status_t::SGX_ERROR_UNEXPECTED);
+    }
+    Ok(enclave)
+}
+
+// Creates the workers of a pool from `enclave_file`.
+pub fn enclave_factory(enclave_file: String, debug: bool) -> EnclaveFactory {
+    Box::new(move || create_enclave(&enclave_file, debug))
+}
+
+// The file recorded in `active` if it's one of `files`, else the first of `files`. Once upgraded, the
+// signing key is sealed for the new enclave only: starting an older one again would generate a new key.
+pub fn active_enclave(active: &Path, files: &[String]) -> String {
+    match fs::read_to_string(active) {
+        Ok(ref file) if files.iter().any(|f| f == file.trim()) => file.trim().to_string(),
+        Ok(file) => {
+            warn!(target: "security", "{} names {}, which isn't in enclave.files, starting {}", active.display(), file.trim(), files[0]);
+            files[0].clone()
+        },
+        Err(_) => files[0].clone(),
+    }
+}
+
+pub fn set_active_enclave(active: &Path, file: &str) -> io::Result<()> {
+    fs::write(active, file)
+}
@@ -0,0 +1,117 @@
+use crate::common_u::errors::EnclaveFailError;
+use enigma_types::EnclaveReturn;
+use failure::Error;
+use sgx_types::*;
+
+// Local attestation between two enclaves of this platform, for instance a running enclave and the build
+// replacing it (`upgrade_u`), or the auxiliary enclaves of a split deployment (a key-management enclave
+// and a matching one): the SGX DH exchange, in which the enclaves check each other's report, so they trust
+// each other without a quote nor an IAS round-trip. The app carries the messages, it never learns the
+// session key. See `local` in the enclave for what each side checks.
+//
+// Each enclave build has edge routines of its own: `Ecalls` names the ones of a build, `SAFETRACE` the
+// ones of this enclave. Another build only has to export the same five ecalls (the `local` module of the
+// enclave) under its own names.
+
+// Without additional properties
+const MSG3_SIZE: usize = std::mem::size_of::<sgx_dh_msg3_t>();
+
+type OfferFn = unsafe extern "C" fn(sgx_enclave_id_t, *mut EnclaveReturn, *mut sgx_dh_msg1_t, *mut u64) -> sgx_status_t;
+type JoinFn = unsafe extern "C" fn(sgx_enclave_id_t, *mut EnclaveReturn, *const sgx_dh_msg1_t, *mut sgx_dh_msg2_t,
+                                   *mut u64) -> sgx_status_t;
+type AcceptFn = unsafe extern "C" fn(sgx_enclave_id_t, *mut EnclaveReturn, u64, *const sgx_dh_msg2_t, *mut u8, usize) -> sgx_status_t;
+type ConfirmFn = unsafe extern "C" fn(sgx_enclave_id_t, *mut EnclaveReturn, u64, *const u8, usize) -> sgx_status_t;
+type CloseFn = unsafe extern "C" fn(sgx_enclave_id_t, *mut EnclaveReturn, u64) -> sgx_status_t;
+
+extern {
+    fn ecall_local_offer(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, msg1: *mut sgx_dh_msg1_t, session: *mut u64) -> sgx_status_t;
+    fn ecall_local_join(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, msg1: *const sgx_dh_msg1_t,
+                        msg2: *mut sgx_dh_msg2_t, session: *mut u64) -> sgx_status_t;
+    fn ecall_local_accept(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, session: u64, msg2: *const sgx_dh_msg2_t,
+                          msg3: *mut u8, msg3_len: usize) -> sgx_status_t;
+    fn ecall_local_confirm(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, session: u64, msg3: *const u8, msg3_len: usize) -> sgx_status_t;
+    fn ecall_local_close(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, session: u64) -> sgx_status_t;
+}
+
+// The local attestation ecalls of an enclave build.
+pub struct Ecalls {
+    pub offer: OfferFn,
+    pub join: JoinFn,
+    pub accept: AcceptFn,
+    pub confirm: ConfirmFn,
+    pub close: CloseFn,
+}
+
+pub static SAFETRACE: Ecalls = Ecalls {
+    offer: ecall_local_offer,
+    join: ecall_local_join,
+    accept: ecall_local_accept,
+    confirm: ecall_local_confirm,
+    close: ecall_local_close,
+};
+
+// An enclave, and the ecalls of its build
+#[derive(Clone, Copy)]
+pub struct LocalEnclave {
+    pub eid: sgx_enclave_id_t,
+    pub ecalls: &'static Ecalls,
+}
+
+impl LocalEnclave {
+    pub fn safetrace(eid: sgx_enclave_id_t) -> Self {
+        LocalEnclave { eid, ecalls: &SAFETRACE }
+    }
+}
+
+// An established session: the id each enclave knows it by, to pass to the ecalls that use it. Those take
+// the session out of the enclave, whatever is left is closed when this is dropped.
+pub struct LocalSession {
+    pub responder: (LocalEnclave, u64),
+    pub initiator: (LocalEnclave, u64),
+}
+
+fn check(ret: EnclaveReturn, status: sgx_status_t) -> Result<(), Error> {
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok(())
+}
+
+fn close(enclave: LocalEnclave, session: u64) {
+    let mut ret = EnclaveReturn::Success;
+    let status = unsafe { (enclave.ecalls.close)(enclave.eid, &mut ret, session) };
+    if let Err(e) = check(ret, status) {
+        warn!("Closing local attestation session {} of enclave {} failed: {}", session, enclave.eid, e);
+    }
+}
+
+// Runs the exchange between `responder` and `initiator`. The caller holds a thread of each enclave.
+pub fn establish(responder: LocalEnclave, initiator: LocalEnclave) -> Result<LocalSession, Error> {
+    let mut ret = EnclaveReturn::Success;
+    let mut msg1 = sgx_dh_msg1_t::default();
+    let mut msg2 = sgx_dh_msg2_t::default();
+    let mut msg3 = vec![0u8; MSG3_SIZE];
+    let (mut responder_session, mut initiator_session) = (0u64, 0u64);
+    let status = unsafe { (responder.ecalls.offer)(responder.eid, &mut ret, &mut msg1, &mut responder_session) };
+    check(ret, status)?;
+    let status = unsafe { (initiator.ecalls.join)(initiator.eid, &mut ret, &msg1, &mut msg2, &mut initiator_session) };
+    if let Err(e) = check(ret, status) {
+        close(responder, responder_session);
+        return Err(e);
+    }
+    // Closes both ends if anything below fails
+    let session = LocalSession { responder: (responder, responder_session), initiator: (initiator, initiator_session) };
+    // The responder checks the initiator here, the initiator the responder in the next step
+    let status = unsafe { (responder.ecalls.accept)(responder.eid, &mut ret, responder_session, &msg2, msg3.as_mut_ptr(), msg3.len()) };
+    check(ret, status)?;
+    let status = unsafe { (initiator.ecalls.confirm)(initiator.eid, &mut ret, initiator_session, msg3.as_ptr(), msg3.len()) };
+    check(ret, status)?;
+    Ok(session)
+}
+
+impl Drop for LocalSession {
+    fn drop(&mut self) {
+        close(self.responder.0, self.responder.1);
+        close(self.initiator.0, self.initiator.1);
+    }
+}
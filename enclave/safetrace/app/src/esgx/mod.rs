@@ -1,2 +1,7 @@
+pub mod chunks;
 pub mod equote;
 pub mod general;
+pub mod local;
+pub mod pool;
+pub mod preflight;
+pub mod threads;
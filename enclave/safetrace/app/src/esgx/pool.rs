@@ -0,0 +1,270 @@
+use crate::common_u::errors::{EnclaveFailError, GetRegisterKeyErr, ProduceQuoteErr};
+use crate::esgx::equote;
+use crate::esgx::threads::{EnclaveThread, EnclaveThreads};
+use enigma_types::EnclaveReturn;
+use failure::Error;
+use sgx_types::*;
+use sgx_urts::SgxEnclave;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::sync::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{Duration, Instant};
+
+// Creates a fresh enclave, used again when a worker has to be replaced.
+pub type EnclaveFactory = Box<dyn Fn() -> SgxResult<SgxEnclave> + Send + Sync>;
+// Hands state from a running enclave to the one replacing it, see `upgrade`.
+pub type Migration<'a> = &'a dyn Fn(sgx_enclave_id_t, sgx_enclave_id_t) -> Result<(), Error>;
+// Per-enclave configuration (padding, rate limits...), replayed on every replacement enclave.
+pub type InitHook = Box<dyn Fn(sgx_enclave_id_t) -> Result<(), Error> + Send + Sync>;
+
+// The enclave is gone and has to be recreated, e.g. after the machine went through S3 sleep.
+pub fn is_lost(status: sgx_status_t) -> bool {
+    status == sgx_status_t::SGX_ERROR_ENCLAVE_LOST || status == sgx_status_t::SGX_ERROR_ENCLAVE_CRASHED
+}
+
+// Whether an ecall failed because the enclave was lost, as opposed to the request being invalid.
+pub fn is_enclave_lost(e: &Error) -> bool {
+    if let Some(e) = e.downcast_ref::<EnclaveFailError>() {
+        return is_lost(e.status);
+    }
+    if let Some(e) = e.downcast_ref::<GetRegisterKeyErr>() {
+        return is_lost(e.status);
+    }
+    if let Some(e) = e.downcast_ref::<ProduceQuoteErr>() {
+        return is_lost(e.status);
+    }
+    false
+}
+
+// A set of identical enclave workers behind a single IPC front-end.
+// All the workers run the same MRENCLAVE, so they unseal the same signing key and share
+// the sealed data file on disk. Requests are routed by the hash of the user's public key:
+// the DH key negotiated in `NewTaskEncryptionKey` only lives in the enclave that created it,
+// so every later request of that user must land on the same worker.
+// It also supervises the workers: a lost enclave is replaced by a new one, which unseals the same
+// signing key and data from disk and gets the init hooks replayed, see `recover`.
+// Each worker runs up to `threads` ecalls at once (see `esgx::threads`). Take the state lock before
+// entering a worker, never the other way around.
+pub struct EnclavePool {
+    enclaves: RwLock<Vec<SgxEnclave>>,
+    // Serializes the read-modify-write cycles the workers do on the shared sealed state, the ecalls
+    // that only read it share it.
+    state_lock: RwLock<()>,
+    threads: EnclaveThreads,
+    // Swapped by `upgrade`
+    factory: RwLock<EnclaveFactory>,
+    hooks: Mutex<Vec<InitHook>>,
+    // One replacement of workers at a time: `recover`, `restart` or `upgrade`
+    replacing: Mutex<()>,
+    // When a worker was last started, by `new` or a replacement
+    started: RwLock<Instant>,
+}
+
+impl EnclavePool {
+    pub fn new(enclaves: Vec<SgxEnclave>, factory: EnclaveFactory, threads: usize) -> Result<Self, Error> {
+        if enclaves.is_empty() {
+            bail!("An enclave pool needs at least one enclave");
+        }
+        let pool = EnclavePool {
+            enclaves: RwLock::new(enclaves),
+            state_lock: RwLock::new(()),
+            threads: EnclaveThreads::new(threads),
+            factory: RwLock::new(factory),
+            hooks: Mutex::new(Vec::new()),
+            replacing: Mutex::new(()),
+            started: RwLock::new(Instant::now()),
+        };
+        pool.sync_signing_keys()?;
+        Ok(pool)
+    }
+
+    fn workers(&self) -> std::sync::RwLockReadGuard<Vec<SgxEnclave>> {
+        self.enclaves.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // The first worker generates (and seals) the signing key, the others unseal it.
+    // Warming them up one by one guarantees they all end up with the same identity.
+    fn sync_signing_keys(&self) -> Result<(), Error> {
+        let expected = equote::get_register_signing_address(self.primary())?;
+        for eid in &self.eids()[1..] {
+            let address = equote::get_register_signing_address(*eid)?;
+            if address != expected {
+                bail!("Enclave {} doesn't share the sealed signing key of the pool", eid);
+            }
+        }
+        Ok(())
+    }
+
+    // Runs `hook` on every worker now, and on every worker created later on.
+    pub fn add_init_hook(&self, hook: InitHook) -> Result<(), Error> {
+        for eid in self.eids() {
+            hook(eid)?;
+        }
+        self.hooks.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).push(hook);
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize { self.workers().len() }
+
+    // Ecalls each worker runs at once
+    pub fn threads(&self) -> usize { self.threads.threads() }
+
+    // Waits for a free thread of the worker `eid`, for as long as the guard lives.
+    pub fn enter(&self, eid: sgx_enclave_id_t) -> EnclaveThread { self.threads.enter(eid) }
+
+    // Since the pool started or last replaced a worker
+    pub fn uptime(&self) -> Duration { self.started.read().unwrap_or_else(|poisoned| poisoned.into_inner()).elapsed() }
+
+    fn started_now(&self) {
+        *self.started.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = Instant::now();
+    }
+
+    pub fn primary(&self) -> sgx_enclave_id_t { self.workers()[0].geteid() }
+
+    pub fn eids(&self) -> Vec<sgx_enclave_id_t> { self.workers().iter().map(SgxEnclave::geteid).collect() }
+
+    pub fn route(&self, user_pubkey: &str) -> sgx_enclave_id_t {
+        let workers = self.workers();
+        let mut hasher = DefaultHasher::new();
+        hasher.write(user_pubkey.to_lowercase().as_bytes());
+        let index = hasher.finish() % workers.len() as u64;
+        workers[index as usize].geteid()
+    }
+
+    // Probes every worker and replaces the lost ones, returns how many were replaced.
+    // In-enclave state that isn't sealed (DH keys, channels, pending queries) is gone with them.
+    pub fn recover(&self) -> Result<usize, Error> {
+        let _replacing = self.replacing.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let expected = self.eids().into_iter()
+            .filter_map(|eid| equote::get_register_signing_address(eid).ok())
+            .next();
+        let mut workers = self.enclaves.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut replaced = 0;
+        for worker in workers.iter_mut() {
+            let lost = match equote::get_register_signing_address(worker.geteid()) {
+                Ok(_) => false,
+                Err(e) => is_enclave_lost(&e),
+            };
+            if !lost {
+                continue;
+            }
+            let enclave = self.create()?;
+            let address = equote::get_register_signing_address(enclave.geteid())?;
+            if expected.map_or(false, |expected| expected != address) {
+                bail!("The new enclave {} doesn't share the sealed signing key of the pool", enclave.geteid());
+            }
+            for hook in self.hooks.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).iter() {
+                hook(enclave.geteid())?;
+            }
+            warn!("Enclave {} was lost, replaced it with enclave {}", worker.geteid(), enclave.geteid());
+            self.threads.forget(worker.geteid());
+            // The old enclave is gone already, no need to destroy it
+            *worker = enclave;
+            replaced += 1;
+        }
+        if replaced > 0 {
+            self.started_now();
+        }
+        Ok(replaced)
+    }
+
+    fn create(&self) -> Result<SgxEnclave, Error> {
+        let factory = self.factory.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+        Ok((*factory)().map_err(|status| EnclaveFailError { err: EnclaveReturn::SgxError, status })?)
+    }
+
+    // As many new enclaves as there are workers, from `factory`, with the init hooks run. `migrate` runs
+    // between the first one and the current primary before anything else, see `upgrade`.
+    fn spawn(&self, factory: &EnclaveFactory, migrate: Option<Migration>) -> Result<Vec<SgxEnclave>, Error> {
+        let count = self.len();
+        let mut fresh: Vec<SgxEnclave> = Vec::with_capacity(count);
+        let spawned = (0..count).try_for_each(|_| -> Result<(), Error> {
+            let enclave = factory().map_err(|status| EnclaveFailError { err: EnclaveReturn::SgxError, status })?;
+            fresh.push(enclave);
+            let eid = fresh[fresh.len() - 1].geteid();
+            if let (Some(migrate), 1) = (migrate, fresh.len()) {
+                let primary = self.primary();
+                let _thread = self.enter(primary);
+                migrate(primary, eid)?;
+            }
+            // One at a time, so the first one seals the key before the others look for it
+            let address = equote::get_register_signing_address(eid)?;
+            if address != equote::get_register_signing_address(fresh[0].geteid())? {
+                bail!("The new enclave {} doesn't share the sealed signing key of the pool", eid);
+            }
+            for hook in self.hooks.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).iter() {
+                hook(eid)?;
+            }
+            Ok(())
+        });
+        if let Err(e) = spawned {
+            for enclave in fresh {
+                enclave.destroy();
+            }
+            return Err(e);
+        }
+        Ok(fresh)
+    }
+
+    // Waits for the ecalls still running (match job steps, key exchanges) to leave the old workers, then
+    // puts `fresh` in their place.
+    fn swap(&self, fresh: Vec<SgxEnclave>) {
+        let _drained: Vec<EnclaveThread> = self.eids().into_iter()
+            .flat_map(|eid| (0..self.threads()).map(move |_| eid))
+            .map(|eid| self.enter(eid))
+            .collect();
+        let mut workers = self.enclaves.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        for enclave in std::mem::replace(&mut *workers, fresh) {
+            self.threads.forget(enclave.geteid());
+            enclave.destroy();
+        }
+        self.started_now();
+    }
+
+    // Replaces every worker with a new enclave, e.g. once the sealed signing key was set aside so the
+    // pool starts over with a new one. As with `recover`, the state that isn't sealed is lost.
+    // The old workers keep serving if anything fails.
+    pub fn restart(&self) -> Result<(), Error> {
+        let _replacing = self.replacing.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let _state = self.lock_state();
+        let fresh = {
+            let factory = self.factory.read().unwrap_or_else(|poisoned| poisoned.into_inner());
+            self.spawn(&factory, None)?
+        };
+        self.swap(fresh);
+        info!("Restarted the {} enclave workers", self.len());
+        Ok(())
+    }
+
+    // Replaces every worker with an enclave of `factory`, another build of the enclave, which also serves
+    // the later replacements. `migrate` hands the first new enclave what it can't unseal itself, from the
+    // current primary, before it reads its signing key. The new workers are started while the old ones
+    // serve, requests only wait for the swap. As with `restart`, the old workers keep serving if anything
+    // fails, and the state that isn't sealed nor migrated is lost.
+    pub fn upgrade(&self, factory: EnclaveFactory, migrate: Migration) -> Result<(), Error> {
+        let _replacing = self.replacing.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let fresh = self.spawn(&factory, Some(migrate))?;
+        {
+            let _state = self.lock_state();
+            self.swap(fresh);
+        }
+        *self.factory.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = factory;
+        info!("Upgraded the {} enclave workers", self.len());
+        Ok(())
+    }
+
+    pub fn lock_state(&self) -> RwLockWriteGuard<()> {
+        self.state_lock.write().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // For the ecalls that only read the sealed state, such as `FindMatch`
+    pub fn read_state(&self) -> RwLockReadGuard<()> {
+        self.state_lock.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn destroy(self) {
+        for enclave in self.enclaves.into_inner().unwrap_or_else(|poisoned| poisoned.into_inner()) {
+            enclave.destroy();
+        }
+    }
+}
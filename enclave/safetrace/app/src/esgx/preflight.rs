@@ -0,0 +1,254 @@
+use crate::esgx::general;
+use sgx_types::sgx_status_t;
+use std::fs;
+use std::io;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+
+// Checks the platform can run the enclave before it's created, so a failed start says what's missing rather
+// than `SGX_ERROR_NO_DEVICE`: SGX in the CPU (CPUID), a driver, the AESM service the launch and the quotes go
+// through, whether the platform has flexible launch control (FLC) and how much EPC it has.
+// `safetrace-app preflight` prints the checks, `--json` as a report for provisioning scripts; a failed enclave
+// creation prints those that failed and what the error usually means (`explain`).
+//
+// This build attests with EPID through IAS. FLC platforms can run it as long as the AESM has its EPID
+// quoting plugin; without FLC a release enclave only launches if Intel whitelisted its signer.
+
+pub const AESM_SOCKET: &str = "/var/run/aesmd/aesm.socket";
+// Where else the socket shows up: distributions without the /var/run link, and the folder of the socket mounted
+// at the root of a container
+const AESM_CANDIDATES: &[&str] = &["/run/aesmd/aesm.socket", "/aesmd/aesm.socket"];
+// The in-kernel driver (Linux 5.11 on), the DCAP driver, the legacy out-of-tree driver
+const DEVICES: &[&str] = &["/dev/sgx_enclave", "/dev/sgx/enclave", "/dev/isgx"];
+// Below this the enclaves page a lot
+const SMALL_EPC: u64 = 64 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct CpuSupport {
+    pub sgx: bool,
+    pub sgx1: bool,
+    pub sgx2: bool,
+    // Flexible launch control
+    pub flc: bool,
+    pub epc_bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub name: String,
+    pub ok: bool,
+    pub detail: String,
+    // What to do about it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hint: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PreflightReport {
+    pub ok: bool,
+    pub simulation: bool,
+    pub cpu: CpuSupport,
+    pub devices: Vec<String>,
+    pub checks: Vec<Diagnostic>,
+}
+
+impl Diagnostic {
+    fn pass(name: &str, detail: String) -> Self {
+        Diagnostic { name: name.to_string(), ok: true, detail, hint: None }
+    }
+
+    fn fail(name: &str, detail: String, hint: &str) -> Self {
+        Diagnostic { name: name.to_string(), ok: false, detail, hint: Some(hint.to_string()) }
+    }
+}
+
+// The EPC sections of CPUID leaf 0x12 from subleaf 2 on, as (eax, ebx, ecx, edx). A section of type 1 has its
+// size in ecx[31:12] and edx[19:0].
+pub fn epc_size(sections: &[(u32, u32, u32, u32)]) -> u64 {
+    sections.iter()
+        .take_while(|&&(eax, _, _, _)| eax & 0xf == 1)
+        .map(|&(_, _, ecx, edx)| u64::from(ecx & 0xffff_f000) | (u64::from(edx & 0x000f_ffff) << 32))
+        .sum()
+}
+
+#[cfg(target_arch = "x86_64")]
+pub fn cpu_support() -> CpuSupport {
+    use std::arch::x86_64::{__cpuid_count, __get_cpuid_max};
+    unsafe {
+        let (max_leaf, _) = __get_cpuid_max(0);
+        if max_leaf < 0x12 {
+            return CpuSupport::default();
+        }
+        let features = __cpuid_count(7, 0);
+        let (sgx, flc) = (features.ebx & (1 << 2) != 0, features.ecx & (1 << 30) != 0);
+        if !sgx {
+            return CpuSupport { flc, ..CpuSupport::default() };
+        }
+        let capabilities = __cpuid_count(0x12, 0);
+        let sections: Vec<_> = (2..10).map(|subleaf| __cpuid_count(0x12, subleaf))
+            .map(|r| (r.eax, r.ebx, r.ecx, r.edx))
+            .collect();
+        CpuSupport { sgx, sgx1: capabilities.eax & 1 != 0, sgx2: capabilities.eax & 2 != 0, flc, epc_bytes: epc_size(&sections) }
+    }
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+pub fn cpu_support() -> CpuSupport {
+    CpuSupport::default()
+}
+
+fn check_cpu(cpu: &CpuSupport) -> Diagnostic {
+    if cpu.sgx && cpu.sgx1 {
+        Diagnostic::pass("cpu", format!("SGX1{}", if cpu.sgx2 { " and SGX2" } else { "" }))
+    } else if cpu.sgx {
+        Diagnostic::fail("cpu", "SGX is reported but SGX1 isn't".to_string(), "enable SGX in the BIOS (Enabled, not Software Controlled)")
+    } else {
+        Diagnostic::fail("cpu", "the CPU doesn't report SGX".to_string(),
+                         "enable SGX in the BIOS, or expose it to the VM; check https://github.com/ayeks/SGX-hardware for the CPU")
+    }
+}
+
+fn check_driver(devices: &[String]) -> Diagnostic {
+    if devices.is_empty() {
+        Diagnostic::fail("driver", format!("none of {} exists", DEVICES.join(", ")),
+                         "install the SGX driver (or use Linux 5.11+), and pass the device to the container, e.g. --device /dev/isgx")
+    } else {
+        Diagnostic::pass("driver", devices.join(", "))
+    }
+}
+
+fn check_aesm(socket: &Path) -> Diagnostic {
+    match UnixStream::connect(socket) {
+        Ok(_) => Diagnostic::pass("aesm", format!("{} accepts connections", socket.display())),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+            Diagnostic::fail("aesm", format!("{} doesn't exist", socket.display()),
+                             "start aesmd (systemctl start aesmd), or mount /var/run/aesmd into the container")
+        },
+        Err(e) => Diagnostic::fail("aesm", format!("{}: {}", socket.display(), e), "restart aesmd, and check this user may use its socket"),
+    }
+}
+
+fn check_launch(cpu: &CpuSupport, devices: &[String]) -> Diagnostic {
+    let legacy = devices.iter().any(|device| device == "/dev/isgx");
+    if cpu.flc {
+        Diagnostic::pass("launchControl", "flexible launch control, the AESM needs its EPID plugin for the IAS quotes".to_string())
+    } else if devices.is_empty() || legacy {
+        Diagnostic::pass("launchControl", "no flexible launch control: EPID, and release enclaves need a signer whitelisted by Intel".to_string())
+    } else {
+        Diagnostic::fail("launchControl", format!("{} needs flexible launch control, which the CPU doesn't have", devices.join(", ")),
+                         "install the legacy out-of-tree driver (/dev/isgx) instead")
+    }
+}
+
+fn check_epc(cpu: &CpuSupport) -> Diagnostic {
+    let mib = cpu.epc_bytes / (1024 * 1024);
+    match cpu.epc_bytes {
+        0 => Diagnostic::fail("epc", "the CPU reports no EPC".to_string(), "set the PRMRR / EPC size in the BIOS"),
+        bytes if bytes < SMALL_EPC => Diagnostic::fail("epc", format!("{} MiB", mib), "raise the EPC size in the BIOS, or run fewer enclave.workers"),
+        _ => Diagnostic::pass("epc", format!("{} MiB", mib)),
+    }
+}
+
+// Every check, against the AESM socket at `aesm`. In simulation mode none of them matters.
+pub fn run_with(aesm: &Path) -> PreflightReport {
+    let cpu = cpu_support();
+    let devices: Vec<String> = DEVICES.iter().filter(|device| Path::new(device).exists()).map(|device| device.to_string()).collect();
+    let checks = vec![check_cpu(&cpu), check_driver(&devices), check_aesm(aesm), check_launch(&cpu, &devices), check_epc(&cpu)];
+    let simulation = general::is_simulation();
+    PreflightReport { ok: simulation || checks.iter().all(|check| check.ok), simulation, cpu, devices, checks }
+}
+
+pub fn run() -> PreflightReport {
+    run_with(Path::new(AESM_SOCKET))
+}
+
+// The first AESM socket that accepts connections: `preferred` (SAFETRACE_AESM_SOCKET), then `AESM_SOCKET`,
+// then the usual other places.
+pub fn locate_aesm(preferred: Option<&Path>) -> Option<PathBuf> {
+    preferred.into_iter().map(Path::to_path_buf)
+        .chain(std::iter::once(AESM_SOCKET).chain(AESM_CANDIDATES.iter().cloned()).map(PathBuf::from))
+        .find(|socket| UnixStream::connect(socket).is_ok())
+}
+
+// The SGX libraries only connect to `AESM_SOCKET`: a socket found elsewhere is linked there.
+pub fn link_aesm(found: &Path) -> io::Result<()> {
+    let target = Path::new(AESM_SOCKET);
+    if found == target {
+        return Ok(());
+    }
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // A stale socket or link left by a previous container
+    if let Err(e) = fs::remove_file(target) {
+        if e.kind() != io::ErrorKind::NotFound {
+            return Err(e);
+        }
+    }
+    std::os::unix::fs::symlink(found, target)
+}
+
+// What an enclave creation error usually means.
+pub fn explain(status: sgx_status_t) -> Option<&'static str> {
+    match status {
+        sgx_status_t::SGX_ERROR_NO_DEVICE => Some("no SGX device: the driver isn't loaded, or the device isn't passed to the container"),
+        sgx_status_t::SGX_ERROR_SERVICE_UNAVAILABLE | sgx_status_t::SGX_ERROR_SERVICE_TIMEOUT =>
+            Some("the AESM service isn't running or doesn't answer"),
+        sgx_status_t::SGX_ERROR_SERVICE_INVALID_PRIVILEGE =>
+            Some("a release enclave needs a signer whitelisted by Intel on platforms without flexible launch control, run it in debug mode"),
+        sgx_status_t::SGX_ERROR_ENCLAVE_FILE_ACCESS => Some("the enclave file of enclave.files can't be read"),
+        sgx_status_t::SGX_ERROR_INVALID_ENCLAVE | sgx_status_t::SGX_ERROR_INVALID_METADATA | sgx_status_t::SGX_ERROR_INVALID_VERSION =>
+            Some("the enclave file isn't a signed enclave for this SDK, build it again"),
+        sgx_status_t::SGX_ERROR_NDEBUG_ENCLAVE => Some("the enclave was signed for production, set enclave.release"),
+        sgx_status_t::SGX_ERROR_MODE_INCOMPATIBLE => Some("the app and the enclave weren't both built for hardware, or both for simulation"),
+        sgx_status_t::SGX_ERROR_OUT_OF_EPC | sgx_status_t::SGX_ERROR_OUT_OF_MEMORY => Some("not enough EPC or memory, run fewer enclave.workers"),
+        sgx_status_t::SGX_ERROR_UPDATE_NEEDED => Some("the platform needs a microcode or SGX software update"),
+        sgx_status_t::SGX_ERROR_DEVICE_BUSY => Some("the SGX device is busy, try again"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_epc_size() {
+        // Two sections of 93 MiB and 4 GiB + 64 MiB, then the end
+        let sections = [(0x7000_0001, 0, 0x05d8_0001, 0), (0x1, 0x1, 0x0400_0001, 0x1), (0, 0, 0, 0), (1, 0, 0x1000, 0)];
+        assert_eq!(epc_size(&sections), 0x05d8_0000 + 0x1_0400_0000);
+        assert_eq!(epc_size(&[]), 0);
+    }
+
+    #[test]
+    fn test_checks() {
+        let cpu = CpuSupport { sgx: true, sgx1: true, sgx2: false, flc: false, epc_bytes: 93 * 1024 * 1024 };
+        assert!(check_cpu(&cpu).ok && check_epc(&cpu).ok);
+        assert!(!check_cpu(&CpuSupport::default()).ok && !check_epc(&CpuSupport::default()).ok);
+        assert!(!check_driver(&[]).ok);
+        // The newer drivers need FLC
+        assert!(check_launch(&cpu, &["/dev/isgx".to_string()]).ok);
+        assert!(!check_launch(&cpu, &["/dev/sgx_enclave".to_string()]).ok);
+        assert!(check_launch(&CpuSupport { flc: true, ..cpu.clone() }, &["/dev/sgx_enclave".to_string()]).ok);
+
+        let aesm = check_aesm(Path::new("/nonexistent/aesm.socket"));
+        assert!(!aesm.ok && aesm.hint.is_some());
+        let report = run_with(Path::new("/nonexistent/aesm.socket"));
+        assert_eq!(report.checks.len(), 5);
+        assert_eq!(report.ok, general::is_simulation());
+        assert!(explain(sgx_status_t::SGX_ERROR_NO_DEVICE).is_some());
+        assert!(explain(sgx_status_t::SGX_SUCCESS).is_none());
+    }
+
+    #[test]
+    fn test_locate_aesm() {
+        use std::os::unix::net::UnixListener;
+        let socket = std::env::temp_dir().join("safetrace-preflight-aesm.socket");
+        let _ = fs::remove_file(&socket);
+        let _listener = UnixListener::bind(&socket).unwrap();
+        assert_eq!(locate_aesm(Some(&socket)), Some(socket.clone()));
+        fs::remove_file(&socket).unwrap();
+    }
+}
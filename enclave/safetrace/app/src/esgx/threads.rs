@@ -0,0 +1,120 @@
+use sgx_types::sgx_enclave_id_t;
+use std::collections::HashMap;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+
+// Enclave threads. An enclave runs as many ecalls at once as it has TCS (`TCSNum` in Enclave.config.xml),
+// one more fails with SGX_ERROR_OUT_OF_TCS. Whoever sends a request to an enclave first takes one of its
+// `enclave.threads` permits, see `EnclavePool::enter`, so the threads of the app (IPC, match jobs, admin)
+// wait for a free TCS instead of running into that error.
+
+// TCSNum of Enclave.config.xml, `enclave.threads` can't be more
+pub const MAX_THREADS: usize = 8;
+
+struct Semaphore {
+    permits: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl Semaphore {
+    fn acquire(&self) {
+        let mut permits = self.permits.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        while *permits == 0 {
+            permits = self.freed.wait(permits).unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+        *permits -= 1;
+    }
+
+    fn release(&self) {
+        *self.permits.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) += 1;
+        self.freed.notify_one();
+    }
+}
+
+// A TCS of an enclave, given back when dropped.
+pub struct EnclaveThread(Arc<Semaphore>);
+
+impl Drop for EnclaveThread {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+pub struct EnclaveThreads {
+    threads: usize,
+    semaphores: Mutex<HashMap<sgx_enclave_id_t, Arc<Semaphore>>>,
+}
+
+impl EnclaveThreads {
+    pub fn new(threads: usize) -> Self {
+        EnclaveThreads { threads, semaphores: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn threads(&self) -> usize { self.threads }
+
+    // Waits for a free thread of `eid`.
+    pub fn enter(&self, eid: sgx_enclave_id_t) -> EnclaveThread {
+        let semaphore = self.semaphores.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+            .entry(eid)
+            .or_insert_with(|| Arc::new(Semaphore { permits: Mutex::new(self.threads), freed: Condvar::new() }))
+            .clone();
+        semaphore.acquire();
+        EnclaveThread(semaphore)
+    }
+
+    // Once an enclave was replaced. The permits still out are given back to the old semaphore.
+    pub fn forget(&self, eid: sgx_enclave_id_t) {
+        self.semaphores.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).remove(&eid);
+    }
+}
+
+// Runs independent tasks on threads of their own, at most `threads` at a time, and returns their results
+// in order. A single task, or a single thread, runs on the caller's thread.
+pub fn scatter<T, F>(tasks: Vec<F>, threads: usize) -> Vec<T>
+where F: FnOnce() -> T + Send + 'static, T: Send + 'static {
+    if threads <= 1 || tasks.len() <= 1 {
+        return tasks.into_iter().map(|task| task()).collect();
+    }
+    let mut results = Vec::with_capacity(tasks.len());
+    let mut tasks = tasks.into_iter().peekable();
+    while tasks.peek().is_some() {
+        let handles: Vec<_> = tasks.by_ref().take(threads).map(thread::spawn).collect();
+        results.extend(handles.into_iter().map(|handle| handle.join().expect("A dispatched task panicked")));
+    }
+    results
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    #[test]
+    fn test_enclave_threads() {
+        let threads = Arc::new(EnclaveThreads::new(2));
+        let (running, most) = (Arc::new(AtomicUsize::new(0)), Arc::new(Mutex::new(0)));
+        let tasks: Vec<_> = (0..6).map(|i| {
+            let (threads, running, most) = (threads.clone(), running.clone(), most.clone());
+            move || {
+                let _thread = threads.enter(7);
+                let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                {
+                    let mut most = most.lock().unwrap();
+                    *most = (*most).max(now);
+                }
+                thread::sleep(Duration::from_millis(20));
+                running.fetch_sub(1, Ordering::SeqCst);
+                i
+            }
+        }).collect();
+        // Results in order, and never more than 2 in the enclave at once
+        assert_eq!(scatter(tasks, 6), vec![0, 1, 2, 3, 4, 5]);
+        assert!(*most.lock().unwrap() <= 2);
+
+        // Another enclave has threads of its own
+        let _first = threads.enter(7);
+        let _second = threads.enter(7);
+        let _other = threads.enter(8);
+    }
+}
@@ -0,0 +1,59 @@
+use crate::common_u::errors::EnclaveFailError;
+use crate::esgx::pool::EnclavePool;
+use crate::telemetry;
+use failure::Error;
+use hex::ToHex;
+use safetrace_client::identity::{self, ServerIdentity};
+use serde_json::Value;
+use sgx_types::{sgx_enclave_id_t, sgx_status_t};
+use enigma_types::{EnclaveReturn};
+
+
+extern {
+    pub fn ecall_sign_response(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, digest: &[u8; 32], address: &mut [u8; 20],
+                               boot: &mut [u8; 16], counter: *mut u64, sig: &mut [u8; 65]) -> sgx_status_t;
+}
+
+// The `identity` of the native responses: the primary enclave signs the digest of every answer with its next
+// counter (`responses` in the enclave, the format is in `identity` of the client), so a host that answers in
+// its place or plays an old answer again is caught by clients. The primary signs them all, the counter of one
+// enclave goes up across every worker's answers.
+
+pub fn sign_response(eid: sgx_enclave_id_t, digest: &[u8; 32]) -> Result<ServerIdentity, Error> {
+    let mut ret = EnclaveReturn::Success;
+    let (mut address, mut boot, mut counter, mut sig) = ([0u8; 20], [0u8; 16], 0u64, [0u8; 65]);
+    let status = telemetry::ecall("ecall_sign_response", || unsafe {
+        ecall_sign_response(eid, &mut ret as *mut EnclaveReturn, digest, &mut address, &mut boot, &mut counter as *mut u64, &mut sig)
+    });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok(ServerIdentity { signing_key: address.to_hex(), boot: boot.to_hex(), counter, signature: sig.to_hex() })
+}
+
+// `response` is the document as it goes out, without `identity`.
+pub fn sign(pool: &EnclavePool, response: &Value) -> Result<ServerIdentity, Error> {
+    let eid = pool.primary();
+    let _thread = pool.enter(eid);
+    sign_response(eid, &identity::digest(response))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::esgx::{equote, general::init_enclave_wrapper};
+    use serde_json::json;
+
+    #[test]
+    fn test_sign_response() {
+        let enclave = init_enclave_wrapper().unwrap();
+        let address = equote::get_register_signing_address(enclave.geteid()).unwrap();
+        let mut response = json!({"id": "a1b2c3d4e5", "type": "Ping", "result": {"nonce": "5eed"}});
+        let first = sign_response(enclave.geteid(), &identity::digest(&response)).unwrap();
+        let second = sign_response(enclave.geteid(), &identity::digest(&response)).unwrap();
+        enclave.destroy();
+        assert_eq!((first.boot.clone(), second.counter), (second.boot.clone(), first.counter + 1));
+        response["identity"] = serde_json::to_value(&second).unwrap();
+        second.verify(&response, &address).unwrap();
+    }
+}
@@ -1,7 +1,12 @@
 use crate::common_u::errors::EnclaveFailError;
+use crate::esgx::general;
+use crate::telemetry;
 use failure::Error;
+use safetrace_client::signatures::{self, Curve};
 use sgx_types::{sgx_enclave_id_t, sgx_status_t};
 use enigma_types::{EnclaveReturn};
+use std::slice;
+use std::sync::Mutex;
 
 
 extern {
@@ -12,6 +17,57 @@ extern {
         pubkey: *mut [u8; 64usize],
         serialized_ptr: *mut u64,
     ) -> sgx_status_t;
+    pub fn ecall_set_signing_curve(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, curve: u8) -> sgx_status_t;
+    pub fn ecall_get_signing_identity(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, curve: *mut u8, pubkey: &mut [u8; 64]) -> sgx_status_t;
+}
+
+// The curve of `enclave.signing_curve`, see `identity` in the enclave. Every enclave the node creates is set to
+// it before it signs anything, see `esgx::general::create_enclave`.
+lazy_static! {
+    static ref CURVE: Mutex<Curve> = Mutex::new(Curve::Secp256k1);
+}
+
+pub fn configure(curve: Curve) {
+    *CURVE.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = curve;
+}
+
+pub fn curve() -> Curve {
+    *CURVE.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// The sealed key of the configured curve, relative to the working directory.
+pub fn keypair_file() -> &'static str {
+    match curve() {
+        Curve::Secp256k1 => general::KEYPAIR_FILE,
+        Curve::P256 => general::P256_KEYPAIR_FILE,
+    }
+}
+
+pub fn set_signing_curve(eid: sgx_enclave_id_t) -> Result<(), Error> {
+    let mut ret = EnclaveReturn::Success;
+    let code = curve().code();
+    let status = telemetry::ecall("ecall_set_signing_curve", || unsafe { ecall_set_signing_curve(eid, &mut ret as *mut EnclaveReturn, code) });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok(())
+}
+
+// Recovers the P-256 key of a signature for the enclave, which has no code for it: 1 with the key in `pubkey`,
+// 0 when there's none. The enclave checks what it's given, see `identity` there.
+#[no_mangle]
+pub extern "C" fn ocall_recover_p256(hash: *const u8, signature: *const u8, pubkey: *mut u8) -> u8 {
+    let mut digest = [0u8; 32];
+    digest.copy_from_slice(unsafe { slice::from_raw_parts(hash, 32) });
+    let mut sig = [0u8; 65];
+    sig.copy_from_slice(unsafe { slice::from_raw_parts(signature, 65) });
+    match signatures::recover_p256(&digest, &sig) {
+        Ok(key) => {
+            unsafe { slice::from_raw_parts_mut(pubkey, 64) }.copy_from_slice(&key);
+            1
+        },
+        Err(_) => 0,
+    }
 }
 
 
@@ -20,9 +76,9 @@ pub fn get_user_key(eid: sgx_enclave_id_t, user_pubkey: &[u8; 64]) -> Result<(Bo
     let mut ret = EnclaveReturn::Success;
     let mut serialized_ptr = 0u64;
 
-    let status = unsafe {
+    let status = telemetry::ecall("ecall_get_user_key", || unsafe {
         ecall_get_user_key(eid, &mut ret as *mut EnclaveReturn, &mut sig, user_pubkey.as_ptr() as _, &mut serialized_ptr as *mut u64)
-    };
+    });
     if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
         return Err(EnclaveFailError { err: ret, status }.into());
     }
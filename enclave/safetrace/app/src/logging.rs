@@ -0,0 +1,93 @@
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+use std::sync::RwLock;
+
+// The app logger: `env_logger` filters that can be swapped at runtime (admin `SetLogLevel`)
+// without restarting the enclaves.
+struct ReloadableLogger {
+    inner: RwLock<Option<(String, env_logger::Logger)>>,
+}
+
+lazy_static! {
+    static ref LOGGER: ReloadableLogger = ReloadableLogger { inner: RwLock::new(None) };
+}
+
+impl Log for ReloadableLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        match &*self.inner.read().unwrap_or_else(|poisoned| poisoned.into_inner()) {
+            Some((_, logger)) => logger.enabled(metadata),
+            None => false,
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        if let Some((_, logger)) = &*self.inner.read().unwrap_or_else(|poisoned| poisoned.into_inner()) {
+            logger.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        if let Some((_, logger)) = &*self.inner.read().unwrap_or_else(|poisoned| poisoned.into_inner()) {
+            logger.flush();
+        }
+    }
+}
+
+// Checks the levels of `env_logger` filters, e.g. `warn,security=info`, which it would otherwise ignore silently.
+pub fn check_filters(filters: &str) -> Result<(), String> {
+    // Everything after a `/` is a message regex
+    let directives = filters.split('/').next().unwrap_or_default();
+    for directive in directives.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+        let mut parts = directive.splitn(2, '=');
+        let (target, level) = (parts.next().unwrap_or_default(), parts.next());
+        if let Some(level) = level {
+            if target.is_empty() || level.parse::<LevelFilter>().is_err() {
+                return Err(format!("Invalid log filter: {}", directive));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn build(filters: &str) -> env_logger::Logger {
+    env_logger::Builder::new().parse_filters(filters).build()
+}
+
+pub fn init(filters: &str) -> Result<(), SetLoggerError> {
+    log::set_logger(&*LOGGER)?;
+    // The configuration is validated right after, this only keeps the startup errors visible
+    if set_filters(filters).is_err() {
+        set_filters("info").ok();
+    }
+    Ok(())
+}
+
+// Replaces the filters of every target, returns the previous ones.
+pub fn set_filters(filters: &str) -> Result<String, String> {
+    check_filters(filters)?;
+    let logger = build(filters);
+    let mut inner = LOGGER.inner.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+    log::set_max_level(logger.filter());
+    let previous = inner.replace((filters.to_string(), logger)).map(|(filters, _)| filters);
+    Ok(previous.unwrap_or_default())
+}
+
+pub fn filters() -> String {
+    match &*LOGGER.inner.read().unwrap_or_else(|poisoned| poisoned.into_inner()) {
+        Some((filters, _)) => filters.clone(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::check_filters;
+
+    #[test]
+    fn test_check_filters() {
+        assert!(check_filters("info").is_ok());
+        assert!(check_filters("warn,security=info,metrics=info").is_ok());
+        assert!(check_filters("safetrace_app::networking=debug/ping").is_ok());
+        assert!(check_filters("security=loud").is_err());
+        assert!(check_filters("=info").is_err());
+    }
+}
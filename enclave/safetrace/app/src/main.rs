@@ -19,8 +19,7 @@
 extern crate sgx_types;
 extern crate sgx_urts;
 
-pub extern crate futures;
-extern crate tokio_zmq;
+extern crate bytes;
 extern crate zmq;
 #[macro_use]
 extern crate failure;
@@ -30,8 +29,24 @@ extern crate serde_repr;
 pub extern crate serde_json;
 extern crate rmp_serde;
 extern crate rustc_hex as hex;
+extern crate reqwest;
+extern crate rand;
+extern crate native_tls;
+extern crate rustls;
+extern crate openssl;
+extern crate sha2;
+extern crate base64;
+extern crate clap;
+extern crate toml;
+extern crate env_logger;
+extern crate zeroize;
 #[macro_use]
 pub extern crate log;
+#[macro_use]
+extern crate lazy_static;
+#[cfg(test)]
+#[macro_use]
+extern crate proptest;
 
 use sgx_types::*;
 use sgx_urts::SgxEnclave;
@@ -40,59 +55,1037 @@ extern crate enigma_types;
 pub extern crate enigma_tools_u;
 extern crate enigma_tools_m;
 extern crate enigma_crypto;
+extern crate safetrace_client;
 
+pub mod attestation;
+pub mod cli;
+pub mod config;
 pub mod common_u;
 pub mod keys_u;
+pub mod channel_u;
+pub mod migration_u;
+pub mod stats_u;
+pub mod padding_u;
+pub mod decoy_u;
+pub mod policy_u;
+pub mod users_u;
+pub mod zones_u;
+pub mod match_u;
+pub mod secrets;
+pub mod logging;
+pub mod purge_u;
+pub mod quorum_u;
+pub mod audit_u;
+pub mod identity_u;
+pub mod cancel_u;
+pub mod tenant_u;
+pub mod selftest_u;
+pub mod store_u;
+pub mod telemetry;
+pub mod upgrade_u;
 pub mod networking;
 pub mod ocalls_u;
 pub mod esgx;
 
-use futures::Future;
-use networking::{ipc_listener, IpcListener};
+use networking::{ipc_listener, router, Listener, ipc_listener::{IpcContext, Origin}, peer::PeerNode};
+use networking::admin::{self, AdminOp, AdminPayload, AdminRequest, Operators};
+use quorum_u::Registration;
+use enigma_crypto::KeyPair;
+use failure::Error;
+use networking::jobs::{self, JobQueue};
+use networking::attestation_jobs::{self, AttestationJobs};
+use networking::reattest::{self, Freshness};
+use networking::lease::{self, Lease};
+use networking::precompute::{self, Precompute};
+use networking::planes::{Plane, Planes};
+use networking::dead_letters::{self, DeadLetters};
+use networking::dashboard::ErrorRates;
+use networking::maintenance::Maintenance;
+use networking::health;
+use audit_u::AuditLog;
+use safetrace_client::audit::AuditKind;
+use safetrace_client::manifest::{BuildProvenance, EnclaveManifest};
+use safetrace_client::tags::FrameKey;
+use sha2::{Digest, Sha256};
+use serde_json::json;
+use esgx::pool::EnclavePool;
+use esgx::preflight;
+use attestation::{archive, cache, IasService, RevocationChecker};
+use config::Config;
+use common_u::errors::IasCredentialsErr;
+use networking::peer::NodeAttestation;
+use hex::ToHex;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+
+fn init_enclave(config: &Config) -> SgxResult<SgxEnclave> {
+    esgx::general::create_enclave(&config.enclave.active_file(), !config.enclave.release)
+}
+
+// What went wrong creating the enclave, with the preflight checks that failed.
+fn enclave_failed(status: sgx_status_t) {
+    println!("[-] Init Enclave Failed {}!", status.as_str());
+    if let Some(explanation) = preflight::explain(status) {
+        println!("[!] Usually {}", explanation);
+    }
+    for check in preflight::run().checks.into_iter().filter(|check| !check.ok) {
+        println!("[!] {}: {}{}", check.name, check.detail, check.hint.map_or(String::new(), |hint| format!(", {}", hint)));
+    }
+}
 
-static ENCLAVE_FILE: &'static str = "enclave.signed.so";
+// `--standalone`: the SGX libraries find the AESM socket wherever the container has it mounted, or
+// SAFETRACE_AESM_SOCKET.
+fn locate_aesm() {
+    let preferred = env::var_os("SAFETRACE_AESM_SOCKET").map(PathBuf::from);
+    match preflight::locate_aesm(preferred.as_ref().map(PathBuf::as_path)) {
+        Some(socket) => match preflight::link_aesm(&socket) {
+            Ok(()) => info!("Using the AESM socket {}", socket.display()),
+            Err(e) => warn!("Unable to link {} to {}: {}", socket.display(), preflight::AESM_SOCKET, e),
+        },
+        None if !esgx::general::is_simulation() => {
+            warn!("No AESM socket answers, mount /var/run/aesmd into the container or set SAFETRACE_AESM_SOCKET")
+        },
+        None => {},
+    }
+}
 
+// Prints the checks of `esgx::preflight`, or their JSON report.
+fn preflight_command(json: bool) {
+    let report = preflight::run();
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report).unwrap());
+        return;
+    }
+    if report.simulation {
+        println!("[+] Simulation build, the platform isn't used");
+    }
+    for check in &report.checks {
+        println!("[{}] {}: {}", if check.ok { "+" } else { "-" }, check.name, check.detail);
+        if let Some(hint) = &check.hint {
+            println!("    {}", hint);
+        }
+    }
+}
 
-fn init_enclave() -> SgxResult<SgxEnclave> {
-    let mut launch_token: sgx_launch_token_t = [0; 1024];
-    let mut launch_token_updated: i32 = 0;
-    // call sgx_create_enclave to initialize an enclave instance
-    // Debug Support: set 2nd parameter to 1
-    let debug = 1;
-    let mut misc_attr = sgx_misc_attribute_t {secs_attr: sgx_attributes_t { flags:0, xfrm:0}, misc_select:0};
-    SgxEnclave::create(ENCLAVE_FILE,
-                       debug,
-                       &mut launch_token,
-                       &mut launch_token_updated,
-                       &mut misc_attr)
+fn init_logging(config: &Config) {
+    if let Err(e) = logging::init(&config.logging.level) {
+        println!("[-] Setting up the logger failed: {}", e);
+    }
 }
 
-fn main() {
-    let enclave= match init_enclave() {
-        Ok(r) => {
-            println!("[+] Init Enclave Successfully {}!", r.geteid());
-            r
+fn migrate_legacy(config: &Config, legacy_dir: Option<&str>) {
+    let legacy_dir = match legacy_dir {
+        Some(dir) => PathBuf::from(dir),
+        None => enigma_tools_u::esgx::general::storage_dir(esgx::general::ENCLAVE_DIR).unwrap(),
+    };
+    let enclave = match init_enclave(config) {
+        Ok(r) => r,
+        Err(x) => {
+            enclave_failed(x);
+            return;
+        },
+    };
+    if let Err(e) = migration_u::run_migration(enclave.geteid(), &legacy_dir) {
+        println!("[-] Migration Failed {}!", e);
+    }
+    enclave.destroy();
+}
+
+fn attestation_service(config: &Config) -> IasService {
+    // Checked with the configuration, the Intel root is the stricter choice if it went away since
+    let root_ca = config.report_root_ca().unwrap_or_else(|e| {
+        error!("{}, verifying against the Intel root", e);
+        attestation::verify::INTEL_ROOT_CA.to_string()
+    });
+    IasService::with_policy(&config.ias.url, config.retry_policy())
+        .with_tls(config.tls_options())
+        .with_api_key(config.ias.key.clone())
+        .with_root_ca(root_ca)
+        .with_revocation(RevocationChecker::new(&config.revocation, config.tls_options()))
+}
+
+// Produces a quote once, has it attested and verifies the report.
+fn attest(config: &Config) {
+    let service = attestation_service(config);
+    let enclave = match init_enclave(config) {
+        Ok(r) => r,
+        Err(x) => {
+            enclave_failed(x);
+            return;
+        },
+    };
+    match NodeAttestation::produce(enclave.geteid(), config.spid.expose(), &service).and_then(|attestation| {
+        let quote = attestation.verify(&service)?;
+        Ok((attestation, quote))
+    }) {
+        Ok((attestation, quote)) => {
+            println!("[+] Signing address: {}", attestation.signing_key);
+            println!("[+] {}", quote);
+            println!("[+] Report verified{}", if esgx::general::is_simulation() { " (simulation, unsigned)" } else { "" });
+            if let Some(status) = attestation::pib::last_status() {
+                println!("[+] TCB status: {}", status.quote_status);
+                for guidance in &status.guidance {
+                    println!("[!] {}", guidance);
+                }
+            }
+        },
+        Err(e) => println!("[-] Attestation Failed {}!", e),
+    }
+    enclave.destroy();
+}
+
+// Prints the enclave signing address, generating (and sealing) the key if there's none yet.
+// With `force` the current key is set aside first, so the enclave generates a new one.
+fn keygen(config: &Config, force: bool) {
+    let key_path = Path::new(keys_u::keypair_file());
+    if force && key_path.exists() {
+        let backup = format!("{}.{}.old", keys_u::keypair_file(), unix_time());
+        if let Err(e) = fs::rename(key_path, &backup) {
+            println!("[-] Unable to set the current key aside: {}", e);
+            return;
+        }
+        println!("[+] Previous key moved to {}", backup);
+    }
+    let enclave = match init_enclave(config) {
+        Ok(r) => r,
+        Err(x) => {
+            enclave_failed(x);
+            return;
+        },
+    };
+    print_signing_identity(enclave.geteid());
+    enclave.destroy();
+}
+
+fn print_signing_identity(eid: sgx_enclave_id_t) {
+    match esgx::equote::get_signing_identity(eid) {
+        Ok(identity) => {
+            println!("[+] Signing address: {} ({})", identity.address.to_hex(), identity.curve.name());
+            println!("[+] Signing public key: {}", identity.public_key.to_hex());
+        },
+        Err(e) => println!("[-] Reading the signing key Failed {}!", e),
+    }
+}
+
+// Writes the manifest of the enclave, out of a quote of it: what a reproducible build of the same commit
+// must measure to. The quote isn't sent to IAS, so it goes without a SigRL, the measurements don't depend on the platform.
+fn manifest(config: &Config, args: &clap::ArgMatches) {
+    let file = config.enclave.active_file();
+    let binary = match fs::read(&file) {
+        Ok(binary) => binary,
+        Err(e) => {
+            println!("[-] Reading {} Failed {}!", file, e);
+            return;
+        },
+    };
+    let enclave = match init_enclave(config) {
+        Ok(r) => r,
+        Err(x) => {
+            enclave_failed(x);
+            return;
+        },
+    };
+    let quote = esgx::equote::retry_quote(enclave.geteid(), config.spid.expose(), &[])
+        .and_then(|quote| attestation::Quote::from_base64(&quote));
+    enclave.destroy();
+    let quote = match quote {
+        Ok(quote) => quote,
+        Err(e) => {
+            println!("[-] Producing a quote Failed {}!", e);
+            return;
         },
+    };
+    let build = BuildProvenance {
+        source: args.value_of("source").unwrap_or_default().to_string(),
+        commit: args.value_of("commit").unwrap_or(env!("SAFETRACE_BUILD_COMMIT")).to_string(),
+        toolchain: env!("SAFETRACE_BUILD_TOOLCHAIN").to_string(),
+        sgx_sdk: env!("SAFETRACE_BUILD_SGX_SDK").to_string(),
+        enclave_sha256: Sha256::digest(&binary).to_hex(),
+    };
+    let manifest = EnclaveManifest::from_quote(&quote, build);
+    if manifest.debug {
+        println!("[!] This is a debug enclave, set enclave.release to describe the production one");
+    }
+    let json = serde_json::to_string_pretty(&manifest).unwrap();
+    match args.value_of("output") {
+        Some(output) => match fs::write(output, json + "\n") {
+            Ok(()) => println!("[+] Manifest of MRENCLAVE {} written to {}", manifest.mr_enclave, output),
+            Err(e) => println!("[-] Writing {} Failed {}!", output, e),
+        },
+        None => println!("{}", json),
+    }
+}
+
+// Copies the sealed files into a new timestamped folder of `dir`.
+// Sealed data can only be unsealed by the same enclave on the same CPU, so this guards against
+// losing or corrupting the files, it doesn't allow moving them to another machine.
+fn seal_backup(dir: &str) {
+    let target = Path::new(dir).join(unix_time().to_string());
+    if let Err(e) = fs::create_dir_all(&target) {
+        println!("[-] Unable to create {}: {}", target.display(), e);
+        return;
+    }
+    for file in &[esgx::general::DATA_FILE, esgx::general::KEYPAIR_FILE, esgx::general::P256_KEYPAIR_FILE] {
+        if !Path::new(file).exists() {
+            println!("[ ] {} doesn't exist, skipped", file);
+            continue;
+        }
+        match fs::copy(file, target.join(file)) {
+            Ok(_) => println!("[+] {} saved to {}", file, target.display()),
+            Err(e) => println!("[-] Saving {} Failed {}!", file, e),
+        }
+    }
+}
+
+// Has the running server delete every record of the sealed store, as `admin purge` does: the enclave purges
+// it, after the operator quorum, and records the purge so the store isn't taken for a rollback. The signing
+// key is kept.
+fn purge(config: &Config, args: &clap::ArgMatches) {
+    if !args.is_present("yes") {
+        println!("[-] This deletes every stored record, run again with --yes to confirm");
+        return;
+    }
+    if let Some(keypair) = read_operator_key(args) {
+        send_admin_op(config, args, &keypair, AdminOp::Purge);
+    }
+}
+
+// Checks the sealed store: its layout, then every entry read by the enclave. With `repair` a log with a torn
+// entry (a write that didn't complete) or deltas is compacted into one snapshot.
+fn fsck(config: &Config, repair: bool) {
+    let bytes = match fs::read(esgx::general::DATA_FILE) {
+        Ok(bytes) => bytes,
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => {
+            println!("[+] No sealed store, nothing to check");
+            return;
+        },
+        Err(e) => {
+            println!("[-] Reading {} Failed {}!", esgx::general::DATA_FILE, e);
+            return;
+        },
+    };
+    let layout = store_u::inspect(&bytes);
+    match layout.format_version {
+        0 => println!("[ ] {}: a single sealed blob, from before the log", esgx::general::DATA_FILE),
+        version => println!("[ ] {}: log format {}, {} entries, {} snapshots", esgx::general::DATA_FILE, version, layout.entries, layout.snapshots),
+    }
+    if layout.torn_bytes > 0 {
+        println!("[!] The last {} bytes aren't a whole entry, a write didn't complete", layout.torn_bytes);
+    }
+    let enclave = match init_enclave(config) {
+        Ok(r) => r,
         Err(x) => {
-            println!("[-] Init Enclave Failed {}!", x.as_str());
+            enclave_failed(x);
+            return;
+        },
+    };
+    // The counter decides which store is fresh, as when the server runs
+    let checked = policy_u::set_rollback_policy(enclave.geteid(), &config.enclave)
+        .and_then(|_| store_u::check_store(enclave.geteid(), repair));
+    match checked {
+        Ok(report) => {
+            println!("[+] {} users, {} records at generation {}, {} deltas since the last snapshot",
+                     report.users, report.records, report.generation, report.deltas);
+            if report.compacted {
+                println!("[+] Compacted into a single snapshot");
+            } else if report.torn_bytes > 0 || report.deltas > 0 {
+                println!("[ ] Run again with --repair to compact it");
+            }
+        },
+        // An older copy would be refused as a rollback, a seal-backup included
+        Err(e) => println!("[-] The sealed store doesn't read, purge it (`admin purge`) to start over: {}", e),
+    }
+    enclave.destroy();
+}
+
+fn status(config: &Config) {
+    println!("[ ] Simulation mode: {}", esgx::general::is_simulation());
+    println!("[ ] Debug enclave: {}", !config.enclave.release);
+    let enclave = match init_enclave(config) {
+        Ok(r) => r,
+        Err(x) => {
+            enclave_failed(x);
+            return;
+        },
+    };
+    print_signing_identity(enclave.geteid());
+    match stats_u::get_stats(enclave.geteid()) {
+        Ok(stats) => println!("[+] Sealed store: {} users, {} records, {} bytes", stats.users, stats.records, stats.bytes_sealed),
+        Err(e) => println!("[-] Reading the sealed store Failed {}!", e),
+    }
+    enclave.destroy();
+}
+
+fn read_operator_key(args: &clap::ArgMatches) -> Option<KeyPair> {
+    match secrets::read_secret_file(Path::new(args.value_of("key").unwrap())).and_then(|key| admin::operator_key(&key)) {
+        Ok(keypair) => Some(keypair),
+        Err(e) => {
+            println!("[-] {}", e);
+            None
+        },
+    }
+}
+
+// Signs an admin operation with an operator key and sends it to the running server.
+fn admin_command(config: &Config, args: &clap::ArgMatches) {
+    let keypair = match read_operator_key(args) {
+        Some(keypair) => keypair,
+        None => return,
+    };
+    let op = match args.value_of("op").unwrap() {
+        "pubkey" => {
+            println!("[+] Operator public key: {}", keypair.get_pubkey().to_hex());
+            return;
+        },
+        "purge" => AdminOp::Purge,
+        "rotate-keys" => AdminOp::RotateKeys,
+        "metrics" => AdminOp::DumpMetrics,
+        "log-level" => AdminOp::SetLogLevel { level: args.value_of("level").unwrap().to_string() },
+        "tcb-status" => AdminOp::GetTcbStatus,
+        "dashboard" => AdminOp::GetDashboard,
+        "maintenance" => AdminOp::SetMaintenance {
+            enabled: args.value_of("state").unwrap() == "on",
+            retry_after: args.value_of("retry-after").map(|seconds| seconds.parse().unwrap()),
+        },
+        "drain-status" => AdminOp::GetMaintenance,
+        "feature" => AdminOp::SetFeatureSwitch {
+            feature: args.value_of("feature").unwrap().parse().unwrap(),
+            enabled: args.value_of("state").unwrap() == "on",
+        },
+        "zones" => {
+            let file = args.value_of("file").unwrap();
+            match fs::read(file).map_err(|e| e.to_string()).and_then(|json| serde_json::from_slice(&json).map_err(|e| e.to_string())) {
+                Ok(zones) => AdminOp::SetExclusionZones { zones },
+                Err(e) => {
+                    println!("[-] Reading the exclusion zones from {} failed: {}", file, e);
+                    return;
+                },
+            }
+        },
+        "upgrade" => AdminOp::Upgrade { file: args.value_of("file").unwrap().to_string() },
+        "policy" => {
+            let file = args.value_of("file").unwrap();
+            match fs::read(file).map_err(|e| e.to_string()).and_then(|json| serde_json::from_slice(&json).map_err(|e| e.to_string())) {
+                Ok(policy) => AdminOp::SetPolicy { policy },
+                Err(e) => {
+                    println!("[-] Reading the signed policy from {} failed: {}", file, e);
+                    return;
+                },
+            }
+        },
+        // Names the operators of this configuration, those of the server must be the same
+        "adopt-operators" => match quorum_u::registry(&config.admin, &config.tenants).and_then(|registry| quorum_u::digest(&registry)) {
+            Ok(digest) => AdminOp::SetOperators { digest },
+            Err(e) => {
+                println!("[-] {}", e);
+                return;
+            },
+        },
+        "cosign" | "submit" => {
+            cosign_command(config, args, &keypair);
+            return;
+        },
+        _ => AdminOp::GetPrincipalCounts,
+    };
+    send_admin_op(config, args, &keypair, op);
+}
+
+// Signs `op` for the admin socket of --endpoint, or writes it to --out for the other operators to cosign.
+fn send_admin_op(config: &Config, args: &clap::ArgMatches, keypair: &KeyPair, op: AdminOp) {
+    // A server bound to every interface is reached on localhost
+    let endpoint = args.value_of("endpoint").map(String::from).unwrap_or_else(|| config.admin.bind.replace('*', "127.0.0.1"));
+    if endpoint.is_empty() {
+        println!("[-] The admin socket is disabled, set admin.bind or pass --endpoint");
+        return;
+    }
+    let nonce = rand::random::<[u8; 16]>().to_hex();
+    let mut payload = AdminPayload::new(op, nonce);
+    payload.tenant = args.value_of("tenant").map(String::from);
+    let request = match payload.sign(unix_time().to_string(), keypair) {
+        Ok(request) => request,
+        Err(e) => {
+            println!("[-] Signing the admin request Failed {}!", e);
+            return;
+        },
+    };
+    // The other operators cosign it within admin.max_skew
+    if let Some(out) = args.value_of("out") {
+        match fs::write(out, serde_json::to_string_pretty(&request).unwrap()) {
+            Ok(()) => println!("[+] Signed request written to {}, `cosign` then `submit` it", out),
+            Err(e) => println!("[-] Writing {} Failed {}!", out, e),
+        }
+        return;
+    }
+    match admin::send(&endpoint, &request) {
+        Ok(response) => println!("{}", serde_json::to_string_pretty(&response).unwrap()),
+        Err(e) => println!("[-] Admin request Failed {}!", e),
+    }
+}
+
+// Adds the signature of `keypair` to the request of --request, or sends it to the server.
+fn cosign_command(config: &Config, args: &clap::ArgMatches, keypair: &KeyPair) {
+    let path = args.value_of("request").unwrap();
+    let mut request: AdminRequest = match fs::read(path).map_err(Error::from).and_then(|json| Ok(serde_json::from_slice(&json)?)) {
+        Ok(request) => request,
+        Err(e) => {
+            println!("[-] Reading the admin request from {} failed: {}", path, e);
+            return;
+        },
+    };
+    if args.value_of("op") == Some("cosign") {
+        let written = request.cosign(keypair).and_then(|()| Ok(fs::write(path, serde_json::to_string_pretty(&request)?)?));
+        match written {
+            Ok(()) => println!("[+] {} now carries {} signatures: {}", path, request.signatures.len() + 1, request.payload),
+            Err(e) => println!("[-] Cosigning {} Failed {}!", path, e),
+        }
+        return;
+    }
+    let endpoint = args.value_of("endpoint").map(String::from).unwrap_or_else(|| config.admin.bind.replace('*', "127.0.0.1"));
+    match admin::send(&endpoint, &request) {
+        Ok(response) => println!("{}", serde_json::to_string_pretty(&response).unwrap()),
+        Err(e) => println!("[-] Admin request Failed {}!", e),
+    }
+}
+
+// Signs a policy document with the policy key, for `admin policy`, or prints the hash of the key to build
+// the enclave with.
+fn sign_policy(args: &clap::ArgMatches) {
+    let keypair = match secrets::read_secret_file(Path::new(args.value_of("key").unwrap())).and_then(|key| admin::operator_key(&key)) {
+        Ok(keypair) => keypair,
+        Err(e) => {
+            println!("[-] {}", e);
+            return;
+        },
+    };
+    let file = match args.value_of("document") {
+        Some(file) => file,
+        None => {
+            println!("[+] SAFETRACE_POLICY_KEY_HASH={}", policy_u::key_hash(&keypair));
+            return;
+        },
+    };
+    // The enclave parses it again, this only spares sending a typo
+    let signed = fs::read_to_string(file).map_err(Error::from)
+        .and_then(|document| serde_json::from_str::<serde_json::Value>(&document).map(|_| document).map_err(Error::from))
+        .and_then(|document| policy_u::SignedPolicy::sign(document, &keypair));
+    match signed {
+        Ok(policy) => println!("{}", serde_json::to_string_pretty(&policy).unwrap()),
+        Err(e) => println!("[-] Signing {} Failed {}!", file, e),
+    }
+}
+
+// Checks the reports of `ias.archive` (or the file given) one by one, against `ias.root_ca` or `--root`.
+fn verify_archive_command(config: &Config, args: &clap::ArgMatches) {
+    let path = args.value_of("file").unwrap_or(config.ias.archive.as_str());
+    if path.is_empty() {
+        println!("[-] No archive is kept, set ias.archive or pass the file");
+        return;
+    }
+    let root_ca = match args.value_of("root") {
+        Some(root) => fs::read_to_string(root).map_err(|e| format_err!("{}: {}", root, e)),
+        None => config.report_root_ca(),
+    };
+    let (root_ca, archive) = match root_ca.and_then(|root_ca| Ok((root_ca, archive::read(Path::new(path))?))) {
+        Ok(read) => read,
+        Err(e) => {
+            println!("[-] {}", e);
+            return;
+        },
+    };
+    let mut failed = 0;
+    for (record, verified) in archive.records.iter().zip(archive::verify_archive(&archive, &root_ca)) {
+        match verified {
+            Ok(verified) => println!("[+] {} {} MRENCLAVE {}{}", record.timestamp, verified.report.quote_status,
+                                     verified.quote.report_body.mr_enclave_hex(),
+                                     if verified.report.advisory_ids.is_empty() { String::new() } else { format!(" {}", verified.report.advisory_ids.join(",")) }),
+            Err(e) => {
+                failed += 1;
+                println!("[-] {} {}", record.timestamp, e);
+            },
+        }
+    }
+    println!("[{}] {} of {} reports verified", if failed == 0 { "+" } else { "-" }, archive.records.len() - failed, archive.records.len());
+}
+
+// Lists the dead letters of `dead_letters.path` (or `--file`), or sends their frames to the server again.
+fn dead_letters_command(config: &Config, args: &clap::ArgMatches) {
+    let path = args.value_of("file").unwrap_or(config.dead_letters.path.as_str());
+    if path.is_empty() {
+        println!("[-] No dead letters are kept, set dead_letters.path or pass --file");
+        return;
+    }
+    let stage = args.value_of("stage").map(|stage| stage.to_ascii_lowercase());
+    let letters: Vec<_> = match dead_letters::read(Path::new(path)) {
+        Ok(letters) => letters.into_iter()
+            .filter(|letter| stage.as_ref().map_or(true, |stage| format!("{:?}", letter.stage).to_ascii_lowercase() == *stage))
+            .filter(|letter| args.value_of("command").map_or(true, |command| letter.command.as_ref().map(String::as_str) == Some(command)))
+            .collect(),
+        Err(e) => {
+            println!("[-] Reading {} Failed {}!", path, e);
+            return;
+        },
+    };
+    if !args.is_present("replay") {
+        for letter in &letters {
+            println!("{} {:?} {} {}{}", letter.timestamp, letter.stage, letter.command.as_ref().map_or("-", String::as_str), letter.error,
+                     if letter.truncated { " (truncated)" } else { "" });
+        }
+        for ((stage, command), count) in dead_letters::summary(&letters) {
+            println!("[+] {:?} {}: {}", stage, if command.is_empty() { "-" } else { command.as_str() }, count);
+        }
+        return;
+    }
+    // A server bound to every interface is reached on localhost
+    let endpoint = args.value_of("endpoint").map(String::from).unwrap_or_else(|| config.server.bind.replace('*', "127.0.0.1"));
+    let endpoint = router::zmq_endpoint(&endpoint);
+    let key = config.server.frame_key.as_ref().and_then(|key| FrameKey::from_hex(key.expose()).ok());
+    for letter in &letters {
+        match dead_letters::replay(&endpoint, letter, key.as_ref()) {
+            Ok(reply) => println!("[+] {} {}", letter.timestamp, String::from_utf8_lossy(&reply)),
+            Err(e) => println!("[-] {} Replay Failed {}!", letter.timestamp, e),
+        }
+    }
+}
+
+fn unix_time() -> u64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// What the enclaves enforce, as recorded in the audit log at startup
+fn startup_policy(config: &Config) -> serde_json::Value {
+    json!({
+        "release": config.enclave.release,
+        "retentionDays": config.retention.days,
+        "matching": config.matching.strategy().ok(),
+        "quantization": config.quantization.active(),
+        "replayWindow": config.enclave.replay_window,
+        "maxClockSkew": config.enclave.max_clock_skew,
+        "requireReplayProtection": config.enclave.require_replay_protection,
+        "requireRegistration": config.enclave.require_registration,
+        "matchRateLimit": config.enclave.match_rate_limit,
+        "healthAuthorities": config.enclave.health_authorities,
+        "declarationMaxAge": config.enclave.declaration_max_age,
+        "statistics": {"enabled": config.statistics.enabled, "epsilon": config.statistics.epsilon},
+        "disabledFeatures": config.server.disabled_features,
+        "tenants": config.tenants.iter().map(|tenant| json!({"id": tenant.id, "retentionDays": tenant.retention_days})).collect::<Vec<_>>(),
+    })
+}
+
+fn run(config: Config, production: bool, skip_selftest: bool) {
+    // Production deployments must not run debug enclaves, their memory can be read with a debugger
+    if production && !config.enclave.release {
+        println!("[-] Refusing to start a debug enclave with --production, set enclave.release (or SAFETRACE_ENCLAVE_RELEASE=1)");
+        return;
+    }
+
+    // Before the enclaves start, their init hooks are traced too
+    if let Err(e) = telemetry::init(&config.telemetry) {
+        println!("[-] Starting the telemetry export failed: {}", e);
+        return;
+    }
+
+    let mut enclaves = Vec::with_capacity(config.enclave.workers);
+    for _ in 0..config.enclave.workers {
+        match init_enclave(&config) {
+            Ok(r) => {
+                println!("[+] Init Enclave Successfully {}!", r.geteid());
+                enclaves.push(r);
+            },
+            Err(x) => {
+                enclave_failed(x);
+                return;
+            },
+        };
+    }
+    let debug = !config.enclave.release;
+    let factory = esgx::general::enclave_factory(config.enclave.active_file(), debug);
+    let pool = match EnclavePool::new(enclaves, factory, config.enclave.threads) {
+        Ok(pool) => pool,
+        Err(e) => {
+            println!("[-] Init Enclave Pool Failed {}!", e);
+            return;
+        },
+    };
+
+    // Pads encrypted outputs to fixed size buckets, so their length doesn't tell whether there was a match.
+    // The configuration was validated when loaded.
+    let padding = config.response_padding().unwrap();
+    let padding_hook = pool.add_init_hook(Box::new(move |eid| {
+        for &(class, bucket) in &padding {
+            padding_u::set_response_padding(eid, class, bucket)?;
+        }
+        Ok(())
+    }));
+    if let Err(e) = padding_hook {
+        println!("[-] Setting the response padding failed: {}", e);
+        return;
+    }
+
+    // Real matching queries each enclave answers per minute, decoys aren't counted
+    let rate_limit = config.enclave.match_rate_limit;
+    if let Err(e) = pool.add_init_hook(Box::new(move |eid| decoy_u::set_match_rate_limit(eid, rate_limit))) {
+        println!("[-] Setting the matching rate limit failed: {}", e);
+        return;
+    }
+
+    // Matching thresholds and retention period, enforced by the enclave
+    let (matching, retention) = (config.matching.clone(), config.retention.clone());
+    if let Err(e) = pool.add_init_hook(Box::new(move |eid| policy_u::set_data_policy(eid, &matching, &retention))) {
+        println!("[-] Setting the data policy failed: {}", e);
+        return;
+    }
+
+    // The matching strategy of the deployment, queries may name another
+    let matching = match config.matching.strategy() {
+        Ok(matching) => matching,
+        Err(e) => {
+            println!("[-] {}", e);
+            return;
+        },
+    };
+    if let Err(e) = pool.add_init_hook(Box::new(move |eid| policy_u::set_matching_strategy(eid, &matching))) {
+        println!("[-] Setting the matching strategy failed: {}", e);
+        return;
+    }
+
+    // A signed policy the enclave sealed takes over the thresholds, retention and strategy above
+    let policy_hook = pool.add_init_hook(Box::new(move |eid| {
+        let (version, hash) = policy_u::restore_signed_policy(eid)?;
+        if version > 0 {
+            info!(target: "security", "Signed policy version {} in force, sha256 {}", version, hash.to_hex());
+        }
+        Ok(())
+    }));
+    if let Err(e) = policy_hook {
+        println!("[-] Restoring the signed policy failed: {}", e);
+        return;
+    }
+
+    // Capacity limits of the store
+    let capacity = config.capacity.clone();
+    if let Err(e) = pool.add_init_hook(Box::new(move |eid| policy_u::set_capacity(eid, &capacity))) {
+        println!("[-] Setting the capacity limits failed: {}", e);
+        return;
+    }
+
+    // Replay protection of the submitted locations, enforced by the enclave
+    let enclave_config = config.enclave.clone();
+    if let Err(e) = pool.add_init_hook(Box::new(move |eid| policy_u::set_replay_policy(eid, &enclave_config))) {
+        println!("[-] Setting the replay policy failed: {}", e);
+        return;
+    }
+
+    // Tolerance of the devices' clocks
+    let enclave_config = config.enclave.clone();
+    if let Err(e) = pool.add_init_hook(Box::new(move |eid| policy_u::set_clock_policy(eid, &enclave_config))) {
+        println!("[-] Setting the clock policy failed: {}", e);
+        return;
+    }
+
+    // Whether the users without a registered signing key are refused
+    let enclave_config = config.enclave.clone();
+    if let Err(e) = pool.add_init_hook(Box::new(move |eid| policy_u::set_auth_policy(eid, &enclave_config))) {
+        println!("[-] Setting the authentication policy failed: {}", e);
+        return;
+    }
+
+    // Before the first ecall that touches the store: the counter decides which store is fresh
+    let enclave_config = config.enclave.clone();
+    if let Err(e) = pool.add_init_hook(Box::new(move |eid| policy_u::set_rollback_policy(eid, &enclave_config))) {
+        println!("[-] Setting the rollback policy failed: {}", e);
+        return;
+    }
+
+    // When the log of the sealed store is compacted
+    let enclave_config = config.enclave.clone();
+    if let Err(e) = pool.add_init_hook(Box::new(move |eid| policy_u::set_storage_policy(eid, &enclave_config))) {
+        println!("[-] Setting the storage policy failed: {}", e);
+        return;
+    }
+
+    // Who may declare a user infected
+    let enclave_config = config.enclave.clone();
+    if let Err(e) = pool.add_init_hook(Box::new(move |eid| policy_u::set_health_authorities(eid, &enclave_config))) {
+        println!("[-] Setting the health authorities failed: {}", e);
+        return;
+    }
+
+    // Coarser locations on ingest
+    let quantization = config.quantization.clone();
+    if let Err(e) = pool.add_init_hook(Box::new(move |eid| policy_u::set_quantization(eid, &quantization))) {
+        println!("[-] Setting the quantization failed: {}", e);
+        return;
+    }
+
+    // Privacy budget of the aggregate statistics
+    let statistics = config.statistics.clone();
+    if let Err(e) = pool.add_init_hook(Box::new(move |eid| policy_u::set_statistics_policy(eid, &statistics))) {
+        println!("[-] Setting the statistics policy failed: {}", e);
+        return;
+    }
+
+    // k-anonymity of the exports for the health authorities
+    let export = config.export.clone();
+    if let Err(e) = pool.add_init_hook(Box::new(move |eid| policy_u::set_export_policy(eid, &export))) {
+        println!("[-] Setting the export policy failed: {}", e);
+        return;
+    }
+
+    // The partitions of the regions or health authorities the deployment serves
+    let tenants = config.tenants.clone();
+    if let Err(e) = pool.add_init_hook(Box::new(move |eid| tenant_u::set_tenants(eid, &tenants).map(|_| ()))) {
+        println!("[-] Setting the tenants failed: {}", e);
+        return;
+    }
+
+    let switches = config.switches().unwrap();
+
+    let attestation = Box::new(attestation_service(&config));
+
+    // Credentials IAS refuses would only show on the first registration. Simulation doesn't use them
+    if config.ias.check_credentials && !esgx::general::is_simulation() {
+        match attestation.check_credentials(config.spid.expose()) {
+            Ok(()) => info!(target: "security", "IAS accepts the SPID and the subscription key"),
+            Err(ref e) if e.downcast_ref::<IasCredentialsErr>().is_some() => {
+                println!("[-] {}, not binding {}", e, config.server.bind);
+                return;
+            },
+            Err(e) => warn!("Checking the IAS credentials failed, the first attestation will tell: {}", e),
+        }
+    }
+
+    // Before the socket is bound: a node that can't attest gets no traffic
+    if config.server.selftest && skip_selftest {
+        warn!(target: "security", "Skipping the startup self-test (--skip-selftest)");
+    } else if config.server.selftest {
+        match selftest_u::run(pool.primary(), config.spid.expose(), &*attestation) {
+            Ok(quote) => info!(target: "security", "Startup self-test passed, MRENCLAVE {}", quote.report_body.mr_enclave.to_hex()),
+            Err(e) => {
+                println!("[-] {}, not binding {}", e, config.server.bind);
+                return;
+            },
+        }
+    }
+
+    let server = match Listener::bind(&config.server.bind, &config.server) {
+        Ok(server) => server,
+        Err(e) => {
+            println!("[-] Binding {} failed: {}", config.server.bind, e);
+            return;
+        },
+    };
+    let notifier = match server.notifier() {
+        Ok(notifier) => notifier,
+        Err(e) => {
+            println!("[-] Connecting to the answers of {} failed: {}", config.server.bind, e);
             return;
         },
     };
 
-    let server = IpcListener::new(&format!("tcp://*:5552"));
+    let peers = PeerNode::from_uris(config.server.peers.clone());
 
-    const SPID: &str = "B0335FD3BC1CCA8F804EB98A6420592D";
+    let quantization = config.quantization.active();
+    let jobs = JobQueue::new(config.jobs.clone());
+    let audit = match AuditLog::open(&config.audit) {
+        Ok(audit) => audit,
+        Err(e) => {
+            println!("[-] Opening the audit log failed: {}", e);
+            return;
+        },
+    };
+    let dead_letters = match DeadLetters::open(&config.dead_letters) {
+        Ok(dead_letters) => dead_letters,
+        Err(e) => {
+            println!("[-] Opening the dead letters failed: {}", e);
+            return;
+        },
+    };
+    let attestation_jobs = match AttestationJobs::open(&config.ias) {
+        Ok(attestation_jobs) => attestation_jobs,
+        Err(e) => {
+            println!("[-] Opening the attestation jobs failed: {}", e);
+            return;
+        },
+    };
+    let manifest = match config.enclave.load_manifest() {
+        Ok(manifest) => manifest,
+        Err(e) => {
+            println!("[-] {}", e);
+            return;
+        },
+    };
+    // Served anyway, clients check it themselves
+    if let Some(manifest) = &manifest {
+        match esgx::equote::get_mr_enclave(pool.primary()) {
+            Ok(mr_enclave) if !manifest.mr_enclave.eq_ignore_ascii_case(&mr_enclave.to_hex()) => {
+                warn!(target: "security", "The enclave isn't the one of enclave.manifest: MRENCLAVE {} instead of {}",
+                      mr_enclave.to_hex(), manifest.mr_enclave);
+            },
+            Ok(_) => {},
+            Err(e) => warn!(target: "security", "Reading MRENCLAVE failed: {}", e),
+        }
+    }
+    // The enclave checks the privileged admin operations against the operators it sealed, it only takes these
+    // if it holds none
+    let registered = quorum_u::registry(&config.admin, &config.tenants)
+        .and_then(|registry| Ok((quorum_u::register_operators(pool.primary(), &registry)?, quorum_u::digest(&registry)?)));
+    match registered {
+        Ok((Registration::PendingAdoption, digest)) => {
+            warn!(target: "security", "The enclave holds other operators than the configured ones (sha256 {}), a quorum of them \
+                                       must sign `admin adopt-operators`", digest);
+        },
+        Ok((Registration::Registered, digest)) => info!(target: "security", "The enclave sealed the configured operators, sha256 {}", digest),
+        Ok((Registration::Unchanged, _)) => {},
+        Err(e) => {
+            println!("[-] Registering the operators failed: {}", e);
+            return;
+        },
+    }
+    let ctx = Arc::new(IpcContext { spid: config.spid.clone(), attestation, pool, switches, peers, quantization, jobs, audit, manifest,
+                                    enclave: config.enclave.clone(), dead_letters, timeouts: config.timeouts(), attestation_jobs,
+                                    tenants: config.tenants.iter().map(|tenant| tenant.id.clone()).collect(),
+                                    max_frame_size: config.server.max_frame_size, errors: ErrorRates::new(),
+                                    freshness: Freshness::new(&config.ias), lease: Lease::new(&config.lease),
+                                    precompute: Precompute::new(&config.precompute), planes: Planes::new(&config.server), notifier,
+                                    max_queued: config.server.max_queued, idle_timeout: config.server.idle_timeout,
+                                    // Validated with the configuration
+                                    frame_key: config.server.frame_key.as_ref().map(|key| FrameKey::from_hex(key.expose()).unwrap()),
+                                    maintenance: Maintenance::default() });
+
+    // The policies the enclaves were started with, signed right away: the first checkpoint of an enclave
+    // resumes the log
+    ctx.audit.record(AuditKind::PolicyChange, "startup", startup_policy(&config));
+    if let Err(e) = ctx.audit.checkpoint(&ctx.pool) {
+        warn!(target: "security", "Signing the startup audit checkpoint failed: {}", e);
+    }
+
+    // Runs the match jobs of `SubmitMatchJob`, `jobs.concurrency` at a time, in steps
+    let events = match jobs::Events::bind(&config.jobs.events) {
+        Ok(events) => Arc::new(events),
+        Err(e) => {
+            println!("[-] Binding the match job events failed: {}", e);
+            return;
+        },
+    };
+    for _ in 0..config.jobs.concurrency {
+        let (jobs_ctx, events) = (ctx.clone(), events.clone());
+        thread::spawn(move || jobs::run(jobs_ctx, events));
+    }
+    // Asks IAS again about the registrations it couldn't answer
+    if ctx.attestation_jobs.enabled() {
+        let (jobs_ctx, events) = (ctx.clone(), events.clone());
+        thread::spawn(move || attestation_jobs::run(jobs_ctx, events));
+    }
+    // Attests the enclave again every `ias.reattest_interval` seconds
+    if ctx.freshness.enabled() {
+        let reattest_ctx = ctx.clone();
+        thread::spawn(move || reattest::run(reattest_ctx));
+    }
+    // Renews the readiness lease every `lease.interval` seconds
+    if ctx.lease.enabled() {
+        let lease_ctx = ctx.clone();
+        thread::spawn(move || lease::run(lease_ctx));
+    }
+    // Brings the matches kept for incremental queries up to every ingest
+    if ctx.precompute.enabled() {
+        let precompute_ctx = ctx.clone();
+        thread::spawn(move || precompute::run(precompute_ctx));
+    }
+    // Probes of the container runtime, see `server.health_bind`
+    if !config.server.health_bind.is_empty() {
+        let (bind, health_ctx) = (config.server.health_bind.clone(), ctx.clone());
+        thread::spawn(move || {
+            if let Err(e) = health::serve_http(&bind, health_ctx) {
+                error!("Serving the health probes failed: {}", e);
+            }
+        });
+    }
 
-    server
-        .run(move |multi| ipc_listener::handle_message(multi, SPID, enclave.geteid(), 1))
+    // Privileged operations, on their own socket and only for the operator keys
+    if config.admin.enabled() {
+        // Both were validated with the configuration
+        let operators = Operators::from_config(&config.admin, &config.tenants).unwrap();
+        let (bind, admin_ctx) = (config.admin.bind.clone(), ctx.clone());
+        thread::spawn(move || {
+            if let Err(e) = admin::serve(&bind, admin_ctx, operators) {
+                error!("The admin socket failed: {}", e);
+            }
+        });
+    }
 
-        //.run(move |multi| ipc_listener::handle_message(multi, &opt.spid, eid, opt.retries))
-        // .run(|mul| {
-        //     println!("{:?}", mul);
-        //     mul
-        // })
-        .wait()
-        .unwrap();
+    // Registrations and key exchanges on a socket of their own, see `networking::planes`
+    let plane = if ctx.planes.split() { Some(Plane::Data) } else { None };
+    if ctx.planes.split() {
+        let (bind, server_config, control_ctx) = (config.server.control_bind.clone(), config.server.clone(), ctx.clone());
+        thread::spawn(move || {
+            let served = Listener::bind(&bind, &server_config).and_then(|control| {
+                control.run(move |multi, client| {
+                    ipc_listener::handle_message(multi, &control_ctx, &Origin { plane: Some(Plane::Control), client: client.clone() })
+                })
+            });
+            if let Err(e) = served {
+                error!("The control socket failed: {}", e);
+            }
+        });
+    }
+
+    if let Err(e) = server.run(move |multi, client| ipc_listener::handle_message(multi, &ctx, &Origin { plane, client: client.clone() })) {
+        println!("[-] The IPC socket failed: {}", e);
+    }
+
+    // pool.destroy();
+}
+
+fn main() {
+    let matches = cli::app().get_matches();
+    let config_path = matches.subcommand().1.and_then(|args| args.value_of("config")).or_else(|| matches.value_of("config"));
+    let mut config = match Config::load(config_path.map(Path::new)) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("[-] {}", e);
+            return;
+        },
+    };
+    init_logging(&config);
+    let standalone = matches.is_present("standalone")
+        || matches.subcommand_matches("run").map_or(false, |args| args.is_present("standalone"));
+    if standalone {
+        config.standalone();
+    }
+    if let Err(e) = config.finish() {
+        println!("[-] {}", e);
+        return;
+    }
+    if standalone {
+        locate_aesm();
+    }
+    archive::configure(if config.ias.archive.is_empty() { None } else { Some(PathBuf::from(&config.ias.archive)) });
+    let report_cache = if config.ias.report_cache.is_empty() { None } else { Some(PathBuf::from(&config.ias.report_cache)) };
+    if let Err(e) = cache::configure(report_cache, config.ias.cache_size) {
+        println!("[-] Unable to read the report cache: {}", e);
+        return;
+    }
+    keys_u::configure(config.enclave.curve().unwrap_or_default());
 
-    // enclave.destroy();
+    match matches.subcommand() {
+        ("attest", _) => attest(&config),
+        ("keygen", Some(args)) => keygen(&config, args.is_present("force")),
+        ("seal-backup", Some(args)) => seal_backup(args.value_of("dir").unwrap()),
+        ("purge", Some(args)) => purge(&config, args),
+        ("status", _) => status(&config),
+        ("fsck", Some(args)) => fsck(&config, args.is_present("repair")),
+        ("admin", Some(args)) => admin_command(&config, args),
+        ("manifest", Some(args)) => manifest(&config, args),
+        ("dead-letters", Some(args)) => dead_letters_command(&config, args),
+        ("sign-policy", Some(args)) => sign_policy(args),
+        ("verify-archive", Some(args)) => verify_archive_command(&config, args),
+        ("preflight", Some(args)) => preflight_command(args.is_present("json")),
+        ("migrate-legacy", Some(args)) => migrate_legacy(&config, args.value_of("dir")),
+        ("run", Some(args)) => run(config, args.is_present("production"), args.is_present("skip-selftest")),
+        // Running the binary without a subcommand starts the server, as it always did
+        _ => run(config, matches.is_present("production"), matches.is_present("skip-selftest")),
+    }
 }
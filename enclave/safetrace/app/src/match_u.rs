@@ -0,0 +1,103 @@
+use crate::common_u::errors::EnclaveFailError;
+use crate::networking::messages::MatchingStrategy;
+use crate::telemetry;
+use failure::Error;
+use sgx_types::{sgx_enclave_id_t, sgx_status_t};
+use enigma_types::{EnclaveReturn};
+
+
+extern {
+    pub fn ecall_start_match_job(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn,
+                                 encrypted_userid: *const u8, encrypted_userid_len: usize,
+                                 encrypted_signature: *const u8, encrypted_signature_len: usize,
+                                 strategy: *const u8, strategy_len: usize,
+                                 user_pub_key: &[u8; 64], job: *mut u64, total: *mut u64, strategy_ptr: *mut u64) -> sgx_status_t;
+    pub fn ecall_match_job_step(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, job: u64, budget: u64, processed: *mut u64) -> sgx_status_t;
+    pub fn ecall_finish_match_job(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, job: u64, serialized_ptr: *mut u64) -> sgx_status_t;
+    pub fn ecall_cancel_match_job(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, job: u64) -> sgx_status_t;
+    pub fn ecall_precompute_matches(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, budget: u64, refreshed: *mut u64, remaining: *mut u64) -> sgx_status_t;
+}
+
+// The strategy an ecall reports it ran, see `save_strategy` in the enclave. Takes back the memory, 0 is none.
+pub fn take_strategy(strategy_ptr: u64) -> Option<MatchingStrategy> {
+    if strategy_ptr == 0 {
+        return None;
+    }
+    let strategy = unsafe { Box::from_raw(strategy_ptr as *mut Box<[u8]>) };
+    serde_json::from_slice(&strategy).ok()
+}
+
+// Starts a `FindMatch` in steps inside the enclave, see `jobs` in the enclave. Uses up the DH key of
+// `user_pub_key` like `FindMatch`. Returns the id of the job in that enclave, how many steps of one
+// stored user it takes and the strategy it runs. `strategy` is a JSON `MatchingStrategy`, empty for the
+// enclave's default.
+pub fn start_match_job(eid: sgx_enclave_id_t, encrypted_userid: &[u8], encrypted_signature: &[u8], strategy: &[u8],
+                       user_pub_key: &[u8; 64]) -> Result<(u64, u64, Option<MatchingStrategy>), Error> {
+    let mut ret = EnclaveReturn::Success;
+    let mut job = 0u64;
+    let mut total = 0u64;
+    let mut strategy_ptr = 0u64;
+    let status = telemetry::ecall("ecall_start_match_job", || unsafe {
+        ecall_start_match_job(eid, &mut ret as *mut EnclaveReturn,
+                              encrypted_userid.as_ptr(), encrypted_userid.len(),
+                              encrypted_signature.as_ptr(), encrypted_signature.len(),
+                              strategy.as_ptr(), strategy.len(),
+                              user_pub_key, &mut job as *mut u64, &mut total as *mut u64, &mut strategy_ptr as *mut u64)
+    });
+    if status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    let strategy = take_strategy(strategy_ptr);
+    if ret != EnclaveReturn::Success {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok((job, total, strategy))
+}
+
+// Compares up to `budget` more stored users, returns how many were compared so far.
+pub fn match_job_step(eid: sgx_enclave_id_t, job: u64, budget: u64) -> Result<u64, Error> {
+    let mut ret = EnclaveReturn::Success;
+    let mut processed = 0u64;
+    let status = telemetry::ecall("ecall_match_job_step", || unsafe { ecall_match_job_step(eid, &mut ret as *mut EnclaveReturn, job, budget, &mut processed as *mut u64) });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok(processed)
+}
+
+// The encrypted results of a job every stored user was compared with, the enclave forgets it.
+pub fn finish_match_job(eid: sgx_enclave_id_t, job: u64) -> Result<Vec<u8>, Error> {
+    let mut ret = EnclaveReturn::Success;
+    let mut serialized_ptr = 0u64;
+    let status = telemetry::ecall("ecall_finish_match_job", || unsafe { ecall_finish_match_job(eid, &mut ret as *mut EnclaveReturn, job, &mut serialized_ptr as *mut u64) });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    let box_ptr = serialized_ptr as *mut Box<[u8]>;
+    let part = unsafe { Box::from_raw(box_ptr) };
+    Ok(part.to_vec())
+}
+
+pub fn cancel_match_job(eid: sgx_enclave_id_t, job: u64) -> Result<(), Error> {
+    let mut ret = EnclaveReturn::Success;
+    let status = telemetry::ecall("ecall_cancel_match_job", || unsafe { ecall_cancel_match_job(eid, &mut ret as *mut EnclaveReturn, job) });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok(())
+}
+
+// Brings the kept matches of up to `budget` users up to the store, see `incremental` in the enclave. Returns how
+// many it went through and how many are still behind. The caller holds the pool state lock, like for a query.
+pub fn precompute_matches(eid: sgx_enclave_id_t, budget: u64) -> Result<(u64, u64), Error> {
+    let mut ret = EnclaveReturn::Success;
+    let mut refreshed = 0u64;
+    let mut remaining = 0u64;
+    let status = telemetry::ecall("ecall_precompute_matches", || unsafe {
+        ecall_precompute_matches(eid, &mut ret as *mut EnclaveReturn, budget, &mut refreshed as *mut u64, &mut remaining as *mut u64)
+    });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok((refreshed, remaining))
+}
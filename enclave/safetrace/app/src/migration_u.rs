@@ -0,0 +1,54 @@
+use crate::common_u::errors::EnclaveFailError;
+use crate::telemetry;
+use failure::Error;
+use sgx_types::{sgx_enclave_id_t, sgx_status_t};
+use enigma_types::{EnclaveReturn};
+use std::path::Path;
+
+
+extern {
+    pub fn ecall_migrate_legacy_data(
+        eid: sgx_enclave_id_t,
+        retval: *mut EnclaveReturn,
+        legacy_path: *const u8,
+        legacy_path_len: usize,
+        migrated_users: *mut u64,
+        migrated_records: *mut u64,
+    ) -> sgx_status_t;
+}
+
+
+// Merges a legacy sealed data file into the current store, returns the number of users and records migrated.
+pub fn migrate_legacy_data(eid: sgx_enclave_id_t, legacy_path: &str) -> Result<(u64, u64), Error> {
+    let mut ret = EnclaveReturn::Success;
+    let mut users = 0u64;
+    let mut records = 0u64;
+
+    let status = telemetry::ecall("ecall_migrate_legacy_data", || unsafe {
+        ecall_migrate_legacy_data(eid, &mut ret as *mut EnclaveReturn, legacy_path.as_ptr(), legacy_path.len(),
+                                  &mut users as *mut u64, &mut records as *mut u64)
+    });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok((users, records))
+}
+
+// `safetrace-app migrate-legacy [dir]`: converts the files left by the enigma-core based prototypes.
+pub fn run_migration(eid: sgx_enclave_id_t, legacy_dir: &Path) -> Result<(), Error> {
+    let data_path = legacy_dir.join("data.sealed");
+    if data_path.exists() {
+        let (users, records) = migrate_legacy_data(eid, &data_path.to_string_lossy())?;
+        println!("[+] Migrated {} users ({} location records) from {}", users, records, data_path.display());
+    } else {
+        println!("[-] No legacy data found at {}", data_path.display());
+    }
+
+    // The legacy signing key is sealed with the MRENCLAVE policy, so only the old enclave can unseal it.
+    let key_path = legacy_dir.join("keypair.sealed");
+    if key_path.exists() {
+        println!("[!] {} is bound to the legacy enclave and can't be migrated.", key_path.display());
+        println!("    A new signing key is generated on first start, clients must verify the new enclave report.");
+    }
+    Ok(())
+}
@@ -0,0 +1,794 @@
+use crate::attestation::{pib, TcbStatus};
+use crate::common_u::errors::AdminAuthErr;
+use crate::config::{AdminConfig, TenantConfig};
+use crate::esgx::{equote, general};
+use crate::keys_u;
+use crate::logging;
+use crate::networking::dashboard::{self, Dashboard};
+use crate::networking::ipc_listener::IpcContext;
+use crate::networking::maintenance::RETRY_AFTER;
+use crate::networking::reattest::LatestAttestation;
+use crate::networking::switches::Feature;
+use crate::policy_u::{self, SignedPolicy};
+use crate::purge_u;
+use crate::quorum_u;
+use crate::tenant_u;
+use crate::secrets::Secret;
+use crate::stats_u::{self, MemoryUsage, StorageStats};
+use crate::store_u;
+use crate::upgrade_u;
+use crate::zones_u::{self, Zone};
+use enigma_crypto::KeyPair;
+use safetrace_client::audit::AuditKind;
+use serde_json::json;
+use failure::Error;
+use hex::{FromHex, ToHex};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// The admin socket: privileged operations for the operators of the node, on a ZMQ REP socket of its
+// own (`[admin] bind`, best kept on localhost or a management network), apart from the public IPC one.
+// Every request is signed by one of the operator keys of `[admin] operators`:
+//
+//   {"id": "1", "payload": "{\"timestamp\":1589000000,\"nonce\":\"a1b2\",\"op\":\"DumpMetrics\"}", "signature": "..."}
+//
+// `payload` is a JSON string so that the signature (65 bytes hex, `KeyPair::sign` of enigma-crypto)
+// covers exactly the bytes sent. Its `timestamp` must be within `max_skew` seconds of our clock and
+// its `nonce` can't be used twice, so a captured request can't be replayed.
+//
+// The operators of a tenant (`[[tenants]] operators`) may only `Purge`, and only with the `tenant` of the
+// payload set to theirs. A `Purge` with a tenant deletes the records of that tenant, without one every record.
+//
+// The privileged operations (`AdminOp::is_privileged`) need `[admin] quorum` operators: the others add their
+// signature of the same payload to `signatures`. The enclave checks them again against the operators it sealed
+// (`quorum_u`) before it runs them.
+
+const MAX_NONCE_LEN: usize = 128;
+// How long `safetrace-app admin` waits for an answer, in milliseconds. Rotating keys and upgrading restart
+// every enclave.
+const CLIENT_TIMEOUT: i32 = 120_000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "op")]
+pub enum AdminOp {
+    // Deletes the stored records, of the `tenant` of the payload if it has one, the signing key is kept
+    Purge,
+    // Generates a new enclave signing key, clients and peers must verify the new report
+    RotateKeys,
+    DumpMetrics,
+    // `env_logger` filters replacing the current ones, e.g. `debug` or `warn,security=info`
+    SetLogLevel { level: String },
+    GetPrincipalCounts,
+    // Quote status, platform info blob and update guidance of the last attestation
+    GetTcbStatus,
+    // Uptime, attestation, pending jobs, storage, IAS and error rates in one document, see `dashboard`
+    GetDashboard,
+    // Replaces the exclusion zones of every worker, an empty list removes them
+    SetExclusionZones { zones: Vec<Zone> },
+    // Replaces the workers with enclaves of `file`, one of `enclave.files`, which take the signing key over
+    Upgrade { file: String },
+    // Has the enclave seal the configured operators in place of the ones it holds, `digest` being theirs
+    // (`quorum_u::digest`). A quorum of the operators it holds must sign
+    SetOperators { digest: String },
+    // Applies a policy signed by the policy key the enclave was built with, see `policy_u`
+    SetPolicy { policy: SignedPolicy },
+    // Refuses the data plane requests with `Maintenance`, telling the clients to retry in `retryAfter` seconds
+    // (`maintenance::RETRY_AFTER` by default), or serves them again. See `maintenance`
+    SetMaintenance {
+        enabled: bool,
+        #[serde(default, rename = "retryAfter", skip_serializing_if = "Option::is_none")]
+        retry_after: Option<u64>,
+    },
+    // Whether the requests and jobs still running are done, the sealed store is flushed once they are
+    GetMaintenance,
+    // Turns a subsystem off or back on, see `switches`. Not served on the IPC socket, anyone can reach that one
+    SetFeatureSwitch { feature: Feature, enabled: bool },
+}
+
+impl AdminOp {
+    // The `op` of the payload
+    pub fn name(&self) -> &'static str {
+        match self {
+            AdminOp::Purge => "Purge",
+            AdminOp::RotateKeys => "RotateKeys",
+            AdminOp::DumpMetrics => "DumpMetrics",
+            AdminOp::SetLogLevel { .. } => "SetLogLevel",
+            AdminOp::GetPrincipalCounts => "GetPrincipalCounts",
+            AdminOp::GetTcbStatus => "GetTcbStatus",
+            AdminOp::GetDashboard => "GetDashboard",
+            AdminOp::SetExclusionZones { .. } => "SetExclusionZones",
+            AdminOp::Upgrade { .. } => "Upgrade",
+            AdminOp::SetOperators { .. } => "SetOperators",
+            AdminOp::SetPolicy { .. } => "SetPolicy",
+            AdminOp::SetMaintenance { .. } => "SetMaintenance",
+            AdminOp::GetMaintenance => "GetMaintenance",
+            AdminOp::SetFeatureSwitch { .. } => "SetFeatureSwitch",
+        }
+    }
+
+    // Destroys the records, hands the signing key over or changes who may: signed by a quorum of operators
+    pub fn is_privileged(&self) -> bool {
+        match self {
+            AdminOp::Purge | AdminOp::RotateKeys | AdminOp::Upgrade { .. } | AdminOp::SetOperators { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AdminPayload {
+    pub timestamp: u64,
+    pub nonce: String,
+    // The tenant a `Purge` is for
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    #[serde(flatten)]
+    pub op: AdminOp,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AdminRequest {
+    pub id: String,
+    pub payload: String,
+    pub signature: String,
+    // Of the other operators, over the same payload, for the privileged operations
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub signatures: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum AdminResult {
+    Purged { users: u64, records: u64 },
+    KeysRotated {
+        #[serde(rename = "signingKey")]
+        signing_key: String,
+        previous: String,
+        // Where the previous sealed key was moved
+        backup: String,
+    },
+    Metrics {
+        stats: StorageStats,
+        features: BTreeMap<Feature, bool>,
+        workers: usize,
+        memory: Vec<MemoryUsage>,
+    },
+    LogLevel { level: String, previous: String },
+    PrincipalCounts { users: u64, operators: usize, peers: usize, channels: usize },
+    // `None` until the node was attested (by IAS) at least once. `latest` and `degraded` with
+    // `ias.reattest_interval`, see `reattest`
+    TcbStatus {
+        status: Option<TcbStatus>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        latest: Option<LatestAttestation>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        degraded: Option<String>,
+    },
+    Dashboard { dashboard: Dashboard },
+    // `digest` is the SHA-256 of the zone list as sealed, the one the audit log records
+    ExclusionZones { zones: u64, digest: String },
+    // The operators the enclave now holds, `digest` as in `SetOperators`
+    OperatorsSet { operators: u64, quorum: u32, digest: String },
+    // `hash` is the SHA-256 of the document, the one the quotes now carry
+    PolicyApplied { version: u64, hash: String },
+    Upgraded {
+        file: String,
+        // MRENCLAVE of the new and of the previous enclave, hex
+        #[serde(rename = "mrEnclave")]
+        mr_enclave: String,
+        previous: String,
+        // The signing key, unchanged
+        #[serde(rename = "signingKey")]
+        signing_key: String,
+    },
+    // `drained` once nothing of the data plane runs, `flushed` the generation of the store compacted then
+    Maintenance {
+        enabled: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        since: Option<u64>,
+        #[serde(default, rename = "retryAfter", skip_serializing_if = "Option::is_none")]
+        retry_after: Option<u64>,
+        #[serde(rename = "inFlight")]
+        in_flight: usize,
+        #[serde(rename = "queuedJobs")]
+        queued_jobs: usize,
+        #[serde(rename = "runningJobs")]
+        running_jobs: usize,
+        drained: bool,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        flushed: Option<u64>,
+    },
+    // Every feature, once switched
+    FeatureSwitches { features: BTreeMap<Feature, bool> },
+    Error { msg: String },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AdminResponse {
+    pub id: String,
+    #[serde(flatten)]
+    pub result: AdminResult,
+}
+
+fn auth_err(message: &str) -> Error {
+    AdminAuthErr { message: message.to_string() }.into()
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl AdminPayload {
+    pub fn new(op: AdminOp, nonce: String) -> Self {
+        AdminPayload { timestamp: now(), nonce, tenant: None, op }
+    }
+
+    // Signs the payload with an operator key, as `safetrace-app admin` does.
+    pub fn sign(&self, id: String, keypair: &KeyPair) -> Result<AdminRequest, Error> {
+        let payload = serde_json::to_string(self)?;
+        let signature = keypair.sign(payload.as_bytes())?;
+        Ok(AdminRequest { id, payload, signature: signature.to_hex(), signatures: Vec::new() })
+    }
+}
+
+impl AdminRequest {
+    // Adds the signature of another operator, for a quorum.
+    pub fn cosign(&mut self, keypair: &KeyPair) -> Result<(), Error> {
+        let signature = keypair.sign(self.payload.as_bytes())?;
+        self.signatures.push(signature.to_hex());
+        Ok(())
+    }
+}
+
+fn parse_signature(signature: &str) -> Result<[u8; 65], Error> {
+    let bytes: Vec<u8> = signature.from_hex().map_err(|_| auth_err("the signature isn't hex"))?;
+    if bytes.len() != 65 {
+        return Err(auth_err("the signature must be 65 bytes long"));
+    }
+    let mut signature = [0u8; 65];
+    signature.copy_from_slice(&bytes);
+    Ok(signature)
+}
+
+// The operator keys, with the tenant of the ones limited to one, and the nonces seen recently. `digest` is
+// the one of these operators as the enclave seals them.
+pub struct Operators {
+    keys: Vec<([u8; 64], Option<String>)>,
+    tenants: Vec<String>,
+    max_skew: u64,
+    quorum: u32,
+    digest: String,
+    seen: Mutex<HashMap<String, u64>>,
+}
+
+impl Operators {
+    // The operators of `[admin]` come first, then those of each tenant.
+    pub fn from_config(config: &AdminConfig, tenants: &[TenantConfig]) -> Result<Self, Error> {
+        let mut keys: Vec<_> = config.operator_keys()?.into_iter().map(|key| (key, None)).collect();
+        for tenant in tenants {
+            keys.extend(tenant.operator_keys()?.into_iter().map(|key| (key, Some(tenant.id.clone()))));
+        }
+        let digest = quorum_u::digest(&quorum_u::registry(config, tenants)?)?;
+        let tenant_ids = tenants.iter().map(|tenant| tenant.id.clone()).collect();
+        Ok(Operators { keys, tenants: tenant_ids, max_skew: config.max_skew, quorum: config.quorum, digest, seen: Mutex::new(HashMap::new()) })
+    }
+
+    pub fn len(&self) -> usize { self.keys.len() }
+
+    // Checks the signature, the clock and the nonce, then returns the index of the operator and the payload.
+    pub fn authenticate(&self, request: &AdminRequest, now: u64) -> Result<(usize, AdminPayload), Error> {
+        let signer = KeyPair::recover(request.payload.as_bytes(), parse_signature(&request.signature)?).map_err(|_| auth_err("invalid signature"))?;
+        let operator = self.keys.iter().position(|(key, _)| key[..] == signer[..])
+            .ok_or_else(|| auth_err("the signer isn't an operator"))?;
+
+        // Only parsed once we know who sent it
+        let payload: AdminPayload = serde_json::from_str(&request.payload)?;
+        if let Some(tenant) = &payload.tenant {
+            if payload.op != AdminOp::Purge {
+                return Err(auth_err("only a Purge is for a tenant"));
+            }
+            if !self.tenants.contains(tenant) {
+                return Err(auth_err("unknown tenant"));
+            }
+        }
+        if let Some(tenant) = &self.keys[operator].1 {
+            if payload.op != AdminOp::Purge || payload.tenant.as_ref() != Some(tenant) {
+                return Err(auth_err("the operators of a tenant may only purge its records"));
+            }
+        }
+        if payload.timestamp + self.max_skew < now || payload.timestamp > now + self.max_skew {
+            return Err(auth_err("the timestamp is too far from the node clock"));
+        }
+        if payload.nonce.is_empty() || payload.nonce.len() > MAX_NONCE_LEN {
+            return Err(auth_err("the nonce must be between 1 and 128 characters"));
+        }
+        let mut seen = self.seen.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        // Older nonces can't come back, their timestamp would be refused
+        let max_skew = self.max_skew;
+        seen.retain(|_, &mut timestamp| timestamp + max_skew >= now);
+        if seen.insert(payload.nonce.clone(), payload.timestamp).is_some() {
+            return Err(auth_err("the nonce was already used"));
+        }
+        Ok((operator, payload))
+    }
+
+    // The signatures of an authenticated privileged request, its own first: from `quorum` distinct operators
+    // allowed its operation. The enclave checks them again.
+    pub fn approvals(&self, request: &AdminRequest, operator: usize, payload: &AdminPayload) -> Result<Vec<[u8; 65]>, Error> {
+        let mut signatures = vec![parse_signature(&request.signature)?];
+        let mut signers = vec![operator];
+        for cosignature in &request.signatures {
+            let signature = parse_signature(cosignature)?;
+            let signer = KeyPair::recover(request.payload.as_bytes(), signature).map_err(|_| auth_err("invalid signature"))?;
+            let cosigner = self.keys.iter().position(|(key, tenant)| {
+                key[..] == signer[..] && tenant.as_ref().map_or(true, |tenant| payload.op == AdminOp::Purge && payload.tenant.as_ref() == Some(tenant))
+            }).ok_or_else(|| auth_err("a cosigner isn't an operator allowed this operation"))?;
+            if signers.contains(&cosigner) {
+                return Err(auth_err("an operator signed twice"));
+            }
+            signers.push(cosigner);
+            signatures.push(signature);
+        }
+        if (signers.len() as u32) < self.quorum {
+            return Err(auth_err(&format!("{} needs {} operator signatures, the request has {}", payload.op.name(), self.quorum, signers.len())));
+        }
+        Ok(signatures)
+    }
+}
+
+// The quorum of a privileged request, checked here then by the enclave, which grants the operation to the
+// ecall running it.
+fn approve(ctx: &IpcContext, operators: &Operators, request: &AdminRequest, operator: usize, payload: &AdminPayload) -> Result<(), Error> {
+    let signatures = operators.approvals(request, operator, payload)?;
+    let _thread = ctx.pool.enter(ctx.pool.primary());
+    let approvals = quorum_u::authorize(ctx.pool.primary(), &request.payload, &signatures)?;
+    info!(target: "security", "Admin {} approved by {} operators, {} checked by the enclave", payload.op.name(), signatures.len(), approvals);
+    Ok(())
+}
+
+fn purge(ctx: &IpcContext, tenant: Option<&str>) -> Result<AdminResult, Error> {
+    let _state = ctx.pool.lock_state();
+    let _thread = ctx.pool.enter(ctx.pool.primary());
+    let (users, records) = tenant_u::scoped(tenant, || purge_u::purge_data(ctx.pool.primary()))?;
+    Ok(AdminResult::Purged { users, records })
+}
+
+// Sets the sealed key aside and restarts the workers, the first one generates a new key.
+// The DH keys of the users and the federation channels are lost with the old enclaves.
+fn rotate_keys(ctx: &IpcContext) -> Result<AdminResult, Error> {
+    let signing_address = || {
+        let _thread = ctx.pool.enter(ctx.pool.primary());
+        equote::get_register_signing_address(ctx.pool.primary())
+    };
+    let previous = signing_address()?;
+    let key_path = Path::new(keys_u::keypair_file());
+    let backup = format!("{}.{}.old", keys_u::keypair_file(), now());
+    fs::rename(key_path, &backup)?;
+    if let Err(e) = ctx.pool.restart() {
+        // The current workers still use the old key, put it back
+        fs::rename(&backup, key_path)?;
+        return Err(e);
+    }
+    ctx.peers.reset();
+    let signing_key = signing_address()?;
+    warn!(target: "security", "Signing key rotated from {} to {}", previous.to_hex(), signing_key.to_hex());
+    Ok(AdminResult::KeysRotated { signing_key: signing_key.to_hex(), previous: previous.to_hex(), backup })
+}
+
+// Starts enclaves of `file` next to the running ones, which hand them the signing key and the audit head
+// over local attestation (see `upgrade_u`), then switches the workers to them and records `file` as the one
+// to start from now on. The sealed key of the old enclave is kept, in case the new one has to be rolled back.
+fn upgrade(ctx: &IpcContext, file: &str) -> Result<AdminResult, Error> {
+    if !ctx.enclave.files.iter().any(|f| f == file) {
+        bail!("{} isn't one of enclave.files", file);
+    }
+    let primary = || {
+        let _thread = ctx.pool.enter(ctx.pool.primary());
+        Ok::<_, Error>((equote::get_mr_enclave(ctx.pool.primary())?, equote::get_register_signing_address(ctx.pool.primary())?))
+    };
+    let (previous, signing_key) = primary()?;
+    let key_path = Path::new(general::KEYPAIR_FILE);
+    let backup = format!("{}.{}.old", general::KEYPAIR_FILE, now());
+    fs::copy(key_path, &backup)?;
+    let factory = general::enclave_factory(file.to_string(), !ctx.enclave.release);
+    if let Err(e) = ctx.pool.upgrade(factory, &|old, new| upgrade_u::migrate(old, new).map(|_| ())) {
+        // The new enclave may have sealed the key for itself already, the old workers need theirs
+        fs::copy(&backup, key_path)?;
+        return Err(e);
+    }
+    if let Err(e) = general::set_active_enclave(Path::new(general::ACTIVE_FILE), file) {
+        error!(target: "security", "Recording {} in {} failed, the next start runs the previous enclave: {}", file, general::ACTIVE_FILE, e);
+    }
+    ctx.peers.reset();
+    let (mr_enclave, address) = primary()?;
+    if address != signing_key {
+        bail!("The upgraded enclave signs with {} instead of {}", address.to_hex(), signing_key.to_hex());
+    }
+    warn!(target: "security", "Upgraded to {}: MRENCLAVE {} instead of {}", file, mr_enclave.to_hex(), previous.to_hex());
+    Ok(AdminResult::Upgraded { file: file.to_string(), mr_enclave: mr_enclave.to_hex(), previous: previous.to_hex(), signing_key: address.to_hex() })
+}
+
+fn dump_metrics(ctx: &IpcContext) -> Result<AdminResult, Error> {
+    let stats = {
+        let _state = ctx.pool.read_state();
+        let _thread = ctx.pool.enter(ctx.pool.primary());
+        stats_u::get_stats(ctx.pool.primary())?
+    };
+    stats.export();
+    // Per worker, each enclave has a heap of its own. The store counts unseal the store the first time
+    let _state = ctx.pool.read_state();
+    let memory = ctx.pool.eids().into_iter().map(|eid| {
+        let _thread = ctx.pool.enter(eid);
+        stats_u::get_memory_usage(eid)
+    }).collect::<Result<Vec<_>, Error>>()?;
+    for (worker, usage) in memory.iter().enumerate() {
+        usage.export(worker);
+    }
+    if let Some(usage) = memory.iter().find(|usage| usage.near_capacity) {
+        warn!("The store holds {} records, past the soft capacity limit of {}", usage.records, usage.soft_limit);
+    }
+    Ok(AdminResult::Metrics { stats, features: ctx.switches.snapshot(), workers: ctx.pool.len(), memory })
+}
+
+fn set_log_level(level: &str) -> Result<AdminResult, Error> {
+    let previous = logging::set_filters(level).map_err(|message| format_err!("{}", message))?;
+    Ok(AdminResult::LogLevel { level: level.to_string(), previous })
+}
+
+fn principal_counts(ctx: &IpcContext, operators: &Operators) -> Result<AdminResult, Error> {
+    let stats = {
+        let _state = ctx.pool.read_state();
+        let _thread = ctx.pool.enter(ctx.pool.primary());
+        stats_u::get_stats(ctx.pool.primary())?
+    };
+    Ok(AdminResult::PrincipalCounts {
+        users: stats.users,
+        operators: operators.len(),
+        peers: ctx.peers.uris().len(),
+        channels: ctx.peers.channel_count(),
+    })
+}
+
+// The enclave checks the quorum of the operators it holds approved these, `digest` names them so the host can't
+// swap them for others.
+fn set_operators(ctx: &IpcContext, operators: &Operators, digest: &str) -> Result<AdminResult, Error> {
+    if digest != operators.digest {
+        bail!("{} isn't the digest of the configured operators, {}", digest, operators.digest);
+    }
+    let (count, quorum) = {
+        let _thread = ctx.pool.enter(ctx.pool.primary());
+        quorum_u::adopt_operators(ctx.pool.primary())?
+    };
+    warn!(target: "security", "The enclave adopted the configured operators: {} operators, quorum {}, sha256 {}", count, quorum, digest);
+    Ok(AdminResult::OperatorsSet { operators: count, quorum, digest: digest.to_string() })
+}
+
+// The first enclave checks and seals the policy, the other workers read it from there.
+fn set_policy(ctx: &IpcContext, policy: &SignedPolicy, operator: usize) -> Result<AdminResult, Error> {
+    let _state = ctx.pool.lock_state();
+    let (version, hash) = {
+        let _thread = ctx.pool.enter(ctx.pool.primary());
+        policy_u::apply_signed_policy(ctx.pool.primary(), policy)?
+    };
+    for eid in ctx.pool.eids().into_iter().filter(|&eid| eid != ctx.pool.primary()) {
+        let _thread = ctx.pool.enter(eid);
+        policy_u::restore_signed_policy(eid)?;
+    }
+    warn!(target: "security", "Signed policy version {} applied by operator {}, sha256 {}", version, operator, hash.to_hex());
+    Ok(AdminResult::PolicyApplied { version, hash: hash.to_hex() })
+}
+
+// The zone list is part of the signed payload, so the audit entry names who uploaded which list.
+fn set_exclusion_zones(ctx: &IpcContext, zones: &[Zone], operator: usize) -> Result<AdminResult, Error> {
+    let (encoded, digest) = zones_u::encode(zones)?;
+    let _state = ctx.pool.lock_state();
+    let mut count = 0;
+    for eid in ctx.pool.eids() {
+        let _thread = ctx.pool.enter(eid);
+        count = zones_u::set_exclusion_zones(eid, &encoded)?;
+    }
+    warn!(target: "security", "Exclusion zones replaced by operator {}: {} zones, sha256 {}", operator, count, digest);
+    Ok(AdminResult::ExclusionZones { zones: count, digest })
+}
+
+// What's left of the data plane. The first time nothing is, the sealed log is compacted into one snapshot so the
+// next enclave reads it whole, and later checks report that generation.
+fn maintenance(ctx: &IpcContext) -> Result<AdminResult, Error> {
+    let state = ctx.maintenance.state().clone();
+    let in_flight = ctx.maintenance.in_flight();
+    let (queued_jobs, running_jobs) = ctx.jobs.pending();
+    let drained = state.is_some() && in_flight == 0 && queued_jobs == 0 && running_jobs == 0;
+    let mut flushed = state.as_ref().and_then(|state| state.flushed);
+    if drained && flushed.is_none() {
+        let report = {
+            let _state = ctx.pool.lock_state();
+            let _thread = ctx.pool.enter(ctx.pool.primary());
+            store_u::check_store(ctx.pool.primary(), true)?
+        };
+        info!("Drained for maintenance, the sealed store is flushed at generation {}", report.generation);
+        if let Some(state) = ctx.maintenance.state().as_mut() {
+            state.flushed = Some(report.generation);
+        }
+        flushed = Some(report.generation);
+    }
+    Ok(AdminResult::Maintenance {
+        enabled: state.is_some(), since: state.as_ref().map(|state| state.since), retry_after: state.as_ref().map(|state| state.retry_after),
+        in_flight, queued_jobs, running_jobs, drained, flushed,
+    })
+}
+
+fn set_maintenance(ctx: &IpcContext, enabled: bool, retry_after: Option<u64>, operator: usize) -> Result<AdminResult, Error> {
+    ctx.maintenance.set(enabled, retry_after.unwrap_or(RETRY_AFTER), now());
+    warn!("Maintenance {} by operator {}", if enabled { "started" } else { "ended" }, operator);
+    maintenance(ctx)
+}
+
+fn set_feature_switch(ctx: &IpcContext, feature: Feature, enabled: bool, operator: usize) -> Result<AdminResult, Error> {
+    ctx.switches.set(feature, enabled);
+    warn!("Feature {:?} {} by operator {}", feature, if enabled { "enabled" } else { "disabled" }, operator);
+    Ok(AdminResult::FeatureSwitches { features: ctx.switches.snapshot() })
+}
+
+// Every authenticated operation goes into the audit log, failed ones too. The reads only by name.
+fn audit(ctx: &IpcContext, operator: usize, op: &str, tenant: Option<&str>, result: &Result<AdminResult, Error>) {
+    let (kind, mut detail) = match result {
+        Ok(AdminResult::Purged { users, records }) => (AuditKind::Deletion, json!({"op": op, "users": users, "records": records})),
+        Ok(AdminResult::ExclusionZones { zones, digest }) => (AuditKind::PolicyChange, json!({"op": op, "zones": zones, "digest": digest})),
+        Ok(AdminResult::PolicyApplied { version, hash }) => (AuditKind::PolicyChange, json!({"op": op, "version": version, "hash": hash})),
+        Ok(AdminResult::OperatorsSet { operators, quorum, digest }) => {
+            (AuditKind::PolicyChange, json!({"op": op, "operators": operators, "quorum": quorum, "digest": digest}))
+        },
+        Ok(AdminResult::KeysRotated { signing_key, previous, .. }) => (AuditKind::Admin, json!({"op": op, "signingKey": signing_key, "previous": previous})),
+        Ok(AdminResult::Upgraded { file, mr_enclave, previous, .. }) => (AuditKind::Admin, json!({"op": op, "file": file, "mrEnclave": mr_enclave, "previous": previous})),
+        Ok(AdminResult::LogLevel { level, previous }) => (AuditKind::Admin, json!({"op": op, "level": level, "previous": previous})),
+        Ok(AdminResult::FeatureSwitches { features }) => (AuditKind::PolicyChange, json!({"op": op, "features": features})),
+        Ok(AdminResult::Maintenance { enabled, drained, .. }) if op == "SetMaintenance" => {
+            (AuditKind::Admin, json!({"op": op, "enabled": enabled, "drained": drained}))
+        },
+        Ok(_) => (AuditKind::Admin, json!({"op": op})),
+        Err(e) => {
+            let kind = match op {
+                "Purge" => AuditKind::Deletion,
+                "SetExclusionZones" | "SetOperators" | "SetPolicy" | "SetFeatureSwitch" => AuditKind::PolicyChange,
+                _ => AuditKind::Admin,
+            };
+            (kind, json!({"op": op, "error": e.to_string()}))
+        },
+    };
+    if let Some(tenant) = tenant {
+        detail["tenant"] = json!(tenant);
+    }
+    ctx.audit.record(kind, &format!("operator {}", operator), detail);
+    ctx.audit.maybe_checkpoint(&ctx.pool);
+}
+
+pub fn handle_message(msg: &[u8], ctx: &IpcContext, operators: &Operators) -> AdminResponse {
+    let request: AdminRequest = match serde_json::from_slice(msg) {
+        Ok(request) => request,
+        Err(e) => return AdminResponse { id: String::new(), result: AdminResult::Error { msg: e.to_string() } },
+    };
+    let result = operators.authenticate(&request, now()).and_then(|(operator, payload)| {
+        info!(target: "security", "Admin {:?} requested by operator {}", payload.op, operator);
+        let name = payload.op.name();
+        let tenant = payload.tenant.as_ref().map(String::as_str);
+        let approved = if payload.op.is_privileged() { approve(ctx, operators, &request, operator, &payload) } else { Ok(()) };
+        let result = match payload.op {
+            _ if approved.is_err() => Err(approved.unwrap_err()),
+            AdminOp::Purge => purge(ctx, tenant),
+            AdminOp::RotateKeys => rotate_keys(ctx),
+            AdminOp::DumpMetrics => dump_metrics(ctx),
+            AdminOp::SetLogLevel { level } => set_log_level(&level),
+            AdminOp::GetPrincipalCounts => principal_counts(ctx, operators),
+            AdminOp::GetTcbStatus => Ok(AdminResult::TcbStatus {
+                status: pib::last_status(),
+                latest: ctx.freshness.latest(),
+                degraded: ctx.freshness.degraded(now()),
+            }),
+            AdminOp::GetDashboard => Ok(AdminResult::Dashboard { dashboard: dashboard::collect(ctx) }),
+            AdminOp::SetExclusionZones { zones } => set_exclusion_zones(ctx, &zones, operator),
+            AdminOp::Upgrade { file } => upgrade(ctx, &file),
+            AdminOp::SetOperators { digest } => set_operators(ctx, operators, &digest),
+            AdminOp::SetPolicy { policy } => set_policy(ctx, &policy, operator),
+            AdminOp::SetMaintenance { enabled, retry_after } => set_maintenance(ctx, enabled, retry_after, operator),
+            AdminOp::GetMaintenance => maintenance(ctx),
+            AdminOp::SetFeatureSwitch { feature, enabled } => set_feature_switch(ctx, feature, enabled, operator),
+        };
+        audit(ctx, operator, name, tenant, &result);
+        result
+    });
+    let result = result.unwrap_or_else(|e| {
+        if e.downcast_ref::<AdminAuthErr>().is_some() {
+            warn!(target: "security", "{}", e);
+        }
+        AdminResult::Error { msg: e.to_string() }
+    });
+    AdminResponse { id: request.id, result }
+}
+
+// Serves the admin socket until it fails, meant for a thread of its own.
+pub fn serve(bind: &str, ctx: Arc<IpcContext>, operators: Operators) -> Result<(), Error> {
+    let context = zmq::Context::new();
+    let socket = context.socket(zmq::REP)?;
+    socket.bind(bind)?;
+    println!("Admin socket bound to: {}", bind);
+    loop {
+        let msg = socket.recv_msg(0)?;
+        let response = handle_message(&msg, &ctx, &operators);
+        socket.send(serde_json::to_vec(&response)?, 0)?;
+    }
+}
+
+// An operator secret key, 32 bytes hex.
+pub fn operator_key(secret: &Secret) -> Result<KeyPair, Error> {
+    let bytes: Vec<u8> = secret.expose().from_hex().map_err(|_| auth_err("the operator key isn't hex"))?;
+    if bytes.len() != 32 {
+        return Err(auth_err("the operator key must be 32 bytes long"));
+    }
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&bytes);
+    Ok(KeyPair::from_slice(&key)?)
+}
+
+// Sends a signed request to the admin socket of a running server and waits for the answer.
+pub fn send(endpoint: &str, request: &AdminRequest) -> Result<AdminResponse, Error> {
+    let context = zmq::Context::new();
+    let socket = context.socket(zmq::REQ)?;
+    socket.set_rcvtimeo(CLIENT_TIMEOUT)?;
+    socket.set_linger(0)?;
+    socket.connect(endpoint)?;
+    socket.send(serde_json::to_vec(request)?, 0)?;
+    let reply = socket.recv_msg(0)?;
+    Ok(serde_json::from_slice(&reply)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn operator() -> KeyPair {
+        KeyPair::from_slice(&[7u8; 32]).unwrap()
+    }
+
+    fn tenant_operator() -> KeyPair {
+        KeyPair::from_slice(&[8u8; 32]).unwrap()
+    }
+
+    fn operators() -> Operators {
+        let config = AdminConfig { operators: vec![operator().get_pubkey().to_hex()], ..AdminConfig::default() };
+        let tenants = vec![TenantConfig { id: "ch-ge".to_string(), operators: vec![tenant_operator().get_pubkey().to_hex()], ..TenantConfig::default() }];
+        Operators::from_config(&config, &tenants).unwrap()
+    }
+
+    fn payload(timestamp: u64, nonce: &str) -> AdminPayload {
+        AdminPayload { timestamp, nonce: nonce.to_string(), tenant: None, op: AdminOp::SetLogLevel { level: "debug".to_string() } }
+    }
+
+    #[test]
+    fn test_payload_format() {
+        let payload: AdminPayload = serde_json::from_str(r#"{"timestamp": 1589000000, "nonce": "a1b2", "op": "DumpMetrics"}"#).unwrap();
+        assert_eq!(payload.op, AdminOp::DumpMetrics);
+        let encoded = serde_json::to_string(&payload).unwrap();
+        assert_eq!(encoded, r#"{"timestamp":1589000000,"nonce":"a1b2","op":"DumpMetrics"}"#);
+        assert_eq!(payload.op.name(), "DumpMetrics");
+
+        let payload: AdminPayload = serde_json::from_str(
+            r#"{"timestamp": 1589000000, "nonce": "a1b2", "op": "SetExclusionZones", "zones": [{"name": "Shelter", "polygon": [[0, 0], [0, 1], [1, 0]], "action": "Drop"}]}"#).unwrap();
+        match payload.op {
+            AdminOp::SetExclusionZones { ref zones } => assert_eq!(zones[0].name, "Shelter"),
+            ref op => panic!("unexpected {:?}", op),
+        }
+        assert_eq!(payload.op.name(), "SetExclusionZones");
+
+        let payload: AdminPayload = serde_json::from_str(
+            r#"{"timestamp": 1589000000, "nonce": "a1b2", "op": "Upgrade", "file": "enclave-v2.signed.so"}"#).unwrap();
+        assert_eq!(payload.op, AdminOp::Upgrade { file: "enclave-v2.signed.so".to_string() });
+        assert_eq!(payload.op.name(), "Upgrade");
+
+        let payload: AdminPayload = serde_json::from_str(r#"{"timestamp": 1589000000, "nonce": "a1b2", "op": "GetDashboard"}"#).unwrap();
+        assert_eq!(payload.op, AdminOp::GetDashboard);
+        assert_eq!(payload.op.name(), "GetDashboard");
+
+        let payload: AdminPayload = serde_json::from_str(r#"{"timestamp": 1589000000, "nonce": "a1b2", "op": "SetMaintenance", "enabled": true}"#).unwrap();
+        assert_eq!(payload.op, AdminOp::SetMaintenance { enabled: true, retry_after: None });
+        assert!(!payload.op.is_privileged());
+        let op = AdminOp::SetMaintenance { enabled: true, retry_after: Some(300) };
+        assert_eq!(serde_json::to_string(&op).unwrap(), r#"{"op":"SetMaintenance","enabled":true,"retryAfter":300}"#);
+        let status = AdminResult::Maintenance {
+            enabled: true, since: Some(1589000000), retry_after: Some(300), in_flight: 0, queued_jobs: 0, running_jobs: 0, drained: true, flushed: Some(12),
+        };
+        assert_eq!(serde_json::to_string(&status).unwrap(),
+                   r#"{"type":"Maintenance","enabled":true,"since":1589000000,"retryAfter":300,"inFlight":0,"queuedJobs":0,"runningJobs":0,"drained":true,"flushed":12}"#);
+
+        let payload: AdminPayload = serde_json::from_str(
+            r#"{"timestamp": 1589000000, "nonce": "a1b2", "op": "SetFeatureSwitch", "feature": "keyExchange", "enabled": false}"#).unwrap();
+        assert_eq!(payload.op, AdminOp::SetFeatureSwitch { feature: Feature::KeyExchange, enabled: false });
+        assert_eq!(payload.op.name(), "SetFeatureSwitch");
+
+        let payload: AdminPayload = serde_json::from_str(r#"{"timestamp": 1589000000, "nonce": "a1b2", "tenant": "ch-ge", "op": "Purge"}"#).unwrap();
+        assert_eq!(payload.tenant, Some("ch-ge".to_string()));
+        assert_eq!(serde_json::to_string(&payload).unwrap(), r#"{"timestamp":1589000000,"nonce":"a1b2","tenant":"ch-ge","op":"Purge"}"#);
+    }
+
+    #[test]
+    fn test_authenticate() {
+        let operators = operators();
+        let request = payload(1000, "first").sign("1".to_string(), &operator()).unwrap();
+        let (index, payload) = operators.authenticate(&request, 1010).unwrap();
+        assert_eq!(index, 0);
+        assert_eq!(payload.op, AdminOp::SetLogLevel { level: "debug".to_string() });
+
+        // Replayed
+        assert!(operators.authenticate(&request, 1020).is_err());
+        // Too old or from the future
+        let stale = super::payload(1000, "second").sign("2".to_string(), &operator()).unwrap();
+        assert!(operators.authenticate(&stale, 1061).is_err());
+        assert!(operators.authenticate(&stale, 939).is_err());
+    }
+
+    #[test]
+    fn test_authenticate_rejects_others() {
+        let operators = operators();
+        let stranger = KeyPair::from_slice(&[9u8; 32]).unwrap();
+        let request = payload(1000, "a").sign("1".to_string(), &stranger).unwrap();
+        assert!(operators.authenticate(&request, 1000).is_err());
+
+        // The signature covers the payload, changing the operation breaks it
+        let mut request = payload(1000, "b").sign("2".to_string(), &operator()).unwrap();
+        request.payload = request.payload.replace("SetLogLevel", "RotateKeys");
+        assert!(operators.authenticate(&request, 1000).is_err());
+    }
+
+    #[test]
+    fn test_tenant_operators() {
+        let operators = operators();
+        let purge = |nonce: &str, tenant: Option<&str>| {
+            AdminPayload { timestamp: 1000, nonce: nonce.to_string(), tenant: tenant.map(String::from), op: AdminOp::Purge }
+        };
+        let (index, payload) = operators.authenticate(&purge("a", Some("ch-ge")).sign("1".to_string(), &tenant_operator()).unwrap(), 1000).unwrap();
+        assert_eq!((index, payload.tenant), (1, Some("ch-ge".to_string())));
+
+        // Only their own records, and nothing else
+        assert!(operators.authenticate(&purge("b", None).sign("2".to_string(), &tenant_operator()).unwrap(), 1000).is_err());
+        let request = payload(1000, "c").sign("3".to_string(), &tenant_operator()).unwrap();
+        assert!(operators.authenticate(&request, 1000).is_err());
+
+        // The node operators purge any tenant, not one that isn't configured
+        operators.authenticate(&purge("d", Some("ch-ge")).sign("4".to_string(), &operator()).unwrap(), 1000).unwrap();
+        assert!(operators.authenticate(&purge("e", Some("ch-vd")).sign("5".to_string(), &operator()).unwrap(), 1000).is_err());
+        let mut other = payload(1000, "f");
+        other.tenant = Some("ch-ge".to_string());
+        assert!(operators.authenticate(&other.sign("6".to_string(), &operator()).unwrap(), 1000).is_err());
+    }
+
+    #[test]
+    fn test_quorum() {
+        let second = KeyPair::from_slice(&[10u8; 32]).unwrap();
+        let config = AdminConfig { operators: vec![operator().get_pubkey().to_hex(), second.get_pubkey().to_hex()], quorum: 2, ..AdminConfig::default() };
+        let tenants = vec![TenantConfig { id: "ch-ge".to_string(), operators: vec![tenant_operator().get_pubkey().to_hex()], ..TenantConfig::default() }];
+        let operators = Operators::from_config(&config, &tenants).unwrap();
+        assert!(AdminOp::Purge.is_privileged() && !AdminOp::DumpMetrics.is_privileged());
+
+        let purge = AdminPayload { timestamp: 1000, nonce: "a".to_string(), tenant: None, op: AdminOp::Purge };
+        let mut request = purge.sign("1".to_string(), &operator()).unwrap();
+        let (index, payload) = operators.authenticate(&request, 1000).unwrap();
+        // A single operator doesn't reach it, nor twice the same one
+        assert!(operators.approvals(&request, index, &payload).is_err());
+        request.cosign(&operator()).unwrap();
+        assert!(operators.approvals(&request, index, &payload).is_err());
+
+        request.signatures.clear();
+        request.cosign(&second).unwrap();
+        assert_eq!(operators.approvals(&request, index, &payload).unwrap().len(), 2);
+        // The signatures travel with the request
+        let decoded: AdminRequest = serde_json::from_str(&serde_json::to_string(&request).unwrap()).unwrap();
+        assert_eq!(decoded, request);
+
+        // The operator of a tenant only counts for purging that tenant
+        request.signatures = Vec::new();
+        request.cosign(&tenant_operator()).unwrap();
+        assert!(operators.approvals(&request, index, &payload).is_err());
+    }
+}
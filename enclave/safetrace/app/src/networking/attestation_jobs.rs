@@ -0,0 +1,333 @@
+use crate::attestation::{bundle, AttestationProvider};
+use crate::common_u::errors::IasUnavailableErr;
+use crate::config::IasConfig;
+use crate::esgx::equote;
+use crate::networking::ipc_listener::IpcContext;
+use crate::networking::jobs::Events;
+use crate::networking::messages::EnclaveReport;
+use failure::Error;
+use hex::ToHex;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Registrations that came while IAS was down. `GetEnclaveReport` fails once the IAS client ran out of
+// retries (`ias.retries`), unless `ias.retry_store` is set: then the quote is kept there as a job, the
+// client gets the job instead of the report (`{"result": {"job": {"jobId": ..., "state": "Pending"}}}`), and
+// `run` asks IAS again every `ias.retry_interval` seconds until it answers. The store is a JSON file
+// rewritten on every change, the jobs survive a restart. A job that got its report is published on the
+// `[jobs] events` socket with the job id as the topic, and kept `ias.retry_retention` seconds for the
+// client to collect with `GetAttestationJob`.
+//
+// A quote only vouches for the enclave that produced it: after a restart with another signing key (e.g. an
+// upgraded enclave), the pending jobs get a quote of the running enclave before IAS is asked again.
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum AttestationState {
+    Pending,
+    Done,
+    // IAS refused the quote, asking again won't change that
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AttestationJob {
+    #[serde(rename = "jobId")] pub job_id: String,
+    pub state: AttestationState,
+    // Times IAS was asked, each time with the retries of `ias.retries`
+    pub attempts: u32,
+    // While `Pending`, in seconds
+    #[serde(rename = "nextAttempt", default, skip_serializing_if = "Option::is_none")] pub next_attempt: Option<u64>,
+    // Why the last attempt failed
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub error: Option<String>,
+    // Once `Done`, what `GetEnclaveReport` answers
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub report: Option<EnclaveReport>,
+}
+
+impl AttestationJob {
+    fn finished(&self) -> bool {
+        self.state != AttestationState::Pending
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+struct Entry {
+    job: AttestationJob,
+    // Base64, as sent to IAS
+    quote: String,
+    #[serde(rename = "signingKey")] signing_key: String,
+    // When the job last changed, in seconds
+    updated: u64,
+}
+
+pub struct AttestationJobs {
+    path: Option<PathBuf>,
+    interval: u64,
+    retention: u64,
+    max_pending: usize,
+    entries: Mutex<Vec<Entry>>,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn read(path: &Path) -> Result<Vec<Entry>, Error> {
+    match fs::read_to_string(path) {
+        Ok(content) => serde_json::from_str(&content).map_err(|e| format_err!("{}: {}", path.display(), e)),
+        Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+impl AttestationJobs {
+    // Reads the jobs a previous run left in `ias.retry_store`.
+    pub fn open(config: &IasConfig) -> Result<Self, Error> {
+        let path = if config.retry_store.is_empty() { None } else { Some(PathBuf::from(&config.retry_store)) };
+        let entries = match &path {
+            Some(path) => read(path)?,
+            None => Vec::new(),
+        };
+        let pending = entries.iter().filter(|entry| !entry.job.finished()).count();
+        if pending > 0 {
+            info!("{} attestation jobs are still waiting for IAS", pending);
+        }
+        Ok(AttestationJobs {
+            path,
+            interval: config.retry_interval,
+            retention: config.retry_retention,
+            max_pending: config.max_pending,
+            entries: Mutex::new(entries),
+        })
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.path.is_some()
+    }
+
+    // The quotes still waiting for IAS
+    pub fn pending(&self) -> usize {
+        self.lock().iter().filter(|entry| !entry.job.finished()).count()
+    }
+
+    fn lock(&self) -> MutexGuard<Vec<Entry>> {
+        let mut entries = self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        // Forgets the reports nobody collected in time
+        let (now, retention) = (now(), self.retention);
+        entries.retain(|entry| !entry.job.finished() || entry.updated + retention >= now);
+        entries
+    }
+
+    // Written aside then renamed, the store is never left half written.
+    fn save(&self, entries: &[Entry]) -> Result<(), Error> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut temporary = path.as_os_str().to_owned();
+        temporary.push(".tmp");
+        fs::write(&temporary, serde_json::to_vec(entries)?)?;
+        fs::rename(&temporary, path)?;
+        Ok(())
+    }
+
+    // Keeps a quote IAS couldn't be asked about, `error` is why.
+    pub fn queue(&self, quote: String, signing_key: String, error: &Error) -> Result<AttestationJob, Error> {
+        let mut entries = self.lock();
+        if entries.iter().filter(|entry| !entry.job.finished()).count() >= self.max_pending {
+            bail!("Too many attestations are waiting for IAS, try again later");
+        }
+        let job = AttestationJob {
+            job_id: rand::random::<[u8; 16]>().to_hex(),
+            state: AttestationState::Pending,
+            attempts: 1,
+            next_attempt: Some(now() + self.interval),
+            error: Some(error.to_string()),
+            report: None,
+        };
+        entries.push(Entry { job: job.clone(), quote, signing_key, updated: now() });
+        // The client would wait for a job nobody retries after a restart
+        if let Err(e) = self.save(&entries) {
+            entries.pop();
+            return Err(e);
+        }
+        Ok(job)
+    }
+
+    pub fn get(&self, job_id: &str) -> Result<AttestationJob, Error> {
+        let job_id = job_id.to_lowercase();
+        match self.lock().iter().find(|entry| entry.job.job_id == job_id) {
+            Some(entry) => Ok(entry.job.clone()),
+            None => bail!("Unknown attestation job, or its report expired"),
+        }
+    }
+
+    fn update<F: FnOnce(&mut Entry)>(&self, job_id: &str, change: F) -> Option<AttestationJob> {
+        let mut entries = self.lock();
+        let entry = entries.iter_mut().find(|entry| entry.job.job_id == job_id)?;
+        change(entry);
+        entry.updated = now();
+        let job = entry.job.clone();
+        if let Err(e) = self.save(&entries) {
+            warn!("Saving the attestation jobs failed: {}", e);
+        }
+        Some(job)
+    }
+
+    // The pending jobs due for another attempt, with their quote and signing key.
+    fn due(&self, now: u64) -> Vec<(String, String, String)> {
+        self.lock().iter()
+            .filter(|entry| !entry.job.finished() && entry.job.next_attempt.map_or(true, |at| at <= now))
+            .map(|entry| (entry.job.job_id.clone(), entry.quote.clone(), entry.signing_key.clone()))
+            .collect()
+    }
+
+    // The pending jobs of another enclave, which need a quote of this one.
+    fn stale(&self, signing_key: &str) -> Vec<String> {
+        self.lock().iter()
+            .filter(|entry| !entry.job.finished() && entry.signing_key != signing_key)
+            .map(|entry| entry.job.job_id.clone())
+            .collect()
+    }
+
+    // Asks IAS about the quote of a job once, returns the job once it changed.
+    fn attempt(&self, provider: &dyn AttestationProvider, job_id: &str, quote: String, signing_key: String) -> Option<AttestationJob> {
+        let result = bundle::request(provider, quote);
+        let next_attempt = now() + self.interval;
+        self.update(job_id, |entry| {
+            entry.job.attempts += 1;
+            match result {
+                Ok(bundle) => {
+                    entry.job.state = AttestationState::Done;
+                    entry.job.next_attempt = None;
+                    entry.job.error = None;
+                    entry.job.report = Some(EnclaveReport::new(signing_key, &bundle));
+                },
+                Err(ref e) if e.downcast_ref::<IasUnavailableErr>().is_some() => {
+                    entry.job.next_attempt = Some(next_attempt);
+                    entry.job.error = Some(e.to_string());
+                },
+                Err(e) => {
+                    entry.job.state = AttestationState::Failed;
+                    entry.job.next_attempt = None;
+                    entry.job.error = Some(e.to_string());
+                },
+            }
+        })
+    }
+}
+
+// Gives the jobs of another enclave a quote of the running one.
+fn refresh(ctx: &IpcContext) -> Result<(), Error> {
+    let eid = ctx.pool.primary();
+    let _thread = ctx.pool.enter(eid);
+    let signing_key: String = equote::get_register_signing_address(eid)?.to_hex();
+    for job_id in ctx.attestation_jobs.stale(&signing_key) {
+        let quote = equote::quote_for(eid, ctx.spid.expose(), &*ctx.attestation)?;
+        let signing_key = signing_key.clone();
+        ctx.attestation_jobs.update(&job_id, |entry| {
+            entry.quote = quote;
+            entry.signing_key = signing_key;
+        });
+    }
+    Ok(())
+}
+
+// Retries the pending jobs until the process exits, meant for a thread of its own.
+pub fn run(ctx: Arc<IpcContext>, events: Arc<Events>) {
+    if let Err(e) = refresh(&ctx) {
+        error!("Quoting the pending attestation jobs again failed: {}", e);
+    }
+    loop {
+        for (job_id, quote, signing_key) in ctx.attestation_jobs.due(now()) {
+            if let Some(job) = ctx.attestation_jobs.attempt(&*ctx.attestation, &job_id, quote, signing_key) {
+                match job.state {
+                    AttestationState::Done => info!("Attestation job {} got its report after {} attempts", job_id, job.attempts),
+                    AttestationState::Failed => warn!("Attestation job {} failed: {}", job_id, job.error.as_ref().map_or("", String::as_str)),
+                    AttestationState::Pending => {},
+                }
+                events.publish(&job.job_id, &job);
+            }
+        }
+        thread::sleep(Duration::from_secs(1));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::attestation::MockAttestationService;
+    use enigma_tools_u::attestation_service::service::ASResult;
+
+    // Any base64 string does, the mock never parses the quote
+    const QUOTE: &str = "AgAAANoKAAAHAAYAAAAAABYB+Vw5ueowf+qruQGtw+54eaWW7MiyrIAooQw/uU3e";
+
+    struct Unavailable;
+
+    impl AttestationProvider for Unavailable {
+        fn get_report(&self, _quote: String) -> Result<ASResult, Error> {
+            Err(IasUnavailableErr { message: "IAS answered 503 Service Unavailable".to_string() }.into())
+        }
+
+        fn verify_report(&self, _result: &ASResult) -> Result<bool, Error> { Ok(false) }
+    }
+
+    struct Refused;
+
+    impl AttestationProvider for Refused {
+        fn get_report(&self, _quote: String) -> Result<ASResult, Error> {
+            bail!("Invalid quote")
+        }
+
+        fn verify_report(&self, _result: &ASResult) -> Result<bool, Error> { Ok(false) }
+    }
+
+    fn unavailable() -> Error {
+        Unavailable.get_report(QUOTE.to_string()).unwrap_err()
+    }
+
+    #[test]
+    fn test_attestation_jobs_persist() {
+        let path = std::env::temp_dir().join(format!("safetrace-attestation-jobs-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let config = IasConfig { retry_store: path.to_string_lossy().to_string(), max_pending: 2, ..IasConfig::default() };
+
+        let jobs = AttestationJobs::open(&config).unwrap();
+        let job = jobs.queue(QUOTE.to_string(), "ab".repeat(20), &unavailable()).unwrap();
+        assert_eq!((job.state, job.attempts, job.job_id.len()), (AttestationState::Pending, 1, 32));
+        let refused = jobs.queue(QUOTE.to_string(), "ab".repeat(20), &unavailable()).unwrap();
+        assert!(jobs.queue(QUOTE.to_string(), "ab".repeat(20), &unavailable()).is_err());
+        // Not due before `retry_interval`
+        assert!(jobs.due(now()).is_empty());
+
+        // A restart finds both, IAS is still down for the first one
+        let jobs = AttestationJobs::open(&config).unwrap();
+        assert_eq!(jobs.get(&job.job_id.to_uppercase()).unwrap(), job);
+        assert_eq!(jobs.due(now() + config.retry_interval).len(), 2);
+        assert_eq!(jobs.stale(&"ab".repeat(20)), Vec::<String>::new());
+        let retried = jobs.attempt(&Unavailable, &job.job_id, QUOTE.to_string(), "ab".repeat(20)).unwrap();
+        assert_eq!((retried.state, retried.attempts), (AttestationState::Pending, 2));
+        assert!(retried.error.unwrap().contains("503"));
+
+        // Then IAS is back
+        let done = jobs.attempt(&MockAttestationService::new().unwrap(), &job.job_id, QUOTE.to_string(), "ab".repeat(20)).unwrap();
+        assert_eq!((done.state, done.next_attempt, done.error), (AttestationState::Done, None, None));
+        assert_eq!(done.report.as_ref().map(|report| report.signing_key.as_str()), Some("ab".repeat(20).as_str()));
+        let failed = jobs.attempt(&Refused, &refused.job_id, QUOTE.to_string(), "ab".repeat(20)).unwrap();
+        assert_eq!(failed.state, AttestationState::Failed);
+        assert_eq!(AttestationJobs::open(&config).unwrap().get(&job.job_id).unwrap(), done);
+        assert!(jobs.due(now() + 3600).is_empty());
+
+        // Finished jobs are forgotten after the retention
+        for entry in jobs.entries.lock().unwrap().iter_mut() {
+            entry.updated -= config.retry_retention + 1;
+        }
+        assert!(jobs.get(&job.job_id).is_err());
+        fs::remove_file(&path).unwrap();
+
+        let disabled = AttestationJobs::open(&IasConfig::default()).unwrap();
+        assert!(!disabled.enabled());
+    }
+}
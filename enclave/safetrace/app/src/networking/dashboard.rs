@@ -0,0 +1,258 @@
+use crate::attestation::{pib, service, IasContact};
+use crate::esgx::general;
+use crate::networking::dead_letters::Stage;
+use crate::networking::ipc_listener::IpcContext;
+use crate::stats_u;
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// The status of the node in one document, for an operations dashboard to poll: the `GetDashboard` admin
+// operation. Only reads, it never takes the state lock for writing nor waits on IAS:
+//
+//   {"generatedAt": 1589000000, "uptime": 86400, "workers": 2,
+//    "attestation": {"quoteStatus": "GROUP_OUT_OF_DATE", "tcbPsvn": "0a0a...", "tcbEvaluationFlags": 1, "upToDate": false, "checkedAt": 1588990000},
+//    "jobs": {"queuedMatches": 0, "runningMatches": 1, "attestations": 3},
+//    "storage": {"users": 1200, "records": 85000, "bytesSealed": 6800000, "fileBytes": 7100000},
+//    "lastIasContact": {"at": 1588999000, "ok": false, "error": "IAS answered 503 Service Unavailable"},
+//    "errors": [{"minutes": 5, "requests": 840, "failures": 12, "rate": 0.0142, "byStage": {"Handler": 12}}, ...]}
+//
+// `storage` is left out when the enclave didn't answer, the rest comes from the host.
+
+// Minutes the error counts are kept for, the longest window
+const RETAINED: u64 = 60;
+// The windows of `errors`, in minutes
+const WINDOWS: &[u64] = &[1, 5, 60];
+
+// TCB level of the last attestation, `pib::TcbStatus` without the guidance.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct TcbLevel {
+    pub quote_status: String,
+    // The platform info blob IAS sent, none when the platform is up to date
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tcb_psvn: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tcb_evaluation_flags: Option<u16>,
+    // Nothing to update
+    pub up_to_date: bool,
+    pub checked_at: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct PendingJobs {
+    pub queued_matches: usize,
+    pub running_matches: usize,
+    // Quotes waiting for IAS, see `attestation_jobs`
+    pub attestations: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Storage {
+    pub users: u64,
+    pub records: u64,
+    pub bytes_sealed: u64,
+    // Of `data.sealed` with its deltas, none before the first write
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file_bytes: Option<u64>,
+}
+
+// The frames of the last `minutes` and those that failed, as the dead letters count them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct ErrorRate {
+    pub minutes: u64,
+    pub requests: u64,
+    pub failures: u64,
+    // `failures` over `requests`, 0 without requests
+    pub rate: f64,
+    pub by_stage: BTreeMap<Stage, u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Dashboard {
+    pub generated_at: u64,
+    // Seconds since the workers were started, or one was last replaced
+    pub uptime: u64,
+    pub workers: usize,
+    // None until the node was attested
+    pub attestation: Option<TcbLevel>,
+    pub jobs: PendingJobs,
+    pub storage: Option<Storage>,
+    pub last_ias_contact: Option<IasContact>,
+    pub errors: Vec<ErrorRate>,
+}
+
+struct Minute {
+    minute: u64,
+    requests: u64,
+    failures: BTreeMap<Stage, u64>,
+}
+
+// The requests and failures of the IPC socket, per minute over the last hour.
+pub struct ErrorRates {
+    minutes: Mutex<VecDeque<Minute>>,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl ErrorRates {
+    pub fn new() -> Self {
+        ErrorRates { minutes: Mutex::new(VecDeque::new()) }
+    }
+
+    fn count<F: FnOnce(&mut Minute)>(&self, at: u64, change: F) {
+        let mut minutes = self.minutes.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let minute = at / 60;
+        if minutes.back().map(|last| last.minute) != Some(minute) {
+            minutes.push_back(Minute { minute, requests: 0, failures: BTreeMap::new() });
+        }
+        while minutes.front().map_or(false, |first| first.minute + RETAINED <= minute) {
+            minutes.pop_front();
+        }
+        change(minutes.back_mut().expect("the current minute"));
+    }
+
+    pub fn request(&self) {
+        self.request_at(now());
+    }
+
+    fn request_at(&self, at: u64) {
+        self.count(at, |minute| minute.requests += 1);
+    }
+
+    pub fn failure(&self, stage: Stage) {
+        self.failure_at(stage, now());
+    }
+
+    fn failure_at(&self, stage: Stage, at: u64) {
+        self.count(at, |minute| *minute.failures.entry(stage).or_insert(0) += 1);
+    }
+
+    // Over the last `minutes`, the current one included.
+    fn window(&self, minutes: u64, at: u64) -> ErrorRate {
+        let since = (at / 60 + 1).saturating_sub(minutes);
+        let counted = self.minutes.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut rate = ErrorRate { minutes, requests: 0, failures: 0, rate: 0.0, by_stage: BTreeMap::new() };
+        for minute in counted.iter().filter(|minute| minute.minute >= since) {
+            rate.requests += minute.requests;
+            for (stage, failures) in &minute.failures {
+                rate.failures += failures;
+                *rate.by_stage.entry(*stage).or_insert(0) += failures;
+            }
+        }
+        if rate.requests > 0 {
+            rate.rate = rate.failures as f64 / rate.requests as f64;
+        }
+        rate
+    }
+
+    pub fn windows(&self) -> Vec<ErrorRate> {
+        let at = now();
+        WINDOWS.iter().map(|&minutes| self.window(minutes, at)).collect()
+    }
+}
+
+fn tcb_level() -> Option<TcbLevel> {
+    pib::last_status().map(|status| TcbLevel {
+        tcb_psvn: status.platform.as_ref().map(|platform| platform.latest_equivalent_tcb_psvn.clone()),
+        tcb_evaluation_flags: status.platform.as_ref().map(|platform| platform.tcb_evaluation_flags),
+        up_to_date: status.guidance.is_empty(),
+        quote_status: status.quote_status,
+        checked_at: status.checked_at,
+    })
+}
+
+fn storage(ctx: &IpcContext) -> Option<Storage> {
+    let stats = {
+        let _state = ctx.pool.read_state();
+        let _thread = ctx.pool.enter(ctx.pool.primary());
+        stats_u::get_stats(ctx.pool.primary())
+    };
+    match stats {
+        Ok(stats) => Some(Storage {
+            users: stats.users,
+            records: stats.records,
+            bytes_sealed: stats.bytes_sealed,
+            file_bytes: fs::metadata(general::DATA_FILE).ok().map(|metadata| metadata.len()),
+        }),
+        Err(e) => {
+            warn!("The dashboard has no storage statistics: {}", e);
+            None
+        },
+    }
+}
+
+pub fn collect(ctx: &IpcContext) -> Dashboard {
+    let (queued_matches, running_matches) = ctx.jobs.pending();
+    Dashboard {
+        generated_at: now(),
+        uptime: ctx.pool.uptime().as_secs(),
+        workers: ctx.pool.len(),
+        attestation: tcb_level(),
+        jobs: PendingJobs { queued_matches, running_matches, attestations: ctx.attestation_jobs.pending() },
+        storage: storage(ctx),
+        last_ias_contact: service::last_contact(),
+        errors: ctx.errors.windows(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_error_rates() {
+        let rates = ErrorRates::new();
+        let start = 1_589_000_000 / 60 * 60;
+        for _ in 0..8 {
+            rates.request_at(start);
+        }
+        rates.failure_at(Stage::Handler, start + 10);
+        // Four minutes later
+        for _ in 0..2 {
+            rates.request_at(start + 240);
+        }
+        rates.failure_at(Stage::Parse, start + 250);
+        rates.failure_at(Stage::Handler, start + 250);
+
+        let last = rates.window(1, start + 250);
+        assert_eq!((last.requests, last.failures, last.rate), (2, 2, 1.0));
+        let five = rates.window(5, start + 250);
+        assert_eq!((five.requests, five.failures), (10, 3));
+        assert!((five.rate - 0.3).abs() < 1e-9);
+        assert_eq!(five.by_stage.get(&Stage::Handler), Some(&2));
+        assert_eq!(five.by_stage.get(&Stage::Parse), Some(&1));
+
+        // Past the hour, only the latest minute is left
+        rates.request_at(start + 3600 + 240);
+        let hour = rates.window(60, start + 3600 + 240);
+        assert_eq!((hour.requests, hour.failures), (1, 0));
+        assert_eq!(rates.minutes.lock().unwrap().len(), 1);
+        assert_eq!(rates.window(5, start).rate, 0.0);
+    }
+
+    #[test]
+    fn test_dashboard_format() {
+        let dashboard = Dashboard {
+            generated_at: 1_589_000_000, uptime: 86400, workers: 2, attestation: None,
+            jobs: PendingJobs { queued_matches: 0, running_matches: 1, attestations: 3 },
+            storage: Some(Storage { users: 1200, records: 85000, bytes_sealed: 6_800_000, file_bytes: None }),
+            last_ias_contact: Some(IasContact { at: 1_588_999_000, ok: true, error: None }),
+            errors: vec![ErrorRate { minutes: 5, requests: 0, failures: 0, rate: 0.0, by_stage: BTreeMap::new() }],
+        };
+        let json = serde_json::to_value(&dashboard).unwrap();
+        assert_eq!(json["jobs"]["runningMatches"], 1);
+        assert_eq!(json["storage"]["bytesSealed"], 6_800_000);
+        assert!(json["storage"].get("fileBytes").is_none());
+        assert_eq!(json["lastIasContact"], serde_json::json!({"at": 1_588_999_000u64, "ok": true}));
+        assert_eq!(json["errors"][0]["byStage"], serde_json::json!({}));
+        assert_eq!(serde_json::from_value::<Dashboard>(json).unwrap(), dashboard);
+    }
+}
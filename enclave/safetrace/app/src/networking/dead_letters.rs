@@ -0,0 +1,243 @@
+use crate::config::DeadLetterConfig;
+use failure::Error;
+use hex::{FromHex, ToHex};
+use safetrace_client::tags::FrameKey;
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// The dead-letter sink: every IPC frame that couldn't be parsed, decoded, validated or handled is kept
+// with the error, instead of only being logged, so operators can look at it and replay it once the cause
+// is fixed (`safetrace-app dead-letters`). One JSON line per failure, appended to `dead_letters.path` and
+// published on the PUB socket `dead_letters.bind`:
+//
+//   {"timestamp": 1589000000123, "stage": "Handler", "command": "FindMatch", "error": "...", "frame": "{\"id\": ...}"}
+//
+// `frame` is the frame as received when it's UTF-8, `frameHex` otherwise. A JSON-RPC batch is one frame:
+// each request of it that fails gives a line with the whole frame. Refusals the client is told about and
+// can't be replayed away (a switched off feature, a retired command) aren't dead letters.
+
+// How long a replay waits for each answer, in milliseconds
+const REPLAY_TIMEOUT: i32 = 30_000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Stage {
+    // Not JSON
+    Parse,
+    // JSON but no request: unknown type or method, missing or mistyped fields
+    Decode,
+    // Refused by `validation`
+    Validation,
+    // The handler or its ecalls failed
+    Handler,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DeadLetter {
+    // Milliseconds
+    pub timestamp: u64,
+    pub stage: Stage,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub command: Option<String>,
+    pub error: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub frame: Option<String>,
+    #[serde(rename = "frameHex", default, skip_serializing_if = "Option::is_none")]
+    pub frame_hex: Option<String>,
+    // Cut at `dead_letters.max_frame_size`, it can't be replayed
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub truncated: bool,
+}
+
+pub struct DeadLetters {
+    config: DeadLetterConfig,
+    file: Mutex<Option<File>>,
+    socket: Option<Mutex<zmq::Socket>>,
+    _context: zmq::Context,
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() * 1000 + u64::from(d.subsec_millis())).unwrap_or(0)
+}
+
+fn rotated(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".1");
+    PathBuf::from(name)
+}
+
+impl DeadLetter {
+    pub fn new(stage: Stage, command: Option<&str>, error: &str, frame: &[u8], max_frame_size: usize) -> Self {
+        let truncated = frame.len() > max_frame_size;
+        let kept = &frame[..frame.len().min(max_frame_size)];
+        let (frame, frame_hex) = match std::str::from_utf8(kept) {
+            Ok(text) => (Some(text.to_string()), None),
+            Err(_) => (None, Some(kept.to_hex())),
+        };
+        DeadLetter { timestamp: now_millis(), stage, command: command.map(String::from), error: error.to_string(), frame, frame_hex, truncated }
+    }
+
+    // The frame as it was received, `None` once truncated.
+    pub fn frame_bytes(&self) -> Option<Vec<u8>> {
+        if self.truncated {
+            return None;
+        }
+        match (&self.frame, &self.frame_hex) {
+            (Some(text), _) => Some(text.as_bytes().to_vec()),
+            (None, Some(hex)) => hex.from_hex().ok(),
+            (None, None) => None,
+        }
+    }
+}
+
+impl DeadLetters {
+    pub fn open(config: &DeadLetterConfig) -> Result<Self, Error> {
+        let file = if config.path.is_empty() { None } else { Some(OpenOptions::new().create(true).append(true).open(&config.path)?) };
+        let context = zmq::Context::new();
+        let socket = if config.bind.is_empty() {
+            None
+        } else {
+            let socket = context.socket(zmq::PUB)?;
+            socket.set_linger(0)?;
+            socket.bind(&config.bind)?;
+            println!("Dead letters published on: {}", config.bind);
+            Some(Mutex::new(socket))
+        };
+        Ok(DeadLetters { config: config.clone(), file: Mutex::new(file), socket, _context: context })
+    }
+
+    fn lock(&self) -> MutexGuard<Option<File>> {
+        self.file.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Moves the file aside once the line wouldn't fit anymore.
+    fn rotate(&self, file: &mut Option<File>, line: usize) -> Result<(), Error> {
+        let size = match file {
+            Some(file) => file.metadata()?.len(),
+            None => return Ok(()),
+        };
+        if size == 0 || size + line as u64 <= self.config.max_file_size {
+            return Ok(());
+        }
+        let path = Path::new(&self.config.path);
+        fs::rename(path, rotated(path))?;
+        *file = Some(OpenOptions::new().create(true).append(true).open(path)?);
+        Ok(())
+    }
+
+    // Keeps a failed frame. Like the failure itself this is reported, never fatal.
+    pub fn record(&self, stage: Stage, command: Option<&str>, error: &str, frame: &[u8]) {
+        if !self.config.enabled() {
+            return;
+        }
+        let letter = DeadLetter::new(stage, command, error, frame, self.config.max_frame_size);
+        let mut line = serde_json::to_vec(&letter).unwrap();
+        line.push(b'\n');
+        {
+            let mut file = self.lock();
+            let written = self.rotate(&mut file, line.len())
+                .and_then(|_| file.as_mut().map_or(Ok(()), |file| file.write_all(&line)).map_err(Error::from));
+            if let Err(e) = written {
+                error!("Writing a dead letter to {} failed: {}", self.config.path, e);
+            }
+        }
+        if let Some(socket) = &self.socket {
+            let socket = socket.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            // Nobody subscribed, or a slow one: the line is dropped, not waited for
+            if let Err(e) = socket.send(&line[..line.len() - 1], zmq::DONTWAIT) {
+                if e != zmq::Error::EAGAIN {
+                    warn!("Publishing a dead letter failed: {}", e);
+                }
+            }
+        }
+    }
+}
+
+// The dead letters of a file, oldest first, the rotated file before the current one.
+pub fn read(path: &Path) -> Result<Vec<DeadLetter>, Error> {
+    let mut letters = Vec::new();
+    for path in &[rotated(path), path.to_path_buf()] {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e.into()),
+        };
+        for (i, line) in content.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+            letters.push(serde_json::from_str(line).map_err(|e| format_err!("{} line {}: {}", path.display(), i + 1, e))?);
+        }
+    }
+    Ok(letters)
+}
+
+// How many letters failed at each stage and command, for the listing.
+pub fn summary(letters: &[DeadLetter]) -> BTreeMap<(Stage, String), usize> {
+    let mut counts = BTreeMap::new();
+    for letter in letters {
+        *counts.entry((letter.stage, letter.command.clone().unwrap_or_default())).or_insert(0) += 1;
+    }
+    counts
+}
+
+// Sends the frame of a letter to the IPC socket again, tagged with `key` if the socket wants it, returns the answer.
+pub fn replay(endpoint: &str, letter: &DeadLetter, key: Option<&FrameKey>) -> Result<Vec<u8>, Error> {
+    let frame = letter.frame_bytes().ok_or_else(|| format_err!("the frame was truncated, it can't be replayed"))?;
+    let context = zmq::Context::new();
+    let socket = context.socket(zmq::REQ)?;
+    socket.set_rcvtimeo(REPLAY_TIMEOUT)?;
+    socket.set_linger(0)?;
+    socket.connect(endpoint)?;
+    socket.send(key.map_or(frame.clone(), |key| key.tag(&frame)), 0)?;
+    let reply = socket.recv_msg(0)?;
+    match key {
+        Some(key) => Ok(key.verify(&reply).ok_or_else(|| format_err!("the tag of the answer doesn't verify"))?.to_vec()),
+        None => Ok(reply.to_vec()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_dead_letter_frames() {
+        let letter = DeadLetter::new(Stage::Decode, None, "unknown variant `Nope`", br#"{"id":"1","type":"Nope"}"#, 1024);
+        let json = serde_json::to_string(&letter).unwrap();
+        assert!(json.contains(r#""stage":"Decode","error":"unknown variant `Nope`","frame":"{\"id\":\"1\",\"type\":\"Nope\"}"}"#), "{}", json);
+        assert_eq!(serde_json::from_str::<DeadLetter>(&json).unwrap(), letter);
+        assert_eq!(letter.frame_bytes().unwrap(), br#"{"id":"1","type":"Nope"}"#.to_vec());
+
+        let binary = DeadLetter::new(Stage::Parse, None, "expected value", &[0x81, 0xa2, 0xff], 1024);
+        assert_eq!((binary.frame.is_none(), binary.frame_hex.as_ref().map(String::as_str)), (true, Some("81a2ff")));
+        assert_eq!(binary.frame_bytes().unwrap(), vec![0x81, 0xa2, 0xff]);
+
+        let cut = DeadLetter::new(Stage::Handler, Some("AddPersonalData"), "EnclaveFailError", &[b'a'; 10], 4);
+        assert_eq!((cut.frame.as_ref().map(String::as_str), cut.truncated), (Some("aaaa"), true));
+        assert_eq!(cut.frame_bytes(), None);
+    }
+
+    #[test]
+    fn test_dead_letter_file() {
+        let path = std::env::temp_dir().join(format!("safetrace-dead-letters-{}.log", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(rotated(&path));
+        let config = DeadLetterConfig { path: path.to_string_lossy().to_string(), max_file_size: 250, ..DeadLetterConfig::default() };
+
+        let letters = DeadLetters::open(&config).unwrap();
+        letters.record(Stage::Parse, None, "expected value", b"not json");
+        letters.record(Stage::Handler, Some("FindMatch"), "the enclave failed", br#"{"id":"2","type":"FindMatch"}"#);
+        letters.record(Stage::Handler, Some("FindMatch"), "the enclave failed", br#"{"id":"3","type":"FindMatch"}"#);
+        // The third line would take the file past 250 bytes, the first two were moved aside and are still read
+        assert!(rotated(&path).exists());
+        let read = read(&path).unwrap();
+        assert_eq!(read.iter().map(|letter| letter.stage).collect::<Vec<_>>(), vec![Stage::Parse, Stage::Handler, Stage::Handler]);
+        assert_eq!(summary(&read).get(&(Stage::Handler, "FindMatch".to_string())), Some(&2));
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(rotated(&path)).unwrap();
+
+        let disabled = DeadLetters::open(&DeadLetterConfig::default()).unwrap();
+        disabled.record(Stage::Parse, None, "expected value", b"not json");
+    }
+}
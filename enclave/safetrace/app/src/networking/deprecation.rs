@@ -0,0 +1,99 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// A command (or a legacy encoding of it) we intend to remove.
+// Until `sunset` every response to it carries a `DeprecationNotice`, from then on it's refused.
+pub struct Deprecation {
+    pub command: &'static str,
+    // ISO 8601 date (`YYYY-MM-DD`, UTC) from which the command is refused.
+    pub sunset: &'static str,
+    pub replacement: Option<&'static str>,
+    pub message: &'static str,
+}
+
+// Nothing is scheduled for removal yet. Add an entry here at least one release before removing a command.
+pub const DEPRECATIONS: &[Deprecation] = &[];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DeprecationNotice {
+    pub command: String,
+    pub sunset: String,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub replacement: Option<String>,
+    pub message: String,
+}
+
+impl<'a> From<&'a Deprecation> for DeprecationNotice {
+    fn from(d: &'a Deprecation) -> Self {
+        DeprecationNotice {
+            command: d.command.to_string(),
+            sunset: d.sunset.to_string(),
+            replacement: d.replacement.map(String::from),
+            message: d.message.to_string(),
+        }
+    }
+}
+
+#[derive(Fail, Debug)]
+#[fail(display = "{} was removed on {}{}", command, sunset, hint)]
+pub struct SunsetErr {
+    pub command: String,
+    pub sunset: String,
+    hint: String,
+}
+
+// Today's UTC date as `YYYY-MM-DD`, which compares correctly as a string.
+pub fn today() -> String {
+    let days = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() / 86400).unwrap_or(0) as i64;
+    // Civil date from days since the epoch (Howard Hinnant's `civil_from_days`)
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+// Notices to attach to the response of `command`, or an error if it's past its sunset.
+pub fn check(registry: &[Deprecation], command: &str, today: &str) -> Result<Vec<DeprecationNotice>, SunsetErr> {
+    let mut notices = Vec::new();
+    for deprecation in registry.iter().filter(|d| d.command == command) {
+        if today >= deprecation.sunset {
+            let hint = deprecation.replacement.map(|r| format!(", use {} instead", r)).unwrap_or_default();
+            return Err(SunsetErr { command: command.to_string(), sunset: deprecation.sunset.to_string(), hint });
+        }
+        notices.push(deprecation.into());
+    }
+    Ok(notices)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const REGISTRY: &[Deprecation] = &[Deprecation {
+        command: "Ping",
+        sunset: "2021-01-01",
+        replacement: Some("GetHealth"),
+        message: "Ping is superseded by GetHealth",
+    }];
+
+    #[test]
+    fn test_deprecation_notice_then_sunset() {
+        assert!(check(REGISTRY, "GetHealth", "2020-06-01").unwrap().is_empty());
+        assert_eq!(check(REGISTRY, "Ping", "2020-12-31").unwrap()[0].replacement, Some("GetHealth".to_string()));
+        let err = check(REGISTRY, "Ping", "2021-01-01").unwrap_err();
+        assert_eq!(err.to_string(), "Ping was removed on 2021-01-01, use GetHealth instead");
+    }
+
+    #[test]
+    fn test_today_format() {
+        let today = today();
+        assert_eq!(today.len(), 10);
+        assert!(today.as_str() > "2020-01-01");
+    }
+}
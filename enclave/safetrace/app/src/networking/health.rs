@@ -0,0 +1,158 @@
+use crate::esgx::{equote, general};
+use crate::networking::ipc_listener::IpcContext;
+use crate::stats_u;
+use failure::Error;
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HealthCheck {
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub detail: Option<String>,
+}
+
+impl HealthCheck {
+    fn pass() -> Self { HealthCheck { ok: true, detail: None } }
+
+    fn from_result<E: ToString>(result: Result<(), E>) -> Self {
+        match result {
+            Ok(()) => Self::pass(),
+            Err(e) => HealthCheck { ok: false, detail: Some(e.to_string()) },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BuildInfo {
+    pub version: String,
+    pub simulation: bool,
+}
+
+impl BuildInfo {
+    pub fn current() -> Self {
+        BuildInfo { version: env!("CARGO_PKG_VERSION").to_string(), simulation: general::is_simulation() }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// Every enclave of the pool answers a trivial ecall.
+pub fn enclaves_alive(ctx: &IpcContext) -> HealthCheck {
+    HealthCheck::from_result(ctx.pool.eids().into_iter().map(|eid| {
+        let _thread = ctx.pool.enter(eid);
+        equote::get_register_signing_address(eid).map(|_| ()).map_err(|e| format!("enclave {}: {}", eid, e))
+    }).collect::<Result<(), String>>())
+}
+
+// The sealed store is either absent (nothing ingested yet) or a file the enclave can unseal.
+pub fn storage_healthy(ctx: &IpcContext) -> HealthCheck {
+    match fs::metadata(general::DATA_FILE) {
+        Ok(ref meta) if !meta.is_file() => return HealthCheck { ok: false, detail: Some(format!("{} isn't a file", general::DATA_FILE)) },
+        Err(ref e) if e.kind() != std::io::ErrorKind::NotFound => return HealthCheck::from_result(Err(e)),
+        _ => {},
+    }
+    let _state = ctx.pool.read_state();
+    let _thread = ctx.pool.enter(ctx.pool.primary());
+    HealthCheck::from_result(stats_u::get_stats(ctx.pool.primary()).map(|_| ()))
+}
+
+fn ias_reachable(ctx: &IpcContext) -> HealthCheck {
+    if general::is_simulation() {
+        return HealthCheck { ok: true, detail: Some("not used in simulation mode".to_string()) };
+    }
+    HealthCheck::from_result(ctx.attestation.check_reachability())
+}
+
+// Liveness: the process and its enclaves respond. Cheap enough to be probed every few seconds.
+pub fn liveness(ctx: &IpcContext) -> BTreeMap<String, HealthCheck> {
+    let mut checks = BTreeMap::new();
+    checks.insert("enclave".to_string(), enclaves_alive(ctx));
+    checks
+}
+
+// Readiness: on top of liveness, the dependencies needed to serve requests are usable, and the node holds its
+// lease when `lease.interval` is set (see `lease`). A node in maintenance isn't, the load balancer moves on.
+pub fn readiness(ctx: &IpcContext) -> BTreeMap<String, HealthCheck> {
+    let mut checks = liveness(ctx);
+    checks.insert("storage".to_string(), storage_healthy(ctx));
+    checks.insert("attestationService".to_string(), ias_reachable(ctx));
+    if ctx.lease.enabled() {
+        checks.insert("lease".to_string(), HealthCheck::from_result(ctx.lease.check(now())));
+    }
+    if let Some(state) = ctx.maintenance.state().as_ref() {
+        checks.insert("maintenance".to_string(), HealthCheck { ok: false, detail: Some(format!("draining since {}", state.since)) });
+    }
+    checks
+}
+
+// Plain HTTP for the probes of container runtimes, on `server.health_bind`: `GET /healthz` is `liveness` and
+// `GET /readyz` is `readiness`, 200 when every check passes and 503 otherwise, with the `GetHealth` result as
+// JSON. Nothing else is served, and one probe is answered at a time.
+pub fn serve_http(bind: &str, ctx: Arc<IpcContext>) -> Result<(), Error> {
+    let listener = TcpListener::bind(bind)?;
+    info!("Serving the health probes on http://{}", bind);
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                if let Err(e) = answer(stream, &ctx) {
+                    debug!("Answering a health probe failed: {}", e);
+                }
+            },
+            Err(e) => warn!("Accepting a health probe failed: {}", e),
+        }
+    }
+    Ok(())
+}
+
+// The path of a `GET` request, without its query.
+fn request_path(request: &str) -> Option<&str> {
+    let mut parts = request.lines().next()?.split_whitespace();
+    match (parts.next(), parts.next()) {
+        (Some("GET"), Some(path)) => path.split('?').next(),
+        _ => None,
+    }
+}
+
+fn probe(checks: BTreeMap<String, HealthCheck>, ctx: &IpcContext) -> (&'static str, String) {
+    let ok = checks.values().all(|check| check.ok);
+    let degraded = ctx.freshness.degraded(now());
+    let body = json!({"ok": ok, "checks": checks, "build": BuildInfo::current(), "degraded": degraded});
+    (if ok { "200 OK" } else { "503 Service Unavailable" }, body.to_string())
+}
+
+fn answer(mut stream: TcpStream, ctx: &IpcContext) -> io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut buffer = [0u8; 1024];
+    let read = stream.read(&mut buffer)?;
+    let request = String::from_utf8_lossy(&buffer[..read]);
+    let (status, body) = match request_path(&request) {
+        Some("/healthz") => probe(liveness(ctx), ctx),
+        Some("/readyz") => probe(readiness(ctx), ctx),
+        Some(_) => ("404 Not Found", "{}".to_string()),
+        None => ("405 Method Not Allowed", "{}".to_string()),
+    };
+    write!(stream, "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+           status, body.len(), body)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_request_path() {
+        assert_eq!(request_path("GET /readyz HTTP/1.1\r\nHost: localhost\r\n\r\n"), Some("/readyz"));
+        assert_eq!(request_path("GET /healthz?verbose=1 HTTP/1.0\r\n"), Some("/healthz"));
+        assert_eq!(request_path("POST /healthz HTTP/1.1\r\n"), None);
+        assert_eq!(request_path(""), None);
+    }
+}
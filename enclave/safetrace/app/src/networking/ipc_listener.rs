@@ -1,55 +1,507 @@
 use crate::networking::messages::*;
-use sgx_types::sgx_enclave_id_t;
-use futures::{Future, Stream};
+use crate::attestation::AttestationProvider;
+use crate::esgx::pool::{is_enclave_lost, EnclavePool};
+use crate::esgx::threads;
+use crate::networking::switches::{Feature, KillSwitches};
+use crate::networking::peer::PeerNode;
+use crate::networking::deprecation::{self, DeprecationNotice, DEPRECATIONS};
+use crate::networking::jobs::{JobQueue, MatchJob};
+use crate::networking::attestation_jobs::AttestationJobs;
+use crate::networking::reattest::Freshness;
+use crate::networking::lease::Lease;
+use crate::networking::precompute::Precompute;
+use crate::networking::planes::{Plane, Planes};
+use crate::networking::router::{Client, ClientId, Multipart, Notifier};
+use crate::networking::dead_letters::{DeadLetters, Stage};
+use crate::networking::dashboard::ErrorRates;
+use crate::networking::maintenance::Maintenance;
+use crate::networking::jsonrpc;
+use crate::networking::validation;
+use crate::cancel_u::{self, Deadline, Timeouts};
+use crate::common_u::errors::{ClientBusyErr, FeatureDisabledErr, PayloadTooLargeErr, ServiceDegradedErr, UnauthenticatedErr, UnknownTenantErr, WrongSocketErr};
+use crate::secrets::Secret;
+use crate::audit_u::AuditLog;
+use crate::identity_u;
+use crate::tenant_u;
+use crate::config::EnclaveConfig;
+use crate::telemetry;
+use safetrace_client::audit::AuditKind;
+use safetrace_client::manifest::EnclaveManifest;
+use safetrace_client::tags::FrameKey;
+use safetrace_client::wire::WireFormat;
+use serde_json::json;
 use std::sync::Arc;
-use tokio_zmq::prelude::*;
-use tokio_zmq::{Error, Multipart, Rep};
 
+// Everything the request handlers share across messages.
+pub struct IpcContext {
+    pub spid: Secret,
+    pub attestation: Box<dyn AttestationProvider>,
+    pub pool: EnclavePool,
+    pub switches: KillSwitches,
+    pub peers: PeerNode,
+    // Announced in `GetProtocolVersion`
+    pub quantization: Option<Quantization>,
+    // `SubmitMatchJob`, run by `jobs::run`
+    pub jobs: JobQueue,
+    // Ingest, feature switches and admin operations are recorded once done
+    pub audit: AuditLog,
+    // `enclave.manifest`, served by `GetEnclaveInfo`
+    pub manifest: Option<EnclaveManifest>,
+    // The enclave files an `Upgrade` may switch to, and the mode they run in
+    pub enclave: EnclaveConfig,
+    // The frames that failed, see `dead_letters`
+    pub dead_letters: DeadLetters,
+    // How long each request may run, see `cancel_u`
+    pub timeouts: Timeouts,
+    // The registrations waiting for IAS, see `attestation_jobs`
+    pub attestation_jobs: AttestationJobs,
+    // The ids of `[[tenants]]`, the ones a request may name
+    pub tenants: Vec<String>,
+    // `server.max_frame_size`, larger frames aren't parsed
+    pub max_frame_size: usize,
+    // The requests and failures of the last hour, for the dashboard
+    pub errors: ErrorRates,
+    // The latest attestation and whether it's fresh, see `reattest`
+    pub freshness: Freshness,
+    // Whether the node is ready, renewed by the heartbeats of `lease`
+    pub lease: Lease,
+    // Wakes the matching in the background on ingest, see `precompute`
+    pub precompute: Precompute,
+    // The control and data sockets, see `planes`
+    pub planes: Planes,
+    // The clients of `server.bind`, for the match jobs submitted with `notify`
+    pub notifier: Notifier,
+    // `server.max_queued`, for the clients past it
+    pub max_queued: usize,
+    // `server.idle_timeout`, announced in `Heartbeat`
+    pub idle_timeout: u64,
+    // The key of `server.frame_key_path`, the frames must be tagged with
+    pub frame_key: Option<FrameKey>,
+    // Whether the data plane is drained for an upgrade, see `maintenance`
+    pub maintenance: Maintenance,
+}
 
-pub struct IpcListener {
-    _context: Arc<zmq::Context>,
-    rep_future: Box<dyn Future<Item = Rep, Error = Error>>,
+// Where a message came from: the socket, None when one socket serves everything, and the client.
+#[derive(Clone, Debug)]
+pub struct Origin {
+    pub plane: Option<Plane>,
+    pub client: Client,
 }
 
-impl IpcListener {
-    pub fn new(conn_str: &str) -> Self {
-        let _context = Arc::new(zmq::Context::new());
-        let rep_future = Rep::builder(_context.clone()).bind(conn_str).build();
-        println!("Binded to socket: {}", conn_str);
-        IpcListener { _context, rep_future }
+// Returns the subsystem a request belongs to, if it can be switched off.
+fn gated_feature(request: &IpcRequest) -> Option<Feature> {
+    match request {
+        IpcRequest::GetEnclaveReport | IpcRequest::GetAttestationJob { .. } | IpcRequest::GetCachedReport { .. } => Some(Feature::Registration),
+        IpcRequest::NewTaskEncryptionKey { .. } => Some(Feature::KeyExchange),
+        IpcRequest::AddPersonalData { .. } | IpcRequest::RegisterUser { .. } | IpcRequest::UpdateUserStatus { .. } => Some(Feature::Ingest),
+        IpcRequest::FindMatch { .. } | IpcRequest::SubmitMatchJob { .. } | IpcRequest::GetMatchJob { .. } => Some(Feature::Matching),
+        IpcRequest::OpenChannel { .. } | IpcRequest::ConnectPeer { .. } |
+        IpcRequest::FindMatchFederated { .. } | IpcRequest::FederatedQuery { .. } => Some(Feature::Federation),
+        IpcRequest::GetFeatureSwitches | IpcRequest::SetFeatureSwitch { .. } |
+        IpcRequest::GetStats | IpcRequest::GetAggregates | IpcRequest::ExportExposureStatistics { .. } | IpcRequest::Ping { .. } |
+        IpcRequest::GetHealth | IpcRequest::GetReadiness | IpcRequest::GetProtocolVersion { .. } |
+        IpcRequest::ExportAuditLog { .. } | IpcRequest::GetEnclaveInfo | IpcRequest::Heartbeat { .. } => None,
     }
+}
 
-    pub fn run<F>(self, f: F) -> impl Future<Item = (), Error = Error>
-    where F: FnMut(Multipart) -> Multipart {
-        self.rep_future.and_then(|rep| {
-            let (sink, stream) = rep.sink_stream(25).split();
-            stream.map(f).forward(sink).map(|(_stream, _sink)| ())
-        })
+// The frames of a message are independent requests, they run side by side on the threads of the enclaves.
+pub fn handle_message(request: Multipart, ctx: &Arc<IpcContext>, origin: &Origin) -> Multipart {
+    let tasks: Vec<_> = request.into_iter().map(|msg| {
+        let (ctx, origin) = (ctx.clone(), origin.clone());
+        move || handle_frame(&msg, &ctx, &origin)
+    }).collect();
+    let mut responses = Multipart::new();
+    for response in threads::scatter(tasks, ctx.planes.workers(origin.plane, ctx.pool.threads())) {
+        responses.push_back(response);
     }
+    responses
 }
 
-pub fn handle_message(request: Multipart, spid: &str, eid: sgx_enclave_id_t, retries: u32) -> Multipart {
-    let mut responses = Multipart::new();
-    for msg in request {
-        let msg: IpcMessageRequest = msg.into();
-        let id = msg.id.clone();
-        let response_msg = match msg.request {
-            IpcRequest::GetEnclaveReport => handling::get_enclave_report(eid, spid, retries),
-            IpcRequest::NewTaskEncryptionKey { userPubKey } => handling::new_task_encryption_key(&userPubKey, eid),
-            IpcRequest::AddPersonalData { input } => handling::add_personal_data(input, eid),
-            IpcRequest::FindMatch { input } => handling::find_match(input, eid),
+// With `server.frame_key_path` set, nothing of a frame is read before its tag verifies, and its answer is tagged.
+// A frame past `server.max_frame_size`, tag included, isn't even hashed.
+fn handle_frame(msg: &[u8], ctx: &Arc<IpcContext>, origin: &Origin) -> zmq::Message {
+    let key = match &ctx.frame_key {
+        Some(key) => key,
+        None => return answer_frame(msg, ctx, origin),
+    };
+    let reply = if msg.len() > ctx.max_frame_size {
+        answer_frame(msg, ctx, origin)
+    } else {
+        match key.verify(msg) {
+            Some(body) => answer_frame(body, ctx, origin),
+            None => unauthenticated(ctx, msg),
+        }
+    };
+    zmq::Message::from(&key.tag(&reply)[..])
+}
+
+// Unsigned and in JSON: a frame from whoever doesn't hold the key doesn't get to cost an ecall.
+fn unauthenticated(ctx: &IpcContext, msg: &[u8]) -> zmq::Message {
+    failed(ctx, Stage::Parse, None, &UnauthenticatedErr.to_string(), msg);
+    let response = Err::<IpcResponse, _>(UnauthenticatedErr).unwrap_or_error();
+    to_message(WireFormat::Json, &IpcMessageResponse::from_response(response, String::new()))
+}
+
+// A frame is JSON or msgpack, and gets its answer in the same encoding, see `WireFormat`.
+fn answer_frame(msg: &[u8], ctx: &Arc<IpcContext>, origin: &Origin) -> zmq::Message {
+    let received_at = handling::now_millis();
+    let format = WireFormat::detect(msg);
+    if msg.len() > ctx.max_frame_size {
+        return too_large(ctx, format, msg);
+    }
+    if let Some(envelope) = decode_frame(msg) {
+        return match envelope {
+            Ok(envelope) => respond(ctx, origin, envelope, received_at, msg),
+            Err(Unreadable { id, command, error }) => {
+                failed(ctx, Stage::Decode, command.as_ref().map(String::as_str), &error.to_string(), msg);
+                let response = Err::<IpcResponse, _>(error).unwrap_or_error();
+                signed_message(ctx, format, IpcMessageResponse::from_response(response, id))
+            },
         };
-        let msg = IpcMessageResponse::from_response(response_msg.unwrap_or_error(), id);
-        responses.push_back(msg.into());
     }
-    responses
+    let doc = match format.decode(msg) {
+        Ok(doc) => doc,
+        Err(e) => {
+            failed(ctx, Stage::Parse, None, &e.to_string(), msg);
+            return to_message(format, &jsonrpc::parse_error(&e));
+        },
+    };
+    if jsonrpc::is_jsonrpc(&doc) {
+        let reject = |error: &jsonrpc::RpcError| {
+            let detail = match &error.data {
+                Some(serde_json::Value::String(data)) => format!("{}: {}", error.message, data),
+                _ => error.message.clone(),
+            };
+            failed(ctx, Stage::Decode, None, &detail, msg);
+        };
+        // Notifications get an empty frame, a REQ client waits for an answer to every message
+        let reply = jsonrpc::handle(doc, |request, timeout_ms, tenant| process(ctx, origin, request, timeout_ms, tenant, received_at, msg), reject);
+        return reply.map_or_else(zmq::Message::new, |reply| to_message(format, &reply));
+    }
+    let id = doc["id"].as_str().unwrap_or_default().to_string();
+    let command = doc["type"].as_str().map(String::from);
+    let envelope: IpcMessageRequest = match serde_json::from_value(doc) {
+        Ok(envelope) => envelope,
+        Err(e) => {
+            failed(ctx, Stage::Decode, command.as_ref().map(String::as_str), &e.to_string(), msg);
+            let response = Err::<IpcResponse, _>(e).unwrap_or_error();
+            return signed_message(ctx, format, IpcMessageResponse::from_response(response, id));
+        },
+    };
+    respond(ctx, origin, envelope, received_at, msg)
+}
+
+// The answer to a frame past `server.max_frame_size`. It isn't parsed, so its id isn't known: the answer is
+// JSON-RPC when the start of the frame looks like it (a batch, or a `jsonrpc` member), a native error otherwise.
+// A failure goes to the dead letters and the error rates. Frames that don't parse nor decode never get to
+// `process`, which counts the others.
+fn failed(ctx: &IpcContext, stage: Stage, command: Option<&str>, error: &str, frame: &[u8]) {
+    if stage == Stage::Parse || stage == Stage::Decode {
+        ctx.errors.request();
+    }
+    ctx.errors.failure(stage);
+    ctx.dead_letters.record(stage, command, error, frame);
+}
+
+fn too_large(ctx: &IpcContext, format: WireFormat, msg: &[u8]) -> zmq::Message {
+    let error = PayloadTooLargeErr { size: msg.len(), limit: ctx.max_frame_size };
+    failed(ctx, Stage::Parse, None, &error.to_string(), msg);
+    let head = &msg[..msg.len().min(256)];
+    let batch = head.iter().find(|b| !b.is_ascii_whitespace()) == Some(&b'[');
+    if batch || head.windows(7).any(|w| w == b"jsonrpc") {
+        return to_message(format, &jsonrpc::payload_too_large(&error));
+    }
+    let response = Err::<IpcResponse, _>(error).unwrap_or_error();
+    signed_message(ctx, format, IpcMessageResponse::from_response(response, String::new()))
+}
+
+// Runs a native envelope, the answer is JSON or msgpack like the frame.
+fn respond(ctx: &Arc<IpcContext>, origin: &Origin, envelope: IpcMessageRequest, received_at: u64, frame: &[u8]) -> zmq::Message {
+    let (response, deprecations) = process(ctx, origin, envelope.request, envelope.timeout_ms, envelope.tenant, received_at, frame);
+    let mut reply = IpcMessageResponse::from_response(response.unwrap_or_error(), envelope.id);
+    reply.deprecations = deprecations;
+    signed_message(ctx, WireFormat::detect(frame), reply)
+}
+
+fn to_message<T: serde::Serialize>(format: WireFormat, reply: &T) -> zmq::Message {
+    zmq::Message::from(&format.encode(reply).unwrap())
+}
+
+// Native answers carry the `identity` of the enclave, JSON-RPC ones keep to the JSON-RPC members. An answer
+// the enclave couldn't sign goes out without it, the clients requiring it refuse it.
+fn signed_message(ctx: &IpcContext, format: WireFormat, mut reply: IpcMessageResponse) -> zmq::Message {
+    match serde_json::to_value(&reply).map_err(failure::Error::from).and_then(|doc| identity_u::sign(&ctx.pool, &doc)) {
+        Ok(identity) => reply.identity = Some(identity),
+        Err(e) => warn!(target: "security", "Signing the answer to {} failed: {}", reply.id, e),
+    }
+    to_message(format, &reply)
+}
+
+// Sends a finished match job to the client that submitted it with `notify`, as the answer to a `GetMatchJob` of
+// it: signed, JSON whatever the client spoke. A client gone meanwhile still finds the job with `GetMatchJob`.
+pub fn notify_job(ctx: &IpcContext, client: &ClientId, job: MatchJob) {
+    let id = job.job_id.clone();
+    let reply = IpcMessageResponse::from_response(IpcResponse::GetMatchJob { result: IpcResults::MatchJob { job } }, id.clone());
+    let mut body = Multipart::new();
+    let message = signed_message(ctx, WireFormat::Json, reply);
+    body.push_back(match &ctx.frame_key {
+        Some(key) => zmq::Message::from(&key.tag(&message)[..]),
+        None => message,
+    });
+    if let Err(e) = ctx.notifier.notify(client, body) {
+        warn!("Notifying the client of match job {} failed: {}", id, e);
+    }
+}
+
+// Runs a request whatever envelope it came in, in a span of the telemetry: its ecalls are spans under it.
+// `frame` is the one the request came in, for the dead letters.
+fn process(ctx: &Arc<IpcContext>, origin: &Origin, request: IpcRequest, timeout_ms: Option<u64>, tenant: Option<String>,
+           received_at: u64, frame: &[u8]) -> (Result<IpcResponse, failure::Error>, Vec<DeprecationNotice>) {
+    ctx.errors.request();
+    let known_tenant = tenant.as_ref().map(String::as_str).filter(|tenant| ctx.tenants.iter().any(|t| t == tenant));
+    let span = telemetry::request(request.command(), known_tenant);
+    let (response, deprecations) = telemetry::scoped(span.context(), || process_request(ctx, origin, request, timeout_ms, tenant, received_at, frame));
+    match &response {
+        Err(e) => span.end(Some(&e.to_string())),
+        Ok(IpcResponse::Error { msg, .. }) => span.end(Some(msg)),
+        Ok(_) => span.end(None),
+    }
+    (response, deprecations)
+}
+
+// The client, socket, feature switches, validation, deprecations, then the handler, within the timeout the request
+// asked for (`timeout_ms`) or the default one, and in the partition of its `tenant`.
+fn process_request(ctx: &Arc<IpcContext>, origin: &Origin, request: IpcRequest, timeout_ms: Option<u64>, tenant: Option<String>,
+                   received_at: u64, frame: &[u8]) -> (Result<IpcResponse, failure::Error>, Vec<DeprecationNotice>) {
+    if origin.client.saturated {
+        return (Err(ClientBusyErr { queued: ctx.max_queued }.into()), Vec::new());
+    }
+    if let Err(e) = ctx.planes.admit(origin.plane, &request) {
+        return (Err(e), Vec::new());
+    }
+    // Held until answered, `GetMaintenance` waits for it
+    let _in_flight = match ctx.maintenance.admit(&request) {
+        Ok(in_flight) => in_flight,
+        Err(e) => return (Err(e.into()), Vec::new()),
+    };
+    if let Some(tenant) = tenant.as_ref().filter(|tenant| !ctx.tenants.contains(tenant)) {
+        return (Err(UnknownTenantErr { tenant: tenant.clone() }.into()), Vec::new());
+    }
+    if let Some(feature) = gated_feature(&request).filter(|&f| !ctx.switches.is_enabled(f)) {
+        return (Err(FeatureDisabledErr { feature }.into()), Vec::new());
+    }
+    // A stale or regressed attestation takes no new clients, the registered ones are still served
+    if let IpcRequest::GetEnclaveReport | IpcRequest::RegisterUser { .. } = request {
+        if let Some(reason) = ctx.freshness.degraded(handling::now_millis() / 1000) {
+            return (Err(ServiceDegradedErr { reason }.into()), Vec::new());
+        }
+    }
+    // Malformed requests never reach the enclave
+    if let Err(e) = validation::validate(&request) {
+        failed(ctx, Stage::Validation, Some(request.command()), &e.to_string(), frame);
+        return (Err(e.into()), Vec::new());
+    }
+    // Deprecated commands get a notice in the envelope, and get refused once past their sunset date
+    let deprecations = match deprecation::check(DEPRECATIONS, request.command(), &deprecation::today()) {
+        Ok(notices) => notices,
+        Err(e) => return (Err(e.into()), Vec::new()),
+    };
+    let response = match ctx.timeouts.for_request(timeout_ms) {
+        Some(timeout) => {
+            let (task_ctx, task_request, span, client) = (ctx.clone(), request.clone(), telemetry::current(), origin.client.id.clone());
+            cancel_u::run(Deadline::after(timeout), move || {
+                let response = telemetry::scoped(span, || {
+                    tenant_u::scoped(tenant.as_ref().map(String::as_str), || run(&task_ctx, task_request.clone(), received_at, &client))
+                });
+                // A request given up on may still have gone through
+                audit(&task_ctx, &task_request, &response);
+                response
+            })
+        },
+        None => {
+            let response = tenant_u::scoped(tenant.as_ref().map(String::as_str), || run(ctx, request.clone(), received_at, &origin.client.id));
+            audit(ctx, &request, &response);
+            response
+        },
+    };
+    match &response {
+        Err(e) => failed(ctx, Stage::Handler, Some(request.command()), &e.to_string(), frame),
+        Ok(IpcResponse::Error { msg, .. }) => failed(ctx, Stage::Handler, Some(request.command()), msg, frame),
+        Ok(_) => {},
+    }
+    (response, deprecations)
+}
+
+fn run(ctx: &IpcContext, request: IpcRequest, received_at: u64, client: &ClientId) -> Result<IpcResponse, failure::Error> {
+    let response = dispatch(ctx, request.clone(), received_at, client);
+    // The enclave was lost (e.g. after S3 sleep): replace it and try once more
+    let lost = match &response {
+        Err(e) => is_enclave_lost(e),
+        Ok(_) => false,
+    };
+    if lost {
+        match ctx.pool.recover() {
+            Ok(replaced) if replaced > 0 => return dispatch(ctx, request, received_at, client),
+            Ok(_) => {},
+            Err(e) => error!("Recovering the enclave pool failed: {}", e),
+        }
+    }
+    response
+}
+
+// Ingest that went through and exports go into the audit log, see `audit_u`. No enclave
+// thread is held anymore, the checkpoint takes one.
+fn audit(ctx: &IpcContext, request: &IpcRequest, response: &Result<IpcResponse, failure::Error>) {
+    let passed = match response {
+        Ok(IpcResponse::AddPersonalData { result: IpcResults::AddPersonalData { status: Status::Passed, .. } }) |
+        Ok(IpcResponse::RegisterUser { result: IpcResults::RegisterUser { status: Status::Passed } }) |
+        Ok(IpcResponse::UpdateUserStatus { result: IpcResults::UpdateUserStatus { status: Status::Passed, .. } }) => true,
+        Ok(IpcResponse::ExportExposureStatistics { .. }) => true,
+        _ => false,
+    };
+    if !passed {
+        return;
+    }
+    match request {
+        IpcRequest::AddPersonalData { .. } | IpcRequest::RegisterUser { .. } | IpcRequest::UpdateUserStatus { .. } => {
+            ctx.audit.record(AuditKind::Ingest, "ipc", json!({"command": request.command()}));
+        },
+        IpcRequest::ExportExposureStatistics { .. } => {
+            if let Ok(IpcResponse::ExportExposureStatistics { result: IpcResults::ExposureExport { bundle } }) = response {
+                ctx.audit.record(AuditKind::Export, "ipc", json!({"command": request.command(), "authority": bundle.authority,
+                    "precision": bundle.precision, "from": bundle.from, "to": bundle.to, "k": bundle.k, "rows": bundle.rows}));
+            }
+        },
+        _ => return,
+    }
+    ctx.audit.maybe_checkpoint(&ctx.pool);
+}
+
+fn dispatch(ctx: &IpcContext, request: IpcRequest, received_at: u64, client: &ClientId) -> Result<IpcResponse, failure::Error> {
+    let (pool, switches) = (&ctx.pool, &ctx.switches);
+    // Every ecall runs on a thread of its worker (`pool.enter`), taken after the state lock
+    match request {
+        IpcRequest::GetEnclaveReport => {
+            let _thread = pool.enter(pool.primary());
+            handling::get_enclave_report(ctx, pool.primary())
+        },
+        IpcRequest::NewTaskEncryptionKey { userPubKey } => {
+            let eid = pool.route(&userPubKey);
+            let _thread = pool.enter(eid);
+            handling::new_task_encryption_key(&userPubKey, eid)
+        },
+        IpcRequest::AddPersonalData { input } => {
+            let eid = pool.route(&input.user_pub_key);
+            let _state = pool.lock_state();
+            let _thread = pool.enter(eid);
+            let response = handling::add_personal_data(input, eid);
+            ctx.precompute.notify();
+            response
+        },
+        IpcRequest::RegisterUser { input } => {
+            let eid = pool.route(&input.user_pub_key);
+            let _state = pool.lock_state();
+            let _thread = pool.enter(eid);
+            handling::register_user(input, eid)
+        },
+        IpcRequest::UpdateUserStatus { input } => {
+            let eid = pool.route(&input.user_pub_key);
+            let _state = pool.lock_state();
+            let _thread = pool.enter(eid);
+            let response = handling::update_user_status(input, eid);
+            ctx.precompute.notify();
+            response
+        },
+        // Matching only reads the store, concurrent queries share the state lock
+        IpcRequest::FindMatch { input } => {
+            let eid = pool.route(&input.user_pub_key);
+            let _state = pool.read_state();
+            let _thread = pool.enter(eid);
+            handling::find_match(ctx, input, eid)
+        },
+        IpcRequest::GetFeatureSwitches => handling::get_feature_switches(switches),
+        // Served on the admin socket, where the operators sign it
+        IpcRequest::SetFeatureSwitch { .. } => Err(WrongSocketErr { command: "SetFeatureSwitch".to_string(), socket: "ipc" }.into()),
+        IpcRequest::OpenChannel { handshake } => {
+            // Later requests from this peer are routed by its address, so they reach the session key
+            let eid = pool.route(&handshake.attestation.signing_key);
+            let _thread = pool.enter(eid);
+            handling::open_channel(ctx, eid, handshake)
+        },
+        IpcRequest::ConnectPeer { uri } => {
+            let _thread = pool.enter(pool.primary());
+            handling::connect_peer(ctx, pool.primary(), &uri)
+        },
+        IpcRequest::FindMatchFederated { input } => {
+            let eid = pool.route(&input.user_pub_key);
+            let _state = pool.read_state();
+            let _thread = pool.enter(eid);
+            handling::find_match_federated(ctx, input, eid)
+        },
+        IpcRequest::FederatedQuery { sender, payload } => {
+            let eid = pool.route(&sender);
+            let _state = pool.read_state();
+            let _thread = pool.enter(eid);
+            handling::federated_query(&sender, &payload, eid)
+        },
+        IpcRequest::Ping { nonce } => handling::ping(nonce, received_at),
+        IpcRequest::Heartbeat { sent_at } => handling::heartbeat(sent_at, received_at, ctx.idle_timeout),
+        IpcRequest::GetHealth => handling::get_health(ctx, false),
+        IpcRequest::GetReadiness => handling::get_health(ctx, true),
+        IpcRequest::GetProtocolVersion { client_version } => {
+            let _thread = pool.enter(pool.primary());
+            handling::get_protocol_version(pool.primary(), client_version, ctx.quantization.clone(), ctx.max_frame_size)
+        },
+        IpcRequest::GetStats => {
+            let _state = pool.read_state();
+            let _thread = pool.enter(pool.primary());
+            handling::get_stats(pool.primary())
+        },
+        IpcRequest::GetAggregates => {
+            let _state = pool.read_state();
+            let _thread = pool.enter(pool.primary());
+            handling::get_aggregates(pool.primary())
+        },
+        IpcRequest::ExportExposureStatistics { request } => {
+            let _state = pool.read_state();
+            let _thread = pool.enter(pool.primary());
+            handling::export_exposure_statistics(pool.primary(), &request)
+        },
+        IpcRequest::SubmitMatchJob { input } => handling::submit_match_job(ctx, input, client),
+        IpcRequest::GetMatchJob { job_id } => handling::get_match_job(&ctx.jobs, &job_id),
+        IpcRequest::ExportAuditLog { from } => handling::export_audit_log(&ctx.audit, from),
+        IpcRequest::GetEnclaveInfo => {
+            let _thread = pool.enter(pool.primary());
+            handling::get_enclave_info(ctx, pool.primary())
+        },
+        IpcRequest::GetAttestationJob { job_id } => handling::get_attestation_job(&ctx.attestation_jobs, &job_id),
+        IpcRequest::GetCachedReport { quote_hash } => handling::get_cached_report(&quote_hash),
+    }
 }
 
 
 pub(self) mod handling {
     use crate::networking::messages::*;
     use crate::keys_u;
-    use crate::esgx::equote;
+    use crate::match_u;
+    use crate::esgx::{chunks, equote, general};
+    use crate::networking::switches::KillSwitches;
+    use crate::networking::peer::{self, ChannelHandshake};
+    use crate::channel_u;
+    use crate::policy_u;
+    use crate::stats_u;
+    use crate::telemetry;
+    use crate::users_u;
+    use crate::tenant_u;
+    use crate::networking::health::{self, BuildInfo};
+    use crate::networking::jobs::{JobQueue, MatchJob};
+    use crate::networking::router::ClientId;
+    use crate::networking::protocol;
+    use crate::audit_u::{self, AuditLog};
+    use crate::esgx::pool;
+    use crate::common_u::errors::{EnclaveFailError, IasUnavailableErr};
+    use super::IpcContext;
+    use std::time::{SystemTime, UNIX_EPOCH};
     use failure::Error;
     use sgx_types::{sgx_enclave_id_t, sgx_status_t};
     use hex::{FromHex, ToHex};
@@ -57,10 +509,9 @@ pub(self) mod handling {
     use rmp_serde::Deserializer;
     use serde::Deserialize;
     use serde_json::Value;
-    use enigma_tools_u::{
-        esgx::equote as equote_tools,
-        attestation_service::{service::AttestationService, constants::ATTESTATION_SERVICE_URL},
-    };
+    use crate::attestation::{bundle, cache, AttestationProvider, Quote, ReportBundle};
+    use crate::networking::attestation_jobs::AttestationJobs;
+    use safetrace_client::manifest::EnclaveManifest;
     use enigma_types::{EnclaveReturn};
 
 
@@ -70,9 +521,26 @@ pub(self) mod handling {
             ret: *mut sgx_status_t,
             encryptedUserId: *const u8,
             encryptedUserId_len: usize,
+            encryptedData_context: u64,
+            encryptedSignature: *const u8,
+            encryptedSignature_len: usize,
+            userPubKey: &[u8; 64],
+            compression: u8,
+            serialized_ptr: *mut u64,
+            rejection: *mut u8) -> sgx_status_t;
+
+        fn ecall_update_user_status(
+            eid: sgx_enclave_id_t,
+            ret: *mut EnclaveReturn,
+            encryptedUserId: *const u8,
+            encryptedUserId_len: usize,
             encryptedData: *const u8,
             encryptedData_len: usize,
-            userPubKey: &[u8; 64]) -> sgx_status_t;
+            encryptedSignature: *const u8,
+            encryptedSignature_len: usize,
+            userPubKey: &[u8; 64],
+            serialized_ptr: *mut u64,
+            rejection: *mut u8) -> sgx_status_t;
     }
 
     extern {
@@ -81,8 +549,14 @@ pub(self) mod handling {
                 ret: *mut sgx_status_t,
                 encryptedUserId: *const u8,
                 encryptedUserId_len: usize,
+                encryptedSignature: *const u8,
+                encryptedSignature_len: usize,
+                strategy: *const u8,
+                strategy_len: usize,
+                incremental: u8,
                 userPubKey: &[u8; 64],
-                serialized_ptr: *mut u64
+                serialized_ptr: *mut u64,
+                strategy_ptr: *mut u64
             ) -> sgx_status_t;
     }
 
@@ -93,32 +567,88 @@ pub(self) mod handling {
         pubkey: Vec<u8>
     }
 
+    // A fresh quote of the enclave, and the report bundle vouching for it.
+    fn attest(eid: sgx_enclave_id_t, spid: &str, provider: &dyn AttestationProvider) -> Result<(String, ReportBundle), Error> {
+        let enc_quote = equote::quote_for(eid, spid, provider)?;
+        let bundle = report(&enc_quote, provider)?;
+        Ok((enc_quote, bundle))
+    }
+
+    fn report(enc_quote: &str, provider: &dyn AttestationProvider) -> Result<ReportBundle, Error> {
+        // *Important* this is decided at *Compile* time.
+        // This means that if you want Simulation mode you need to build with `--features sgx-sim` (or `SGX_MODE=SW`).
+        if general::is_simulation() { // Simulation Mode
+            Ok(ReportBundle { report: enc_quote.to_string(), ..Default::default() })
+        } else { // Hardware Mode
+            bundle::request(provider, enc_quote.to_string())
+        }
+    }
+
     //#[logfn(TRACE)]
-    pub fn get_enclave_report(eid: sgx_enclave_id_t, spid: &str, retries: u32) -> ResponseResult {
+    // While IAS is down the quote is queued in `ctx.attestation_jobs` if they're kept, the client gets the job
+    // to pick the report up later.
+    pub fn get_enclave_report(ctx: &IpcContext, eid: sgx_enclave_id_t) -> ResponseResult {
+
+        let signing_key = equote::get_register_signing_address(eid)?.to_hex();
+
+        let enc_quote = equote::quote_for(eid, ctx.spid.expose(), &*ctx.attestation)?;
+        let bundle = match report(&enc_quote, &*ctx.attestation) {
+            Err(ref e) if ctx.attestation_jobs.enabled() && e.downcast_ref::<IasUnavailableErr>().is_some() => {
+                let job = ctx.attestation_jobs.queue(enc_quote, signing_key, e)?;
+                warn!("IAS is unavailable, the report is retried as attestation job {}", job.job_id);
+                return Ok(IpcResponse::GetEnclaveReport { result: IpcResults::AttestationJob { job } });
+            },
+            result => result?,
+        };
 
-        let signing_key = equote::get_register_signing_address(eid)?;
+        let result = IpcResults::EnclaveReport(EnclaveReport::new(signing_key, &bundle));
+        Ok(IpcResponse::GetEnclaveReport { result })
+    }
 
-        let enc_quote = equote_tools::retry_quote(eid, spid, 18)?;
-        println!("{:?}", enc_quote);
+    pub fn get_attestation_job(jobs: &AttestationJobs, job_id: &str) -> ResponseResult {
+        let job = jobs.get(job_id)?;
+        Ok(IpcResponse::GetAttestationJob { result: IpcResults::AttestationJob { job } })
+    }
 
+    // The report IAS gave for a quote this server sent it, from `ias.report_cache`: never asks IAS again.
+    pub fn get_cached_report(quote_hash: &str) -> ResponseResult {
+        let cached = cache::get(quote_hash)?;
+        Ok(IpcResponse::GetCachedReport { result: IpcResults::CachedReport {
+            quote_hash: cached.quote_hash,
+            quote: cached.quote,
+            bundle: bundle::compact(&cached.bundle),
+            cached_at: cached.cached_at,
+        } })
+    }
 
-        // *Important* `option_env!()` runs on *Compile* time.
-        // This means that if you want Simulation mode you need to run `export SGX_MODE=SW` Before compiling.
-        let (signature, report_hex) = if option_env!("SGX_MODE").unwrap_or_default() == "SW" { // Simulation Mode
-            let report =  enc_quote.as_bytes().to_hex();
-            let sig = String::new();
-            (sig, report)
-        } else { // Hardware Mode
-            let service: AttestationService = AttestationService::new_with_retries(ATTESTATION_SERVICE_URL, retries);
-            let response = service.get_report(enc_quote)?;
-            let report = response.result.report_string.as_bytes().to_hex();
-            let sig = response.result.signature;
-            (sig, report)
-        };
+    // The measurements are read out of the quote the bundle vouches for, so they are what a client verifying
+    // the bundle gets too.
+    pub fn get_enclave_info(ctx: &IpcContext, eid: sgx_enclave_id_t) -> ResponseResult {
+        let signing_key = equote::get_register_signing_address(eid)?;
+        // Read before the quote: a policy applied in between shows as a mismatch, not as a stale document
+        let settings = policy_u::get_settings(eid)?;
+        let (enc_quote, bundle) = attest(eid, ctx.spid.expose(), &*ctx.attestation)?;
+        let quote = Quote::from_base64(&enc_quote)?;
+        let running = EnclaveManifest::from_quote(&quote, Default::default());
+        let manifest_matches = ctx.manifest.as_ref().map(|manifest| manifest.check(&quote).is_ok());
+        if manifest_matches == Some(false) {
+            warn!(target: "security", "The running enclave doesn't match enclave.manifest");
+        }
 
-        let result = IpcResults::EnclaveReport { signing_key: signing_key.to_hex(), report: report_hex, signature };
+        let result = IpcResults::EnclaveInfo {
+            mr_enclave: running.mr_enclave,
+            mr_signer: running.mr_signer,
+            isv_prod_id: running.isv_prod_id,
+            isv_svn: running.isv_svn,
+            debug: running.debug,
+            signing_key: signing_key.to_hex(),
+            bundle: bundle::compact(&bundle),
+            manifest: ctx.manifest.clone(),
+            manifest_matches,
+            settings: Some(settings),
+        };
 
-        Ok(IpcResponse::GetEnclaveReport { result })
+        Ok(IpcResponse::GetEnclaveInfo { result })
     }
 
     // TODO
@@ -143,60 +673,324 @@ pub(self) mod handling {
     pub fn add_personal_data(input: IpcInputData, eid: sgx_enclave_id_t) -> ResponseResult {
 
         let mut ret = sgx_status_t::SGX_SUCCESS;
+        let mut serialized_ptr = 0u64;
+        let mut rejection = 0u8;
         let encrypted_userid = input.encrypted_userid.from_hex()?;
-        let encrypted_data = input.encrypted_data.from_hex()?;
+        let encrypted_signature = input.encrypted_signature.from_hex()?;
         let mut user_pub_key = [0u8; 64];
         user_pub_key.clone_from_slice(&input.user_pub_key.from_hex()?);
+        let compression = input.compression.map_or(0, Compression::code);
 
-        unsafe { ecall_add_personal_data(eid,
+        // The location history goes into the enclave in chunks, decoded on the way, the ecall takes it out
+        let encrypted_data_context = chunks::upload_hex(eid, input.encrypted_data.as_str())?;
+        let status = telemetry::ecall("ecall_add_personal_data", || unsafe { ecall_add_personal_data(eid,
                                          &mut ret as *mut sgx_status_t,
                                          encrypted_userid.as_ptr() as * const u8,
                                          encrypted_userid.len(),
-                                         encrypted_data.as_ptr() as * const u8,
-                                         encrypted_data.len(),
-                                         &user_pub_key) };
+                                         encrypted_data_context,
+                                         encrypted_signature.as_ptr() as * const u8,
+                                         encrypted_signature.len(),
+                                         &user_pub_key,
+                                         compression,
+                                         &mut serialized_ptr as *mut u64,
+                                         &mut rejection as *mut u8) });
+        if pool::is_lost(status) {
+            return Err(EnclaveFailError { err: EnclaveReturn::SgxError, status }.into());
+        }
+        if status != sgx_status_t::SGX_SUCCESS {
+            chunks::discard(eid, encrypted_data_context);
+        }
+        // Without a successful ecall there's no receipt to take back
+        if status != sgx_status_t::SGX_SUCCESS || serialized_ptr == 0 {
+            return Err(EnclaveFailError { err: EnclaveReturn::SgxError, status }.into());
+        }
 
+        // The receipt is encrypted for the user, passed or failed. It's the placeholder byte if the
+        // enclave couldn't get that far (e.g. without a DH key).
+        let box_ptr = serialized_ptr as *mut Box<[u8]>;
+        let part = unsafe { Box::from_raw(box_ptr) };
+        let receipt = if part.len() > 1 { part.to_hex() } else { String::new() };
+
+        let error = Rejection::from_code(rejection);
+        match error {
+            Some(Rejection::CapacityExceeded) => warn!("Refused an addPersonalData submission: the store is at capacity.hard_records"),
+            Some(Rejection::ClockSkew) => warn!("Refused an addPersonalData submission: its locations are ahead of enclave.max_clock_skew"),
+            Some(error) => warn!(target: "security", "Refused an addPersonalData envelope: {:?}", error),
+            None => (),
+        }
         let result;
         if(ret == sgx_status_t::SGX_SUCCESS) {
-            result = IpcResults::AddPersonalData { status: Status::Passed };
+            result = IpcResults::AddPersonalData { status: Status::Passed, encryptedOutput: receipt, error };
         } else {
-            result = IpcResults::AddPersonalData { status: Status::Failed };
+            result = IpcResults::AddPersonalData { status: Status::Failed, encryptedOutput: receipt, error };
         }
         Ok(IpcResponse::AddPersonalData { result })
     }
 
+    // Like `add_personal_data`, the receipt tells the user how many of their locations changed
+    pub fn update_user_status(input: IpcInputData, eid: sgx_enclave_id_t) -> ResponseResult {
+        let mut ret = EnclaveReturn::Success;
+        let mut serialized_ptr = 0u64;
+        let mut rejection = 0u8;
+        let encrypted_userid = input.encrypted_userid.from_hex()?;
+        let encrypted_data = input.encrypted_data.as_str().from_hex()?;
+        let encrypted_signature = input.encrypted_signature.from_hex()?;
+        let mut user_pub_key = [0u8; 64];
+        user_pub_key.clone_from_slice(&input.user_pub_key.from_hex()?);
+
+        let status = telemetry::ecall("ecall_update_user_status", || unsafe { ecall_update_user_status(eid,
+                                         &mut ret as *mut EnclaveReturn,
+                                         encrypted_userid.as_ptr() as * const u8,
+                                         encrypted_userid.len(),
+                                         encrypted_data.as_ptr() as * const u8,
+                                         encrypted_data.len(),
+                                         encrypted_signature.as_ptr() as * const u8,
+                                         encrypted_signature.len(),
+                                         &user_pub_key,
+                                         &mut serialized_ptr as *mut u64,
+                                         &mut rejection as *mut u8) });
+        if pool::is_lost(status) {
+            return Err(EnclaveFailError { err: EnclaveReturn::SgxError, status }.into());
+        }
+        // Without a successful ecall there's no receipt to take back
+        if status != sgx_status_t::SGX_SUCCESS || serialized_ptr == 0 {
+            return Err(EnclaveFailError { err: EnclaveReturn::SgxError, status }.into());
+        }
+
+        let box_ptr = serialized_ptr as *mut Box<[u8]>;
+        let part = unsafe { Box::from_raw(box_ptr) };
+        let receipt = if part.len() > 1 { part.to_hex() } else { String::new() };
+
+        let error = Rejection::from_code(rejection);
+        if let Some(error) = error {
+            warn!(target: "security", "Refused an updateUserStatus envelope: {:?}", error);
+        }
+        let status = if ret == EnclaveReturn::Success { Status::Passed } else { Status::Failed };
+        Ok(IpcResponse::UpdateUserStatus { result: IpcResults::UpdateUserStatus { status, encryptedOutput: receipt, error } })
+    }
+
+    // A refused registration is a security event: either a user lost their key, or someone's claiming their id
+    pub fn register_user(input: IpcInputRegistration, eid: sgx_enclave_id_t) -> ResponseResult {
+        let encrypted_userid = input.encrypted_userid.from_hex()?;
+        let encrypted_data = input.encrypted_data.from_hex()?;
+        let mut user_pub_key = [0u8; 64];
+        user_pub_key.clone_from_slice(&input.user_pub_key.from_hex()?);
+
+        let status = match users_u::register_user(eid, &encrypted_userid, &encrypted_data, &user_pub_key) {
+            Ok(()) => Status::Passed,
+            Err(e) => {
+                if pool::is_enclave_lost(&e) {
+                    return Err(e);
+                }
+                warn!(target: "security", "Refused a user registration: {}", e);
+                Status::Failed
+            },
+        };
+        Ok(IpcResponse::RegisterUser { result: IpcResults::RegisterUser { status } })
+    }
+
     // TODO
     //#[logfn(DEBUG)]
-    pub fn find_match( input: IpcInputMatch, eid: sgx_enclave_id_t) -> ResponseResult {
+    pub fn find_match(ctx: &IpcContext, input: IpcInputMatch, eid: sgx_enclave_id_t) -> ResponseResult {
 
         let mut ret = sgx_status_t::SGX_SUCCESS;
         let mut serialized_ptr = 0u64;
+        let mut strategy_ptr = 0u64;
         let encrypted_userid = input.encrypted_userid.from_hex()?;
+        let encrypted_signature = input.encrypted_signature.from_hex()?;
+        // Empty unless the client named one, the enclave then runs its default and tells which
+        let strategy = match &input.matching {
+            Some(matching) => serde_json::to_vec(matching)?,
+            None => Vec::new(),
+        };
         let mut user_pub_key = [0u8; 64];
         user_pub_key.clone_from_slice(&input.user_pub_key.from_hex()?);
 
-        let status = unsafe { 
+        let status = telemetry::ecall("ecall_find_match", || unsafe {
             ecall_find_match(
                 eid,
                 &mut ret as *mut sgx_status_t,
                 encrypted_userid.as_ptr() as * const u8,
                 encrypted_userid.len(),
+                encrypted_signature.as_ptr() as * const u8,
+                encrypted_signature.len(),
+                strategy.as_ptr(),
+                strategy.len(),
+                input.incremental.unwrap_or(false) as u8,
                 &user_pub_key,
-                &mut serialized_ptr as *mut u64
+                &mut serialized_ptr as *mut u64,
+                &mut strategy_ptr as *mut u64
             )
-        };
+        });
+        if pool::is_lost(status) {
+            return Err(EnclaveFailError { err: EnclaveReturn::SgxError, status }.into());
+        }
+        // Without a successful ecall there's no output to take back
+        if status != sgx_status_t::SGX_SUCCESS || serialized_ptr == 0 {
+            return Err(EnclaveFailError { err: EnclaveReturn::SgxError, status }.into());
+        }
 
         let box_ptr = serialized_ptr as *mut Box<[u8]>;
         let part = unsafe { Box::from_raw(box_ptr) };
+        let matching = match_u::take_strategy(strategy_ptr);
 
         let result;
         if(ret == sgx_status_t::SGX_SUCCESS) {
-            result = IpcResults::FindMatch { status: Status::Passed, encryptedOutput: part.to_hex()};
+            result = IpcResults::FindMatch { status: Status::Passed, encryptedOutput: part.to_hex(), matching };
         } else {
-            result = IpcResults::FindMatch { status: Status::Failed, encryptedOutput: "".to_string() };
+            result = IpcResults::FindMatch { status: Status::Failed, encryptedOutput: "".to_string(), matching: None };
         }
         Ok(IpcResponse::FindMatch { result })
     }
 
+    // Queued for the job worker, the state lock is taken by each step of the job instead. With `notify` the client
+    // is sent the job once it's done, see `notify_job`
+    pub fn submit_match_job(ctx: &IpcContext, input: IpcInputMatch, client: &ClientId) -> ResponseResult {
+        let notify = if input.notify == Some(true) { Some(client.clone()) } else { None };
+        let job = ctx.jobs.submit(input, tenant_u::current(), notify)?;
+        Ok(IpcResponse::SubmitMatchJob { result: IpcResults::MatchJob { job } })
+    }
+
+    pub fn get_match_job(jobs: &JobQueue, job_id: &str) -> ResponseResult {
+        let job = jobs.get(job_id)?;
+        Ok(IpcResponse::GetMatchJob { result: IpcResults::MatchJob { job } })
+    }
+
+    // A page of the log and what `audit::verify` makes of it, clients should verify it themselves
+    pub fn export_audit_log(audit: &AuditLog, from: u64) -> ResponseResult {
+        let (records, next) = audit.export(from)?;
+        let verification = audit_u::verification(&records);
+        Ok(IpcResponse::ExportAuditLog { result: IpcResults::AuditLog { records, verification, next } })
+    }
+
+    pub fn get_feature_switches(switches: &KillSwitches) -> ResponseResult {
+        let result = IpcResults::FeatureSwitches { features: switches.snapshot() };
+        Ok(IpcResponse::GetFeatureSwitches { result })
+    }
+
+    pub fn open_channel(ctx: &IpcContext, eid: sgx_enclave_id_t, handshake: ChannelHandshake) -> ResponseResult {
+        let handshake = ctx.peers.accept(eid, ctx.spid.expose(), &*ctx.attestation, &handshake)?;
+        let result = IpcResults::Channel { handshake };
+        Ok(IpcResponse::OpenChannel { result })
+    }
+
+    pub fn connect_peer(ctx: &IpcContext, eid: sgx_enclave_id_t, uri: &str) -> ResponseResult {
+        let peer_address = ctx.peers.connect(eid, ctx.spid.expose(), &*ctx.attestation, uri)?;
+        let result = IpcResults::Peer { peer_address: peer_address.to_hex() };
+        Ok(IpcResponse::ConnectPeer { result })
+    }
+
+    // Queries every configured peer we can open a channel with, unreachable peers are skipped
+    // so a single faulty deployment doesn't break matching for everyone.
+    pub fn find_match_federated(ctx: &IpcContext, input: IpcInputMatch, eid: sgx_enclave_id_t) -> ResponseResult {
+        let encrypted_userid = input.encrypted_userid.from_hex()?;
+        let encrypted_signature = input.encrypted_signature.from_hex()?;
+        let mut user_pub_key = [0u8; 64];
+        user_pub_key.clone_from_slice(&input.user_pub_key.from_hex()?);
+
+        let mut uris = Vec::new();
+        let mut addresses = Vec::new();
+        for uri in ctx.peers.uris() {
+            match ctx.peers.connect(eid, ctx.spid.expose(), &*ctx.attestation, uri) {
+                Ok(address) => {
+                    uris.push(uri);
+                    addresses.push(address);
+                },
+                Err(e) => warn!("Skipping federation peer {}: {}", uri, e),
+            }
+        }
+
+        let queries = channel_u::federated_begin(eid, &encrypted_userid, &encrypted_signature, &user_pub_key, &addresses)?;
+        let sender = equote::get_register_signing_address(eid)?.to_hex();
+        let answers: Vec<Vec<u8>> = uris.iter().zip(queries.iter()).map(|(uri, query)| {
+            let request = IpcRequest::FederatedQuery { sender: sender.clone(), payload: query.to_hex() };
+            // The peers match the user in the same tenant
+            let mut message = IpcMessageRequest::from_request(request, sender[..10].to_string());
+            message.tenant = tenant_u::current();
+            let answer = peer::send_request(uri, message)
+                .and_then(|reply| Ok(reply["result"]["payload"].as_str().unwrap_or_default().from_hex()?));
+            answer.unwrap_or_else(|e| {
+                warn!("Federation peer {} didn't answer: {}", uri, e);
+                Vec::new()
+            })
+        }).collect();
+
+        let output = channel_u::federated_end(eid, &user_pub_key, &addresses, &answers)?;
+        let result = IpcResults::FindMatch { status: Status::Passed, encryptedOutput: output.to_hex(), matching: None };
+        Ok(IpcResponse::FindMatchFederated { result })
+    }
+
+    pub fn federated_query(sender: &str, payload: &str, eid: sgx_enclave_id_t) -> ResponseResult {
+        let sender = sender.from_hex()?;
+        if sender.len() != 20 {
+            bail!("The federation sender must be a 20 bytes address");
+        }
+        let mut peer = [0u8; 20];
+        peer.clone_from_slice(&sender);
+        let answer = channel_u::federated_answer(eid, &peer, &payload.from_hex()?)?;
+        let result = IpcResults::FederatedAnswer { payload: answer.to_hex() };
+        Ok(IpcResponse::FederatedQuery { result })
+    }
+
+    // Operator command: per-epoch rollups of the sealed store, for capacity planning.
+    pub fn get_stats(eid: sgx_enclave_id_t) -> ResponseResult {
+        let stats = stats_u::get_stats(eid)?;
+        stats.export();
+        Ok(IpcResponse::GetStats { result: IpcResults::Stats { stats } })
+    }
+
+    // The differentially private aggregates, the only view of the stored locations meant to be published.
+    pub fn get_aggregates(eid: sgx_enclave_id_t) -> ResponseResult {
+        let aggregates = stats_u::get_aggregates(eid)?;
+        Ok(IpcResponse::GetAggregates { result: IpcResults::Aggregates { aggregates } })
+    }
+
+    // Signed by the enclave, the health authority checks the bundle against the enclave report.
+    pub fn export_exposure_statistics(eid: sgx_enclave_id_t, request: &stats_u::ExportRequest) -> ResponseResult {
+        let bundle = stats_u::export_statistics(eid, request)?;
+        info!("Exported {} rows of exposure statistics to the health authority {}", bundle.rows, bundle.authority);
+        Ok(IpcResponse::ExportExposureStatistics { result: IpcResults::ExposureExport { bundle } })
+    }
+
+    pub fn now_millis() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() * 1000 + u64::from(d.subsec_millis())).unwrap_or(0)
+    }
+
+    // Liveness check for the client SDKs, echoes the nonce and never touches the enclave.
+    pub fn ping(nonce: String, received_at: u64) -> ResponseResult {
+        Ok(IpcResponse::Ping { result: IpcResults::Pong { nonce, received_at, sent_at: now_millis() } })
+    }
+
+    // Keeps a client's connection busy and tells it how far off the server is, never touches the enclave: the
+    // client's `sent_at` comes back with the server's times.
+    pub fn heartbeat(sent_at: u64, received_at: u64, idle_timeout: u64) -> ResponseResult {
+        Ok(IpcResponse::Heartbeat { result: IpcResults::Heartbeat { sent_at, received_at, replied_at: now_millis(), idle_timeout } })
+    }
 
+    // Lets clients find out what this server speaks before using it, see `protocol`.
+    pub fn get_protocol_version(eid: sgx_enclave_id_t, client_version: Option<u32>, quantization: Option<Quantization>,
+                                max_frame_size: usize) -> ResponseResult {
+        let mr_enclave = equote::get_mr_enclave(eid)?;
+        let result = IpcResults::ProtocolVersion {
+            version: protocol::PROTOCOL_VERSION,
+            min_version: protocol::MIN_PROTOCOL_VERSION,
+            compatible: protocol::is_compatible(client_version),
+            commands: protocol::commands(),
+            schemas: protocol::schemas(),
+            capabilities: protocol::CAPABILITIES.iter().map(|c| c.to_string()).collect(),
+            mr_enclave: mr_enclave.to_hex(),
+            quantization,
+            max_frame_size: Some(max_frame_size),
+        };
+        Ok(IpcResponse::GetProtocolVersion { result })
+    }
+
+    // Probes for orchestrators: liveness only checks the enclaves, readiness also checks storage and IAS.
+    pub fn get_health(ctx: &IpcContext, readiness: bool) -> ResponseResult {
+        let checks = if readiness { health::readiness(ctx) } else { health::liveness(ctx) };
+        let ok = checks.values().all(|check| check.ok);
+        let degraded = ctx.freshness.degraded(now_millis() / 1000);
+        let result = IpcResults::Health { ok, checks, build: BuildInfo::current(), degraded };
+        Ok(if readiness { IpcResponse::GetReadiness { result } } else { IpcResponse::GetHealth { result } })
+    }
 }
@@ -0,0 +1,287 @@
+use crate::config::JobsConfig;
+use crate::match_u;
+use crate::networking::ipc_listener::{self, IpcContext};
+use crate::networking::messages::{IpcInputMatch, MatchingStrategy};
+use crate::networking::router::ClientId;
+use crate::tenant_u;
+use failure::Error;
+use hex::{FromHex, ToHex};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Asynchronous matching. `FindMatch` holds the enclave for as long as it compares the user with the whole
+// store, `SubmitMatchJob` queues the same query and answers at once with a job id. `[jobs] concurrency`
+// worker threads run the jobs, `[jobs] step_size` stored users per ecall, each step on a thread of the
+// enclave of its own so submissions go on meanwhile (see `jobs` in the enclave). Clients poll `GetMatchJob` for the
+// progress and, once `Done`, the encrypted results, or subscribe to the `[jobs] events` PUB socket, where
+// every change of a job is published with its id as the topic. A DEALER client may also submit it with `notify`:
+// the job is then sent to that client once done or failed, see `ipc_listener::notify_job`.
+//
+// A job runs in the tenant of the request that submitted it, see `tenant_u`.
+//
+// Job ids are 16 random bytes, only the client that submitted a job knows it. Finished jobs are kept
+// `[jobs] retention` seconds for the client to collect, the queue is lost with the process.
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum JobState {
+    Queued,
+    Running,
+    Done,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MatchJob {
+    #[serde(rename = "jobId")] pub job_id: String,
+    pub state: JobState,
+    // Stored users compared so far, out of `total`, which is 0 until the job runs
+    pub processed: u64,
+    pub total: u64,
+    // Once `Done`, the same as the `encryptedOutput` of `FindMatch`
+    #[serde(rename = "encryptedOutput", default, skip_serializing_if = "String::is_empty")] pub encrypted_output: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub error: Option<String>,
+    // The strategy the job runs, to reproduce the results: the one the client named until the enclave
+    // starts the job, then the one it reports
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub matching: Option<MatchingStrategy>,
+}
+
+impl MatchJob {
+    fn finished(&self) -> bool {
+        self.state == JobState::Done || self.state == JobState::Failed
+    }
+}
+
+struct Entry {
+    job: MatchJob,
+    // When the job last changed, in seconds
+    updated: u64,
+}
+
+// A job for the workers.
+struct Queued {
+    job_id: String,
+    input: IpcInputMatch,
+    tenant: Option<String>,
+    // The client to send the finished job to
+    notify: Option<ClientId>,
+}
+
+// The job store of the IPC handlers, and the queue of the worker.
+pub struct JobQueue {
+    config: JobsConfig,
+    jobs: Mutex<HashMap<String, Entry>>,
+    sender: Mutex<mpsc::Sender<Queued>>,
+    // Shared by the workers
+    receiver: Mutex<mpsc::Receiver<Queued>>,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl JobQueue {
+    pub fn new(config: JobsConfig) -> Self {
+        let (sender, receiver) = mpsc::channel();
+        JobQueue { config, jobs: Mutex::new(HashMap::new()), sender: Mutex::new(sender), receiver: Mutex::new(receiver) }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<HashMap<String, Entry>> {
+        let mut jobs = self.jobs.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        // Forgets the results nobody collected in time
+        let (now, retention) = (now(), self.config.retention);
+        jobs.retain(|_, entry| !entry.job.finished() || entry.updated + retention >= now);
+        jobs
+    }
+
+    pub fn submit(&self, input: IpcInputMatch, tenant: Option<String>, notify: Option<ClientId>) -> Result<MatchJob, Error> {
+        let mut jobs = self.lock();
+        if jobs.values().filter(|entry| !entry.job.finished()).count() >= self.config.max_queued {
+            bail!("Too many match jobs are queued, try again later");
+        }
+        let job_id = rand::random::<[u8; 16]>().to_hex();
+        let job = MatchJob {
+            job_id: job_id.clone(), state: JobState::Queued, processed: 0, total: 0, encrypted_output: String::new(), error: None,
+            matching: input.matching.clone(),
+        };
+        jobs.insert(job_id.clone(), Entry { job: job.clone(), updated: now() });
+        let sender = self.sender.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if sender.send(Queued { job_id: job_id.clone(), input, tenant, notify }).is_err() {
+            jobs.remove(&job_id);
+            bail!("The match job worker isn't running");
+        }
+        Ok(job)
+    }
+
+    pub fn get(&self, job_id: &str) -> Result<MatchJob, Error> {
+        match self.lock().get(&job_id.to_lowercase()) {
+            Some(entry) => Ok(entry.job.clone()),
+            None => bail!("Unknown match job, or its results expired"),
+        }
+    }
+
+    // The jobs queued, and those running
+    pub fn pending(&self) -> (usize, usize) {
+        let jobs = self.lock();
+        let count = |state: JobState| jobs.values().filter(|entry| entry.job.state == state).count();
+        (count(JobState::Queued), count(JobState::Running))
+    }
+
+    fn update<F: FnOnce(&mut MatchJob)>(&self, job_id: &str, change: F) -> Option<MatchJob> {
+        let mut jobs = self.lock();
+        let entry = jobs.get_mut(job_id)?;
+        change(&mut entry.job);
+        entry.updated = now();
+        Some(entry.job.clone())
+    }
+}
+
+// Publishes the changes of the jobs, if `[jobs] events` is set: the match jobs, and the attestation jobs
+// of `attestation_jobs`.
+pub struct Events(Mutex<Option<zmq::Socket>>);
+
+impl Events {
+    pub fn bind(endpoint: &str) -> Result<Self, Error> {
+        if endpoint.is_empty() {
+            return Ok(Events(Mutex::new(None)));
+        }
+        let socket = zmq::Context::new().socket(zmq::PUB)?;
+        socket.bind(endpoint)?;
+        println!("Match job events bound to: {}", endpoint);
+        Ok(Events(Mutex::new(Some(socket))))
+    }
+
+    // Subscribers filter on the job id, the first frame
+    pub fn publish<T: Serialize>(&self, job_id: &str, job: &T) {
+        // ZMQ sockets aren't shared between threads, the workers take turns
+        if let Some(socket) = &*self.0.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) {
+            let sent = serde_json::to_vec(job).map_err(Error::from)
+                .and_then(|event| Ok(socket.send(job_id.as_bytes(), zmq::SNDMORE).and_then(|_| socket.send(event, 0))?));
+            if let Err(e) = sent {
+                warn!("Publishing the progress of job {} failed: {}", job_id, e);
+            }
+        }
+    }
+}
+
+fn run_job(ctx: &IpcContext, job_id: &str, input: &IpcInputMatch, events: &Events) -> Result<Vec<u8>, Error> {
+    let encrypted_userid = input.encrypted_userid.from_hex()?;
+    let encrypted_signature = input.encrypted_signature.from_hex()?;
+    // Empty unless the client named one, the enclave then runs its default and tells which
+    let strategy = match &input.matching {
+        Some(matching) => serde_json::to_vec(matching)?,
+        None => Vec::new(),
+    };
+    let mut user_pub_key = [0u8; 64];
+    user_pub_key.clone_from_slice(&input.user_pub_key.from_hex()?);
+
+    // The enclave that holds the DH key of the user, and then the job
+    let eid = ctx.pool.route(&input.user_pub_key);
+    // The snapshot reads the store, the steps only go through it
+    let (job, total, matching) = {
+        let _state = ctx.pool.read_state();
+        let _thread = ctx.pool.enter(eid);
+        match_u::start_match_job(eid, &encrypted_userid, &encrypted_signature, &strategy, &user_pub_key)?
+    };
+    let publish = |processed| {
+        if let Some(status) = ctx.jobs.update(job_id, |status| {
+            status.state = JobState::Running;
+            status.matching = matching.clone();
+            status.processed = processed;
+            status.total = total;
+        }) {
+            events.publish(&status.job_id, &status);
+        }
+    };
+    publish(0);
+    let steps = (|| {
+        let mut processed = 0;
+        while processed < total {
+            let _thread = ctx.pool.enter(eid);
+            processed = match_u::match_job_step(eid, job, ctx.jobs.config.step_size)?;
+            publish(processed);
+        }
+        let _thread = ctx.pool.enter(eid);
+        match_u::finish_match_job(eid, job)
+    })();
+    if steps.is_err() {
+        // The enclave keeps a copy of the store per job, don't leave it there
+        let _thread = ctx.pool.enter(eid);
+        if let Err(e) = match_u::cancel_match_job(eid, job) {
+            warn!("Cancelling match job {} failed: {}", job_id, e);
+        }
+    }
+    steps
+}
+
+// Runs the queued jobs until the process exits, meant for a thread of its own per `[jobs] concurrency`.
+pub fn run(ctx: Arc<IpcContext>, events: Arc<Events>) {
+    loop {
+        // Only held while waiting, the job runs with the queue free for the other workers
+        let next = ctx.jobs.receiver.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).recv();
+        let Queued { job_id, input, tenant, notify } = match next {
+            Ok(next) => next,
+            Err(_) => return,
+        };
+        let result = tenant_u::scoped(tenant.as_ref().map(String::as_str), || run_job(&ctx, &job_id, &input, &events));
+        let status = ctx.jobs.update(&job_id, |status| match result {
+            Ok(output) => {
+                status.state = JobState::Done;
+                status.processed = status.total;
+                status.encrypted_output = output.to_hex();
+            },
+            Err(e) => {
+                status.state = JobState::Failed;
+                status.error = Some(e.to_string());
+            },
+        });
+        if let Some(status) = status {
+            events.publish(&status.job_id, &status);
+            if let Some(client) = &notify {
+                ipc_listener::notify_job(&ctx, client, status);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn input() -> IpcInputMatch {
+        IpcInputMatch { encrypted_userid: "ab".repeat(40), user_pub_key: "cd".repeat(64), encrypted_signature: String::new(), matching: None, incremental: None, notify: None }
+    }
+
+    #[test]
+    fn test_job_store() {
+        let queue = JobQueue::new(JobsConfig { max_queued: 2, ..JobsConfig::default() });
+        let first = queue.submit(input(), None, None).unwrap();
+        assert_eq!(first.state, JobState::Queued);
+        assert_eq!(first.job_id.len(), 32);
+        assert_eq!(queue.get(&first.job_id.to_uppercase()).unwrap(), first);
+
+        // The job says which strategy it runs
+        let grid = MatchingStrategy::Grid { cell: 0.001, min_overlap: 300 };
+        let second = queue.submit(IpcInputMatch { matching: Some(grid.clone()), ..input() }, Some("ch-ge".to_string()), None).unwrap();
+        assert_ne!(first.job_id, second.job_id);
+        assert_eq!((first.matching.as_ref(), second.matching.as_ref()), (None, Some(&grid)));
+        // Full until one of them is done
+        assert!(queue.submit(input(), None, None).is_err());
+        queue.update(&first.job_id, |job| job.state = JobState::Done).unwrap();
+        queue.submit(input(), None, None).unwrap();
+
+        // Finished jobs are forgotten after the retention, the others wait
+        for entry in queue.jobs.lock().unwrap().values_mut() {
+            entry.updated -= 601;
+        }
+        assert!(queue.get(&first.job_id).is_err());
+        assert_eq!(queue.get(&second.job_id).unwrap().state, JobState::Queued);
+        assert!(queue.get("00").is_err());
+
+        // The worker gets the tenant of each job
+        let receiver = queue.receiver.lock().unwrap();
+        let tenants: Vec<_> = receiver.try_iter().map(|queued| queued.tenant).collect();
+        assert_eq!(tenants, vec![None, Some("ch-ge".to_string()), None]);
+    }
+}
@@ -0,0 +1,322 @@
+use crate::common_u::errors::{self, ErrorCode, PayloadTooLargeErr};
+use crate::networking::deprecation::DeprecationNotice;
+use crate::networking::messages::{IpcRequest, IpcResponse, COMMANDS};
+use crate::networking::validation::ValidationErr;
+use failure::Error;
+use serde_json::{json, Map, Value};
+
+// JSON-RPC 2.0 (https://www.jsonrpc.org/specification) on top of the IPC socket.
+// A message is handled as JSON-RPC when it's an array (a batch) or carries a `jsonrpc` member,
+// anything else is the original `{"id", "type", ...}` envelope, which keeps working unchanged.
+// Methods are the request types, in PascalCase (`FindMatch`) or camelCase (`findMatch`), and take
+// their fields as by-name params. The `input` object of `AddPersonalData`, `RegisterUser`, `UpdateUserStatus`,
+// `FindMatch`, `FindMatchFederated` and `SubmitMatchJob` can be passed as the params themselves.
+// Notifications (requests without an `id`) are processed but not answered. Since a ZMQ REQ client
+// waits for an answer to every message, a notification or a batch of them gets an empty frame back.
+
+// Error codes, the table is published in enclave/README.md. Errors also carry the `errorCode` of the native
+// replies (`ErrorCode`), finer than these: both attestation errors are -32003 but 1001 or 1008.
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+// Server errors, in the range the specification reserves for implementations
+pub const ENCLAVE_ERROR: i64 = -32000;
+pub const FEATURE_DISABLED: i64 = -32001;
+pub const METHOD_RETIRED: i64 = -32002;
+pub const ATTESTATION_ERROR: i64 = -32003;
+pub const PEER_ERROR: i64 = -32004;
+pub const REQUEST_TIMEOUT: i64 = -32005;
+pub const PAYLOAD_TOO_LARGE: i64 = -32006;
+pub const RATE_LIMITED: i64 = -32007;
+pub const UNAUTHENTICATED: i64 = -32008;
+pub const MAINTENANCE: i64 = -32009;
+
+const VERSION: &str = "2.0";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub data: Option<Value>,
+    // Not part of the specification either
+    #[serde(rename = "errorCode", skip_serializing_if = "Option::is_none", default)]
+    pub error_code: Option<ErrorCode>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RpcResponse {
+    pub jsonrpc: String,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub result: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub error: Option<RpcError>,
+    pub id: Value,
+    // Not part of the specification, clients ignore members they don't know
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deprecations: Vec<DeprecationNotice>,
+}
+
+impl RpcError {
+    // The errors of the JSON-RPC layer itself, before a request is run
+    pub fn new(code: i64, message: &str) -> Self {
+        let error_code = if code == METHOD_NOT_FOUND { ErrorCode::UnknownMethod } else { ErrorCode::InvalidRequest };
+        RpcError { code, message: message.to_string(), data: None, error_code: Some(error_code) }
+    }
+
+    fn with_data(mut self, data: Value) -> Self {
+        self.data = Some(data);
+        self
+    }
+}
+
+impl RpcResponse {
+    pub fn success(id: Value, result: Value) -> Self {
+        RpcResponse { jsonrpc: VERSION.to_string(), result: Some(result), error: None, id, deprecations: Vec::new() }
+    }
+
+    pub fn failure(id: Value, error: RpcError) -> Self {
+        RpcResponse { jsonrpc: VERSION.to_string(), result: None, error: Some(error), id, deprecations: Vec::new() }
+    }
+}
+
+pub fn is_jsonrpc(doc: &Value) -> bool {
+    doc.is_array() || doc.get("jsonrpc").is_some()
+}
+
+// The answer to a frame that is neither JSON nor msgpack.
+pub fn parse_error(e: &dyn std::fmt::Display) -> RpcResponse {
+    RpcResponse::failure(Value::Null, RpcError::new(PARSE_ERROR, "Parse error").with_data(Value::String(e.to_string())))
+}
+
+// The answer to a frame past `server.max_frame_size`, which isn't read: its id isn't known either.
+pub fn payload_too_large(e: &PayloadTooLargeErr) -> RpcResponse {
+    let data = json!({"size": e.size, "limit": e.limit});
+    let error = RpcError { code: PAYLOAD_TOO_LARGE, message: "Payload too large".to_string(), data: Some(data), error_code: Some(ErrorCode::PayloadTooLarge) };
+    RpcResponse::failure(Value::Null, error)
+}
+
+// Maps the code of an error of the request handlers to one of the table.
+pub fn rpc_code(code: ErrorCode) -> i64 {
+    match code {
+        ErrorCode::FeatureDisabled => FEATURE_DISABLED,
+        ErrorCode::MethodRetired => METHOD_RETIRED,
+        ErrorCode::AttestationFailed | ErrorCode::AttestationUnavailable | ErrorCode::ServiceDegraded => ATTESTATION_ERROR,
+        ErrorCode::PeerError => PEER_ERROR,
+        ErrorCode::RequestTimeout => REQUEST_TIMEOUT,
+        ErrorCode::PayloadTooLarge => PAYLOAD_TOO_LARGE,
+        ErrorCode::RateLimited => RATE_LIMITED,
+        ErrorCode::Unauthenticated => UNAUTHENTICATED,
+        ErrorCode::Maintenance => MAINTENANCE,
+        ErrorCode::EnclaveError => ENCLAVE_ERROR,
+        ErrorCode::ValidationError | ErrorCode::UnknownTenant | ErrorCode::InvalidRequest => INVALID_PARAMS,
+        ErrorCode::UnknownMethod => METHOD_NOT_FOUND,
+        ErrorCode::InternalError => INTERNAL_ERROR,
+    }
+}
+
+fn handler_error(e: &Error) -> RpcError {
+    let error_code = errors::error_code(e);
+    RpcError { code: rpc_code(error_code), message: e.to_string(), data: error_data(e), error_code: Some(error_code) }
+}
+
+// Machine readable details of an error, e.g. the fields that failed validation.
+fn error_data(e: &Error) -> Option<Value> {
+    if let Some(e) = e.downcast_ref::<errors::MaintenanceErr>() {
+        return Some(json!({ "retryAfter": e.retry_after }));
+    }
+    e.downcast_ref::<ValidationErr>().and_then(|e| serde_json::to_value(&e.errors).ok())
+}
+
+// Handles a request or a batch, `process` runs a single request, with the `timeoutMs` and `tenant` members
+// of the request object if it has them, and `reject` is told about the ones that aren't even a request (the dead
+// letters of the decode stage). Returns `None` when there is nothing to
+// answer (notifications only).
+pub fn handle<F, G>(doc: Value, process: F, reject: G) -> Option<Value>
+where F: Fn(IpcRequest, Option<u64>, Option<String>) -> (Result<IpcResponse, Error>, Vec<DeprecationNotice>), G: Fn(&RpcError) {
+    match doc {
+        Value::Array(ref batch) if batch.is_empty() => {
+            let error = RpcError::new(INVALID_REQUEST, "Empty batch");
+            reject(&error);
+            Some(serde_json::to_value(RpcResponse::failure(Value::Null, error)).unwrap())
+        },
+        Value::Array(batch) => {
+            let replies: Vec<Value> = batch.into_iter()
+                .filter_map(|request| handle_one(request, &process, &reject))
+                .map(|reply| serde_json::to_value(reply).unwrap())
+                .collect();
+            if replies.is_empty() { None } else { Some(Value::Array(replies)) }
+        },
+        request => handle_one(request, &process, &reject).map(|reply| serde_json::to_value(reply).unwrap()),
+    }
+}
+
+fn handle_one<F, G>(doc: Value, process: &F, reject: &G) -> Option<RpcResponse>
+where F: Fn(IpcRequest, Option<u64>, Option<String>) -> (Result<IpcResponse, Error>, Vec<DeprecationNotice>), G: Fn(&RpcError) {
+    let rejected = |id: Value, error: RpcError| {
+        reject(&error);
+        RpcResponse::failure(id, error)
+    };
+    let mut doc = match doc {
+        Value::Object(doc) => doc,
+        _ => return Some(rejected(Value::Null, RpcError::new(INVALID_REQUEST, "A request must be an object"))),
+    };
+    // No `id` member at all makes it a notification, `"id": null` is a (discouraged) request
+    let id = doc.remove("id");
+    let reply_id = id.clone().unwrap_or(Value::Null);
+    if doc.get("jsonrpc").and_then(Value::as_str) != Some(VERSION) {
+        return Some(rejected(reply_id, RpcError::new(INVALID_REQUEST, "jsonrpc must be \"2.0\"")));
+    }
+    if !(reply_id.is_string() || reply_id.is_number() || reply_id.is_null()) {
+        return Some(rejected(Value::Null, RpcError::new(INVALID_REQUEST, "id must be a string or a number")));
+    }
+    // Not a JSON-RPC member: like `deprecations` in the replies, an extension of this server
+    let timeout_ms = doc.remove("timeoutMs").and_then(|timeout| timeout.as_u64());
+    let tenant = doc.remove("tenant").and_then(|tenant| tenant.as_str().map(String::from));
+    let request = match doc.remove("method") {
+        Some(Value::String(method)) => to_request(&method, doc.remove("params").unwrap_or(Value::Null)),
+        _ => Err(RpcError::new(INVALID_REQUEST, "method must be a string")),
+    };
+    let (result, deprecations) = match request {
+        Ok(request) => process(request, timeout_ms, tenant),
+        Err(error) => {
+            let reply = rejected(reply_id, error);
+            return id.map(|_| reply);
+        },
+    };
+    let id = id?;
+    let mut reply = match result {
+        Ok(IpcResponse::Error { code, msg }) => {
+            RpcResponse::failure(id, RpcError { code: rpc_code(code), message: msg, data: None, error_code: Some(code) })
+        },
+        Ok(response) => RpcResponse::success(id, result_value(response)),
+        Err(e) => RpcResponse::failure(id, handler_error(&e)),
+    };
+    reply.deprecations = deprecations;
+    Some(reply)
+}
+
+// `findMatch` -> `FindMatch`, then the params become the fields of the request.
+fn to_request(method: &str, params: Value) -> Result<IpcRequest, RpcError> {
+    let mut chars = method.chars();
+    let command: String = chars.next().map(|c| c.to_ascii_uppercase()).into_iter().chain(chars).collect();
+    if !COMMANDS.contains(&command.as_str()) {
+        return Err(RpcError::new(METHOD_NOT_FOUND, &format!("Method not found: {}", method)));
+    }
+    let mut fields = match params {
+        Value::Object(params) => params,
+        Value::Null => Map::new(),
+        _ => return Err(RpcError::new(INVALID_PARAMS, "params must be an object")),
+    };
+    let takes_input = ["AddPersonalData", "RegisterUser", "UpdateUserStatus", "FindMatch", "FindMatchFederated", "SubmitMatchJob"]
+        .contains(&command.as_str());
+    if takes_input && !fields.contains_key("input") {
+        let input = std::mem::replace(&mut fields, Map::new());
+        fields.insert("input".to_string(), Value::Object(input));
+    }
+    fields.insert("type".to_string(), Value::String(command));
+    serde_json::from_value(Value::Object(fields))
+        .map_err(|e| RpcError::new(INVALID_PARAMS, "Invalid params").with_data(Value::String(e.to_string())))
+}
+
+// The fields of the response, without the envelope: `{"type": "FindMatch", "findMatch": {...}}`
+// becomes `{...}`.
+fn result_value(response: IpcResponse) -> Value {
+    let mut doc = match serde_json::to_value(response) {
+        Ok(Value::Object(doc)) => doc,
+        Ok(other) => return other,
+        Err(e) => return Value::String(e.to_string()),
+    };
+    doc.remove("type");
+    if doc.len() == 1 {
+        let key = doc.keys().next().cloned().unwrap();
+        return doc.remove(&key).unwrap();
+    }
+    Value::Object(doc)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::common_u::errors::{FeatureDisabledErr, RequestTimeoutErr};
+    use crate::networking::messages::{IpcResults, Status};
+    use serde_json::json;
+
+    fn process(request: IpcRequest, timeout_ms: Option<u64>, tenant: Option<String>) -> (Result<IpcResponse, Error>, Vec<DeprecationNotice>) {
+        if let Some(timeout_ms) = timeout_ms {
+            return (Err(RequestTimeoutErr { timeout_ms }.into()), Vec::new());
+        }
+        let response = match request {
+            // Echoes the tenant instead of the nonce, when there is one
+            IpcRequest::Ping { nonce } => Ok(IpcResponse::Ping { result: IpcResults::Pong { nonce: tenant.unwrap_or(nonce), received_at: 1, sent_at: 2 } }),
+            IpcRequest::FindMatch { input } => Ok(IpcResponse::FindMatch {
+                result: IpcResults::FindMatch { status: Status::Passed, encryptedOutput: input.encrypted_userid, matching: input.matching },
+            }),
+            _ => Err(FeatureDisabledErr { feature: crate::networking::switches::Feature::Ingest }.into()),
+        };
+        (response, Vec::new())
+    }
+
+    fn ignore(_: &RpcError) {}
+
+    #[test]
+    fn test_request_and_errors() {
+        let reply = handle(json!({"jsonrpc": "2.0", "method": "ping", "params": {"nonce": "5eed"}, "id": 7}), process, ignore).unwrap();
+        assert_eq!(reply, json!({"jsonrpc": "2.0", "result": {"nonce": "5eed", "receivedAt": 1, "sentAt": 2}, "id": 7}));
+
+        let reply = handle(json!({"jsonrpc": "2.0", "method": "findMatch", "params": {"encryptedUserId": "ab", "userPubKey": "cd"}, "id": "x"}), process, ignore).unwrap();
+        assert_eq!(reply["result"], json!({"status": 0, "encryptedOutput": "ab"}));
+
+        let reply = handle(json!({"jsonrpc": "2.0", "method": "addPersonalData", "params": {"input": {}}, "id": 1}), process, ignore).unwrap();
+        assert_eq!(reply["error"]["code"], INVALID_PARAMS);
+        assert_eq!(reply["error"]["errorCode"], ErrorCode::InvalidRequest.code());
+        let reply = handle(json!({"jsonrpc": "2.0", "method": "nope", "id": 1}), process, ignore).unwrap();
+        assert_eq!(reply["error"]["code"], METHOD_NOT_FOUND);
+        assert_eq!(reply["error"]["errorCode"], ErrorCode::UnknownMethod.code());
+        let reply = handle(json!({"jsonrpc": "2.0", "method": "GetStats", "id": 1}), process, ignore).unwrap();
+        assert_eq!(reply["error"]["code"], FEATURE_DISABLED);
+        assert_eq!(reply["error"]["errorCode"], ErrorCode::FeatureDisabled.code());
+        let reply = handle(json!({"jsonrpc": "1.0", "method": "Ping", "id": 1}), process, ignore).unwrap();
+        assert_eq!(reply["error"]["code"], INVALID_REQUEST);
+        let reply = handle(json!({"jsonrpc": "2.0", "method": "GetStats", "timeoutMs": 50, "id": 1}), process, ignore).unwrap();
+        assert_eq!(reply["error"]["code"], REQUEST_TIMEOUT);
+        assert_eq!(reply["error"]["errorCode"], 1010);
+        let reply = handle(json!({"jsonrpc": "2.0", "method": "Ping", "params": {"nonce": "5eed"}, "tenant": "ch-ge", "id": 1}), process, ignore).unwrap();
+        assert_eq!(reply["result"]["nonce"], "ch-ge");
+    }
+
+    #[test]
+    fn test_payload_too_large() {
+        let reply = serde_json::to_value(payload_too_large(&PayloadTooLargeErr { size: 2048, limit: 1024 })).unwrap();
+        assert_eq!(reply, json!({"jsonrpc": "2.0", "error": {"code": PAYLOAD_TOO_LARGE, "message": "Payload too large", "data": {"size": 2048, "limit": 1024},
+                                 "errorCode": 1011}, "id": null}));
+    }
+
+    #[test]
+    fn test_batch_and_notifications() {
+        assert_eq!(handle(json!({"jsonrpc": "2.0", "method": "Ping", "params": {"nonce": "a"}}), process, ignore), None);
+        assert_eq!(handle(json!([]), process, ignore).unwrap()["error"]["code"], INVALID_REQUEST);
+
+        let reply = handle(json!([
+            {"jsonrpc": "2.0", "method": "Ping", "params": {"nonce": "a"}, "id": 1},
+            {"jsonrpc": "2.0", "method": "Ping", "params": {"nonce": "b"}},
+            1,
+        ]), process, ignore).unwrap();
+        let replies = reply.as_array().unwrap();
+        assert_eq!(replies.len(), 2);
+        assert_eq!(replies[0]["result"]["nonce"], "a");
+        assert_eq!(replies[1]["error"]["code"], INVALID_REQUEST);
+
+        // What isn't a request is rejected, notifications included; a refused request isn't
+        let rejected = std::cell::RefCell::new(Vec::new());
+        let reject = |error: &RpcError| rejected.borrow_mut().push(error.code);
+        handle(json!([
+            {"jsonrpc": "2.0", "method": "nope"},
+            {"jsonrpc": "2.0", "method": "GetStats", "id": 2},
+            "Ping",
+        ]), process, reject);
+        assert_eq!(*rejected.borrow(), vec![METHOD_NOT_FOUND, INVALID_REQUEST]);
+    }
+}
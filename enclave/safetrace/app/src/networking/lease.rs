@@ -0,0 +1,156 @@
+use crate::config::LeaseConfig;
+use crate::networking::health;
+use crate::networking::ipc_listener::IpcContext;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Readiness lease. With `lease.interval` set, `run` has every enclave answer a trivial ecall and the sealed store
+// unsealed every so many seconds; each heartbeat that passes renews the lease for `lease.duration` seconds. The
+// node is ready while it holds the lease: `GetReadiness` and `/readyz` fail once it expired or the last heartbeat
+// failed, so a rolling deployment waits for the new pods' enclaves and stops routing to an enclave that hangs.
+//
+// With `lease.file` set the lease is also written there, `{"renewedAt": 1589000000, "expiresAt": 1589000030}`,
+// on every renewal, and the file removed as soon as a heartbeat fails: for exec probes
+// (`test -f /tmp/safetrace.ready`) and for the sidecars that only see the filesystem.
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct Renewal {
+    pub renewed_at: u64,
+    pub expires_at: u64,
+}
+
+#[derive(Default)]
+struct State {
+    renewal: Option<Renewal>,
+    // Why the last heartbeat failed, None once one passes
+    failure: Option<String>,
+}
+
+pub struct Lease {
+    interval: u64,
+    duration: u64,
+    file: Option<PathBuf>,
+    state: Mutex<State>,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+impl Lease {
+    pub fn new(config: &LeaseConfig) -> Self {
+        let file = if config.file.is_empty() { None } else { Some(PathBuf::from(&config.file)) };
+        // Left by a previous run, the node isn't ready before its first heartbeat
+        if let Some(file) = &file {
+            let _ = fs::remove_file(file);
+        }
+        Lease { interval: config.interval, duration: config.duration, file, state: Mutex::new(State::default()) }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.interval > 0
+    }
+
+    fn lock(&self) -> MutexGuard<State> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn renewal(&self) -> Option<Renewal> {
+        self.lock().renewal
+    }
+
+    fn renew(&self, now: u64) {
+        let renewal = Renewal { renewed_at: now, expires_at: now + self.duration };
+        let mut state = self.lock();
+        if state.failure.take().is_some() {
+            info!("The readiness lease is held again");
+        }
+        state.renewal = Some(renewal);
+        if let Some(file) = &self.file {
+            let mut temporary = file.as_os_str().to_owned();
+            temporary.push(".tmp");
+            let written = fs::write(&temporary, serde_json::to_vec(&renewal).unwrap()).and_then(|_| fs::rename(&temporary, file));
+            if let Err(e) = written {
+                warn!("Unable to write the readiness file {}: {}", file.display(), e);
+            }
+        }
+    }
+
+    fn release(&self, reason: String) {
+        warn!("Releasing the readiness lease: {}", reason);
+        let mut state = self.lock();
+        state.renewal = None;
+        state.failure = Some(reason);
+        if let Some(file) = &self.file {
+            if let Err(e) = fs::remove_file(file) {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    error!("Unable to remove the readiness file {}, probes may still find it: {}", file.display(), e);
+                }
+            }
+        }
+    }
+
+    // Whether the node holds the lease at `now`, why not otherwise.
+    pub fn check(&self, now: u64) -> Result<(), String> {
+        let state = self.lock();
+        match (&state.failure, &state.renewal) {
+            (Some(failure), _) => Err(failure.clone()),
+            (None, None) => Err("no heartbeat yet".to_string()),
+            (None, Some(renewal)) if now >= renewal.expires_at => {
+                Err(format!("the lease expired {} seconds ago, the heartbeat is stuck", now - renewal.expires_at))
+            },
+            (None, Some(_)) => Ok(()),
+        }
+    }
+
+    // Renews the lease if every enclave answers and the store unseals.
+    fn heartbeat(&self, ctx: &IpcContext) {
+        let checks = vec![("enclave", health::enclaves_alive(ctx)), ("storage", health::storage_healthy(ctx))];
+        match checks.into_iter().find(|(_, check)| !check.ok) {
+            None => self.renew(now()),
+            Some((name, check)) => self.release(format!("{}: {}", name, check.detail.unwrap_or_default())),
+        }
+    }
+}
+
+// Beats every `lease.interval` seconds until the process exits, meant for a thread of its own.
+pub fn run(ctx: Arc<IpcContext>) {
+    loop {
+        ctx.lease.heartbeat(&ctx);
+        thread::sleep(Duration::from_secs(ctx.lease.interval));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_lease() {
+        let file = env::temp_dir().join("safetrace-lease-test.ready");
+        let _ = fs::remove_file(&file);
+        let lease = Lease::new(&LeaseConfig { interval: 10, duration: 30, file: file.to_str().unwrap().to_string() });
+        assert!(lease.enabled() && lease.check(100).is_err());
+
+        lease.renew(100);
+        assert_eq!(lease.check(129), Ok(()));
+        assert!(lease.check(130).unwrap_err().contains("expired"));
+        let written: Renewal = serde_json::from_slice(&fs::read(&file).unwrap()).unwrap();
+        assert_eq!(written, Renewal { renewed_at: 100, expires_at: 130 });
+
+        // A failed heartbeat drops the lease and the file at once
+        lease.release("storage: the store doesn't unseal".to_string());
+        assert!(lease.check(101).unwrap_err().contains("storage"));
+        assert!(!file.exists() && lease.renewal().is_none());
+        lease.renew(110);
+        assert_eq!(lease.check(111), Ok(()));
+        fs::remove_file(&file).unwrap();
+
+        assert!(!Lease::new(&LeaseConfig::default()).enabled());
+    }
+}
@@ -0,0 +1,103 @@
+use crate::common_u::errors::MaintenanceErr;
+use crate::networking::messages::IpcRequest;
+use crate::networking::planes::{self, Plane};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+// Maintenance mode, for orderly upgrades. `SetMaintenance` on the admin socket has the IPC sockets refuse the
+// requests of the data plane (`planes::of`) with `Maintenance` (1016) and the seconds to retry in, while the
+// registrations, liveness and version requests are still served. The requests already running finish, and so do
+// the match jobs already submitted. `GetMaintenance` reports how many are left; the first time it finds none it
+// compacts the sealed store into a single snapshot, as `fsck --repair` would, and reports the node `drained`
+// with the generation it flushed. `SetMaintenance` with `enabled: false` takes the requests again.
+
+// Seconds the refused clients are told to wait, unless `SetMaintenance` names another delay
+pub const RETRY_AFTER: u64 = 60;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct State {
+    // When maintenance started, in seconds
+    pub since: u64,
+    pub retry_after: u64,
+    // The generation of the sealed store once flushed
+    pub flushed: Option<u64>,
+}
+
+#[derive(Default)]
+pub struct Maintenance {
+    state: Mutex<Option<State>>,
+    // Data plane requests running
+    in_flight: AtomicUsize,
+}
+
+// A data plane request running, until dropped.
+pub struct InFlight<'a>(Option<&'a AtomicUsize>);
+
+impl<'a> Drop for InFlight<'a> {
+    fn drop(&mut self) {
+        if let Some(count) = self.0 {
+            count.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+impl Maintenance {
+    pub fn state(&self) -> MutexGuard<Option<State>> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Enters maintenance, or leaves it. Entering again only changes the delay.
+    pub fn set(&self, enabled: bool, retry_after: u64, now: u64) {
+        let mut state = self.state();
+        match (enabled, state.as_mut()) {
+            (true, Some(current)) => current.retry_after = retry_after,
+            (true, None) => *state = Some(State { since: now, retry_after, flushed: None }),
+            (false, _) => *state = None,
+        }
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    // Whether `request` is served now. The data plane requests count as running until the guard is dropped.
+    pub fn admit(&self, request: &IpcRequest) -> Result<InFlight, MaintenanceErr> {
+        if planes::of(request) != Some(Plane::Data) {
+            return Ok(InFlight(None));
+        }
+        // Counted before the check, `GetMaintenance` can't miss one admitted meanwhile
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        let guard = InFlight(Some(&self.in_flight));
+        if let Some(state) = self.state().as_ref() {
+            return Err(MaintenanceErr { retry_after: state.retry_after });
+        }
+        Ok(guard)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_maintenance() {
+        let maintenance = Maintenance::default();
+        let stats = IpcRequest::GetStats;
+        let ping = IpcRequest::Ping { nonce: "a1".to_string() };
+        let running = maintenance.admit(&stats).unwrap();
+        assert_eq!(maintenance.in_flight(), 1);
+
+        maintenance.set(true, 30, 1000);
+        assert_eq!(maintenance.admit(&stats).err().map(|e| e.retry_after), Some(30));
+        assert!(maintenance.admit(&ping).is_ok());
+        // The refused one doesn't count, the one running until it's done
+        assert_eq!(maintenance.in_flight(), 1);
+        drop(running);
+        assert_eq!(maintenance.in_flight(), 0);
+
+        maintenance.set(true, 120, 2000);
+        assert_eq!(*maintenance.state(), Some(State { since: 1000, retry_after: 120, flushed: None }));
+        maintenance.set(false, RETRY_AFTER, 3000);
+        assert!(maintenance.admit(&stats).is_ok() && maintenance.state().is_none());
+    }
+}
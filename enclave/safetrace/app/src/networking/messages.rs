@@ -1,6 +1,25 @@
-use serde_json;
+use bytes::Bytes;
+use hex::ToHex;
+use serde::de::{self, IgnoredAny};
+use serde_json::{self, Value};
 use serde_repr::{Serialize_repr, Deserialize_repr};
+use std::collections::BTreeMap;
+use std::{fmt, str};
 use zmq::Message;
+use crate::common_u::errors::{self, ErrorCode};
+use crate::networking::switches::Feature;
+use crate::networking::peer::ChannelHandshake;
+use crate::stats_u::{Aggregates, ExportBundle, ExportRequest, StorageStats};
+use crate::networking::health::{BuildInfo, HealthCheck};
+use crate::networking::deprecation::DeprecationNotice;
+use crate::networking::jobs::MatchJob;
+use crate::networking::attestation_jobs::AttestationJob;
+use crate::audit_u::AuditVerification;
+use crate::attestation::{self, ReportBundle};
+use safetrace_client::audit::AuditRecord;
+use safetrace_client::manifest::EnclaveManifest;
+use safetrace_client::identity::ServerIdentity;
+use safetrace_client::wire::WireFormat;
 
 
 // These attributes enable the status to be casted as an i8 object as well
@@ -11,6 +30,29 @@ pub enum Status {
     Passed = 0,
 }
 
+// Why the enclave refused an `AddPersonalData` envelope, see `replay` in the enclave, the locations of a
+// full store (`capacity.hard_records`), or locations later than the clock of the enclave allows (`clock`).
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Rejection {
+    Replay,
+    Expired,
+    CapacityExceeded,
+    ClockSkew,
+}
+
+impl Rejection {
+    // The `rejection` code of `ecall_add_personal_data`, 0 when there's none.
+    pub fn from_code(code: u8) -> Option<Self> {
+        match code {
+            1 => Some(Rejection::Replay),
+            2 => Some(Rejection::Expired),
+            3 => Some(Rejection::CapacityExceeded),
+            4 => Some(Rejection::ClockSkew),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct GeolocationTime {
     lat: f32,
@@ -22,6 +64,12 @@ pub struct GeolocationTime {
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct IpcMessageRequest {
     pub id: String,
+    // Milliseconds the client waits, instead of `server.request_timeout`
+    #[serde(rename = "timeoutMs", default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+    // The tenant of the request, see `tenant_u`, none for the default one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
     #[serde(flatten)]
     pub request: IpcRequest
 }
@@ -30,7 +78,12 @@ pub struct IpcMessageRequest {
 pub struct IpcMessageResponse {
     pub id: String,
     #[serde(flatten)]
-    pub response: IpcResponse
+    pub response: IpcResponse,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deprecations: Vec<DeprecationNotice>,
+    // The enclave vouching for the answer, see `identity_u`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub identity: Option<ServerIdentity>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -39,8 +92,31 @@ pub enum IpcResponse {
     GetEnclaveReport { #[serde(flatten)] result: IpcResults },
     NewTaskEncryptionKey { #[serde(flatten)] result: IpcResults },
     AddPersonalData { #[serde(flatten)] result: IpcResults },
+    RegisterUser { #[serde(flatten)] result: IpcResults },
+    UpdateUserStatus { #[serde(flatten)] result: IpcResults },
     FindMatch { #[serde(flatten)] result: IpcResults },
-    Error { msg: String },
+    GetFeatureSwitches { #[serde(flatten)] result: IpcResults },
+    SetFeatureSwitch { #[serde(flatten)] result: IpcResults },
+    OpenChannel { #[serde(flatten)] result: IpcResults },
+    ConnectPeer { #[serde(flatten)] result: IpcResults },
+    FindMatchFederated { #[serde(flatten)] result: IpcResults },
+    FederatedQuery { #[serde(flatten)] result: IpcResults },
+    GetStats { #[serde(flatten)] result: IpcResults },
+    GetAggregates { #[serde(flatten)] result: IpcResults },
+    ExportExposureStatistics { #[serde(flatten)] result: IpcResults },
+    Ping { #[serde(flatten)] result: IpcResults },
+    GetHealth { #[serde(flatten)] result: IpcResults },
+    GetReadiness { #[serde(flatten)] result: IpcResults },
+    GetProtocolVersion { #[serde(flatten)] result: IpcResults },
+    SubmitMatchJob { #[serde(flatten)] result: IpcResults },
+    GetMatchJob { #[serde(flatten)] result: IpcResults },
+    ExportAuditLog { #[serde(flatten)] result: IpcResults },
+    GetEnclaveInfo { #[serde(flatten)] result: IpcResults },
+    GetAttestationJob { #[serde(flatten)] result: IpcResults },
+    Heartbeat { #[serde(flatten)] result: IpcResults },
+    GetCachedReport { #[serde(flatten)] result: IpcResults },
+    // `code` says why, for the clients to branch on, `msg` is for people
+    Error { code: ErrorCode, msg: String },
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -50,11 +126,189 @@ pub enum IpcResults {
     #[serde(rename = "result")]
     Request { request: String, sig: String },
     #[serde(rename = "result")]
-    EnclaveReport { #[serde(rename = "signingKey")] signing_key: String, report: String, signature: String },
+    EnclaveReport(EnclaveReport),
     #[serde(rename = "result")]
     DHKey { taskPubKey: String, sig: String },
-    AddPersonalData { status: Status },
-    FindMatch { status: Status, #[serde(skip_serializing_if = "String::is_empty")] encryptedOutput: String },
+    // `encryptedOutput` is the receipt, `{"status": "Passed", "records": 3, "duplicates": 1}` or `{"status": "Failed", "error": "..."}`
+    // encrypted with the DH key like the match results
+    // `error` tells a replayed or expired envelope apart from the other failures, which only the receipt describes
+    AddPersonalData {
+        status: Status,
+        #[serde(skip_serializing_if = "String::is_empty")] encryptedOutput: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")] error: Option<Rejection>,
+    },
+    RegisterUser { status: Status },
+    // The same receipt and errors as `AddPersonalData`, `records` counts the locations updated
+    UpdateUserStatus {
+        status: Status,
+        #[serde(skip_serializing_if = "String::is_empty")] encryptedOutput: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")] error: Option<Rejection>,
+    },
+    // `matching` is the strategy the enclave ran, to reproduce the results
+    FindMatch {
+        status: Status,
+        #[serde(skip_serializing_if = "String::is_empty")] encryptedOutput: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")] matching: Option<MatchingStrategy>,
+    },
+    #[serde(rename = "result")]
+    FeatureSwitches { features: BTreeMap<Feature, bool> },
+    #[serde(rename = "result")]
+    Channel { handshake: ChannelHandshake },
+    #[serde(rename = "result")]
+    Peer { #[serde(rename = "peerAddress")] peer_address: String },
+    #[serde(rename = "result")]
+    FederatedAnswer { payload: String },
+    #[serde(rename = "result")]
+    Stats { stats: StorageStats },
+    #[serde(rename = "result")]
+    Aggregates { aggregates: Aggregates },
+    #[serde(rename = "result")]
+    ExposureExport { bundle: ExportBundle },
+    #[serde(rename = "result")]
+    Pong { nonce: String, #[serde(rename = "receivedAt")] received_at: u64, #[serde(rename = "sentAt")] sent_at: u64 },
+    // `sentAt` is the client's, echoed; `idleTimeout` the seconds a connection may go without traffic
+    #[serde(rename = "result")]
+    Heartbeat {
+        #[serde(rename = "sentAt")] sent_at: u64,
+        #[serde(rename = "receivedAt")] received_at: u64,
+        #[serde(rename = "repliedAt")] replied_at: u64,
+        #[serde(rename = "idleTimeout")] idle_timeout: u64,
+    },
+    #[serde(rename = "result")]
+    Health {
+        ok: bool,
+        checks: BTreeMap<String, HealthCheck>,
+        build: BuildInfo,
+        // Why new registrations are refused, see `reattest`
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        degraded: Option<String>,
+    },
+    #[serde(rename = "result")]
+    ProtocolVersion {
+        version: u32,
+        #[serde(rename = "minVersion")] min_version: u32,
+        compatible: bool,
+        commands: Vec<String>,
+        schemas: BTreeMap<String, u32>,
+        capabilities: Vec<String>,
+        #[serde(rename = "mrEnclave")] mr_enclave: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")] quantization: Option<Quantization>,
+        // Bytes a frame may take, see `server.max_frame_size`
+        #[serde(rename = "maxFrameSize", default, skip_serializing_if = "Option::is_none")] max_frame_size: Option<usize>,
+    },
+    #[serde(rename = "result")]
+    MatchJob { job: MatchJob },
+    // `GetEnclaveReport` while IAS is down, and `GetAttestationJob`
+    #[serde(rename = "result")]
+    AttestationJob { job: AttestationJob },
+    // `next` is the first entry left out, ask again `from` there
+    #[serde(rename = "result")]
+    AuditLog {
+        records: Vec<AuditRecord>,
+        verification: AuditVerification,
+        #[serde(default, skip_serializing_if = "Option::is_none")] next: Option<u64>,
+    },
+    // The measurements of the running enclave, out of its quote, and the report vouching for them
+    #[serde(rename = "result")]
+    EnclaveInfo {
+        #[serde(rename = "mrEnclave")] mr_enclave: String,
+        #[serde(rename = "mrSigner")] mr_signer: String,
+        #[serde(rename = "isvProdId")] isv_prod_id: u16,
+        #[serde(rename = "isvSvn")] isv_svn: u16,
+        debug: bool,
+        #[serde(rename = "signingKey")] signing_key: String,
+        // See `attestation::bundle`
+        bundle: String,
+        // `enclave.manifest`, and whether the enclave is the build it describes
+        #[serde(default, skip_serializing_if = "Option::is_none")] manifest: Option<EnclaveManifest>,
+        #[serde(default, rename = "manifestMatches", skip_serializing_if = "Option::is_none")] manifest_matches: Option<bool>,
+        // The settings the enclave enforces, whose SHA-256 is bytes 20 to 52 of the report data
+        #[serde(default, skip_serializing_if = "Option::is_none")] settings: Option<String>,
+    },
+    // A report IAS signed for this server, as it was sent, see `attestation::cache`. `cachedAt` in seconds
+    #[serde(rename = "result")]
+    CachedReport {
+        #[serde(rename = "quoteHash")] quote_hash: String,
+        quote: String,
+        // See `attestation::bundle`
+        bundle: String,
+        #[serde(rename = "cachedAt")] cached_at: u64,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EnclaveReport {
+    #[serde(rename = "signingKey")] pub signing_key: String,
+    pub report: String,
+    pub signature: String,
+    // The IAS certificate chain (PEM), so clients can verify `signature` themselves
+    pub certificate: String,
+    pub ca: String,
+    // `report`, `signature` and the chain in one string, see `attestation::bundle`
+    pub bundle: String,
+}
+
+impl EnclaveReport {
+    pub fn new(signing_key: String, bundle: &ReportBundle) -> Self {
+        EnclaveReport {
+            signing_key,
+            report: bundle.report.as_bytes().to_hex(),
+            signature: bundle.signature.clone(),
+            certificate: bundle.certificate.clone(),
+            ca: bundle.ca.clone(),
+            bundle: attestation::bundle::compact(bundle),
+        }
+    }
+}
+
+// How the enclave coarsens the locations it stores, see `config::QuantizationConfig`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Quantization {
+    // Degrees
+    pub grid: f64,
+    // Seconds
+    #[serde(rename = "timeBucket")] pub time_bucket: u64,
+}
+
+// How the enclave compares the locations of a user with the infected ones, see `matching` in the enclave.
+// The deployment has one (`[matching]`), `FindMatch` and `SubmitMatchJob` may name another.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "algorithm", rename_all = "camelCase")]
+pub enum MatchingStrategy {
+    // Within `distance` meters for more than `minOverlap` seconds
+    Radius {
+        #[serde(rename = "minOverlap")] min_overlap: i32,
+        distance: f64,
+    },
+    // In the same cell of a grid of `cell` degrees for more than `minOverlap` seconds
+    Grid {
+        cell: f64,
+        #[serde(rename = "minOverlap")] min_overlap: i32,
+    },
+    // Within `distance` meters for at least `minDuration` seconds, adding up the overlaps
+    Duration {
+        distance: f64,
+        #[serde(rename = "minDuration")] min_duration: i32,
+    },
+}
+
+impl MatchingStrategy {
+    // Why the enclave would refuse the parameters, if it would.
+    pub fn check(&self) -> Result<(), &'static str> {
+        let valid_distance = |distance: f64| distance.is_finite() && distance > 0.0;
+        match *self {
+            MatchingStrategy::Radius { distance, .. } | MatchingStrategy::Duration { distance, .. } if !valid_distance(distance) =>
+                Err("the distance must be positive"),
+            MatchingStrategy::Radius { min_overlap, .. } | MatchingStrategy::Grid { min_overlap, .. } if min_overlap < 0 =>
+                Err("the overlap can't be negative"),
+            // Beyond a degree (111km) nothing would be close anymore
+            MatchingStrategy::Grid { cell, .. } if !cell.is_finite() || cell <= 0.0 || cell > 1.0 =>
+                Err("the cell must be between 0 and 1 degree"),
+            MatchingStrategy::Duration { min_duration, .. } if min_duration <= 0 =>
+                Err("the duration must be at least 1 second"),
+            _ => Ok(()),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -63,20 +317,155 @@ pub enum IpcRequest {
     GetEnclaveReport,
     NewTaskEncryptionKey { userPubKey: String },
     AddPersonalData { input: IpcInputData },
+    RegisterUser { input: IpcInputRegistration },
+    // A new test result for the locations a user already stored, `encryptedData` is
+    // `{"nonce", "timestamp", "testResult", "since", "declaration"}`, see `data` in the enclave
+    UpdateUserStatus { input: IpcInputData },
     FindMatch { input: IpcInputMatch },
+    GetFeatureSwitches,
+    // Refused, the admin socket serves it (`AdminOp::SetFeatureSwitch`). Kept so the old clients get an answer
+    SetFeatureSwitch { feature: Feature, enabled: bool },
+    OpenChannel { handshake: ChannelHandshake },
+    ConnectPeer { uri: String },
+    FindMatchFederated { input: IpcInputMatch },
+    FederatedQuery { sender: String, payload: String },
+    GetStats,
+    // Differentially private statistics for public health dashboards
+    GetAggregates,
+    // The k-anonymous statistics of a health authority, see `export` in the enclave
+    ExportExposureStatistics { request: ExportRequest },
+    Ping { nonce: String },
+    GetHealth,
+    GetReadiness,
+    // Hello handshake, clients may say which protocol version they speak
+    GetProtocolVersion {
+        #[serde(default, rename = "clientVersion", skip_serializing_if = "Option::is_none")] client_version: Option<u32>,
+    },
+    // `FindMatch` in the background, see `jobs`
+    SubmitMatchJob { input: IpcInputMatch },
+    GetMatchJob { #[serde(rename = "jobId")] job_id: String },
+    // The audit log from entry `from` on, see `audit_u`
+    ExportAuditLog { #[serde(default)] from: u64 },
+    // Transparency: what the running enclave measures to, to compare with a reproducible build
+    GetEnclaveInfo,
+    // The report of a `GetEnclaveReport` that came while IAS was down, see `attestation_jobs`
+    GetAttestationJob { #[serde(rename = "jobId")] job_id: String },
+    // Keepalive of long-lived connections, `sentAt` in milliseconds of the client's clock, see `router`
+    Heartbeat { #[serde(rename = "sentAt")] sent_at: u64 },
+    // The cached report of a quote, by the SHA-256 of its bytes (hex), see `attestation::cache`
+    GetCachedReport { #[serde(rename = "quoteHash")] quote_hash: String },
+}
+
+// A hex field that can be large, the location history of `AddPersonalData`: the text as received, shared
+// rather than copied when the request is (`process` keeps one for the audit and the dead letters, a timed
+// request runs on a thread of its own), and decoded chunk by chunk into the ecall buffers (`chunks::upload_hex`)
+// rather than all at once.
+#[derive(Clone, PartialEq)]
+pub struct HexPayload(Bytes);
+
+impl HexPayload {
+    pub fn as_str(&self) -> &str {
+        // Only ever built from a str
+        unsafe { str::from_utf8_unchecked(&self.0) }
+    }
+
+    pub fn len(&self) -> usize { self.0.len() }
+
+    pub fn is_empty(&self) -> bool { self.0.is_empty() }
+}
+
+impl From<String> for HexPayload {
+    fn from(text: String) -> Self { HexPayload(Bytes::from(text)) }
+}
+
+impl<'a> From<&'a str> for HexPayload {
+    fn from(text: &'a str) -> Self { HexPayload(Bytes::from(text)) }
+}
+
+// The length only, it doesn't belong in the logs
+impl fmt::Debug for HexPayload {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HexPayload({} digits)", self.len())
+    }
 }
 
+impl serde::Serialize for HexPayload {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for HexPayload {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+        impl<'de> de::Visitor<'de> for Visitor {
+            type Value = HexPayload;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a hex string")
+            }
+
+            // Copied once out of the frame
+            fn visit_str<E: de::Error>(self, text: &str) -> Result<HexPayload, E> {
+                Ok(HexPayload::from(text))
+            }
+
+            // Taken over from a `Value`
+            fn visit_string<E: de::Error>(self, text: String) -> Result<HexPayload, E> {
+                Ok(HexPayload::from(text))
+            }
+        }
+        deserializer.deserialize_str(Visitor)
+    }
+}
+
+// How the client compressed the plaintext of `encryptedData` before encrypting it, see `compression` in the enclave.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    Deflate,
+}
+
+impl Compression {
+    // As the ecall takes it, 0 is uncompressed
+    pub fn code(self) -> u8 {
+        match self {
+            Compression::Deflate => 1,
+        }
+    }
+}
+
+// `encryptedSignature` is required once the user registered a signing key (`RegisterUser`), see `users` in the enclave.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct IpcInputData {
     #[serde(rename = "encryptedUserId")] pub encrypted_userid: String,
-    #[serde(rename = "encryptedData")] pub encrypted_data: String,
+    #[serde(rename = "encryptedData")] pub encrypted_data: HexPayload,
     #[serde(rename = "userPubKey")] pub user_pub_key: String,
+    #[serde(rename = "encryptedSignature", default, skip_serializing_if = "String::is_empty")] pub encrypted_signature: String,
+    // `AddPersonalData` only, uncompressed when missing
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub compression: Option<Compression>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct IpcInputMatch {
     #[serde(rename = "encryptedUserId")] pub encrypted_userid: String,
     #[serde(rename = "userPubKey")] pub user_pub_key: String,
+    #[serde(rename = "encryptedSignature", default, skip_serializing_if = "String::is_empty")] pub encrypted_signature: String,
+    // The deployment's strategy when missing
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub matching: Option<MatchingStrategy>,
+    // `FindMatch` only: the matches new since the user's previous query, with the others summed up, see
+    // `incremental` in the enclave
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub incremental: Option<bool>,
+    // `SubmitMatchJob` only: the finished job is also sent to the client that submitted it, see `ipc_listener`
+    #[serde(default, skip_serializing_if = "Option::is_none")] pub notify: Option<bool>,
+}
+
+// `encryptedData` is the user's signing public key and the signature of `userPubKey` with it
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct IpcInputRegistration {
+    #[serde(rename = "encryptedUserId")] pub encrypted_userid: String,
+    #[serde(rename = "encryptedData")] pub encrypted_data: String,
+    #[serde(rename = "userPubKey")] pub user_pub_key: String,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -89,14 +478,111 @@ pub struct IpcStatusResult {
 
 impl IpcMessageResponse {
     pub fn from_response(response: IpcResponse, id: String) -> Self {
-        Self { id, response }
+        Self { id, response, deprecations: Vec::new(), identity: None }
+    }
+}
+
+// Every request `type`, keep in line with `IpcRequest::command`.
+pub const COMMANDS: &[&str] = &[
+    "GetEnclaveReport", "NewTaskEncryptionKey", "AddPersonalData", "RegisterUser", "FindMatch", "GetFeatureSwitches",
+    "SetFeatureSwitch", "OpenChannel", "ConnectPeer", "FindMatchFederated", "FederatedQuery", "GetStats",
+    "GetAggregates", "Ping", "GetHealth", "GetReadiness", "GetProtocolVersion", "SubmitMatchJob", "GetMatchJob",
+    "ExportAuditLog", "GetEnclaveInfo", "GetAttestationJob", "ExportExposureStatistics", "UpdateUserStatus",
+    "Heartbeat", "GetCachedReport",
+];
+
+impl IpcRequest {
+    // The `type` of the request on the wire.
+    pub fn command(&self) -> &'static str {
+        match self {
+            IpcRequest::GetEnclaveReport => "GetEnclaveReport",
+            IpcRequest::NewTaskEncryptionKey { .. } => "NewTaskEncryptionKey",
+            IpcRequest::AddPersonalData { .. } => "AddPersonalData",
+            IpcRequest::RegisterUser { .. } => "RegisterUser",
+            IpcRequest::UpdateUserStatus { .. } => "UpdateUserStatus",
+            IpcRequest::FindMatch { .. } => "FindMatch",
+            IpcRequest::GetFeatureSwitches => "GetFeatureSwitches",
+            IpcRequest::SetFeatureSwitch { .. } => "SetFeatureSwitch",
+            IpcRequest::OpenChannel { .. } => "OpenChannel",
+            IpcRequest::ConnectPeer { .. } => "ConnectPeer",
+            IpcRequest::FindMatchFederated { .. } => "FindMatchFederated",
+            IpcRequest::FederatedQuery { .. } => "FederatedQuery",
+            IpcRequest::GetStats => "GetStats",
+            IpcRequest::GetAggregates => "GetAggregates",
+            IpcRequest::ExportExposureStatistics { .. } => "ExportExposureStatistics",
+            IpcRequest::Ping { .. } => "Ping",
+            IpcRequest::GetHealth => "GetHealth",
+            IpcRequest::GetReadiness => "GetReadiness",
+            IpcRequest::GetProtocolVersion { .. } => "GetProtocolVersion",
+            IpcRequest::SubmitMatchJob { .. } => "SubmitMatchJob",
+            IpcRequest::GetMatchJob { .. } => "GetMatchJob",
+            IpcRequest::ExportAuditLog { .. } => "ExportAuditLog",
+            IpcRequest::GetEnclaveInfo => "GetEnclaveInfo",
+            IpcRequest::GetAttestationJob { .. } => "GetAttestationJob",
+            IpcRequest::Heartbeat { .. } => "Heartbeat",
+            IpcRequest::GetCachedReport { .. } => "GetCachedReport",
+        }
     }
 }
 
 impl IpcMessageRequest {
     pub fn from_request(request: IpcRequest, id: String) -> Self {
-        Self { id, request }
+        Self { id, timeout_ms: None, tenant: None, request }
+    }
+}
+
+// The members `decode_frame` looks at first, the others are skipped without being copied
+#[derive(Deserialize)]
+struct Peek {
+    jsonrpc: Option<IgnoredAny>,
+    id: Option<Value>,
+    #[serde(rename = "type")]
+    command: Option<Value>,
+}
+
+// What is known of a native envelope that didn't decode, for the error and the dead letters
+pub struct Unreadable {
+    pub id: String,
+    pub command: Option<String>,
+    pub error: serde_json::Error,
+}
+
+// A native envelope decoded straight from the frame, JSON or msgpack, without the `Value` tree of the rest of
+// the frames: `None` for anything else (a JSON-RPC request or batch, neither encoding), to be read as a `Value`.
+// The strings are read in place, `encryptedData` is copied once out of the frame and decoded from there a chunk
+// at a time on its way to the enclave (`chunks::upload_hex`).
+pub fn decode_frame(frame: &[u8]) -> Option<Result<IpcMessageRequest, Unreadable>> {
+    match WireFormat::detect(frame) {
+        WireFormat::Json => {
+            if frame.iter().find(|b| !b.is_ascii_whitespace()) != Some(&b'{') {
+                return None;
+            }
+            let peek: Peek = serde_json::from_slice(frame).ok()?;
+            decode_envelope(peek, || serde_json::from_slice(frame))
+        },
+        WireFormat::Msgpack => {
+            // fixmap, map 16/32; an array is a batch
+            match frame[0] {
+                0x80..=0x8f | 0xde | 0xdf => {},
+                _ => return None,
+            }
+            let peek: Peek = rmp_serde::from_slice(frame).ok()?;
+            // Failing like the JSON envelopes, with their error code
+            decode_envelope(peek, || rmp_serde::from_slice(frame).map_err(<serde_json::Error as de::Error>::custom))
+        },
+    }
+}
+
+fn decode_envelope<F>(peek: Peek, decode: F) -> Option<Result<IpcMessageRequest, Unreadable>>
+    where F: FnOnce() -> Result<IpcMessageRequest, serde_json::Error> {
+    if peek.jsonrpc.is_some() {
+        return None;
     }
+    Some(decode().map_err(|error| Unreadable {
+        id: peek.id.as_ref().and_then(Value::as_str).unwrap_or_default().to_string(),
+        command: peek.command.as_ref().and_then(Value::as_str).map(String::from),
+        error,
+    }))
 }
 
 impl From<Message> for IpcMessageRequest {
@@ -107,6 +593,13 @@ impl From<Message> for IpcMessageRequest {
     }
 }
 
+impl Into<Message> for IpcMessageRequest {
+    fn into(self) -> Message {
+        let msg = serde_json::to_vec(&self).unwrap();
+        Message::from(&msg)
+    }
+}
+
 impl Into<Message> for IpcMessageResponse {
     fn into(self) -> Message {
         let msg = serde_json::to_vec(&self).unwrap();
@@ -118,14 +611,707 @@ pub(crate) trait UnwrapError<T> {
     fn unwrap_or_error(self) -> T;
 }
 
-impl<E: std::fmt::Display> UnwrapError<IpcResponse> for Result<IpcResponse, E> {
+impl<E: Into<failure::Error>> UnwrapError<IpcResponse> for Result<IpcResponse, E> {
     fn unwrap_or_error(self) -> IpcResponse {
         match self {
             Ok(m) => m,
             Err(e) => {
+                let e = e.into();
                 error!("Unwrapped Message failed: {}", e);
-                IpcResponse::Error {msg: format!("{}", e)}
+                IpcResponse::Error { code: errors::error_code(&e), msg: format!("{}", e) }
             }
         }
     }
-}
\ No newline at end of file
+}
+
+// Golden-file compatibility suite. Every protocol message is serialized to JSON and msgpack and
+// compared byte-for-byte with the fixtures in `tests/golden`, so a serde attribute change that would
+// break deployed clients fails `cargo test` instead of going unnoticed.
+// The msgpack fixtures are the msgpack encoding of the same JSON document (`serde_json::Value`), as the
+// server sends them (`safetrace_client::wire`).
+// To regenerate the fixtures after an *intentional* protocol change run:
+// `SAFETRACE_UPDATE_GOLDEN=1 cargo test golden`
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::networking::jobs::JobState;
+    use crate::networking::attestation_jobs::AttestationState;
+    use safetrace_client::audit::{AuditCheckpoint, AuditEntry, AuditKind, AuditSummary};
+    use safetrace_client::manifest::BuildProvenance;
+    use crate::networking::peer::NodeAttestation;
+    use crate::networking::{jsonrpc, validation};
+    use hex::FromHex;
+    use proptest::collection::{hash_map, vec};
+    use proptest::prelude::*;
+    use proptest::sample::Index;
+    use crate::stats_u::{CellCount, EpochCount, EpochStats};
+    use serde::Serialize;
+    use serde_json::Value;
+    use std::{env, fs, path::PathBuf};
+
+    const ID: &str = "a1b2c3d4e5";
+    const USER_PUBKEY: &str = "2b4d1a7e2b4d1a7e2b4d1a7e2b4d1a7e2b4d1a7e2b4d1a7e2b4d1a7e2b4d1a7e\
+                               2b4d1a7e2b4d1a7e2b4d1a7e2b4d1a7e2b4d1a7e2b4d1a7e2b4d1a7e2b4d1a7e";
+    const ENCRYPTED_USERID: &str = "e1a3c5f7d9b2";
+    const ENCRYPTED_DATA: &str = "9f8e7d6c5b4a39281706f5e4d3c2b1a0";
+    const SIGNING_KEY: &str = "5f9d3d0a1c1e7b3f2a6c8e4d0b9a7c5e3f1d2b4a";
+    const JOB_ID: &str = "0f1e2d3c4b5a69788796a5b4c3d2e1f0";
+    const QUOTE_HASH: &str = "5d41402abc4b2a76b9719d911017c5925d41402abc4b2a76b9719d911017c592";
+
+    fn handshake() -> ChannelHandshake {
+        ChannelHandshake {
+            attestation: NodeAttestation {
+                signing_key: SIGNING_KEY.to_string(),
+                report: r#"{"id":"123"}"#.to_string(),
+                signature: "c2lnbmF0dXJl".to_string(),
+                certificate: "-----BEGIN CERTIFICATE-----".to_string(),
+                ca: "-----BEGIN CERTIFICATE-----".to_string(),
+            },
+            channel_pub_key: USER_PUBKEY.to_string(),
+            sig: "ab".repeat(65),
+        }
+    }
+
+    fn export_request() -> ExportRequest {
+        ExportRequest { issued_at: 1589000000, format: "csv".to_string(), precision: 6, from: 18380, to: 18390, signature: "ab".repeat(65) }
+    }
+
+    fn enclave_report() -> EnclaveReport {
+        EnclaveReport {
+            signing_key: SIGNING_KEY.to_string(),
+            report: "7b226964223a22313233227d".to_string(),
+            signature: "c2lnbmF0dXJl".to_string(),
+            certificate: "-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----\n".to_string(),
+            ca: "-----BEGIN CERTIFICATE-----\nMIIC\n-----END CERTIFICATE-----\n".to_string(),
+            bundle: "lAGseyJpZCI6IjEyMyJ9rGMybG5ibUYwZFhKbJLEAzCCAcQDMIIC".to_string(),
+        }
+    }
+
+    fn golden_path(name: &str, ext: &str) -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden").join(format!("{}.{}", name, ext))
+    }
+
+    fn check_golden<T: Serialize>(name: &str, msg: &T) {
+        let json = serde_json::to_string(msg).unwrap();
+        let msgpack = WireFormat::Msgpack.encode(msg).unwrap();
+        if env::var("SAFETRACE_UPDATE_GOLDEN").is_ok() {
+            fs::write(golden_path(name, "json"), format!("{}\n", json)).unwrap();
+            fs::write(golden_path(name, "msgpack"), &msgpack).unwrap();
+        }
+        let golden_json = fs::read_to_string(golden_path(name, "json")).unwrap();
+        let golden_msgpack = fs::read(golden_path(name, "msgpack")).unwrap();
+        assert_eq!(golden_json.trim_end(), json, "JSON encoding of `{}` changed", name);
+        assert_eq!(golden_msgpack, msgpack, "msgpack encoding of `{}` changed", name);
+    }
+
+    // Requests are decoded by the server, so they must also parse back from both fixtures.
+    fn check_golden_request(name: &str, request: IpcRequest) {
+        assert!(COMMANDS.contains(&request.command()), "{} is missing from COMMANDS", request.command());
+        check_golden_envelope(name, IpcMessageRequest::from_request(request, ID.to_string()));
+    }
+
+    fn check_golden_envelope(name: &str, msg: IpcMessageRequest) {
+        check_golden(name, &msg);
+
+        let golden_json = fs::read_to_string(golden_path(name, "json")).unwrap();
+        let from_json: IpcMessageRequest = serde_json::from_str(&golden_json).unwrap();
+        assert_eq!(serde_json::to_string(&from_json).unwrap(), serde_json::to_string(&msg).unwrap());
+
+        let golden_msgpack = fs::read(golden_path(name, "msgpack")).unwrap();
+        let doc = WireFormat::Msgpack.decode(&golden_msgpack).unwrap();
+        let from_msgpack: IpcMessageRequest = serde_json::from_value(doc).unwrap();
+        assert_eq!(serde_json::to_string(&from_msgpack).unwrap(), serde_json::to_string(&msg).unwrap());
+        // As the server reads them, without the `Value`
+        for frame in &[golden_json.as_bytes(), &golden_msgpack[..]] {
+            let decoded = decode_frame(frame).unwrap().ok().unwrap();
+            assert_eq!(serde_json::to_string(&decoded).unwrap(), serde_json::to_string(&msg).unwrap());
+        }
+    }
+
+    // Responses are only ever encoded by the server (several `IpcResults` variants share the
+    // `result` tag, so they don't round-trip), hence we only pin their encoding.
+    fn check_golden_response(name: &str, response: IpcResponse) {
+        check_golden(name, &IpcMessageResponse::from_response(response, ID.to_string()));
+    }
+
+    #[test]
+    fn test_golden_requests() {
+        check_golden_request("request_get_enclave_report", IpcRequest::GetEnclaveReport);
+        check_golden_request("request_new_task_encryption_key",
+                             IpcRequest::NewTaskEncryptionKey { userPubKey: USER_PUBKEY.to_string() });
+        check_golden_request("request_add_personal_data", IpcRequest::AddPersonalData {
+            input: IpcInputData {
+                encrypted_userid: ENCRYPTED_USERID.to_string(),
+                encrypted_data: ENCRYPTED_DATA.into(),
+                user_pub_key: USER_PUBKEY.to_string(),
+                encrypted_signature: String::new(),
+                compression: None,
+            }
+        });
+        check_golden_request("request_add_personal_data_deflate", IpcRequest::AddPersonalData {
+            input: IpcInputData {
+                encrypted_userid: ENCRYPTED_USERID.to_string(),
+                encrypted_data: ENCRYPTED_DATA.into(),
+                user_pub_key: USER_PUBKEY.to_string(),
+                encrypted_signature: String::new(),
+                compression: Some(Compression::Deflate),
+            }
+        });
+        check_golden_request("request_register_user", IpcRequest::RegisterUser {
+            input: IpcInputRegistration {
+                encrypted_userid: ENCRYPTED_USERID.to_string(),
+                encrypted_data: ENCRYPTED_DATA.to_string(),
+                user_pub_key: USER_PUBKEY.to_string(),
+            }
+        });
+        check_golden_request("request_update_user_status", IpcRequest::UpdateUserStatus {
+            input: IpcInputData {
+                encrypted_userid: ENCRYPTED_USERID.to_string(),
+                encrypted_data: ENCRYPTED_DATA.into(),
+                user_pub_key: USER_PUBKEY.to_string(),
+                encrypted_signature: String::new(),
+                compression: None,
+            }
+        });
+        check_golden_request("request_find_match", IpcRequest::FindMatch {
+            input: IpcInputMatch { encrypted_userid: ENCRYPTED_USERID.to_string(), user_pub_key: USER_PUBKEY.to_string(), encrypted_signature: String::new(), matching: None, incremental: None, notify: None }
+        });
+        let mut tenant = IpcMessageRequest::from_request(IpcRequest::FindMatch {
+            input: IpcInputMatch { encrypted_userid: ENCRYPTED_USERID.to_string(), user_pub_key: USER_PUBKEY.to_string(), encrypted_signature: String::new(), matching: None, incremental: None, notify: None }
+        }, ID.to_string());
+        tenant.tenant = Some("ch-ge".to_string());
+        check_golden_envelope("request_find_match_tenant", tenant);
+        check_golden_request("request_find_match_signed", IpcRequest::FindMatch {
+            input: IpcInputMatch {
+                encrypted_userid: ENCRYPTED_USERID.to_string(),
+                user_pub_key: USER_PUBKEY.to_string(),
+                encrypted_signature: ENCRYPTED_DATA.to_string(),
+                matching: None,
+                incremental: None,
+                notify: None,
+            }
+        });
+        check_golden_request("request_find_match_strategy", IpcRequest::FindMatch {
+            input: IpcInputMatch {
+                encrypted_userid: ENCRYPTED_USERID.to_string(),
+                user_pub_key: USER_PUBKEY.to_string(),
+                encrypted_signature: String::new(),
+                matching: Some(MatchingStrategy::Duration { distance: 25.0, min_duration: 900 }),
+                incremental: None,
+                notify: None,
+            }
+        });
+        check_golden_request("request_get_feature_switches", IpcRequest::GetFeatureSwitches);
+        check_golden_request("request_set_feature_switch",
+                             IpcRequest::SetFeatureSwitch { feature: Feature::Ingest, enabled: false });
+        check_golden_request("request_open_channel", IpcRequest::OpenChannel { handshake: handshake() });
+        check_golden_request("request_connect_peer", IpcRequest::ConnectPeer { uri: "tcp://peer.example.org:5552".to_string() });
+        check_golden_request("request_find_match_federated", IpcRequest::FindMatchFederated {
+            input: IpcInputMatch { encrypted_userid: ENCRYPTED_USERID.to_string(), user_pub_key: USER_PUBKEY.to_string(), encrypted_signature: String::new(), matching: None, incremental: None, notify: None }
+        });
+        check_golden_request("request_federated_query",
+                             IpcRequest::FederatedQuery { sender: SIGNING_KEY.to_string(), payload: ENCRYPTED_DATA.to_string() });
+        check_golden_request("request_get_stats", IpcRequest::GetStats);
+        check_golden_request("request_get_aggregates", IpcRequest::GetAggregates);
+        check_golden_request("request_export_exposure_statistics", IpcRequest::ExportExposureStatistics { request: export_request() });
+        check_golden_request("request_ping", IpcRequest::Ping { nonce: "5eed".to_string() });
+        check_golden_request("request_get_health", IpcRequest::GetHealth);
+        check_golden_request("request_get_readiness", IpcRequest::GetReadiness);
+        check_golden_request("request_get_protocol_version", IpcRequest::GetProtocolVersion { client_version: Some(1) });
+        check_golden_request("request_submit_match_job", IpcRequest::SubmitMatchJob {
+            input: IpcInputMatch { encrypted_userid: ENCRYPTED_USERID.to_string(), user_pub_key: USER_PUBKEY.to_string(), encrypted_signature: String::new(), matching: None, incremental: None, notify: None }
+        });
+        check_golden_request("request_get_match_job", IpcRequest::GetMatchJob { job_id: JOB_ID.to_string() });
+        check_golden_request("request_export_audit_log", IpcRequest::ExportAuditLog { from: 41 });
+        check_golden_request("request_get_enclave_info", IpcRequest::GetEnclaveInfo);
+        check_golden_request("request_get_attestation_job", IpcRequest::GetAttestationJob { job_id: JOB_ID.to_string() });
+        check_golden_request("request_heartbeat", IpcRequest::Heartbeat { sent_at: 1585699199990 });
+        check_golden_request("request_get_cached_report", IpcRequest::GetCachedReport { quote_hash: QUOTE_HASH.to_string() });
+    }
+
+    #[test]
+    fn test_golden_responses() {
+        check_golden_response("response_get_enclave_report", IpcResponse::GetEnclaveReport {
+            result: IpcResults::EnclaveReport(enclave_report())
+        });
+        check_golden_response("response_get_enclave_report_pending", IpcResponse::GetEnclaveReport {
+            result: IpcResults::AttestationJob { job: AttestationJob {
+                job_id: JOB_ID.to_string(), state: AttestationState::Pending, attempts: 1, next_attempt: Some(1589000060),
+                error: Some("The attestation service is unavailable = (IAS answered 503 Service Unavailable)".to_string()), report: None,
+            } }
+        });
+        check_golden_response("response_get_attestation_job_done", IpcResponse::GetAttestationJob {
+            result: IpcResults::AttestationJob { job: AttestationJob {
+                job_id: JOB_ID.to_string(), state: AttestationState::Done, attempts: 3, next_attempt: None, error: None, report: Some(enclave_report()),
+            } }
+        });
+        check_golden_response("response_new_task_encryption_key", IpcResponse::NewTaskEncryptionKey {
+            result: IpcResults::DHKey { taskPubKey: USER_PUBKEY.to_string(), sig: "ab".repeat(65) }
+        });
+        check_golden_response("response_add_personal_data", IpcResponse::AddPersonalData {
+            result: IpcResults::AddPersonalData { status: Status::Passed, encryptedOutput: String::new(), error: None }
+        });
+        check_golden_response("response_add_personal_data_receipt", IpcResponse::AddPersonalData {
+            result: IpcResults::AddPersonalData { status: Status::Failed, encryptedOutput: ENCRYPTED_DATA.to_string(), error: None }
+        });
+        check_golden_response("response_add_personal_data_replay", IpcResponse::AddPersonalData {
+            result: IpcResults::AddPersonalData {
+                status: Status::Failed, encryptedOutput: ENCRYPTED_DATA.to_string(), error: Some(Rejection::Replay),
+            }
+        });
+        check_golden_response("response_add_personal_data_capacity", IpcResponse::AddPersonalData {
+            result: IpcResults::AddPersonalData {
+                status: Status::Failed, encryptedOutput: ENCRYPTED_DATA.to_string(), error: Some(Rejection::CapacityExceeded),
+            }
+        });
+        check_golden_response("response_add_personal_data_clock_skew", IpcResponse::AddPersonalData {
+            result: IpcResults::AddPersonalData {
+                status: Status::Failed, encryptedOutput: ENCRYPTED_DATA.to_string(), error: Some(Rejection::ClockSkew),
+            }
+        });
+        check_golden_response("response_register_user", IpcResponse::RegisterUser {
+            result: IpcResults::RegisterUser { status: Status::Passed }
+        });
+        check_golden_response("response_update_user_status", IpcResponse::UpdateUserStatus {
+            result: IpcResults::UpdateUserStatus { status: Status::Passed, encryptedOutput: ENCRYPTED_DATA.to_string(), error: None }
+        });
+        check_golden_response("response_find_match_passed", IpcResponse::FindMatch {
+            result: IpcResults::FindMatch { status: Status::Passed, encryptedOutput: ENCRYPTED_DATA.to_string(), matching: None }
+        });
+        check_golden_response("response_find_match_failed", IpcResponse::FindMatch {
+            result: IpcResults::FindMatch { status: Status::Failed, encryptedOutput: String::new(), matching: None }
+        });
+        check_golden_response("response_find_match_strategy", IpcResponse::FindMatch {
+            result: IpcResults::FindMatch {
+                status: Status::Passed,
+                encryptedOutput: ENCRYPTED_DATA.to_string(),
+                matching: Some(MatchingStrategy::Grid { cell: 0.0005, min_overlap: 300 }),
+            }
+        });
+        let features = Feature::ALL.iter().map(|&f| (f, f != Feature::Ingest)).collect();
+        check_golden_response("response_feature_switches", IpcResponse::SetFeatureSwitch {
+            result: IpcResults::FeatureSwitches { features }
+        });
+        check_golden_response("response_open_channel", IpcResponse::OpenChannel {
+            result: IpcResults::Channel { handshake: handshake() }
+        });
+        check_golden_response("response_connect_peer", IpcResponse::ConnectPeer {
+            result: IpcResults::Peer { peer_address: SIGNING_KEY.to_string() }
+        });
+        check_golden_response("response_find_match_federated", IpcResponse::FindMatchFederated {
+            result: IpcResults::FindMatch { status: Status::Passed, encryptedOutput: ENCRYPTED_DATA.to_string(), matching: None }
+        });
+        check_golden_response("response_federated_query", IpcResponse::FederatedQuery {
+            result: IpcResults::FederatedAnswer { payload: ENCRYPTED_DATA.to_string() }
+        });
+        check_golden_response("response_get_stats", IpcResponse::GetStats {
+            result: IpcResults::Stats { stats: StorageStats {
+                epochs: vec![EpochStats { epoch: 18353, records: 3, users: 2, bytes: 240 }],
+                records: 3,
+                users: 2,
+                bytes_sealed: 1184,
+            } }
+        });
+        check_golden_response("response_get_aggregates", IpcResponse::GetAggregates {
+            result: IpcResults::Aggregates { aggregates: Aggregates {
+                epsilon: 1.0,
+                precision: 5,
+                cells: vec![CellCount { cell: "dr5ru".to_string(), infected: 12 }],
+                epochs: vec![EpochCount { epoch: 18353, users: 41, infected: 6 }],
+            } }
+        });
+        check_golden_response("response_export_exposure_statistics", IpcResponse::ExportExposureStatistics {
+            result: IpcResults::ExposureExport { bundle: ExportBundle {
+                authority: SIGNING_KEY.to_string(),
+                issued_at: 1589000000,
+                format: "csv".to_string(),
+                precision: 6,
+                from: 18380,
+                to: 18390,
+                k: 10,
+                generated_at: 1589000002,
+                rows: 1,
+                body: "cell,epoch,users,infected\ndr5ru7,18385,12,\n".to_string(),
+                signature: "cd".repeat(65),
+            } }
+        });
+        check_golden_response("response_ping", IpcResponse::Ping {
+            result: IpcResults::Pong { nonce: "5eed".to_string(), received_at: 1585699200000, sent_at: 1585699200002 }
+        });
+        check_golden_response("response_heartbeat", IpcResponse::Heartbeat {
+            result: IpcResults::Heartbeat { sent_at: 1585699199990, received_at: 1585699200000, replied_at: 1585699200001, idle_timeout: 60 }
+        });
+        check_golden_response("response_get_cached_report", IpcResponse::GetCachedReport {
+            result: IpcResults::CachedReport {
+                quote_hash: QUOTE_HASH.to_string(),
+                quote: "AgAAANoKAAAHAAYAAAAAABYB+Vw5ueowf+qruQGtw+54eaWW7MiyrIAooQw/uU3e".to_string(),
+                bundle: "lAGseyJpZCI6IjEyMyJ9rGMybG5ibUYwZFhKbJLEAzCCAcQDMIIC".to_string(),
+                cached_at: 1589000000,
+            }
+        });
+        let mut checks = BTreeMap::new();
+        checks.insert("enclave".to_string(), HealthCheck { ok: true, detail: None });
+        checks.insert("storage".to_string(), HealthCheck { ok: false, detail: Some("data.sealed isn't a file".to_string()) });
+        check_golden_response("response_get_readiness", IpcResponse::GetReadiness {
+            result: IpcResults::Health { ok: false, checks, build: BuildInfo { version: "1.0.0".to_string(), simulation: false }, degraded: None }
+        });
+        let mut schemas = BTreeMap::new();
+        schemas.insert("FindMatch".to_string(), 1);
+        schemas.insert("Ping".to_string(), 2);
+        check_golden_response("response_get_protocol_version", IpcResponse::GetProtocolVersion {
+            result: IpcResults::ProtocolVersion {
+                version: 2,
+                min_version: 1,
+                compatible: true,
+                commands: vec!["FindMatch".to_string(), "Ping".to_string()],
+                schemas,
+                capabilities: vec!["jsonrpc-2.0".to_string()],
+                mr_enclave: "ab".repeat(32),
+                quantization: None,
+                max_frame_size: None,
+            }
+        });
+        check_golden_response("response_get_protocol_version_quantized", IpcResponse::GetProtocolVersion {
+            result: IpcResults::ProtocolVersion {
+                version: 7,
+                min_version: 1,
+                compatible: true,
+                commands: vec!["FindMatch".to_string()],
+                schemas: BTreeMap::new(),
+                capabilities: vec!["jsonrpc-2.0".to_string()],
+                mr_enclave: "ab".repeat(32),
+                quantization: Some(Quantization { grid: 0.001, time_bucket: 300 }),
+                max_frame_size: None,
+            }
+        });
+        check_golden_response("response_get_protocol_version_limits", IpcResponse::GetProtocolVersion {
+            result: IpcResults::ProtocolVersion {
+                version: 23,
+                min_version: 1,
+                compatible: true,
+                commands: vec!["FindMatch".to_string()],
+                schemas: BTreeMap::new(),
+                capabilities: vec!["frame-size-limit".to_string()],
+                mr_enclave: "ab".repeat(32),
+                quantization: None,
+                max_frame_size: Some(1_048_576),
+            }
+        });
+        check_golden_response("response_submit_match_job", IpcResponse::SubmitMatchJob {
+            result: IpcResults::MatchJob { job: MatchJob {
+                job_id: JOB_ID.to_string(), state: JobState::Queued, processed: 0, total: 0, encrypted_output: String::new(), error: None, matching: None,
+            } }
+        });
+        check_golden_response("response_get_match_job_running", IpcResponse::GetMatchJob {
+            result: IpcResults::MatchJob { job: MatchJob {
+                job_id: JOB_ID.to_string(), state: JobState::Running, processed: 2000, total: 5120, encrypted_output: String::new(), error: None, matching: None,
+            } }
+        });
+        check_golden_response("response_get_match_job_done", IpcResponse::GetMatchJob {
+            result: IpcResults::MatchJob { job: MatchJob {
+                job_id: JOB_ID.to_string(), state: JobState::Done, processed: 5120, total: 5120, encrypted_output: ENCRYPTED_DATA.to_string(), error: None, matching: None,
+            } }
+        });
+        let (head, key) = ("22".repeat(32), "ab".repeat(20));
+        check_golden_response("response_export_audit_log", IpcResponse::ExportAuditLog {
+            result: IpcResults::AuditLog {
+                records: vec![
+                    AuditRecord::Entry(AuditEntry {
+                        seq: 41, timestamp: 1589000000, kind: AuditKind::Ingest, actor: "ipc".to_string(),
+                        detail: serde_json::json!({"command": "AddPersonalData"}), prev: "11".repeat(32), hash: head.clone(),
+                    }),
+                    AuditRecord::Checkpoint(AuditCheckpoint {
+                        prev_seq: 0, prev_head: "00".repeat(32), seq: 41, head, timestamp: 1589000005, resumed: true,
+                        signing_key: key.clone(), signature: "cd".repeat(65),
+                    }),
+                ],
+                verification: AuditVerification {
+                    valid: true,
+                    summary: AuditSummary {
+                        entries: 1, checkpoints: 1, first_seq: 41, last_seq: 41, signed_seq: 41, resumptions: 1, signing_keys: vec![key],
+                    },
+                    error: None,
+                },
+                next: Some(42),
+            }
+        });
+        let (mr_enclave, mr_signer) = ("3f".repeat(32), "8a".repeat(32));
+        let manifest = EnclaveManifest {
+            version: 1, mr_enclave: mr_enclave.clone(), mr_signer: mr_signer.clone(), isv_prod_id: 0, isv_svn: 1, debug: false,
+            build: BuildProvenance {
+                source: "https://github.com/sbellem/SafeTrace".to_string(),
+                commit: format!("d287e91c{}", "0".repeat(32)),
+                toolchain: "nightly-2019-08-01".to_string(),
+                sgx_sdk: "2.9.1".to_string(),
+                enclave_sha256: "e4".repeat(32),
+            },
+        };
+        check_golden_response("response_get_enclave_info", IpcResponse::GetEnclaveInfo {
+            result: IpcResults::EnclaveInfo {
+                mr_enclave, mr_signer, isv_prod_id: 0, isv_svn: 1, debug: false,
+                signing_key: SIGNING_KEY.to_string(),
+                bundle: "lAGseyJpZCI6IjEyMyJ9rGMybG5ibUYwZFhKbJLEAzCCAcQDMIIC".to_string(),
+                manifest: Some(manifest),
+                manifest_matches: Some(true),
+                settings: None,
+            }
+        });
+        let mut deprecated = IpcMessageResponse::from_response(IpcResponse::Ping {
+            result: IpcResults::Pong { nonce: "5eed".to_string(), received_at: 1585699200000, sent_at: 1585699200002 }
+        }, ID.to_string());
+        deprecated.deprecations.push(DeprecationNotice {
+            command: "Ping".to_string(),
+            sunset: "2021-01-01".to_string(),
+            replacement: Some("GetHealth".to_string()),
+            message: "Ping is superseded by GetHealth".to_string(),
+        });
+        check_golden("response_deprecated", &deprecated);
+        let mut signed = IpcMessageResponse::from_response(IpcResponse::Ping {
+            result: IpcResults::Pong { nonce: "5eed".to_string(), received_at: 1585699200000, sent_at: 1585699200002 }
+        }, ID.to_string());
+        signed.identity = Some(ServerIdentity {
+            signing_key: SIGNING_KEY.to_string(), boot: "0f".repeat(16), counter: 42, signature: "ab".repeat(65),
+        });
+        check_golden("response_signed", &signed);
+        check_golden_response("response_error", IpcResponse::Error { code: ErrorCode::EnclaveError, msg: "Error inside the Enclave = (KeysError)".to_string() });
+    }
+
+    #[test]
+    fn test_error_codes() {
+        let code = |response: IpcResponse| match response {
+            IpcResponse::Error { code, .. } => code,
+            _ => panic!("not an error"),
+        };
+        let undecodable = serde_json::from_str::<IpcMessageRequest>("{}").unwrap_err();
+        assert_eq!(code(Err::<IpcResponse, _>(undecodable).unwrap_or_error()), ErrorCode::InvalidRequest);
+        let too_large = errors::PayloadTooLargeErr { size: 2048, limit: 1024 };
+        assert_eq!(code(Err::<IpcResponse, _>(too_large).unwrap_or_error()), ErrorCode::PayloadTooLarge);
+        let unavailable: failure::Error = errors::IasUnavailableErr { message: "503".to_string() }.into();
+        assert_eq!(code(Err::<IpcResponse, _>(unavailable).unwrap_or_error()), ErrorCode::AttestationUnavailable);
+        let degraded = errors::ServiceDegradedErr { reason: "the TCB status regressed to GROUP_REVOKED".to_string() };
+        assert_eq!(code(Err::<IpcResponse, _>(degraded).unwrap_or_error()), ErrorCode::ServiceDegraded);
+        let limited = errors::RateLimitedErr { socket: "data" };
+        assert_eq!(code(Err::<IpcResponse, _>(limited).unwrap_or_error()), ErrorCode::RateLimited);
+        assert_eq!(code(Err::<IpcResponse, _>(errors::ClientBusyErr { queued: 64 }).unwrap_or_error()), ErrorCode::RateLimited);
+        assert_eq!(code(Err::<IpcResponse, _>(errors::UnauthenticatedErr).unwrap_or_error()), ErrorCode::Unauthenticated);
+        assert_eq!(code(Err::<IpcResponse, _>(errors::MaintenanceErr { retry_after: 60 }).unwrap_or_error()), ErrorCode::Maintenance);
+        assert_eq!(code(Err::<IpcResponse, _>(failure::err_msg("unexpected")).unwrap_or_error()), ErrorCode::InternalError);
+        // The numbers are the protocol
+        assert_eq!(serde_json::to_string(&ErrorCode::AttestationFailed).unwrap(), "1001");
+        assert_eq!(serde_json::from_str::<ErrorCode>("1003").unwrap(), ErrorCode::ValidationError);
+        assert!(serde_json::from_str::<ErrorCode>("999").is_err());
+    }
+
+    // The server reads and writes msgpack frames through `WireFormat`, which must give the fixtures: every
+    // document decodes the same from both encodings, and encodes back to the same bytes.
+    #[test]
+    fn test_golden_wire_formats() {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden");
+        let mut checked = 0;
+        for entry in fs::read_dir(&dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("msgpack") {
+                continue;
+            }
+            let msgpack = fs::read(&path).unwrap();
+            let json = fs::read(path.with_extension("json")).unwrap();
+            assert_eq!((WireFormat::detect(&msgpack), WireFormat::detect(&json)), (WireFormat::Msgpack, WireFormat::Json), "{:?}", path);
+            let doc = WireFormat::Json.decode(&json).unwrap();
+            assert_eq!(WireFormat::Msgpack.decode(&msgpack).unwrap(), doc, "{:?}", path);
+            assert_eq!(WireFormat::Msgpack.encode(&doc).unwrap(), msgpack, "{:?}", path);
+            checked += 1;
+        }
+        assert!(checked > 0);
+    }
+
+    #[test]
+    fn test_decode_frame() {
+        let envelope = serde_json::json!({"id": ID, "type": "FindMatch", "input": {"userPubKey": USER_PUBKEY}});
+        for &format in &[WireFormat::Json, WireFormat::Msgpack] {
+            // Left to the `Value` path
+            assert!(decode_frame(&format.encode(&serde_json::json!([envelope])).unwrap()).is_none());
+            assert!(decode_frame(&format.encode(&serde_json::json!({"jsonrpc": "2.0", "id": 1, "method": "ping"})).unwrap()).is_none());
+            // `encryptedUserId` is missing
+            let unreadable = decode_frame(&format.encode(&envelope).unwrap()).unwrap().err().unwrap();
+            assert_eq!((unreadable.id.as_str(), unreadable.command.as_ref().map(String::as_str)), (ID, Some("FindMatch")));
+            assert_eq!(errors::error_code(&unreadable.error.into()), ErrorCode::InvalidRequest, "{}", format.name());
+        }
+        assert!(decode_frame(b"not json").is_none());
+    }
+
+    // Before and after of the path of an `AddPersonalData` upload, from the frame to the decoded bytes:
+    //   cargo test --release bench_large_uploads -- --ignored --nocapture
+    // Before, the frame was read into a `Value`, converted to the request, copied for the audit, the dead
+    // letters and the timed request, and decoded whole. After, it's decoded straight from the frame, shared
+    // by those copies, and decoded a chunk at a time (`chunks::upload_hex`).
+    #[test]
+    #[ignore]
+    fn bench_large_uploads() {
+        use crate::esgx::chunks::CHUNK_SIZE;
+        use std::time::Instant;
+        const ROUNDS: u32 = 10;
+        for &size in &[1 << 20, 4 << 20, 16 << 20] {
+            let frame = serde_json::to_vec(&serde_json::json!({"id": ID, "type": "AddPersonalData", "input": {
+                "encryptedUserId": ENCRYPTED_USERID, "encryptedData": "a5".repeat(size), "userPubKey": USER_PUBKEY}})).unwrap();
+
+            let start = Instant::now();
+            for _ in 0..ROUNDS {
+                let doc: Value = serde_json::from_slice(&frame).unwrap();
+                let msg: IpcMessageRequest = serde_json::from_value(doc).unwrap();
+                let text = match &msg.request {
+                    IpcRequest::AddPersonalData { input } => input.encrypted_data.as_str(),
+                    _ => unreachable!(),
+                };
+                let copies = vec![text.to_string(), text.to_string(), text.to_string()];
+                let data: Vec<u8> = copies[2].from_hex().unwrap();
+                assert_eq!(data.len(), size);
+            }
+            let before = start.elapsed() / ROUNDS;
+
+            let start = Instant::now();
+            for _ in 0..ROUNDS {
+                let msg = decode_frame(&frame).unwrap().ok().unwrap();
+                let copies = vec![msg.request.clone(), msg.request.clone(), msg.request];
+                let text = match &copies[2] {
+                    IpcRequest::AddPersonalData { input } => input.encrypted_data.as_str(),
+                    _ => unreachable!(),
+                };
+                let mut decoded = 0;
+                for digits in text.as_bytes().chunks(2 * CHUNK_SIZE) {
+                    decoded += std::str::from_utf8(digits).unwrap().from_hex::<Vec<u8>>().unwrap().len();
+                }
+                assert_eq!(decoded, size);
+            }
+            let after = start.elapsed() / ROUNDS;
+            println!("{:>3} MB upload: before {:?}, after {:?}", size >> 20, before, after);
+        }
+    }
+
+    fn golden_requests() -> Vec<Value> {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden");
+        let mut names: Vec<_> = fs::read_dir(dir).unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().map_or(false, |ext| ext == "json"))
+            .filter(|path| path.file_name().unwrap().to_string_lossy().starts_with("request_"))
+            .collect();
+        names.sort();
+        names.iter().map(|path| serde_json::from_str(&fs::read_to_string(path).unwrap()).unwrap()).collect()
+    }
+
+    // The keys the handlers copy into `[u8; 64]`
+    fn pub_keys(request: &IpcRequest) -> Vec<&str> {
+        match request {
+            IpcRequest::NewTaskEncryptionKey { userPubKey } => vec![userPubKey.as_str()],
+            IpcRequest::AddPersonalData { input } | IpcRequest::UpdateUserStatus { input } => vec![input.user_pub_key.as_str()],
+            IpcRequest::RegisterUser { input } => vec![input.user_pub_key.as_str()],
+            IpcRequest::FindMatch { input } | IpcRequest::FindMatchFederated { input } |
+            IpcRequest::SubmitMatchJob { input } => vec![input.user_pub_key.as_str()],
+            IpcRequest::OpenChannel { handshake } => vec![handshake.channel_pub_key.as_str()],
+            _ => Vec::new(),
+        }
+    }
+
+    fn check_request(request: &IpcRequest) {
+        if validation::validate(request).is_ok() {
+            for key in pub_keys(request) {
+                assert_eq!(key.from_hex().map(|key: Vec<u8>| key.len()).ok(), Some(64), "validation let {:?} through", key);
+            }
+        }
+    }
+
+    // What `ipc_listener::handle_frame` does with a frame before anything reaches an enclave: either
+    // encoding, either envelope, then validation. Errors are answers, a panic would take the frame's thread down.
+    fn parse_frame(bytes: &[u8]) {
+        let doc = match WireFormat::detect(bytes).decode(bytes) {
+            Ok(doc) => doc,
+            Err(_) => return,
+        };
+        if jsonrpc::is_jsonrpc(&doc) {
+            jsonrpc::handle(doc, |request, _| {
+                check_request(&request);
+                (Ok(IpcResponse::Error { code: ErrorCode::InternalError, msg: String::new() }), Vec::new())
+            }, |_| {});
+        } else if let Ok(msg) = serde_json::from_value::<IpcMessageRequest>(doc) {
+            check_request(&msg.request);
+        }
+    }
+
+    fn json_value() -> impl Strategy<Value = Value> {
+        let leaf = prop_oneof![
+            Just(Value::Null),
+            any::<bool>().prop_map(Value::from),
+            any::<i64>().prop_map(Value::from),
+            any::<f64>().prop_map(Value::from),
+            ".{0,40}".prop_map(Value::from),
+            "[0-9a-fA-F]{0,300}".prop_map(Value::from),
+        ];
+        leaf.prop_recursive(3, 32, 4, |inner| prop_oneof![
+            vec(inner.clone(), 0..4).prop_map(Value::Array),
+            hash_map("[a-zA-Z]{0,12}", inner, 0..4).prop_map(|fields| Value::Object(fields.into_iter().collect())),
+        ])
+    }
+
+    // JSON pointers to every member of `doc`, nested ones included
+    fn pointers(doc: &Value, prefix: String, out: &mut Vec<String>) {
+        if let Value::Object(fields) = doc {
+            for (key, value) in fields {
+                let pointer = format!("{}/{}", prefix, key);
+                pointers(value, pointer.clone(), out);
+                out.push(pointer);
+            }
+        }
+    }
+
+    // `{"id", "type", ...fields}` as `{"jsonrpc", "id", "method", "params"}`
+    fn to_jsonrpc(mut doc: Value) -> Value {
+        let fields = doc.as_object_mut().unwrap();
+        let id = fields.remove("id").unwrap_or(Value::Null);
+        let command = fields.remove("type").and_then(|command| command.as_str().map(str::to_string)).unwrap_or_default();
+        let mut chars = command.chars();
+        let method: String = chars.next().map(|c| c.to_ascii_lowercase()).into_iter().chain(chars).collect();
+        serde_json::json!({"jsonrpc": "2.0", "id": id, "method": method, "params": doc})
+    }
+
+    proptest! {
+        #[test]
+        fn prop_arbitrary_frames(bytes in vec(any::<u8>(), 0..512)) {
+            parse_frame(&bytes);
+        }
+
+        #[test]
+        fn prop_arbitrary_documents(doc in json_value(), rpc in any::<bool>()) {
+            parse_frame(&serde_json::to_vec(&doc).unwrap());
+            parse_frame(&WireFormat::Msgpack.encode(&doc).unwrap());
+            if rpc && doc.is_object() {
+                parse_frame(&serde_json::to_vec(&to_jsonrpc(doc)).unwrap());
+            }
+        }
+
+        // Flipped and truncated bytes of the golden requests
+        #[test]
+        fn prop_corrupted_requests(golden in any::<Index>(), edits in vec((any::<Index>(), any::<u8>()), 1..8), cut in any::<Index>(),
+                                   msgpack in any::<bool>()) {
+            let requests = golden_requests();
+            let format = if msgpack { WireFormat::Msgpack } else { WireFormat::Json };
+            let mut bytes = format.encode(golden.get(&requests)).unwrap();
+            for (at, byte) in edits {
+                let i = at.index(bytes.len());
+                bytes[i] = byte;
+            }
+            bytes.truncate(cut.index(bytes.len() + 1));
+            parse_frame(&bytes);
+        }
+
+        // Well-formed frames with one member of a golden request replaced by anything, in both envelopes
+        #[test]
+        fn prop_mutated_requests(golden in any::<Index>(), member in any::<Index>(), value in json_value(), rpc in any::<bool>()) {
+            let requests = golden_requests();
+            let mut doc = golden.get(&requests).clone();
+            let mut members = Vec::new();
+            pointers(&doc, String::new(), &mut members);
+            *doc.pointer_mut(member.get(&members)).unwrap() = value;
+            let doc = if rpc { to_jsonrpc(doc) } else { doc };
+            parse_frame(&serde_json::to_vec(&doc).unwrap());
+        }
+    }
+}
@@ -1,4 +1,23 @@
 pub mod ipc_listener;
 pub mod messages;
+pub mod peer;
+pub mod switches;
+pub mod health;
+pub mod deprecation;
+pub mod jsonrpc;
+pub mod protocol;
+pub mod validation;
+pub mod admin;
+pub mod jobs;
+pub mod attestation_jobs;
+pub mod reattest;
+pub mod lease;
+pub mod precompute;
+pub mod planes;
+pub mod router;
+pub mod tcp;
+pub mod dead_letters;
+pub mod dashboard;
+pub mod maintenance;
 
-pub use self::ipc_listener::IpcListener;
\ No newline at end of file
+pub use self::router::{IpcListener, Listener};
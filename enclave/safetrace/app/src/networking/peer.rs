@@ -0,0 +1,229 @@
+use crate::attestation::{archive, bundle, pib, AttestationProvider, Quote};
+use crate::channel_u;
+use crate::common_u::errors::P2PErr;
+use crate::esgx::{equote, general};
+use crate::networking::messages::*;
+use enigma_tools_u::attestation_service::service::{ASReport, ASResult};
+use failure::Error;
+use hex::{FromHex, ToHex};
+use serde_json::{json, Value};
+use sgx_types::sgx_enclave_id_t;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+// How long to wait for a peer to answer a handshake, in milliseconds.
+const PEER_TIMEOUT: i32 = 30_000;
+
+// Everything a peer needs to check our enclave: the IAS report and the chain that signed it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct NodeAttestation {
+    pub signing_key: String,
+    pub report: String,
+    pub signature: String,
+    pub certificate: String,
+    pub ca: String,
+}
+
+// One side of the channel handshake: an ephemeral ECDH key signed by the attested signing key.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct ChannelHandshake {
+    pub attestation: NodeAttestation,
+    pub channel_pub_key: String,
+    pub sig: String,
+}
+
+fn p2p_err(cmd: &str, msg: &str) -> Error {
+    P2PErr { cmd: cmd.to_string(), msg: msg.to_string() }.into()
+}
+
+impl NodeAttestation {
+    pub fn produce(eid: sgx_enclave_id_t, spid: &str, provider: &dyn AttestationProvider) -> Result<Self, Error> {
+        let signing_key = equote::get_register_signing_address(eid)?;
+        let enc_quote = equote::quote_for(eid, spid, provider)?;
+        if general::is_simulation() {
+            return Ok(Self::simulated(signing_key.to_hex(), enc_quote));
+        }
+        let result = provider.get_report(enc_quote.clone())?;
+        pib::check_report(&result);
+        archive::record(&enc_quote, &bundle::from_result(&result), Some(signing_key.to_hex()));
+        Ok(NodeAttestation {
+            signing_key: signing_key.to_hex(),
+            report: result.report_string,
+            signature: result.signature,
+            certificate: result.certificate,
+            ca: result.ca,
+        })
+    }
+
+    // A report shaped like the IAS one, but unsigned. Only ever accepted by simulation builds.
+    fn simulated(signing_key: String, enc_quote: String) -> Self {
+        let report = json!({
+            "id": "simulation",
+            "timestamp": "",
+            "version": 3,
+            "isvEnclaveQuoteStatus": "SW_SIMULATION",
+            "isvEnclaveQuoteBody": enc_quote,
+        });
+        NodeAttestation { signing_key, report: report.to_string(), signature: String::new(), certificate: String::new(), ca: String::new() }
+    }
+
+    pub fn address(&self) -> Result<[u8; 20], Error> {
+        let bytes: Vec<u8> = self.signing_key.from_hex()?;
+        if bytes.len() != 20 {
+            return Err(p2p_err("attestation", "the signing key isn't a 20 bytes address"));
+        }
+        let mut address = [0u8; 20];
+        address.copy_from_slice(&bytes);
+        Ok(address)
+    }
+
+    // Checks the IAS signature over the report and returns the quote it vouches for: with an `OK` status, and of
+    // an enclave that doesn't run in debug mode, whose memory the host can read.
+    pub fn verify(&self, provider: &dyn AttestationProvider) -> Result<Quote, Error> {
+        let report: ASReport = serde_json::from_str(&self.report)?;
+        let quote_body = report.isv_enclave_quote_body.clone();
+        if general::is_simulation() {
+            warn!("Simulation mode: accepting an unsigned peer report");
+            return Quote::from_base64(&quote_body);
+        }
+        let result = ASResult {
+            ca: self.ca.clone(),
+            certificate: self.certificate.clone(),
+            report,
+            report_string: self.report.clone(),
+            signature: self.signature.clone(),
+            validate: false,
+        };
+        if !provider.verify_report(&result)? {
+            return Err(p2p_err("attestation", "the IAS signature over the report is invalid"));
+        }
+        let report: Value = serde_json::from_str(&self.report)?;
+        let quote_status = report["isvEnclaveQuoteStatus"].as_str().unwrap_or_default();
+        if quote_status != "OK" {
+            return Err(p2p_err("attestation", &format!("the quote status is {}", quote_status)));
+        }
+        let quote = Quote::from_base64(&quote_body)?;
+        if quote.report_body.attributes().is_debug() {
+            return Err(p2p_err("attestation", "the enclave runs in debug mode"));
+        }
+        Ok(quote)
+    }
+}
+
+// Tracks our own attestation, the configured federation peers and the channels this node initiated.
+#[derive(Default)]
+pub struct PeerNode {
+    uris: Vec<String>,
+    own: Mutex<Option<NodeAttestation>>,
+    channels: Mutex<HashMap<(sgx_enclave_id_t, String), [u8; 20]>>,
+}
+
+impl PeerNode {
+    // `peers` is a comma separated list of ZMQ endpoints, e.g. `tcp://10.0.0.2:5552,tcp://10.0.0.3:5552`
+    pub fn new(peers: &str) -> Self {
+        Self::from_uris(peers.split(',').map(str::trim).filter(|uri| !uri.is_empty()).map(String::from).collect())
+    }
+
+    pub fn from_uris(uris: Vec<String>) -> Self {
+        PeerNode { uris, ..Default::default() }
+    }
+
+    pub fn uris(&self) -> &[String] { &self.uris }
+
+    pub fn channel_count(&self) -> usize { self.channels.lock().unwrap().len() }
+
+    // Forgets our attestation and the open channels, after the enclaves were restarted with a new key.
+    pub fn reset(&self) {
+        *self.own.lock().unwrap() = None;
+        self.channels.lock().unwrap().clear();
+    }
+
+    fn own_attestation(&self, eid: sgx_enclave_id_t, spid: &str, provider: &dyn AttestationProvider) -> Result<NodeAttestation, Error> {
+        let mut own = self.own.lock().unwrap();
+        if own.is_none() {
+            *own = Some(NodeAttestation::produce(eid, spid, provider)?);
+        }
+        Ok(own.clone().unwrap())
+    }
+
+    fn handshake(&self, eid: sgx_enclave_id_t, spid: &str, provider: &dyn AttestationProvider) -> Result<([u8; 64], ChannelHandshake), Error> {
+        let attestation = self.own_attestation(eid, spid, provider)?;
+        let (channel_pubkey, sig) = channel_u::new_channel_key(eid)?;
+        let handshake = ChannelHandshake { attestation, channel_pub_key: channel_pubkey.to_hex(), sig: sig.to_hex() };
+        Ok((channel_pubkey, handshake))
+    }
+
+    // A peer is accepted only if IAS vouches for it (`verify`), it runs the same MRENCLAVE as we do,
+    // and its report_data commits to the signing address it claims.
+    fn complete(&self, eid: sgx_enclave_id_t, spid: &str, provider: &dyn AttestationProvider,
+                channel_pubkey: &[u8; 64], peer: &ChannelHandshake) -> Result<[u8; 20], Error> {
+        let own_quote = self.own_attestation(eid, spid, provider)?.verify(provider)?;
+        let peer_quote = peer.attestation.verify(provider)?;
+        let peer_address = peer.attestation.address()?;
+        if peer_quote.report_body.mr_enclave != own_quote.report_body.mr_enclave {
+            return Err(p2p_err("handshake", "the peer runs a different enclave (MRENCLAVE mismatch)"));
+        }
+        if peer_quote.report_body.report_data[..20] != peer_address[..] {
+            return Err(p2p_err("handshake", "the peer signing key isn't the one bound in its quote"));
+        }
+
+        let mut peer_pubkey = [0u8; 64];
+        let mut peer_sig = [0u8; 65];
+        parse_hex_into(&peer.channel_pub_key, &mut peer_pubkey, "channelPubKey")?;
+        parse_hex_into(&peer.sig, &mut peer_sig, "sig")?;
+        channel_u::open_channel(eid, channel_pubkey, &peer_pubkey, &peer_sig, &peer_address)?;
+        Ok(peer_address)
+    }
+
+    // Responder side of the handshake.
+    pub fn accept(&self, eid: sgx_enclave_id_t, spid: &str, provider: &dyn AttestationProvider, peer: &ChannelHandshake) -> Result<ChannelHandshake, Error> {
+        let (channel_pubkey, handshake) = self.handshake(eid, spid, provider)?;
+        let peer_address = self.complete(eid, spid, provider, &channel_pubkey, peer)?;
+        info!("Opened a secure channel with peer {}", peer_address.to_hex());
+        Ok(handshake)
+    }
+
+    // Initiator side of the handshake, returns the attested address of the peer.
+    pub fn connect(&self, eid: sgx_enclave_id_t, spid: &str, provider: &dyn AttestationProvider, uri: &str) -> Result<[u8; 20], Error> {
+        if let Some(address) = self.channels.lock().unwrap().get(&(eid, uri.to_string())) {
+            return Ok(*address);
+        }
+        let (channel_pubkey, handshake) = self.handshake(eid, spid, provider)?;
+        let id = channel_pubkey[..5].to_hex();
+        let reply = send_request(uri, IpcMessageRequest::from_request(IpcRequest::OpenChannel { handshake }, id))?;
+        let peer: ChannelHandshake = serde_json::from_value(reply["result"]["handshake"].clone())?;
+
+        let peer_address = self.complete(eid, spid, provider, &channel_pubkey, &peer)?;
+        info!("Opened a secure channel with peer {} at {}", peer_address.to_hex(), uri);
+        self.channels.lock().unwrap().insert((eid, uri.to_string()), peer_address);
+        Ok(peer_address)
+    }
+}
+
+fn parse_hex_into(hex: &str, out: &mut [u8], field: &str) -> Result<(), Error> {
+    let bytes: Vec<u8> = hex.from_hex()?;
+    if bytes.len() != out.len() {
+        return Err(p2p_err("handshake", &format!("{} has the wrong length", field)));
+    }
+    out.copy_from_slice(&bytes);
+    Ok(())
+}
+
+// Sends a single request to another SafeTrace node and returns the raw JSON reply.
+pub(crate) fn send_request(uri: &str, request: IpcMessageRequest) -> Result<Value, Error> {
+    let context = zmq::Context::new();
+    let socket = context.socket(zmq::REQ)?;
+    socket.set_rcvtimeo(PEER_TIMEOUT)?;
+    socket.set_linger(0)?;
+    socket.connect(uri)?;
+    let msg: zmq::Message = request.into();
+    socket.send(msg, 0)?;
+    let reply = socket.recv_msg(0)?;
+    let reply: Value = serde_json::from_slice(&reply)?;
+    if reply["type"] == "Error" {
+        return Err(p2p_err("request", reply["msg"].as_str().unwrap_or_default()));
+    }
+    Ok(reply)
+}
@@ -0,0 +1,176 @@
+use crate::common_u::errors::{RateLimitedErr, WrongSocketErr};
+use crate::config::ServerConfig;
+use crate::networking::messages::IpcRequest;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+// The data and control planes. With `server.control_bind` set, registrations, key exchanges and the other
+// requests a client makes once per session go to a socket of their own, the control socket, and `server.bind`
+// serves the uploads and the queries: the data socket. Each socket has its own ROUTER loop, handles `*_workers`
+// frames of a message at once, and may take `*_rate_limit` requests per second, so a burst of uploads queues
+// behind the other uploads and not in front of the registrations. A request sent to the other plane's socket
+// is refused, the liveness and version requests are served on both.
+//
+// Without `server.control_bind` the one socket serves everything, as before, with neither limit.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Plane {
+    Control,
+    Data,
+}
+
+impl Plane {
+    pub fn name(self) -> &'static str {
+        match self {
+            Plane::Control => "control",
+            Plane::Data => "data",
+        }
+    }
+}
+
+// The plane of a request, None for the ones both sockets serve.
+pub fn of(request: &IpcRequest) -> Option<Plane> {
+    match request {
+        IpcRequest::GetEnclaveReport | IpcRequest::NewTaskEncryptionKey { .. } | IpcRequest::RegisterUser { .. } |
+        IpcRequest::GetAttestationJob { .. } | IpcRequest::GetCachedReport { .. } | IpcRequest::GetEnclaveInfo | IpcRequest::GetFeatureSwitches |
+        IpcRequest::SetFeatureSwitch { .. } | IpcRequest::OpenChannel { .. } | IpcRequest::ConnectPeer { .. } => Some(Plane::Control),
+        IpcRequest::AddPersonalData { .. } | IpcRequest::UpdateUserStatus { .. } | IpcRequest::FindMatch { .. } |
+        IpcRequest::SubmitMatchJob { .. } | IpcRequest::GetMatchJob { .. } | IpcRequest::FindMatchFederated { .. } |
+        IpcRequest::FederatedQuery { .. } | IpcRequest::GetStats | IpcRequest::GetAggregates |
+        IpcRequest::ExportExposureStatistics { .. } | IpcRequest::ExportAuditLog { .. } => Some(Plane::Data),
+        IpcRequest::Ping { .. } | IpcRequest::GetHealth | IpcRequest::GetReadiness | IpcRequest::GetProtocolVersion { .. } |
+        IpcRequest::Heartbeat { .. } => None,
+    }
+}
+
+// A token bucket of `per_second` requests, as many in a burst.
+struct RateLimit {
+    per_second: u64,
+    // Tokens left, and when they were counted
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl RateLimit {
+    fn new(per_second: u64) -> Self {
+        RateLimit { per_second, bucket: Mutex::new((per_second as f64, Instant::now())) }
+    }
+
+    fn admit(&self, now: Instant) -> bool {
+        if self.per_second == 0 {
+            return true;
+        }
+        let mut bucket = self.bucket.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let elapsed = if now > bucket.1 { now - bucket.1 } else { Duration::from_secs(0) };
+        let rate = self.per_second as f64;
+        let seconds = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+        *bucket = ((bucket.0 + seconds * rate).min(rate), now);
+        if bucket.0 < 1.0 {
+            return false;
+        }
+        bucket.0 -= 1.0;
+        true
+    }
+}
+
+struct Socket {
+    workers: usize,
+    limit: RateLimit,
+}
+
+pub struct Planes {
+    split: bool,
+    control: Socket,
+    data: Socket,
+    // Requests refused per plane, for the metrics
+    refused: Mutex<[u64; 2]>,
+}
+
+impl Planes {
+    pub fn new(config: &ServerConfig) -> Self {
+        Planes {
+            split: !config.control_bind.is_empty(),
+            control: Socket { workers: config.control_workers, limit: RateLimit::new(config.control_rate_limit) },
+            data: Socket { workers: config.data_workers, limit: RateLimit::new(config.data_rate_limit) },
+            refused: Mutex::new([0; 2]),
+        }
+    }
+
+    pub fn split(&self) -> bool {
+        self.split
+    }
+
+    fn socket(&self, plane: Plane) -> &Socket {
+        match plane {
+            Plane::Control => &self.control,
+            Plane::Data => &self.data,
+        }
+    }
+
+    // Frames of a message handled at once on the socket of `plane`, None for the one socket.
+    pub fn workers(&self, plane: Option<Plane>, default: usize) -> usize {
+        plane.map_or(default, |plane| self.socket(plane).workers)
+    }
+
+    fn refused(&self) -> MutexGuard<[u64; 2]> {
+        self.refused.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    // Whether the socket of `plane` serves `request` now, None being the one socket.
+    pub fn admit(&self, plane: Option<Plane>, request: &IpcRequest) -> Result<(), failure::Error> {
+        let plane = match plane {
+            Some(plane) => plane,
+            None => return Ok(()),
+        };
+        if of(request).map_or(false, |other| other != plane) {
+            return Err(WrongSocketErr { command: request.command().to_string(), socket: plane.name() }.into());
+        }
+        if !self.socket(plane).limit.admit(Instant::now()) {
+            let refused = {
+                let mut refused = self.refused();
+                refused[plane as usize] += 1;
+                refused[plane as usize]
+            };
+            info!(target: "metrics", "safetrace_rate_limited_requests{{socket=\"{}\"}}={}", plane.name(), refused);
+            return Err(RateLimitedErr { socket: plane.name() }.into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_rate_limit() {
+        let limit = RateLimit::new(2);
+        let start = Instant::now();
+        assert!(limit.admit(start) && limit.admit(start));
+        assert!(!limit.admit(start + Duration::from_millis(100)));
+        assert!(limit.admit(start + Duration::from_millis(600)));
+        // A quiet period doesn't save up more than a second's worth
+        let later = start + Duration::from_secs(60);
+        assert!(limit.admit(later) && limit.admit(later) && !limit.admit(later));
+        assert!((0..1000).all(|_| RateLimit::new(0).admit(start)));
+    }
+
+    #[test]
+    fn test_planes() {
+        let ping = IpcRequest::Ping { nonce: "a1".to_string() };
+        let register = IpcRequest::NewTaskEncryptionKey { userPubKey: "2b4d".to_string() };
+        let stats = IpcRequest::GetStats;
+        let single = Planes::new(&ServerConfig { data_rate_limit: 1, ..ServerConfig::default() });
+        assert!(!single.split());
+        assert!((0..10).all(|_| single.admit(None, &stats).is_ok()));
+
+        let planes = Planes::new(&ServerConfig { control_bind: "tcp://*:5551".to_string(), data_rate_limit: 1, ..ServerConfig::default() });
+        assert!(planes.split());
+        assert!(planes.admit(Some(Plane::Control), &register).is_ok() && planes.admit(Some(Plane::Control), &ping).is_ok());
+        assert!(planes.admit(Some(Plane::Control), &stats).unwrap_err().downcast_ref::<WrongSocketErr>().is_some());
+        assert!(planes.admit(Some(Plane::Data), &register).is_err());
+        // The uploads and queries run out of their budget, the control socket still has its own
+        assert!(planes.admit(Some(Plane::Data), &stats).is_ok());
+        assert!(planes.admit(Some(Plane::Data), &ping).unwrap_err().downcast_ref::<RateLimitedErr>().is_some());
+        assert!(planes.admit(Some(Plane::Control), &register).is_ok());
+    }
+}
@@ -0,0 +1,119 @@
+use crate::config::PrecomputeConfig;
+use crate::match_u;
+use crate::networking::ipc_listener::IpcContext;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Matching in the background. With `precompute.enabled` set, every ingest wakes `run`, which has each enclave
+// bring the matches it keeps for incremental queries (see `incremental` in the enclave) up to the new store, a
+// `precompute.step_size` users per ecall. A query of these users then takes its matches from what was computed,
+// the new ones queued in the enclave until then.
+//
+// The steps share the state lock with the queries and take an enclave thread each; after each one the thread
+// rests long enough for the background matching to keep at most `precompute.max_cpu` percent of it busy.
+
+pub struct Precompute {
+    config: PrecomputeConfig,
+    // Whether something was ingested since the last round
+    pending: Mutex<bool>,
+    wake: Condvar,
+}
+
+impl Precompute {
+    pub fn new(config: &PrecomputeConfig) -> Self {
+        Precompute { config: config.clone(), pending: Mutex::new(false), wake: Condvar::new() }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.config.enabled
+    }
+
+    // Called once infected locations may have been ingested.
+    pub fn notify(&self) {
+        if !self.enabled() {
+            return;
+        }
+        *self.pending.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = true;
+        self.wake.notify_one();
+    }
+
+    // Until something is ingested, or `precompute.interval` passed.
+    fn wait(&self) {
+        let mut pending = self.pending.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        while !*pending {
+            if self.config.interval == 0 {
+                pending = self.wake.wait(pending).unwrap_or_else(|poisoned| poisoned.into_inner());
+            } else {
+                let (woken, timeout) = self.wake.wait_timeout(pending, Duration::from_secs(self.config.interval))
+                    .unwrap_or_else(|poisoned| poisoned.into_inner());
+                pending = woken;
+                if timeout.timed_out() {
+                    break;
+                }
+            }
+        }
+        *pending = false;
+    }
+
+    // The rest after a step that took `busy`, for the steps to take `max_cpu` percent of the time.
+    fn pause(&self, busy: Duration) -> Duration {
+        busy * (100 - self.config.max_cpu) / self.config.max_cpu
+    }
+}
+
+// Runs a round after every ingest until the process exits, meant for a thread of its own.
+pub fn run(ctx: Arc<IpcContext>) {
+    loop {
+        ctx.precompute.wait();
+        let mut refreshed = 0;
+        for eid in ctx.pool.eids() {
+            loop {
+                let started = Instant::now();
+                let step = {
+                    let _state = ctx.pool.read_state();
+                    let _thread = ctx.pool.enter(eid);
+                    match_u::precompute_matches(eid, ctx.precompute.config.step_size)
+                };
+                let busy = started.elapsed();
+                match step {
+                    Ok((done, remaining)) => {
+                        refreshed += done;
+                        thread::sleep(ctx.precompute.pause(busy));
+                        if remaining == 0 {
+                            break;
+                        }
+                    },
+                    Err(e) => {
+                        warn!("Matching in the background on enclave {} failed: {}", eid, e);
+                        break;
+                    },
+                }
+            }
+        }
+        if refreshed > 0 {
+            info!(target: "metrics", "safetrace_precomputed_users={}", refreshed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_precompute() {
+        let precompute = Precompute::new(&PrecomputeConfig { enabled: true, step_size: 64, max_cpu: 25, interval: 0 });
+        assert_eq!(precompute.pause(Duration::from_millis(100)), Duration::from_millis(300));
+        let all = Precompute::new(&PrecomputeConfig { max_cpu: 100, ..PrecomputeConfig::default() });
+        assert_eq!(all.pause(Duration::from_millis(100)), Duration::from_millis(0));
+
+        // An ingest before the wait isn't missed
+        precompute.notify();
+        precompute.wait();
+        assert!(!*precompute.pending.lock().unwrap());
+        // Nothing is pending without the background matching
+        all.notify();
+        assert!(!*all.pending.lock().unwrap());
+    }
+}
@@ -0,0 +1,84 @@
+use crate::networking::messages::COMMANDS;
+use std::collections::BTreeMap;
+
+// Protocol versioning, reported by `GetProtocolVersion`.
+// `PROTOCOL_VERSION` goes up with every change clients can observe. Adding an optional field or a
+// new command is backward compatible and only bumps the schema version of the commands it touches.
+// Removing or changing a field also raises `MIN_PROTOCOL_VERSION`, clients older than that get
+// `compatible: false` and should upgrade rather than misread the answers.
+pub const PROTOCOL_VERSION: u32 = 26;
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+// Schema version of each command, request and response together.
+pub const SCHEMAS: &[(&str, u32)] = &[
+    // 2: the certificate chain and the compact report bundle, 3: the attestation `job` while IAS is down
+    ("GetEnclaveReport", 3),
+    ("NewTaskEncryptionKey", 1),
+    // 2: the encrypted receipt, 3: the replay `error`, 4: `encryptedSignature`, 5: the `CapacityExceeded` error,
+    // 6: `tzOffset`, the `ClockSkew` error and the receipt's `clockDrift`, 7: merged with the stored locations,
+    // the receipt's `duplicates`, 8: `compression`
+    ("AddPersonalData", 8),
+    ("RegisterUser", 1),
+    // 2: `encryptedSignature`, 3: the `matching` strategy, asked and echoed
+    ("FindMatch", 3),
+    ("GetFeatureSwitches", 1),
+    ("SetFeatureSwitch", 1),
+    ("OpenChannel", 1),
+    ("ConnectPeer", 1),
+    // 2: `encryptedSignature`
+    ("FindMatchFederated", 2),
+    ("FederatedQuery", 1),
+    ("GetStats", 1),
+    ("GetAggregates", 1),
+    ("Ping", 1),
+    ("GetHealth", 1),
+    ("GetReadiness", 1),
+    // 2: the active `quantization`, 3: `maxFrameSize`
+    ("GetProtocolVersion", 3),
+    // 2: the `matching` strategy
+    ("SubmitMatchJob", 2),
+    // 2: the `matching` strategy of the job
+    ("GetMatchJob", 2),
+    ("ExportAuditLog", 1),
+    ("GetEnclaveInfo", 1),
+    ("GetAttestationJob", 1),
+    ("ExportExposureStatistics", 1),
+    ("UpdateUserStatus", 1),
+    ("Heartbeat", 1),
+    ("GetCachedReport", 1),
+];
+
+// Optional behaviours of the server, beyond the commands themselves.
+pub const CAPABILITIES: &[&str] = &["jsonrpc-2.0", "jsonrpc-batch", "deprecation-notices", "report-bundle", "encrypted-receipts", "replay-protection", "user-signatures",
+                                       "health-authority-declarations", "match-jobs", "audit-log",
+                                       "enclave-info", "request-timeouts", "msgpack", "attestation-retries", "server-identity",
+                                       "k-anonymous-export", "tenants", "matching-strategies",
+                                       "clock-skew-tolerance", "status-updates", "deduplication", "compression-deflate",
+                                       "frame-size-limit", "error-codes", "heartbeats", "report-cache"];
+
+pub fn schemas() -> BTreeMap<String, u32> {
+    SCHEMAS.iter().map(|&(command, version)| (command.to_string(), version)).collect()
+}
+
+pub fn commands() -> Vec<String> {
+    COMMANDS.iter().map(|command| command.to_string()).collect()
+}
+
+// Clients that don't say which version they speak are assumed to be compatible.
+pub fn is_compatible(client_version: Option<u32>) -> bool {
+    client_version.map_or(true, |version| version >= MIN_PROTOCOL_VERSION)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_every_command_has_a_schema() {
+        for command in COMMANDS {
+            assert!(SCHEMAS.iter().any(|&(c, _)| c == *command), "{} has no schema version", command);
+        }
+        assert_eq!(SCHEMAS.len(), COMMANDS.len());
+        assert!(is_compatible(None) && is_compatible(Some(PROTOCOL_VERSION)) && !is_compatible(Some(0)));
+    }
+}
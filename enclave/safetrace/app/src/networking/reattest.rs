@@ -0,0 +1,167 @@
+use crate::attestation::bundle;
+use crate::config::IasConfig;
+use crate::networking::ipc_listener::IpcContext;
+use crate::networking::peer::NodeAttestation;
+use safetrace_client::bundle::ReportBundle;
+use serde_json::Value;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Re-attestation. With `ias.reattest_interval` set, `run` has the enclave quoted and attested again every so
+// many seconds and keeps the latest report, served by the `tcb-status` admin operation (and archived with the
+// others, see `attestation::archive`). An attestation that fails leaves the previous one in place.
+//
+// The service is degraded while the latest attestation is older than `ias.max_attestation_age`, or while its
+// quote status is worse than the best one of this run (e.g. `OK` then `GROUP_OUT_OF_DATE` after a TCB recovery):
+// it still serves the clients that registered, but refuses new registrations (`GetEnclaveReport`,
+// `RegisterUser`) with `ServiceDegraded`. `GetHealth` and `GetReadiness` say why in `degraded`. A platform
+// brought up to date attests back to the best status; restarting takes the current status as the best.
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct LatestAttestation {
+    pub signing_key: String,
+    pub quote_status: String,
+    // Unix seconds
+    pub attested_at: u64,
+    // See `attestation::bundle`
+    pub bundle: String,
+}
+
+#[derive(Default)]
+struct State {
+    latest: Option<LatestAttestation>,
+    // Rank of the best quote status of this run, see `rank`
+    best: Option<u8>,
+}
+
+pub struct Freshness {
+    interval: u64,
+    max_age: u64,
+    state: Mutex<State>,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+// How far a quote status is from `OK`, the higher the worse. Unknown statuses are the worst.
+pub fn rank(quote_status: &str) -> u8 {
+    match quote_status {
+        "OK" | "SW_SIMULATION" => 0,
+        "SW_HARDENING_NEEDED" => 1,
+        "CONFIGURATION_NEEDED" => 2,
+        "CONFIGURATION_AND_SW_HARDENING_NEEDED" => 3,
+        "GROUP_OUT_OF_DATE" => 4,
+        _ => 5,
+    }
+}
+
+impl Freshness {
+    pub fn new(config: &IasConfig) -> Self {
+        Freshness { interval: config.reattest_interval, max_age: config.max_attestation_age, state: Mutex::new(State::default()) }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.interval > 0
+    }
+
+    fn lock(&self) -> MutexGuard<State> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    pub fn latest(&self) -> Option<LatestAttestation> {
+        self.lock().latest.clone()
+    }
+
+    pub fn record(&self, signing_key: String, bundle: &ReportBundle, attested_at: u64) {
+        let report: Value = serde_json::from_str(&bundle.report).unwrap_or_default();
+        let quote_status = report["isvEnclaveQuoteStatus"].as_str().unwrap_or_default().to_string();
+        let mut state = self.lock();
+        let (current, best) = (rank(&quote_status), state.best);
+        if best.map_or(false, |best| current > best) {
+            warn!(target: "security", "The TCB status went down to {}, new registrations are refused until it's back", quote_status);
+        }
+        state.best = Some(best.map_or(current, |best| best.min(current)));
+        state.latest = Some(LatestAttestation { signing_key, quote_status, attested_at, bundle: bundle::compact(bundle) });
+    }
+
+    // Why the service is degraded at `now`, None while it isn't.
+    pub fn degraded(&self, now: u64) -> Option<String> {
+        if !self.enabled() {
+            return None;
+        }
+        let state = self.lock();
+        let latest = match &state.latest {
+            Some(latest) => latest,
+            None if self.max_age > 0 => return Some("the enclave wasn't attested yet".to_string()),
+            None => return None,
+        };
+        if self.max_age > 0 && now.saturating_sub(latest.attested_at) > self.max_age {
+            return Some(format!("the last attestation is {} seconds old, past ias.max_attestation_age", now - latest.attested_at));
+        }
+        if state.best.map_or(false, |best| rank(&latest.quote_status) > best) {
+            return Some(format!("the TCB status regressed to {}", latest.quote_status));
+        }
+        None
+    }
+}
+
+fn attest(ctx: &IpcContext) -> Result<(), failure::Error> {
+    let eid = ctx.pool.primary();
+    let attestation = {
+        let _thread = ctx.pool.enter(eid);
+        NodeAttestation::produce(eid, ctx.spid.expose(), &*ctx.attestation)?
+    };
+    let bundle = ReportBundle { report: attestation.report, signature: attestation.signature,
+                                certificate: attestation.certificate, ca: attestation.ca };
+    ctx.freshness.record(attestation.signing_key, &bundle, now());
+    Ok(())
+}
+
+// Attests the enclave every `ias.reattest_interval` seconds until the process exits, meant for a thread of
+// its own.
+pub fn run(ctx: Arc<IpcContext>) {
+    loop {
+        match attest(&ctx) {
+            Ok(()) => info!("The enclave was attested again"),
+            Err(e) => warn!("Attesting the enclave again failed, the previous report stays: {}", e),
+        }
+        if let Some(reason) = ctx.freshness.degraded(now()) {
+            warn!(target: "security", "Degraded, new registrations are refused: {}", reason);
+        }
+        thread::sleep(Duration::from_secs(ctx.freshness.interval));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn report(quote_status: &str) -> ReportBundle {
+        ReportBundle { report: format!(r#"{{"isvEnclaveQuoteStatus":"{}"}}"#, quote_status), ..Default::default() }
+    }
+
+    #[test]
+    fn test_freshness() {
+        let freshness = Freshness::new(&IasConfig { reattest_interval: 60, max_attestation_age: 300, ..IasConfig::default() });
+        assert!(freshness.degraded(1000).is_some());
+        freshness.record("ab".repeat(20), &report("GROUP_OUT_OF_DATE"), 1000);
+        assert_eq!(freshness.degraded(1300), None);
+        assert!(freshness.degraded(1301).unwrap().contains("301 seconds"));
+
+        // Better is fine, worse than the best is a regression until it's back
+        freshness.record("ab".repeat(20), &report("OK"), 1400);
+        assert_eq!(freshness.degraded(1400), None);
+        freshness.record("ab".repeat(20), &report("GROUP_REVOKED"), 1500);
+        assert!(freshness.degraded(1500).unwrap().contains("GROUP_REVOKED"));
+        freshness.record("ab".repeat(20), &report("OK"), 1600);
+        assert_eq!(freshness.degraded(1600), None);
+        assert_eq!(freshness.latest().map(|latest| latest.attested_at), Some(1600));
+
+        // Without re-attestation nothing is enforced
+        let disabled = Freshness::new(&IasConfig::default());
+        assert!(!disabled.enabled() && disabled.degraded(1000).is_none());
+    }
+}
@@ -0,0 +1,357 @@
+use crate::config::ServerConfig;
+use crate::networking::tcp::{Connections, TlsListener};
+use failure::Error;
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+
+// The IPC sockets. Each is a ZMQ ROUTER: every message comes prefixed with the identity of the connection it came
+// from, and its answer goes back to that connection once it's ready. `server.workers` threads handle the messages of
+// a socket, so a slow query holds up neither the other clients nor the next messages of the same client, and the
+// answers go out in the order they're done. REQ clients still get theirs in order, they send one message at a time;
+// DEALER clients may send several and tell the answers apart by their `id`.
+//
+// A client has up to `server.max_in_flight` messages handled at once, its next `server.max_queued` ones wait for
+// one of them to be answered and the ones past that are answered right away, refused: a client flooding the socket
+// only queues behind itself.
+//
+// A `Notifier` sends a client messages it didn't ask for, e.g. the match jobs it submitted once they're done. Only
+// DEALER clients take them, a REQ socket drops what isn't the answer it waits for.
+//
+// Every `server.heartbeat_interval` seconds ZMQ pings each connection, and closes the ones that answered nothing for
+// `server.idle_timeout`: the state of a client that vanished doesn't linger. A client waiting on a long answer sends
+// `Heartbeat` meanwhile, the answer to which tells it the connection still goes both ways.
+
+pub type Multipart = VecDeque<zmq::Message>;
+
+// A bind on a Unix domain socket, `unix:///run/safetrace/ipc.sock`: the API gateway of the same host connects
+// without going through TCP, and only the users `server.socket_mode` lets in can. ZMQ calls it `ipc://`.
+pub const UNIX_SCHEME: &str = "unix://";
+
+// Frames the workers and the `Notifier` prefix what they hand the loop with
+const REPLY: &[u8] = b"reply";
+const NOTIFICATION: &[u8] = b"notification";
+
+// Each socket hands its answers over on an inproc socket of its own
+static LISTENERS: AtomicUsize = AtomicUsize::new(0);
+
+// A client of a socket: the identity ZMQ gave its connection, and the empty delimiter of REQ clients.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ClientId(Vec<Vec<u8>>);
+
+impl ClientId {
+    // A connection of the `tls` transport, which has no ZMQ identity.
+    pub fn connection(number: usize) -> Self {
+        ClientId(vec![number.to_be_bytes().to_vec()])
+    }
+}
+
+// Who sent a message, and whether it's past its `server.max_queued`: it's then only answered with an error.
+#[derive(Clone, Debug)]
+pub struct Client {
+    pub id: ClientId,
+    pub saturated: bool,
+}
+
+// The messages of a client being handled, and those waiting for them.
+#[derive(Default)]
+struct Pending {
+    in_flight: usize,
+    queued: VecDeque<Multipart>,
+}
+
+pub struct IpcListener {
+    context: zmq::Context,
+    router: zmq::Socket,
+    // The answers of the workers and the notifications, see `forward`
+    replies: zmq::Socket,
+    endpoint: String,
+    workers: usize,
+    max_in_flight: usize,
+    max_queued: usize,
+}
+
+// Sends messages to the clients of a socket, from any thread.
+pub enum Notifier {
+    Zmq(Mutex<zmq::Socket>),
+    Tls(Connections),
+}
+
+impl Notifier {
+    pub fn notify(&self, client: &ClientId, body: Multipart) -> Result<(), Error> {
+        match self {
+            Notifier::Zmq(socket) => {
+                // ZMQ sockets aren't shared between threads, the senders take turns
+                let socket = socket.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                Ok(forward(&socket, NOTIFICATION, client, &body)?)
+            },
+            Notifier::Tls(connections) => {
+                let connections = connections.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+                match connections.get(client).map(|connection| connection.send(body)) {
+                    Some(Ok(())) => Ok(()),
+                    _ => bail!("The client disconnected"),
+                }
+            },
+        }
+    }
+}
+
+// A socket of `server.transport`.
+pub enum Listener {
+    Zmq(IpcListener),
+    Tls(TlsListener),
+}
+
+impl Listener {
+    pub fn bind(bind: &str, config: &ServerConfig) -> Result<Self, Error> {
+        if config.transport == "tls" {
+            Ok(Listener::Tls(TlsListener::new(bind, config)?))
+        } else {
+            Ok(Listener::Zmq(IpcListener::new(bind, config)?))
+        }
+    }
+
+    pub fn notifier(&self) -> Result<Notifier, Error> {
+        match self {
+            Listener::Zmq(listener) => listener.notifier(),
+            Listener::Tls(listener) => Ok(listener.notifier()),
+        }
+    }
+
+    pub fn run<F>(self, handle: F) -> Result<(), Error>
+    where F: Fn(Multipart, &Client) -> Multipart + Send + Sync + 'static {
+        match self {
+            Listener::Zmq(listener) => listener.run(handle),
+            Listener::Tls(listener) => listener.run(handle),
+        }
+    }
+}
+
+// The ZMQ endpoint of a bind.
+pub fn zmq_endpoint(bind: &str) -> String {
+    if bind.starts_with(UNIX_SCHEME) {
+        format!("ipc://{}", &bind[UNIX_SCHEME.len()..])
+    } else {
+        bind.to_string()
+    }
+}
+
+// Hands a message for `client` to the loop: its kind, the length of the identity, the identity, then the body.
+fn forward(socket: &zmq::Socket, kind: &[u8], client: &ClientId, body: &Multipart) -> Result<(), zmq::Error> {
+    socket.send(kind, zmq::SNDMORE)?;
+    socket.send(&[client.0.len() as u8][..], zmq::SNDMORE)?;
+    let parts: Vec<&[u8]> = client.0.iter().map(|part| &part[..]).chain(body.iter().map(|part| &part[..])).collect();
+    send_parts(socket, &parts)
+}
+
+fn send_parts(socket: &zmq::Socket, parts: &[&[u8]]) -> Result<(), zmq::Error> {
+    for (i, part) in parts.iter().enumerate() {
+        socket.send(*part, if i + 1 < parts.len() { zmq::SNDMORE } else { 0 })?;
+    }
+    Ok(())
+}
+
+// Runs the messages the loop hands over until the loop is gone.
+fn work<F>(push: &zmq::Socket, receiver: &Mutex<mpsc::Receiver<(ClientId, Multipart)>>, handle: &F)
+where F: Fn(Multipart, &Client) -> Multipart {
+    loop {
+        // Only held while waiting, the message runs with the queue free for the other workers
+        let next = receiver.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).recv();
+        let (id, body) = match next {
+            Ok(next) => next,
+            Err(_) => return,
+        };
+        let client = Client { id, saturated: false };
+        let reply = handle(body, &client);
+        if let Err(e) = forward(push, REPLY, &client.id, &reply) {
+            error!("Handing over an answer failed: {}", e);
+        }
+    }
+}
+
+impl IpcListener {
+    pub fn new(conn_str: &str, config: &ServerConfig) -> Result<Self, Error> {
+        let context = zmq::Context::new();
+        let router = context.socket(zmq::ROUTER)?;
+        // An answer to a client that went away fails instead of being dropped quietly
+        router.set_router_mandatory(true)?;
+        // Half-open connections, e.g. of a phone that lost its network, are closed once they miss the heartbeats
+        if config.heartbeat_interval > 0 {
+            router.set_heartbeat_ivl(config.heartbeat_interval as i32 * 1000)?;
+            router.set_heartbeat_timeout(config.idle_timeout as i32 * 1000)?;
+            router.set_heartbeat_ttl(config.idle_timeout as i32 * 1000)?;
+        }
+        router.bind(&zmq_endpoint(conn_str))?;
+        // ZMQ replaced any socket left by a previous run, the new one is created with the umask's permissions
+        if conn_str.starts_with(UNIX_SCHEME) {
+            fs::set_permissions(&conn_str[UNIX_SCHEME.len()..], fs::Permissions::from_mode(config.socket_mode))?;
+        }
+        let endpoint = format!("inproc://safetrace-replies-{}", LISTENERS.fetch_add(1, Ordering::SeqCst));
+        let replies = context.socket(zmq::PULL)?;
+        replies.bind(&endpoint)?;
+        println!("Binded to socket: {}", conn_str);
+        Ok(IpcListener {
+            context, router, replies, endpoint,
+            workers: config.workers, max_in_flight: config.max_in_flight, max_queued: config.max_queued,
+        })
+    }
+
+    fn push(&self) -> Result<zmq::Socket, Error> {
+        let push = self.context.socket(zmq::PUSH)?;
+        push.connect(&self.endpoint)?;
+        Ok(push)
+    }
+
+    pub fn notifier(&self) -> Result<Notifier, Error> {
+        Ok(Notifier::Zmq(Mutex::new(self.push()?)))
+    }
+
+    // Serves the socket until it fails, `handle` answering each message on one of the workers.
+    pub fn run<F>(self, handle: F) -> Result<(), Error>
+    where F: Fn(Multipart, &Client) -> Multipart + Send + Sync + 'static {
+        let handle = Arc::new(handle);
+        let (sender, receiver) = mpsc::channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..self.workers {
+            let (handle, receiver, push) = (handle.clone(), receiver.clone(), self.push()?);
+            thread::spawn(move || work(&push, &receiver, &*handle));
+        }
+        let mut clients: HashMap<ClientId, Pending> = HashMap::new();
+        loop {
+            let mut items = [self.router.as_poll_item(zmq::POLLIN), self.replies.as_poll_item(zmq::POLLIN)];
+            zmq::poll(&mut items, -1)?;
+
+            if items[1].is_readable() {
+                let mut frames = self.replies.recv_multipart(0)?;
+                let rest = frames.split_off(2);
+                let (kind, length) = (&frames[0], frames[1][0] as usize);
+                let client = ClientId(rest[..length].to_vec());
+                let parts: Vec<&[u8]> = rest.iter().map(|part| &part[..]).collect();
+                if let Err(e) = send_parts(&self.router, &parts) {
+                    warn!("Sending a client its {} failed, it may have disconnected: {}", String::from_utf8_lossy(kind), e);
+                }
+                // The client's next message takes the place of the one answered
+                if &kind[..] == REPLY {
+                    let next = clients.get_mut(&client).and_then(|pending| {
+                        let next = pending.queued.pop_front();
+                        if next.is_none() {
+                            pending.in_flight -= 1;
+                        }
+                        next
+                    });
+                    match next {
+                        Some(body) => {
+                            if sender.send((client, body)).is_err() {
+                                bail!("The workers of the socket stopped");
+                            }
+                        },
+                        None => {
+                            if clients.get(&client).map_or(false, |pending| pending.in_flight == 0) {
+                                clients.remove(&client);
+                            }
+                        },
+                    }
+                }
+            }
+
+            if items[0].is_readable() {
+                let mut frames = self.router.recv_multipart(0)?;
+                // The identity, and the empty delimiter of a REQ client
+                let length = if frames.len() > 1 && frames[1].is_empty() { 2 } else { 1 };
+                let body: Multipart = frames.split_off(length).iter().map(zmq::Message::from).collect();
+                let client = ClientId(frames);
+                let pending = clients.entry(client.clone()).or_default();
+                if pending.in_flight < self.max_in_flight {
+                    pending.in_flight += 1;
+                    if sender.send((client, body)).is_err() {
+                        bail!("The workers of the socket stopped");
+                    }
+                } else if pending.queued.len() < self.max_queued {
+                    pending.queued.push_back(body);
+                } else {
+                    // Refused on the loop, it doesn't get to run
+                    let client = Client { id: client, saturated: true };
+                    let reply = (*handle)(body, &client);
+                    let parts: Vec<&[u8]> = client.id.0.iter().map(|part| &part[..]).chain(reply.iter().map(|part| &part[..])).collect();
+                    if let Err(e) = send_parts(&self.router, &parts) {
+                        warn!("Refusing a message failed, the client may have disconnected: {}", e);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::Duration;
+
+    fn recv(socket: &zmq::Socket) -> Vec<String> {
+        socket.recv_multipart(0).unwrap().into_iter().map(|part| String::from_utf8(part).unwrap()).collect()
+    }
+
+    #[test]
+    fn test_router() {
+        let config = ServerConfig { workers: 2, max_in_flight: 1, max_queued: 1, ..ServerConfig::default() };
+        let listener = IpcListener::new("tcp://127.0.0.1:5561", &config).unwrap();
+        let notifier = listener.notifier().unwrap();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let handled = seen.clone();
+        thread::spawn(move || listener.run(move |body, client| {
+            handled.lock().unwrap().push(client.id.clone());
+            let body: Vec<String> = body.iter().map(|part| String::from_utf8(part.to_vec()).unwrap()).collect();
+            if client.saturated {
+                return body.iter().map(|_| zmq::Message::from("refused")).collect();
+            }
+            if body[0] == "slow" {
+                thread::sleep(Duration::from_millis(300));
+            }
+            body.iter().map(|part| zmq::Message::from(&part.to_uppercase()[..])).collect()
+        }));
+
+        let context = zmq::Context::new();
+        let (dealer, req) = (context.socket(zmq::DEALER).unwrap(), context.socket(zmq::REQ).unwrap());
+        dealer.connect("tcp://127.0.0.1:5561").unwrap();
+        req.connect("tcp://127.0.0.1:5561").unwrap();
+        // The second message of the DEALER waits for the first, the third is refused
+        for body in &["slow", "next", "third"] {
+            dealer.send(body.as_bytes(), 0).unwrap();
+        }
+        // The REQ client isn't held up by the slow one, the frames of a message stay together
+        thread::sleep(Duration::from_millis(50));
+        req.send(&b"a"[..], zmq::SNDMORE).unwrap();
+        req.send(&b"b"[..], 0).unwrap();
+        assert_eq!(recv(&req), vec!["A", "B"]);
+        assert_eq!(recv(&dealer), vec!["refused"]);
+        assert_eq!(recv(&dealer), vec!["SLOW"]);
+        assert_eq!(recv(&dealer), vec!["NEXT"]);
+
+        // Out of turn, to the DEALER client
+        let first = seen.lock().unwrap()[0].clone();
+        let mut body = Multipart::new();
+        body.push_back(zmq::Message::from("done"));
+        notifier.notify(&first, body).unwrap();
+        assert_eq!(recv(&dealer), vec!["done"]);
+    }
+
+    #[test]
+    fn test_unix_socket() {
+        let path = std::env::temp_dir().join(format!("safetrace-test-{}.sock", std::process::id()));
+        let bind = format!("{}{}", UNIX_SCHEME, path.display());
+        assert_eq!(zmq_endpoint(&bind), format!("ipc://{}", path.display()));
+        assert_eq!(zmq_endpoint("tcp://*:5552"), "tcp://*:5552");
+
+        let listener = IpcListener::new(&bind, &ServerConfig { socket_mode: 0o600, ..ServerConfig::default() }).unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        thread::spawn(move || listener.run(|body, _| body));
+        let req = zmq::Context::new().socket(zmq::REQ).unwrap();
+        req.connect(&zmq_endpoint(&bind)).unwrap();
+        req.send(&b"ping"[..], 0).unwrap();
+        assert_eq!(recv(&req), vec!["ping"]);
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,67 @@
+use std::collections::{BTreeMap, HashSet};
+use std::str::FromStr;
+use std::sync::RwLock;
+
+// Subsystems that can be turned off at runtime, without restarting (and re-attesting) the enclave.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(rename_all = "camelCase")]
+pub enum Feature {
+    Registration,
+    KeyExchange,
+    Ingest,
+    Matching,
+    Federation,
+}
+
+impl Feature {
+    pub const ALL: [Feature; 5] = [Feature::Registration, Feature::KeyExchange, Feature::Ingest, Feature::Matching,
+                                   Feature::Federation];
+}
+
+impl FromStr for Feature {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "registration" => Ok(Feature::Registration),
+            "keyExchange" => Ok(Feature::KeyExchange),
+            "ingest" => Ok(Feature::Ingest),
+            "matching" => Ok(Feature::Matching),
+            "federation" => Ok(Feature::Federation),
+            other => Err(format!("Unknown feature: {}", other)),
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct KillSwitches {
+    disabled: RwLock<HashSet<Feature>>,
+}
+
+impl KillSwitches {
+    // Parses a comma separated list of features to start disabled, e.g. `ingest,matching`
+    pub fn from_config(disabled: &str) -> Result<Self, String> {
+        let switches = KillSwitches::default();
+        for name in disabled.split(',').filter(|name| !name.trim().is_empty()) {
+            switches.set(name.parse()?, false);
+        }
+        Ok(switches)
+    }
+
+    pub fn is_enabled(&self, feature: Feature) -> bool {
+        !self.disabled.read().unwrap_or_else(|poisoned| poisoned.into_inner()).contains(&feature)
+    }
+
+    pub fn set(&self, feature: Feature, enabled: bool) {
+        let mut disabled = self.disabled.write().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if enabled {
+            disabled.remove(&feature);
+        } else {
+            disabled.insert(feature);
+        }
+        info!("Feature {:?} is now {}", feature, if enabled { "enabled" } else { "disabled" });
+    }
+
+    pub fn snapshot(&self) -> BTreeMap<Feature, bool> {
+        Feature::ALL.iter().map(|&feature| (feature, self.is_enabled(feature))).collect()
+    }
+}
@@ -0,0 +1,240 @@
+use crate::common_u::errors::ConfigErr;
+use crate::config::ServerConfig;
+use crate::networking::router::{Client, ClientId, Multipart, Notifier};
+use failure::Error;
+use rustls::internal::pemfile;
+use rustls::{NoClientAuth, ServerSession, StreamOwned};
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+// The IPC sockets without ZMQ, with `server.transport = "tls"`: `server.bind` and `server.control_bind` take TLS
+// connections, with the certificate chain and key of `server.tls_cert` and `server.tls_key`. Each frame is its
+// length, 4 bytes big-endian, then a request in JSON or msgpack as on ZMQ, and goes through the same
+// `handle_message`. The requests of a connection are answered in the order they're done, as a DEALER client gets
+// them, with the same `server.max_in_flight` and `server.max_queued`; a JSON-RPC notification gets no frame back.
+//
+// The match jobs submitted with `notify` come back on the connection that submitted them, between two answers, as
+// they would to a DEALER client. With `server.heartbeat_interval` set, a connection that sent nothing for
+// `server.idle_timeout` seconds is closed: a client waiting on a long answer sends `Heartbeat` meanwhile.
+
+// How often a connection waiting for a frame looks for notifications
+const POLL: Duration = Duration::from_millis(100);
+const LENGTH: usize = 4;
+
+// The identities of the connections, unique across the listeners
+static CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+// The open connections of a listener, for the `Notifier`.
+pub type Connections = Arc<Mutex<HashMap<ClientId, mpsc::Sender<Multipart>>>>;
+
+pub struct TlsListener {
+    listener: TcpListener,
+    tls: Arc<rustls::ServerConfig>,
+    max_frame_size: usize,
+    max_in_flight: usize,
+    max_queued: usize,
+    idle_timeout: Option<Duration>,
+    connections: Connections,
+}
+
+fn tls_err(message: String) -> Error {
+    ConfigErr { message }.into()
+}
+
+fn load_tls(cert: &Path, key: &Path) -> Result<rustls::ServerConfig, Error> {
+    let certs = pemfile::certs(&mut BufReader::new(File::open(cert)?))
+        .map_err(|_| tls_err(format!("{} isn't a PEM certificate chain", cert.display())))?;
+    let read_keys = |pkcs8: bool| -> Result<_, Error> {
+        let mut reader = BufReader::new(File::open(key)?);
+        let keys = if pkcs8 { pemfile::pkcs8_private_keys(&mut reader) } else { pemfile::rsa_private_keys(&mut reader) };
+        Ok(keys.unwrap_or_default())
+    };
+    let key_der = read_keys(true)?.into_iter().chain(read_keys(false)?).next()
+        .ok_or_else(|| tls_err(format!("{} holds no PKCS#8 nor RSA private key", key.display())))?;
+    let mut tls = rustls::ServerConfig::new(NoClientAuth::new());
+    tls.set_single_cert(certs, key_der)?;
+    Ok(tls)
+}
+
+// The frame at the start of `buffer` once it's all in, taken out of it.
+fn take_frame(buffer: &mut Vec<u8>, max_frame_size: usize) -> Result<Option<Vec<u8>>, Error> {
+    if buffer.len() < LENGTH {
+        return Ok(None);
+    }
+    let length = buffer[..LENGTH].iter().fold(0usize, |length, b| length << 8 | *b as usize);
+    // Its length is all that's read of a frame too large, the connection can't go on
+    if length > max_frame_size {
+        bail!("A frame of {} bytes is past server.max_frame_size ({})", length, max_frame_size);
+    }
+    if buffer.len() < LENGTH + length {
+        return Ok(None);
+    }
+    let rest = buffer.split_off(LENGTH + length);
+    let frame = buffer.split_off(LENGTH);
+    *buffer = rest;
+    Ok(Some(frame))
+}
+
+fn write_frame<W: Write>(stream: &mut W, frame: &[u8]) -> io::Result<()> {
+    stream.write_all(&(frame.len() as u32).to_be_bytes())?;
+    stream.write_all(frame)
+}
+
+impl TlsListener {
+    pub fn new(bind: &str, config: &ServerConfig) -> Result<Self, Error> {
+        let (cert, key) = match (&config.tls_cert, &config.tls_key) {
+            (Some(cert), Some(key)) => (cert, key),
+            _ => bail!("The tls transport needs server.tls_cert and server.tls_key"),
+        };
+        let tls = Arc::new(load_tls(cert, key)?);
+        let listener = TcpListener::bind(bind)?;
+        println!("Binded to socket: tls://{}", bind);
+        let idle_timeout = if config.heartbeat_interval > 0 { Some(Duration::from_secs(config.idle_timeout)) } else { None };
+        Ok(TlsListener {
+            listener, tls, max_frame_size: config.max_frame_size, max_in_flight: config.max_in_flight, max_queued: config.max_queued,
+            idle_timeout, connections: Connections::default(),
+        })
+    }
+
+    pub fn notifier(&self) -> Notifier {
+        Notifier::Tls(self.connections.clone())
+    }
+
+    // Serves the connections until accepting them fails, `handle` answering each frame on a thread of its own.
+    pub fn run<F>(self, handle: F) -> Result<(), Error>
+    where F: Fn(Multipart, &Client) -> Multipart + Send + Sync + 'static {
+        let handle = Arc::new(handle);
+        for stream in self.listener.incoming() {
+            let stream = stream?;
+            let id = ClientId::connection(CONNECTIONS.fetch_add(1, Ordering::SeqCst));
+            let (sender, receiver) = mpsc::channel();
+            self.connections.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).insert(id.clone(), sender);
+            let connection = Connection {
+                client: Client { id, saturated: false }, notifications: receiver,
+                max_frame_size: self.max_frame_size, max_in_flight: self.max_in_flight, max_queued: self.max_queued,
+                idle_timeout: self.idle_timeout,
+            };
+            let (tls, handle, connections) = (self.tls.clone(), handle.clone(), self.connections.clone());
+            thread::spawn(move || {
+                let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+                if let Err(e) = connection.serve(stream, &tls, handle) {
+                    debug!("The connection of {} ended: {}", peer, e);
+                }
+                connections.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).remove(&connection.client.id);
+            });
+        }
+        Ok(())
+    }
+}
+
+struct Connection {
+    client: Client,
+    notifications: mpsc::Receiver<Multipart>,
+    max_frame_size: usize,
+    max_in_flight: usize,
+    max_queued: usize,
+    idle_timeout: Option<Duration>,
+}
+
+impl Connection {
+    fn serve<F>(&self, stream: TcpStream, tls: &Arc<rustls::ServerConfig>, handle: Arc<F>) -> Result<(), Error>
+    where F: Fn(Multipart, &Client) -> Multipart + Send + Sync + 'static {
+        // Short reads, the answers and notifications go out while the connection waits
+        stream.set_read_timeout(Some(POLL))?;
+        let mut stream = StreamOwned::new(ServerSession::new(tls), stream);
+        let (replies, answered) = mpsc::channel();
+        let (mut in_flight, mut queued) = (0, VecDeque::new());
+        let (mut buffer, mut chunk, mut heard) = (Vec::new(), vec![0u8; 16 * 1024], Instant::now());
+        loop {
+            while let Some(frame) = take_frame(&mut buffer, self.max_frame_size)? {
+                if in_flight + queued.len() < self.max_in_flight + self.max_queued {
+                    queued.push_back(frame);
+                    continue;
+                }
+                // Refused on the connection's thread, it doesn't get to run
+                let refused = handle(request(&frame), &Client { saturated: true, ..self.client.clone() });
+                send(&mut stream, &refused)?;
+            }
+            while in_flight < self.max_in_flight {
+                let frame = match queued.pop_front() {
+                    Some(frame) => frame,
+                    None => break,
+                };
+                in_flight += 1;
+                let (handle, client, replies) = (handle.clone(), self.client.clone(), replies.clone());
+                thread::spawn(move || {
+                    let _ = replies.send(handle(request(&frame), &client));
+                });
+            }
+            while let Ok(reply) = answered.try_recv() {
+                in_flight -= 1;
+                send(&mut stream, &reply)?;
+            }
+            while let Ok(body) = self.notifications.try_recv() {
+                send(&mut stream, &body)?;
+            }
+            stream.flush()?;
+            match stream.read(&mut chunk) {
+                Ok(0) => return Ok(()),
+                Ok(read) => {
+                    buffer.extend_from_slice(&chunk[..read]);
+                    heard = Instant::now();
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {
+                    // A client waiting on its answers isn't idle
+                    if in_flight > 0 {
+                        heard = Instant::now();
+                    } else if self.idle_timeout.map_or(false, |idle_timeout| heard.elapsed() >= idle_timeout) {
+                        bail!("Nothing came for {} seconds", heard.elapsed().as_secs());
+                    }
+                },
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
+}
+
+fn request(frame: &[u8]) -> Multipart {
+    let mut request = Multipart::new();
+    request.push_back(zmq::Message::from(frame));
+    request
+}
+
+// The frames of an answer, but the empty ones of the JSON-RPC notifications.
+fn send<W: Write>(stream: &mut W, body: &Multipart) -> io::Result<()> {
+    for part in body.iter().filter(|part| !part.is_empty()) {
+        write_frame(stream, part)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_frames() {
+        let mut wire = Vec::new();
+        write_frame(&mut wire, b"{\"id\":\"a1\"}").unwrap();
+        write_frame(&mut wire, b"").unwrap();
+        assert_eq!(&wire[..LENGTH], &[0, 0, 0, 11]);
+
+        // A frame is only taken once it's all in
+        let mut buffer = wire[..8].to_vec();
+        assert_eq!(take_frame(&mut buffer, 64).unwrap(), None);
+        buffer.extend_from_slice(&wire[8..]);
+        assert_eq!(take_frame(&mut buffer, 64).unwrap(), Some(b"{\"id\":\"a1\"}".to_vec()));
+        assert_eq!(take_frame(&mut buffer, 64).unwrap(), Some(Vec::new()));
+        assert!(buffer.is_empty() && take_frame(&mut buffer, 64).unwrap().is_none());
+
+        let mut large = vec![0, 0, 1, 0];
+        assert!(take_frame(&mut large, 255).is_err());
+    }
+}
@@ -0,0 +1,284 @@
+use crate::networking::messages::{IpcInputData, IpcInputMatch, IpcInputRegistration, IpcRequest, MatchingStrategy};
+use crate::networking::peer::ChannelHandshake;
+use crate::stats_u::ExportRequest;
+use std::fmt;
+
+// Checks of the plaintext envelope of the requests, before anything reaches an ecall.
+// The encrypted payloads can only be checked by the enclave once decrypted (see `data::validate_locations`),
+// here we make sure they are well formed hex of a plausible size, and that keys have the right length.
+
+// AES-256-GCM adds a 12 bytes IV and a 16 bytes tag to the plaintext.
+const CIPHERTEXT_OVERHEAD: usize = 28;
+const MAX_USERID_BYTES: usize = 256;
+// Submissions larger than the sealed store (`data::SEAL_LOG_SIZE`) can never be saved.
+const MAX_DATA_BYTES: usize = 4096;
+// A status update and its declaration
+const MAX_STATUS_BYTES: usize = 1024;
+// Federation payloads carry whole query batches.
+const MAX_PAYLOAD_BYTES: usize = 64 * 1024;
+const MAX_NONCE_LEN: usize = 128;
+const MAX_URI_LEN: usize = 256;
+// A secp256k1 signature, and a signing public key with one
+const SIGNATURE_BYTES: usize = 65;
+const REGISTRATION_BYTES: usize = 64 + SIGNATURE_BYTES;
+const JOB_ID_BYTES: usize = 16;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FieldError {
+    pub field: String,
+    pub message: String,
+}
+
+#[derive(Fail, Debug)]
+pub struct ValidationErr {
+    pub errors: Vec<FieldError>,
+}
+
+impl fmt::Display for ValidationErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let errors: Vec<String> = self.errors.iter().map(|e| format!("{}: {}", e.field, e.message)).collect();
+        write!(f, "Invalid request: {}", errors.join("; "))
+    }
+}
+
+#[derive(Default)]
+struct Checker {
+    errors: Vec<FieldError>,
+}
+
+impl Checker {
+    fn fail(&mut self, field: &str, message: String) {
+        self.errors.push(FieldError { field: field.to_string(), message });
+    }
+
+    // Hex of `min..=max` bytes.
+    fn hex(&mut self, field: &str, value: &str, min: usize, max: usize) {
+        if !value.chars().all(|c| c.is_ascii_hexdigit()) {
+            self.fail(field, "must be hex encoded".to_string());
+        } else if value.len() % 2 != 0 {
+            self.fail(field, "must have an even number of hex digits".to_string());
+        } else if value.len() / 2 < min || value.len() / 2 > max {
+            if min == max {
+                self.fail(field, format!("must be {} bytes long", min));
+            } else {
+                self.fail(field, format!("must be between {} and {} bytes long", min, max));
+            }
+        }
+    }
+
+    fn ciphertext(&mut self, field: &str, value: &str, max_plaintext: usize) {
+        self.hex(field, value, CIPHERTEXT_OVERHEAD + 1, CIPHERTEXT_OVERHEAD + max_plaintext);
+    }
+
+    fn pub_key(&mut self, field: &str, value: &str) {
+        self.hex(field, value, 64, 64);
+    }
+
+    fn text(&mut self, field: &str, value: &str, max: usize) {
+        if value.is_empty() || value.len() > max {
+            self.fail(field, format!("must be between 1 and {} characters", max));
+        }
+    }
+
+    // Optional, whether it's required is up to the enclave
+    fn signature(&mut self, field: &str, value: &str) {
+        if !value.is_empty() {
+            self.hex(field, value, CIPHERTEXT_OVERHEAD + SIGNATURE_BYTES, CIPHERTEXT_OVERHEAD + SIGNATURE_BYTES);
+        }
+    }
+
+    fn input_match(&mut self, input: &IpcInputMatch) {
+        self.ciphertext("input.encryptedUserId", &input.encrypted_userid, MAX_USERID_BYTES);
+        self.pub_key("input.userPubKey", &input.user_pub_key);
+        self.signature("input.encryptedSignature", &input.encrypted_signature);
+        if let Some(Err(message)) = input.matching.as_ref().map(MatchingStrategy::check) {
+            self.fail("input.matching", message.to_string());
+        }
+    }
+
+    fn input_data(&mut self, input: &IpcInputData) {
+        self.ciphertext("input.encryptedUserId", &input.encrypted_userid, MAX_USERID_BYTES);
+        self.ciphertext("input.encryptedData", input.encrypted_data.as_str(), MAX_DATA_BYTES);
+        self.pub_key("input.userPubKey", &input.user_pub_key);
+        self.signature("input.encryptedSignature", &input.encrypted_signature);
+    }
+
+    fn input_status(&mut self, input: &IpcInputData) {
+        self.ciphertext("input.encryptedUserId", &input.encrypted_userid, MAX_USERID_BYTES);
+        self.ciphertext("input.encryptedData", input.encrypted_data.as_str(), MAX_STATUS_BYTES);
+        self.pub_key("input.userPubKey", &input.user_pub_key);
+        self.signature("input.encryptedSignature", &input.encrypted_signature);
+        if input.compression.is_some() {
+            self.fail("input.compression", "status updates aren't compressed".to_string());
+        }
+    }
+
+    fn input_registration(&mut self, input: &IpcInputRegistration) {
+        self.ciphertext("input.encryptedUserId", &input.encrypted_userid, MAX_USERID_BYTES);
+        self.hex("input.encryptedData", &input.encrypted_data, CIPHERTEXT_OVERHEAD + REGISTRATION_BYTES, CIPHERTEXT_OVERHEAD + REGISTRATION_BYTES);
+        self.pub_key("input.userPubKey", &input.user_pub_key);
+    }
+
+    fn export_request(&mut self, request: &ExportRequest) {
+        if request.format != "csv" && request.format != "json" {
+            self.fail("request.format", "must be csv or json".to_string());
+        }
+        if request.precision == 0 || request.precision > 12 {
+            self.fail("request.precision", "must be between 1 and 12".to_string());
+        }
+        if request.from > request.to {
+            self.fail("request.to", "can't be before request.from".to_string());
+        }
+        self.hex("request.signature", &request.signature, 65, 65);
+    }
+
+    fn handshake(&mut self, handshake: &ChannelHandshake) {
+        self.hex("handshake.attestation.signingKey", &handshake.attestation.signing_key, 20, 20);
+        self.pub_key("handshake.channelPubKey", &handshake.channel_pub_key);
+        self.hex("handshake.sig", &handshake.sig, 65, 65);
+    }
+}
+
+pub fn validate(request: &IpcRequest) -> Result<(), ValidationErr> {
+    let mut check = Checker::default();
+    match request {
+        IpcRequest::NewTaskEncryptionKey { userPubKey } => check.pub_key("userPubKey", userPubKey),
+        IpcRequest::AddPersonalData { input } => check.input_data(input),
+        IpcRequest::RegisterUser { input } => check.input_registration(input),
+        IpcRequest::UpdateUserStatus { input } => check.input_status(input),
+        IpcRequest::FindMatch { input } => {
+            check.input_match(input);
+            if input.notify.is_some() {
+                check.fail("input.notify", "only SubmitMatchJob notifies".to_string());
+            }
+        },
+        IpcRequest::SubmitMatchJob { input } => {
+            check.input_match(input);
+            if input.incremental.is_some() {
+                check.fail("input.incremental", "only FindMatch answers incrementally".to_string());
+            }
+        },
+        IpcRequest::FindMatchFederated { input } => {
+            check.input_match(input);
+            // Every deployment matches with its own
+            if input.matching.is_some() {
+                check.fail("input.matching", "federated queries run with the strategy of each deployment".to_string());
+            }
+            if input.incremental.is_some() {
+                check.fail("input.incremental", "only FindMatch answers incrementally".to_string());
+            }
+            if input.notify.is_some() {
+                check.fail("input.notify", "only SubmitMatchJob notifies".to_string());
+            }
+        },
+        IpcRequest::GetMatchJob { job_id } |
+        IpcRequest::GetAttestationJob { job_id } => check.hex("jobId", job_id, JOB_ID_BYTES, JOB_ID_BYTES),
+        IpcRequest::GetCachedReport { quote_hash } => check.hex("quoteHash", quote_hash, 32, 32),
+        IpcRequest::OpenChannel { handshake } => check.handshake(handshake),
+        IpcRequest::ConnectPeer { uri } => {
+            check.text("uri", uri, MAX_URI_LEN);
+            if !uri.starts_with("tcp://") && !uri.starts_with("ipc://") {
+                check.fail("uri", "must be a tcp:// or ipc:// ZMQ endpoint".to_string());
+            }
+        },
+        IpcRequest::FederatedQuery { sender, payload } => {
+            check.hex("sender", sender, 20, 20);
+            check.ciphertext("payload", payload, MAX_PAYLOAD_BYTES);
+        },
+        IpcRequest::Ping { nonce } => check.text("nonce", nonce, MAX_NONCE_LEN),
+        IpcRequest::ExportExposureStatistics { request } => check.export_request(request),
+        IpcRequest::GetEnclaveReport | IpcRequest::GetFeatureSwitches | IpcRequest::SetFeatureSwitch { .. } |
+        IpcRequest::GetStats | IpcRequest::GetAggregates | IpcRequest::GetHealth | IpcRequest::GetReadiness |
+        IpcRequest::GetProtocolVersion { .. } | IpcRequest::ExportAuditLog { .. } | IpcRequest::GetEnclaveInfo |
+        IpcRequest::Heartbeat { .. } => {},
+    }
+    if check.errors.is_empty() { Ok(()) } else { Err(ValidationErr { errors: check.errors }) }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::networking::messages::Compression;
+
+    #[test]
+    fn test_validate_find_match() {
+        let valid = IpcInputMatch { encrypted_userid: "ab".repeat(40), user_pub_key: "cd".repeat(64), encrypted_signature: String::new(), matching: None, incremental: None, notify: None };
+        assert!(validate(&IpcRequest::FindMatch { input: valid.clone() }).is_ok());
+
+        let invalid = IpcInputMatch { encrypted_userid: "xyz".to_string(), user_pub_key: "cd".repeat(10), encrypted_signature: String::new(), matching: None, incremental: None, notify: None };
+        let errors = validate(&IpcRequest::FindMatch { input: invalid }).unwrap_err().errors;
+        let fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(fields, vec!["input.encryptedUserId", "input.userPubKey"]);
+        assert_eq!(errors[1].message, "must be 64 bytes long");
+
+        let strategy = |matching| IpcRequest::SubmitMatchJob { input: IpcInputMatch { matching: Some(matching), ..valid.clone() } };
+        assert!(validate(&strategy(MatchingStrategy::Grid { cell: 0.001, min_overlap: 300 })).is_ok());
+        for matching in vec![
+            MatchingStrategy::Radius { min_overlap: -1, distance: 10.0 },
+            MatchingStrategy::Grid { cell: 2.0, min_overlap: 300 },
+            MatchingStrategy::Duration { distance: std::f64::NAN, min_duration: 900 },
+            MatchingStrategy::Duration { distance: 10.0, min_duration: 0 },
+        ] {
+            let errors = validate(&strategy(matching)).unwrap_err().errors;
+            assert_eq!(errors[0].field, "input.matching");
+        }
+        let federated = IpcInputMatch { matching: Some(MatchingStrategy::Radius { min_overlap: 300, distance: 10.0 }), ..valid.clone() };
+        assert!(validate(&IpcRequest::FindMatchFederated { input: federated }).is_err());
+        let incremental = IpcInputMatch { incremental: Some(true), ..valid.clone() };
+        assert!(validate(&IpcRequest::FindMatch { input: incremental.clone() }).is_ok());
+        assert!(validate(&IpcRequest::SubmitMatchJob { input: incremental }).is_err());
+        let notify = IpcInputMatch { notify: Some(true), ..valid };
+        assert!(validate(&IpcRequest::SubmitMatchJob { input: notify.clone() }).is_ok());
+        assert!(validate(&IpcRequest::FindMatch { input: notify }).is_err());
+    }
+
+    #[test]
+    fn test_validate_sizes() {
+        // Shorter than the IV and tag alone, it can't hold anything
+        let input = IpcInputData {
+            encrypted_userid: "ab".repeat(28), encrypted_data: "ab".repeat(5000).into(), user_pub_key: "cd".repeat(64), encrypted_signature: String::new(),
+            compression: None,
+        };
+        let errors = validate(&IpcRequest::AddPersonalData { input: input.clone() }).unwrap_err().errors;
+        assert_eq!(errors.len(), 2);
+        // A status update is far smaller than a location history
+        let status = IpcInputData { encrypted_userid: "ab".repeat(40), encrypted_data: "ab".repeat(2000).into(), ..input };
+        let errors = validate(&IpcRequest::UpdateUserStatus { input: status.clone() }).unwrap_err().errors;
+        assert_eq!(errors[0].field, "input.encryptedData");
+        let deflated = IpcInputData { encrypted_data: "ab".repeat(100).into(), compression: Some(Compression::Deflate), ..status };
+        assert!(validate(&IpcRequest::AddPersonalData { input: deflated.clone() }).is_ok());
+        let errors = validate(&IpcRequest::UpdateUserStatus { input: deflated }).unwrap_err().errors;
+        assert_eq!(errors[0].field, "input.compression");
+        assert!(validate(&IpcRequest::Ping { nonce: String::new() }).is_err());
+        assert!(validate(&IpcRequest::ConnectPeer { uri: "http://peer".to_string() }).is_err());
+        assert!(validate(&IpcRequest::GetMatchJob { job_id: "0f".repeat(16) }).is_ok());
+        assert!(validate(&IpcRequest::GetMatchJob { job_id: "0f".repeat(8) }).is_err());
+        assert!(validate(&IpcRequest::GetAttestationJob { job_id: "0F".repeat(16) }).is_ok());
+        assert!(validate(&IpcRequest::GetCachedReport { quote_hash: "0f".repeat(32) }).is_ok());
+        assert!(validate(&IpcRequest::GetCachedReport { quote_hash: "0f".repeat(20) }).is_err());
+    }
+
+    #[test]
+    fn test_validate_signatures() {
+        let signed = |signature: &str| IpcInputMatch {
+            encrypted_userid: "ab".repeat(40), user_pub_key: "cd".repeat(64), encrypted_signature: signature.to_string(), matching: None, incremental: None, notify: None,
+        };
+        assert!(validate(&IpcRequest::FindMatch { input: signed(&"ef".repeat(93)) }).is_ok());
+        let errors = validate(&IpcRequest::FindMatch { input: signed(&"ef".repeat(65)) }).unwrap_err().errors;
+        assert_eq!(errors[0].field, "input.encryptedSignature");
+
+        let registration = |data: String| IpcInputRegistration { encrypted_userid: "ab".repeat(40), encrypted_data: data, user_pub_key: "cd".repeat(64) };
+        assert!(validate(&IpcRequest::RegisterUser { input: registration("ef".repeat(157)) }).is_ok());
+        assert!(validate(&IpcRequest::RegisterUser { input: registration("ef".repeat(64)) }).is_err());
+    }
+
+    #[test]
+    fn test_validate_export_request() {
+        let request = ExportRequest { issued_at: 1589000000, format: "csv".to_string(), precision: 6, from: 18380, to: 18390, signature: "ab".repeat(65) };
+        assert!(validate(&IpcRequest::ExportExposureStatistics { request: request.clone() }).is_ok());
+        let invalid = ExportRequest { format: "xlsx".to_string(), precision: 13, from: 18391, signature: "ab".repeat(64), ..request };
+        let errors = validate(&IpcRequest::ExportExposureStatistics { request: invalid }).unwrap_err().errors;
+        let fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+        assert_eq!(fields, vec!["request.format", "request.precision", "request.to", "request.signature"]);
+    }
+}
@@ -1,4 +1,6 @@
 use sgx_types::{sgx_enclave_id_t, sgx_status_t, sgx_target_info_t, sgx_report_t};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 //use crate::esgx::general;
 
@@ -9,6 +11,35 @@ use sgx_types::{sgx_enclave_id_t, sgx_status_t, sgx_target_info_t, sgx_report_t}
 //     ptr as u64
 // }
 
+/// Forward jumps of the host clock larger than this (in seconds) are reported as security events.
+const TIME_JUMP_THRESHOLD: u64 = 60 * 60;
+
+// Latest time handed to the enclave by `ocall_get_time`.
+static LAST_TIME: AtomicU64 = AtomicU64::new(0);
+
+/// Time source for the enclave: seconds since the UNIX epoch, never going backwards.
+/// If the system clock moves back we keep serving the last value, and both backward
+/// moves and large forward jumps are logged as security events.
+#[no_mangle]
+pub extern "C" fn ocall_get_time() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let mut last = LAST_TIME.load(Ordering::SeqCst);
+    loop {
+        if now < last {
+            warn!(target: "security", "system clock moved backwards by {}s, serving the enclave the last known time", last - now);
+            return last;
+        }
+        match LAST_TIME.compare_exchange(last, now, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => break,
+            Err(current) => last = current,
+        }
+    }
+    if last != 0 && now - last > TIME_JUMP_THRESHOLD {
+        warn!(target: "security", "system clock jumped forward by {}s", now - last);
+    }
+    now
+}
+
 #[no_mangle]
 extern "C" {
     pub fn ecall_get_registration_quote(
@@ -22,4 +53,5 @@ extern "C" {
 #[no_mangle]
 extern "C" {
     pub fn ecall_get_signing_address(eid: sgx_enclave_id_t, arr: *mut [u8; 20usize]) -> sgx_status_t;
+    pub fn ecall_get_mr_enclave(eid: sgx_enclave_id_t, arr: *mut [u8; 32usize]) -> sgx_status_t;
 }
\ No newline at end of file
@@ -0,0 +1,59 @@
+use crate::common_u::errors::EnclaveFailError;
+use crate::telemetry;
+use failure::Error;
+use sgx_types::{sgx_enclave_id_t, sgx_status_t};
+use enigma_types::{EnclaveReturn};
+use std::str::FromStr;
+
+
+extern {
+    pub fn ecall_set_response_padding(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, padding_class: u8, bucket: u64) -> sgx_status_t;
+}
+
+// Classes of encrypted outputs that can be padded, must match `padding::PaddingClass` in the enclave.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PaddingClass {
+    Matching = 0,
+    Federation = 1,
+}
+
+impl PaddingClass {
+    pub fn name(self) -> &'static str {
+        match self {
+            PaddingClass::Matching => "matching",
+            PaddingClass::Federation => "federation",
+        }
+    }
+}
+
+impl FromStr for PaddingClass {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "matching" => Ok(PaddingClass::Matching),
+            "federation" => Ok(PaddingClass::Federation),
+            other => Err(format!("Unknown padding class: {}", other)),
+        }
+    }
+}
+
+// Parses a comma separated list of `class=bucket` pairs, e.g. `matching=1024,federation=4096`
+pub fn parse_config(config: &str) -> Result<Vec<(PaddingClass, u64)>, String> {
+    config.split(',').filter(|entry| !entry.trim().is_empty()).map(|entry| {
+        let mut parts = entry.splitn(2, '=');
+        let class = parts.next().unwrap_or_default().parse()?;
+        let bucket = parts.next().and_then(|b| b.trim().parse().ok())
+            .ok_or_else(|| format!("Invalid padding bucket in: {}", entry))?;
+        Ok((class, bucket))
+    }).collect()
+}
+
+// Plaintexts of `class` get padded to a multiple of `bucket` bytes before being encrypted, 0 disables it.
+pub fn set_response_padding(eid: sgx_enclave_id_t, class: PaddingClass, bucket: u64) -> Result<(), Error> {
+    let mut ret = EnclaveReturn::Success;
+    let status = telemetry::ecall("ecall_set_response_padding", || unsafe { ecall_set_response_padding(eid, &mut ret as *mut EnclaveReturn, class as u8, bucket) });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok(())
+}
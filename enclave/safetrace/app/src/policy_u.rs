@@ -0,0 +1,253 @@
+use crate::common_u::errors::EnclaveFailError;
+use crate::config::{CapacityConfig, EnclaveConfig, ExportConfig, MatchingConfig, QuantizationConfig, RetentionConfig, StatisticsConfig};
+use crate::networking::messages::MatchingStrategy;
+use crate::telemetry;
+use enigma_crypto::KeyPair;
+use failure::Error;
+use hex::{FromHex, ToHex};
+use sha2::{Digest, Sha256};
+use sgx_types::{sgx_enclave_id_t, sgx_status_t};
+use enigma_types::{EnclaveReturn};
+
+
+extern {
+    pub fn ecall_set_data_policy(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn,
+                                 min_overlap: i32, distance: f64, retention: u64) -> sgx_status_t;
+    pub fn ecall_set_matching_strategy(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn,
+                                       strategy: *const u8, strategy_len: usize) -> sgx_status_t;
+    pub fn ecall_set_replay_policy(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, window: u64, required: u8) -> sgx_status_t;
+    pub fn ecall_set_clock_policy(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, max_skew: u64) -> sgx_status_t;
+    pub fn ecall_set_auth_policy(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, required: u8) -> sgx_status_t;
+    pub fn ecall_set_rollback_policy(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, monotonic: u8) -> sgx_status_t;
+    pub fn ecall_set_storage_policy(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, compact_after: u64) -> sgx_status_t;
+    pub fn ecall_set_capacity(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, soft_records: u64, hard_records: u64) -> sgx_status_t;
+    pub fn ecall_set_quantization(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, grid: f64, time_bucket: u64) -> sgx_status_t;
+    pub fn ecall_set_export_policy(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, k: u64) -> sgx_status_t;
+    pub fn ecall_set_statistics_policy(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn,
+                                       epsilon: f64, precision: u8, min_count: u64) -> sgx_status_t;
+    pub fn ecall_set_health_authorities(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, keys: *const u8, keys_len: usize, max_age: u64) -> sgx_status_t;
+    pub fn ecall_apply_policy(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, document: *const u8, document_len: usize,
+                              signature: *const u8, signature_len: usize, version: *mut u64, hash: &mut [u8; 32]) -> sgx_status_t;
+    pub fn ecall_restore_policy(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, version: *mut u64, hash: &mut [u8; 32]) -> sgx_status_t;
+    pub fn ecall_get_settings(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, serialized_ptr: *mut u64) -> sgx_status_t;
+}
+
+// A policy document and its signature by the policy key (65 bytes hex), see `policy` in the enclave. The
+// document is kept as signed, the enclave hashes it as it is.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SignedPolicy {
+    pub document: String,
+    pub signature: String,
+}
+
+impl SignedPolicy {
+    pub fn sign(document: String, keypair: &KeyPair) -> Result<SignedPolicy, Error> {
+        let signature = keypair.sign(document.as_bytes())?.to_hex();
+        Ok(SignedPolicy { document, signature })
+    }
+}
+
+// The SHA-256 of a policy public key, what `SAFETRACE_POLICY_KEY_HASH` bakes into the enclave.
+pub fn key_hash(keypair: &KeyPair) -> String {
+    Sha256::digest(&keypair.get_pubkey()[..]).to_hex()
+}
+
+// Has an enclave check and apply a signed policy, which it seals. Returns its version and hash.
+pub fn apply_signed_policy(eid: sgx_enclave_id_t, policy: &SignedPolicy) -> Result<(u64, [u8; 32]), Error> {
+    let signature: Vec<u8> = policy.signature.from_hex().map_err(|_| format_err!("the policy signature isn't hex"))?;
+    let mut ret = EnclaveReturn::Success;
+    let (mut version, mut hash) = (0u64, [0u8; 32]);
+    let status = telemetry::ecall("ecall_apply_policy", || unsafe {
+        ecall_apply_policy(eid, &mut ret as *mut EnclaveReturn, policy.document.as_ptr(), policy.document.len(),
+                           signature.as_ptr(), signature.len(), &mut version as *mut u64, &mut hash)
+    });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok((version, hash))
+}
+
+// Applies the sealed policy again, over the one of the configuration: after the other policies of a worker
+// were set. Version 0 when there's none.
+pub fn restore_signed_policy(eid: sgx_enclave_id_t) -> Result<(u64, [u8; 32]), Error> {
+    let mut ret = EnclaveReturn::Success;
+    let (mut version, mut hash) = (0u64, [0u8; 32]);
+    let status = telemetry::ecall("ecall_restore_policy", || unsafe {
+        ecall_restore_policy(eid, &mut ret as *mut EnclaveReturn, &mut version as *mut u64, &mut hash)
+    });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok((version, hash))
+}
+
+// Hands the matching thresholds and the retention period to the enclave, which enforces them.
+pub fn set_data_policy(eid: sgx_enclave_id_t, matching: &MatchingConfig, retention: &RetentionConfig) -> Result<(), Error> {
+    let mut ret = EnclaveReturn::Success;
+    let status = telemetry::ecall("ecall_set_data_policy", || unsafe {
+        ecall_set_data_policy(eid, &mut ret as *mut EnclaveReturn, matching.min_overlap, matching.distance, retention.seconds())
+    });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok(())
+}
+
+// The strategy of the queries that don't name one, see `matching` in the enclave.
+pub fn set_matching_strategy(eid: sgx_enclave_id_t, strategy: &MatchingStrategy) -> Result<(), Error> {
+    let encoded = serde_json::to_vec(strategy)?;
+    let mut ret = EnclaveReturn::Success;
+    let status = telemetry::ecall("ecall_set_matching_strategy", || unsafe { ecall_set_matching_strategy(eid, &mut ret as *mut EnclaveReturn, encoded.as_ptr(), encoded.len()) });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok(())
+}
+
+// The replay window of `addPersonalData` envelopes, and whether the payloads without nonce are still accepted.
+pub fn set_replay_policy(eid: sgx_enclave_id_t, config: &EnclaveConfig) -> Result<(), Error> {
+    let mut ret = EnclaveReturn::Success;
+    let required = config.require_replay_protection as u8;
+    let status = telemetry::ecall("ecall_set_replay_policy", || unsafe { ecall_set_replay_policy(eid, &mut ret as *mut EnclaveReturn, config.replay_window, required) });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok(())
+}
+
+// How far off the clocks of the devices may be, see `clock` in the enclave.
+pub fn set_clock_policy(eid: sgx_enclave_id_t, config: &EnclaveConfig) -> Result<(), Error> {
+    let mut ret = EnclaveReturn::Success;
+    let status = telemetry::ecall("ecall_set_clock_policy", || unsafe { ecall_set_clock_policy(eid, &mut ret as *mut EnclaveReturn, config.max_clock_skew) });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok(())
+}
+
+// Whether the users who never registered a signing key may still submit and query.
+pub fn set_auth_policy(eid: sgx_enclave_id_t, config: &EnclaveConfig) -> Result<(), Error> {
+    let mut ret = EnclaveReturn::Success;
+    let status = telemetry::ecall("ecall_set_auth_policy", || unsafe { ecall_set_auth_policy(eid, &mut ret as *mut EnclaveReturn, config.require_registration as u8) });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok(())
+}
+
+// Whether the sealed store is bound to a monotonic counter, on top of the generation the enclave checks anyway.
+pub fn set_rollback_policy(eid: sgx_enclave_id_t, config: &EnclaveConfig) -> Result<(), Error> {
+    let mut ret = EnclaveReturn::Success;
+    let status = telemetry::ecall("ecall_set_rollback_policy", || unsafe { ecall_set_rollback_policy(eid, &mut ret as *mut EnclaveReturn, config.monotonic_counter as u8) });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok(())
+}
+
+// How many writes the log of the sealed store takes before it's compacted.
+pub fn set_storage_policy(eid: sgx_enclave_id_t, config: &EnclaveConfig) -> Result<(), Error> {
+    let mut ret = EnclaveReturn::Success;
+    let status = telemetry::ecall("ecall_set_storage_policy", || unsafe { ecall_set_storage_policy(eid, &mut ret as *mut EnclaveReturn, config.compact_after) });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok(())
+}
+
+// How many records the store may hold, the enclave refuses the submissions past the hard limit.
+pub fn set_capacity(eid: sgx_enclave_id_t, config: &CapacityConfig) -> Result<(), Error> {
+    let mut ret = EnclaveReturn::Success;
+    let status = telemetry::ecall("ecall_set_capacity", || unsafe { ecall_set_capacity(eid, &mut ret as *mut EnclaveReturn, config.soft_records, config.hard_records) });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok(())
+}
+
+// The health authorities whose declarations may mark a user as infected. The enclave takes them once and
+// refuses an empty list: with none it's left out, and the enclave trusts every submission.
+pub fn set_health_authorities(eid: sgx_enclave_id_t, config: &EnclaveConfig) -> Result<(), Error> {
+    let keys = config.health_authority_keys()?.concat();
+    if keys.is_empty() {
+        return Ok(());
+    }
+    let mut ret = EnclaveReturn::Success;
+    let status = telemetry::ecall("ecall_set_health_authorities", || unsafe {
+        ecall_set_health_authorities(eid, &mut ret as *mut EnclaveReturn, keys.as_ptr(), keys.len(), config.declaration_max_age)
+    });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok(())
+}
+
+// The privacy budget of the aggregate statistics, an epsilon of 0 tells the enclave they're disabled.
+pub fn set_statistics_policy(eid: sgx_enclave_id_t, config: &StatisticsConfig) -> Result<(), Error> {
+    let mut ret = EnclaveReturn::Success;
+    let epsilon = if config.enabled { config.epsilon } else { 0.0 };
+    let status = telemetry::ecall("ecall_set_statistics_policy", || unsafe {
+        ecall_set_statistics_policy(eid, &mut ret as *mut EnclaveReturn, epsilon, config.geohash_precision, config.min_count)
+    });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok(())
+}
+
+// The k-anonymity threshold of the exports, 0 tells the enclave they're disabled.
+pub fn set_export_policy(eid: sgx_enclave_id_t, config: &ExportConfig) -> Result<(), Error> {
+    let mut ret = EnclaveReturn::Success;
+    let k = if config.enabled { config.k_anonymity } else { 0 };
+    let status = telemetry::ecall("ecall_set_export_policy", || unsafe { ecall_set_export_policy(eid, &mut ret as *mut EnclaveReturn, k) });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok(())
+}
+
+// How coarse the stored locations are, applied by the enclave before sealing.
+pub fn set_quantization(eid: sgx_enclave_id_t, config: &QuantizationConfig) -> Result<(), Error> {
+    let mut ret = EnclaveReturn::Success;
+    let status = telemetry::ecall("ecall_set_quantization", || unsafe { ecall_set_quantization(eid, &mut ret as *mut EnclaveReturn, config.grid, config.time_bucket) });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok(())
+}
+
+// The settings the enclave enforces, the JSON document its quotes commit to (see `settings` in the enclave).
+// Kept as the enclave wrote it: clients hash it as it is.
+pub fn get_settings(eid: sgx_enclave_id_t) -> Result<String, Error> {
+    let mut ret = EnclaveReturn::Success;
+    let mut serialized_ptr = 0u64;
+
+    let status = telemetry::ecall("ecall_get_settings", || unsafe { ecall_get_settings(eid, &mut ret as *mut EnclaveReturn, &mut serialized_ptr as *mut u64) });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    let box_ptr = serialized_ptr as *mut Box<[u8]>;
+    let part = unsafe { Box::from_raw(box_ptr) };
+    Ok(String::from_utf8(part.to_vec())?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_signed_policy() {
+        let keypair = KeyPair::from_slice(&[5u8; 32]).unwrap();
+        let policy = SignedPolicy::sign(r#"{"version": 1, "retentionDays": 14}"#.to_string(), &keypair).unwrap();
+        assert_eq!(policy.signature.len(), 130);
+        let signature: Vec<u8> = policy.signature.from_hex().unwrap();
+        let mut sig = [0u8; 65];
+        sig.copy_from_slice(&signature);
+        assert_eq!(&KeyPair::recover(policy.document.as_bytes(), sig).unwrap()[..], &keypair.get_pubkey()[..]);
+
+        // The document travels as signed, whitespace included
+        let decoded: SignedPolicy = serde_json::from_str(&serde_json::to_string(&policy).unwrap()).unwrap();
+        assert_eq!(decoded, policy);
+        assert_eq!(key_hash(&keypair).len(), 64);
+    }
+}
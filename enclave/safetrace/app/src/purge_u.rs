@@ -0,0 +1,25 @@
+use crate::common_u::errors::EnclaveFailError;
+use crate::telemetry;
+use failure::Error;
+use sgx_types::{sgx_enclave_id_t, sgx_status_t};
+use enigma_types::{EnclaveReturn};
+
+
+extern {
+    pub fn ecall_purge_data(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn,
+                            purged_users: *mut u64, purged_records: *mut u64) -> sgx_status_t;
+}
+
+// Empties the sealed store of a running enclave, returns the number of users and records deleted.
+// The caller holds the pool state lock, like for any other write.
+pub fn purge_data(eid: sgx_enclave_id_t) -> Result<(u64, u64), Error> {
+    let mut ret = EnclaveReturn::Success;
+    let mut users = 0u64;
+    let mut records = 0u64;
+
+    let status = telemetry::ecall("ecall_purge_data", || unsafe { ecall_purge_data(eid, &mut ret as *mut EnclaveReturn, &mut users as *mut u64, &mut records as *mut u64) });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok((users, records))
+}
@@ -0,0 +1,131 @@
+use crate::common_u::errors::EnclaveFailError;
+use crate::config::{AdminConfig, TenantConfig};
+use crate::telemetry;
+use failure::Error;
+use hex::ToHex;
+use sgx_types::{sgx_enclave_id_t, sgx_status_t};
+use sha2::{Digest, Sha256};
+use enigma_types::{EnclaveReturn};
+
+
+extern {
+    pub fn ecall_register_operators(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn,
+                                    operators: *const u8, operators_len: usize, state: *mut u8) -> sgx_status_t;
+    pub fn ecall_authorize_admin(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, payload: *const u8, payload_len: usize,
+                                 signatures: *const u8, signatures_len: usize, approvals: *mut u64) -> sgx_status_t;
+    pub fn ecall_adopt_operators(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, operators: *mut u64, required: *mut u32) -> sgx_status_t;
+}
+
+// The operator keys and the quorum the enclave seals, see `quorum` in the enclave. It checks the signatures of
+// the privileged admin operations itself, and purges or hands its key to an upgrade only once they did.
+// The sealed operators stay until a quorum of them signs a `SetOperators` naming the `digest` of the configured
+// ones, so a host restarted with other keys can't take over.
+
+// What the enclave accepts, see `config::AdminConfig`
+pub const ENCLAVE_MAX_SKEW: u64 = 300;
+pub const MAX_OPERATORS: usize = 16;
+
+// The enclave compares and hashes this encoding, keep the fields and their order
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Operator {
+    pub key: String,
+    pub tenant: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Registry {
+    pub operators: Vec<Operator>,
+    pub quorum: u32,
+}
+
+// What the enclave did with the configured operators
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Registration {
+    // None were sealed, these are now
+    Registered,
+    Unchanged,
+    // Others are sealed and stay, until a `SetOperators`
+    PendingAdoption,
+}
+
+// The admin operators then those of each tenant, the keys in lowercase hex.
+pub fn registry(admin: &AdminConfig, tenants: &[TenantConfig]) -> Result<Registry, Error> {
+    let mut operators: Vec<Operator> = admin.operator_keys()?.iter().map(|key| Operator { key: key.to_hex(), tenant: None }).collect();
+    for tenant in tenants {
+        operators.extend(tenant.operator_keys()?.iter().map(|key| Operator { key: key.to_hex(), tenant: Some(tenant.id.clone()) }));
+    }
+    Ok(Registry { operators, quorum: admin.quorum })
+}
+
+// SHA-256 (hex) of the encoding, which a `SetOperators` names.
+pub fn digest(registry: &Registry) -> Result<String, Error> {
+    Ok(Sha256::digest(&serde_json::to_vec(registry)?).to_hex())
+}
+
+// Hands the configured operators to an enclave, every worker reads the sealed ones on its own.
+pub fn register_operators(eid: sgx_enclave_id_t, registry: &Registry) -> Result<Registration, Error> {
+    let encoded = serde_json::to_vec(registry)?;
+    let mut ret = EnclaveReturn::Success;
+    let mut state = 0u8;
+    let status = telemetry::ecall("ecall_register_operators", || unsafe {
+        ecall_register_operators(eid, &mut ret as *mut EnclaveReturn, encoded.as_ptr(), encoded.len(), &mut state as *mut u8)
+    });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok(match state {
+        0 => Registration::Registered,
+        1 => Registration::Unchanged,
+        _ => Registration::PendingAdoption,
+    })
+}
+
+// Has the enclave check the signatures (65 bytes each) of an admin payload, and grant its operation to the
+// next ecall needing a quorum. Returns how many operators signed, 0 when none were ever registered.
+pub fn authorize(eid: sgx_enclave_id_t, payload: &str, signatures: &[[u8; 65]]) -> Result<u64, Error> {
+    let signatures = signatures.concat();
+    let mut ret = EnclaveReturn::Success;
+    let mut approvals = 0u64;
+    let status = telemetry::ecall("ecall_authorize_admin", || unsafe {
+        ecall_authorize_admin(eid, &mut ret as *mut EnclaveReturn, payload.as_ptr(), payload.len(),
+                              signatures.as_ptr(), signatures.len(), &mut approvals as *mut u64)
+    });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok(approvals)
+}
+
+// Seals the configured operators in place of the current ones, after an `authorize` of their `SetOperators`.
+// Returns how many operators there now are, and the quorum.
+pub fn adopt_operators(eid: sgx_enclave_id_t) -> Result<(u64, u32), Error> {
+    let mut ret = EnclaveReturn::Success;
+    let (mut operators, mut required) = (0u64, 0u32);
+    let status = telemetry::ecall("ecall_adopt_operators", || unsafe {
+        ecall_adopt_operators(eid, &mut ret as *mut EnclaveReturn, &mut operators as *mut u64, &mut required as *mut u32)
+    });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok((operators, required))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_registry() {
+        let admin = AdminConfig { operators: vec!["AB".repeat(64)], quorum: 1, ..AdminConfig::default() };
+        let tenants = vec![TenantConfig { id: "ch-ge".to_string(), operators: vec!["cd".repeat(64)], ..TenantConfig::default() }];
+        let registry = registry(&admin, &tenants).unwrap();
+        // As the enclave reads it, the keys normalized
+        assert_eq!(serde_json::to_string(&registry).unwrap(),
+                   format!(r#"{{"operators":[{{"key":"{}","tenant":null}},{{"key":"{}","tenant":"ch-ge"}}],"quorum":1}}"#, "ab".repeat(64), "cd".repeat(64)));
+        assert_eq!(digest(&registry).unwrap().len(), 64);
+
+        // Another quorum is another set of operators
+        let stricter = Registry { quorum: 2, ..registry.clone() };
+        assert_ne!(digest(&stricter).unwrap(), digest(&registry).unwrap());
+    }
+}
@@ -0,0 +1,169 @@
+use crate::common_u::errors::ConfigErr;
+use failure::Error;
+use reqwest::Client;
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use zeroize::Zeroizing;
+
+// Time given to the secret manager to answer at startup.
+const VAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+// A credential (SPID, IAS key...). The memory holding it is wiped when it's dropped, and it never
+// shows up in logs or `Debug` output. Code using it calls `expose` at the last moment.
+#[derive(Clone)]
+pub struct Secret(Zeroizing<String>);
+
+impl Secret {
+    pub fn new(value: String) -> Self {
+        Secret(Zeroizing::new(value))
+    }
+
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl<'a> From<&'a str> for Secret {
+    fn from(value: &'a str) -> Self {
+        Secret::new(value.to_string())
+    }
+}
+
+impl PartialEq for Secret {
+    fn eq(&self, other: &Self) -> bool {
+        self.expose() == other.expose()
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Secret(***)")
+    }
+}
+
+impl<'de> Deserialize<'de> for Secret {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer).map(Secret::new)
+    }
+}
+
+fn secret_err(message: String) -> Error {
+    ConfigErr { message }.into()
+}
+
+// Somewhere secrets can be fetched from by name, e.g. `spid` or `ias_key`.
+pub trait SecretProvider {
+    fn name(&self) -> &str;
+    // `None` when the provider doesn't hold this secret, so the next one can be asked.
+    fn get(&self, secret: &str) -> Result<Option<Secret>, Error>;
+}
+
+// Reads a secret file, trimming the trailing newline most tools add.
+pub fn read_secret_file(path: &Path) -> Result<Secret, Error> {
+    let mut content = Zeroizing::new(String::new());
+    fs::File::open(path).and_then(|mut file| file.read_to_string(&mut content))
+        .map_err(|e| secret_err(format!("unable to read the secret {}: {}", path.display(), e)))?;
+    warn_if_shared(path);
+    Ok(Secret::new(content.trim().to_string()))
+}
+
+#[cfg(unix)]
+fn warn_if_shared(path: &Path) {
+    use std::os::unix::fs::PermissionsExt;
+    if let Ok(meta) = fs::metadata(path) {
+        if meta.permissions().mode() & 0o077 != 0 {
+            warn!(target: "security", "The secret {} can be read by other users, restrict it to mode 0600", path.display());
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn warn_if_shared(_path: &Path) {}
+
+// One file per secret in `dir`, named after the secret. That's how docker and kubernetes
+// mount their secrets, e.g. `/run/secrets/spid`.
+pub struct FileSecrets {
+    dir: PathBuf,
+}
+
+impl FileSecrets {
+    pub fn new(dir: PathBuf) -> Self {
+        FileSecrets { dir }
+    }
+}
+
+impl SecretProvider for FileSecrets {
+    fn name(&self) -> &str { "files" }
+
+    fn get(&self, secret: &str) -> Result<Option<Secret>, Error> {
+        let path = self.dir.join(secret);
+        if !path.exists() {
+            return Ok(None);
+        }
+        read_secret_file(&path).map(Some)
+    }
+}
+
+// The KV secrets engine of HashiCorp Vault, both versions. `path` is the secret path under the
+// API, e.g. `secret/data/safetrace` (v2) or `secret/safetrace` (v1), and every field of it is a secret.
+pub struct VaultSecrets {
+    addr: String,
+    path: String,
+    token: Secret,
+}
+
+impl VaultSecrets {
+    pub fn new(addr: &str, path: &str, token_file: &Path) -> Result<Self, Error> {
+        Ok(VaultSecrets {
+            addr: addr.trim_end_matches('/').to_string(),
+            path: path.trim_matches('/').to_string(),
+            token: read_secret_file(token_file)?,
+        })
+    }
+}
+
+impl SecretProvider for VaultSecrets {
+    fn name(&self) -> &str { "vault" }
+
+    fn get(&self, secret: &str) -> Result<Option<Secret>, Error> {
+        let url = format!("{}/v1/{}", self.addr, self.path);
+        let client = Client::builder().timeout(VAULT_TIMEOUT).build()?;
+        let mut res = client.get(url.as_str()).header("X-Vault-Token", self.token.expose()).send()?;
+        if !res.status().is_success() {
+            return Err(secret_err(format!("Vault answered {} for {}", res.status(), self.path)));
+        }
+        let body = Zeroizing::new(res.text()?);
+        let body: serde_json::Value = serde_json::from_str(&body)?;
+        // KV v2 nests the fields one level deeper than v1
+        let data = if body["data"]["data"].is_object() { &body["data"]["data"] } else { &body["data"] };
+        Ok(data[secret].as_str().map(Secret::from))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{read_secret_file, FileSecrets, Secret, SecretProvider};
+    use std::fs;
+
+    #[test]
+    fn test_file_secrets() {
+        let dir = std::env::temp_dir().join(format!("safetrace-secrets-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("spid"), "B0335FD3BC1CCA8F804EB98A6420592D\n").unwrap();
+
+        let secrets = FileSecrets::new(dir.clone());
+        assert_eq!(secrets.get("spid").unwrap(), Some(Secret::from("B0335FD3BC1CCA8F804EB98A6420592D")));
+        assert_eq!(secrets.get("ias_key").unwrap(), None);
+        assert!(read_secret_file(&dir.join("missing")).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_secret_is_redacted() {
+        assert_eq!(format!("{:?}", Secret::from("hunter2")), "Secret(***)");
+    }
+}
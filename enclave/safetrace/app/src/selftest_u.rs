@@ -0,0 +1,82 @@
+use crate::attestation::{AttestationProvider, Quote};
+use crate::common_u::errors::SelfTestErr;
+use crate::esgx::equote;
+use crate::networking::peer::NodeAttestation;
+use failure::Error;
+use hex::ToHex;
+use sgx_types::sgx_enclave_id_t;
+
+// The boot self-test of `server.selftest`: the server doesn't bind its socket before the enclave went
+// through what a client checks on registration. A quote is produced and attested by the provider, the
+// signature over the report verified, and the quote it vouches for must be of this enclave (MRENCLAVE)
+// and bind its signing address in the report data. A platform IAS refuses, a signing key that doesn't
+// match the sealed one, or a provider whose reports can't be verified fail the start instead of every
+// registration after it.
+
+fn failed(step: &str, message: String) -> Error {
+    SelfTestErr { step: step.to_string(), message }.into()
+}
+
+// Returns the verified quote, for the startup log.
+pub fn run(eid: sgx_enclave_id_t, spid: &str, provider: &dyn AttestationProvider) -> Result<Quote, Error> {
+    let attestation = NodeAttestation::produce(eid, spid, provider).map_err(|e| failed("attestation", e.to_string()))?;
+    let quote = attestation.verify(provider).map_err(|e| failed("verification", e.to_string()))?;
+    let address = attestation.address().map_err(|e| failed("signing key", e.to_string()))?;
+    let mr_enclave = equote::get_mr_enclave(eid).map_err(|e| failed("measurement", e.to_string()))?;
+    check(&quote, &address, &mr_enclave)?;
+    Ok(quote)
+}
+
+// The quote is the one of the enclave running, and commits to its signing address.
+pub fn check(quote: &Quote, address: &[u8; 20], mr_enclave: &[u8; 32]) -> Result<(), Error> {
+    let body = &quote.report_body;
+    if body.mr_enclave != *mr_enclave {
+        return Err(failed("measurement", format!("the report is of MRENCLAVE {}, the enclave is {}",
+                                                 body.mr_enclave.to_hex(), mr_enclave.to_hex())));
+    }
+    if body.report_data[..20] != address[..] {
+        return Err(failed("signing key", format!("the report binds {}, the enclave signs with {}",
+                                                 body.report_data[..20].to_hex(), address.to_hex())));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::attestation::MockAttestationService;
+    use crate::esgx::general::init_enclave_wrapper;
+    use safetrace_client::quote::QUOTE_BODY_SIZE;
+
+    const SPID: &str = "B0335FD3BC1CCA8F804EB98A6420592D";
+
+    fn quote(mr_enclave: u8, address: &[u8; 20]) -> Quote {
+        let mut bytes = vec![0u8; QUOTE_BODY_SIZE];
+        bytes[0] = 2;
+        for byte in bytes[48 + 64..48 + 96].iter_mut() {
+            *byte = mr_enclave;
+        }
+        bytes[QUOTE_BODY_SIZE - 64..QUOTE_BODY_SIZE - 44].copy_from_slice(address);
+        Quote::from_bytes(&bytes).unwrap()
+    }
+
+    #[test]
+    fn test_selftest_checks() {
+        let quote = quote(7, &[0x5f; 20]);
+        check(&quote, &[0x5f; 20], &[7; 32]).unwrap();
+        let e = check(&quote, &[0x5f; 20], &[8; 32]).unwrap_err();
+        assert_eq!(e.downcast_ref::<SelfTestErr>().unwrap().step, "measurement");
+        let e = check(&quote, &[0x60; 20], &[7; 32]).unwrap_err();
+        assert_eq!(e.downcast_ref::<SelfTestErr>().unwrap().step, "signing key");
+    }
+
+    #[test]
+    fn test_selftest_mock_provider() {
+        let enclave = init_enclave_wrapper().unwrap();
+        let service = MockAttestationService::new().unwrap();
+        let result = run(enclave.geteid(), SPID, &service);
+        let address = equote::get_register_signing_address(enclave.geteid()).unwrap();
+        enclave.destroy();
+        assert_eq!(&result.unwrap().report_body.report_data[..20], &address[..]);
+    }
+}
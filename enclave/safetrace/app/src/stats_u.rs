@@ -0,0 +1,195 @@
+use crate::common_u::errors::EnclaveFailError;
+use crate::telemetry;
+use failure::Error;
+use sgx_types::{sgx_enclave_id_t, sgx_status_t};
+use enigma_types::{EnclaveReturn};
+
+
+extern {
+    pub fn ecall_get_stats(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, serialized_ptr: *mut u64) -> sgx_status_t;
+    pub fn ecall_get_aggregates(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, serialized_ptr: *mut u64) -> sgx_status_t;
+    pub fn ecall_export_statistics(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn,
+                                   request: *const u8, request_len: usize, serialized_ptr: *mut u64) -> sgx_status_t;
+    pub fn ecall_get_memory_usage(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, serialized_ptr: *mut u64) -> sgx_status_t;
+}
+
+// Storage figures for one epoch (UTC day), as rolled up by the enclave.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct EpochStats {
+    pub epoch: i32,
+    pub records: u64,
+    pub users: u64,
+    pub bytes: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct StorageStats {
+    pub epochs: Vec<EpochStats>,
+    pub records: u64,
+    pub users: u64,
+    pub bytes_sealed: u64,
+}
+
+impl StorageStats {
+    // Hands the rollups to whatever scrapes the `metrics` log target.
+    pub fn export(&self) {
+        info!(target: "metrics", "safetrace_records={} safetrace_users={} safetrace_bytes_sealed={}", self.records, self.users, self.bytes_sealed);
+        for epoch in &self.epochs {
+            info!(target: "metrics", "safetrace_epoch_records{{epoch=\"{}\"}}={} safetrace_epoch_users{{epoch=\"{}\"}}={} safetrace_epoch_bytes{{epoch=\"{}\"}}={}",
+                  epoch.epoch, epoch.records, epoch.epoch, epoch.users, epoch.epoch, epoch.bytes);
+        }
+    }
+}
+
+// What an enclave keeps on its heap between ecalls (chunked inputs and match jobs), in bytes, against
+// the budget it refuses more beyond, and how full the store is against the capacity limits (`capacity`).
+// See `memory` in the enclave.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MemoryUsage {
+    pub in_use: u64,
+    pub peak: u64,
+    pub budget: u64,
+    pub heap_max: u64,
+    pub chunk_contexts: u64,
+    pub match_jobs: u64,
+    pub users: u64,
+    pub records: u64,
+    pub heap_estimate: u64,
+    // In records, 0 is no limit
+    pub soft_limit: u64,
+    pub hard_limit: u64,
+    pub near_capacity: bool,
+}
+
+impl MemoryUsage {
+    pub fn export(&self, worker: usize) {
+        info!(target: "metrics", "safetrace_enclave_memory_bytes{{worker=\"{}\"}}={} safetrace_enclave_memory_peak_bytes{{worker=\"{}\"}}={} \
+                                  safetrace_enclave_memory_budget_bytes{{worker=\"{}\"}}={} safetrace_chunk_contexts{{worker=\"{}\"}}={} \
+                                  safetrace_match_jobs{{worker=\"{}\"}}={} safetrace_enclave_heap_estimate_bytes{{worker=\"{}\"}}={}",
+              worker, self.in_use, worker, self.peak, worker, self.budget, worker, self.chunk_contexts, worker, self.match_jobs,
+              worker, self.heap_estimate);
+        info!(target: "metrics", "safetrace_capacity_soft_records={} safetrace_capacity_hard_records={} safetrace_near_capacity={}",
+              self.soft_limit, self.hard_limit, self.near_capacity as u8);
+    }
+}
+
+// Users who tested positive in a geohash cell, noisy.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CellCount {
+    pub cell: String,
+    pub infected: u64,
+}
+
+// Users, and those who tested positive, with locations starting on a given epoch (UTC day), noisy.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EpochCount {
+    pub epoch: i32,
+    pub users: u64,
+    pub infected: u64,
+}
+
+// The differentially private release of `aggregates` in the enclave, safe to publish.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Aggregates {
+    pub epsilon: f64,
+    pub precision: u8,
+    pub cells: Vec<CellCount>,
+    pub epochs: Vec<EpochCount>,
+}
+
+// What a health authority asks `ExportExposureStatistics` for, signed with its key: the users, and those
+// who tested positive, per geohash cell of `precision` and day from `from` to `to` (UTC epochs). See `export`
+// in the enclave.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ExportRequest {
+    #[serde(rename = "issuedAt")]
+    pub issued_at: u64,
+    // `csv` or `json`
+    pub format: String,
+    pub precision: u8,
+    pub from: i32,
+    pub to: i32,
+    // 65 bytes hex
+    pub signature: String,
+}
+
+// The rows of the cells with at least `k` users, in `body`, signed by the enclave.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ExportBundle {
+    // Address of the health authority that asked
+    pub authority: String,
+    #[serde(rename = "issuedAt")]
+    pub issued_at: u64,
+    pub format: String,
+    pub precision: u8,
+    pub from: i32,
+    pub to: i32,
+    pub k: u64,
+    #[serde(rename = "generatedAt")]
+    pub generated_at: u64,
+    pub rows: usize,
+    pub body: String,
+    pub signature: String,
+}
+
+// Per-epoch statistics of the sealed store, kept up to date by the enclave on every write.
+pub fn get_stats(eid: sgx_enclave_id_t) -> Result<StorageStats, Error> {
+    let mut ret = EnclaveReturn::Success;
+    let mut serialized_ptr = 0u64;
+
+    let status = telemetry::ecall("ecall_get_stats", || unsafe { ecall_get_stats(eid, &mut ret as *mut EnclaveReturn, &mut serialized_ptr as *mut u64) });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    let box_ptr = serialized_ptr as *mut Box<[u8]>;
+    let part = unsafe { Box::from_raw(box_ptr) };
+    Ok(serde_json::from_slice(&part)?)
+}
+
+// The aggregate statistics for public health dashboards, fails when they're disabled.
+pub fn get_aggregates(eid: sgx_enclave_id_t) -> Result<Aggregates, Error> {
+    let mut ret = EnclaveReturn::Success;
+    let mut serialized_ptr = 0u64;
+
+    let status = telemetry::ecall("ecall_get_aggregates", || unsafe { ecall_get_aggregates(eid, &mut ret as *mut EnclaveReturn, &mut serialized_ptr as *mut u64) });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    let box_ptr = serialized_ptr as *mut Box<[u8]>;
+    let part = unsafe { Box::from_raw(box_ptr) };
+    Ok(serde_json::from_slice(&part)?)
+}
+
+pub fn get_memory_usage(eid: sgx_enclave_id_t) -> Result<MemoryUsage, Error> {
+    let mut ret = EnclaveReturn::Success;
+    let mut serialized_ptr = 0u64;
+
+    let status = telemetry::ecall("ecall_get_memory_usage", || unsafe { ecall_get_memory_usage(eid, &mut ret as *mut EnclaveReturn, &mut serialized_ptr as *mut u64) });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    let box_ptr = serialized_ptr as *mut Box<[u8]>;
+    let part = unsafe { Box::from_raw(box_ptr) };
+    Ok(serde_json::from_slice(&part)?)
+}
+
+// The k-anonymous export of a health authority, fails unless `export.enabled` and the request is signed by
+// one of `enclave.health_authorities`.
+pub fn export_statistics(eid: sgx_enclave_id_t, request: &ExportRequest) -> Result<ExportBundle, Error> {
+    let mut ret = EnclaveReturn::Success;
+    let mut serialized_ptr = 0u64;
+    let request = serde_json::to_vec(request)?;
+
+    let status = telemetry::ecall("ecall_export_statistics", || unsafe {
+        ecall_export_statistics(eid, &mut ret as *mut EnclaveReturn, request.as_ptr(), request.len(), &mut serialized_ptr as *mut u64)
+    });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    let box_ptr = serialized_ptr as *mut Box<[u8]>;
+    let part = unsafe { Box::from_raw(box_ptr) };
+    Ok(serde_json::from_slice(&part)?)
+}
@@ -0,0 +1,125 @@
+use crate::common_u::errors::EnclaveFailError;
+use crate::telemetry;
+use failure::Error;
+use sgx_types::{sgx_enclave_id_t, sgx_status_t};
+use enigma_types::{EnclaveReturn};
+
+
+extern {
+    pub fn ecall_check_store(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, repair: u8, serialized_ptr: *mut u64) -> sgx_status_t;
+}
+
+// The sealed store is a log (see `sealed_log` in the enclave): a header, then entries each sealed on its own.
+// The host can't unseal them but it can tell their layout, which `fsck` reports before asking the enclave to
+// read them.
+pub const MAGIC: &[u8; 4] = b"STLG";
+const HEADER_LEN: usize = 8;
+const ENTRY_HEADER_LEN: usize = 5;
+
+// What the host sees of the store file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layout {
+    // 0 for the single sealed blob of the enclaves before the log
+    pub format_version: u32,
+    pub entries: u64,
+    pub snapshots: u64,
+    // Bytes at the end that aren't a whole entry, a write that didn't complete
+    pub torn_bytes: u64,
+}
+
+pub fn inspect(bytes: &[u8]) -> Layout {
+    if bytes.len() < HEADER_LEN || &bytes[..MAGIC.len()] != MAGIC {
+        return Layout { format_version: 0, entries: 1, snapshots: 1, torn_bytes: 0 };
+    }
+    let u32_at = |offset: usize| {
+        let mut le = [0u8; 4];
+        le.copy_from_slice(&bytes[offset..offset + 4]);
+        u32::from_le_bytes(le)
+    };
+    let mut layout = Layout { format_version: u32_at(MAGIC.len()), entries: 0, snapshots: 0, torn_bytes: 0 };
+    let mut offset = HEADER_LEN;
+    while bytes.len() - offset >= ENTRY_HEADER_LEN {
+        let end = offset + ENTRY_HEADER_LEN + u32_at(offset) as usize;
+        if end > bytes.len() {
+            break;
+        }
+        layout.entries += 1;
+        if bytes[offset + 4] == 0 {
+            layout.snapshots += 1;
+        }
+        offset = end;
+    }
+    layout.torn_bytes = (bytes.len() - offset) as u64;
+    layout
+}
+
+// What the enclave found reading every entry, see `Report` in `sealed_log`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct StoreReport {
+    pub format_version: u32,
+    pub entries: u64,
+    pub deltas: u64,
+    pub generation: u64,
+    pub users: u64,
+    pub records: u64,
+    pub bytes: u64,
+    pub torn_bytes: u64,
+    pub compacted: bool,
+}
+
+// Has the enclave read the whole store, and with `repair` compact it into a single snapshot when it has a torn
+// entry or deltas. Fails on an entry that doesn't read, which only a backup restores.
+pub fn check_store(eid: sgx_enclave_id_t, repair: bool) -> Result<StoreReport, Error> {
+    let mut ret = EnclaveReturn::Success;
+    let mut serialized_ptr = 0u64;
+
+    let status = telemetry::ecall("ecall_check_store", || unsafe { ecall_check_store(eid, &mut ret as *mut EnclaveReturn, repair as u8, &mut serialized_ptr as *mut u64) });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    let box_ptr = serialized_ptr as *mut Box<[u8]>;
+    let part = unsafe { Box::from_raw(box_ptr) };
+    Ok(serde_json::from_slice(&part)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn entry(kind: u8, len: usize) -> Vec<u8> {
+        let mut entry = (len as u32).to_le_bytes().to_vec();
+        entry.push(kind);
+        entry.extend(vec![0xab; len]);
+        entry
+    }
+
+    #[test]
+    fn test_inspect() {
+        let mut log = MAGIC.to_vec();
+        log.extend_from_slice(&1u32.to_le_bytes());
+        assert_eq!(inspect(&log), Layout { format_version: 1, entries: 0, snapshots: 0, torn_bytes: 0 });
+
+        log.extend(entry(0, 600));
+        log.extend(entry(1, 300));
+        log.extend(entry(1, 300));
+        assert_eq!(inspect(&log), Layout { format_version: 1, entries: 3, snapshots: 1, torn_bytes: 0 });
+
+        // A delta cut short, then only part of the length of one
+        let torn = entry(1, 300);
+        log.extend_from_slice(&torn[..100]);
+        assert_eq!(inspect(&log), Layout { format_version: 1, entries: 3, snapshots: 1, torn_bytes: 100 });
+        log.truncate(log.len() - 98);
+        assert_eq!(inspect(&log).torn_bytes, 2);
+
+        // The sealed blob of before the log
+        assert_eq!(inspect(&[0u8; 4096]), Layout { format_version: 0, entries: 1, snapshots: 1, torn_bytes: 0 });
+    }
+
+    #[test]
+    fn test_store_report() {
+        let report: StoreReport = serde_json::from_str(r#"{"formatVersion":1,"entries":3,"deltas":2,"generation":41,"users":2,
+            "records":18,"bytes":1624,"tornBytes":100,"compacted":true}"#).unwrap();
+        assert_eq!((report.deltas, report.torn_bytes, report.compacted), (2, 100, true));
+    }
+}
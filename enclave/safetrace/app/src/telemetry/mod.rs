@@ -0,0 +1,466 @@
+//! Traces and metrics for the observability stack of the deployment, next to the `metrics` log target.
+//! Each IPC request is a server span named after its command, with a span per ecall it makes under it
+//! (an ecall outside of a request, e.g. a match job step, is a trace of its own). Counters of the requests
+//! and ecalls, and of the time they took, go with them. An `Exporter` sends both every `telemetry.interval`:
+//! `otlp` to an OpenTelemetry collector over OTLP/gRPC, `log` to the `telemetry` log target.
+//!
+//! Spans carry the command (`safetrace.message_type`), the tenant (`safetrace.tenant`) and the ecall
+//! (`safetrace.ecall`), never anything of the payload. `telemetry.sampling` is the share of the traces
+//! kept, decided from the trace id when the request comes in and followed by its ecalls; the counters
+//! count every request.
+
+use crate::config::TelemetryConfig;
+use failure::Error;
+use hex::ToHex;
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+pub mod otlp;
+
+// Span attributes
+pub const MESSAGE_TYPE: &str = "safetrace.message_type";
+pub const TENANT: &str = "safetrace.tenant";
+pub const ECALL: &str = "safetrace.ecall";
+const OUTCOME: &str = "safetrace.outcome";
+
+// The counters: name, unit, description
+pub const METRICS: &[(&str, &str, &str)] = &[
+    ("safetrace.ipc.requests", "1", "IPC requests handled"),
+    ("safetrace.ipc.time", "us", "Time spent handling IPC requests"),
+    ("safetrace.ecall.calls", "1", "Ecalls made"),
+    ("safetrace.ecall.time", "us", "Time spent in ecalls"),
+    ("safetrace.revocation.checks", "1", "Revocation checks of the IAS signing certificate, by source and outcome"),
+    ("safetrace.telemetry.dropped_spans", "1", "Spans dropped, past telemetry.max_spans between two exports"),
+];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AttributeValue {
+    String(String),
+    Int(i64),
+}
+
+impl<'a> From<&'a str> for AttributeValue {
+    fn from(value: &'a str) -> Self { AttributeValue::String(value.to_string()) }
+}
+
+impl From<String> for AttributeValue {
+    fn from(value: String) -> Self { AttributeValue::String(value) }
+}
+
+impl From<i64> for AttributeValue {
+    fn from(value: i64) -> Self { AttributeValue::Int(value) }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SpanKind {
+    Internal,
+    Server,
+}
+
+// A span once it ended, as the exporters get it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpanData {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub parent_span_id: Option<[u8; 8]>,
+    pub name: String,
+    pub kind: SpanKind,
+    // Nanoseconds since the epoch
+    pub start: u64,
+    pub end: u64,
+    pub attributes: Vec<(&'static str, AttributeValue)>,
+    // Why it failed, none when it didn't
+    pub error: Option<String>,
+}
+
+// The value since the start of the process of a counter, for one set of attributes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Counter {
+    pub name: &'static str,
+    pub attributes: Vec<(&'static str, String)>,
+    pub value: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Metrics {
+    // Nanoseconds since the epoch, the counters are cumulative from `start`
+    pub start: u64,
+    pub time: u64,
+    pub counters: Vec<Counter>,
+}
+
+// Where the spans and counters go.
+pub trait Exporter: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn export_spans(&self, spans: &[SpanData]) -> Result<(), Error>;
+    fn export_metrics(&self, metrics: &Metrics) -> Result<(), Error>;
+}
+
+// Writes them to the `telemetry` log target, one line each.
+pub struct LogExporter;
+
+impl Exporter for LogExporter {
+    fn name(&self) -> &'static str { "log" }
+
+    fn export_spans(&self, spans: &[SpanData]) -> Result<(), Error> {
+        for span in spans {
+            let mut line = format!("span {} trace_id={} span_id={}", span.name, span.trace_id.to_hex(), span.span_id.to_hex());
+            if let Some(parent) = span.parent_span_id {
+                line.push_str(&format!(" parent_span_id={}", parent.to_hex()));
+            }
+            line.push_str(&format!(" duration_us={}", span.end.saturating_sub(span.start) / 1000));
+            for (key, value) in &span.attributes {
+                match value {
+                    AttributeValue::String(value) => line.push_str(&format!(" {}={:?}", key, value)),
+                    AttributeValue::Int(value) => line.push_str(&format!(" {}={}", key, value)),
+                }
+            }
+            if let Some(error) = &span.error {
+                line.push_str(&format!(" error={:?}", error));
+            }
+            info!(target: "telemetry", "{}", line);
+        }
+        Ok(())
+    }
+
+    fn export_metrics(&self, metrics: &Metrics) -> Result<(), Error> {
+        for counter in &metrics.counters {
+            let labels: Vec<String> = counter.attributes.iter().map(|(key, value)| format!("{}={:?}", key, value)).collect();
+            info!(target: "telemetry", "{}{{{}}}={}", counter.name, labels.join(","), counter.value);
+        }
+        Ok(())
+    }
+}
+
+type CounterKey = (&'static str, Vec<(&'static str, String)>);
+
+struct Pipeline {
+    exporter: Box<dyn Exporter>,
+    sampling: f64,
+    max_spans: usize,
+    spans: Mutex<Vec<SpanData>>,
+    dropped: AtomicU64,
+    counters: Mutex<BTreeMap<CounterKey, u64>>,
+    started: u64,
+}
+
+lazy_static! {
+    static ref PIPELINE: RwLock<Option<Arc<Pipeline>>> = RwLock::new(None);
+}
+
+thread_local! {
+    // The span of the request the current thread runs, the parent of its ecalls
+    static CURRENT: Cell<Option<SpanContext>> = Cell::new(None);
+}
+
+fn now_nanos() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() * 1_000_000_000 + u64::from(d.subsec_nanos())).unwrap_or(0)
+}
+
+fn micros(duration: Duration) -> u64 {
+    duration.as_secs() * 1_000_000 + u64::from(duration.subsec_micros())
+}
+
+fn pipeline() -> Option<Arc<Pipeline>> {
+    PIPELINE.read().unwrap_or_else(|poisoned| poisoned.into_inner()).clone()
+}
+
+// Whether a trace is kept, from the low 8 bytes of its id as OpenTelemetry's `TraceIdRatioBased` decides.
+fn sampled(trace_id: &[u8; 16], ratio: f64) -> bool {
+    if ratio >= 1.0 {
+        return true;
+    }
+    let mut low = [0u8; 8];
+    low.copy_from_slice(&trace_id[8..]);
+    u64::from_be_bytes(low) < (ratio.max(0.0) * u64::max_value() as f64) as u64
+}
+
+impl Pipeline {
+    fn new(exporter: Box<dyn Exporter>, sampling: f64, max_spans: usize) -> Self {
+        Pipeline {
+            exporter, sampling, max_spans,
+            spans: Mutex::new(Vec::new()),
+            dropped: AtomicU64::new(0),
+            counters: Mutex::new(BTreeMap::new()),
+            started: now_nanos(),
+        }
+    }
+
+    fn push(&self, span: SpanData) {
+        let mut spans = self.spans.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if spans.len() >= self.max_spans {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        spans.push(span);
+    }
+
+    fn count(&self, name: &'static str, attributes: Vec<(&'static str, String)>, by: u64) {
+        *self.counters.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).entry((name, attributes)).or_insert(0) += by;
+    }
+
+    fn metrics(&self) -> Metrics {
+        let mut counters: Vec<Counter> = self.counters.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).iter()
+            .map(|((name, attributes), &value)| Counter { name: *name, attributes: attributes.clone(), value })
+            .collect();
+        counters.push(Counter { name: "safetrace.telemetry.dropped_spans", attributes: Vec::new(), value: self.dropped.load(Ordering::Relaxed) });
+        Metrics { start: self.started, time: now_nanos(), counters }
+    }
+
+    fn flush(&self) {
+        let spans = std::mem::replace(&mut *self.spans.lock().unwrap_or_else(|poisoned| poisoned.into_inner()), Vec::new());
+        if !spans.is_empty() {
+            if let Err(e) = self.exporter.export_spans(&spans) {
+                warn!("Exporting {} spans to {} failed: {}", spans.len(), self.exporter.name(), e);
+            }
+        }
+        if let Err(e) = self.exporter.export_metrics(&self.metrics()) {
+            warn!("Exporting the metrics to {} failed: {}", self.exporter.name(), e);
+        }
+    }
+}
+
+// Starts exporting as `[telemetry]` says, from a thread of its own. Nothing is recorded without an exporter.
+pub fn init(config: &TelemetryConfig) -> Result<(), Error> {
+    let exporter: Box<dyn Exporter> = match config.exporter.as_str() {
+        "" => return Ok(()),
+        "log" => Box::new(LogExporter),
+        "otlp" => Box::new(otlp::OtlpExporter::new(&config.endpoint, &config.service_name, Duration::from_secs(config.timeout))?),
+        other => bail!("Unknown telemetry exporter {}", other),
+    };
+    let pipeline = Arc::new(Pipeline::new(exporter, config.sampling, config.max_spans));
+    *PIPELINE.write().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(pipeline.clone());
+    let interval = Duration::from_secs(config.interval);
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        pipeline.flush();
+    });
+    info!("Exporting traces and metrics to {}, {} of the traces", config.exporter, config.sampling);
+    Ok(())
+}
+
+// What a span hands its children: its trace, its id, and whether the trace is kept.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct SpanContext {
+    pub trace_id: [u8; 16],
+    pub span_id: [u8; 8],
+    pub sampled: bool,
+}
+
+// Ends when dropped. Does nothing without an exporter, or when its trace wasn't sampled.
+pub struct Span {
+    context: Option<SpanContext>,
+    data: Option<(SpanData, Arc<Pipeline>)>,
+}
+
+impl Span {
+    fn inert() -> Self {
+        Span { context: None, data: None }
+    }
+
+    pub fn context(&self) -> Option<SpanContext> {
+        self.context
+    }
+
+    pub fn set<V: Into<AttributeValue>>(&mut self, key: &'static str, value: V) {
+        if let Some((data, _)) = &mut self.data {
+            data.attributes.push((key, value.into()));
+        }
+    }
+
+    pub fn fail(&mut self, error: &str) {
+        if let Some((data, _)) = &mut self.data {
+            data.error = Some(error.to_string());
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let Some((mut data, pipeline)) = self.data.take() {
+            data.end = now_nanos();
+            pipeline.push(data);
+        }
+    }
+}
+
+fn start_in(pipeline: Arc<Pipeline>, parent: Option<SpanContext>, name: &str, kind: SpanKind) -> Span {
+    let (trace_id, parent_span_id, sampled) = match parent {
+        Some(parent) => (parent.trace_id, Some(parent.span_id), parent.sampled),
+        None => {
+            let trace_id = rand::random::<[u8; 16]>();
+            (trace_id, None, sampled(&trace_id, pipeline.sampling))
+        },
+    };
+    let context = SpanContext { trace_id, span_id: rand::random(), sampled };
+    let data = if sampled {
+        let data = SpanData {
+            trace_id, span_id: context.span_id, parent_span_id, name: name.to_string(), kind,
+            start: now_nanos(), end: 0, attributes: Vec::new(), error: None,
+        };
+        Some((data, pipeline))
+    } else {
+        None
+    };
+    Span { context: Some(context), data }
+}
+
+// A span under the one of this thread, or the first of a trace.
+pub fn start(name: &str, kind: SpanKind) -> Span {
+    match pipeline() {
+        Some(pipeline) => start_in(pipeline, current(), name, kind),
+        None => Span::inert(),
+    }
+}
+
+// The span of the request of this thread.
+pub fn current() -> Option<SpanContext> {
+    CURRENT.with(Cell::get)
+}
+
+// Runs `task` with `context` as the parent of the spans it starts on this thread, e.g. on the thread a
+// request with a deadline runs on.
+pub fn scoped<T, F: FnOnce() -> T>(context: Option<SpanContext>, task: F) -> T {
+    let previous = CURRENT.with(|current| current.replace(context));
+    let result = task();
+    CURRENT.with(|current| current.set(previous));
+    result
+}
+
+pub fn count(name: &'static str, attributes: Vec<(&'static str, String)>, by: u64) {
+    if let Some(pipeline) = pipeline() {
+        pipeline.count(name, attributes, by);
+    }
+}
+
+// Makes the ecall `name` in a span of its own, and counts it.
+pub fn ecall<T, F: FnOnce() -> T>(name: &'static str, call: F) -> T {
+    let pipeline = match pipeline() {
+        Some(pipeline) => pipeline,
+        None => return call(),
+    };
+    let mut span = start_in(pipeline.clone(), current(), name, SpanKind::Internal);
+    span.set(ECALL, name);
+    let started = Instant::now();
+    let result = call();
+    let time = micros(started.elapsed());
+    pipeline.count("safetrace.ecall.calls", vec![(ECALL, name.to_string())], 1);
+    pipeline.count("safetrace.ecall.time", vec![(ECALL, name.to_string())], time);
+    result
+}
+
+// The span of an IPC request, ended with its outcome.
+pub struct Request {
+    span: Span,
+    attributes: Vec<(&'static str, String)>,
+    started: Instant,
+}
+
+// `tenant` only once checked, as it's an attribute of the counters.
+pub fn request(command: &'static str, tenant: Option<&str>) -> Request {
+    let mut span = start(command, SpanKind::Server);
+    let mut attributes = vec![(MESSAGE_TYPE, command.to_string())];
+    if let Some(tenant) = tenant {
+        attributes.push((TENANT, tenant.to_string()));
+    }
+    for (key, value) in &attributes {
+        span.set(*key, value.as_str());
+    }
+    Request { span, attributes, started: Instant::now() }
+}
+
+impl Request {
+    pub fn context(&self) -> Option<SpanContext> {
+        self.span.context()
+    }
+
+    pub fn end(mut self, error: Option<&str>) {
+        if let Some(error) = error {
+            self.span.fail(error);
+        }
+        let time = micros(self.started.elapsed());
+        let mut attributes = self.attributes.clone();
+        attributes.push((OUTCOME, if error.is_some() { "error" } else { "ok" }.to_string()));
+        count("safetrace.ipc.requests", attributes, 1);
+        count("safetrace.ipc.time", self.attributes.clone(), time);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    struct Collected(Mutex<Vec<SpanData>>);
+
+    impl Exporter for Arc<Collected> {
+        fn name(&self) -> &'static str { "test" }
+        fn export_spans(&self, spans: &[SpanData]) -> Result<(), Error> {
+            self.0.lock().unwrap().extend_from_slice(spans);
+            Ok(())
+        }
+        fn export_metrics(&self, _: &Metrics) -> Result<(), Error> { Ok(()) }
+    }
+
+    #[test]
+    fn test_sampling() {
+        assert!(sampled(&[0xff; 16], 1.0));
+        assert!(!sampled(&[0xff; 16], 0.5));
+        assert!(sampled(&[0x00; 16], 0.5));
+        assert!(!sampled(&[0x00; 16], 0.0));
+        let kept = (0..2000).filter(|_| sampled(&rand::random(), 0.25)).count();
+        assert!(kept > 350 && kept < 650, "{}", kept);
+    }
+
+    #[test]
+    fn test_spans() {
+        let collected = Arc::new(Collected(Mutex::new(Vec::new())));
+        let pipeline = Arc::new(Pipeline::new(Box::new(collected.clone()), 1.0, 3));
+        {
+            let mut request = start_in(pipeline.clone(), None, "FindMatch", SpanKind::Server);
+            request.set(MESSAGE_TYPE, "FindMatch");
+            let context = request.context();
+            scoped(context, || {
+                let _ecall = start_in(pipeline.clone(), current(), "ecall_find_match", SpanKind::Internal);
+            });
+            assert_eq!(current(), None);
+        }
+        pipeline.flush();
+        let spans = collected.0.lock().unwrap().clone();
+        assert_eq!(spans.len(), 2);
+        let (ecall, request) = (&spans[0], &spans[1]);
+        assert_eq!(ecall.trace_id, request.trace_id);
+        assert_eq!(ecall.parent_span_id, Some(request.span_id));
+        assert_eq!(request.parent_span_id, None);
+        assert_eq!(request.attributes, vec![(MESSAGE_TYPE, AttributeValue::String("FindMatch".to_string()))]);
+        assert!(request.end >= request.start);
+
+        // Children follow the decision of the trace, and past `max_spans` spans are dropped
+        let unsampled = SpanContext { trace_id: [1; 16], span_id: [2; 8], sampled: false };
+        drop(start_in(pipeline.clone(), Some(unsampled), "ecall_get_stats", SpanKind::Internal));
+        for _ in 0..4 {
+            drop(start_in(pipeline.clone(), None, "Ping", SpanKind::Server));
+        }
+        assert_eq!(pipeline.spans.lock().unwrap().len(), 3);
+        let dropped = pipeline.metrics().counters.into_iter().find(|counter| counter.name == "safetrace.telemetry.dropped_spans").unwrap();
+        assert_eq!(dropped.value, 1);
+    }
+
+    #[test]
+    fn test_counters() {
+        let pipeline = Pipeline::new(Box::new(LogExporter), 1.0, 10);
+        pipeline.count("safetrace.ecall.calls", vec![(ECALL, "ecall_get_stats".to_string())], 1);
+        pipeline.count("safetrace.ecall.calls", vec![(ECALL, "ecall_get_stats".to_string())], 1);
+        pipeline.count("safetrace.ecall.calls", vec![(ECALL, "ecall_find_match".to_string())], 1);
+        let metrics = pipeline.metrics();
+        let values: Vec<(String, u64)> = metrics.counters.iter()
+            .filter(|counter| counter.name == "safetrace.ecall.calls")
+            .map(|counter| (counter.attributes[0].1.clone(), counter.value))
+            .collect();
+        assert_eq!(values, vec![("ecall_find_match".to_string(), 1), ("ecall_get_stats".to_string(), 2)]);
+        assert!(metrics.time >= metrics.start);
+    }
+}
@@ -0,0 +1,322 @@
+use super::{AttributeValue, Exporter, Metrics, SpanData, SpanKind, METRICS};
+use failure::Error;
+use reqwest::Client;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+// OTLP over gRPC, the `Export` calls of the trace and metrics services of the OpenTelemetry collector
+// (opentelemetry-proto v1). The requests are written by hand, protobuf being a few wire types, and sent as
+// gRPC frames (a flag byte, the length on 4 bytes big endian, the message) over HTTP/2 without TLS: the
+// collector is expected next to the node, e.g. as a sidecar, its default receiver being `http://host:4317`.
+
+const TRACE_EXPORT: &str = "/opentelemetry.proto.collector.trace.v1.TraceService/Export";
+const METRICS_EXPORT: &str = "/opentelemetry.proto.collector.metrics.v1.MetricsService/Export";
+const SCOPE: &str = "safetrace-app";
+
+// Protobuf wire types
+const VARINT: u64 = 0;
+const FIXED64: u64 = 1;
+const LEN: u64 = 2;
+
+// An encoded message, fields in the order they are added.
+#[derive(Default)]
+struct Message(Vec<u8>);
+
+impl Message {
+    fn varint(&mut self, mut value: u64) {
+        while value >= 0x80 {
+            self.0.push(value as u8 | 0x80);
+            value >>= 7;
+        }
+        self.0.push(value as u8);
+    }
+
+    fn key(&mut self, field: u64, wire_type: u64) {
+        self.varint(field << 3 | wire_type);
+    }
+
+    fn uint(&mut self, field: u64, value: u64) -> &mut Self {
+        self.key(field, VARINT);
+        self.varint(value);
+        self
+    }
+
+    fn fixed64(&mut self, field: u64, value: u64) -> &mut Self {
+        self.key(field, FIXED64);
+        self.0.extend_from_slice(&value.to_le_bytes());
+        self
+    }
+
+    fn bytes(&mut self, field: u64, value: &[u8]) -> &mut Self {
+        self.key(field, LEN);
+        self.varint(value.len() as u64);
+        self.0.extend_from_slice(value);
+        self
+    }
+
+    fn string(&mut self, field: u64, value: &str) -> &mut Self {
+        self.bytes(field, value.as_bytes())
+    }
+
+    fn message(&mut self, field: u64, value: &Message) -> &mut Self {
+        self.bytes(field, &value.0)
+    }
+}
+
+// KeyValue { key = 1, AnyValue value = 2 }, AnyValue { string_value = 1, int_value = 3 }
+fn key_value(key: &str, value: &AttributeValue) -> Message {
+    let mut any = Message::default();
+    match value {
+        AttributeValue::String(value) => any.string(1, value),
+        AttributeValue::Int(value) => any.uint(3, *value as u64),
+    };
+    let mut kv = Message::default();
+    kv.string(1, key).message(2, &any);
+    kv
+}
+
+// Resource { repeated KeyValue attributes = 1 }
+fn resource(service_name: &str) -> Message {
+    let attributes = [
+        ("service.name", service_name),
+        ("service.version", env!("CARGO_PKG_VERSION")),
+        ("telemetry.sdk.name", SCOPE),
+        ("telemetry.sdk.language", "rust"),
+    ];
+    let mut resource = Message::default();
+    for (key, value) in attributes.iter() {
+        resource.message(1, &key_value(key, &AttributeValue::String(value.to_string())));
+    }
+    resource
+}
+
+// InstrumentationScope { name = 1, version = 2 }
+fn scope() -> Message {
+    let mut scope = Message::default();
+    scope.string(1, SCOPE).string(2, env!("CARGO_PKG_VERSION"));
+    scope
+}
+
+// Span { trace_id = 1, span_id = 2, parent_span_id = 4, name = 5, kind = 6, start_time_unix_nano = 7,
+// end_time_unix_nano = 8, repeated KeyValue attributes = 9, Status status = 15 }
+fn span(span: &SpanData) -> Message {
+    let mut encoded = Message::default();
+    encoded.bytes(1, &span.trace_id).bytes(2, &span.span_id);
+    if let Some(parent) = &span.parent_span_id {
+        encoded.bytes(4, parent);
+    }
+    // SPAN_KIND_INTERNAL = 1, SPAN_KIND_SERVER = 2
+    let kind = match span.kind {
+        SpanKind::Internal => 1,
+        SpanKind::Server => 2,
+    };
+    encoded.string(5, &span.name).uint(6, kind).fixed64(7, span.start).fixed64(8, span.end);
+    for (key, value) in &span.attributes {
+        encoded.message(9, &key_value(key, value));
+    }
+    // Status { message = 2, code = 3 }, STATUS_CODE_OK = 1, STATUS_CODE_ERROR = 2
+    let mut status = Message::default();
+    match &span.error {
+        Some(error) => status.string(2, error).uint(3, 2),
+        None => status.uint(3, 1),
+    };
+    encoded.message(15, &status);
+    encoded
+}
+
+// ExportTraceServiceRequest { repeated ResourceSpans resource_spans = 1 }, ResourceSpans { resource = 1,
+// repeated ScopeSpans scope_spans = 2 }, ScopeSpans { scope = 1, repeated Span spans = 2 }
+pub fn encode_spans(service_name: &str, spans: &[SpanData]) -> Vec<u8> {
+    let mut scope_spans = Message::default();
+    scope_spans.message(1, &scope());
+    for data in spans {
+        scope_spans.message(2, &span(data));
+    }
+    let mut resource_spans = Message::default();
+    resource_spans.message(1, &resource(service_name)).message(2, &scope_spans);
+    let mut request = Message::default();
+    request.message(1, &resource_spans);
+    request.0
+}
+
+// ExportMetricsServiceRequest { repeated ResourceMetrics resource_metrics = 1 }, ResourceMetrics { resource = 1,
+// repeated ScopeMetrics scope_metrics = 2 }, ScopeMetrics { scope = 1, repeated Metric metrics = 2 }. Each
+// counter is a cumulative monotonic Sum: Metric { name = 1, description = 2, unit = 3, Sum sum = 7 }, Sum {
+// repeated NumberDataPoint data_points = 1, aggregation_temporality = 2, is_monotonic = 3 }, NumberDataPoint {
+// start_time_unix_nano = 2, time_unix_nano = 3, as_int = 6 (sfixed64), repeated KeyValue attributes = 7 }.
+pub fn encode_metrics(service_name: &str, metrics: &Metrics) -> Vec<u8> {
+    let mut sums: BTreeMap<&str, Message> = BTreeMap::new();
+    for counter in &metrics.counters {
+        let mut point = Message::default();
+        point.fixed64(2, metrics.start).fixed64(3, metrics.time).fixed64(6, counter.value);
+        for (key, value) in &counter.attributes {
+            point.message(7, &key_value(key, &AttributeValue::String(value.clone())));
+        }
+        sums.entry(counter.name).or_insert_with(Message::default).message(1, &point);
+    }
+    let mut scope_metrics = Message::default();
+    scope_metrics.message(1, &scope());
+    for (name, mut sum) in sums {
+        // AGGREGATION_TEMPORALITY_CUMULATIVE = 2
+        sum.uint(2, 2).uint(3, 1);
+        let (unit, description) = METRICS.iter().find(|metric| metric.0 == name).map_or(("1", ""), |metric| (metric.1, metric.2));
+        let mut metric = Message::default();
+        metric.string(1, name).string(2, description).string(3, unit).message(7, &sum);
+        scope_metrics.message(2, &metric);
+    }
+    let mut resource_metrics = Message::default();
+    resource_metrics.message(1, &resource(service_name)).message(2, &scope_metrics);
+    let mut request = Message::default();
+    request.message(1, &resource_metrics);
+    request.0
+}
+
+// A gRPC frame: not compressed, then the length of the message.
+pub fn grpc_frame(message: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(5 + message.len());
+    frame.push(0);
+    frame.extend_from_slice(&(message.len() as u32).to_be_bytes());
+    frame.extend_from_slice(message);
+    frame
+}
+
+pub struct OtlpExporter {
+    endpoint: String,
+    service_name: String,
+    client: Client,
+}
+
+impl OtlpExporter {
+    pub fn new(endpoint: &str, service_name: &str, timeout: Duration) -> Result<Self, Error> {
+        let client = Client::builder().h2_prior_knowledge().timeout(timeout).build()?;
+        Ok(OtlpExporter { endpoint: endpoint.trim_end_matches('/').to_string(), service_name: service_name.to_string(), client })
+    }
+
+    fn call(&self, method: &str, message: Vec<u8>) -> Result<(), Error> {
+        let res = self.client.post(&format!("{}{}", self.endpoint, method))
+            .header("content-type", "application/grpc")
+            .header("te", "trailers")
+            .body(grpc_frame(&message))
+            .send()?;
+        if !res.status().is_success() {
+            bail!("the collector answered {}", res.status());
+        }
+        // A call refused before it was read gets its status in the headers, the others in the trailers
+        let header = |name: &str| res.headers().get(name).and_then(|v| v.to_str().ok()).map(String::from);
+        match header("grpc-status") {
+            Some(ref status) if status != "0" => bail!("the collector refused the export: {} {}", status, header("grpc-message").unwrap_or_default()),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Exporter for OtlpExporter {
+    fn name(&self) -> &'static str { "otlp" }
+
+    fn export_spans(&self, spans: &[SpanData]) -> Result<(), Error> {
+        self.call(TRACE_EXPORT, encode_spans(&self.service_name, spans))
+    }
+
+    fn export_metrics(&self, metrics: &Metrics) -> Result<(), Error> {
+        self.call(METRICS_EXPORT, encode_metrics(&self.service_name, metrics))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::telemetry::{Counter, ECALL};
+
+    // The fields of a message, (number, wire type, value or payload)
+    fn fields(mut bytes: &[u8]) -> Vec<(u64, u64, Vec<u8>)> {
+        fn varint(bytes: &mut &[u8]) -> u64 {
+            let mut value = 0;
+            for shift in (0..64).step_by(7) {
+                let byte = bytes[0];
+                *bytes = &bytes[1..];
+                value |= u64::from(byte & 0x7f) << shift;
+                if byte < 0x80 {
+                    break;
+                }
+            }
+            value
+        }
+        let mut fields = Vec::new();
+        while !bytes.is_empty() {
+            let key = varint(&mut bytes);
+            let (field, wire_type) = (key >> 3, key & 7);
+            let value = match wire_type {
+                VARINT => varint(&mut bytes).to_le_bytes().to_vec(),
+                FIXED64 => {
+                    let (value, rest) = bytes.split_at(8);
+                    bytes = rest;
+                    value.to_vec()
+                },
+                _ => {
+                    let len = varint(&mut bytes) as usize;
+                    let (value, rest) = bytes.split_at(len);
+                    bytes = rest;
+                    value.to_vec()
+                },
+            };
+            fields.push((field, wire_type, value));
+        }
+        fields
+    }
+
+    fn field(bytes: &[u8], number: u64) -> Vec<Vec<u8>> {
+        fields(bytes).into_iter().filter(|f| f.0 == number).map(|f| f.2).collect()
+    }
+
+    #[test]
+    fn test_encode_spans() {
+        let span = SpanData {
+            trace_id: [0xab; 16], span_id: [0xcd; 8], parent_span_id: Some([0xef; 8]), name: "ecall_find_match".to_string(),
+            kind: SpanKind::Internal, start: 1_589_000_000_000_000_000, end: 1_589_000_000_250_000_000,
+            attributes: vec![(ECALL, AttributeValue::String("ecall_find_match".to_string()))], error: Some("no such user".to_string()),
+        };
+        let request = encode_spans("safetrace", &[span]);
+        let resource_spans = &field(&request, 1)[0];
+        let resource = &field(resource_spans, 1)[0];
+        let service = &field(resource, 1)[0];
+        assert_eq!(field(service, 1)[0], b"service.name");
+        assert_eq!(field(&field(service, 2)[0], 1)[0], b"safetrace");
+
+        let scope_spans = &field(resource_spans, 2)[0];
+        assert_eq!(field(&field(scope_spans, 1)[0], 1)[0], SCOPE.as_bytes());
+        let encoded = &field(scope_spans, 2)[0];
+        assert_eq!(field(encoded, 1)[0], vec![0xab; 16]);
+        assert_eq!(field(encoded, 4)[0], vec![0xef; 8]);
+        assert_eq!(field(encoded, 5)[0], b"ecall_find_match");
+        assert_eq!(field(encoded, 6)[0], 1u64.to_le_bytes());
+        assert_eq!(field(encoded, 8)[0], 1_589_000_000_250_000_000u64.to_le_bytes());
+        let attribute = &field(encoded, 9)[0];
+        assert_eq!(field(attribute, 1)[0], ECALL.as_bytes());
+        let status = &field(encoded, 15)[0];
+        assert_eq!(field(status, 2)[0], b"no such user");
+        assert_eq!(field(status, 3)[0], 2u64.to_le_bytes());
+    }
+
+    #[test]
+    fn test_encode_metrics() {
+        let counter = |ecall: &str, value| Counter { name: "safetrace.ecall.calls", attributes: vec![(ECALL, ecall.to_string())], value };
+        let metrics = Metrics { start: 1, time: 2, counters: vec![counter("ecall_get_stats", 3), counter("ecall_find_match", 5)] };
+        let request = encode_metrics("safetrace", &metrics);
+        let scope_metrics = &field(&field(&request, 1)[0], 2)[0];
+        let encoded = field(scope_metrics, 2);
+        assert_eq!(encoded.len(), 1);
+        assert_eq!(field(&encoded[0], 1)[0], b"safetrace.ecall.calls");
+        assert_eq!(field(&encoded[0], 3)[0], b"1");
+        let sum = &field(&encoded[0], 7)[0];
+        let points = field(sum, 1);
+        assert_eq!(field(&points[1], 6)[0], 5u64.to_le_bytes());
+        assert_eq!(field(sum, 2)[0], 2u64.to_le_bytes());
+        assert_eq!(field(sum, 3)[0], 1u64.to_le_bytes());
+    }
+
+    #[test]
+    fn test_grpc_frame() {
+        assert_eq!(grpc_frame(&[0x0a, 0x00]), vec![0, 0, 0, 0, 2, 0x0a, 0x00]);
+        assert_eq!(grpc_frame(&vec![0; 300])[..5], [0, 0, 0, 1, 44]);
+    }
+}
@@ -0,0 +1,108 @@
+use crate::common_u::errors::EnclaveFailError;
+use crate::config::TenantConfig;
+use crate::telemetry;
+use failure::Error;
+use serde_json::json;
+use sgx_types::{sgx_enclave_id_t, sgx_status_t};
+use enigma_types::{EnclaveReturn};
+use std::cell::RefCell;
+use std::slice;
+
+
+extern {
+    pub fn ecall_set_tenants(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn,
+                             tenants: *const u8, tenants_len: usize, count: *mut u64) -> sgx_status_t;
+}
+
+// Tenants: the regions or health authorities one deployment serves (`[[tenants]]`). A request names its
+// tenant with the `tenant` member of its envelope, and runs with it set on its thread: the ecalls it makes
+// read it through `ocall_current_tenant` (see `tenants` in the enclave), which stores, matches, registers
+// and hands out task keys within the partition of the tenant. Requests without one are in the default
+// partition, the one of the deployments before tenants.
+
+thread_local! {
+    // The tenant of the request the current thread runs, read by the ocall
+    static CURRENT: RefCell<Option<String>> = RefCell::new(None);
+}
+
+// Runs `task` with `tenant` as the tenant of the ecalls it makes on this thread.
+pub fn scoped<T, F: FnOnce() -> T>(tenant: Option<&str>, task: F) -> T {
+    let previous = CURRENT.with(|current| current.replace(tenant.map(String::from)));
+    let result = task();
+    CURRENT.with(|current| *current.borrow_mut() = previous);
+    result
+}
+
+// The tenant of the request of this thread, e.g. for the requests it sends on to the peers.
+pub fn current() -> Option<String> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+// Copies the tenant of this thread into `tenant` and returns its length, 0 for none. A tenant longer than
+// the buffer isn't copied, the enclave refuses the length.
+#[no_mangle]
+pub extern "C" fn ocall_current_tenant(tenant: *mut u8, tenant_len: usize) -> usize {
+    CURRENT.with(|current| match &*current.borrow() {
+        Some(id) => {
+            if id.len() <= tenant_len {
+                unsafe { slice::from_raw_parts_mut(tenant, id.len()) }.copy_from_slice(id.as_bytes());
+            }
+            id.len()
+        },
+        None => 0,
+    })
+}
+
+// The tenants and their retention, as the enclave takes them.
+pub fn encode(tenants: &[TenantConfig]) -> Result<Vec<u8>, Error> {
+    let policies: Vec<_> = tenants.iter().map(|tenant| json!({"id": tenant.id, "retention": tenant.retention_seconds()})).collect();
+    Ok(serde_json::to_vec(&policies)?)
+}
+
+// Replaces the tenants of an enclave, returns how many it now knows. Every worker must be given the list.
+pub fn set_tenants(eid: sgx_enclave_id_t, tenants: &[TenantConfig]) -> Result<u64, Error> {
+    let encoded = encode(tenants)?;
+    let mut ret = EnclaveReturn::Success;
+    let mut count = 0u64;
+    let status = telemetry::ecall("ecall_set_tenants", || unsafe {
+        ecall_set_tenants(eid, &mut ret as *mut EnclaveReturn, encoded.as_ptr(), encoded.len(), &mut count as *mut u64)
+    });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_scoped_tenant() {
+        let mut buffer = [0u8; 32];
+        assert_eq!(ocall_current_tenant(buffer.as_mut_ptr(), buffer.len()), 0);
+        let len = scoped(Some("ch-ge"), || {
+            assert_eq!(current(), Some("ch-ge".to_string()));
+            // A nested scope gives the outer one back
+            scoped(None, || assert_eq!(current(), None));
+            assert_eq!(current(), Some("ch-ge".to_string()));
+            ocall_current_tenant(buffer.as_mut_ptr(), buffer.len())
+        });
+        assert_eq!(&buffer[..len], b"ch-ge");
+        assert_eq!(current(), None);
+
+        // Too long for the enclave: its length, nothing copied
+        let mut short = [0u8; 2];
+        assert_eq!(scoped(Some("ch-ge"), || ocall_current_tenant(short.as_mut_ptr(), short.len())), 5);
+        assert_eq!(short, [0u8; 2]);
+    }
+
+    #[test]
+    fn test_tenant_format() {
+        let tenants = vec![
+            TenantConfig { id: "ch-ge".to_string(), retention_days: Some(14), operators: Vec::new() },
+            TenantConfig { id: "ch-vd".to_string(), retention_days: None, operators: Vec::new() },
+        ];
+        assert_eq!(encode(&tenants).unwrap(), br#"[{"id":"ch-ge","retention":1209600},{"id":"ch-vd","retention":null}]"#.to_vec());
+    }
+}
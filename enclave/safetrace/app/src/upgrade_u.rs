@@ -0,0 +1,39 @@
+use crate::common_u::errors::EnclaveFailError;
+use crate::esgx::local::{self, LocalEnclave};
+use crate::telemetry;
+use enigma_types::EnclaveReturn;
+use failure::Error;
+use sgx_types::*;
+
+// Size of the encrypted state, see `upgrade` in the enclave
+pub const TRANSFER_SIZE: usize = 101;
+
+extern {
+    fn ecall_upgrade_export(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, session: u64,
+                            transfer: &mut [u8; TRANSFER_SIZE]) -> sgx_status_t;
+    fn ecall_upgrade_import(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn, session: u64, transfer: &[u8; TRANSFER_SIZE],
+                            address: &mut [u8; 20]) -> sgx_status_t;
+}
+
+fn check(ret: EnclaveReturn, status: sgx_status_t) -> Result<(), Error> {
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok(())
+}
+
+// Hands the signing key and the audit head of the running enclave `from` over to `to`, a new version
+// of it that didn't read its signing key yet. The enclaves attest each other locally (`esgx::local`), the
+// app only carries the messages: it never sees the session key nor the state. Returns the signing address
+// `to` took over.
+pub fn migrate(from: sgx_enclave_id_t, to: sgx_enclave_id_t) -> Result<[u8; 20], Error> {
+    let session = local::establish(LocalEnclave::safetrace(from), LocalEnclave::safetrace(to))?;
+    let mut ret = EnclaveReturn::Success;
+    let mut transfer = [0u8; TRANSFER_SIZE];
+    let mut address = [0u8; 20];
+    let status = telemetry::ecall("ecall_upgrade_export", || unsafe { ecall_upgrade_export(from, &mut ret, session.responder.1, &mut transfer) });
+    check(ret, status)?;
+    let status = telemetry::ecall("ecall_upgrade_import", || unsafe { ecall_upgrade_import(to, &mut ret, session.initiator.1, &transfer, &mut address) });
+    check(ret, status)?;
+    Ok(address)
+}
@@ -0,0 +1,26 @@
+use crate::common_u::errors::EnclaveFailError;
+use crate::telemetry;
+use failure::Error;
+use sgx_types::{sgx_enclave_id_t, sgx_status_t};
+use enigma_types::{EnclaveReturn};
+
+
+extern {
+    pub fn ecall_register_user(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn,
+                               encrypted_userid: *const u8, encrypted_userid_len: usize,
+                               encrypted_data: *const u8, encrypted_data_len: usize,
+                               user_pubkey: &[u8; 64]) -> sgx_status_t;
+}
+
+// Registers the signing key in `encrypted_data` for the user, see `users` in the enclave.
+pub fn register_user(eid: sgx_enclave_id_t, encrypted_userid: &[u8], encrypted_data: &[u8], user_pubkey: &[u8; 64]) -> Result<(), Error> {
+    let mut ret = EnclaveReturn::Success;
+    let status = telemetry::ecall("ecall_register_user", || unsafe {
+        ecall_register_user(eid, &mut ret as *mut EnclaveReturn, encrypted_userid.as_ptr(), encrypted_userid.len(),
+                            encrypted_data.as_ptr(), encrypted_data.len(), user_pubkey)
+    });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok(())
+}
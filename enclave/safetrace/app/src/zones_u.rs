@@ -0,0 +1,68 @@
+use crate::common_u::errors::EnclaveFailError;
+use crate::telemetry;
+use failure::Error;
+use hex::ToHex;
+use sgx_types::{sgx_enclave_id_t, sgx_status_t};
+use sha2::{Digest, Sha256};
+use enigma_types::{EnclaveReturn};
+
+
+extern {
+    pub fn ecall_set_exclusion_zones(eid: sgx_enclave_id_t, retval: *mut EnclaveReturn,
+                                     zones: *const u8, zones_len: usize, count: *mut u64) -> sgx_status_t;
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum ZoneAction {
+    // The locations inside the zone aren't stored
+    Drop,
+    // They're moved to the centre of the zone
+    Mask,
+}
+
+// A geofenced place such as a hospital or a shelter, `polygon` being its [lat, lng] vertices.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Zone {
+    pub name: String,
+    pub polygon: Vec<[f64; 2]>,
+    pub action: ZoneAction,
+}
+
+// The JSON the enclave seals, and its SHA-256 (hex) for the audit log.
+pub fn encode(zones: &[Zone]) -> Result<(Vec<u8>, String), Error> {
+    let encoded = serde_json::to_vec(zones)?;
+    let digest: String = Sha256::digest(&encoded).to_hex();
+    Ok((encoded, digest))
+}
+
+// Replaces the exclusion zones of an enclave, returns how many it now applies. The enclave seals them,
+// but each worker keeps its own copy in memory: every one of them must be given the list.
+pub fn set_exclusion_zones(eid: sgx_enclave_id_t, encoded: &[u8]) -> Result<u64, Error> {
+    let mut ret = EnclaveReturn::Success;
+    let mut count = 0u64;
+    let status = telemetry::ecall("ecall_set_exclusion_zones", || unsafe {
+        ecall_set_exclusion_zones(eid, &mut ret as *mut EnclaveReturn, encoded.as_ptr(), encoded.len(), &mut count as *mut u64)
+    });
+    if ret != EnclaveReturn::Success || status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveFailError { err: ret, status }.into());
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_zone_format() {
+        let zones: Vec<Zone> = serde_json::from_str(
+            r#"[{"name": "St Mary", "polygon": [[51.517, -0.174], [51.518, -0.171], [51.516, -0.17]], "action": "Mask"}]"#).unwrap();
+        assert_eq!(zones[0].action, ZoneAction::Mask);
+        assert_eq!(zones[0].polygon[1], [51.518, -0.171]);
+
+        let (encoded, digest) = encode(&zones).unwrap();
+        assert_eq!(encoded, br#"[{"name":"St Mary","polygon":[[51.517,-0.174],[51.518,-0.171],[51.516,-0.17]],"action":"Mask"}]"#.to_vec());
+        assert_eq!(digest.len(), 64);
+        assert_eq!(encode(&[]).unwrap().1, "4f53cda18c2baa0c0354bb5f9a3ecbe5ed12ab4d8e11ba873c2f11161202b945");
+    }
+}
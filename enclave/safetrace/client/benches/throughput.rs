@@ -0,0 +1,115 @@
+// Throughput of the enclave data path, through a running app:
+//   SAFETRACE_BENCH_SERVER=tcp://localhost:5552 cargo bench
+// Without a server only the client side (trace generation, payload encryption) is measured. Run the app
+// built with `sgx-sim` (or trust its report some other way), on a store nobody else uses: the benchmark
+// submits its synthetic users to it. SAFETRACE_BENCH_USERS lists the store sizes matching is measured at.
+// The sealed store holds 4 kB (`SEAL_LOG_SIZE` in the enclave), a couple of users: benchmark an enclave
+// built with a larger one.
+
+mod traces;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use enigma_crypto::{asymmetric::KeyPair, symmetric};
+use safetrace_client::messages::{self, Location};
+use safetrace_client::{Client, ReportPolicy, Transport, Trust, ZmqTransport};
+use serde_json::json;
+use std::env;
+use traces::TraceGenerator;
+
+// Fixes per user, a day at one an hour
+const POINTS: usize = 24;
+
+fn server() -> Option<String> {
+    env::var("SAFETRACE_BENCH_SERVER").ok().filter(|uri| !uri.is_empty())
+}
+
+fn store_sizes() -> Vec<usize> {
+    env::var("SAFETRACE_BENCH_USERS").unwrap_or_else(|_| "10,100,1000".to_string())
+        .split(',')
+        .map(|size| size.trim().parse().expect("SAFETRACE_BENCH_USERS is a list of numbers"))
+        .collect()
+}
+
+fn client(uri: &str) -> Client<ZmqTransport> {
+    let policy = ReportPolicy::new(Trust::Simulation).allow_debug();
+    Client::new(ZmqTransport::new(uri), policy, KeyPair::new().unwrap())
+}
+
+fn client_side(c: &mut Criterion) {
+    let mut generator = TraceGenerator::new(1);
+    c.bench_function("generate a day of traces", |b| b.iter(|| generator.day(POINTS)));
+
+    // What a client does to every submission, the enclave undoes it
+    let trace = generator.day(POINTS);
+    let key = [7u8; 32];
+    let mut group = c.benchmark_group("payload");
+    group.throughput(Throughput::Elements(trace.len() as u64));
+    group.bench_function("encode and encrypt", |b| b.iter(|| {
+        let payload = serde_json::to_vec(&messages::personal_data(&trace, messages::now(), None)).unwrap();
+        symmetric::encrypt(&payload, &key).unwrap()
+    }));
+    group.finish();
+}
+
+// A round trip that doesn't enter the enclave against one that does (`GetProtocolVersion` reads MRENCLAVE)
+fn ecall_overhead(c: &mut Criterion) {
+    let uri = match server() {
+        Some(uri) => uri,
+        None => return,
+    };
+    let transport = ZmqTransport::new(&uri);
+    let mut group = c.benchmark_group("round trip");
+    group.bench_function("without an ecall", |b| b.iter(|| {
+        transport.call(json!({"id": messages::new_id(), "type": "Ping", "nonce": "5eed"})).unwrap()
+    }));
+    group.bench_function("with an ecall", |b| b.iter(|| {
+        transport.call(messages::get_protocol_version(&messages::new_id())).unwrap()
+    }));
+    group.finish();
+}
+
+// Each submission is a key exchange and an `AddPersonalData`, so are real ones
+fn ingest(c: &mut Criterion) {
+    let uri = match server() {
+        Some(uri) => uri,
+        None => return,
+    };
+    let client = client(&uri);
+    let mut generator = TraceGenerator::new(2);
+    let mut group = c.benchmark_group("ingest");
+    group.throughput(Throughput::Elements(POINTS as u64));
+    group.sample_size(10);
+    let mut user = 0;
+    group.bench_function("addPersonalData", |b| b.iter(|| {
+        user += 1;
+        let receipt = client.add_personal_data(&format!("bench-ingest-{}", user), &generator.day(POINTS)).unwrap();
+        assert_eq!(receipt.status, "Passed", "the store refused the trace: {:?}", receipt.error);
+    }));
+    group.finish();
+}
+
+fn match_latency(c: &mut Criterion) {
+    let uri = match server() {
+        Some(uri) => uri,
+        None => return,
+    };
+    let client = client(&uri);
+    let mut generator = TraceGenerator::new(3);
+    let mut group = c.benchmark_group("findMatch");
+    group.sample_size(10);
+    let mut stored = 0;
+    for size in store_sizes() {
+        // The store grows from one size to the next
+        while stored < size {
+            let trace: Vec<Location> = generator.day(POINTS);
+            let receipt = client.add_personal_data(&format!("bench-match-{}", stored), &trace).unwrap();
+            assert_eq!(receipt.status, "Passed", "the store refused user {}: {:?}", stored, receipt.error);
+            stored += 1;
+        }
+        group.bench_with_input(BenchmarkId::new("users", size), &size, |b, _| b.iter(|| client.find_match("bench-match-0").unwrap()));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, client_side, ecall_overhead, ingest, match_latency);
+criterion_main!(benches);
@@ -0,0 +1,82 @@
+// Synthetic GPS traces for the benchmarks: people going about their day in a city, sampled the way a phone
+// would, rather than uniform random points that never cross. Each person has a home and a workplace, and
+// spends the day between them and a few errands; they move in straight lines at walking or driving speed,
+// and every fix carries some GPS noise. Seeded, so every run submits the same traces.
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use safetrace_client::Location;
+
+// Manhattan, roughly
+const CITY: ((f64, f64), (f64, f64)) = ((40.70, -74.02), (40.80, -73.93));
+// About 10 m of noise, in degrees
+const GPS_NOISE: f64 = 0.0001;
+// Seconds from one place to the next
+const TRAVEL: i32 = 1200;
+// 1 March 2020, 00:00 UTC
+const DAY_START: i32 = 1_583_020_800;
+
+pub struct TraceGenerator {
+    rng: StdRng,
+    // Seconds between two fixes
+    pub interval: i32,
+    // Share of the people who tested positive
+    pub infected: f64,
+    // Places several people go to, so traces cross
+    hotspots: Vec<(f64, f64)>,
+}
+
+impl TraceGenerator {
+    pub fn new(seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let hotspots = (0..8).map(|_| random_place(&mut rng)).collect();
+        TraceGenerator { rng, interval: 3600, infected: 0.2, hotspots }
+    }
+
+    // One day of one person, `points` fixes at most, each one standing for `interval` seconds.
+    pub fn day(&mut self, points: usize) -> Vec<Location> {
+        let home = random_place(&mut self.rng);
+        let work = self.hotspot();
+        let errand = self.hotspot();
+        let infected = self.rng.gen_bool(self.infected);
+        // (place, hour they leave it)
+        let schedule = [(home, 8.0), (work, 12.0), (errand, 13.0), (work, 17.5), (errand, 19.0), (home, 24.0)];
+
+        let mut trace = Vec::with_capacity(points);
+        let mut t = DAY_START + self.rng.gen_range(0, self.interval);
+        let (mut from, mut departed) = (home, t);
+        for &(place, leave) in &schedule {
+            let leave_at = DAY_START + (leave * 3600.0) as i32;
+            while t < leave_at && trace.len() < points {
+                // On the way for the first `TRAVEL` seconds, then there
+                let travelled = f64::from(t - departed) / f64::from(TRAVEL);
+                let (lat, lng) = if travelled < 1.0 { lerp(from, place, travelled) } else { place };
+                trace.push(Location {
+                    lat: lat + self.rng.gen_range(-GPS_NOISE, GPS_NOISE),
+                    lng: lng + self.rng.gen_range(-GPS_NOISE, GPS_NOISE),
+                    startTS: t,
+                    endTS: t + self.interval,
+                    testResult: infected,
+                });
+                t += self.interval;
+            }
+            from = place;
+            departed = leave_at;
+        }
+        trace
+    }
+
+    fn hotspot(&mut self) -> (f64, f64) {
+        let i = self.rng.gen_range(0, self.hotspots.len());
+        self.hotspots[i]
+    }
+}
+
+fn random_place(rng: &mut StdRng) -> (f64, f64) {
+    let ((south, west), (north, east)) = CITY;
+    (rng.gen_range(south, north), rng.gen_range(west, east))
+}
+
+fn lerp(a: (f64, f64), b: (f64, f64), t: f64) -> (f64, f64) {
+    (a.0 + (b.0 - a.0) * t, a.1 + (b.1 - a.1) * t)
+}
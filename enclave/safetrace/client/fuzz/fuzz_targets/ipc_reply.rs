@@ -0,0 +1,18 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use safetrace_client::messages::{parse_response, EnclaveReport, EnclaveResult, TaskKey};
+use serde_json::Value;
+
+// A reply of the server, as the client reads it
+fuzz_target!(|data: &[u8]| {
+    let response: Value = match serde_json::from_slice(data) {
+        Ok(response) => response,
+        Err(_) => return,
+    };
+    if let Ok(report) = parse_response::<EnclaveReport>("GetEnclaveReport", &response) {
+        let _ = report.to_bundle();
+    }
+    let _ = parse_response::<TaskKey>("NewTaskEncryptionKey", &response);
+    let _ = parse_response::<EnclaveResult>("FindMatch", &response);
+    let _ = parse_response::<EnclaveResult>("AddPersonalData", &response);
+});
@@ -0,0 +1,11 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use safetrace_client::quote::{QBody, QEcdsaHeader, QReportBody};
+
+fuzz_target!(|data: &[u8]| {
+    let _ = QBody::from_bytes(data);
+    let _ = QEcdsaHeader::from_bytes(data);
+    if let Ok(report_body) = QReportBody::from_bytes(data) {
+        let _ = (report_body.mr_enclave_hex(), report_body.attributes().flag_names());
+    }
+});
@@ -0,0 +1,10 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use safetrace_client::quote::Quote;
+
+// `isvEnclaveQuoteBody` of an IAS report, as it comes in the JSON
+fuzz_target!(|data: &[u8]| {
+    if let Ok(encoded) = std::str::from_utf8(data) {
+        let _ = Quote::from_base64(encoded);
+    }
+});
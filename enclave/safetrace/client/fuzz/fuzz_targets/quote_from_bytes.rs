@@ -0,0 +1,11 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use safetrace_client::quote::Quote;
+
+// A quote from a peer's attestation or an `isvEnclaveQuoteBody`, decoded
+fuzz_target!(|data: &[u8]| {
+    if let Ok(quote) = Quote::from_bytes(data) {
+        assert_eq!(quote.to_bytes(), data);
+        let _ = (quote.to_json(), quote.to_string());
+    }
+});
@@ -0,0 +1,62 @@
+#ifndef SAFETRACE_H
+#define SAFETRACE_H
+
+/* Generated by cbindgen from src/ffi.rs, don't edit by hand */
+
+#include <stddef.h>
+#include <stdint.h>
+
+#define SAFETRACE_ERROR -2
+
+typedef struct SafetraceEnclave SafetraceEnclave;
+
+typedef struct SafetraceKeyPair SafetraceKeyPair;
+
+typedef struct SafetraceSession SafetraceSession;
+
+typedef struct {
+  uint8_t *data;
+  size_t len;
+} SafetraceBuffer;
+
+char *safetrace_last_error(void);
+
+void safetrace_string_free(char *s);
+
+void safetrace_buffer_free(SafetraceBuffer buffer);
+
+SafetraceKeyPair *safetrace_keypair_new(void);
+
+SafetraceKeyPair *safetrace_keypair_from_secret(const uint8_t *secret, size_t len);
+
+char *safetrace_keypair_pubkey(const SafetraceKeyPair *keypair);
+
+void safetrace_keypair_free(SafetraceKeyPair *keypair);
+
+SafetraceEnclave *safetrace_verify_report(const char *response, const char *root_ca, const uint8_t *mr_enclave);
+
+char *safetrace_enclave_mr_enclave(const SafetraceEnclave *enclave);
+
+void safetrace_enclave_free(SafetraceEnclave *enclave);
+
+char *safetrace_task_key_request(const SafetraceKeyPair *keypair);
+
+SafetraceSession *safetrace_session_new(const SafetraceKeyPair *keypair, const SafetraceEnclave *enclave, const char *response);
+
+void safetrace_session_free(SafetraceSession *session);
+
+char *safetrace_add_personal_data_request(const SafetraceSession *session, const SafetraceKeyPair *keypair, const char *user_id, const char *locations);
+
+char *safetrace_add_declared_personal_data_request(const SafetraceSession *session, const SafetraceKeyPair *keypair, const char *user_id, const char *locations, const char *declaration);
+
+char *safetrace_find_match_request(const SafetraceSession *session, const SafetraceKeyPair *keypair, const char *user_id);
+
+char *safetrace_register_user_request(const SafetraceSession *session, const SafetraceKeyPair *keypair, const SafetraceKeyPair *signing_keypair, const char *user_id);
+
+char *safetrace_sign_request(const SafetraceSession *session, const SafetraceKeyPair *signing_keypair, const char *request);
+
+char *safetrace_request_to_jsonrpc(const char *request);
+
+int32_t safetrace_session_open_response(const SafetraceSession *session, const char *request_type, const char *response, SafetraceBuffer *output);
+
+#endif /* SAFETRACE_H */
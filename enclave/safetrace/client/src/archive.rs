@@ -0,0 +1,254 @@
+use crate::bundle::{der_to_pem, pem_to_der, ReportBundle};
+use crate::errors::ReportErr;
+use crate::quote::{Quote, QUOTE_BODY_SIZE};
+use crate::verify::IasReport;
+use crate::x509;
+use failure::Error;
+use hex::FromHex;
+#[cfg(not(target_arch = "wasm32"))]
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+// Attestation archives: the reports a server got over time, each with everything needed to check it again
+// years later, without the server nor IAS. For the operators keeping a record of their attestations, and for
+// the third parties auditing them.
+//
+// A record is the quote as the enclave produced it, the report exactly as IAS signed it, the signature and the
+// certificate chain (DER). The timestamp, the quote status, the advisories and the platform info blob (the TCB
+// guidance of the platform, hex) are copied out of the report so an archive can be searched without parsing
+// every report; `verify_record` checks they are the report's. An archive is JSON, or CBOR for the smaller file.
+//
+// The chain is checked at the time IAS signed the report, not now: the signing certificate of an old report
+// may have expired since, the report was still signed while it was valid.
+
+const ARCHIVE_VERSION: u8 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct AttestationRecord {
+    // Base64
+    pub quote: String,
+    pub report: String,
+    pub signature: String,
+    // The signing certificate first, then the CAs
+    pub chain: Vec<ByteBuf>,
+    pub timestamp: String,
+    pub quote_status: String,
+    #[serde(default)]
+    pub advisory_ids: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform_info_blob: Option<String>,
+    // The enclave signing address (hex) when the server knew it, the quote must commit to it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub signing_key: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AttestationArchive {
+    pub version: u8,
+    pub records: Vec<AttestationRecord>,
+}
+
+// A record whose report, signature, chain and quote checked out. `verified_at` is when the chain was valid:
+// when IAS signed the report, Unix seconds.
+#[derive(Debug, Clone)]
+pub struct VerifiedRecord {
+    pub report: IasReport,
+    pub quote: Quote,
+    pub verified_at: u64,
+}
+
+fn archive_err(message: &str) -> Error {
+    ReportErr { message: format!("attestation archive: {}", message) }.into()
+}
+
+impl AttestationRecord {
+    // `quote` is the one `bundle` is the report of.
+    pub fn new(quote: &str, bundle: &ReportBundle, signing_key: Option<String>) -> Result<Self, Error> {
+        let report: IasReport = serde_json::from_str(&bundle.report).map_err(|e| archive_err(&format!("malformed report: {}", e)))?;
+        let mut chain = pem_to_der(&bundle.certificate)?;
+        chain.extend(pem_to_der(&bundle.ca)?);
+        Ok(AttestationRecord {
+            quote: quote.to_string(),
+            report: bundle.report.clone(),
+            signature: bundle.signature.clone(),
+            chain: chain.into_iter().map(ByteBuf::from).collect(),
+            timestamp: report.timestamp,
+            quote_status: report.quote_status,
+            advisory_ids: report.advisory_ids,
+            platform_info_blob: report.platform_info_blob,
+            signing_key,
+        })
+    }
+
+    // The report bundle of the record, certificates as PEM.
+    pub fn bundle(&self) -> ReportBundle {
+        let mut chain = self.chain.iter().map(|der| der_to_pem(der));
+        ReportBundle {
+            report: self.report.clone(),
+            signature: self.signature.clone(),
+            certificate: chain.next().unwrap_or_default(),
+            ca: chain.collect(),
+        }
+    }
+}
+
+impl Default for AttestationArchive {
+    fn default() -> Self {
+        AttestationArchive { version: ARCHIVE_VERSION, records: Vec::new() }
+    }
+}
+
+impl AttestationArchive {
+    pub fn to_json(&self) -> Result<String, Error> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+
+    pub fn to_cbor(&self) -> Result<Vec<u8>, Error> {
+        Ok(serde_cbor::to_vec(self)?)
+    }
+
+    // JSON or CBOR, a JSON archive starts with `{`: a CBOR map of two entries starts with 0xa2.
+    pub fn decode(bytes: &[u8]) -> Result<Self, Error> {
+        let archive: AttestationArchive = match bytes.iter().find(|b| !b.is_ascii_whitespace()) {
+            Some(b'{') => serde_json::from_slice(bytes)?,
+            Some(_) => serde_cbor::from_slice(bytes)?,
+            None => return Err(archive_err("empty archive")),
+        };
+        if archive.version != ARCHIVE_VERSION {
+            return Err(archive_err(&format!("unsupported version {}", archive.version)));
+        }
+        Ok(archive)
+    }
+}
+
+// Checks one record against the root `root_ca` (PEM), usually `INTEL_ROOT_CA`. What the quote must say is up
+// to the caller, as with `verify::verify_report`.
+pub fn verify_record(record: &AttestationRecord, root_ca: &str) -> Result<VerifiedRecord, Error> {
+    let report: IasReport = serde_json::from_str(&record.report).map_err(|e| archive_err(&format!("malformed report: {}", e)))?;
+    let verified_at = x509::parse_timestamp(&report.timestamp)?;
+    let root = pem_to_der(root_ca)?;
+    let root = root.first().ok_or_else(|| archive_err("missing root CA"))?;
+    let chain: Vec<Vec<u8>> = record.chain.iter().map(|der| der.to_vec()).collect();
+    let signing = chain.first().ok_or_else(|| archive_err("missing signing certificate"))?;
+    x509::verify_chain(root, &chain, verified_at)?;
+    let signature = base64::decode(record.signature.trim()).map_err(|_| archive_err("the signature isn't base64"))?;
+    x509::verify_signature(signing, record.report.as_bytes(), &signature)?;
+
+    // The copies must be what IAS signed
+    if record.timestamp != report.timestamp || record.quote_status != report.quote_status
+        || record.advisory_ids != report.advisory_ids || record.platform_info_blob != report.platform_info_blob {
+        return Err(archive_err("the record doesn't match its report"));
+    }
+
+    // IAS signs the quote without its signature, the body
+    let body = base64::decode(&report.quote_body).map_err(|_| archive_err("the quote body of the report isn't base64"))?;
+    let quote = base64::decode(record.quote.trim()).map_err(|_| archive_err("the quote isn't base64"))?;
+    if body.len() < QUOTE_BODY_SIZE || !quote.starts_with(&body[..QUOTE_BODY_SIZE]) {
+        return Err(archive_err("the report isn't about the quote of the record"));
+    }
+    let quote = Quote::from_bytes(&quote)?;
+    if let Some(signing_key) = &record.signing_key {
+        let address: Vec<u8> = signing_key.trim_start_matches("0x").from_hex().map_err(|_| archive_err("the signing key isn't hex"))?;
+        if address.len() != 20 || quote.report_body.report_data[..20] != address[..] {
+            return Err(archive_err("the quote doesn't commit to the signing key of the record"));
+        }
+    }
+    Ok(VerifiedRecord { report, quote, verified_at })
+}
+
+// Every record of `archive`, in order: one that doesn't verify doesn't stop the others from being checked.
+pub fn verify_archive(archive: &AttestationArchive, root_ca: &str) -> Vec<Result<VerifiedRecord, Error>> {
+    verify_reports_batch(&archive.records, root_ca)
+}
+
+// `verify_record` of each of `records`, on every core: an archive of years of reports, or the history a federation
+// peer keeps of another's attestations. The results come in the order of `records`.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn verify_reports_batch(records: &[AttestationRecord], root_ca: &str) -> Vec<Result<VerifiedRecord, Error>> {
+    records.par_iter().map(|record| verify_record(record, root_ca)).collect()
+}
+
+// One by one in a browser, which has no threads to spare.
+#[cfg(target_arch = "wasm32")]
+pub fn verify_reports_batch(records: &[AttestationRecord], root_ca: &str) -> Vec<Result<VerifiedRecord, Error>> {
+    records.iter().map(|record| verify_record(record, root_ca)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::report::test::{certificate, quote_body, signed_bundle};
+
+    fn record(address: &[u8; 20]) -> (AttestationRecord, String) {
+        let (root, key) = certificate("Attestation Report Signing");
+        let root_pem = String::from_utf8(root.to_pem().unwrap()).unwrap();
+        let body = quote_body(1, address);
+        let report = format!(r#"{{"id":"1","timestamp":"2020-05-10T12:00:00.000000","isvEnclaveQuoteStatus":"GROUP_OUT_OF_DATE",
+            "isvEnclaveQuoteBody":"{}","platformInfoBlob":"1502006504","advisoryIDs":["INTEL-SA-00334"]}}"#, body);
+        let bundle = signed_bundle(&report, &key, &root);
+        // The quote as the enclave produced it, its EPID signature after the body
+        let mut quote = base64::decode(&body).unwrap();
+        quote.extend(&[4, 0, 0, 0, 1, 2, 3, 4]);
+        (AttestationRecord::new(&base64::encode(&quote), &bundle, Some(address.iter().map(|b| format!("{:02x}", b)).collect())).unwrap(), root_pem)
+    }
+
+    #[test]
+    fn test_archive_round_trip() {
+        let (record, root_pem) = record(&[7u8; 20]);
+        assert_eq!(record.quote_status, "GROUP_OUT_OF_DATE");
+        assert_eq!(record.platform_info_blob, Some("1502006504".to_string()));
+        assert_eq!(record.bundle().certificate, der_to_pem(&record.chain[0]));
+
+        let archive = AttestationArchive { records: vec![record.clone(), record], ..Default::default() };
+        let json = archive.to_json().unwrap();
+        assert_eq!(AttestationArchive::decode(json.as_bytes()).unwrap(), archive);
+        let cbor = archive.to_cbor().unwrap();
+        assert!(cbor.len() < json.len());
+        assert_eq!(AttestationArchive::decode(&cbor).unwrap(), archive);
+        assert!(AttestationArchive::decode(b" ").is_err());
+        assert!(AttestationArchive::decode(br#"{"version":2,"records":[]}"#).is_err());
+
+        let verified = verify_archive(&archive, &root_pem);
+        assert_eq!(verified.len(), 2);
+        let verified = verified[0].as_ref().unwrap();
+        assert_eq!(verified.verified_at, 1_589_112_000);
+        assert_eq!(verified.quote.report_body.mr_enclave, [1u8; 32]);
+    }
+
+    #[test]
+    fn test_verify_record_tampered() {
+        let (record, root_pem) = record(&[7u8; 20]);
+        assert!(verify_record(&record, &root_pem).is_ok());
+        assert!(verify_record(&record, crate::verify::INTEL_ROOT_CA).is_err());
+
+        // A copied field, the quote, the signing key or the report changed after the fact
+        let advisories = AttestationRecord { advisory_ids: Vec::new(), ..record.clone() };
+        assert!(verify_record(&advisories, &root_pem).is_err());
+        let status = AttestationRecord { quote_status: "OK".to_string(), ..record.clone() };
+        assert!(verify_record(&status, &root_pem).is_err());
+        let quote = AttestationRecord { quote: quote_body(2, &[7u8; 20]), ..record.clone() };
+        assert!(verify_record(&quote, &root_pem).is_err());
+        let signing_key = AttestationRecord { signing_key: Some("08".repeat(20)), ..record.clone() };
+        assert!(verify_record(&signing_key, &root_pem).is_err());
+        let report = AttestationRecord { report: record.report.replace("\"1\"", "\"2\""), ..record.clone() };
+        assert!(verify_record(&report, &root_pem).is_err());
+        let unsigned = AttestationRecord { chain: Vec::new(), ..record };
+        assert!(verify_record(&unsigned, &root_pem).is_err());
+    }
+
+    #[test]
+    fn test_verify_reports_batch() {
+        let (record, root_pem) = record(&[7u8; 20]);
+        let tampered = AttestationRecord { quote_status: "OK".to_string(), ..record.clone() };
+        let mut records = vec![record; 16];
+        records[5] = tampered;
+        let verified = verify_reports_batch(&records, &root_pem);
+        assert_eq!(verified.len(), 16);
+        // In order, the one that fails among them
+        assert_eq!(verified.iter().position(|result| result.is_err()), Some(5));
+        assert_eq!(verified.iter().filter(|result| result.is_ok()).count(), 15);
+        assert!(verify_reports_batch(&[], &root_pem).is_empty());
+    }
+}
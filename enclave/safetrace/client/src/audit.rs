@@ -0,0 +1,311 @@
+use crate::errors::AuditErr;
+use crate::signatures;
+use failure::Error;
+use hex::{FromHex, ToHex};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+// The audit log of a server: an entry for every operation that affects the stored data (ingest, deletion,
+// policy changes, admin actions), hashed into a chain,
+//
+//   hash = SHA-256(prev || SHA-256(JSON of {seq, timestamp, kind, actor, detail}))
+//
+// The server writes the entries, the enclave signs checkpoints of the chain: it folds the digests written since
+// its last checkpoint into its own copy of the head, and signs
+//
+//   "safetrace-audit" || prev seq || prev head || seq || head        (sequence numbers 8 bytes big endian)
+//
+// with its signing key, the one of the enclave report. It never signs a head that doesn't extend the last one
+// it signed, so a checkpoint vouches for every entry since the previous one. The enclave keeps its head in
+// memory: the first checkpoint after it started (`resumed`) takes the previous head from the server, and only
+// vouches for the entries after it.
+
+// `prev` of the first entry
+pub const GENESIS: [u8; 32] = [0u8; 32];
+const CHECKPOINT_TAG: &[u8] = b"safetrace-audit";
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum AuditKind {
+    // A submission or a registration the enclave accepted
+    Ingest,
+    // Stored records deleted
+    Deletion,
+    // A setting that changes what is stored, matched or served
+    PolicyChange,
+    // Anything else an operator did on the admin socket
+    Admin,
+    // Statistics released to a health authority
+    Export,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    // From 1, without gaps
+    pub seq: u64,
+    // Unix time, in seconds
+    pub timestamp: u64,
+    pub kind: AuditKind,
+    // Who asked: `ipc`, `operator 0`, `startup`
+    pub actor: String,
+    pub detail: Value,
+    // Hex, the `hash` of the entry before, `GENESIS` for the first one
+    pub prev: String,
+    pub hash: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditCheckpoint {
+    #[serde(rename = "prevSeq")] pub prev_seq: u64,
+    #[serde(rename = "prevHead")] pub prev_head: String,
+    // The `seq` and `hash` of the last entry it covers
+    pub seq: u64,
+    pub head: String,
+    // Not signed, when the server asked for it
+    pub timestamp: u64,
+    // The enclave started since its last checkpoint, `prevSeq` and `prevHead` are the server's word
+    pub resumed: bool,
+    // Address of the enclave signing key, as in the report
+    #[serde(rename = "signingKey")] pub signing_key: String,
+    pub signature: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "record", rename_all = "camelCase")]
+pub enum AuditRecord {
+    Entry(AuditEntry),
+    Checkpoint(AuditCheckpoint),
+}
+
+// What `verify` found in a run of records
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct AuditSummary {
+    pub entries: u64,
+    pub checkpoints: u64,
+    #[serde(rename = "firstSeq")] pub first_seq: u64,
+    #[serde(rename = "lastSeq")] pub last_seq: u64,
+    // The last entry a checkpoint vouches for, the ones after it aren't signed yet
+    #[serde(rename = "signedSeq")] pub signed_seq: u64,
+    // Checkpoints taken after the enclave (re)started
+    pub resumptions: u64,
+    #[serde(rename = "signingKeys")] pub signing_keys: Vec<String>,
+}
+
+fn audit_err(message: String) -> Error {
+    AuditErr { message }.into()
+}
+
+fn hash_from_hex(what: &str, value: &str) -> Result<[u8; 32], Error> {
+    let bytes: Vec<u8> = value.from_hex().map_err(|_| audit_err(format!("{} isn't hex", what)))?;
+    if bytes.len() != 32 {
+        return Err(audit_err(format!("{} isn't 32 bytes long", what)));
+    }
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(&bytes);
+    Ok(hash)
+}
+
+pub fn chain(prev: &[u8; 32], digest: &[u8; 32]) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.input(&prev[..]);
+    hasher.input(&digest[..]);
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(&hasher.result());
+    hash
+}
+
+// The bytes the enclave signs.
+pub fn checkpoint_message(prev_seq: u64, prev_head: &[u8; 32], seq: u64, head: &[u8; 32]) -> Vec<u8> {
+    let mut message = CHECKPOINT_TAG.to_vec();
+    message.extend_from_slice(&prev_seq.to_be_bytes());
+    message.extend_from_slice(prev_head);
+    message.extend_from_slice(&seq.to_be_bytes());
+    message.extend_from_slice(head);
+    message
+}
+
+impl AuditEntry {
+    pub fn new(seq: u64, timestamp: u64, kind: AuditKind, actor: &str, detail: Value, prev: &[u8; 32]) -> Self {
+        let mut entry = AuditEntry { seq, timestamp, kind, actor: actor.to_string(), detail, prev: prev.to_hex(), hash: String::new() };
+        entry.hash = chain(prev, &entry.digest()).to_hex();
+        entry
+    }
+
+    // What the enclave folds into its head, the entry without `prev` and `hash`.
+    pub fn digest(&self) -> [u8; 32] {
+        let body = json!({"seq": self.seq, "timestamp": self.timestamp, "kind": self.kind, "actor": self.actor, "detail": self.detail});
+        let mut digest = [0u8; 32];
+        digest.copy_from_slice(&Sha256::digest(body.to_string().as_bytes()));
+        digest
+    }
+}
+
+impl AuditCheckpoint {
+    // Checks the signature, returns the address of the signer.
+    pub fn verify_signature(&self) -> Result<[u8; 20], Error> {
+        let message = checkpoint_message(self.prev_seq, &hash_from_hex("prevHead", &self.prev_head)?, self.seq, &hash_from_hex("head", &self.head)?);
+        let bytes: Vec<u8> = self.signature.from_hex().map_err(|_| audit_err("the checkpoint signature isn't hex".to_string()))?;
+        if bytes.len() != 65 {
+            return Err(audit_err("the checkpoint signature must be 65 bytes long".to_string()));
+        }
+        let mut signature = [0u8; 65];
+        signature.copy_from_slice(&bytes);
+        let named: Vec<u8> = self.signing_key.from_hex().map_err(|_| audit_err("the checkpoint signing key isn't hex".to_string()))?;
+        if named.len() != 20 {
+            return Err(audit_err("the checkpoint signing key must be a 20 bytes address".to_string()));
+        }
+        let mut address = [0u8; 20];
+        address.copy_from_slice(&named);
+        if !signatures::signed_by(&message, &signature, &address) {
+            return Err(audit_err(format!("checkpoint {} isn't signed by {}", self.seq, self.signing_key)));
+        }
+        Ok(address)
+    }
+}
+
+// Checks a run of consecutive records, e.g. an export: every entry chains to the one before, every checkpoint
+// covers the entries before it and extends the previous checkpoint, and is signed by the key it names. With
+// `signing_keys`, only those signers are accepted, the addresses of verified enclave reports.
+pub fn verify(records: &[AuditRecord], signing_keys: Option<&[[u8; 20]]>) -> Result<AuditSummary, Error> {
+    let mut summary = AuditSummary::default();
+    // The seq and hash of the last entry, from the first record on
+    let mut last: Option<(u64, [u8; 32])> = None;
+    let mut last_checkpoint: Option<(u64, [u8; 32])> = None;
+    for record in records {
+        match record {
+            AuditRecord::Entry(entry) => {
+                let prev = hash_from_hex("prev", &entry.prev)?;
+                if let Some((seq, hash)) = last {
+                    if entry.seq != seq + 1 {
+                        return Err(audit_err(format!("entry {} follows entry {}", entry.seq, seq)));
+                    }
+                    if prev != hash {
+                        return Err(audit_err(format!("entry {} doesn't chain to entry {}", entry.seq, seq)));
+                    }
+                } else {
+                    summary.first_seq = entry.seq;
+                }
+                let hash = chain(&prev, &entry.digest());
+                if hash != hash_from_hex("hash", &entry.hash)? {
+                    return Err(audit_err(format!("entry {} was modified", entry.seq)));
+                }
+                last = Some((entry.seq, hash));
+                summary.entries += 1;
+                summary.last_seq = entry.seq;
+            },
+            AuditRecord::Checkpoint(checkpoint) => {
+                let head = hash_from_hex("head", &checkpoint.head)?;
+                match last {
+                    Some((seq, hash)) if seq != checkpoint.seq || hash != head => {
+                        return Err(audit_err(format!("checkpoint {} doesn't match entry {}", checkpoint.seq, seq)));
+                    },
+                    Some(_) => {},
+                    // An export starting at a checkpoint
+                    None => last = Some((checkpoint.seq, head)),
+                }
+                let prev = (checkpoint.prev_seq, hash_from_hex("prevHead", &checkpoint.prev_head)?);
+                if let Some(previous) = last_checkpoint {
+                    if !checkpoint.resumed && prev != previous {
+                        return Err(audit_err(format!("checkpoint {} doesn't extend checkpoint {}", checkpoint.seq, previous.0)));
+                    }
+                }
+                let signer = checkpoint.verify_signature()?;
+                if let Some(keys) = signing_keys {
+                    if !keys.iter().any(|key| key == &signer) {
+                        return Err(audit_err(format!("checkpoint {} is signed by an unknown key {}", checkpoint.seq, checkpoint.signing_key)));
+                    }
+                }
+                let signer: String = signer.to_hex();
+                if !summary.signing_keys.contains(&signer) {
+                    summary.signing_keys.push(signer);
+                }
+                if checkpoint.resumed {
+                    summary.resumptions += 1;
+                }
+                last_checkpoint = Some((checkpoint.seq, head));
+                summary.checkpoints += 1;
+                summary.signed_seq = checkpoint.seq;
+            },
+        }
+    }
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use enigma_crypto::asymmetric::KeyPair;
+    use enigma_tools_m::utils::EthereumAddress;
+
+    // What the enclave does, see `audit` in the enclave
+    fn sign(key: &KeyPair, prev: (u64, [u8; 32]), last: &AuditEntry, resumed: bool) -> AuditRecord {
+        let head = hash_from_hex("head", &last.hash).unwrap();
+        let signature = key.sign(&checkpoint_message(prev.0, &prev.1, last.seq, &head)).unwrap();
+        AuditRecord::Checkpoint(AuditCheckpoint {
+            prev_seq: prev.0, prev_head: prev.1.to_hex(), seq: last.seq, head: last.hash.clone(), timestamp: 1589000000,
+            resumed, signing_key: key.get_pubkey().address().to_hex(), signature: signature.to_hex(),
+        })
+    }
+
+    fn entries(count: u64, first: u64, prev: [u8; 32]) -> Vec<AuditEntry> {
+        let mut prev = prev;
+        (first..first + count).map(|seq| {
+            let entry = AuditEntry::new(seq, 1589000000 + seq, AuditKind::Ingest, "ipc", json!({"command": "AddPersonalData"}), &prev);
+            prev = hash_from_hex("hash", &entry.hash).unwrap();
+            entry
+        }).collect()
+    }
+
+    fn head(entry: &AuditEntry) -> (u64, [u8; 32]) {
+        (entry.seq, hash_from_hex("hash", &entry.hash).unwrap())
+    }
+
+    #[test]
+    fn test_verify_audit_log() {
+        let key = KeyPair::new().unwrap();
+        let first = entries(3, 1, GENESIS);
+        let second = entries(2, 4, head(&first[2]).1);
+        let mut records: Vec<AuditRecord> = first.iter().cloned().map(AuditRecord::Entry).collect();
+        records.push(sign(&key, (0, GENESIS), &first[2], true));
+        records.extend(second.iter().cloned().map(AuditRecord::Entry));
+        records.push(sign(&key, head(&first[2]), &second[1], false));
+
+        let summary = verify(&records, Some(&[key.get_pubkey().address()])).unwrap();
+        assert_eq!((summary.entries, summary.checkpoints, summary.signed_seq, summary.resumptions), (5, 2, 5, 1));
+        // An export from entry 4 on
+        assert_eq!(verify(&records[4..], None).unwrap().first_seq, 4);
+        assert!(verify(&records, Some(&[[0u8; 20]])).is_err());
+
+        let json = serde_json::to_value(&records[0]).unwrap();
+        assert_eq!(json["record"], "entry");
+        assert_eq!(json["kind"], "Ingest");
+        assert_eq!(serde_json::from_value::<AuditRecord>(json).unwrap(), records[0]);
+    }
+
+    #[test]
+    fn test_tampered_audit_log() {
+        let key = KeyPair::new().unwrap();
+        let log = entries(4, 1, GENESIS);
+        let mut records: Vec<AuditRecord> = log.iter().cloned().map(AuditRecord::Entry).collect();
+        records.push(sign(&key, (0, GENESIS), &log[3], true));
+
+        // A changed detail, a dropped entry, a checkpoint over other entries
+        let mut modified = records.clone();
+        if let AuditRecord::Entry(entry) = &mut modified[1] {
+            entry.detail = json!({"command": "Purge"});
+        }
+        assert!(verify(&modified, None).is_err());
+        let mut dropped = records.clone();
+        dropped.remove(2);
+        assert!(verify(&dropped, None).is_err());
+        let forged = entries(4, 1, [1u8; 32]);
+        let mut rebuilt: Vec<AuditRecord> = forged.into_iter().map(AuditRecord::Entry).collect();
+        rebuilt.push(records[4].clone());
+        assert!(verify(&rebuilt, None).is_err());
+
+        // A checkpoint that restarts from an earlier head without the enclave having restarted
+        let mut records = records;
+        records.push(sign(&key, (2, head(&log[1]).1), &log[3], false));
+        assert!(verify(&records, None).is_err());
+    }
+}
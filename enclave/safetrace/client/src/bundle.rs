@@ -0,0 +1,131 @@
+use crate::errors::ReportErr;
+use failure::Error;
+use serde::{Deserialize, Serialize};
+use serde_bytes::ByteBuf;
+
+// Everything a client needs to check the enclave report on its own, rather than trusting the
+// `validate` flag of the server: the report exactly as IAS signed it, the signature, and the
+// certificate chain up to Intel's Attestation Report Signing CA.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct ReportBundle {
+    pub report: String,
+    pub signature: String,
+    // PEM, the signing certificate then the CA
+    pub certificate: String,
+    pub ca: String,
+}
+
+// Version of the compact encoding, its first field.
+const COMPACT_VERSION: u8 = 1;
+const PEM_BEGIN: &str = "-----BEGIN CERTIFICATE-----";
+const PEM_END: &str = "-----END CERTIFICATE-----";
+
+// The compact encoding is a msgpack array `[version, report, signature, [certificate DER, ca DER...]]`,
+// base64url encoded without padding: a single string that fits in a URL or a QR code, with the certificates
+// as DER rather than PEM.
+#[derive(Serialize, Deserialize)]
+struct CompactBundle {
+    version: u8,
+    report: String,
+    signature: String,
+    chain: Vec<ByteBuf>,
+}
+
+fn bundle_err(message: &str) -> Error {
+    ReportErr { message: format!("report bundle: {}", message) }.into()
+}
+
+// The DER certificates of a PEM document, in order.
+pub fn pem_to_der(pem: &str) -> Result<Vec<Vec<u8>>, Error> {
+    let mut certificates = Vec::new();
+    let mut rest = pem;
+    while let Some(start) = rest.find(PEM_BEGIN) {
+        let body = &rest[start + PEM_BEGIN.len()..];
+        let end = body.find(PEM_END).ok_or_else(|| bundle_err("unterminated PEM certificate"))?;
+        let encoded: String = body[..end].chars().filter(|c| !c.is_whitespace()).collect();
+        certificates.push(base64::decode(&encoded).map_err(|_| bundle_err("invalid PEM certificate"))?);
+        rest = &body[end + PEM_END.len()..];
+    }
+    if certificates.is_empty() && !pem.trim().is_empty() {
+        return Err(bundle_err("no PEM certificate found"));
+    }
+    Ok(certificates)
+}
+
+pub fn der_to_pem(der: &[u8]) -> String {
+    let encoded = base64::encode(der);
+    let lines: Vec<&str> = encoded.as_bytes().chunks(64).map(|line| std::str::from_utf8(line).unwrap()).collect();
+    format!("{}\n{}\n{}\n", PEM_BEGIN, lines.join("\n"), PEM_END)
+}
+
+impl ReportBundle {
+    pub fn to_compact(&self) -> Result<String, Error> {
+        let mut chain = pem_to_der(&self.certificate)?;
+        chain.extend(pem_to_der(&self.ca)?);
+        let compact = CompactBundle {
+            version: COMPACT_VERSION,
+            report: self.report.clone(),
+            signature: self.signature.clone(),
+            chain: chain.into_iter().map(ByteBuf::from).collect(),
+        };
+        Ok(base64::encode_config(&rmp_serde::to_vec(&compact)?, base64::URL_SAFE_NO_PAD))
+    }
+
+    // The certificates come back as PEM, the report and the signature byte for byte.
+    pub fn from_compact(encoded: &str) -> Result<Self, Error> {
+        let bytes = base64::decode_config(encoded.trim(), base64::URL_SAFE_NO_PAD).map_err(|_| bundle_err("invalid base64url"))?;
+        let compact: CompactBundle = rmp_serde::from_slice(&bytes)?;
+        if compact.version != COMPACT_VERSION {
+            return Err(bundle_err(&format!("unsupported version {}", compact.version)));
+        }
+        let mut chain = compact.chain.iter().map(|der| der_to_pem(der));
+        Ok(ReportBundle {
+            report: compact.report,
+            signature: compact.signature,
+            certificate: chain.next().unwrap_or_default(),
+            ca: chain.collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn bundle() -> ReportBundle {
+        ReportBundle {
+            report: r#"{"id":"123","isvEnclaveQuoteStatus":"OK"}"#.to_string(),
+            signature: "c2lnbmF0dXJl".to_string(),
+            certificate: der_to_pem(&(0..160).map(|i| i as u8).collect::<Vec<u8>>()),
+            ca: der_to_pem(&(0..80).map(|i| 255 - i as u8).collect::<Vec<u8>>()),
+        }
+    }
+
+    #[test]
+    fn test_compact_round_trip() {
+        let bundle = bundle();
+        let compact = bundle.to_compact().unwrap();
+        assert!(!compact.contains('=') && !compact.contains('+') && !compact.contains('/'));
+        assert_eq!(ReportBundle::from_compact(&compact).unwrap(), bundle);
+
+        // Simulation builds have no certificates
+        let unsigned = ReportBundle { report: "{}".to_string(), ..Default::default() };
+        assert_eq!(ReportBundle::from_compact(&unsigned.to_compact().unwrap()).unwrap(), unsigned);
+    }
+
+    #[test]
+    fn test_decode_golden_bundle() {
+        // The `bundle` of app/tests/golden/response_get_enclave_report.json
+        let bundle = ReportBundle::from_compact("lAGseyJpZCI6IjEyMyJ9rGMybG5ibUYwZFhKbJLEAzCCAcQDMIIC").unwrap();
+        assert_eq!(bundle.report, r#"{"id":"123"}"#);
+        assert_eq!(bundle.certificate, "-----BEGIN CERTIFICATE-----\nMIIB\n-----END CERTIFICATE-----\n");
+        assert_eq!(bundle.ca, "-----BEGIN CERTIFICATE-----\nMIIC\n-----END CERTIFICATE-----\n");
+    }
+
+    #[test]
+    fn test_compact_errors() {
+        assert!(ReportBundle::from_compact("not base64!").is_err());
+        let broken = ReportBundle { certificate: "-----BEGIN CERTIFICATE-----\nMIIB".to_string(), ..bundle() };
+        assert!(broken.to_compact().is_err());
+    }
+}
@@ -0,0 +1,500 @@
+use crate::errors::{AttestationPendingErr, IdentityErr, SessionErr};
+use crate::export::{ExportBundle, ExportReply, ExportRequest};
+use crate::identity::{ResponseCounter, ServerIdentity};
+use crate::manifest::EnclaveManifest;
+use crate::messages::{self, AttestationJob, CachedReport, Declaration, EnclaveInfo, EnclaveReport, EnclaveResult, HeartbeatReply, JobReply, Location,
+                      ProtocolVersion, Receipt, ReportReply, TaskKey, server_err};
+use crate::report::{self, EnclaveIdentity, EnclaveSettings, ReportPolicy};
+use crate::session::Session;
+use crate::transport::Transport;
+use enigma_crypto::asymmetric::KeyPair;
+use failure::Error;
+use hex::ToHex;
+use std::cell::RefCell;
+
+// A SafeTrace user: verifies the enclave once, then exchanges a fresh key with it for every request.
+pub struct Client<T: Transport> {
+    transport: T,
+    policy: ReportPolicy,
+    user_key: KeyPair,
+    signing_key: Option<KeyPair>,
+    identity: RefCell<Option<EnclaveIdentity>>,
+    require_signed: bool,
+    responses: RefCell<ResponseCounter>,
+    tenant: Option<String>,
+    deflate: bool,
+}
+
+impl<T: Transport> Client<T> {
+    pub fn new(transport: T, policy: ReportPolicy, user_key: KeyPair) -> Self {
+        Client { transport, policy, user_key, signing_key: None, identity: RefCell::new(None), require_signed: false,
+                 responses: RefCell::new(ResponseCounter::default()), tenant: None, deflate: false }
+    }
+
+    // Refuses the responses without the `identity` of the enclave, once it's verified. Signed responses
+    // are checked either way.
+    pub fn require_signed_responses(mut self) -> Self {
+        self.require_signed = true;
+        self
+    }
+
+    // Signs the requests with `signing_key`, which the user registers with `register`. Keep it: once it's
+    // registered, the enclave refuses the requests of the user that aren't signed with it.
+    pub fn with_signing_key(mut self, signing_key: KeyPair) -> Self {
+        self.signing_key = Some(signing_key);
+        self
+    }
+
+    // Sends every request in the partition of `tenant`, on servers that serve several regions or health
+    // authorities. The task keys are per tenant, a client sticks to one.
+    pub fn with_tenant(mut self, tenant: &str) -> Self {
+        self.tenant = Some(tenant.to_string());
+        self
+    }
+
+    // Deflates the location histories before encrypting them, for servers with the capability `compression-deflate`.
+    pub fn with_compression(mut self) -> Self {
+        self.deflate = true;
+        self
+    }
+
+    pub fn user_pubkey(&self) -> String { self.user_key.get_pubkey().to_hex() }
+
+    fn call<R: for<'de> serde::Deserialize<'de>>(&self, request: serde_json::Value) -> Result<R, Error> {
+        let kind = request["type"].as_str().unwrap_or_default().to_string();
+        let request = match &self.tenant {
+            Some(tenant) => messages::with_tenant(request, tenant),
+            None => request,
+        };
+        let response = self.transport.call(request)?;
+        self.check_identity(&kind, &response)?;
+        messages::parse_response(&kind, &response)
+    }
+
+    // Until the enclave is verified there's no signing address to check against.
+    fn check_identity(&self, kind: &str, response: &serde_json::Value) -> Result<(), Error> {
+        let signing_address = match self.identity.borrow().as_ref() {
+            Some(identity) => identity.signing_address,
+            None => return Ok(()),
+        };
+        match ServerIdentity::of(response)? {
+            Some(identity) => {
+                identity.verify(response, &signing_address)?;
+                self.responses.borrow_mut().check(&identity)
+            },
+            None if self.require_signed => Err(IdentityErr { message: format!("the {} response isn't signed", kind) }.into()),
+            None => Ok(()),
+        }
+    }
+
+    pub fn get_protocol_version(&self) -> Result<ProtocolVersion, Error> {
+        self.call(messages::get_protocol_version(&messages::new_id()))
+    }
+
+    // Whether the server still answers, and how far off its clock is, see `HeartbeatReply`.
+    pub fn heartbeat(&self) -> Result<HeartbeatReply, Error> {
+        self.call(messages::heartbeat(&messages::new_id(), messages::now_millis()))
+    }
+
+    // An `AttestationPendingErr` while the server can't reach IAS.
+    pub fn get_enclave_report(&self) -> Result<EnclaveReport, Error> {
+        match self.call(messages::get_enclave_report(&messages::new_id()))? {
+            ReportReply::Report(report) => Ok(report),
+            ReportReply::Pending { job } => Err(AttestationPendingErr { job_id: job.job_id }.into()),
+        }
+    }
+
+    // The report the server has cached for a quote, by the SHA-256 of its bytes (hex), see `CachedReport`.
+    pub fn get_cached_report(&self, quote_hash: &str) -> Result<CachedReport, Error> {
+        self.call(messages::get_cached_report(&messages::new_id(), quote_hash))
+    }
+
+    // The job of a pending report, with the report once `Done`.
+    pub fn get_attestation_job(&self, job_id: &str) -> Result<AttestationJob, Error> {
+        let reply: JobReply = self.call(messages::get_attestation_job(&messages::new_id(), job_id))?;
+        Ok(reply.job)
+    }
+
+    // Fetches and checks the report against the policy, once: the enclave keeps its signing key.
+    pub fn verify_enclave(&self) -> Result<EnclaveIdentity, Error> {
+        if let Some(identity) = self.identity.borrow().as_ref() {
+            return Ok(identity.clone());
+        }
+        let report = self.get_enclave_report()?;
+        let signing_address = report::parse_signing_address(&report.signing_key)?;
+        let identity = report::verify_enclave(&report.to_bundle()?, &signing_address, &self.policy)?;
+        *self.identity.borrow_mut() = Some(identity.clone());
+        Ok(identity)
+    }
+
+    // What the server says it runs, to compare with a published manifest. Use `verify_manifest` to rely on it.
+    pub fn get_enclave_info(&self) -> Result<EnclaveInfo, Error> {
+        self.call(messages::get_enclave_info(&messages::new_id()))
+    }
+
+    // Verifies the enclave, then reads the settings it enforces, checked against its report. A policy applied
+    // since the enclave was verified fails the check, verify it again with a new client.
+    pub fn verify_settings(&self) -> Result<EnclaveSettings, Error> {
+        let identity = self.verify_enclave()?;
+        let info = self.get_enclave_info()?;
+        let settings = info.settings.ok_or_else(|| server_err("GetEnclaveInfo", "the server doesn't serve its settings"))?;
+        report::verify_settings(&identity.quote, &settings)
+    }
+
+    // For a health authority: the k-anonymous statistics of `request` (see `export`), checked to be signed by
+    // the verified enclave.
+    pub fn export_exposure_statistics(&self, request: &ExportRequest) -> Result<ExportBundle, Error> {
+        let identity = self.verify_enclave()?;
+        let reply: ExportReply = self.call(messages::export_exposure_statistics(&messages::new_id(), request))?;
+        reply.bundle.verify(request, &identity.signing_address)?;
+        Ok(reply.bundle)
+    }
+
+    // Verifies the enclave, then that it is the build `manifest` describes.
+    pub fn verify_manifest(&self, manifest: &EnclaveManifest) -> Result<EnclaveIdentity, Error> {
+        let identity = self.verify_enclave()?;
+        manifest.check(&identity.quote)?;
+        Ok(identity)
+    }
+
+    // The enclave drops a task key once it served a request, hence a session per request.
+    pub fn new_session(&self) -> Result<Session, Error> {
+        let identity = self.verify_enclave()?;
+        let key: TaskKey = self.call(messages::new_task_encryption_key(&messages::new_id(), &self.user_pubkey()))?;
+        Session::new(&self.user_key, &key.taskPubKey, &key.sig, &identity.signing_address)
+    }
+
+    fn signed(&self, request: serde_json::Value, session: &Session) -> Result<serde_json::Value, Error> {
+        match &self.signing_key {
+            Some(signing_key) => messages::sign(request, session, signing_key),
+            None => Ok(request),
+        }
+    }
+
+    // Registers the signing key for `user_id`, false if the id is already registered with another key.
+    pub fn register(&self, user_id: &str) -> Result<bool, Error> {
+        let signing_key = self.signing_key.as_ref().ok_or_else(|| SessionErr { message: "no signing key to register".to_string() })?;
+        let session = self.new_session()?;
+        let request = messages::register_user(
+            &messages::new_id(),
+            &session.encrypt(user_id.as_bytes())?,
+            &session.registration(signing_key, &self.user_key.get_pubkey())?,
+            &self.user_pubkey(),
+        );
+        let result: EnclaveResult = self.call(request)?;
+        Ok(result.passed())
+    }
+
+    pub fn add_personal_data(&self, user_id: &str, locations: &[Location]) -> Result<Receipt, Error> {
+        self.submit(user_id, locations, None)
+    }
+
+    // The locations of a user who tested positive, with the declaration their health authority issued for them.
+    pub fn add_declared_personal_data(&self, user_id: &str, locations: &[Location], declaration: &Declaration) -> Result<Receipt, Error> {
+        self.submit(user_id, locations, Some(declaration))
+    }
+
+    fn submit(&self, user_id: &str, locations: &[Location], declaration: Option<&Declaration>) -> Result<Receipt, Error> {
+        let session = self.new_session()?;
+        let mut payload = serde_json::to_vec(&messages::personal_data(locations, messages::now(), declaration))?;
+        if self.deflate {
+            payload = messages::deflate(&payload);
+        }
+        let mut request = messages::add_personal_data(
+            &messages::new_id(),
+            &session.encrypt(user_id.as_bytes())?,
+            &session.encrypt(&payload)?,
+            &self.user_pubkey(),
+        );
+        if self.deflate {
+            request = messages::with_compression(request);
+        }
+        let result: EnclaveResult = self.call(self.signed(request, &session)?)?;
+        receipt(&session, result)
+    }
+
+    // Sets the test result of the locations `user_id` stored from `since` on (all of them when `None`) without
+    // submitting them again: a user who tested positive sends the declaration their health authority issued.
+    pub fn update_user_status(&self, user_id: &str, test_result: bool, since: Option<u64>, declaration: Option<&Declaration>) -> Result<Receipt, Error> {
+        let session = self.new_session()?;
+        let request = messages::update_user_status(
+            &messages::new_id(),
+            &session.encrypt(user_id.as_bytes())?,
+            &session.encrypt(&serde_json::to_vec(&messages::status_update(test_result, since, messages::now(), declaration))?)?,
+            &self.user_pubkey(),
+        );
+        let result: EnclaveResult = self.call(self.signed(request, &session)?)?;
+        receipt(&session, result)
+    }
+
+    // The locations of the infected users the user's crossed, empty if none.
+    pub fn find_match(&self, user_id: &str) -> Result<Vec<Location>, Error> {
+        let session = self.new_session()?;
+        let request = messages::find_match(&messages::new_id(), &session.encrypt(user_id.as_bytes())?, &self.user_pubkey());
+        let result: EnclaveResult = self.call(self.signed(request, &session)?)?;
+        if !result.passed() || result.encryptedOutput.is_empty() {
+            return Ok(Vec::new());
+        }
+        Ok(serde_json::from_slice(&session.decrypt(&result.encryptedOutput)?)?)
+    }
+}
+
+// The receipt of `addPersonalData` or `updateUserStatus`.
+fn receipt(session: &Session, result: EnclaveResult) -> Result<Receipt, Error> {
+    if result.encryptedOutput.is_empty() {
+        // A server older than the encrypted receipts
+        let status = if result.passed() { "Passed" } else { "Failed" };
+        return Ok(Receipt { status: status.to_string(), records: 0, duplicates: 0, error: result.error, clock_drift: 0 });
+    }
+    let receipt = session.decrypt(&result.encryptedOutput)?;
+    // The receipt is padded with spaces to a fixed size
+    Ok(serde_json::from_slice(&receipt)?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::identity::test::sign as sign_response;
+    use crate::quote::Quote;
+    use crate::report::{test::quote_body, Trust};
+    use crate::session::test::enclave_task_key;
+    use enigma_crypto::symmetric;
+    use enigma_types::DhKey;
+    use enigma_tools_m::utils::EthereumAddress;
+    use hex::FromHex;
+    use serde_json::{json, Value};
+
+    // Answers like the app in simulation mode, with the enclave crypto done in the test.
+    struct FakeServer {
+        signing_key: KeyPair,
+        session: RefCell<Option<DhKey>>,
+        calls: RefCell<Vec<String>>,
+        // The `tenant` of each request
+        tenants: RefCell<Vec<Value>>,
+        // The key registered for "user1", like `users` in the enclave
+        registered: RefCell<Option<Vec<u8>>>,
+        // The counter of the last signed response, `None` doesn't sign them
+        counter: RefCell<Option<u64>>,
+    }
+
+    impl FakeServer {
+        fn decrypt(&self, ciphertext: &Value) -> Vec<u8> {
+            let ciphertext: Vec<u8> = ciphertext.as_str().unwrap().from_hex().unwrap();
+            symmetric::decrypt(&ciphertext, self.session.borrow().as_ref().unwrap()).unwrap()
+        }
+
+        // Whether the request carries the signature of the registered key over `fields`
+        fn authenticated(&self, input: &Value, fields: &[&str]) -> bool {
+            let registered = match self.registered.borrow().clone() {
+                Some(registered) => registered,
+                None => return true,
+            };
+            if input["encryptedSignature"].is_null() {
+                return false;
+            }
+            let mut signed = Vec::new();
+            for field in fields {
+                let part: Vec<u8> = input[*field].as_str().unwrap().from_hex().unwrap();
+                signed.extend(part);
+            }
+            let mut signature = [0u8; 65];
+            signature.copy_from_slice(&self.decrypt(&input["encryptedSignature"]));
+            KeyPair::recover(&signed, signature).unwrap()[..] == registered[..]
+        }
+
+        fn encrypt(&self, plaintext: &[u8]) -> String {
+            // Like `get_io_key`, the key serves once
+            symmetric::encrypt(plaintext, &self.session.borrow_mut().take().unwrap()).unwrap().to_hex()
+        }
+    }
+
+    impl<'a> Transport for &'a FakeServer {
+        fn call(&self, request: Value) -> Result<Value, Error> {
+            let kind = request["type"].as_str().unwrap().to_string();
+            self.calls.borrow_mut().push(kind.clone());
+            self.tenants.borrow_mut().push(request["tenant"].clone());
+            let id = request["id"].clone();
+            let signed: Vec<&str> = ["encryptedUserId", "encryptedData", "userPubKey"].iter().cloned()
+                .filter(|field| !request["input"][*field].is_null()).collect();
+            if (kind == "AddPersonalData" || kind == "UpdateUserStatus" || kind == "FindMatch") && !self.authenticated(&request["input"], &signed) {
+                self.session.borrow_mut().take();
+                let field = match kind.as_str() { "FindMatch" => "findMatch", "UpdateUserStatus" => "updateUserStatus", _ => "addPersonalData" };
+                return Ok(json!({"id": id, "type": kind, field: {"status": -1}}));
+            }
+            let mut response = match kind.as_str() {
+                "GetEnclaveReport" => {
+                    let address = self.signing_key.get_pubkey().address();
+                    json!({"id": id, "type": kind, "result": {
+                        "signingKey": address.to_hex(), "report": quote_body(1, &address).as_bytes().to_hex(),
+                        "signature": "", "certificate": "", "ca": "", "bundle": "",
+                    }})
+                },
+                "NewTaskEncryptionKey" => {
+                    let mut user_pubkey = [0u8; 64];
+                    user_pubkey.copy_from_slice(&request["userPubKey"].as_str().unwrap().from_hex().unwrap());
+                    let (task_pubkey, sig, key) = enclave_task_key(&self.signing_key, &user_pubkey);
+                    *self.session.borrow_mut() = Some(key);
+                    json!({"id": id, "type": kind, "result": {"taskPubKey": task_pubkey, "sig": sig}})
+                },
+                "RegisterUser" => {
+                    let registration = self.decrypt(&request["input"]["encryptedData"]);
+                    let user_pubkey: Vec<u8> = request["input"]["userPubKey"].as_str().unwrap().from_hex().unwrap();
+                    let mut signature = [0u8; 65];
+                    signature.copy_from_slice(&registration[64..]);
+                    assert_eq!(KeyPair::recover(&user_pubkey, signature).unwrap()[..], registration[..64]);
+                    self.session.borrow_mut().take();
+                    // The first key registered is the one that counts
+                    let mut registered = self.registered.borrow_mut();
+                    let status = match registered.as_ref() {
+                        Some(key) if key[..] != registration[..64] => -1,
+                        _ => 0,
+                    };
+                    registered.get_or_insert(registration[..64].to_vec());
+                    json!({"id": id, "type": kind, "registerUser": {"status": status}})
+                },
+                "AddPersonalData" => {
+                    assert_eq!(self.decrypt(&request["input"]["encryptedUserId"]), b"user1".to_vec());
+                    let mut plaintext = self.decrypt(&request["input"]["encryptedData"]);
+                    if request["input"]["compression"] == "deflate" {
+                        plaintext = miniz_oxide::inflate::decompress_to_vec(&plaintext).unwrap();
+                    }
+                    let payload: Value = serde_json::from_slice(&plaintext).unwrap();
+                    assert_eq!(payload["nonce"].as_str().unwrap().len(), 32);
+                    assert!((payload["timestamp"].as_u64().unwrap() as i64 - messages::now() as i64).abs() < 60);
+                    let locations: Vec<Location> = serde_json::from_value(payload["locations"].clone()).unwrap();
+                    let receipt = format!(r#"{{"status":"Passed","records":{}}}      "#, locations.len());
+                    json!({"id": id, "type": kind, "addPersonalData": {"status": 0, "encryptedOutput": self.encrypt(receipt.as_bytes())}})
+                },
+                "UpdateUserStatus" => {
+                    assert_eq!(self.decrypt(&request["input"]["encryptedUserId"]), b"user1".to_vec());
+                    let payload: Value = serde_json::from_slice(&self.decrypt(&request["input"]["encryptedData"])).unwrap();
+                    assert_eq!(payload["testResult"], true);
+                    assert!(!payload["declaration"]["signature"].is_null());
+                    let receipt = r#"{"status":"Passed","records":2}      "#;
+                    json!({"id": id, "type": kind, "updateUserStatus": {"status": 0, "encryptedOutput": self.encrypt(receipt.as_bytes())}})
+                },
+                "FindMatch" => {
+                    let matches = vec![location()];
+                    json!({"id": id, "type": kind, "findMatch": {"status": 0, "encryptedOutput": self.encrypt(&serde_json::to_vec(&matches).unwrap())}})
+                },
+                _ => json!({"id": id, "type": "Error", "code": 1005, "msg": "unknown request"}),
+            };
+            if let Some(counter) = self.counter.borrow_mut().as_mut() {
+                *counter += 1;
+                sign_response(&self.signing_key, &mut response, [1; 16], *counter);
+            }
+            Ok(response)
+        }
+    }
+
+    fn location() -> Location {
+        Location { lat: 40.7, lng: -74.0, startTS: 1583064000, endTS: 1583067600, testResult: true }
+    }
+
+    fn fake_server() -> FakeServer {
+        FakeServer {
+            signing_key: KeyPair::new().unwrap(),
+            session: RefCell::new(None),
+            calls: RefCell::new(Vec::new()),
+            tenants: RefCell::new(Vec::new()),
+            registered: RefCell::new(None),
+            counter: RefCell::new(None),
+        }
+    }
+
+    #[test]
+    fn test_client_flow() {
+        let server = fake_server();
+        let policy = ReportPolicy::new(Trust::Simulation).with_mr_enclave([1u8; 32]);
+        let client = Client::new(&server, policy, KeyPair::new().unwrap());
+
+        let receipt = client.add_personal_data("user1", &[location(), location()]).unwrap();
+        assert_eq!(receipt, Receipt { status: "Passed".to_string(), records: 2, duplicates: 0, error: None, clock_drift: 0 });
+        assert_eq!(client.find_match("user1").unwrap(), vec![location()]);
+        // Tested positive later on
+        let declaration = Declaration::issue(&KeyPair::new().unwrap(), "user1", messages::now()).unwrap();
+        assert_eq!(client.update_user_status("user1", true, Some(1583064000), Some(&declaration)).unwrap().records, 2);
+        // The report is verified once, a task key is asked for every request
+        assert_eq!(*server.calls.borrow(), vec![
+            "GetEnclaveReport", "NewTaskEncryptionKey", "AddPersonalData", "NewTaskEncryptionKey", "FindMatch",
+            "NewTaskEncryptionKey", "UpdateUserStatus",
+        ]);
+        assert!(client.get_protocol_version().is_err());
+    }
+
+    #[test]
+    fn test_client_compression() {
+        let server = fake_server();
+        let client = Client::new(&server, ReportPolicy::new(Trust::Simulation), KeyPair::new().unwrap()).with_compression();
+        assert_eq!(client.add_personal_data("user1", &vec![location(); 20]).unwrap().records, 20);
+    }
+
+    #[test]
+    fn test_client_tenant() {
+        let server = fake_server();
+        let client = Client::new(&server, ReportPolicy::new(Trust::Simulation), KeyPair::new().unwrap());
+        assert_eq!(client.find_match("user1").unwrap(), vec![location()]);
+        assert!(server.tenants.borrow().iter().all(Value::is_null));
+
+        let server = fake_server();
+        let client = Client::new(&server, ReportPolicy::new(Trust::Simulation), KeyPair::new().unwrap()).with_tenant("ch-ge");
+        assert_eq!(client.find_match("user1").unwrap(), vec![location()]);
+        assert_eq!(*server.tenants.borrow(), vec![json!("ch-ge"); 3]);
+    }
+
+    #[test]
+    fn test_client_signs_requests() {
+        let server = fake_server();
+        let policy = ReportPolicy::new(Trust::Simulation);
+        let client = Client::new(&server, policy, KeyPair::new().unwrap()).with_signing_key(KeyPair::new().unwrap());
+        assert!(client.register("user1").unwrap());
+        assert_eq!(client.add_personal_data("user1", &[location()]).unwrap().records, 1);
+        assert_eq!(client.find_match("user1").unwrap(), vec![location()]);
+
+        // Someone else, who doesn't hold the registered key
+        let policy = ReportPolicy::new(Trust::Simulation);
+        let other = Client::new(&server, policy, KeyPair::new().unwrap());
+        assert!(other.find_match("user1").unwrap().is_empty());
+        assert_eq!(other.add_personal_data("user1", &[location()]).unwrap().status, "Failed");
+        let declaration = Declaration::issue(&KeyPair::new().unwrap(), "user1", messages::now()).unwrap();
+        assert_eq!(other.update_user_status("user1", true, None, Some(&declaration)).unwrap().status, "Failed");
+        assert!(other.register("user1").is_err());
+        let policy = ReportPolicy::new(Trust::Simulation);
+        let impostor = Client::new(&server, policy, KeyPair::new().unwrap()).with_signing_key(KeyPair::new().unwrap());
+        assert!(!impostor.register("user1").unwrap());
+    }
+
+    #[test]
+    fn test_client_checks_server_identity() {
+        let server = fake_server();
+        let client = Client::new(&server, ReportPolicy::new(Trust::Simulation), KeyPair::new().unwrap()).require_signed_responses();
+        assert!(client.find_match("user1").unwrap_err().to_string().contains("NewTaskEncryptionKey response isn't signed"));
+
+        *server.counter.borrow_mut() = Some(0);
+        assert_eq!(client.find_match("user1").unwrap(), vec![location()]);
+        // The host answers with responses the enclave signed before
+        *server.counter.borrow_mut() = Some(0);
+        assert!(client.find_match("user1").unwrap_err().to_string().contains("comes after response"));
+    }
+
+    #[test]
+    fn test_client_verifies_manifest() {
+        let server = fake_server();
+        let client = Client::new(&server, ReportPolicy::new(Trust::Simulation), KeyPair::new().unwrap());
+        let address = server.signing_key.get_pubkey().address();
+        let quote = Quote::from_base64(&quote_body(1, &address)).unwrap();
+        let mut manifest = EnclaveManifest::from_quote(&quote, Default::default());
+        assert_eq!(client.verify_manifest(&manifest).unwrap().mr_enclave, [1u8; 32]);
+        manifest.debug = true;
+        assert!(client.verify_manifest(&manifest).unwrap_err().to_string().contains("debug is false"));
+    }
+
+    #[test]
+    fn test_client_rejects_unexpected_enclave() {
+        let server = fake_server();
+        let policy = ReportPolicy::new(Trust::Simulation).with_mr_enclave([2u8; 32]);
+        let client = Client::new(&server, policy, KeyPair::new().unwrap());
+        assert!(client.find_match("user1").is_err());
+        assert_eq!(*server.calls.borrow(), vec!["GetEnclaveReport"]);
+    }
+}
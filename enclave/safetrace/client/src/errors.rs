@@ -0,0 +1,149 @@
+use failure::Fail;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+#[derive(Fail, Debug)]
+#[fail(display = "Error while decoding the quote = ({})", message)]
+pub struct QuoteErr {
+    pub message: String,
+}
+
+// The report (or its bundle) is malformed, or doesn't vouch for the enclave we expect
+#[derive(Fail, Debug)]
+#[fail(display = "Error while verifying the enclave report = ({})", message)]
+pub struct ReportErr {
+    pub message: String,
+}
+
+// The key exchange with the enclave failed, or a payload couldn't be decrypted
+#[derive(Fail, Debug)]
+#[fail(display = "Error in the session with the enclave = ({})", message)]
+pub struct SessionErr {
+    pub message: String,
+}
+
+// The server answered with an error, or with something we don't understand. `code` is the one of the error
+// reply, none for a reply we couldn't read or a server from before the codes.
+#[derive(Fail, Debug)]
+#[fail(display = "Error from the server, request: {}, error: {}", request, message)]
+pub struct ServerErr {
+    pub request: String,
+    pub message: String,
+    pub code: Option<ErrorCode>,
+}
+
+// Why the server failed a request, the `code` of a native error reply and the `errorCode` of a JSON-RPC one.
+// The numbers are stable: a code is never reused, new ones are added at the end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(u16)]
+pub enum ErrorCode {
+    // Anything not listed below
+    InternalError = 1000,
+    // IAS refused the quote, or its answer didn't verify
+    AttestationFailed = 1001,
+    // The enclave failed to process the request
+    EnclaveError = 1002,
+    // Fields out of their limits, the `data` of JSON-RPC lists them
+    ValidationError = 1003,
+    // The frame doesn't parse or isn't a request
+    InvalidRequest = 1004,
+    UnknownMethod = 1005,
+    // Switched off by the operator (the admin `SetFeatureSwitch`)
+    FeatureDisabled = 1006,
+    // Past its sunset date
+    MethodRetired = 1007,
+    // IAS kept failing, a later attempt may succeed
+    AttestationUnavailable = 1008,
+    // A federation peer failed or was rejected
+    PeerError = 1009,
+    RequestTimeout = 1010,
+    PayloadTooLarge = 1011,
+    UnknownTenant = 1012,
+    // The attestation of the server is stale or its TCB status regressed, it refuses new registrations
+    ServiceDegraded = 1013,
+    // The socket is past its `server.*_rate_limit`, a later attempt may succeed
+    RateLimited = 1014,
+    // The frame doesn't carry the tag of the key shared with the gateway, see `tags`
+    Unauthenticated = 1015,
+    // The node is draining for maintenance, the data plane requests are retried later
+    Maintenance = 1016,
+}
+
+const ERROR_CODES: &[ErrorCode] = &[ErrorCode::InternalError, ErrorCode::AttestationFailed, ErrorCode::EnclaveError, ErrorCode::ValidationError,
+                                    ErrorCode::InvalidRequest, ErrorCode::UnknownMethod, ErrorCode::FeatureDisabled, ErrorCode::MethodRetired,
+                                    ErrorCode::AttestationUnavailable, ErrorCode::PeerError, ErrorCode::RequestTimeout, ErrorCode::PayloadTooLarge,
+                                    ErrorCode::UnknownTenant, ErrorCode::ServiceDegraded, ErrorCode::RateLimited,
+                                    ErrorCode::Unauthenticated, ErrorCode::Maintenance];
+
+impl ErrorCode {
+    pub fn code(self) -> u16 {
+        self as u16
+    }
+
+    // None for a code this client doesn't know, from a newer server
+    pub fn from_code(code: u64) -> Option<ErrorCode> {
+        ERROR_CODES.iter().cloned().find(|known| u64::from(known.code()) == code)
+    }
+}
+
+impl Serialize for ErrorCode {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u16(self.code())
+    }
+}
+
+impl<'de> Deserialize<'de> for ErrorCode {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let code = u64::deserialize(deserializer)?;
+        ErrorCode::from_code(code).ok_or_else(|| de::Error::custom(format!("unknown error code {}", code)))
+    }
+}
+
+// IAS was down when the server asked for the report, it retries in the background: collect the report
+// with `Client::get_attestation_job`
+#[derive(Fail, Debug)]
+#[fail(display = "The enclave report is pending, attestation job {}", job_id)]
+pub struct AttestationPendingErr {
+    pub job_id: String,
+}
+
+// An audit log that doesn't verify: a broken chain, or a checkpoint the enclave didn't sign
+#[derive(Fail, Debug)]
+#[fail(display = "Error while verifying the audit log = ({})", message)]
+pub struct AuditErr {
+    pub message: String,
+}
+
+// A frame key that can't be one, or an answer whose tag doesn't verify, see `tags`
+#[derive(Fail, Debug)]
+#[fail(display = "Error while authenticating the frames = ({})", message)]
+pub struct TagErr {
+    pub message: String,
+}
+
+// A response the attested enclave didn't sign, or that comes out of order
+#[derive(Fail, Debug)]
+#[fail(display = "Error while verifying the server identity = ({})", message)]
+pub struct IdentityErr {
+    pub message: String,
+}
+
+// An export bundle the attested enclave didn't sign, or that answers another request
+#[derive(Fail, Debug)]
+#[fail(display = "Error while verifying the export bundle = ({})", message)]
+pub struct ExportErr {
+    pub message: String,
+}
+
+// The running enclave isn't the build a published manifest describes
+#[derive(Fail, Debug)]
+#[fail(display = "The enclave doesn't match its manifest = ({})", message)]
+pub struct ManifestErr {
+    pub message: String,
+}
+
+// A signature that doesn't recover to a key of its curve
+#[derive(Fail, Debug)]
+#[fail(display = "Error while recovering the signer = ({})", message)]
+pub struct SignatureErr {
+    pub message: String,
+}
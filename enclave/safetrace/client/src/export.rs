@@ -0,0 +1,148 @@
+use crate::errors::ExportErr;
+use crate::signatures;
+use enigma_crypto::asymmetric::KeyPair;
+use failure::Error;
+use hex::{FromHex, ToHex};
+use serde::{Deserialize, Serialize};
+
+// The exposure statistics a health authority exports (`ExportExposureStatistics`, see `export` in the
+// enclave). The authority signs its request with one of the keys the server trusts,
+//
+//   "safetrace:export:<issuedAt>:<format>:<precision>:<from>:<to>"
+//
+// and the enclave answers with the rows of the geohash cells and days with at least `k` users, signed with
+// its key, the one of the enclave report:
+//
+//   "safetrace:export-bundle:<authority>:<issuedAt>:<format>:<precision>:<from>:<to>:<k>:<generatedAt>:" || body
+//
+// `from` and `to` are days since the Unix epoch (UTC), both included.
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ExportRequest {
+    #[serde(rename = "issuedAt")]
+    pub issued_at: u64,
+    // `csv` or `json`
+    pub format: String,
+    // Geohash characters of the cells
+    pub precision: u8,
+    pub from: i32,
+    pub to: i32,
+    // Of `request_message`, 65 bytes hex
+    pub signature: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ExportBundle {
+    // Address of the health authority that asked
+    pub authority: String,
+    #[serde(rename = "issuedAt")]
+    pub issued_at: u64,
+    pub format: String,
+    pub precision: u8,
+    pub from: i32,
+    pub to: i32,
+    pub k: u64,
+    #[serde(rename = "generatedAt")]
+    pub generated_at: u64,
+    pub rows: usize,
+    // `cell,epoch,users,infected` lines, or the JSON array of those rows. An infected count under `k` is
+    // left empty (null)
+    pub body: String,
+    pub signature: String,
+}
+
+// The answer to `ExportExposureStatistics`
+#[derive(Deserialize, Debug, Clone)]
+pub struct ExportReply {
+    pub bundle: ExportBundle,
+}
+
+fn export_err(message: String) -> Error {
+    ExportErr { message }.into()
+}
+
+pub fn request_message(issued_at: u64, format: &str, precision: u8, from: i32, to: i32) -> Vec<u8> {
+    format!("safetrace:export:{}:{}:{}:{}:{}", issued_at, format, precision, from, to).into_bytes()
+}
+
+pub fn bundle_message(bundle: &ExportBundle) -> Vec<u8> {
+    let mut message = format!("safetrace:export-bundle:{}:{}:{}:{}:{}:{}:{}:{}:", bundle.authority, bundle.issued_at, bundle.format,
+                              bundle.precision, bundle.from, bundle.to, bundle.k, bundle.generated_at).into_bytes();
+    message.extend_from_slice(bundle.body.as_bytes());
+    message
+}
+
+impl ExportRequest {
+    // For the health authority, with one of the keys the server is configured with.
+    pub fn issue(authority_key: &KeyPair, issued_at: u64, format: &str, precision: u8, from: i32, to: i32) -> Result<Self, Error> {
+        let signature = authority_key.sign(&request_message(issued_at, format, precision, from, to))
+            .map_err(|e| export_err(format!("signing failed: {:?}", e)))?;
+        Ok(ExportRequest { issued_at, format: format.to_string(), precision, from, to, signature: signature.to_hex() })
+    }
+}
+
+impl ExportBundle {
+    // Checks the bundle is signed by the attested enclave at `signing_address`, and answers `request`.
+    pub fn verify(&self, request: &ExportRequest, signing_address: &[u8; 20]) -> Result<(), Error> {
+        if (self.issued_at, self.format.as_str(), self.precision, self.from, self.to) !=
+            (request.issued_at, request.format.as_str(), request.precision, request.from, request.to) {
+            return Err(export_err("the bundle doesn't answer the request".to_string()));
+        }
+        let sig: Vec<u8> = self.signature.from_hex().map_err(|_| export_err("the signature isn't hex".to_string()))?;
+        if sig.len() != 65 {
+            return Err(export_err("the signature isn't 65 bytes long".to_string()));
+        }
+        let mut signature = [0u8; 65];
+        signature.copy_from_slice(&sig);
+        if !signatures::signed_by(&bundle_message(self), &signature, signing_address) {
+            return Err(export_err(format!("the bundle isn't signed by the attested enclave {}", signing_address.to_hex())));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use enigma_tools_m::utils::EthereumAddress;
+
+    fn bundle(enclave: &KeyPair, request: &ExportRequest, authority: &KeyPair) -> ExportBundle {
+        let mut bundle = ExportBundle {
+            authority: authority.get_pubkey().address().to_hex(),
+            issued_at: request.issued_at,
+            format: request.format.clone(),
+            precision: request.precision,
+            from: request.from,
+            to: request.to,
+            k: 10,
+            generated_at: request.issued_at + 2,
+            rows: 1,
+            body: "cell,epoch,users,infected\ndr5ru7,18385,12,\n".to_string(),
+            signature: String::new(),
+        };
+        bundle.signature = enclave.sign(&bundle_message(&bundle)).unwrap().to_hex();
+        bundle
+    }
+
+    #[test]
+    fn test_export_bundle_verifies() {
+        let (enclave, authority) = (KeyPair::new().unwrap(), KeyPair::new().unwrap());
+        let request = ExportRequest::issue(&authority, 1589000000, "csv", 6, 18380, 18390).unwrap();
+        let signature: Vec<u8> = request.signature.from_hex().unwrap();
+        let mut sig = [0u8; 65];
+        sig.copy_from_slice(&signature);
+        let signer = KeyPair::recover(&request_message(1589000000, "csv", 6, 18380, 18390), sig).unwrap();
+        assert_eq!(signer.address(), authority.get_pubkey().address());
+
+        let address = enclave.get_pubkey().address();
+        let bundle = bundle(&enclave, &request, &authority);
+        bundle.verify(&request, &address).unwrap();
+
+        // The host can't change the rows, nor hand over the bundle of another request
+        let tampered = ExportBundle { body: "cell,epoch,users,infected\ndr5ru7,18385,12,3\n".to_string(), ..bundle.clone() };
+        assert!(tampered.verify(&request, &address).is_err());
+        let other = ExportRequest::issue(&authority, 1589000000, "csv", 7, 18380, 18390).unwrap();
+        assert!(bundle.verify(&other, &address).is_err());
+        assert!(bundle.verify(&request, &authority.get_pubkey().address()).is_err());
+    }
+}
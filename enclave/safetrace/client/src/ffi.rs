@@ -0,0 +1,384 @@
+// The C API, for the iOS and Android apps: everything but the transport, the apps send the requests over
+// their own HTTP stack (JSON-RPC, see `safetrace_request_to_jsonrpc`). The header is include/safetrace.h,
+// generated with `cbindgen --config cbindgen.toml --output include/safetrace.h` from the client directory.
+//
+// Conventions: strings are NUL terminated UTF-8. Whatever a function returns as `char *`, a `SafetraceBuffer`
+// or a handle is owned by the caller, who frees it with the matching `_free` function. On error, functions
+// return NULL (or a negative status) and `safetrace_last_error` tells why, from the same thread.
+
+use crate::messages::{self, EnclaveReport, EnclaveResult, TaskKey};
+use crate::report::{self, EnclaveIdentity, ReportPolicy, Trust};
+use crate::session::Session;
+use enigma_crypto::asymmetric::KeyPair;
+use failure::{format_err, Error};
+use hex::ToHex;
+use serde_json::Value;
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::panic::{self, AssertUnwindSafe};
+use std::{ptr, slice};
+
+// Returned instead of a status, -1 is the enclave's own `Failed`
+pub const SAFETRACE_ERROR: i32 = -2;
+
+// The user key pair, the key the task keys are exchanged with.
+pub struct SafetraceKeyPair(KeyPair);
+
+// An enclave whose report passed the policy.
+pub struct SafetraceEnclave(EnclaveIdentity);
+
+// A key exchanged with the enclave, for a single request.
+pub struct SafetraceSession(Session);
+
+#[repr(C)]
+pub struct SafetraceBuffer {
+    pub data: *mut u8,
+    pub len: usize,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<String>> = RefCell::new(None);
+}
+
+fn set_last_error(message: String) {
+    LAST_ERROR.with(|last| *last.borrow_mut() = Some(message));
+}
+
+// Runs `f`, keeping its error (or panic, which mustn't unwind into C) for `safetrace_last_error`.
+fn guard<T, F: FnOnce() -> Result<T, Error>>(f: F, on_error: T) -> T {
+    match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => value,
+        Ok(Err(e)) => {
+            set_last_error(e.to_string());
+            on_error
+        },
+        Err(_) => {
+            set_last_error("panic in safetrace-client".to_string());
+            on_error
+        },
+    }
+}
+
+unsafe fn read_str<'a>(s: *const c_char, name: &str) -> Result<&'a str, Error> {
+    if s.is_null() {
+        return Err(format_err!("{} is NULL", name));
+    }
+    CStr::from_ptr(s).to_str().map_err(|_| format_err!("{} isn't UTF-8", name))
+}
+
+unsafe fn read_ref<'a, T>(handle: *const T, name: &str) -> Result<&'a T, Error> {
+    handle.as_ref().ok_or_else(|| format_err!("{} is NULL", name))
+}
+
+fn into_c_string(s: String) -> Result<*mut c_char, Error> {
+    Ok(CString::new(s)?.into_raw())
+}
+
+fn into_handle<T>(value: T) -> *mut T { Box::into_raw(Box::new(value)) }
+
+// Why the last call failed on this thread, NULL if it didn't. Free with `safetrace_string_free`.
+#[no_mangle]
+pub extern "C" fn safetrace_last_error() -> *mut c_char {
+    LAST_ERROR.with(|last| match last.borrow().as_ref() {
+        Some(message) => CString::new(message.replace('\0', " ")).map(CString::into_raw).unwrap_or(ptr::null_mut()),
+        None => ptr::null_mut(),
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn safetrace_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn safetrace_buffer_free(buffer: SafetraceBuffer) {
+    if !buffer.data.is_null() {
+        drop(Box::from_raw(slice::from_raw_parts_mut(buffer.data, buffer.len)));
+    }
+}
+
+// A new random key pair.
+#[no_mangle]
+pub extern "C" fn safetrace_keypair_new() -> *mut SafetraceKeyPair {
+    guard(|| Ok(into_handle(SafetraceKeyPair(KeyPair::new().map_err(|e| format_err!("{:?}", e))?))), ptr::null_mut())
+}
+
+// The key pair of a 32 bytes secret key, e.g. kept in the keychain.
+#[no_mangle]
+pub unsafe extern "C" fn safetrace_keypair_from_secret(secret: *const u8, len: usize) -> *mut SafetraceKeyPair {
+    guard(|| {
+        if secret.is_null() || len != 32 {
+            return Err(format_err!("the secret key must be 32 bytes long"));
+        }
+        let mut key = [0u8; 32];
+        key.copy_from_slice(slice::from_raw_parts(secret, len));
+        Ok(into_handle(SafetraceKeyPair(KeyPair::from_slice(&key).map_err(|e| format_err!("{:?}", e))?)))
+    }, ptr::null_mut())
+}
+
+// The `userPubKey` of the requests, hex.
+#[no_mangle]
+pub unsafe extern "C" fn safetrace_keypair_pubkey(keypair: *const SafetraceKeyPair) -> *mut c_char {
+    guard(|| into_c_string(read_ref(keypair, "keypair")?.0.get_pubkey().to_hex()), ptr::null_mut())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn safetrace_keypair_free(keypair: *mut SafetraceKeyPair) {
+    if !keypair.is_null() {
+        drop(Box::from_raw(keypair));
+    }
+}
+
+// Verifies the reply to `getEnclaveReport`, the native one or its JSON-RPC `result`. `root_ca` is the PEM of
+// Intel's Attestation Report Signing CA, NULL only for a server built in simulation mode. `mr_enclave` is NULL
+// or the 32 bytes MRENCLAVE the report must be for.
+#[no_mangle]
+pub unsafe extern "C" fn safetrace_verify_report(response: *const c_char, root_ca: *const c_char, mr_enclave: *const u8) -> *mut SafetraceEnclave {
+    guard(|| {
+        let response: Value = serde_json::from_str(read_str(response, "response")?)?;
+        let trust = if root_ca.is_null() { Trust::Simulation } else { Trust::Ias { root_ca: read_str(root_ca, "root_ca")?.to_string() } };
+        let mut policy = ReportPolicy::new(trust);
+        if !mr_enclave.is_null() {
+            let mut expected = [0u8; 32];
+            expected.copy_from_slice(slice::from_raw_parts(mr_enclave, 32));
+            policy = policy.with_mr_enclave(expected);
+        }
+        let report: EnclaveReport = messages::parse_response("GetEnclaveReport", &response)?;
+        let signing_address = report::parse_signing_address(&report.signing_key)?;
+        Ok(into_handle(SafetraceEnclave(report::verify_enclave(&report.to_bundle()?, &signing_address, &policy)?)))
+    }, ptr::null_mut())
+}
+
+// The MRENCLAVE of the verified enclave, hex.
+#[no_mangle]
+pub unsafe extern "C" fn safetrace_enclave_mr_enclave(enclave: *const SafetraceEnclave) -> *mut c_char {
+    guard(|| into_c_string(read_ref(enclave, "enclave")?.0.mr_enclave.to_hex()), ptr::null_mut())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn safetrace_enclave_free(enclave: *mut SafetraceEnclave) {
+    if !enclave.is_null() {
+        drop(Box::from_raw(enclave));
+    }
+}
+
+// The request for a task key, native JSON.
+#[no_mangle]
+pub unsafe extern "C" fn safetrace_task_key_request(keypair: *const SafetraceKeyPair) -> *mut c_char {
+    guard(|| {
+        let pubkey = read_ref(keypair, "keypair")?.0.get_pubkey().to_hex();
+        into_c_string(messages::new_task_encryption_key(&messages::new_id(), &pubkey).to_string())
+    }, ptr::null_mut())
+}
+
+// Checks that the reply to `newTaskEncryptionKey` is signed by `enclave`, and derives the session key.
+#[no_mangle]
+pub unsafe extern "C" fn safetrace_session_new(keypair: *const SafetraceKeyPair, enclave: *const SafetraceEnclave, response: *const c_char) -> *mut SafetraceSession {
+    guard(|| {
+        let keypair = read_ref(keypair, "keypair")?;
+        let enclave = read_ref(enclave, "enclave")?;
+        let response: Value = serde_json::from_str(read_str(response, "response")?)?;
+        let key: TaskKey = messages::parse_response("NewTaskEncryptionKey", &response)?;
+        Ok(into_handle(SafetraceSession(Session::new(&keypair.0, &key.taskPubKey, &key.sig, &enclave.0.signing_address)?)))
+    }, ptr::null_mut())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn safetrace_session_free(session: *mut SafetraceSession) {
+    if !session.is_null() {
+        drop(Box::from_raw(session));
+    }
+}
+
+// The `addPersonalData` request, native JSON. `locations` is the JSON array of
+// `{"lat", "lng", "startTS", "endTS", "testResult"}`, checked before it's encrypted along with a nonce and the time.
+#[no_mangle]
+pub unsafe extern "C" fn safetrace_add_personal_data_request(session: *const SafetraceSession, keypair: *const SafetraceKeyPair, user_id: *const c_char, locations: *const c_char) -> *mut c_char {
+    safetrace_add_declared_personal_data_request(session, keypair, user_id, locations, ptr::null())
+}
+
+// The same with the declaration of a health authority, the JSON `{"issuedAt", "signature"}` the authority
+// issued to the user, or NULL.
+#[no_mangle]
+pub unsafe extern "C" fn safetrace_add_declared_personal_data_request(session: *const SafetraceSession, keypair: *const SafetraceKeyPair, user_id: *const c_char, locations: *const c_char, declaration: *const c_char) -> *mut c_char {
+    guard(|| {
+        let session = &read_ref(session, "session")?.0;
+        let pubkey = read_ref(keypair, "keypair")?.0.get_pubkey().to_hex();
+        let locations: Vec<messages::Location> = serde_json::from_str(read_str(locations, "locations")?)?;
+        let declaration: Option<messages::Declaration> = if declaration.is_null() {
+            None
+        } else {
+            Some(serde_json::from_str(read_str(declaration, "declaration")?)?)
+        };
+        let request = messages::add_personal_data(
+            &messages::new_id(),
+            &session.encrypt(read_str(user_id, "user_id")?.as_bytes())?,
+            &session.encrypt(&serde_json::to_vec(&messages::personal_data(&locations, messages::now(), declaration.as_ref()))?)?,
+            &pubkey,
+        );
+        into_c_string(request.to_string())
+    }, ptr::null_mut())
+}
+
+// The `findMatch` request, native JSON.
+#[no_mangle]
+pub unsafe extern "C" fn safetrace_find_match_request(session: *const SafetraceSession, keypair: *const SafetraceKeyPair, user_id: *const c_char) -> *mut c_char {
+    guard(|| {
+        let session = &read_ref(session, "session")?.0;
+        let pubkey = read_ref(keypair, "keypair")?.0.get_pubkey().to_hex();
+        let encrypted_userid = session.encrypt(read_str(user_id, "user_id")?.as_bytes())?;
+        into_c_string(messages::find_match(&messages::new_id(), &encrypted_userid, &pubkey).to_string())
+    }, ptr::null_mut())
+}
+
+// The `registerUser` request, native JSON: registers `signing_keypair` for the user, the later requests of
+// the user must then be signed with it (`safetrace_sign_request`).
+#[no_mangle]
+pub unsafe extern "C" fn safetrace_register_user_request(session: *const SafetraceSession, keypair: *const SafetraceKeyPair, signing_keypair: *const SafetraceKeyPair, user_id: *const c_char) -> *mut c_char {
+    guard(|| {
+        let session = &read_ref(session, "session")?.0;
+        let user_key = &read_ref(keypair, "keypair")?.0;
+        let signing_key = &read_ref(signing_keypair, "signing_keypair")?.0;
+        let request = messages::register_user(
+            &messages::new_id(),
+            &session.encrypt(read_str(user_id, "user_id")?.as_bytes())?,
+            &session.registration(signing_key, &user_key.get_pubkey())?,
+            &user_key.get_pubkey().to_hex(),
+        );
+        into_c_string(request.to_string())
+    }, ptr::null_mut())
+}
+
+// Signs an `addPersonalData` or `findMatch` native request with the registered key, in the same session.
+#[no_mangle]
+pub unsafe extern "C" fn safetrace_sign_request(session: *const SafetraceSession, signing_keypair: *const SafetraceKeyPair, request: *const c_char) -> *mut c_char {
+    guard(|| {
+        let session = &read_ref(session, "session")?.0;
+        let signing_key = &read_ref(signing_keypair, "signing_keypair")?.0;
+        let request: Value = serde_json::from_str(read_str(request, "request")?)?;
+        into_c_string(messages::sign(request, session, signing_key)?.to_string())
+    }, ptr::null_mut())
+}
+
+// The body of the JSON-RPC call to the api-server for a native request.
+#[no_mangle]
+pub unsafe extern "C" fn safetrace_request_to_jsonrpc(request: *const c_char) -> *mut c_char {
+    guard(|| {
+        let request: Value = serde_json::from_str(read_str(request, "request")?)?;
+        let (method, params) = messages::to_jsonrpc(&request);
+        let body = serde_json::json!({"jsonrpc": "2.0", "id": request["id"], "method": method, "params": params});
+        into_c_string(body.to_string())
+    }, ptr::null_mut())
+}
+
+// Decrypts the output of the reply to `addPersonalData` or `findMatch`: the receipt JSON, or the JSON array
+// of matching locations. An authentic output only decrypts with the session key, anything else is an error.
+// Returns the enclave `status` (0 passed, -1 failed), `output` is left empty when there's none.
+#[no_mangle]
+pub unsafe extern "C" fn safetrace_session_open_response(session: *const SafetraceSession, request_type: *const c_char, response: *const c_char, output: *mut SafetraceBuffer) -> i32 {
+    guard(|| {
+        let session = &read_ref(session, "session")?.0;
+        let response: Value = serde_json::from_str(read_str(response, "response")?)?;
+        let result: EnclaveResult = messages::parse_response(read_str(request_type, "request_type")?, &response)?;
+        let mut plaintext = if result.encryptedOutput.is_empty() { Vec::new() } else { session.decrypt(&result.encryptedOutput)? };
+        // The enclave pads its outputs with spaces
+        while plaintext.last() == Some(&b' ') {
+            plaintext.pop();
+        }
+        if !output.is_null() {
+            let len = plaintext.len();
+            let data = Box::into_raw(plaintext.into_boxed_slice()) as *mut u8;
+            *output = SafetraceBuffer { data, len };
+        }
+        Ok(i32::from(result.status))
+    }, SAFETRACE_ERROR)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::report::test::quote_body;
+    use crate::session::test::enclave_task_key;
+    use enigma_crypto::symmetric;
+    use enigma_tools_m::utils::EthereumAddress;
+    use hex::FromHex;
+    use serde_json::json;
+
+    fn c(s: &str) -> CString { CString::new(s).unwrap() }
+
+    unsafe fn take_string(s: *mut c_char) -> String {
+        assert!(!s.is_null());
+        let owned = CStr::from_ptr(s).to_str().unwrap().to_string();
+        safetrace_string_free(s);
+        owned
+    }
+
+    #[test]
+    fn test_ffi_flow() {
+        unsafe {
+            let signing_key = KeyPair::new().unwrap();
+            let address = signing_key.get_pubkey().address();
+            let report = json!({"id": "a1b2c3d4e5", "type": "GetEnclaveReport", "result": {
+                "signingKey": address.to_hex(), "report": quote_body(1, &address).as_bytes().to_hex(), "signature": "",
+            }});
+            let enclave = safetrace_verify_report(c(&report.to_string()).as_ptr(), ptr::null(), [1u8; 32].as_ptr());
+            assert!(!enclave.is_null());
+            assert_eq!(take_string(safetrace_enclave_mr_enclave(enclave)), [1u8; 32].to_hex());
+
+            let keypair = safetrace_keypair_from_secret([7u8; 32].as_ptr(), 32);
+            let user_pubkey: Vec<u8> = take_string(safetrace_keypair_pubkey(keypair)).from_hex().unwrap();
+            let mut pubkey = [0u8; 64];
+            pubkey.copy_from_slice(&user_pubkey);
+            let (task_pubkey, sig, key) = enclave_task_key(&signing_key, &pubkey);
+            let task_key = json!({"id": "1", "type": "NewTaskEncryptionKey", "result": {"taskPubKey": task_pubkey, "sig": sig}});
+            let session = safetrace_session_new(keypair, enclave, c(&task_key.to_string()).as_ptr());
+            assert!(!session.is_null());
+
+            let request = take_string(safetrace_find_match_request(session, keypair, c("user1").as_ptr()));
+            let signing_keypair = safetrace_keypair_new();
+            let signed: Value = serde_json::from_str(&take_string(safetrace_sign_request(session, signing_keypair, c(&request).as_ptr()))).unwrap();
+            assert!(signed["input"]["encryptedSignature"].is_string());
+            safetrace_keypair_free(signing_keypair);
+            let body: Value = serde_json::from_str(&take_string(safetrace_request_to_jsonrpc(c(&request).as_ptr()))).unwrap();
+            assert_eq!(body["method"], "findMatch");
+            let encrypted_userid: Vec<u8> = body["params"]["encryptedUserId"].as_str().unwrap().from_hex().unwrap();
+            assert_eq!(symmetric::decrypt(&encrypted_userid, &key).unwrap(), b"user1".to_vec());
+
+            let output = symmetric::encrypt(b"[]   ", &key).unwrap().to_hex();
+            let response = json!({"id": "1", "type": "FindMatch", "findMatch": {"status": 0, "encryptedOutput": output}});
+            let mut buffer = SafetraceBuffer { data: ptr::null_mut(), len: 0 };
+            assert_eq!(safetrace_session_open_response(session, c("FindMatch").as_ptr(), c(&response.to_string()).as_ptr(), &mut buffer), 0);
+            assert_eq!(slice::from_raw_parts(buffer.data, buffer.len), b"[]");
+            safetrace_buffer_free(buffer);
+
+            safetrace_session_free(session);
+            safetrace_keypair_free(keypair);
+            safetrace_enclave_free(enclave);
+        }
+    }
+
+    #[test]
+    fn test_ffi_errors() {
+        unsafe {
+            assert!(safetrace_keypair_from_secret(ptr::null(), 32).is_null());
+            assert!(take_string(safetrace_last_error()).contains("32 bytes"));
+            // Signed by another enclave
+            let keypair = safetrace_keypair_new();
+            let signing_key = KeyPair::new().unwrap();
+            let address = signing_key.get_pubkey().address();
+            let report = json!({"id": "1", "type": "GetEnclaveReport", "result": {
+                "signingKey": address.to_hex(), "report": quote_body(1, &address).as_bytes().to_hex(), "signature": "",
+            }});
+            let enclave = safetrace_verify_report(c(&report.to_string()).as_ptr(), ptr::null(), ptr::null());
+            let (task_pubkey, sig, _) = enclave_task_key(&KeyPair::new().unwrap(), &KeyPair::new().unwrap().get_pubkey());
+            let task_key = json!({"id": "1", "type": "NewTaskEncryptionKey", "result": {"taskPubKey": task_pubkey, "sig": sig}});
+            assert!(safetrace_session_new(keypair, enclave, c(&task_key.to_string()).as_ptr()).is_null());
+            assert!(!take_string(safetrace_last_error()).is_empty());
+            safetrace_keypair_free(keypair);
+            safetrace_enclave_free(enclave);
+        }
+    }
+}
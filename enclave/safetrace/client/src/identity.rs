@@ -0,0 +1,166 @@
+use crate::errors::IdentityErr;
+use crate::signatures;
+use failure::Error;
+use hex::{FromHex, ToHex};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+// The identity block of the server responses. The host relays every answer of the enclave, and could
+// answer in its place: the `identity` of a response is the enclave vouching for it. It signs
+//
+//   "safetrace-response" || boot || counter || SHA-256(JSON of the response without `identity`)
+//
+// with its signing key, the one of the enclave report (`counter` 8 bytes big endian). `counter` goes up by
+// one with every response the enclave signs, whoever asked, so a response can't be played again in the
+// place of a later one. It lives in enclave memory: `boot` is drawn anew when the enclave starts, and the
+// counter starts over with it. A client keeps the last counter of each boot in `ResponseCounter`.
+
+const RESPONSE_TAG: &[u8] = b"safetrace-response";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ServerIdentity {
+    // Address of the enclave signing key, as in the report
+    #[serde(rename = "signingKey")] pub signing_key: String,
+    // 16 bytes hex
+    pub boot: String,
+    pub counter: u64,
+    pub signature: String,
+}
+
+fn identity_err(message: String) -> Error {
+    IdentityErr { message }.into()
+}
+
+fn bytes_from_hex(what: &str, hex: &str, len: usize) -> Result<Vec<u8>, Error> {
+    let bytes: Vec<u8> = hex.from_hex().map_err(|_| identity_err(format!("{} isn't hex", what)))?;
+    if bytes.len() != len {
+        return Err(identity_err(format!("{} isn't {} bytes long", what, len)));
+    }
+    Ok(bytes)
+}
+
+// What the enclave signs the digest of: the response as it was sent, `identity` aside.
+pub fn digest(response: &Value) -> [u8; 32] {
+    let mut response = response.clone();
+    if let Some(fields) = response.as_object_mut() {
+        fields.remove("identity");
+    }
+    let mut digest = [0u8; 32];
+    digest.copy_from_slice(&Sha256::digest(response.to_string().as_bytes()));
+    digest
+}
+
+// The bytes the enclave signs.
+pub fn response_message(boot: &[u8; 16], counter: u64, digest: &[u8; 32]) -> Vec<u8> {
+    let mut message = RESPONSE_TAG.to_vec();
+    message.extend_from_slice(boot);
+    message.extend_from_slice(&counter.to_be_bytes());
+    message.extend_from_slice(digest);
+    message
+}
+
+impl ServerIdentity {
+    // The `identity` of `response`, `None` for the servers that don't sign their responses.
+    pub fn of(response: &Value) -> Result<Option<Self>, Error> {
+        match response.get("identity") {
+            None | Some(Value::Null) => Ok(None),
+            Some(identity) => Ok(Some(serde_json::from_value(identity.clone())
+                .map_err(|e| identity_err(format!("malformed identity: {}", e)))?)),
+        }
+    }
+
+    pub fn boot(&self) -> Result<[u8; 16], Error> {
+        let mut boot = [0u8; 16];
+        boot.copy_from_slice(&bytes_from_hex("boot", &self.boot, 16)?);
+        Ok(boot)
+    }
+
+    // Checks the signature over `response` is the one of `signing_address`.
+    pub fn verify(&self, response: &Value, signing_address: &[u8; 20]) -> Result<(), Error> {
+        let mut signature = [0u8; 65];
+        signature.copy_from_slice(&bytes_from_hex("the signature", &self.signature, 65)?);
+        let message = response_message(&self.boot()?, self.counter, &digest(response));
+        if !signatures::signed_by(&message, &signature, signing_address) {
+            return Err(identity_err(format!("response {} isn't signed by the attested enclave {}", self.counter, signing_address.to_hex())));
+        }
+        Ok(())
+    }
+}
+
+// The counters a client saw. A response must come after the last one of its boot, and a boot the client
+// moved on from doesn't come back.
+#[derive(Debug, Clone, Default)]
+pub struct ResponseCounter {
+    current: Option<([u8; 16], u64)>,
+    past: Vec<[u8; 16]>,
+}
+
+impl ResponseCounter {
+    pub fn check(&mut self, identity: &ServerIdentity) -> Result<(), Error> {
+        let boot = identity.boot()?;
+        if self.past.contains(&boot) {
+            return Err(identity_err(format!("response {} is of an enclave that restarted since", identity.counter)));
+        }
+        match self.current {
+            Some((current, last)) if current == boot && identity.counter <= last => {
+                return Err(identity_err(format!("response {} comes after response {}", identity.counter, last)));
+            },
+            Some((current, _)) if current != boot => self.past.push(current),
+            _ => {},
+        }
+        self.current = Some((boot, identity.counter));
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use enigma_crypto::asymmetric::KeyPair;
+    use enigma_tools_m::utils::EthereumAddress;
+    use serde_json::json;
+
+    pub(crate) fn sign(key: &KeyPair, response: &mut Value, boot: [u8; 16], counter: u64) {
+        let signature = key.sign(&response_message(&boot, counter, &digest(response))).unwrap();
+        response["identity"] = serde_json::to_value(ServerIdentity {
+            signing_key: key.get_pubkey().address().to_hex(), boot: boot.to_hex(), counter, signature: signature.to_hex(),
+        }).unwrap();
+    }
+
+    #[test]
+    fn test_server_identity() {
+        let key = KeyPair::new().unwrap();
+        let address = key.get_pubkey().address();
+        let mut response = json!({"id": "a1b2c3d4e5", "type": "Ping", "result": {"pong": true, "time": 1.5}});
+        assert_eq!(ServerIdentity::of(&response).unwrap(), None);
+        sign(&key, &mut response, [1; 16], 7);
+        let identity = ServerIdentity::of(&response).unwrap().unwrap();
+        identity.verify(&response, &address).unwrap();
+        // The keys of the document don't have to come in any order
+        let reordered: Value = serde_json::from_str(&serde_json::to_string(&response).unwrap()).unwrap();
+        identity.verify(&reordered, &address).unwrap();
+
+        assert!(identity.verify(&response, &[0u8; 20]).is_err());
+        let mut substituted = response.clone();
+        substituted["result"]["pong"] = json!(false);
+        assert!(identity.verify(&substituted, &address).is_err());
+        let mut replayed = identity.clone();
+        replayed.counter = 8;
+        assert!(replayed.verify(&response, &address).is_err());
+        assert!(ServerIdentity::of(&json!({"identity": {"counter": 1}})).is_err());
+    }
+
+    #[test]
+    fn test_response_counter() {
+        let identity = |boot: u8, counter: u64| ServerIdentity { signing_key: String::new(), boot: [boot; 16].to_hex(), counter, signature: String::new() };
+        let mut counter = ResponseCounter::default();
+        counter.check(&identity(1, 5)).unwrap();
+        counter.check(&identity(1, 9)).unwrap();
+        assert!(counter.check(&identity(1, 9)).is_err());
+        assert!(counter.check(&identity(1, 3)).is_err());
+        // The enclave restarted
+        counter.check(&identity(2, 1)).unwrap();
+        assert!(counter.check(&identity(1, 10)).is_err());
+    }
+}
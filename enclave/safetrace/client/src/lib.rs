@@ -0,0 +1,50 @@
+//! A client for SafeTrace servers, for Rust backends that shouldn't reimplement the wire format:
+//! verifying the enclave report, the key exchange with the enclave, the encrypted payloads,
+//! over the ZMQ socket of the app or the JSON-RPC api-server.
+//!
+//! ```ignore
+//! let policy = ReportPolicy::new(Trust::intel()).with_mr_enclave(mr_enclave);
+//! let client = Client::new(HttpTransport::new("http://localhost:8080")?, policy, KeyPair::new()?);
+//! client.add_personal_data("user1", &locations)?;
+//! let matches = client.find_match("user1")?;
+//! ```
+
+pub mod archive;
+pub mod audit;
+pub mod bundle;
+pub mod client;
+pub mod errors;
+pub mod export;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod identity;
+pub mod manifest;
+pub mod messages;
+pub mod quote;
+pub mod report;
+pub mod session;
+pub mod signatures;
+pub mod tags;
+pub mod transport;
+pub mod verify;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+pub mod wire;
+pub mod x509;
+
+pub use crate::archive::{verify_archive, verify_reports_batch, AttestationArchive, AttestationRecord};
+pub use crate::bundle::ReportBundle;
+pub use crate::client::Client;
+pub use crate::export::{ExportBundle, ExportRequest};
+pub use crate::manifest::EnclaveManifest;
+pub use crate::messages::{Declaration, Location, Receipt};
+pub use crate::quote::Quote;
+pub use crate::report::{verify_enclave, EnclaveIdentity, ReportPolicy, Trust};
+pub use crate::session::Session;
+pub use crate::signatures::Curve;
+pub use crate::tags::FrameKey;
+pub use crate::transport::Transport;
+pub use crate::verify::{verify_report, VerifiedReport, INTEL_ROOT_CA};
+pub use crate::wire::WireFormat;
+#[cfg(feature = "transport")]
+pub use crate::transport::{HttpTransport, ZmqTransport};
@@ -0,0 +1,119 @@
+use crate::errors::ManifestErr;
+use crate::quote::Quote;
+use failure::Error;
+use serde::{Deserialize, Serialize};
+
+// A published description of an enclave build: the measurements a reproducible build of `build.commit` gives,
+// so users can rebuild the enclave, compare, then check the running one against them (`check`, or
+// `Client::verify_manifest`). `safetrace-app manifest` writes it, `GetEnclaveInfo` serves it (`enclave.manifest`):
+//
+//   {"version": 1, "mrEnclave": "...", "mrSigner": "...", "isvProdId": 0, "isvSvn": 0, "debug": false,
+//    "build": {"source": "https://github.com/...", "commit": "...", "toolchain": "...", "sgxSdk": "...", "enclaveSha256": "..."}}
+//
+// Only the measurements are vouched for by the quote, `build` says how to get the same binary.
+
+pub const MANIFEST_VERSION: u32 = 1;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct EnclaveManifest {
+    pub version: u32,
+    // hex
+    #[serde(rename = "mrEnclave")] pub mr_enclave: String,
+    #[serde(rename = "mrSigner")] pub mr_signer: String,
+    #[serde(rename = "isvProdId")] pub isv_prod_id: u16,
+    #[serde(rename = "isvSvn")] pub isv_svn: u16,
+    pub debug: bool,
+    #[serde(default)]
+    pub build: BuildProvenance,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct BuildProvenance {
+    // The repository and commit the enclave was built from
+    #[serde(default)] pub source: String,
+    #[serde(default)] pub commit: String,
+    // Rust toolchain and SGX SDK versions
+    #[serde(default)] pub toolchain: String,
+    #[serde(rename = "sgxSdk", default)] pub sgx_sdk: String,
+    // SHA-256 of enclave.signed.so, hex
+    #[serde(rename = "enclaveSha256", default)] pub enclave_sha256: String,
+}
+
+fn manifest_err(message: String) -> Error {
+    ManifestErr { message }.into()
+}
+
+impl EnclaveManifest {
+    // The manifest of the enclave `quote` is from.
+    pub fn from_quote(quote: &Quote, build: BuildProvenance) -> Self {
+        let body = &quote.report_body;
+        EnclaveManifest {
+            version: MANIFEST_VERSION,
+            mr_enclave: body.mr_enclave_hex(),
+            mr_signer: body.mr_signer_hex(),
+            isv_prod_id: body.isv_prod_id(),
+            isv_svn: body.isv_svn(),
+            debug: body.attributes().is_debug(),
+            build,
+        }
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, Error> {
+        let manifest: EnclaveManifest = serde_json::from_str(json).map_err(|e| manifest_err(format!("malformed manifest: {}", e)))?;
+        if manifest.version != MANIFEST_VERSION {
+            return Err(manifest_err(format!("unsupported manifest version {}", manifest.version)));
+        }
+        Ok(manifest)
+    }
+
+    // Every measurement of the quote must be the one of the manifest. Verify the report the quote comes from first.
+    pub fn check(&self, quote: &Quote) -> Result<(), Error> {
+        let running = EnclaveManifest::from_quote(quote, BuildProvenance::default());
+        let mut mismatches = Vec::new();
+        if !running.mr_enclave.eq_ignore_ascii_case(&self.mr_enclave) {
+            mismatches.push(format!("MRENCLAVE is {}", running.mr_enclave));
+        }
+        if !running.mr_signer.eq_ignore_ascii_case(&self.mr_signer) {
+            mismatches.push(format!("MRSIGNER is {}", running.mr_signer));
+        }
+        if running.isv_prod_id != self.isv_prod_id {
+            mismatches.push(format!("ISV product id is {}", running.isv_prod_id));
+        }
+        if running.isv_svn != self.isv_svn {
+            mismatches.push(format!("ISV SVN is {}", running.isv_svn));
+        }
+        if running.debug != self.debug {
+            mismatches.push(format!("debug is {}", running.debug));
+        }
+        if !mismatches.is_empty() {
+            return Err(manifest_err(mismatches.join(", ")));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::report::test::quote_body;
+
+    #[test]
+    fn test_manifest_check() {
+        let quote = Quote::from_base64(&quote_body(1, &[7u8; 20])).unwrap();
+        let build = BuildProvenance { commit: "0123abcd".to_string(), ..Default::default() };
+        let manifest = EnclaveManifest::from_quote(&quote, build);
+        assert_eq!((manifest.mr_enclave.as_str(), manifest.debug), ("01".repeat(32).as_str(), false));
+        manifest.check(&quote).unwrap();
+
+        let json = serde_json::to_string(&manifest).unwrap();
+        assert!(json.starts_with(r#"{"version":1,"mrEnclave":"0101"#));
+        let mut published = EnclaveManifest::from_json(&json).unwrap();
+        assert_eq!(published, manifest);
+
+        published.mr_enclave = "02".repeat(32);
+        published.isv_svn = 2;
+        let e = published.check(&quote).unwrap_err().to_string();
+        assert!(e.contains("MRENCLAVE") && e.contains("ISV SVN is 0"), "{}", e);
+        assert!(EnclaveManifest::from_json(&json.replace(r#""version":1"#, r#""version":2"#)).is_err());
+    }
+}
@@ -0,0 +1,639 @@
+use crate::bundle::ReportBundle;
+use crate::errors::{ErrorCode, ServerErr, SessionErr};
+use crate::export::ExportRequest;
+use crate::manifest::EnclaveManifest;
+use crate::session::Session;
+use enigma_crypto::asymmetric::KeyPair;
+use failure::Error;
+use hex::{FromHex, ToHex};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+// The messages of the server (see `networking::messages` in the app), as the native envelope:
+// `{"id": ..., "type": "FindMatch", ...}`. `to_jsonrpc` turns them into the calls of the api-server.
+
+// The protocol version this client speaks, sent in `GetProtocolVersion`
+pub const CLIENT_VERSION: u32 = 5;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[allow(non_snake_case)]
+pub struct Location {
+    pub lat: f64,
+    pub lng: f64,
+    pub startTS: i32,
+    pub endTS: i32,
+    pub testResult: bool,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct ProtocolVersion {
+    pub version: u32,
+    #[serde(rename = "minVersion")]
+    pub min_version: u32,
+    pub compatible: bool,
+    pub commands: Vec<String>,
+    #[serde(default)]
+    pub capabilities: Vec<String>,
+    #[serde(rename = "mrEnclave", default)]
+    pub mr_enclave: String,
+    // How coarse the server stores the locations, none when it stores them as sent
+    #[serde(default)]
+    pub quantization: Option<Quantization>,
+    // Bytes a frame may take, larger ones are refused with `PayloadTooLarge`
+    #[serde(rename = "maxFrameSize", default)]
+    pub max_frame_size: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Quantization {
+    // Degrees
+    pub grid: f64,
+    // Seconds
+    #[serde(rename = "timeBucket")]
+    pub time_bucket: u64,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct EnclaveReport {
+    #[serde(rename = "signingKey")]
+    pub signing_key: String,
+    // hex
+    pub report: String,
+    pub signature: String,
+    #[serde(default)]
+    pub certificate: String,
+    #[serde(default)]
+    pub ca: String,
+}
+
+// The answer to `GetEnclaveReport`: the report, or the job of a server that couldn't reach IAS.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum ReportReply {
+    Pending { job: AttestationJob },
+    Report(EnclaveReport),
+}
+
+// `state` is `Pending`, `Done` with the `report`, or `Failed` with the `error` of IAS
+#[derive(Deserialize, Debug, Clone)]
+pub struct AttestationJob {
+    #[serde(rename = "jobId")]
+    pub job_id: String,
+    pub state: String,
+    pub attempts: u32,
+    #[serde(default)]
+    pub error: Option<String>,
+    #[serde(default)]
+    pub report: Option<EnclaveReport>,
+}
+
+// The answer to `GetAttestationJob`
+#[derive(Deserialize, Debug, Clone)]
+pub struct JobReply {
+    pub job: AttestationJob,
+}
+
+// The answer to `GetCachedReport` (capability `report-cache`): the report IAS gave for a quote the server sent it,
+// `cachedAt` in seconds. `to_bundle` checks nothing, verify it like the report of `GetEnclaveReport`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct CachedReport {
+    #[serde(rename = "quoteHash")]
+    pub quote_hash: String,
+    // base64
+    pub quote: String,
+    // See `bundle`
+    pub bundle: String,
+    #[serde(rename = "cachedAt")]
+    pub cached_at: u64,
+}
+
+impl CachedReport {
+    pub fn to_bundle(&self) -> Result<ReportBundle, Error> {
+        ReportBundle::from_compact(&self.bundle)
+    }
+}
+
+// The answer to `Heartbeat` (capability `heartbeats`): the client's `sentAt` echoed, with the times the server
+// received and answered it, all in milliseconds.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct HeartbeatReply {
+    #[serde(rename = "sentAt")]
+    pub sent_at: u64,
+    #[serde(rename = "receivedAt")]
+    pub received_at: u64,
+    #[serde(rename = "repliedAt")]
+    pub replied_at: u64,
+    // Seconds the server keeps a connection that goes without traffic, send heartbeats more often than that
+    #[serde(rename = "idleTimeout")]
+    pub idle_timeout: u64,
+}
+
+impl HeartbeatReply {
+    // The round trip of the heartbeat answered at `now`, without the time the server held it.
+    pub fn round_trip(&self, now: u64) -> u64 {
+        now.saturating_sub(self.sent_at).saturating_sub(self.replied_at.saturating_sub(self.received_at))
+    }
+
+    // How far ahead of the client's clock the server's is, as NTP estimates it.
+    pub fn clock_offset(&self, now: u64) -> i64 {
+        ((self.received_at as i64 - self.sent_at as i64) + (self.replied_at as i64 - now as i64)) / 2
+    }
+}
+
+// The measurements of the running enclave and the manifest the server publishes, see `manifest`. Only the
+// quote of `bundle` vouches for them: `Client::verify_manifest` checks the manifest against the verified report.
+#[derive(Deserialize, Debug, Clone)]
+pub struct EnclaveInfo {
+    #[serde(rename = "mrEnclave")]
+    pub mr_enclave: String,
+    #[serde(rename = "mrSigner")]
+    pub mr_signer: String,
+    #[serde(rename = "isvProdId")]
+    pub isv_prod_id: u16,
+    #[serde(rename = "isvSvn")]
+    pub isv_svn: u16,
+    pub debug: bool,
+    #[serde(rename = "signingKey")]
+    pub signing_key: String,
+    #[serde(default)]
+    pub bundle: String,
+    #[serde(default)]
+    pub manifest: Option<EnclaveManifest>,
+    #[serde(rename = "manifestMatches", default)]
+    pub manifest_matches: Option<bool>,
+    // The settings the enclave enforces, as it hashed them: check them with `report::verify_settings`
+    #[serde(default)]
+    pub settings: Option<String>,
+}
+
+impl EnclaveReport {
+    pub fn to_bundle(&self) -> Result<ReportBundle, Error> {
+        let report: Vec<u8> = self.report.from_hex().map_err(|_| server_err("GetEnclaveReport", "the report isn't hex"))?;
+        Ok(ReportBundle {
+            report: String::from_utf8(report)?,
+            signature: self.signature.clone(),
+            certificate: self.certificate.clone(),
+            ca: self.ca.clone(),
+        })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[allow(non_snake_case)]
+pub struct TaskKey {
+    pub taskPubKey: String,
+    pub sig: String,
+}
+
+// `addPersonalData`, `updateUserStatus` and `findMatch`, `status` is 0 when passed, -1 when failed
+#[derive(Deserialize, Debug, Clone)]
+#[allow(non_snake_case)]
+pub struct EnclaveResult {
+    pub status: i8,
+    #[serde(default)]
+    pub encryptedOutput: String,
+    // `Replay` or `Expired` when the enclave refused the envelope, see `personal_data`, `CapacityExceeded` when the
+    // store is full, `ClockSkew` when locations are later than the server's clock allows
+    #[serde(default)]
+    pub error: Option<String>,
+    // `findMatch`: the strategy the enclave matched with, e.g. `{"algorithm": "radius", "minOverlap": 300, "distance": 10.0}`
+    #[serde(default)]
+    pub matching: Option<Value>,
+}
+
+impl EnclaveResult {
+    pub fn passed(&self) -> bool { self.status == 0 }
+}
+
+// The decrypted `encryptedOutput` of `AddPersonalData`, and of `UpdateUserStatus`, whose `records` are the
+// locations it updated.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Receipt {
+    pub status: String,
+    // The new locations, `duplicates` those the server already had (capability `deduplication`)
+    #[serde(default)]
+    pub records: usize,
+    #[serde(default)]
+    pub duplicates: usize,
+    #[serde(default)]
+    pub error: Option<String>,
+    // Seconds the device's clock is ahead of the enclave's (behind if negative), the locations were shifted
+    // back by it. 0 when within a few seconds
+    #[serde(default, rename = "clockDrift")]
+    pub clock_drift: i64,
+}
+
+// A health authority's declaration that the user tested positive, submitted along with their locations:
+// when the server trusts health authorities, locations with `testResult` set are refused without one.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Declaration {
+    #[serde(rename = "issuedAt")]
+    pub issued_at: u64,
+    // Of `declaration_message`, 65 bytes hex
+    pub signature: String,
+}
+
+// What the health authority signs, see `authority` in the enclave.
+pub fn declaration_message(user_id: &str, issued_at: u64) -> Vec<u8> {
+    format!("safetrace:infected:{}:{}", user_id, issued_at).into_bytes()
+}
+
+impl Declaration {
+    // For the health authority, with one of the keys the server is configured with.
+    pub fn issue(authority_key: &KeyPair, user_id: &str, issued_at: u64) -> Result<Declaration, Error> {
+        let signature = authority_key.sign(&declaration_message(user_id, issued_at))
+            .map_err(|e| SessionErr { message: format!("signing failed: {:?}", e) })?;
+        Ok(Declaration { issued_at, signature: signature.to_hex() })
+    }
+}
+
+pub(crate) fn server_err(request: &str, message: &str) -> Error {
+    ServerErr { request: request.to_string(), message: message.to_string(), code: None }.into()
+}
+
+// `FindMatch` -> `findMatch`
+fn lower_camel(kind: &str) -> String {
+    let mut chars = kind.chars();
+    match chars.next() {
+        Some(first) => first.to_lowercase().chain(chars).collect(),
+        None => String::new(),
+    }
+}
+
+pub fn new_id() -> String {
+    let id: [u8; 5] = rand::random();
+    id.to_hex()
+}
+
+// The plaintext of `encryptedData`: the locations with a fresh nonce and the time, in seconds, so the
+// enclave refuses the same message sent again or too late, and the declaration of a health authority if any.
+pub fn personal_data(locations: &[Location], timestamp: u64, declaration: Option<&Declaration>) -> Value {
+    let nonce: [u8; 16] = rand::random();
+    let mut payload = json!({"nonce": nonce.to_hex(), "timestamp": timestamp, "locations": locations});
+    if let Some(declaration) = declaration {
+        payload["declaration"] = json!(declaration);
+    }
+    payload
+}
+
+// The plaintext of `encryptedData` for `UpdateUserStatus`: `test_result` for the locations the user stored
+// that end at or after `since` (all of them when `None`). Marking them as infected takes a declaration when
+// the server trusts health authorities, like submitting them so.
+pub fn status_update(test_result: bool, since: Option<u64>, timestamp: u64, declaration: Option<&Declaration>) -> Value {
+    let nonce: [u8; 16] = rand::random();
+    let mut payload = json!({"nonce": nonce.to_hex(), "timestamp": timestamp, "testResult": test_result});
+    if let Some(since) = since {
+        payload["since"] = json!(since);
+    }
+    if let Some(declaration) = declaration {
+        payload["declaration"] = json!(declaration);
+    }
+    payload
+}
+
+// For locations stamped in local time: `tz_offset` seconds east of UTC, the enclave converts them
+// (capability `clock-skew-tolerance`).
+pub fn in_timezone(mut payload: Value, tz_offset: i32) -> Value {
+    payload["tzOffset"] = json!(tz_offset);
+    payload
+}
+
+// wasm32 has no clock, the JavaScript side passes `Date.now()` instead.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn now() -> u64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn now_millis() -> u64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() * 1000 + u64::from(d.subsec_millis())).unwrap_or(0)
+}
+
+// Deflates the plaintext of `encryptedData` before it's encrypted, the enclave inflates it once decrypted
+// (capability `compression-deflate`), up to 1 MB. Send the request `with_compression`.
+pub fn deflate(plaintext: &[u8]) -> Vec<u8> {
+    miniz_oxide::deflate::compress_to_vec(plaintext, 6)
+}
+
+// Says the `encryptedData` of `AddPersonalData` was deflated.
+pub fn with_compression(mut request: Value) -> Value {
+    request["input"]["compression"] = Value::String("deflate".to_string());
+    request
+}
+
+// Sends `request` in the partition of `tenant`, for servers that serve several (capability `tenants`).
+pub fn with_tenant(mut request: Value, tenant: &str) -> Value {
+    request["tenant"] = Value::String(tenant.to_string());
+    request
+}
+
+// Matches `FindMatch` or `SubmitMatchJob` with `strategy` rather than the server's (capability
+// `matching-strategies`), see `MatchingStrategy` in the app.
+pub fn with_matching(mut request: Value, strategy: Value) -> Value {
+    request["input"]["matching"] = strategy;
+    request
+}
+
+// Asks `FindMatch` for the matches new since the user's previous query, the others summed up:
+//   {"matches": [...], "prior": {"matches": 12, "from": 1589000000, "to": 1589090000}, "watermark": 42}
+// once decrypted, rather than the list of every match.
+pub fn with_incremental(mut request: Value) -> Value {
+    request["input"]["incremental"] = Value::Bool(true);
+    request
+}
+
+pub fn get_protocol_version(id: &str) -> Value {
+    json!({"id": id, "type": "GetProtocolVersion", "clientVersion": CLIENT_VERSION})
+}
+
+pub fn get_enclave_report(id: &str) -> Value {
+    json!({"id": id, "type": "GetEnclaveReport"})
+}
+
+pub fn get_attestation_job(id: &str, job_id: &str) -> Value {
+    json!({"id": id, "type": "GetAttestationJob", "jobId": job_id})
+}
+
+// `quote_hash` is the SHA-256 of the quote bytes, hex.
+pub fn get_cached_report(id: &str, quote_hash: &str) -> Value {
+    json!({"id": id, "type": "GetCachedReport", "quoteHash": quote_hash})
+}
+
+// `sent_at` in milliseconds, see `HeartbeatReply`.
+pub fn heartbeat(id: &str, sent_at: u64) -> Value {
+    json!({"id": id, "type": "Heartbeat", "sentAt": sent_at})
+}
+
+pub fn get_enclave_info(id: &str) -> Value {
+    json!({"id": id, "type": "GetEnclaveInfo"})
+}
+
+pub fn export_exposure_statistics(id: &str, request: &ExportRequest) -> Value {
+    json!({"id": id, "type": "ExportExposureStatistics", "request": request})
+}
+
+pub fn new_task_encryption_key(id: &str, user_pubkey: &str) -> Value {
+    json!({"id": id, "type": "NewTaskEncryptionKey", "userPubKey": user_pubkey})
+}
+
+pub fn add_personal_data(id: &str, encrypted_userid: &str, encrypted_data: &str, user_pubkey: &str) -> Value {
+    json!({"id": id, "type": "AddPersonalData", "input": {
+        "encryptedUserId": encrypted_userid, "encryptedData": encrypted_data, "userPubKey": user_pubkey,
+    }})
+}
+
+// Capability `status-updates`
+pub fn update_user_status(id: &str, encrypted_userid: &str, encrypted_data: &str, user_pubkey: &str) -> Value {
+    json!({"id": id, "type": "UpdateUserStatus", "input": {
+        "encryptedUserId": encrypted_userid, "encryptedData": encrypted_data, "userPubKey": user_pubkey,
+    }})
+}
+
+pub fn register_user(id: &str, encrypted_userid: &str, encrypted_registration: &str, user_pubkey: &str) -> Value {
+    json!({"id": id, "type": "RegisterUser", "input": {
+        "encryptedUserId": encrypted_userid, "encryptedData": encrypted_registration, "userPubKey": user_pubkey,
+    }})
+}
+
+// Signs `AddPersonalData`, `UpdateUserStatus` or `FindMatch` for a user who registered `signing_key`: the signature covers
+// `encryptedUserId`, `encryptedData` (if any) and `userPubKey`.
+pub fn sign(mut request: Value, session: &Session, signing_key: &KeyPair) -> Result<Value, Error> {
+    let kind = request["type"].as_str().unwrap_or_default().to_string();
+    let input = &request["input"];
+    let mut parts = Vec::new();
+    for field in &["encryptedUserId", "encryptedData", "userPubKey"] {
+        if let Some(part) = input[*field].as_str() {
+            parts.push(part);
+        }
+    }
+    if parts.is_empty() {
+        return Err(SessionErr { message: format!("{} has no input to sign", kind) }.into());
+    }
+    let signature = session.sign(signing_key, &parts)?;
+    request["input"]["encryptedSignature"] = Value::String(signature);
+    Ok(request)
+}
+
+pub fn find_match(id: &str, encrypted_userid: &str, user_pubkey: &str) -> Value {
+    json!({"id": id, "type": "FindMatch", "input": {"encryptedUserId": encrypted_userid, "userPubKey": user_pubkey}})
+}
+
+// The api-server method and its (flat) params: `FindMatch` with `input` becomes `findMatch` with the input fields.
+pub fn to_jsonrpc(request: &Value) -> (String, Value) {
+    let method = lower_camel(request["type"].as_str().unwrap_or_default());
+    let mut params = serde_json::Map::new();
+    if let Some(request) = request.as_object() {
+        for (key, value) in request {
+            match (key.as_str(), value) {
+                ("id", _) | ("type", _) => (),
+                ("input", Value::Object(input)) => params.extend(input.clone()),
+                _ => { params.insert(key.clone(), value.clone()); },
+            }
+        }
+    }
+    (method, Value::Object(params))
+}
+
+// The payload of a reply to `request`: under "result" or the lowerCamel name of the request
+// (`addPersonalData`, `findMatch`), an `Error` reply becomes a `ServerErr` with its `code`.
+pub fn parse_response<T: for<'de> Deserialize<'de>>(request: &str, response: &Value) -> Result<T, Error> {
+    if response["type"] == "Error" {
+        let message = response["msg"].as_str().unwrap_or("unknown error").to_string();
+        let code = response["code"].as_u64().and_then(ErrorCode::from_code);
+        return Err(ServerErr { request: request.to_string(), message, code }.into());
+    }
+    let field = lower_camel(request);
+    let payload = match response.get("result") {
+        Some(result) => result,
+        None => response.get(field.as_str()).ok_or_else(|| server_err(request, "the reply has no result"))?,
+    };
+    serde_json::from_value(payload.clone()).map_err(|e| server_err(request, &format!("unexpected reply: {}", e)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::export::ExportReply;
+
+    const ID: &str = "a1b2c3d4e5";
+    const PUBKEY: &str = "2b4d1a7e2b4d1a7e2b4d1a7e2b4d1a7e2b4d1a7e2b4d1a7e2b4d1a7e2b4d1a7e2b4d1a7e2b4d1a7e2b4d1a7e2b4d1a7e2b4d1a7e2b4d1a7e2b4d1a7e2b4d1a7e";
+
+    fn golden(json: &str) -> Value { serde_json::from_str(json).unwrap() }
+
+    #[test]
+    fn test_requests_match_the_server_goldens() {
+        assert_eq!(get_enclave_report(ID), golden(include_str!("../../app/tests/golden/request_get_enclave_report.json")));
+        assert_eq!(new_task_encryption_key(ID, PUBKEY), golden(include_str!("../../app/tests/golden/request_new_task_encryption_key.json")));
+        assert_eq!(
+            add_personal_data(ID, "e1a3c5f7d9b2", "9f8e7d6c5b4a39281706f5e4d3c2b1a0", PUBKEY),
+            golden(include_str!("../../app/tests/golden/request_add_personal_data.json"))
+        );
+        assert_eq!(find_match(ID, "e1a3c5f7d9b2", PUBKEY), golden(include_str!("../../app/tests/golden/request_find_match.json")));
+        assert_eq!(with_tenant(find_match(ID, "e1a3c5f7d9b2", PUBKEY), "ch-ge"),
+                   golden(include_str!("../../app/tests/golden/request_find_match_tenant.json")));
+        assert_eq!(with_matching(find_match(ID, "e1a3c5f7d9b2", PUBKEY), json!({"algorithm": "duration", "distance": 25.0, "minDuration": 900})),
+                   golden(include_str!("../../app/tests/golden/request_find_match_strategy.json")));
+        assert_eq!(with_incremental(find_match(ID, "e1a3c5f7d9b2", PUBKEY))["input"]["incremental"], json!(true));
+        assert_eq!(
+            register_user(ID, "e1a3c5f7d9b2", "9f8e7d6c5b4a39281706f5e4d3c2b1a0", PUBKEY),
+            golden(include_str!("../../app/tests/golden/request_register_user.json"))
+        );
+        assert_eq!(
+            with_compression(add_personal_data(ID, "e1a3c5f7d9b2", "9f8e7d6c5b4a39281706f5e4d3c2b1a0", PUBKEY)),
+            golden(include_str!("../../app/tests/golden/request_add_personal_data_deflate.json"))
+        );
+        assert_eq!(
+            update_user_status(ID, "e1a3c5f7d9b2", "9f8e7d6c5b4a39281706f5e4d3c2b1a0", PUBKEY),
+            golden(include_str!("../../app/tests/golden/request_update_user_status.json"))
+        );
+        assert_eq!(get_enclave_info(ID), golden(include_str!("../../app/tests/golden/request_get_enclave_info.json")));
+        assert_eq!(get_attestation_job(ID, "0f1e2d3c4b5a69788796a5b4c3d2e1f0"),
+                   golden(include_str!("../../app/tests/golden/request_get_attestation_job.json")));
+        assert_eq!(heartbeat(ID, 1585699199990), golden(include_str!("../../app/tests/golden/request_heartbeat.json")));
+        assert_eq!(get_cached_report(ID, &"5d41402abc4b2a76b9719d911017c592".repeat(2)),
+                   golden(include_str!("../../app/tests/golden/request_get_cached_report.json")));
+        let export = ExportRequest { issued_at: 1589000000, format: "csv".to_string(), precision: 6, from: 18380, to: 18390, signature: "ab".repeat(65) };
+        assert_eq!(export_exposure_statistics(ID, &export), golden(include_str!("../../app/tests/golden/request_export_exposure_statistics.json")));
+        assert_eq!(new_id().len(), 10);
+    }
+
+    #[test]
+    fn test_parse_server_goldens() {
+        let report: EnclaveReport = parse_response("GetEnclaveReport", &golden(include_str!("../../app/tests/golden/response_get_enclave_report.json"))).unwrap();
+        assert_eq!(report.to_bundle().unwrap().report, r#"{"id":"123"}"#);
+        let reply: ReportReply = parse_response("GetEnclaveReport", &golden(include_str!("../../app/tests/golden/response_get_enclave_report.json"))).unwrap();
+        assert!(match reply { ReportReply::Report(report) => report.signing_key.len() == 40, _ => false });
+        let pending: ReportReply = parse_response("GetEnclaveReport", &golden(include_str!("../../app/tests/golden/response_get_enclave_report_pending.json"))).unwrap();
+        assert!(match pending { ReportReply::Pending { job } => job.state == "Pending" && job.report.is_none(), _ => false });
+        let done: JobReply = parse_response("GetAttestationJob", &golden(include_str!("../../app/tests/golden/response_get_attestation_job_done.json"))).unwrap();
+        assert_eq!((done.job.state.as_str(), done.job.attempts), ("Done", 3));
+        assert_eq!(done.job.report.unwrap().to_bundle().unwrap().report, r#"{"id":"123"}"#);
+        let key: TaskKey = parse_response("NewTaskEncryptionKey", &golden(include_str!("../../app/tests/golden/response_new_task_encryption_key.json"))).unwrap();
+        assert_eq!(key.taskPubKey, PUBKEY);
+        let passed: EnclaveResult = parse_response("FindMatch", &golden(include_str!("../../app/tests/golden/response_find_match_passed.json"))).unwrap();
+        assert!(passed.passed() && !passed.encryptedOutput.is_empty());
+        let failed: EnclaveResult = parse_response("FindMatch", &golden(include_str!("../../app/tests/golden/response_find_match_failed.json"))).unwrap();
+        assert!(!failed.passed() && failed.encryptedOutput.is_empty() && failed.matching.is_none());
+        let strategy: EnclaveResult = parse_response("FindMatch", &golden(include_str!("../../app/tests/golden/response_find_match_strategy.json"))).unwrap();
+        assert_eq!(strategy.matching.unwrap()["algorithm"], "grid");
+        let replay: EnclaveResult = parse_response("AddPersonalData", &golden(include_str!("../../app/tests/golden/response_add_personal_data_replay.json"))).unwrap();
+        assert_eq!(replay.error, Some("Replay".to_string()));
+        let full: EnclaveResult = parse_response("AddPersonalData", &golden(include_str!("../../app/tests/golden/response_add_personal_data_capacity.json"))).unwrap();
+        assert_eq!(full.error, Some("CapacityExceeded".to_string()));
+        let skewed: EnclaveResult = parse_response("AddPersonalData", &golden(include_str!("../../app/tests/golden/response_add_personal_data_clock_skew.json"))).unwrap();
+        assert_eq!(skewed.error, Some("ClockSkew".to_string()));
+        let updated: EnclaveResult = parse_response("UpdateUserStatus", &golden(include_str!("../../app/tests/golden/response_update_user_status.json"))).unwrap();
+        assert!(updated.passed() && !updated.encryptedOutput.is_empty());
+        let export: ExportReply = parse_response("ExportExposureStatistics", &golden(include_str!("../../app/tests/golden/response_export_exposure_statistics.json"))).unwrap();
+        assert_eq!((export.bundle.k, export.bundle.rows, export.bundle.format.as_str()), (10, 1, "csv"));
+        let version: ProtocolVersion = parse_response("GetProtocolVersion", &golden(include_str!("../../app/tests/golden/response_get_protocol_version.json"))).unwrap();
+        assert!(version.compatible && version.quantization.is_none());
+        let quantized: ProtocolVersion = parse_response("GetProtocolVersion", &golden(include_str!("../../app/tests/golden/response_get_protocol_version_quantized.json"))).unwrap();
+        assert_eq!(quantized.quantization, Some(Quantization { grid: 0.001, time_bucket: 300 }));
+        assert_eq!(quantized.max_frame_size, None);
+        let limits: ProtocolVersion = parse_response("GetProtocolVersion", &golden(include_str!("../../app/tests/golden/response_get_protocol_version_limits.json"))).unwrap();
+        assert_eq!(limits.max_frame_size, Some(1_048_576));
+        let info: EnclaveInfo = parse_response("GetEnclaveInfo", &golden(include_str!("../../app/tests/golden/response_get_enclave_info.json"))).unwrap();
+        let manifest = info.manifest.unwrap();
+        assert_eq!((manifest.mr_enclave, manifest.build.toolchain.as_str()), (info.mr_enclave, "nightly-2019-08-01"));
+        assert_eq!((info.isv_svn, info.debug, info.manifest_matches), (1, false, Some(true)));
+        let heartbeat: HeartbeatReply = parse_response("Heartbeat", &golden(include_str!("../../app/tests/golden/response_heartbeat.json"))).unwrap();
+        assert_eq!(heartbeat.idle_timeout, 60);
+        // Sent at ...990, back at ...012 on the client's clock: 22 ms, 1 of which in the server
+        assert_eq!(heartbeat.round_trip(1585699200012), 21);
+        assert_eq!(heartbeat.clock_offset(1585699200012), 0);
+        assert_eq!(heartbeat.clock_offset(1585699199992), 9);
+        let cached: CachedReport = parse_response("GetCachedReport", &golden(include_str!("../../app/tests/golden/response_get_cached_report.json"))).unwrap();
+        assert_eq!((cached.quote_hash.len(), cached.cached_at), (64, 1589000000));
+        assert_eq!(cached.to_bundle().unwrap().report, r#"{"id":"123"}"#);
+
+        let error = parse_response::<TaskKey>("NewTaskEncryptionKey", &golden(include_str!("../../app/tests/golden/response_error.json"))).unwrap_err();
+        assert!(error.to_string().contains("KeysError"));
+        assert_eq!(error.downcast_ref::<ServerErr>().unwrap().code, Some(ErrorCode::EnclaveError));
+        // A code of a newer server, or none at all
+        let error = parse_response::<TaskKey>("NewTaskEncryptionKey", &json!({"id": ID, "type": "Error", "code": 1999, "msg": "?"})).unwrap_err();
+        assert_eq!(error.downcast_ref::<ServerErr>().unwrap().code, None);
+    }
+
+    #[test]
+    fn test_to_jsonrpc() {
+        let (method, params) = to_jsonrpc(&find_match(ID, "e1a3c5f7d9b2", PUBKEY));
+        assert_eq!(method, "findMatch");
+        assert_eq!(params, json!({"encryptedUserId": "e1a3c5f7d9b2", "userPubKey": PUBKEY}));
+        let (method, params) = to_jsonrpc(&get_protocol_version(ID));
+        assert_eq!(method, "getProtocolVersion");
+        assert_eq!(params, json!({"clientVersion": CLIENT_VERSION}));
+    }
+
+    #[test]
+    fn test_personal_data_envelope() {
+        let location = Location { lat: 40.7, lng: -74.0, startTS: 1583064000, endTS: 1583067600, testResult: true };
+        let first = personal_data(&[location.clone()], 1589000000, None);
+        assert_eq!(first["timestamp"], 1589000000);
+        assert_eq!(first["locations"], json!([location]));
+        assert!(first.get("declaration").is_none());
+        // A fresh nonce every time
+        assert_eq!(first["nonce"].as_str().unwrap().len(), 32);
+        assert_ne!(first["nonce"], personal_data(&[location], 1589000000, None)["nonce"]);
+        assert_eq!(in_timezone(first, 7200)["tzOffset"], 7200);
+
+        let receipt: Receipt = serde_json::from_str(r#"{"status": "Passed", "records": 2, "clockDrift": -95}   "#).unwrap();
+        assert_eq!((receipt.records, receipt.duplicates, receipt.clock_drift), (2, 0, -95));
+        let resubmitted: Receipt = serde_json::from_str(r#"{"status": "Passed", "records": 1, "duplicates": 3}"#).unwrap();
+        assert_eq!((resubmitted.records, resubmitted.duplicates), (1, 3));
+    }
+
+    #[test]
+    fn test_deflate() {
+        // A history of the same places compresses well
+        let location = Location { lat: 40.7, lng: -74.0, startTS: 1583064000, endTS: 1583067600, testResult: false };
+        let history = serde_json::to_vec(&personal_data(&vec![location; 50], 1589000000, None)).unwrap();
+        let deflated = deflate(&history);
+        assert!(deflated.len() < history.len() / 10);
+        assert_eq!(miniz_oxide::inflate::decompress_to_vec(&deflated).unwrap(), history);
+    }
+
+    #[test]
+    fn test_declarations() {
+        let authority = KeyPair::new().unwrap();
+        let declaration = Declaration::issue(&authority, "user1", 1589000000).unwrap();
+        assert_eq!(declaration_message("user1", 1589000000), b"safetrace:infected:user1:1589000000".to_vec());
+
+        // The enclave recovers the authority from the signature of the message
+        let sig: Vec<u8> = declaration.signature.from_hex().unwrap();
+        let mut signature = [0u8; 65];
+        signature.copy_from_slice(&sig);
+        let signer = KeyPair::recover(&declaration_message("user1", 1589000000), signature).unwrap();
+        assert_eq!(signer[..], authority.get_pubkey()[..]);
+        assert_ne!(KeyPair::recover(&declaration_message("user2", 1589000000), signature).unwrap()[..], authority.get_pubkey()[..]);
+
+        let payload = personal_data(&[], 1589000000, Some(&declaration));
+        assert_eq!(payload["declaration"], json!({"issuedAt": 1589000000, "signature": declaration.signature}));
+
+        let update = status_update(true, Some(1588600000), 1589000000, Some(&declaration));
+        assert_eq!((update["testResult"].clone(), update["since"].clone()), (json!(true), json!(1588600000)));
+        assert_eq!(update["declaration"], payload["declaration"]);
+        assert_eq!(update["nonce"].as_str().unwrap().len(), 32);
+        let cleared = status_update(false, None, 1589000000, None);
+        assert!(cleared.get("since").is_none() && cleared.get("declaration").is_none());
+    }
+
+    #[test]
+    fn test_sign_requests() {
+        use crate::session::test::enclave_task_key;
+        use enigma_tools_m::utils::EthereumAddress;
+
+        let signing_key = KeyPair::new().unwrap();
+        let user_key = KeyPair::new().unwrap();
+        let (task_pubkey, sig, _) = enclave_task_key(&signing_key, &user_key.get_pubkey());
+        let session = Session::new(&user_key, &task_pubkey, &sig, &signing_key.get_pubkey().address()).unwrap();
+
+        let user_signing_key = KeyPair::new().unwrap();
+        let signed = sign(find_match(ID, "e1a3c5f7d9b2", PUBKEY), &session, &user_signing_key).unwrap();
+        assert!(signed["input"]["encryptedSignature"].as_str().unwrap().len() > 2 * 65);
+        assert_eq!(signed["input"]["encryptedUserId"], "e1a3c5f7d9b2");
+        assert!(sign(get_enclave_report(ID), &session, &user_signing_key).is_err());
+    }
+}
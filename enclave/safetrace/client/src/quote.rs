@@ -0,0 +1,561 @@
+use crate::errors::QuoteErr;
+use failure::Error;
+use hex::ToHex;
+use serde_json::{json, Value};
+use std::fmt;
+
+// SGX quotes, ported from enigma-tools-u: its `Quote::from_base64` only knows the EPID layout and
+// reads exactly 432 bytes, whatever the version says. Here the version picks the layout:
+//
+//   v1, v2 (EPID):  header (48) | report body (384) | [signature length (4) | signature]
+//   v3 (ECDSA):     header (48) | report body (384) | [signature data length (4) | signature data]
+//   v4 (ECDSA):     same as v3, the header tells the TEE type, only SGX (not TDX) is supported
+//
+// IAS returns the header and the report body only (`isvEnclaveQuoteBody`), so the signature is optional.
+// When it's there its length must account for every byte left, signatures aren't fixed size
+// (an EPID one grows with the SigRL, an ECDSA one carries the QE certification data).
+
+const HEADER_SIZE: usize = 48;
+// The header and the report body, all IAS returns
+pub const QUOTE_BODY_SIZE: usize = HEADER_SIZE + QReportBody::SIZE;
+const SIGNATURE_LEN_SIZE: usize = 4;
+
+const TEE_TYPE_SGX: u32 = 0x0000_0000;
+
+fn quote_err(message: &str) -> Error {
+    QuoteErr { message: message.to_string() }.into()
+}
+
+fn u16_le(bytes: [u8; 2]) -> u16 { u16::from_le_bytes(bytes) }
+
+fn u32_le(bytes: [u8; 4]) -> u32 { u32::from_le_bytes(bytes) }
+
+fn u64_le(bytes: &[u8]) -> u64 {
+    let mut array = [0u8; 8];
+    array.copy_from_slice(&bytes[..8]);
+    u64::from_le_bytes(array)
+}
+
+// `SGX_FLAGS_*` of the enclave attributes.
+const ATTRIBUTE_FLAGS: &[(u64, &str)] = &[
+    (0x01, "INITTED"),
+    (0x02, "DEBUG"),
+    (0x04, "MODE64BIT"),
+    (0x10, "PROVISION_KEY"),
+    (0x20, "EINITTOKEN_KEY"),
+    (0x80, "KSS"),
+];
+const ATTRIBUTE_DEBUG: u64 = 0x02;
+
+// Declares a structure made of byte arrays, laid out in declaration order, with a safe all-zero
+// `Default` (arrays over 32 elements don't implement it), `PartialEq`, `SIZE`, and the
+// `from_bytes`/`write_to` pair to parse and re-encode it.
+macro_rules! byte_struct {
+    ($(#[$meta:meta])* pub struct $name:ident { $($field:ident: [u8; $size:expr],)+ }) => {
+        $(#[$meta])*
+        #[derive(Clone, Copy)]
+        pub struct $name {
+            $(pub $field: [u8; $size],)+
+        }
+
+        impl $name {
+            pub const SIZE: usize = 0 $(+ $size)+;
+
+            pub fn from_bytes(bytes: &[u8]) -> Result<Self, Error> {
+                if bytes.len() != Self::SIZE {
+                    return Err(quote_err(&format!("{} is {} bytes, got {}", stringify!($name), Self::SIZE, bytes.len())));
+                }
+                let rest = bytes;
+                $(
+                    let ($field, rest) = rest.split_at($size);
+                    let $field = {
+                        let mut array = [0u8; $size];
+                        array.copy_from_slice($field);
+                        array
+                    };
+                )+
+                debug_assert!(rest.is_empty());
+                Ok($name { $($field,)+ })
+            }
+
+            pub fn write_to(&self, out: &mut Vec<u8>) {
+                $(out.extend_from_slice(&self.$field);)+
+            }
+        }
+
+        impl Default for $name {
+            fn default() -> Self {
+                $name { $($field: [0u8; $size],)+ }
+            }
+        }
+
+        impl PartialEq for $name {
+            fn eq(&self, other: &Self) -> bool {
+                true $(&& self.$field[..] == other.$field[..])+
+            }
+        }
+    };
+}
+
+byte_struct! {
+    // Header of the EPID quotes (v1, v2).
+    #[derive(Debug)]
+    pub struct QBody {
+        version: [u8; 2],
+        signature_type: [u8; 2],
+        gid: [u8; 4],
+        isv_svn_qe: [u8; 2],
+        isv_svn_pce: [u8; 2],
+        reserved: [u8; 4],
+        base_name: [u8; 32],
+    }
+}
+
+byte_struct! {
+    // Header of the ECDSA quotes (v3, v4), `tee_type` is reserved in v3.
+    #[derive(Debug)]
+    pub struct QEcdsaHeader {
+        version: [u8; 2],
+        att_key_type: [u8; 2],
+        tee_type: [u8; 4],
+        qe_svn: [u8; 2],
+        pce_svn: [u8; 2],
+        qe_vendor_id: [u8; 16],
+        user_data: [u8; 20],
+    }
+}
+
+byte_struct! {
+    pub struct QReportBody {
+        cpu_svn: [u8; 16],
+        misc_select: [u8; 4],
+        reserved: [u8; 28],
+        attributes: [u8; 16],
+        mr_enclave: [u8; 32],
+        reserved2: [u8; 32],
+        mr_signer: [u8; 32],
+        reserved3: [u8; 96],
+        isv_prod_id: [u8; 2],
+        isv_svn: [u8; 2],
+        reserved4: [u8; 60],
+        report_data: [u8; 64],
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QuoteHeader {
+    Epid(QBody),
+    Ecdsa(QEcdsaHeader),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Quote {
+    pub header: QuoteHeader,
+    pub report_body: QReportBody,
+    // `None` when the quote came without it, e.g. from IAS
+    pub signature: Option<Vec<u8>>,
+}
+
+impl fmt::Debug for QReportBody {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("QReportBody")
+            .field("cpu_svn", &self.cpu_svn)
+            .field("misc_select", &self.misc_select)
+            .field("attributes", &self.attributes)
+            .field("mr_enclave", &self.mr_enclave)
+            .field("mr_signer", &self.mr_signer)
+            .field("isv_prod_id", &self.isv_prod_id)
+            .field("isv_svn", &self.isv_svn)
+            .field("report_data", &&self.report_data[..])
+            .finish()
+    }
+}
+
+impl QuoteHeader {
+    pub fn version(&self) -> u16 {
+        match self {
+            QuoteHeader::Epid(header) => u16_le(header.version),
+            QuoteHeader::Ecdsa(header) => u16_le(header.version),
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            QuoteHeader::Epid(_) => "EPID",
+            QuoteHeader::Ecdsa(_) => "ECDSA",
+        }
+    }
+
+    // ISV SVN of the quoting enclave
+    pub fn qe_svn(&self) -> u16 {
+        match self {
+            QuoteHeader::Epid(header) => u16_le(header.isv_svn_qe),
+            QuoteHeader::Ecdsa(header) => u16_le(header.qe_svn),
+        }
+    }
+
+    // ISV SVN of the provisioning certification enclave
+    pub fn pce_svn(&self) -> u16 {
+        match self {
+            QuoteHeader::Epid(header) => u16_le(header.isv_svn_pce),
+            QuoteHeader::Ecdsa(header) => u16_le(header.pce_svn),
+        }
+    }
+
+    // The EPID group, IAS lists its revocations. ECDSA quotes have none.
+    pub fn epid_group(&self) -> Option<u32> {
+        match self {
+            QuoteHeader::Epid(header) => Some(u32_le(header.gid)),
+            QuoteHeader::Ecdsa(_) => None,
+        }
+    }
+}
+
+// The enclave attributes of a report body: `SGX_FLAGS_*` and the XSAVE feature request mask.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Attributes {
+    pub flags: u64,
+    pub xfrm: u64,
+}
+
+impl Attributes {
+    pub fn is_debug(&self) -> bool { self.flags & ATTRIBUTE_DEBUG != 0 }
+
+    pub fn flag_names(&self) -> Vec<&'static str> {
+        ATTRIBUTE_FLAGS.iter().filter(|&&(bit, _)| self.flags & bit != 0).map(|&(_, name)| name).collect()
+    }
+}
+
+impl QReportBody {
+    pub fn mr_enclave_hex(&self) -> String { self.mr_enclave.to_hex() }
+
+    pub fn mr_signer_hex(&self) -> String { self.mr_signer.to_hex() }
+
+    pub fn report_data_hex(&self) -> String { self.report_data.to_hex() }
+
+    pub fn isv_prod_id(&self) -> u16 { u16_le(self.isv_prod_id) }
+
+    pub fn isv_svn(&self) -> u16 { u16_le(self.isv_svn) }
+
+    pub fn misc_select(&self) -> u32 { u32_le(self.misc_select) }
+
+    pub fn attributes(&self) -> Attributes {
+        Attributes { flags: u64_le(&self.attributes[..8]), xfrm: u64_le(&self.attributes[8..]) }
+    }
+}
+
+// The signature section after the report body: nothing, or a length and exactly that many bytes.
+fn read_signature(rest: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+    if rest.is_empty() {
+        return Ok(None);
+    }
+    if rest.len() < SIGNATURE_LEN_SIZE {
+        return Err(quote_err(&format!("{} trailing bytes, too short for a signature length", rest.len())));
+    }
+    let mut len = [0u8; SIGNATURE_LEN_SIZE];
+    len.copy_from_slice(&rest[..SIGNATURE_LEN_SIZE]);
+    let len = u32_le(len) as usize;
+    let signature = &rest[SIGNATURE_LEN_SIZE..];
+    if signature.len() != len {
+        return Err(quote_err(&format!("the signature length says {} bytes, {} follow", len, signature.len())));
+    }
+    Ok(Some(signature.to_vec()))
+}
+
+impl Quote {
+    pub fn from_base64(encoded_quote: &str) -> Result<Quote, Error> {
+        let quote_bytes = base64::decode(encoded_quote).map_err(|e| quote_err(&format!("invalid base64: {}", e)))?;
+        Self::from_bytes(&quote_bytes)
+    }
+
+    pub fn from_bytes(quote_bytes: &[u8]) -> Result<Quote, Error> {
+        if quote_bytes.len() < QUOTE_BODY_SIZE {
+            return Err(quote_err(&format!("expected at least {} bytes, got {}", QUOTE_BODY_SIZE, quote_bytes.len())));
+        }
+        let (header, rest) = quote_bytes.split_at(HEADER_SIZE);
+        let (report_body, rest) = rest.split_at(QReportBody::SIZE);
+        let header = match u16_le([header[0], header[1]]) {
+            1 | 2 => QuoteHeader::Epid(QBody::from_bytes(header)?),
+            version @ 3 | version @ 4 => {
+                let header = QEcdsaHeader::from_bytes(header)?;
+                let tee_type = u32_le(header.tee_type);
+                if version == 4 && tee_type != TEE_TYPE_SGX {
+                    return Err(quote_err(&format!("unsupported TEE type {:#x}, only SGX quotes are", tee_type)));
+                }
+                QuoteHeader::Ecdsa(header)
+            },
+            version => return Err(quote_err(&format!("unsupported quote version {}", version))),
+        };
+        let report_body = QReportBody::from_bytes(report_body)?;
+        let signature = read_signature(rest)?;
+        Ok(Quote { header, report_body, signature })
+    }
+
+    // The exact bytes `from_bytes` parsed.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(QUOTE_BODY_SIZE + self.signature.as_ref().map_or(0, |s| SIGNATURE_LEN_SIZE + s.len()));
+        match &self.header {
+            QuoteHeader::Epid(header) => header.write_to(&mut bytes),
+            QuoteHeader::Ecdsa(header) => header.write_to(&mut bytes),
+        }
+        self.report_body.write_to(&mut bytes);
+        if let Some(signature) = &self.signature {
+            bytes.extend_from_slice(&(signature.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(signature);
+        }
+        bytes
+    }
+
+    pub fn to_base64(&self) -> String { base64::encode(&self.to_bytes()) }
+
+    pub fn version(&self) -> u16 { self.header.version() }
+
+    // What operators look at, the reserved fields and the signature itself are left out.
+    pub fn to_json(&self) -> Value {
+        let body = &self.report_body;
+        let attributes = body.attributes();
+        json!({
+            "version": self.version(),
+            "kind": self.header.kind(),
+            "qeSvn": self.header.qe_svn(),
+            "pceSvn": self.header.pce_svn(),
+            "epidGroup": self.header.epid_group(),
+            "cpuSvn": body.cpu_svn.to_hex(),
+            "miscSelect": body.misc_select(),
+            "attributes": {
+                "flags": attributes.flag_names(),
+                "xfrm": format!("{:#x}", attributes.xfrm),
+            },
+            "mrEnclave": body.mr_enclave_hex(),
+            "mrSigner": body.mr_signer_hex(),
+            "isvProdId": body.isv_prod_id(),
+            "isvSvn": body.isv_svn(),
+            "reportData": body.report_data_hex(),
+            "signatureLen": self.signature.as_ref().map(Vec::len),
+        })
+    }
+}
+
+impl fmt::Display for Quote {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let body = &self.report_body;
+        let attributes = body.attributes();
+        writeln!(f, "Quote v{} ({}), QE SVN {}, PCE SVN {}", self.version(), self.header.kind(), self.header.qe_svn(), self.header.pce_svn())?;
+        if let Some(group) = self.header.epid_group() {
+            writeln!(f, "  EPID group:  {:08x}", group)?;
+        }
+        writeln!(f, "  MRENCLAVE:   {}", body.mr_enclave_hex())?;
+        writeln!(f, "  MRSIGNER:    {}", body.mr_signer_hex())?;
+        writeln!(f, "  ISV product: {}, SVN {}", body.isv_prod_id(), body.isv_svn())?;
+        writeln!(f, "  Attributes:  {} (XFRM {:#x})", attributes.flag_names().join(" | "), attributes.xfrm)?;
+        write!(f, "  Report data: {}", body.report_data_hex())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    fn quote_bytes(version: u16, tee_type: u32, signature: Option<&[u8]>) -> Vec<u8> {
+        let mut bytes = vec![0u8; QUOTE_BODY_SIZE];
+        bytes[..2].copy_from_slice(&version.to_le_bytes());
+        bytes[4..8].copy_from_slice(&tee_type.to_le_bytes());
+        // MRENCLAVE, then the report data
+        for (i, byte) in bytes[HEADER_SIZE + 64..HEADER_SIZE + 96].iter_mut().enumerate() {
+            *byte = i as u8;
+        }
+        for byte in bytes[QUOTE_BODY_SIZE - 64..].iter_mut() {
+            *byte = 0xaa;
+        }
+        if let Some(signature) = signature {
+            bytes.extend(&(signature.len() as u32).to_le_bytes());
+            bytes.extend(signature);
+        }
+        bytes
+    }
+
+    #[test]
+    fn test_ias_quote_body() {
+        let encoded = base64::encode(&quote_bytes(2, 0, None));
+        let quote = Quote::from_base64(&encoded).unwrap();
+        assert_eq!(quote.version(), 2);
+        assert_eq!(quote.signature, None);
+        assert_eq!(quote.report_body.mr_enclave[31], 31);
+        assert!(quote.report_body.report_data.iter().all(|&byte| byte == 0xaa));
+        match quote.header {
+            QuoteHeader::Epid(_) => (),
+            header => panic!("expected an EPID header, got {:?}", header),
+        }
+    }
+
+    #[test]
+    fn test_quote_versions() {
+        let epid_signature: Vec<u8> = (0..680).map(|i| i as u8).collect();
+        let quote = Quote::from_bytes(&quote_bytes(2, 0, Some(&epid_signature))).unwrap();
+        assert_eq!(quote.signature, Some(epid_signature));
+
+        let ecdsa_signature: Vec<u8> = (0..4200).map(|i| (i % 251) as u8).collect();
+        let quote = Quote::from_bytes(&quote_bytes(3, 0, Some(&ecdsa_signature))).unwrap();
+        assert_eq!(quote.version(), 3);
+        assert_eq!(quote.signature.map(|s| s.len()), Some(4200));
+        assert_eq!(quote.report_body.mr_enclave[1], 1);
+
+        let quote = Quote::from_bytes(&quote_bytes(4, TEE_TYPE_SGX, Some(&[]))).unwrap();
+        assert_eq!(quote.version(), 4);
+        assert_eq!(quote.signature, Some(Vec::new()));
+        match quote.header {
+            QuoteHeader::Ecdsa(_) => (),
+            header => panic!("expected an ECDSA header, got {:?}", header),
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for bytes in vec![quote_bytes(2, 0, None), quote_bytes(2, 0, Some(&[9u8; 680])), quote_bytes(3, 0, Some(&[])), quote_bytes(4, 0, Some(&[1, 2]))] {
+            let quote = Quote::from_bytes(&bytes).unwrap();
+            assert_eq!(quote.to_bytes(), bytes);
+            assert_eq!(Quote::from_base64(&quote.to_base64()).unwrap(), quote);
+        }
+        let mut report_body = QReportBody::default();
+        report_body.isv_svn = [1, 0];
+        let mut bytes = Vec::new();
+        report_body.write_to(&mut bytes);
+        assert_eq!(bytes.len(), QReportBody::SIZE);
+        assert_eq!(QReportBody::from_bytes(&bytes).unwrap(), report_body);
+        assert!(QReportBody::from_bytes(&bytes[1..]).is_err());
+        assert_eq!(QBody::SIZE, HEADER_SIZE);
+        assert_eq!(QEcdsaHeader::SIZE, HEADER_SIZE);
+    }
+
+    #[test]
+    fn test_getters() {
+        let mut bytes = quote_bytes(2, 0, None);
+        // gid, then ISV SVN of the QE and the PCE
+        bytes[4..8].copy_from_slice(&[0x69, 0x0b, 0, 0]);
+        bytes[8..12].copy_from_slice(&[7, 0, 6, 0]);
+        // flags INITTED | DEBUG | MODE64BIT, XFRM 0x1f
+        bytes[HEADER_SIZE + 48] = 0x07;
+        bytes[HEADER_SIZE + 56] = 0x1f;
+        bytes[HEADER_SIZE + 256..HEADER_SIZE + 260].copy_from_slice(&[3, 0, 2, 1]);
+        let quote = Quote::from_bytes(&bytes).unwrap();
+
+        assert_eq!(quote.header.epid_group(), Some(0x0b69));
+        assert_eq!((quote.header.qe_svn(), quote.header.pce_svn()), (7, 6));
+        let body = &quote.report_body;
+        assert_eq!((body.isv_prod_id(), body.isv_svn()), (3, 0x0102));
+        assert_eq!(body.mr_enclave_hex(), (0..32).map(|i| format!("{:02x}", i)).collect::<String>());
+        assert_eq!(body.attributes(), Attributes { flags: 0x07, xfrm: 0x1f });
+        assert!(body.attributes().is_debug());
+        assert_eq!(body.attributes().flag_names(), vec!["INITTED", "DEBUG", "MODE64BIT"]);
+
+        let json = quote.to_json();
+        assert_eq!(json["kind"], "EPID");
+        assert_eq!(json["isvSvn"], 0x0102);
+        assert_eq!(json["mrEnclave"], body.mr_enclave_hex());
+        assert_eq!(json["signatureLen"], Value::Null);
+        let printed = quote.to_string();
+        assert!(printed.starts_with("Quote v2 (EPID), QE SVN 7, PCE SVN 6"));
+        assert!(printed.contains(&format!("MRENCLAVE:   {}", body.mr_enclave_hex())));
+        assert!(printed.contains("INITTED | DEBUG | MODE64BIT (XFRM 0x1f)"));
+
+        let quote = Quote::from_bytes(&quote_bytes(3, 0, Some(&[1, 2, 3]))).unwrap();
+        assert_eq!(quote.header.epid_group(), None);
+        assert_eq!(quote.to_json()["signatureLen"], 3);
+        assert!(!quote.to_string().contains("EPID group"));
+    }
+
+    #[test]
+    fn test_invalid_quotes() {
+        // TDX
+        assert!(Quote::from_bytes(&quote_bytes(4, 0x81, None)).is_err());
+        assert!(Quote::from_bytes(&quote_bytes(5, 0, None)).is_err());
+        assert!(Quote::from_bytes(&quote_bytes(0, 0, None)).is_err());
+        assert!(Quote::from_bytes(&quote_bytes(2, 0, None)[..QUOTE_BODY_SIZE - 1]).is_err());
+        // A signature length with the wrong number of bytes after it
+        let mut bytes = quote_bytes(2, 0, Some(&[1, 2, 3]));
+        bytes.push(4);
+        assert!(Quote::from_bytes(&bytes).is_err());
+        assert!(Quote::from_bytes(&bytes[..bytes.len() - 2]).is_err());
+        assert!(Quote::from_bytes(&quote_bytes(3, 0, Some(&[1]))[..QUOTE_BODY_SIZE + 2]).is_err());
+        assert!(Quote::from_base64("not base64!").is_err());
+    }
+
+    #[test]
+    fn test_fuzz_quotes() {
+        // Truncated and corrupted quotes of every version are rejected or parsed, never panic
+        let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let seeds: Vec<Vec<u8>> = (1..6).map(|version| quote_bytes(version, 0, Some(&[7u8; 96]))).collect();
+        for _ in 0..5000 {
+            let mut bytes = seeds[next() as usize % seeds.len()].clone();
+            match next() % 3 {
+                0 => bytes.truncate(next() as usize % (bytes.len() + 1)),
+                1 => {
+                    let i = next() as usize % bytes.len();
+                    bytes[i] = next() as u8;
+                },
+                _ => bytes.extend((0..next() % 16).map(|_| next() as u8).collect::<Vec<u8>>()),
+            }
+            if let Ok(quote) = Quote::from_bytes(&bytes) {
+                assert!(quote.version() >= 1 && quote.version() <= 4);
+                assert_eq!(quote.to_bytes(), bytes);
+            }
+        }
+    }
+
+    // IAS, a peer's attestation or a client hands us these bytes, whatever they are
+    proptest! {
+        #[test]
+        fn prop_quote_from_bytes(bytes in vec(any::<u8>(), 0..QUOTE_BODY_SIZE + 128)) {
+            if let Ok(quote) = Quote::from_bytes(&bytes) {
+                prop_assert_eq!(quote.to_bytes(), bytes);
+                let _ = (quote.to_json(), quote.to_string());
+            }
+        }
+
+        #[test]
+        fn prop_byte_structs(bytes in vec(any::<u8>(), 0..QReportBody::SIZE + 8)) {
+            prop_assert_eq!(QBody::from_bytes(&bytes).is_ok(), bytes.len() == QBody::SIZE);
+            prop_assert_eq!(QEcdsaHeader::from_bytes(&bytes).is_ok(), bytes.len() == QEcdsaHeader::SIZE);
+            if let Ok(report_body) = QReportBody::from_bytes(&bytes) {
+                let mut written = Vec::new();
+                report_body.write_to(&mut written);
+                prop_assert_eq!(written, bytes);
+            }
+        }
+
+        #[test]
+        fn prop_quote_from_base64(encoded in ".{0,700}") {
+            let _ = Quote::from_base64(&encoded);
+        }
+
+        // A well-formed quote of any supported version parses, whatever its fields hold
+        #[test]
+        fn prop_valid_quotes(
+            version in 1u16..5,
+            fields in vec(any::<u8>(), QUOTE_BODY_SIZE - 2),
+            signature in proptest::option::of(vec(any::<u8>(), 0..1024)),
+        ) {
+            let mut bytes = version.to_le_bytes().to_vec();
+            bytes.extend(&fields);
+            if version == 4 {
+                bytes[4..8].copy_from_slice(&TEE_TYPE_SGX.to_le_bytes());
+            }
+            if let Some(signature) = &signature {
+                bytes.extend(&(signature.len() as u32).to_le_bytes());
+                bytes.extend(signature);
+            }
+            let quote = Quote::from_bytes(&bytes).unwrap();
+            prop_assert_eq!(quote.version(), version);
+            prop_assert_eq!(&quote.signature, &signature);
+            prop_assert_eq!(Quote::from_base64(&base64::encode(&bytes)).unwrap(), quote);
+        }
+    }
+}
@@ -0,0 +1,288 @@
+use crate::bundle::ReportBundle;
+use crate::errors::ReportErr;
+use crate::quote::Quote;
+use crate::signatures::Curve;
+use crate::verify;
+use failure::Error;
+use hex::FromHex;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+// What a report has to satisfy before the client talks to the enclave: who signs the reports,
+// and what enclave they must vouch for.
+#[derive(Debug, Clone)]
+pub enum Trust {
+    // An IAS report, signed by a certificate chaining up to this root (PEM), Intel's Attestation Report Signing CA.
+    Ias { root_ca: String },
+    // A server built with `sgx-sim` returns the bare quote, signed by no one: for development only.
+    Simulation,
+}
+
+impl Trust {
+    // IAS reports anchored at the Intel root pinned in `verify`
+    pub fn intel() -> Self {
+        Trust::Ias { root_ca: verify::INTEL_ROOT_CA.to_string() }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ReportPolicy {
+    pub trust: Trust,
+    // MRENCLAVE of the enclave build the client expects, any if `None`
+    pub mr_enclave: Option<[u8; 32]>,
+    // `isvEnclaveQuoteStatus` values to accept, only "OK" by default
+    pub allowed_statuses: Vec<String>,
+    pub allow_debug: bool,
+}
+
+impl ReportPolicy {
+    pub fn new(trust: Trust) -> Self {
+        ReportPolicy { trust, mr_enclave: None, allowed_statuses: vec!["OK".to_string()], allow_debug: false }
+    }
+
+    pub fn with_mr_enclave(mut self, mr_enclave: [u8; 32]) -> Self {
+        self.mr_enclave = Some(mr_enclave);
+        self
+    }
+
+    // e.g. "GROUP_OUT_OF_DATE" while the platforms get their TCB recovery
+    pub fn allow_status(mut self, status: &str) -> Self {
+        self.allowed_statuses.push(status.to_string());
+        self
+    }
+
+    pub fn allow_debug(mut self) -> Self {
+        self.allow_debug = true;
+        self
+    }
+}
+
+// The enclave a report vouches for.
+#[derive(Debug, Clone)]
+pub struct EnclaveIdentity {
+    // The address of the key signing the task keys, bound by the report data
+    pub signing_address: [u8; 20],
+    pub mr_enclave: [u8; 32],
+    pub quote_status: String,
+    pub quote: Quote,
+}
+
+fn report_err(message: &str) -> Error {
+    ReportErr { message: message.to_string() }.into()
+}
+
+// Checks `bundle` against `policy`, and that it binds `signing_address`: the first 20 bytes of the report data.
+// The `report` of `GetEnclaveReport` is hex encoded, decode it first (or use the `bundle` field).
+pub fn verify_enclave(bundle: &ReportBundle, signing_address: &[u8; 20], policy: &ReportPolicy) -> Result<EnclaveIdentity, Error> {
+    let (quote, quote_status) = match &policy.trust {
+        Trust::Ias { root_ca } => {
+            let verified = verify::verify_report(bundle, root_ca)?;
+            (verified.quote, verified.report.quote_status)
+        },
+        Trust::Simulation => (Quote::from_base64(bundle.report.trim())?, "OK".to_string()),
+    };
+
+    if !policy.allowed_statuses.contains(&quote_status) {
+        return Err(report_err(&format!("the quote status is {}", quote_status)));
+    }
+    let body = &quote.report_body;
+    if let Some(mr_enclave) = &policy.mr_enclave {
+        if &body.mr_enclave != mr_enclave {
+            return Err(report_err(&format!("unexpected MRENCLAVE {}", body.mr_enclave_hex())));
+        }
+    }
+    if body.attributes().is_debug() && !policy.allow_debug {
+        return Err(report_err("the enclave runs in debug mode"));
+    }
+    if &body.report_data[..20] != &signing_address[..] {
+        return Err(report_err("the report doesn't bind the signing key"));
+    }
+    Ok(EnclaveIdentity { signing_address: *signing_address, mr_enclave: body.mr_enclave, quote_status, quote })
+}
+
+// The settings an enclave enforces, the `settings` of `GetEnclaveInfo`. `policy` is the SHA-256 of the signed
+// policy in force, if any; `retention` is in seconds, 0 keeping everything. `signing_curve` is the curve of the
+// signing key, secp256k1 for the enclaves from before it was reported.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct EnclaveSettings {
+    pub version: u32,
+    pub policy: Option<String>,
+    pub min_overlap: i32,
+    pub distance: f64,
+    pub retention: u64,
+    pub strategy: Option<serde_json::Value>,
+    pub grid: f64,
+    pub time_bucket: i32,
+    pub replay_window: u64,
+    pub require_replay_protection: bool,
+    pub max_clock_skew: u64,
+    pub require_registration: bool,
+    pub health_authorities: Vec<String>,
+    // 0 for the enclaves from before it was reported
+    #[serde(default)]
+    pub declaration_max_age: u64,
+    pub export_k: u64,
+    pub statistics_epsilon: f64,
+    #[serde(default)]
+    pub signing_curve: Curve,
+}
+
+// Checks `settings` are the ones the verified `quote` commits to, their SHA-256 being bytes 20 to 52 of the
+// report data, and parses them.
+pub fn verify_settings(quote: &Quote, settings: &str) -> Result<EnclaveSettings, Error> {
+    if Sha256::digest(settings.as_bytes())[..] != quote.report_body.report_data[20..52] {
+        return Err(report_err("the report doesn't bind these settings"));
+    }
+    serde_json::from_str(settings).map_err(|e| report_err(&format!("the settings don't parse: {}", e)))
+}
+
+// The `signingKey` of `GetEnclaveReport`.
+pub fn parse_signing_address(signing_key: &str) -> Result<[u8; 20], Error> {
+    let bytes: Vec<u8> = signing_key.from_hex().map_err(|_| report_err("the signing key isn't hex"))?;
+    if bytes.len() != 20 {
+        return Err(report_err("the signing key must be a 20 bytes address"));
+    }
+    let mut address = [0u8; 20];
+    address.copy_from_slice(&bytes);
+    Ok(address)
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::quote::QUOTE_BODY_SIZE;
+    use openssl::{asn1::Asn1Time, bn::BigNum, hash::MessageDigest, pkey::{PKey, Private}, rsa::Rsa, sign::Signer};
+    use openssl::x509::{extension::{BasicConstraints, KeyUsage}, X509, X509Builder, X509NameBuilder};
+
+    // An EPID v2 quote body with `report_data` starting with `address`, see `Quote::from_bytes` for the offsets.
+    pub(crate) fn quote_body(mr_enclave: u8, address: &[u8; 20]) -> String {
+        let mut bytes = vec![0u8; QUOTE_BODY_SIZE];
+        bytes[0] = 2;
+        for byte in bytes[48 + 64..48 + 96].iter_mut() {
+            *byte = mr_enclave;
+        }
+        bytes[QUOTE_BODY_SIZE - 64..QUOTE_BODY_SIZE - 44].copy_from_slice(address);
+        base64::encode(&bytes)
+    }
+
+    // Self-signed without `issuer`. A CA may sign certificates and reports, the others reports if `may_sign`.
+    pub(crate) fn issue(name: &str, issuer: Option<&(X509, PKey<Private>)>, ca: bool, may_sign: bool, not_after: &Asn1Time) -> (X509, PKey<Private>) {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut subject = X509NameBuilder::new().unwrap();
+        subject.append_entry_by_text("CN", name).unwrap();
+        let subject = subject.build();
+        let mut builder = X509Builder::new().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap()).unwrap();
+        builder.set_subject_name(&subject).unwrap();
+        match issuer {
+            Some((certificate, _)) => builder.set_issuer_name(certificate.subject_name()).unwrap(),
+            None => builder.set_issuer_name(&subject).unwrap(),
+        }
+        builder.set_pubkey(&key).unwrap();
+        builder.set_not_before(&Asn1Time::from_unix(0).unwrap()).unwrap();
+        builder.set_not_after(not_after).unwrap();
+        if ca {
+            builder.append_extension(BasicConstraints::new().critical().ca().build().unwrap()).unwrap();
+            builder.append_extension(KeyUsage::new().critical().key_cert_sign().digital_signature().build().unwrap()).unwrap();
+        } else if may_sign {
+            builder.append_extension(KeyUsage::new().digital_signature().non_repudiation().build().unwrap()).unwrap();
+        } else {
+            builder.append_extension(KeyUsage::new().key_encipherment().build().unwrap()).unwrap();
+        }
+        builder.sign(issuer.map_or(&key, |(_, key)| key), MessageDigest::sha256()).unwrap();
+        (builder.build(), key)
+    }
+
+    pub(crate) fn certificate(name: &str) -> (X509, PKey<Private>) {
+        issue(name, None, true, true, &Asn1Time::days_from_now(1).unwrap())
+    }
+
+    pub(crate) fn signed_bundle(report: &str, key: &PKey<Private>, certificate: &X509) -> ReportBundle {
+        let mut signer = Signer::new(MessageDigest::sha256(), key).unwrap();
+        signer.update(report.as_bytes()).unwrap();
+        ReportBundle {
+            report: report.to_string(),
+            signature: base64::encode(&signer.sign_to_vec().unwrap()),
+            certificate: String::from_utf8(certificate.to_pem().unwrap()).unwrap(),
+            ca: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_verify_simulation_report() {
+        let address = [7u8; 20];
+        let bundle = ReportBundle { report: quote_body(1, &address), ..Default::default() };
+        let policy = ReportPolicy::new(Trust::Simulation);
+        let identity = verify_enclave(&bundle, &address, &policy).unwrap();
+        assert_eq!(identity.mr_enclave, [1u8; 32]);
+        assert_eq!(identity.quote_status, "OK");
+
+        assert!(verify_enclave(&bundle, &[8u8; 20], &policy).is_err());
+        assert!(verify_enclave(&bundle, &address, &policy.clone().with_mr_enclave([2u8; 32])).is_err());
+        assert!(verify_enclave(&bundle, &address, &policy.with_mr_enclave([1u8; 32])).is_ok());
+    }
+
+    #[test]
+    fn test_verify_ias_report() {
+        let address = [7u8; 20];
+        let (root, key) = certificate("Attestation Report Signing");
+        let root_pem = String::from_utf8(root.to_pem().unwrap()).unwrap();
+        let report = format!(r#"{{"id":"1","isvEnclaveQuoteStatus":"OK","isvEnclaveQuoteBody":"{}"}}"#, quote_body(1, &address));
+        let policy = ReportPolicy::new(Trust::Ias { root_ca: root_pem.clone() });
+
+        let bundle = signed_bundle(&report, &key, &root);
+        assert!(verify_enclave(&bundle, &address, &policy).is_ok());
+
+        // The report was changed after IAS signed it
+        let tampered = ReportBundle { report: report.replace(r#""id":"1""#, r#""id":"2""#), ..bundle.clone() };
+        assert!(verify_enclave(&tampered, &address, &policy).is_err());
+
+        // Signed by someone else
+        let (other, other_key) = certificate("Someone else");
+        assert!(verify_enclave(&signed_bundle(&report, &other_key, &other), &address, &policy).is_err());
+
+        let outdated = signed_bundle(&report.replace(r#""OK""#, r#""GROUP_OUT_OF_DATE""#), &key, &root);
+        assert!(verify_enclave(&outdated, &address, &policy).is_err());
+        assert!(verify_enclave(&outdated, &address, &policy.allow_status("GROUP_OUT_OF_DATE")).is_ok());
+    }
+
+    #[test]
+    fn test_x509_agrees_with_openssl() {
+        let (root, key) = certificate("Attestation Report Signing");
+        let (other, other_key) = certificate("Someone else");
+        let report = b"{\"id\":\"1\"}";
+        let mut signer = Signer::new(MessageDigest::sha256(), &key).unwrap();
+        signer.update(report).unwrap();
+        let signature = signer.sign_to_vec().unwrap();
+
+        let root_der = root.to_der().unwrap();
+        assert!(crate::x509::verify_report_signature(&root_der, &root_der, report, &signature).is_ok());
+        assert!(crate::x509::verify_report_signature(&root_der, &root_der, b"{}", &signature).is_err());
+        assert!(crate::x509::verify_report_signature(&root_der, &other.to_der().unwrap(), report, &signature).is_err());
+        let mut signer = Signer::new(MessageDigest::sha256(), &other_key).unwrap();
+        signer.update(report).unwrap();
+        assert!(crate::x509::verify_report_signature(&root_der, &root_der, report, &signer.sign_to_vec().unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_verify_settings() {
+        let settings = r#"{"version":1,"policy":null,"minOverlap":300,"distance":10.0,"retention":1209600,"strategy":null,"grid":0.0,"timeBucket":0,"replayWindow":300,"requireReplayProtection":false,"maxClockSkew":300,"requireRegistration":true,"healthAuthorities":[],"exportK":0,"statisticsEpsilon":0.0}"#;
+        let mut body = base64::decode(&quote_body(1, &[7u8; 20])).unwrap();
+        body[QUOTE_BODY_SIZE - 44..QUOTE_BODY_SIZE - 12].copy_from_slice(&Sha256::digest(settings.as_bytes()));
+        let quote = Quote::from_base64(&base64::encode(&body)).unwrap();
+
+        let parsed = verify_settings(&quote, settings).unwrap();
+        assert_eq!((parsed.retention, parsed.require_registration, parsed.policy), (1209600, true, None));
+        assert_eq!(parsed.signing_curve, Curve::Secp256k1);
+        // Not the settings the enclave hashed
+        assert!(verify_settings(&quote, &settings.replace("1209600", "0")).is_err());
+    }
+
+    #[test]
+    fn test_parse_signing_address() {
+        assert_eq!(parse_signing_address("5f9d3d0a1c1e7b3f2a6c8e4d0b9a7c5e3f1d2b4a").unwrap()[0], 0x5f);
+        assert!(parse_signing_address("5f9d").is_err());
+    }
+}
@@ -0,0 +1,166 @@
+use crate::errors::SessionErr;
+use crate::signatures;
+use enigma_crypto::{asymmetric::KeyPair, symmetric};
+use enigma_tools_m::primitives::km_primitives::UserMessage;
+use enigma_types::{DhKey, PubKey};
+use failure::Error;
+use hex::{FromHex, ToHex};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+// The key exchange with the enclave. `NewTaskEncryptionKey` returns an ephemeral enclave key (the task key)
+// signed by the enclave signing key, whose address the attestation report binds. Once the signature checks
+// out, both sides derive the same AES-256-GCM key by ECDH between the task key and the user key: the host
+// relays the ciphertexts but can't read them, nor swap the task key for one of its own.
+// The enclave forgets its side of the key after a single request, so a session serves one request.
+
+// The marker of the decoy user ids, see `decoy` in the enclave
+const DECOY_LABEL: &[u8] = b"safetrace:decoy:";
+pub struct Session {
+    task_pubkey: PubKey,
+    key: DhKey,
+}
+
+fn session_err(message: &str) -> Error {
+    SessionErr { message: message.to_string() }.into()
+}
+
+fn parse_hex<'a>(hex: &str, out: &'a mut [u8], field: &str) -> Result<&'a [u8], Error> {
+    let bytes: Vec<u8> = hex.from_hex().map_err(|_| session_err(&format!("{} isn't hex", field)))?;
+    if bytes.len() != out.len() {
+        return Err(session_err(&format!("{} must be {} bytes long", field, out.len())));
+    }
+    out.copy_from_slice(&bytes);
+    Ok(out)
+}
+
+impl Session {
+    // `task_pubkey` and `sig` as returned by `NewTaskEncryptionKey`, `signing_address` from the verified report.
+    pub fn new(user_key: &KeyPair, task_pubkey: &str, sig: &str, signing_address: &[u8; 20]) -> Result<Self, Error> {
+        let mut pubkey = [0u8; 64];
+        let mut signature = [0u8; 65];
+        parse_hex(task_pubkey, &mut pubkey, "taskPubKey")?;
+        parse_hex(sig, &mut signature, "sig")?;
+
+        let signed = UserMessage::new(pubkey).to_sign();
+        if !signatures::signed_by(&signed, &signature, signing_address) {
+            return Err(session_err("the task key isn't signed by the attested enclave"));
+        }
+        let key = user_key.derive_key(&pubkey).map_err(|e| session_err(&format!("key derivation failed: {:?}", e)))?;
+        Ok(Session { task_pubkey: pubkey, key })
+    }
+
+    pub fn task_pubkey(&self) -> &PubKey { &self.task_pubkey }
+
+    // Hex, the way the server takes `encryptedUserId` and `encryptedData`.
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<String, Error> {
+        let ciphertext = symmetric::encrypt(plaintext, &self.key).map_err(|e| session_err(&format!("encryption failed: {:?}", e)))?;
+        Ok(ciphertext.to_hex())
+    }
+
+    // The `encryptedSignature` of a request: the signature of the raw bytes of `parts` (hex fields of the request,
+    // in order) with the key the user registered, see `users` in the enclave.
+    pub fn sign(&self, signing_key: &KeyPair, parts: &[&str]) -> Result<String, Error> {
+        let mut signed = Vec::new();
+        for part in parts {
+            let bytes: Vec<u8> = part.from_hex().map_err(|_| session_err("the signed fields must be hex"))?;
+            signed.extend(bytes);
+        }
+        let signature = signing_key.sign(&signed).map_err(|e| session_err(&format!("signing failed: {:?}", e)))?;
+        self.encrypt(&signature)
+    }
+
+    // The `encryptedData` of `RegisterUser`: the signing key, and the signature of `user_pubkey` proving we hold it.
+    pub fn registration(&self, signing_key: &KeyPair, user_pubkey: &PubKey) -> Result<String, Error> {
+        let signature = signing_key.sign(&user_pubkey[..]).map_err(|e| session_err(&format!("signing failed: {:?}", e)))?;
+        let mut registration = signing_key.get_pubkey().to_vec();
+        registration.extend_from_slice(&signature);
+        self.encrypt(&registration)
+    }
+
+    // A user id for a decoy `findMatch` of this session: 16 random bytes and the first 16 bytes of their HMAC-SHA256
+    // under the session key, hex. Only the enclave, which holds the key too, tells it from a user id.
+    pub fn decoy_userid(&self) -> String {
+        let nonce: [u8; 16] = rand::random();
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.key).expect("HMAC takes keys of any length");
+        mac.input(DECOY_LABEL);
+        mac.input(&nonce);
+        let mut userid = nonce.to_vec();
+        userid.extend_from_slice(&mac.result().code()[..16]);
+        userid.to_hex()
+    }
+
+    pub fn decrypt(&self, ciphertext: &str) -> Result<Vec<u8>, Error> {
+        let ciphertext: Vec<u8> = ciphertext.from_hex().map_err(|_| session_err("the ciphertext isn't hex"))?;
+        symmetric::decrypt(&ciphertext, &self.key).map_err(|_| session_err("the enclave output doesn't decrypt with the session key"))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use enigma_tools_m::utils::EthereumAddress;
+
+    // The enclave side of `NewTaskEncryptionKey`, see `get_user_key_internal`.
+    pub(crate) fn enclave_task_key(signing_key: &KeyPair, user_pubkey: &PubKey) -> (String, String, DhKey) {
+        let task_key = KeyPair::new().unwrap();
+        let sig = signing_key.sign(&UserMessage::new(task_key.get_pubkey()).to_sign()).unwrap();
+        let key = task_key.derive_key(user_pubkey).unwrap();
+        (task_key.get_pubkey().to_hex(), sig.to_hex(), key)
+    }
+
+    #[test]
+    fn test_session() {
+        let signing_key = KeyPair::new().unwrap();
+        let user_key = KeyPair::new().unwrap();
+        let (task_pubkey, sig, enclave_key) = enclave_task_key(&signing_key, &user_key.get_pubkey());
+
+        let session = Session::new(&user_key, &task_pubkey, &sig, &signing_key.get_pubkey().address()).unwrap();
+        let ciphertext = session.encrypt(b"user1").unwrap();
+        let sent: Vec<u8> = ciphertext.from_hex().unwrap();
+        assert_eq!(symmetric::decrypt(&sent, &enclave_key).unwrap(), b"user1".to_vec());
+
+        let output = symmetric::encrypt(b"[]", &enclave_key).unwrap();
+        assert_eq!(session.decrypt(&output.to_hex()).unwrap(), b"[]".to_vec());
+        assert!(session.decrypt("00ff").is_err());
+
+        // What `decoy::is_decoy` checks
+        let decoy: Vec<u8> = session.decoy_userid().from_hex().unwrap();
+        assert_eq!(decoy.len(), 32);
+        let mut mac = Hmac::<Sha256>::new_varkey(&enclave_key).unwrap();
+        mac.input(DECOY_LABEL);
+        mac.input(&decoy[..16]);
+        assert_eq!(mac.result().code()[..16], decoy[16..]);
+        assert_ne!(session.decoy_userid(), session.decoy_userid());
+    }
+
+    #[test]
+    fn test_session_signatures() {
+        let signing_key = KeyPair::new().unwrap();
+        let user_key = KeyPair::new().unwrap();
+        let (task_pubkey, sig, enclave_key) = enclave_task_key(&signing_key, &user_key.get_pubkey());
+        let session = Session::new(&user_key, &task_pubkey, &sig, &signing_key.get_pubkey().address()).unwrap();
+
+        // What `users::authenticate` checks
+        let user_signing_key = KeyPair::new().unwrap();
+        let signature: Vec<u8> = session.sign(&user_signing_key, &["e1a3", "9f8e"]).unwrap().from_hex().unwrap();
+        let mut signature_bytes = [0u8; 65];
+        signature_bytes.copy_from_slice(&symmetric::decrypt(&signature, &enclave_key).unwrap());
+        assert_eq!(KeyPair::recover(&[0xe1, 0xa3, 0x9f, 0x8e], signature_bytes).unwrap()[..], user_signing_key.get_pubkey()[..]);
+        assert!(session.sign(&user_signing_key, &["xyz"]).is_err());
+
+        let registration: Vec<u8> = session.registration(&user_signing_key, &user_key.get_pubkey()).unwrap().from_hex().unwrap();
+        let registration = symmetric::decrypt(&registration, &enclave_key).unwrap();
+        assert_eq!(registration.len(), 64 + 65);
+        assert_eq!(registration[..64], user_signing_key.get_pubkey()[..]);
+    }
+
+    #[test]
+    fn test_session_rejects_other_signers() {
+        let signing_key = KeyPair::new().unwrap();
+        let user_key = KeyPair::new().unwrap();
+        let (task_pubkey, sig, _) = enclave_task_key(&KeyPair::new().unwrap(), &user_key.get_pubkey());
+        assert!(Session::new(&user_key, &task_pubkey, &sig, &signing_key.get_pubkey().address()).is_err());
+        assert!(Session::new(&user_key, "abcd", &sig, &signing_key.get_pubkey().address()).is_err());
+    }
+}
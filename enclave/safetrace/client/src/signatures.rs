@@ -0,0 +1,196 @@
+use crate::errors::SignatureErr;
+use enigma_crypto::asymmetric::KeyPair;
+use enigma_tools_m::utils::EthereumAddress;
+use failure::Error;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+// The signatures of the enclave. Its signing key is on one of two curves, picked per deployment
+// (`enclave.signing_curve` of the host, `signingCurve` of the settings the quotes commit to):
+//
+//   secp256k1  keccak256 of the message, what Ethereum's `ecrecover` checks
+//   p256       SHA-256 of the message, NIST P-256 (secp256r1) as the SGX SDK and the P-256 precompiles sign it
+//
+// Either way a signature is 65 bytes, r || s || v, r and s big endian, s in the lower half of the order and
+// v = 27 + the parity of the y of R, so a contract recovers the signer instead of being handed its key. The
+// address of a key is the last 20 bytes of the keccak256 of its 64 bytes (x || y), the one of the reports:
+// a signature checks against an address on whichever curve recovers to it.
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Curve {
+    Secp256k1,
+    P256,
+}
+
+impl Default for Curve {
+    fn default() -> Self { Curve::Secp256k1 }
+}
+
+impl Curve {
+    pub fn parse(name: &str) -> Option<Curve> {
+        match name {
+            "secp256k1" => Some(Curve::Secp256k1),
+            "p256" => Some(Curve::P256),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Curve::Secp256k1 => "secp256k1",
+            Curve::P256 => "p256",
+        }
+    }
+
+    // As the enclave takes it
+    pub fn code(self) -> u8 {
+        match self {
+            Curve::Secp256k1 => 0,
+            Curve::P256 => 1,
+        }
+    }
+}
+
+fn signature_err(message: &str) -> Error {
+    SignatureErr { message: message.to_string() }.into()
+}
+
+// The P-256 key that signed `hash`, x || y.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn recover_p256(hash: &[u8; 32], signature: &[u8; 65]) -> Result<[u8; 64], Error> {
+    use openssl::bn::{BigNum, BigNumContext};
+    use openssl::ec::{EcGroup, EcPoint, PointConversionForm};
+    use openssl::nid::Nid;
+
+    if signature[64] != 27 && signature[64] != 28 {
+        return Err(signature_err("v must be 27 or 28"));
+    }
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)?;
+    let mut ctx = BigNumContext::new()?;
+    let mut order = BigNum::new()?;
+    group.order(&mut order, &mut ctx)?;
+    let zero = BigNum::new()?;
+    let r = BigNum::from_slice(&signature[..32])?;
+    let s = BigNum::from_slice(&signature[32..64])?;
+    if r == zero || s == zero || r >= order || s >= order {
+        return Err(signature_err("r and s must be within the order of the curve"));
+    }
+    // R, from its x and the parity of its y
+    let mut compressed = vec![2 + signature[64] - 27];
+    compressed.extend_from_slice(&signature[..32]);
+    let point = EcPoint::from_bytes(&group, &compressed, &mut ctx).map_err(|_| signature_err("r isn't the x of a point"))?;
+
+    // Q = r^-1 (s R - e G)
+    let mut r_inv = BigNum::new()?;
+    r_inv.mod_inverse(&r, &order, &mut ctx)?;
+    let mut e = BigNum::new()?;
+    e.nnmod(&BigNum::from_slice(hash)?, &order, &mut ctx)?;
+    let mut minus_e = BigNum::new()?;
+    minus_e.checked_sub(&order, &e)?;
+    let (mut u1, mut u2) = (BigNum::new()?, BigNum::new()?);
+    u1.mod_mul(&minus_e, &r_inv, &order, &mut ctx)?;
+    u2.mod_mul(&s, &r_inv, &order, &mut ctx)?;
+    let mut signer = EcPoint::new(&group)?;
+    signer.mul_full(&group, &u1, &point, &u2, &mut ctx)?;
+    if signer.is_infinity(&group) {
+        return Err(signature_err("the signature recovers to no key"));
+    }
+    let bytes = signer.to_bytes(&group, PointConversionForm::UNCOMPRESSED, &mut ctx)?;
+    let mut pubkey = [0u8; 64];
+    pubkey.copy_from_slice(&bytes[1..]);
+    Ok(pubkey)
+}
+
+// No openssl for wasm32
+#[cfg(target_arch = "wasm32")]
+pub fn recover_p256(_hash: &[u8; 32], _signature: &[u8; 65]) -> Result<[u8; 64], Error> {
+    Err(signature_err("P-256 signatures can't be recovered on wasm32"))
+}
+
+// The key of `curve` that signed `message`.
+pub fn recover(curve: Curve, message: &[u8], signature: &[u8; 65]) -> Result<[u8; 64], Error> {
+    match curve {
+        Curve::Secp256k1 => KeyPair::recover(message, *signature).map_err(|_| signature_err("invalid secp256k1 signature")),
+        Curve::P256 => {
+            let mut hash = [0u8; 32];
+            hash.copy_from_slice(&Sha256::digest(message));
+            recover_p256(&hash, signature)
+        },
+    }
+}
+
+// Whether `signature` over `message` is the one of the key at `address`, on either curve.
+pub fn signed_by(message: &[u8], signature: &[u8; 65], address: &[u8; 20]) -> bool {
+    [Curve::Secp256k1, Curve::P256].iter()
+        .any(|&curve| recover(curve, message, signature).map_or(false, |signer| signer.address() == *address))
+}
+
+#[cfg(all(test, not(target_arch = "wasm32")))]
+mod test {
+    use super::*;
+    use openssl::bn::{BigNum, BigNumContext};
+    use openssl::ec::{EcGroup, EcKey, PointConversionForm};
+    use openssl::ecdsa::EcdsaSig;
+    use openssl::nid::Nid;
+
+    // A P-256 signature as the enclave makes it: low s, and the v that recovers `pubkey`.
+    fn sign_p256(key: &EcKey<openssl::pkey::Private>, pubkey: &[u8; 64], message: &[u8]) -> [u8; 65] {
+        let mut hash = [0u8; 32];
+        hash.copy_from_slice(&Sha256::digest(message));
+        let sig = EcdsaSig::sign(&hash, key).unwrap();
+        let mut ctx = BigNumContext::new().unwrap();
+        let mut order = BigNum::new().unwrap();
+        key.group().order(&mut order, &mut ctx).unwrap();
+        let mut half = BigNum::new().unwrap();
+        half.rshift1(&order).unwrap();
+        let mut s = sig.s().to_owned().unwrap();
+        if s > half {
+            let mut low = BigNum::new().unwrap();
+            low.checked_sub(&order, &s).unwrap();
+            s = low;
+        }
+        let mut signature = [0u8; 65];
+        signature[..32].copy_from_slice(&sig.r().to_vec_padded(32).unwrap());
+        signature[32..64].copy_from_slice(&s.to_vec_padded(32).unwrap());
+        for v in &[27, 28] {
+            signature[64] = *v;
+            if recover_p256(&hash, &signature).ok() == Some(*pubkey) {
+                return signature;
+            }
+        }
+        panic!("no v recovers the key");
+    }
+
+    #[test]
+    fn test_recover_both_curves() {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = EcKey::generate(&group).unwrap();
+        let mut ctx = BigNumContext::new().unwrap();
+        let bytes = key.public_key().to_bytes(&group, PointConversionForm::UNCOMPRESSED, &mut ctx).unwrap();
+        let mut pubkey = [0u8; 64];
+        pubkey.copy_from_slice(&bytes[1..]);
+
+        let message = b"safetrace-response";
+        let signature = sign_p256(&key, &pubkey, message);
+        assert_eq!(recover(Curve::P256, message, &signature).unwrap()[..], pubkey[..]);
+        assert!(signed_by(message, &signature, &pubkey.address()));
+        assert!(!signed_by(b"another message", &signature, &pubkey.address()));
+        // The other v is another key
+        let mut flipped = signature;
+        flipped[64] = 55 - signature[64];
+        assert!(!signed_by(message, &flipped, &pubkey.address()));
+        let mut zero = signature;
+        zero[..32].copy_from_slice(&[0u8; 32]);
+        assert!(recover(Curve::P256, message, &zero).is_err());
+
+        let keypair = KeyPair::new().unwrap();
+        let signature = keypair.sign(message).unwrap();
+        assert!(signed_by(message, &signature, &keypair.get_pubkey().address()));
+        assert!(!signed_by(message, &signature, &pubkey.address()));
+
+        assert_eq!(Curve::parse("p256").map(Curve::name), Some("p256"));
+        assert_eq!(serde_json::to_string(&Curve::Secp256k1).unwrap(), "\"secp256k1\"");
+        assert!(Curve::parse("secp256r1").is_none());
+    }
+}
@@ -0,0 +1,90 @@
+use crate::errors::TagErr;
+use failure::Error;
+use hex::FromHex;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+// Authentication tags of the IPC frames, for a trusted gateway and the app sharing a key (`server.frame_key_path`
+// in the app): each frame starts with the HMAC-SHA256 of the rest of it under the key, 32 bytes. The app checks
+// the tag before reading anything else of the frame, so traffic from whoever doesn't hold the key is turned away
+// without being parsed, and tags its answers the same way. Empty frames, the answers to JSON-RPC notifications,
+// aren't tagged.
+
+pub const TAG_SIZE: usize = 32;
+// The shortest key, as long as the tag
+pub const MIN_KEY_SIZE: usize = 32;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Clone)]
+pub struct FrameKey(Vec<u8>);
+
+impl FrameKey {
+    pub fn new(key: &[u8]) -> Result<Self, Error> {
+        if key.len() < MIN_KEY_SIZE {
+            return Err(tag_err(format!("the frame key must be at least {} bytes", MIN_KEY_SIZE)));
+        }
+        Ok(FrameKey(key.to_vec()))
+    }
+
+    pub fn from_hex(key: &str) -> Result<Self, Error> {
+        let key: Vec<u8> = key.trim().from_hex().map_err(|_| tag_err("the frame key isn't hex".to_string()))?;
+        Self::new(&key)
+    }
+
+    fn mac(&self, body: &[u8]) -> HmacSha256 {
+        // HMAC takes keys of any length
+        let mut mac = HmacSha256::new_varkey(&self.0).unwrap();
+        mac.input(body);
+        mac
+    }
+
+    // `body` with its tag in front.
+    pub fn tag(&self, body: &[u8]) -> Vec<u8> {
+        if body.is_empty() {
+            return Vec::new();
+        }
+        let mut frame = self.mac(body).result().code().to_vec();
+        frame.extend_from_slice(body);
+        frame
+    }
+
+    // The body of `frame`, None unless its tag is right. The comparison takes the same time wherever they differ.
+    pub fn verify<'a>(&self, frame: &'a [u8]) -> Option<&'a [u8]> {
+        if frame.len() < TAG_SIZE {
+            return None;
+        }
+        let (tag, body) = frame.split_at(TAG_SIZE);
+        self.mac(body).verify(tag).ok().map(|_| body)
+    }
+}
+
+fn tag_err(message: String) -> Error {
+    TagErr { message }.into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_frame_tags() {
+        assert!(FrameKey::new(&[7u8; 16]).is_err());
+        assert!(FrameKey::from_hex("not hex").is_err());
+        let key = FrameKey::from_hex(&"2b".repeat(32)).unwrap();
+        let body = br#"{"id":"a1","type":"Ping","nonce":"a1"}"#;
+        let frame = key.tag(body);
+        assert_eq!(frame.len(), TAG_SIZE + body.len());
+        assert_eq!(key.verify(&frame), Some(&body[..]));
+
+        // A bit flipped anywhere, another key or no tag at all
+        for i in &[0, TAG_SIZE, frame.len() - 1] {
+            let mut forged = frame.clone();
+            forged[*i] ^= 1;
+            assert_eq!(key.verify(&forged), None);
+        }
+        assert_eq!(FrameKey::new(&[1u8; 32]).unwrap().verify(&frame), None);
+        assert_eq!(key.verify(body), None);
+        assert!(key.tag(b"").is_empty());
+    }
+}
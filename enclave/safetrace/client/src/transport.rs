@@ -0,0 +1,182 @@
+use failure::Error;
+use serde_json::Value;
+#[cfg(feature = "transport")]
+use crate::errors::{ErrorCode, ServerErr, TagErr};
+#[cfg(feature = "transport")]
+use crate::messages::{self, to_jsonrpc};
+#[cfg(feature = "transport")]
+use crate::tags::FrameKey;
+#[cfg(feature = "transport")]
+use crate::wire::WireFormat;
+#[cfg(feature = "transport")]
+use serde_json::json;
+#[cfg(feature = "transport")]
+use std::time::{Duration, Instant};
+
+// Sends one native request (see `messages`) and returns the native reply, whichever way the server is reached.
+pub trait Transport {
+    fn call(&self, request: Value) -> Result<Value, Error>;
+}
+
+#[cfg(feature = "transport")]
+fn transport_err(request: &Value, message: &str) -> Error {
+    rpc_err(request, message, None)
+}
+
+#[cfg(feature = "transport")]
+fn rpc_err(request: &Value, message: &str, code: Option<ErrorCode>) -> Error {
+    ServerErr { request: request["type"].as_str().unwrap_or_default().to_string(), message: message.to_string(), code }.into()
+}
+
+// Straight to the app's ZMQ socket, e.g. "tcp://localhost:5552" or "unix:///run/safetrace/ipc.sock" on the same host,
+// in JSON unless `with_format` picks msgpack.
+//
+// `with_heartbeat` is for connections that may go half-open, e.g. of a mobile gateway: while it waits for an answer
+// the transport sends a `Heartbeat` every `interval`, and gives up once nothing at all came back for `idle_timeout`
+// rather than waiting out the whole timeout of a `FindMatch`. Keep `idle_timeout` below the `idleTimeout` the
+// server announces.
+//
+// `with_frame_key` tags every frame with the key the app shares with its gateway, see `tags`.
+#[cfg(feature = "transport")]
+pub struct ZmqTransport {
+    context: zmq::Context,
+    uri: String,
+    timeout: Duration,
+    format: WireFormat,
+    // The interval and the idle timeout
+    heartbeat: Option<(Duration, Duration)>,
+    frame_key: Option<FrameKey>,
+}
+
+#[cfg(feature = "transport")]
+impl ZmqTransport {
+    pub fn new(uri: &str) -> Self {
+        // ZMQ calls the Unix domain sockets `ipc://`
+        let uri = if uri.starts_with("unix://") { uri.replacen("unix://", "ipc://", 1) } else { uri.to_string() };
+        ZmqTransport {
+            context: zmq::Context::new(), uri, timeout: Duration::from_secs(30), format: WireFormat::Json, heartbeat: None, frame_key: None,
+        }
+    }
+
+    pub fn with_format(mut self, format: WireFormat) -> Self {
+        self.format = format;
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_heartbeat(mut self, interval: Duration, idle_timeout: Duration) -> Self {
+        self.heartbeat = Some((interval, idle_timeout));
+        self
+    }
+
+    pub fn with_frame_key(mut self, key: FrameKey) -> Self {
+        self.frame_key = Some(key);
+        self
+    }
+
+    fn encode(&self, request: &Value) -> Result<Vec<u8>, Error> {
+        let frame = self.format.encode(request)?;
+        Ok(match &self.frame_key {
+            Some(key) => key.tag(&frame),
+            None => frame,
+        })
+    }
+
+    // The reply comes in the encoding of the request
+    fn decode(&self, frame: &[u8]) -> Result<Value, Error> {
+        let body = match &self.frame_key {
+            Some(key) => key.verify(frame).ok_or_else(|| TagErr { message: "the tag of the answer doesn't verify".to_string() })?,
+            None => frame,
+        };
+        self.format.decode(body)
+    }
+
+    // A DEALER socket, which may send heartbeats before the answer is in. The server tells the answers apart by `id`.
+    fn call_with_heartbeat(&self, request: Value, interval: Duration, idle_timeout: Duration) -> Result<Value, Error> {
+        let socket = self.context.socket(zmq::DEALER)?;
+        socket.set_sndtimeo(self.timeout.as_millis() as i32)?;
+        socket.set_linger(0)?;
+        socket.connect(&self.uri)?;
+        socket.send(&self.encode(&request)?, 0)?;
+        let (started, mut heard, mut beats) = (Instant::now(), Instant::now(), 0);
+        loop {
+            let left = match self.timeout.checked_sub(started.elapsed()) {
+                Some(left) => left,
+                None => return Err(transport_err(&request, "timed out waiting for the reply")),
+            };
+            let quiet = match idle_timeout.checked_sub(heard.elapsed()) {
+                Some(quiet) => quiet,
+                None => return Err(transport_err(&request, "the server stopped answering heartbeats, the connection looks half-open")),
+            };
+            let wait = interval.min(left).min(quiet);
+            if socket.poll(zmq::POLLIN, wait.as_millis() as i64)? > 0 {
+                let reply = self.decode(&socket.recv_bytes(0)?)?;
+                heard = Instant::now();
+                if reply["id"] == request["id"] {
+                    return Ok(reply);
+                }
+                // The answer to a heartbeat
+                continue;
+            }
+            beats += 1;
+            let id = format!("{}-heartbeat-{}", request["id"].as_str().unwrap_or_default(), beats);
+            socket.send(&self.encode(&messages::heartbeat(&id, messages::now_millis()))?, 0)?;
+        }
+    }
+}
+
+#[cfg(feature = "transport")]
+impl Transport for ZmqTransport {
+    fn call(&self, request: Value) -> Result<Value, Error> {
+        if let Some((interval, idle_timeout)) = self.heartbeat {
+            return self.call_with_heartbeat(request, interval, idle_timeout);
+        }
+        // A REQ socket stays stuck after a lost reply, a new one per call keeps it simple
+        let socket = self.context.socket(zmq::REQ)?;
+        let timeout = self.timeout.as_millis() as i32;
+        socket.set_rcvtimeo(timeout)?;
+        socket.set_sndtimeo(timeout)?;
+        socket.set_linger(0)?;
+        socket.connect(&self.uri)?;
+        socket.send(&self.encode(&request)?, 0)?;
+        let reply = socket.recv_bytes(0).map_err(|e| match e {
+            zmq::Error::EAGAIN => transport_err(&request, "timed out waiting for the reply"),
+            e => e.into(),
+        })?;
+        self.decode(&reply)
+    }
+}
+
+// Through the api-server, JSON-RPC 2.0 over HTTP, e.g. "http://localhost:8080".
+#[cfg(feature = "transport")]
+pub struct HttpTransport {
+    client: reqwest::Client,
+    url: String,
+}
+
+#[cfg(feature = "transport")]
+impl HttpTransport {
+    pub fn new(url: &str) -> Result<Self, Error> {
+        let client = reqwest::Client::builder().timeout(Duration::from_secs(30)).build()?;
+        Ok(HttpTransport { client, url: url.to_string() })
+    }
+}
+
+#[cfg(feature = "transport")]
+impl Transport for HttpTransport {
+    fn call(&self, request: Value) -> Result<Value, Error> {
+        let (method, params) = to_jsonrpc(&request);
+        let body = json!({"jsonrpc": "2.0", "id": request["id"], "method": method, "params": params});
+        let mut response = self.client.post(&self.url).json(&body).send()?;
+        let reply: Value = response.json()?;
+        if let Some(error) = reply.get("error") {
+            let code = error["errorCode"].as_u64().and_then(ErrorCode::from_code);
+            return Err(rpc_err(&request, error["message"].as_str().unwrap_or("JSON-RPC error"), code));
+        }
+        reply.get("result").cloned().ok_or_else(|| transport_err(&request, "the JSON-RPC reply has no result"))
+    }
+}
@@ -0,0 +1,242 @@
+use crate::bundle::{self, ReportBundle};
+use crate::errors::ReportErr;
+use crate::quote::Quote;
+use crate::x509;
+use failure::Error;
+#[cfg(not(target_arch = "wasm32"))]
+use openssl::{hash::MessageDigest, sign::Verifier, stack::Stack, x509::{store::X509StoreBuilder, X509, X509StoreContext}};
+use serde::Deserialize;
+#[cfg(not(target_arch = "wasm32"))]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Checks an IAS report on its own, without talking to IAS nor to the server: the signature over the report, the
+// certificate chain behind it, and the quote inside. Everything a client, or an auditor going through stored
+// bundles, needs offline. There's no networking here, only the certificates and the report.
+//
+// The chain must end at a root the caller trusts, usually `INTEL_ROOT_CA`. The CA certificate IAS sends along is
+// only used to build the chain: a report signed under any other root doesn't verify, even if the CA it came with
+// issued the signing certificate. Every certificate of the chain, the root included, must be valid and allowed
+// its role: the CAs must be CAs that may sign certificates, the signing certificate must be allowed to sign (see
+// `x509::verify_chain`).
+
+// Intel's SGX Attestation Report Signing CA, the root of the certificates signing IAS reports. Valid until 2049.
+// SHA-256 fingerprint 7B:42:E4:1E:C4:3B:91:DB:83:4A:06:5D:E4:F9:8A:13:C4:4D:69:55:70:E8:39:CF:A8:92:1E:58:4E:40:73:5D
+pub const INTEL_ROOT_CA: &str = "\
+-----BEGIN CERTIFICATE-----
+MIIFSzCCA7OgAwIBAgIJANEHdl0yo7CUMA0GCSqGSIb3DQEBCwUAMH4xCzAJBgNV
+BAYTAlVTMQswCQYDVQQIDAJDQTEUMBIGA1UEBwwLU2FudGEgQ2xhcmExGjAYBgNV
+BAoMEUludGVsIENvcnBvcmF0aW9uMTAwLgYDVQQDDCdJbnRlbCBTR1ggQXR0ZXN0
+YXRpb24gUmVwb3J0IFNpZ25pbmcgQ0EwIBcNMTYxMTE0MTUzNzMxWhgPMjA0OTEy
+MzEyMzU5NTlaMH4xCzAJBgNVBAYTAlVTMQswCQYDVQQIDAJDQTEUMBIGA1UEBwwL
+U2FudGEgQ2xhcmExGjAYBgNVBAoMEUludGVsIENvcnBvcmF0aW9uMTAwLgYDVQQD
+DCdJbnRlbCBTR1ggQXR0ZXN0YXRpb24gUmVwb3J0IFNpZ25pbmcgQ0EwggGiMA0G
+CSqGSIb3DQEBAQUAA4IBjwAwggGKAoIBgQCfPGR+tXc8u1EtJzLA10Feu1Wg+p7e
+LmSRmeaCHbkQ1TF3Nwl3RmpqXkeGzNLd69QUnWovYyVSndEMyYc3sHecGgfinEeh
+rgBJSEdsSJ9FpaFdesjsxqzGRa20PYdnnfWcCTvFoulpbFR4VBuXnnVLVzkUvlXT
+L/TAnd8nIZk0zZkFJ7P5LtePvykkar7LcSQO85wtcQe0R1Raf/sQ6wYKaKmFgCGe
+NpEJUmg4ktal4qgIAxk+QHUxQE42sxViN5mqglB0QJdUot/o9a/V/mMeH8KvOAiQ
+byinkNndn+Bgk5sSV5DFgF0DffVqmVMblt5p3jPtImzBIH0QQrXJq39AT8cRwP5H
+afuVeLHcDsRp6hol4P+ZFIhu8mmbI1u0hH3W/0C2BuYXB5PC+5izFFh/nP0lc2Lf
+6rELO9LZdnOhpL1ExFOq9H/B8tPQ84T3Sgb4nAifDabNt/zu6MmCGo5U8lwEFtGM
+RoOaX4AS+909x00lYnmtwsDVWv9vBiJCXRsCAwEAAaOByTCBxjBgBgNVHR8EWTBX
+MFWgU6BRhk9odHRwOi8vdHJ1c3RlZHNlcnZpY2VzLmludGVsLmNvbS9jb250ZW50
+L0NSTC9TR1gvQXR0ZXN0YXRpb25SZXBvcnRTaWduaW5nQ0EuY3JsMB0GA1UdDgQW
+BBR4Q3t2pn680K9+QjfrNXw7hwFRPDAfBgNVHSMEGDAWgBR4Q3t2pn680K9+Qjfr
+NXw7hwFRPDAOBgNVHQ8BAf8EBAMCAQYwEgYDVR0TAQH/BAgwBgEB/wIBADANBgkq
+hkiG9w0BAQsFAAOCAYEAeF8tYMXICvQqeXYQITkV2oLJsp6J4JAqJabHWxYJHGir
+IEqucRiJSSx+HjIJEUVaj8E0QjEud6Y5lNmXlcjqRXaCPOqK0eGRz6hi+ripMtPZ
+sFNaBwLQVV905SDjAzDzNIDnrcnXyB4gcDFCvwDFKKgLRjOB/WAqgscDUoGq5ZVi
+zLUzTqiQPmULAQaB9c6Oti6snEFJiCQ67JLyW/E83/frzCmO5Ru6WjU4tmsmy8Ra
+Ud4APK0wZTGtfPXU7w+IBdG5Ez0kE1qzxGQaL4gINJ1zMyleDnbuS8UicjJijvqA
+152Sq049ESDz+1rRGc2NVEqh1KaGXmtXvqxXcTB+Ljy5Bw2ke0v8iGngFBPqCTVB
+3op5KBG3RjbF6RRSzwzuWfL7QErNC8WEy5yDVARzTA5+xmBc388v9Dm21HGfcC8O
+DD+gT9sSpssq0ascmvH49MOgjt1yoysLtdCtJW/9FZpoOypaHx0R+mJTLwPXVMrv
+DaVzWh5aiEx+idkSGMnX
+-----END CERTIFICATE-----
+";
+
+// The fields of an IAS report, see `enigma_tools_u::attestation_service::service::ASReport`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct IasReport {
+    #[serde(default)]
+    pub id: String,
+    #[serde(default)]
+    pub timestamp: String,
+    #[serde(rename = "isvEnclaveQuoteStatus")]
+    pub quote_status: String,
+    #[serde(rename = "isvEnclaveQuoteBody")]
+    pub quote_body: String,
+    // Hex, when the platform is behind on its TCB
+    #[serde(default)]
+    pub platform_info_blob: Option<String>,
+    #[serde(default, rename = "advisoryIDs")]
+    pub advisory_ids: Vec<String>,
+    #[serde(default)]
+    pub nonce: Option<String>,
+}
+
+// A report whose signature and chain checked out.
+#[derive(Debug, Clone)]
+pub struct VerifiedReport {
+    pub report: IasReport,
+    pub quote: Quote,
+}
+
+fn report_err(message: &str) -> Error {
+    ReportErr { message: message.to_string() }.into()
+}
+
+// The signature is over the report exactly as IAS returned it, the certificate chain carries the signing key.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn verify_signature(bundle: &ReportBundle, root_ca: &str) -> Result<(), Error> {
+    let root = X509::from_pem(root_ca.as_bytes()).map_err(|_| report_err("invalid root CA"))?;
+    let certificate = X509::from_pem(bundle.certificate.as_bytes()).map_err(|_| report_err("invalid signing certificate"))?;
+    let mut chain = Stack::new()?;
+    if !bundle.ca.trim().is_empty() {
+        for ca in X509::stack_from_pem(bundle.ca.as_bytes()).map_err(|_| report_err("invalid CA certificate"))? {
+            chain.push(ca)?;
+        }
+    }
+
+    // Only the pinned root is trusted, what came with the report goes in as untrusted
+    let mut store = X509StoreBuilder::new()?;
+    store.add_cert(root)?;
+    let store = store.build();
+    let mut context = X509StoreContext::new()?;
+    if !context.init(&store, &certificate, &chain, |c| c.verify_cert())? {
+        return Err(report_err("the signing certificate doesn't chain up to the root CA"));
+    }
+
+    let signature = base64::decode(bundle.signature.trim()).map_err(|_| report_err("the signature isn't base64"))?;
+    let key = certificate.public_key()?;
+    let mut verifier = Verifier::new(MessageDigest::sha256(), &key)?;
+    verifier.update(bundle.report.as_bytes())?;
+    if !verifier.verify(&signature)? {
+        return Err(report_err("invalid report signature"));
+    }
+    Ok(())
+}
+
+// The same without openssl, the chain is left to `verify_chain`.
+#[cfg(target_arch = "wasm32")]
+pub fn verify_signature(bundle: &ReportBundle, _root_ca: &str) -> Result<(), Error> {
+    let certificate = bundle::pem_to_der(&bundle.certificate)?;
+    let certificate = certificate.first().ok_or_else(|| report_err("missing certificate"))?;
+    let signature = base64::decode(bundle.signature.trim()).map_err(|_| report_err("the signature isn't base64"))?;
+    x509::verify_signature(certificate, bundle.report.as_bytes(), &signature)
+}
+
+// The certificates of `bundle` against the root `root_ca`, at `now` (Unix seconds). See `x509::verify_chain`.
+pub fn verify_chain(bundle: &ReportBundle, root_ca: &str, now: u64) -> Result<(), Error> {
+    let root = bundle::pem_to_der(root_ca)?;
+    let root = root.first().ok_or_else(|| report_err("missing root CA"))?;
+    let mut chain = bundle::pem_to_der(&bundle.certificate)?;
+    chain.extend(bundle::pem_to_der(&bundle.ca)?);
+    x509::verify_chain(root, &chain, now)
+}
+
+// When the certificates must be valid: now, or in a browser, whose clock isn't something to trust, when IAS
+// signed the report.
+#[cfg(not(target_arch = "wasm32"))]
+fn validity_time(_report: &IasReport) -> Result<u64, Error> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0))
+}
+
+#[cfg(target_arch = "wasm32")]
+fn validity_time(report: &IasReport) -> Result<u64, Error> {
+    x509::parse_timestamp(&report.timestamp)
+}
+
+// Checks `bundle` was signed under `root_ca` (PEM) and parses the report and its quote. What the quote must
+// say, the MRENCLAVE or the quote status, is up to the caller: see `report::verify_enclave`.
+pub fn verify_report(bundle: &ReportBundle, root_ca: &str) -> Result<VerifiedReport, Error> {
+    let report: IasReport = serde_json::from_str(&bundle.report).map_err(|e| report_err(&format!("malformed report: {}", e)))?;
+    verify_chain(bundle, root_ca, validity_time(&report)?)?;
+    verify_signature(bundle, root_ca)?;
+    let quote = Quote::from_base64(&report.quote_body)?;
+    Ok(VerifiedReport { report, quote })
+}
+
+// `verify_report` against the pinned Intel root.
+pub fn verify_intel_report(bundle: &ReportBundle) -> Result<VerifiedReport, Error> {
+    verify_report(bundle, INTEL_ROOT_CA)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::report::test::{certificate, issue, quote_body, signed_bundle};
+    use openssl::{asn1::Asn1Time, pkey::{PKey, Private}};
+
+    fn pem(certificate: &X509) -> String {
+        String::from_utf8(certificate.to_pem().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_intel_root_ca() {
+        let root = X509::from_pem(INTEL_ROOT_CA.as_bytes()).unwrap();
+        let subject = root.subject_name().entries().last().unwrap().data().as_utf8().unwrap().to_string();
+        assert_eq!(subject, "Intel SGX Attestation Report Signing CA");
+        assert!(root.verify(&root.public_key().unwrap()).unwrap());
+        let fingerprint = root.digest(MessageDigest::sha256()).unwrap();
+        assert_eq!(&fingerprint[..4], &[0x7b, 0x42, 0xe4, 0x1e]);
+    }
+
+    #[test]
+    fn test_verify_report() {
+        let address = [7u8; 20];
+        let (root, key) = certificate("Attestation Report Signing");
+        let root_pem = String::from_utf8(root.to_pem().unwrap()).unwrap();
+        let report = format!(r#"{{"id":"1","timestamp":"2020-05-10T12:00:00.000000","isvEnclaveQuoteStatus":"GROUP_OUT_OF_DATE",
+            "isvEnclaveQuoteBody":"{}","platformInfoBlob":"1502006504","advisoryIDs":["INTEL-SA-00334"]}}"#, quote_body(1, &address));
+        let bundle = signed_bundle(&report, &key, &root);
+
+        let verified = verify_report(&bundle, &root_pem).unwrap();
+        assert_eq!(verified.report.quote_status, "GROUP_OUT_OF_DATE");
+        assert_eq!(verified.report.advisory_ids, vec!["INTEL-SA-00334".to_string()]);
+        assert_eq!(verified.report.platform_info_blob, Some("1502006504".to_string()));
+        assert_eq!(verified.quote.report_body.mr_enclave, [1u8; 32]);
+
+        // The CA sent along issued the signing certificate, but it isn't Intel's
+        let with_ca = ReportBundle { ca: root_pem.clone(), ..bundle.clone() };
+        assert!(verify_report(&with_ca, &root_pem).is_ok());
+        assert!(verify_intel_report(&with_ca).is_err());
+    }
+
+    #[test]
+    fn test_verify_chain() {
+        let report = format!(r#"{{"id":"1","isvEnclaveQuoteStatus":"OK","isvEnclaveQuoteBody":"{}"}}"#, quote_body(1, &[7u8; 20]));
+        let tomorrow = Asn1Time::days_from_now(1).unwrap();
+        let root = issue("Root", None, true, true, &tomorrow);
+        let root_pem = pem(&root.0);
+        let bundle = |signing: &(X509, PKey<Private>), ca: String| ReportBundle { ca, ..signed_bundle(&report, &signing.1, &signing.0) };
+
+        let signing = issue("Signing", Some(&root), false, true, &tomorrow);
+        assert!(verify_report(&bundle(&signing, String::new()), &root_pem).is_ok());
+        assert!(verify_report(&bundle(&signing, root_pem.clone()), &root_pem).is_ok());
+
+        // Through an intermediate CA, and through one that isn't a CA
+        let intermediate = issue("Intermediate", Some(&root), true, true, &tomorrow);
+        let below = issue("Signing", Some(&intermediate), false, true, &tomorrow);
+        assert!(verify_report(&bundle(&below, format!("{}{}", pem(&intermediate.0), root_pem)), &root_pem).is_ok());
+        assert!(verify_report(&bundle(&below, String::new()), &root_pem).is_err());
+        let not_ca = issue("Not a CA", Some(&root), false, true, &tomorrow);
+        let below = issue("Signing", Some(&not_ca), false, true, &tomorrow);
+        assert!(verify_report(&bundle(&below, pem(&not_ca.0)), &root_pem).is_err());
+
+        // Expired, or not allowed to sign
+        let expired = issue("Signing", Some(&root), false, true, &Asn1Time::from_unix(1_000_000_000).unwrap());
+        assert!(verify_report(&bundle(&expired, String::new()), &root_pem).is_err());
+        let encipher_only = issue("Signing", Some(&root), false, false, &tomorrow);
+        assert!(verify_report(&bundle(&encipher_only, String::new()), &root_pem).is_err());
+
+        // A root that isn't a CA, and a chain under another root
+        let leaf_root = issue("Root", None, false, true, &tomorrow);
+        let signing = issue("Signing", Some(&leaf_root), false, true, &tomorrow);
+        assert!(verify_report(&bundle(&signing, String::new()), &pem(&leaf_root.0)).is_err());
+        let other = issue("Root", None, true, true, &tomorrow);
+        let signing = issue("Signing", Some(&other), false, true, &tomorrow);
+        assert!(verify_report(&bundle(&signing, pem(&other.0)), &root_pem).is_err());
+    }
+}
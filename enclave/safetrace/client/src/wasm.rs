@@ -0,0 +1,140 @@
+// The envelope for the JavaScript client, built with wasm-pack (see the README): the same report
+// verification, key exchange and AES-GCM sealing as the Rust client, so the two can't drift apart.
+// The JavaScript side keeps the JSON-RPC calls and hands the replies over as they come.
+
+use crate::messages::{self, EnclaveReport, TaskKey};
+use crate::report::{self, EnclaveIdentity, ReportPolicy, Trust};
+use crate::session::Session;
+use enigma_crypto::asymmetric::KeyPair;
+use failure::Error;
+use hex::{FromHex, ToHex};
+use wasm_bindgen::prelude::*;
+
+fn js_err(e: Error) -> JsValue { JsValue::from_str(&e.to_string()) }
+
+fn parse_json(json: &str) -> Result<serde_json::Value, JsValue> {
+    serde_json::from_str(json).map_err(|e| JsValue::from_str(&format!("invalid JSON: {}", e)))
+}
+
+#[wasm_bindgen]
+pub struct UserKey(KeyPair);
+
+#[wasm_bindgen]
+impl UserKey {
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Result<UserKey, JsValue> {
+        KeyPair::new().map(UserKey).map_err(|e| JsValue::from_str(&format!("{:?}", e)))
+    }
+
+    // A 32 bytes secret key, hex
+    #[wasm_bindgen(js_name = fromSecret)]
+    pub fn from_secret(secret: &str) -> Result<UserKey, JsValue> {
+        let secret: Vec<u8> = secret.from_hex().map_err(|_| JsValue::from_str("the secret key isn't hex"))?;
+        if secret.len() != 32 {
+            return Err(JsValue::from_str("the secret key must be 32 bytes long"));
+        }
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&secret);
+        KeyPair::from_slice(&key).map(UserKey).map_err(|e| JsValue::from_str(&format!("{:?}", e)))
+    }
+
+    // The `userPubKey` of the requests, hex
+    #[wasm_bindgen(getter, js_name = publicKey)]
+    pub fn public_key(&self) -> String { self.0.get_pubkey().to_hex() }
+}
+
+#[wasm_bindgen]
+pub struct Enclave(EnclaveIdentity);
+
+// Verifies the reply to `getEnclaveReport` (the JSON-RPC `result`, as a string). `rootCa` is the PEM of Intel's
+// Attestation Report Signing CA, `undefined` only for a server built in simulation mode. `mrEnclave` is the
+// hex MRENCLAVE the report must be for, if given.
+#[wasm_bindgen(js_name = verifyReport)]
+pub fn verify_report(response: &str, root_ca: Option<String>, mr_enclave: Option<String>) -> Result<Enclave, JsValue> {
+    let trust = match root_ca {
+        Some(root_ca) => Trust::Ias { root_ca },
+        None => Trust::Simulation,
+    };
+    let mut policy = ReportPolicy::new(trust);
+    if let Some(mr_enclave) = mr_enclave {
+        let bytes: Vec<u8> = mr_enclave.from_hex().map_err(|_| JsValue::from_str("mrEnclave isn't hex"))?;
+        if bytes.len() != 32 {
+            return Err(JsValue::from_str("mrEnclave must be 32 bytes long"));
+        }
+        let mut expected = [0u8; 32];
+        expected.copy_from_slice(&bytes);
+        policy = policy.with_mr_enclave(expected);
+    }
+    let report: EnclaveReport = messages::parse_response("GetEnclaveReport", &parse_json(response)?).map_err(js_err)?;
+    let signing_address = report::parse_signing_address(&report.signing_key).map_err(js_err)?;
+    let bundle = report.to_bundle().map_err(js_err)?;
+    report::verify_enclave(&bundle, &signing_address, &policy).map(Enclave).map_err(js_err)
+}
+
+#[wasm_bindgen]
+impl Enclave {
+    #[wasm_bindgen(getter, js_name = mrEnclave)]
+    pub fn mr_enclave(&self) -> String { self.0.mr_enclave.to_hex() }
+
+    #[wasm_bindgen(getter, js_name = signingAddress)]
+    pub fn signing_address(&self) -> String { self.0.signing_address.to_hex() }
+
+    #[wasm_bindgen(getter, js_name = quoteStatus)]
+    pub fn quote_status(&self) -> String { self.0.quote_status.clone() }
+
+    // Checks the reply to `newTaskEncryptionKey` is signed by this enclave, and derives the session key.
+    #[wasm_bindgen(js_name = openSession)]
+    pub fn open_session(&self, user_key: &UserKey, response: &str) -> Result<EnvelopeSession, JsValue> {
+        let key: TaskKey = messages::parse_response("NewTaskEncryptionKey", &parse_json(response)?).map_err(js_err)?;
+        Session::new(&user_key.0, &key.taskPubKey, &key.sig, &self.0.signing_address).map(EnvelopeSession).map_err(js_err)
+    }
+}
+
+// A key exchanged with the enclave, for a single request.
+#[wasm_bindgen(js_name = Session)]
+pub struct EnvelopeSession(Session);
+
+#[wasm_bindgen(js_class = Session)]
+impl EnvelopeSession {
+    // `encryptedUserId` or `encryptedData`, hex
+    pub fn encrypt(&self, plaintext: &str) -> Result<String, JsValue> {
+        self.0.encrypt(plaintext.as_bytes()).map_err(js_err)
+    }
+
+    // The `encryptedData` of `addPersonalData`: the JSON array of locations, sealed with a nonce and
+    // `timestamp`, in seconds (`Math.floor(Date.now() / 1000)`), and the JSON declaration of a health authority if any
+    #[wasm_bindgen(js_name = encryptLocations)]
+    pub fn encrypt_locations(&self, locations: &str, timestamp: f64, declaration: Option<String>) -> Result<String, JsValue> {
+        let locations: Vec<messages::Location> = serde_json::from_str(locations).map_err(|e| JsValue::from_str(&format!("invalid locations: {}", e)))?;
+        let declaration: Option<messages::Declaration> = match declaration {
+            Some(declaration) => Some(serde_json::from_str(&declaration).map_err(|e| JsValue::from_str(&format!("invalid declaration: {}", e)))?),
+            None => None,
+        };
+        let payload = messages::personal_data(&locations, timestamp as u64, declaration.as_ref());
+        self.0.encrypt(payload.to_string().as_bytes()).map_err(js_err)
+    }
+
+    // The `encryptedData` of `registerUser`, for the key the user's later requests are signed with
+    pub fn registration(&self, signing_key: &UserKey, user_key: &UserKey) -> Result<String, JsValue> {
+        self.0.registration(&signing_key.0, &user_key.0.get_pubkey()).map_err(js_err)
+    }
+
+    // The `encryptedSignature` of a request, over its hex fields: `encryptedData` is `""` for `findMatch`
+    #[wasm_bindgen(js_name = signRequest)]
+    pub fn sign_request(&self, signing_key: &UserKey, encrypted_userid: &str, encrypted_data: &str, user_pubkey: &str) -> Result<String, JsValue> {
+        self.0.sign(&signing_key.0, &[encrypted_userid, encrypted_data, user_pubkey]).map_err(js_err)
+    }
+
+    // A user id for a decoy `findMatch` of this session, to be encrypted like any other
+    #[wasm_bindgen(js_name = decoyUserId)]
+    pub fn decoy_userid(&self) -> String {
+        self.0.decoy_userid()
+    }
+
+    // The `encryptedOutput` of the enclave, without the padding
+    pub fn decrypt(&self, ciphertext: &str) -> Result<String, JsValue> {
+        let plaintext = self.0.decrypt(ciphertext).map_err(js_err)?;
+        let plaintext = String::from_utf8(plaintext).map_err(|_| JsValue::from_str("the output isn't UTF-8"))?;
+        Ok(plaintext.trim_end_matches(' ').to_string())
+    }
+}
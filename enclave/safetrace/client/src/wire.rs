@@ -0,0 +1,72 @@
+use failure::Error;
+use serde::Serialize;
+use serde_json::Value;
+
+// The encodings of the ZMQ frames: JSON, for web clients and debugging, or msgpack, smaller and cheaper to
+// parse on phones. Both carry the same documents (see `messages`, and `networking::messages` in the app),
+// msgpack being the encoding of the JSON document: same field names, same values. Each frame says which one
+// it is by its first byte, a msgpack map or array marker being no byte a JSON document starts with, and the
+// server answers every frame in the encoding it came in. Nothing is agreed on beforehand, a client may mix
+// both on the same socket.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WireFormat {
+    Json,
+    Msgpack,
+}
+
+impl WireFormat {
+    pub fn detect(frame: &[u8]) -> Self {
+        match frame.first() {
+            // fixmap, fixarray, array 16/32, map 16/32
+            Some(0x80..=0x9f) | Some(0xdc..=0xdf) => WireFormat::Msgpack,
+            _ => WireFormat::Json,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            WireFormat::Json => "json",
+            WireFormat::Msgpack => "msgpack",
+        }
+    }
+
+    pub fn decode(self, frame: &[u8]) -> Result<Value, Error> {
+        match self {
+            WireFormat::Json => Ok(serde_json::from_slice(frame)?),
+            WireFormat::Msgpack => Ok(rmp_serde::from_slice(frame)?),
+        }
+    }
+
+    pub fn encode<T: Serialize>(self, msg: &T) -> Result<Vec<u8>, Error> {
+        match self {
+            WireFormat::Json => Ok(serde_json::to_vec(msg)?),
+            // Through the JSON document: `rmp_serde` can't encode `#[serde(flatten)]` structs of unknown length
+            WireFormat::Msgpack => Ok(rmp_serde::to_vec(&serde_json::to_value(msg)?)?),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_wire_formats() {
+        let doc = json!({"id": "a1b2c3d4e5", "type": "FindMatch", "input": {"encryptedUserId": "e1a3", "userPubKey": "2b4d"},
+                         "nested": [1, -2, 3.5, null, true, {"empty": []}]});
+        for &format in &[WireFormat::Json, WireFormat::Msgpack] {
+            let frame = format.encode(&doc).unwrap();
+            assert_eq!(WireFormat::detect(&frame), format, "{}", format.name());
+            assert_eq!(format.decode(&frame).unwrap(), doc, "{}", format.name());
+        }
+        // A batch is an array
+        assert_eq!(WireFormat::detect(&WireFormat::Msgpack.encode(&json!([doc, doc])).unwrap()), WireFormat::Msgpack);
+        assert_eq!(WireFormat::detect(b"  [{\"jsonrpc\": \"2.0\"}]"), WireFormat::Json);
+        // Neither is JSON, the server answers with a JSON parse error
+        assert_eq!(WireFormat::detect(b""), WireFormat::Json);
+        assert_eq!(WireFormat::detect(b"not json"), WireFormat::Json);
+        assert!(WireFormat::Msgpack.decode(&[0x81, 0xa2]).is_err());
+    }
+}
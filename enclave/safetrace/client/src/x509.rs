@@ -0,0 +1,464 @@
+use crate::errors::ReportErr;
+use failure::Error;
+use rsa::{hash::Hashes, BigUint, PaddingScheme, PublicKey, RSAPublicKey};
+use sha2::{Digest, Sha256};
+
+// Just enough DER to check an IAS report without openssl, which doesn't build for wasm32: each certificate
+// of the chain must be issued and signed by the next one, the last by the root, and the report signed by the
+// signing certificate, all RSA PKCS#1 v1.5 with SHA-256. `verify_chain` also checks what openssl doesn't
+// check on its own: that the CAs are CAs allowed to sign certificates and that the signing certificate may
+// sign, from their basicConstraints and keyUsage extensions. It's used on every target, with openssl too.
+
+const TAG_BOOLEAN: u8 = 0x01;
+const TAG_INTEGER: u8 = 0x02;
+const TAG_BIT_STRING: u8 = 0x03;
+const TAG_OCTET_STRING: u8 = 0x04;
+const TAG_OID: u8 = 0x06;
+const TAG_UTC_TIME: u8 = 0x17;
+const TAG_GENERALIZED_TIME: u8 = 0x18;
+const TAG_SEQUENCE: u8 = 0x30;
+const TAG_VERSION: u8 = 0xa0;
+const TAG_EXTENSIONS: u8 = 0xa3;
+// 1.2.840.113549.1.1.11
+const SHA256_WITH_RSA: &[u8] = &[0x2a, 0x86, 0x48, 0x86, 0xf7, 0x0d, 0x01, 0x01, 0x0b];
+// 2.5.29.15 and 2.5.29.19
+const KEY_USAGE: &[u8] = &[0x55, 0x1d, 0x0f];
+const BASIC_CONSTRAINTS: &[u8] = &[0x55, 0x1d, 0x13];
+
+// The keyUsage bits we look at, in the first byte of the BIT STRING
+const DIGITAL_SIGNATURE: u8 = 0x80;
+const NON_REPUDIATION: u8 = 0x40;
+const KEY_CERT_SIGN: u8 = 0x04;
+
+fn x509_err(message: &str) -> Error {
+    ReportErr { message: format!("certificate: {}", message) }.into()
+}
+
+// One element: its tag, its content, the whole element, and what follows it.
+struct Element<'a> {
+    tag: u8,
+    content: &'a [u8],
+    raw: &'a [u8],
+}
+
+fn read<'a>(input: &'a [u8]) -> Result<(Element<'a>, &'a [u8]), Error> {
+    if input.len() < 2 {
+        return Err(x509_err("truncated"));
+    }
+    let (len, header) = match input[1] {
+        len @ 0..=0x7f => (len as usize, 2),
+        0x81..=0x83 => {
+            let size = (input[1] & 0x7f) as usize;
+            if input.len() < 2 + size {
+                return Err(x509_err("truncated length"));
+            }
+            (input[2..2 + size].iter().fold(0usize, |len, &byte| len << 8 | byte as usize), 2 + size)
+        },
+        _ => return Err(x509_err("unsupported length")),
+    };
+    if input.len() < header + len {
+        return Err(x509_err("truncated element"));
+    }
+    let element = Element { tag: input[0], content: &input[header..header + len], raw: &input[..header + len] };
+    Ok((element, &input[header + len..]))
+}
+
+fn expect<'a>(input: &'a [u8], tag: u8) -> Result<(Element<'a>, &'a [u8]), Error> {
+    let (element, rest) = read(input)?;
+    if element.tag != tag {
+        return Err(x509_err(&format!("expected tag {:#x}, got {:#x}", tag, element.tag)));
+    }
+    Ok((element, rest))
+}
+
+struct Certificate<'a> {
+    tbs: &'a [u8],
+    serial: &'a [u8],
+    issuer: &'a [u8],
+    subject: &'a [u8],
+    // Unix seconds
+    not_before: u64,
+    not_after: u64,
+    modulus: &'a [u8],
+    exponent: &'a [u8],
+    signature: &'a [u8],
+    // The first byte of keyUsage, none without the extension
+    key_usage: Option<u8>,
+    // cA of basicConstraints
+    ca: bool,
+}
+
+// What a certificate is in the chain, which decides what its key must be allowed to do.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Role {
+    Signing,
+    Ca,
+}
+
+// The content of a BIT STRING, without its unused bits count (always 0 here).
+fn bit_string(element: &Element) -> Result<&[u8], Error> {
+    match element.content.split_first() {
+        Some((0, bits)) => Ok(bits),
+        _ => Err(x509_err("unexpected BIT STRING")),
+    }
+}
+
+fn check_algorithm(algorithm: &Element) -> Result<(), Error> {
+    let (oid, _) = expect(algorithm.content, TAG_OID)?;
+    if oid.content != SHA256_WITH_RSA {
+        return Err(x509_err("only sha256WithRSAEncryption is supported"));
+    }
+    Ok(())
+}
+
+// Days since 1970-01-01 of a date of the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = (if year >= 0 { year } else { year - 399 }) / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+// `digits` of the form YYYYMMDDHHMMSS (UTC), in Unix seconds.
+fn unix_time(digits: &str) -> Result<u64, Error> {
+    if digits.len() != 14 || !digits.bytes().all(|byte| byte.is_ascii_digit()) {
+        return Err(x509_err("invalid time"));
+    }
+    let field = |from: usize, to: usize| digits[from..to].parse::<i64>().unwrap_or(0);
+    let (month, day, hour, minute, second) = (field(4, 6), field(6, 8), field(8, 10), field(10, 12), field(12, 14));
+    if month < 1 || month > 12 || day < 1 || day > 31 || hour > 23 || minute > 59 || second > 60 {
+        return Err(x509_err("invalid time"));
+    }
+    let seconds = days_from_civil(field(0, 4), month, day) * 86_400 + hour * 3600 + minute * 60 + second;
+    if seconds < 0 {
+        return Err(x509_err("time before 1970"));
+    }
+    Ok(seconds as u64)
+}
+
+// UTCTime (YYMMDDHHMMSSZ, 19YY from 50 on) or GeneralizedTime (YYYYMMDDHHMMSSZ).
+fn time(element: &Element) -> Result<u64, Error> {
+    let text = std::str::from_utf8(element.content).map_err(|_| x509_err("invalid time"))?;
+    let digits = match (element.tag, text.len()) {
+        (TAG_UTC_TIME, 13) if text.ends_with('Z') => {
+            let century = if &text[..2] < "50" { "20" } else { "19" };
+            format!("{}{}", century, &text[..12])
+        },
+        (TAG_GENERALIZED_TIME, 15) if text.ends_with('Z') => text[..14].to_string(),
+        _ => return Err(x509_err("unsupported time")),
+    };
+    unix_time(&digits)
+}
+
+// The `timestamp` of an IAS report, e.g. "2020-05-10T12:00:00.000000", UTC.
+pub fn parse_timestamp(timestamp: &str) -> Result<u64, Error> {
+    let digits: String = timestamp.split('.').next().unwrap_or("").chars().filter(|c| c.is_ascii_digit()).collect();
+    unix_time(&digits).map_err(|_| x509_err(&format!("invalid report timestamp {:?}", timestamp)))
+}
+
+// keyUsage and basicConstraints, the others are left to openssl where there's one.
+fn extensions(mut fields: &[u8], certificate: &mut Certificate) -> Result<(), Error> {
+    while !fields.is_empty() {
+        let (field, rest) = read(fields)?;
+        fields = rest;
+        if field.tag != TAG_EXTENSIONS {
+            // The issuer and subject unique ids
+            continue;
+        }
+        let (list, _) = expect(field.content, TAG_SEQUENCE)?;
+        let mut list = list.content;
+        while !list.is_empty() {
+            let (extension, rest) = expect(list, TAG_SEQUENCE)?;
+            list = rest;
+            let (oid, mut value) = expect(extension.content, TAG_OID)?;
+            if value.first() == Some(&TAG_BOOLEAN) {
+                value = read(value)?.1;
+            }
+            let (value, _) = expect(value, TAG_OCTET_STRING)?;
+            if oid.content == KEY_USAGE {
+                let (bits, _) = expect(value.content, TAG_BIT_STRING)?;
+                certificate.key_usage = Some(bits.content.get(1).cloned().unwrap_or(0));
+            } else if oid.content == BASIC_CONSTRAINTS {
+                let (constraints, _) = expect(value.content, TAG_SEQUENCE)?;
+                certificate.ca = match read(constraints.content) {
+                    Ok((ca, _)) if ca.tag == TAG_BOOLEAN => ca.content.iter().any(|&byte| byte != 0),
+                    _ => false,
+                };
+            }
+        }
+    }
+    Ok(())
+}
+
+fn parse(der: &[u8]) -> Result<Certificate, Error> {
+    let (certificate, _) = expect(der, TAG_SEQUENCE)?;
+    let (tbs, rest) = expect(certificate.content, TAG_SEQUENCE)?;
+    let (algorithm, rest) = expect(rest, TAG_SEQUENCE)?;
+    check_algorithm(&algorithm)?;
+    let (signature, _) = expect(rest, TAG_BIT_STRING)?;
+
+    let mut fields = tbs.content;
+    if fields.first() == Some(&TAG_VERSION) {
+        fields = read(fields)?.1;
+    }
+    let (serial, fields) = expect(fields, TAG_INTEGER)?;
+    let (_algorithm, fields) = expect(fields, TAG_SEQUENCE)?;
+    let (issuer, fields) = expect(fields, TAG_SEQUENCE)?;
+    let (validity, fields) = expect(fields, TAG_SEQUENCE)?;
+    let (not_before, rest) = read(validity.content)?;
+    let (not_after, _) = read(rest)?;
+    let (subject, fields) = expect(fields, TAG_SEQUENCE)?;
+    let (spki, fields) = expect(fields, TAG_SEQUENCE)?;
+    let (_key_algorithm, key) = expect(spki.content, TAG_SEQUENCE)?;
+    let (key, _) = expect(key, TAG_BIT_STRING)?;
+    let (rsa_key, _) = expect(bit_string(&key)?, TAG_SEQUENCE)?;
+    let (modulus, rest) = expect(rsa_key.content, TAG_INTEGER)?;
+    let (exponent, _) = expect(rest, TAG_INTEGER)?;
+
+    let mut certificate = Certificate {
+        tbs: tbs.raw,
+        serial: serial.content,
+        issuer: issuer.raw,
+        subject: subject.raw,
+        not_before: time(&not_before)?,
+        not_after: time(&not_after)?,
+        modulus: modulus.content,
+        exponent: exponent.content,
+        signature: bit_string(&signature)?,
+        key_usage: None,
+        ca: false,
+    };
+    extensions(fields, &mut certificate)?;
+    Ok(certificate)
+}
+
+// `name` is what the errors call it.
+fn check(certificate: &Certificate, role: Role, name: &str, now: u64) -> Result<(), Error> {
+    if now < certificate.not_before {
+        return Err(x509_err(&format!("{} isn't valid yet", name)));
+    }
+    if now > certificate.not_after {
+        return Err(x509_err(&format!("{} has expired", name)));
+    }
+    let allowed = |bits: u8| certificate.key_usage.map_or(true, |usage| usage & bits != 0);
+    match role {
+        Role::Ca if !certificate.ca => Err(x509_err(&format!("{} isn't a CA (basicConstraints)", name))),
+        Role::Ca if !allowed(KEY_CERT_SIGN) => Err(x509_err(&format!("{} may not sign certificates (keyUsage)", name))),
+        Role::Signing if !allowed(DIGITAL_SIGNATURE | NON_REPUDIATION) => Err(x509_err(&format!("{} may not sign (keyUsage)", name))),
+        _ => Ok(()),
+    }
+}
+
+fn verify_rsa(certificate: &Certificate, message: &[u8], signature: &[u8]) -> Result<(), Error> {
+    let key = RSAPublicKey::new(BigUint::from_bytes_be(certificate.modulus), BigUint::from_bytes_be(certificate.exponent))
+        .map_err(|_| x509_err("invalid RSA key"))?;
+    let digest = Sha256::digest(message);
+    key.verify(PaddingScheme::PKCS1v15, Some(&Hashes::SHA2_256), &digest, signature).map_err(|_| x509_err("invalid signature"))
+}
+
+// `chain` is the signing certificate then the CAs that came with it, all DER. Each must be issued and signed by
+// the next one and the last by `root`, the pinned root: the root itself may come along, it's only trusted as the
+// pinned one. All must be valid at `now` (Unix seconds) and be allowed to do what they do in the chain.
+pub fn verify_chain(root: &[u8], chain: &[Vec<u8>], now: u64) -> Result<(), Error> {
+    let anchor = parse(root)?;
+    check(&anchor, Role::Ca, "the root CA", now)?;
+    let certificates = chain.iter().take_while(|der| der.as_slice() != root).map(|der| parse(der)).collect::<Result<Vec<_>, _>>()?;
+    if chain.is_empty() {
+        return Err(x509_err("no signing certificate"));
+    }
+    // The root signing reports itself, only ever in tests
+    if certificates.is_empty() {
+        return check(&anchor, Role::Signing, "the signing certificate", now);
+    }
+    for (i, certificate) in certificates.iter().enumerate() {
+        match i {
+            0 => check(certificate, Role::Signing, "the signing certificate", now)?,
+            _ => check(certificate, Role::Ca, "a CA certificate", now)?,
+        }
+        let issuer = certificates.get(i + 1).unwrap_or(&anchor);
+        if certificate.issuer != issuer.subject {
+            return Err(x509_err("the chain doesn't anchor to the root CA"));
+        }
+        verify_rsa(issuer, certificate.tbs, certificate.signature).map_err(|_| x509_err("the chain doesn't anchor to the root CA"))?;
+    }
+    Ok(())
+}
+
+// The report signature, by the signing certificate (DER).
+pub fn verify_signature(signing: &[u8], report: &[u8], signature: &[u8]) -> Result<(), Error> {
+    verify_rsa(&parse(signing)?, report, signature)
+}
+
+// The serial number of a certificate (DER), the content of its INTEGER as a CRL lists it.
+pub fn serial_number(der: &[u8]) -> Result<Vec<u8>, Error> {
+    Ok(parse(der)?.serial.to_vec())
+}
+
+// A certificate revocation list whose signature checked out.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Crl {
+    // Unix seconds, the next list is due at `next_update`
+    pub this_update: u64,
+    pub next_update: Option<u64>,
+    // Serial numbers, see `serial_number`
+    pub revoked: Vec<Vec<u8>>,
+}
+
+impl Crl {
+    pub fn is_revoked(&self, serial: &[u8]) -> bool {
+        self.revoked.iter().any(|revoked| revoked.as_slice() == serial)
+    }
+}
+
+// Checks `crl` (DER) is issued and signed by `issuer` (DER) and reads the serial numbers it revokes. Whether it's
+// still current, past `next_update`, is up to the caller.
+pub fn verify_crl(crl: &[u8], issuer: &[u8]) -> Result<Crl, Error> {
+    let issuer = parse(issuer)?;
+    let (list, _) = expect(crl, TAG_SEQUENCE)?;
+    let (tbs, rest) = expect(list.content, TAG_SEQUENCE)?;
+    let (algorithm, rest) = expect(rest, TAG_SEQUENCE)?;
+    check_algorithm(&algorithm)?;
+    let (signature, _) = expect(rest, TAG_BIT_STRING)?;
+    verify_rsa(&issuer, tbs.raw, bit_string(&signature)?).map_err(|_| x509_err("the CRL isn't signed by its CA"))?;
+
+    let mut fields = tbs.content;
+    if fields.first() == Some(&TAG_INTEGER) {
+        fields = read(fields)?.1;
+    }
+    let (_algorithm, fields) = expect(fields, TAG_SEQUENCE)?;
+    let (name, fields) = expect(fields, TAG_SEQUENCE)?;
+    if name.raw != issuer.subject {
+        return Err(x509_err("the CRL isn't issued by the CA"));
+    }
+    let (this_update, mut fields) = read(fields)?;
+    let mut crl = Crl { this_update: time(&this_update)?, next_update: None, revoked: Vec::new() };
+    if let Some(&tag) = fields.first() {
+        if tag == TAG_UTC_TIME || tag == TAG_GENERALIZED_TIME {
+            let (next_update, rest) = read(fields)?;
+            crl.next_update = Some(time(&next_update)?);
+            fields = rest;
+        }
+    }
+    if fields.first() == Some(&TAG_SEQUENCE) {
+        let (entries, _) = read(fields)?;
+        let mut entries = entries.content;
+        while !entries.is_empty() {
+            let (entry, rest) = expect(entries, TAG_SEQUENCE)?;
+            entries = rest;
+            crl.revoked.push(expect(entry.content, TAG_INTEGER)?.0.content.to_vec());
+        }
+    }
+    Ok(crl)
+}
+
+// `root` and `signing` are DER, `signature` is over `report`.
+pub fn verify_report_signature(root: &[u8], signing: &[u8], report: &[u8], signature: &[u8]) -> Result<(), Error> {
+    let root = parse(root)?;
+    let signing = parse(signing)?;
+    if signing.issuer != root.subject {
+        return Err(x509_err("the signing certificate isn't issued by the root CA"));
+    }
+    verify_rsa(&root, signing.tbs, signing.signature).map_err(|_| x509_err("the signing certificate doesn't chain up to the root CA"))?;
+    verify_rsa(&signing, report, signature)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_read_lengths() {
+        let (element, rest) = read(&[0x04, 0x02, 0xaa, 0xbb, 0xcc]).unwrap();
+        assert_eq!((element.tag, element.content, rest), (0x04, &[0xaa, 0xbb][..], &[0xcc][..]));
+
+        let mut long = vec![0x04, 0x81, 0x80];
+        long.extend(vec![0u8; 0x80]);
+        assert_eq!(read(&long).unwrap().0.content.len(), 0x80);
+        assert!(read(&[0x04, 0x05, 0x00]).is_err());
+        assert!(read(&[0x04, 0x84, 0, 0, 0, 1]).is_err());
+    }
+
+    #[test]
+    fn test_rejects_garbage() {
+        assert!(verify_report_signature(&[0x30, 0x00], &[0x30, 0x00], b"{}", &[]).is_err());
+        assert!(verify_chain(&[0x30, 0x00], &[], 0).is_err());
+    }
+
+    fn der(tag: u8, content: &[u8]) -> Vec<u8> {
+        let mut element = vec![tag];
+        match content.len() {
+            len @ 0..=0x7f => element.push(len as u8),
+            len @ 0x80..=0xff => element.extend(&[0x81, len as u8]),
+            len => element.extend(&[0x82, (len >> 8) as u8, len as u8]),
+        }
+        element.extend(content);
+        element
+    }
+
+    // A CRL of `issuer` revoking `serials`, signed with `key`.
+    fn crl(issuer: &[u8], key: &openssl::pkey::PKey<openssl::pkey::Private>, serials: &[&[u8]]) -> Vec<u8> {
+        let algorithm = der(TAG_SEQUENCE, &[der(TAG_OID, SHA256_WITH_RSA), vec![0x05, 0x00]].concat());
+        let entries: Vec<u8> = serials.iter()
+            .flat_map(|serial| der(TAG_SEQUENCE, &[der(TAG_INTEGER, serial), der(TAG_UTC_TIME, b"200510120000Z")].concat()))
+            .collect();
+        let tbs = der(TAG_SEQUENCE, &[
+            der(TAG_INTEGER, &[1]),
+            algorithm.clone(),
+            parse(issuer).unwrap().subject.to_vec(),
+            der(TAG_UTC_TIME, b"200510120000Z"),
+            der(TAG_UTC_TIME, b"200610120000Z"),
+            der(TAG_SEQUENCE, &entries),
+        ].concat());
+        let mut signer = openssl::sign::Signer::new(openssl::hash::MessageDigest::sha256(), key).unwrap();
+        signer.update(&tbs).unwrap();
+        let signature = [&[0u8][..], &signer.sign_to_vec().unwrap()].concat();
+        der(TAG_SEQUENCE, &[tbs, algorithm, der(TAG_BIT_STRING, &signature)].concat())
+    }
+
+    #[test]
+    fn test_verify_crl() {
+        let (root, key) = crate::report::test::certificate("Root");
+        let root = root.to_der().unwrap();
+        let list = crl(&root, &key, &[&[1], &[0x00, 0x9a]]);
+        let verified = verify_crl(&list, &root).unwrap();
+        assert_eq!((verified.this_update, verified.next_update), (1_589_112_000, Some(1_591_790_400)));
+        assert!(verified.is_revoked(&serial_number(&root).unwrap()));
+        assert!(verified.is_revoked(&[0x00, 0x9a]) && !verified.is_revoked(&[2]));
+
+        // Signed by another key, or tampered with
+        let (other, other_key) = crate::report::test::certificate("Root");
+        assert!(verify_crl(&crl(&root, &other_key, &[]), &root).is_err());
+        assert!(verify_crl(&list, &other.to_der().unwrap()).is_err());
+        let mut tampered = list.clone();
+        let at = tampered.len() - 300;
+        tampered[at] ^= 1;
+        assert!(verify_crl(&tampered, &root).is_err());
+        assert!(verify_crl(&crl(&root, &key, &[]), &root).unwrap().revoked.is_empty());
+    }
+
+    #[test]
+    fn test_times() {
+        assert_eq!(unix_time("20161114153731").unwrap(), 1_479_137_851);
+        assert_eq!(time(&Element { tag: TAG_UTC_TIME, content: b"991231000000Z", raw: &[] }).unwrap(), 946_598_400);
+        assert_eq!(time(&Element { tag: TAG_GENERALIZED_TIME, content: b"20491231235959Z", raw: &[] }).unwrap(), 2_524_607_999);
+        assert!(time(&Element { tag: TAG_UTC_TIME, content: b"991231000000+0100", raw: &[] }).is_err());
+        assert!(unix_time("20161314153731").is_err());
+        assert_eq!(parse_timestamp("2020-05-10T12:00:00.123456").unwrap(), 1_589_112_000);
+        assert!(parse_timestamp("yesterday").is_err());
+    }
+
+    #[test]
+    fn test_intel_root_ca() {
+        let der = crate::bundle::pem_to_der(crate::verify::INTEL_ROOT_CA).unwrap().remove(0);
+        let root = parse(&der).unwrap();
+        assert_eq!((root.not_before, root.not_after), (1_479_137_851, 2_524_607_999));
+        assert!(root.ca);
+        assert_eq!(root.key_usage.map(|usage| usage & KEY_CERT_SIGN), Some(KEY_CERT_SIGN));
+        assert!(check(&root, Role::Ca, "the root CA", 1_589_112_000).is_ok());
+        assert!(check(&root, Role::Ca, "the root CA", 2_524_608_000).is_err());
+        // It signs certificates, not reports
+        assert!(check(&root, Role::Signing, "the root CA", 1_589_112_000).is_err());
+    }
+}
@@ -0,0 +1,209 @@
+use crate::data::{self, GeolocationTime};
+use crate::settings;
+use crate::stats::EPOCH_SECONDS;
+use crate::time_t;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use enigma_tools_m::utils::LockExpectMutex;
+use enigma_tools_t::common::errors_t::{EnclaveError, FailedTaskError::InputError};
+use serde::Serialize;
+use sgx_rand::{Rng, SgxRng};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::{string::{String, ToString}, sync::SgxMutex, vec::Vec};
+
+// Aggregate statistics for public health dashboards, with differential privacy.
+// Two releases, each computed over distinct users rather than records:
+//   - `cells`: the users who tested positive, per geohash cell of their locations
+//   - `epochs`: the users, and those who tested positive, per UTC day over the last `EPOCHS` days
+// A user counts towards at most `MAX_CONTRIBUTIONS` cells and days, which bounds how much any one
+// user changes a release. Every count gets Laplace noise of scale `MAX_CONTRIBUTIONS / (epsilon / 3)`,
+// the budget being split between the three histograms. Cells whose noisy count is under `min_count`
+// are left out: the set of cells isn't fixed like the days are, and a cell with a single user would
+// otherwise tell where they've been.
+// The noisy release is computed once per version of the sealed store and per day: asking again returns
+// the same noise instead of spending the budget again. The policy is set once, before the first quote
+// commits to it, so the host can't refill the budget by setting it again.
+
+pub const EPOCHS: i32 = 28;
+pub const MAX_CONTRIBUTIONS: usize = 10;
+// Past it the noise protects next to nothing
+pub const MAX_EPSILON: f64 = 10.0;
+const BASE32: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+// The bits of the f64 epsilon, 0 until the host enables the statistics.
+static EPSILON: AtomicU64 = AtomicU64::new(0);
+static PRECISION: AtomicU64 = AtomicU64::new(5);
+static MIN_COUNT: AtomicU64 = AtomicU64::new(5);
+static SET: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    // (the day it was computed, the encoded release)
+    static ref RELEASE: SgxMutex<Option<(i32, Vec<u8>)>> = SgxMutex::new(None);
+}
+
+#[derive(Serialize, Debug)]
+pub struct CellCount {
+    pub cell: String,
+    pub infected: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct EpochCount {
+    pub epoch: i32,
+    pub users: u64,
+    pub infected: u64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Aggregates {
+    pub epsilon: f64,
+    pub precision: u8,
+    pub cells: Vec<CellCount>,
+    pub epochs: Vec<EpochCount>,
+}
+
+fn invalid(message: &str) -> EnclaveError {
+    EnclaveError::FailedTaskError(InputError { message: message.to_string() })
+}
+
+// An epsilon of 0 turns the statistics off.
+pub fn set(epsilon: f64, precision: u8, min_count: u64) -> Result<(), EnclaveError> {
+    if !epsilon.is_finite() || epsilon < 0.0 || precision == 0 || precision > 12 {
+        return Err(invalid("invalid statistics policy"));
+    }
+    if epsilon > MAX_EPSILON {
+        return Err(invalid("the statistics epsilon must be at most 10"));
+    }
+    if settings::quoted() {
+        return Err(invalid("a quote committed to the statistics policy already"));
+    }
+    if SET.swap(true, Ordering::SeqCst) {
+        return Err(invalid("the statistics policy is already set"));
+    }
+    EPSILON.store(epsilon.to_bits(), Ordering::SeqCst);
+    PRECISION.store(u64::from(precision), Ordering::SeqCst);
+    MIN_COUNT.store(min_count, Ordering::SeqCst);
+    invalidate();
+    Ok(())
+}
+
+pub fn epsilon() -> f64 {
+    f64::from_bits(EPSILON.load(Ordering::SeqCst))
+}
+
+// Called whenever the sealed store changes.
+pub fn invalidate() {
+    *RELEASE.lock_expect("Aggregates") = None;
+}
+
+pub fn geohash(lat: f64, lng: f64, precision: usize) -> String {
+    let (mut lat_range, mut lng_range) = ((-90.0, 90.0), (-180.0, 180.0));
+    let mut hash = String::with_capacity(precision);
+    let (mut even, mut bits, mut index) = (true, 0, 0);
+    while hash.len() < precision {
+        let (range, value): (&mut (f64, f64), f64) = if even { (&mut lng_range, lng) } else { (&mut lat_range, lat) };
+        let mid = (range.0 + range.1) / 2.0;
+        index <<= 1;
+        if value >= mid {
+            index |= 1;
+            range.0 = mid;
+        } else {
+            range.1 = mid;
+        }
+        even = !even;
+        bits += 1;
+        if bits == 5 {
+            hash.push(BASE32[index] as char);
+            bits = 0;
+            index = 0;
+        }
+    }
+    hash
+}
+
+fn laplace(rng: &mut SgxRng, scale: f64) -> f64 {
+    loop {
+        // Uniform in [-0.5, 0.5), -0.5 would be ln(0)
+        let u = (rng.next_u64() >> 11) as f64 / (1u64 << 53) as f64 - 0.5;
+        if u > -0.5 {
+            return -scale * u.signum() * (1.0 - 2.0 * u.abs()).ln();
+        }
+    }
+}
+
+fn noisy(rng: &mut SgxRng, count: u64, scale: f64) -> u64 {
+    let value = (count as f64 + laplace(rng, scale)).round();
+    if value > 0.0 { value as u64 } else { 0 }
+}
+
+// The first `MAX_CONTRIBUTIONS` distinct values, in the order of the locations.
+fn capped<T: Ord + Clone>(values: impl Iterator<Item = T>) -> BTreeSet<T> {
+    let mut kept = BTreeSet::new();
+    for value in values {
+        if kept.len() == MAX_CONTRIBUTIONS {
+            break;
+        }
+        kept.insert(value);
+    }
+    kept
+}
+
+fn compute(data: &HashMap<String, Vec<GeolocationTime>>, epsilon: f64, today: i32) -> Result<Aggregates, EnclaveError> {
+    let precision = PRECISION.load(Ordering::SeqCst) as usize;
+    let min_count = MIN_COUNT.load(Ordering::SeqCst);
+    let first = today - EPOCHS + 1;
+
+    let mut cells: BTreeMap<String, u64> = BTreeMap::new();
+    let mut epochs: BTreeMap<i32, (u64, u64)> = (first..=today).map(|epoch| (epoch, (0, 0))).collect();
+    for locations in data.values() {
+        let infected = locations.iter().filter(|location| location.testResult);
+        for cell in capped(infected.map(|location| geohash(location.lat, location.lng, precision))) {
+            *cells.entry(cell).or_insert(0) += 1;
+        }
+        let days = locations.iter().filter_map(|location| {
+            let epoch = location.startTS / EPOCH_SECONDS;
+            if epoch >= first && epoch <= today { Some((epoch, location.testResult)) } else { None }
+        });
+        let mut seen: BTreeMap<i32, bool> = BTreeMap::new();
+        for (epoch, infected) in days {
+            if seen.len() == MAX_CONTRIBUTIONS && !seen.contains_key(&epoch) {
+                continue;
+            }
+            *seen.entry(epoch).or_insert(false) |= infected;
+        }
+        for (epoch, infected) in seen {
+            let counts = epochs.get_mut(&epoch).expect("epochs in range");
+            counts.0 += 1;
+            counts.1 += infected as u64;
+        }
+    }
+
+    let mut rng = SgxRng::new().map_err(|_| invalid("no randomness for the noise"))?;
+    let scale = MAX_CONTRIBUTIONS as f64 / (epsilon / 3.0);
+    let cells = cells.into_iter()
+        .map(|(cell, infected)| CellCount { cell, infected: noisy(&mut rng, infected, scale) })
+        .filter(|cell| cell.infected >= min_count)
+        .collect();
+    let epochs = epochs.into_iter()
+        .map(|(epoch, (users, infected))| EpochCount { epoch, users: noisy(&mut rng, users, scale), infected: noisy(&mut rng, infected, scale) })
+        .collect();
+    Ok(Aggregates { epsilon, precision: precision as u8, cells, epochs })
+}
+
+pub fn get_aggregates_internal() -> Result<Vec<u8>, EnclaveError> {
+    let epsilon = f64::from_bits(EPSILON.load(Ordering::SeqCst));
+    if epsilon == 0.0 {
+        return Err(invalid("the aggregate statistics are disabled"));
+    }
+    let today = (time_t::now()? / EPOCH_SECONDS as u64) as i32;
+    let mut release = RELEASE.lock_expect("Aggregates");
+    if let Some((day, encoded)) = &*release {
+        if *day == today {
+            return Ok(encoded.clone());
+        }
+    }
+    let data = data::unseal_data_wrapper()?;
+    let aggregates = compute(&data, epsilon, today)?;
+    let encoded = serde_json::to_vec(&aggregates).map_err(|_| data::Error::SerializeError)?;
+    *release = Some((today, encoded.clone()));
+    Ok(encoded)
+}
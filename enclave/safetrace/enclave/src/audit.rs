@@ -0,0 +1,81 @@
+use crate::identity;
+use enigma_tools_m::utils::LockExpectMutex;
+use enigma_tools_t::common::errors_t::{EnclaveError, FailedTaskError::InputError};
+use sgx_tcrypto::rsgx_sha256_slice;
+use std::{string::ToString, sync::SgxMutex, vec::Vec};
+
+// Checkpoints of the audit log the host writes (`audit` in the app, the format is in `audit` of the client).
+// The host hands over the digests of the entries written since the last checkpoint, the enclave folds them
+// into the head of the chain it keeps and signs the step from the previous head to the new one. It doesn't
+// sign a step that starts anywhere but at its own head, so the host can't rewrite or drop entries behind a
+// checkpoint. The head is lost with the enclave: the first checkpoint after it started takes the previous
+// head from the host and says so (`resumed`).
+
+const CHECKPOINT_TAG: &[u8] = b"safetrace-audit";
+// Digests per checkpoint, the host checkpoints far more often
+pub const MAX_DIGESTS: usize = 100_000;
+
+lazy_static! {
+    // (seq, head) of the last checkpoint
+    static ref HEAD: SgxMutex<Option<(u64, [u8; 32])>> = SgxMutex::new(None);
+}
+
+pub struct Checkpoint {
+    pub seq: u64,
+    pub head: [u8; 32],
+    pub signature: [u8; 65],
+    pub resumed: bool,
+}
+
+fn invalid(message: &str) -> EnclaveError {
+    EnclaveError::FailedTaskError(InputError { message: message.to_string() })
+}
+
+fn chain(prev: &[u8; 32], digest: &[u8]) -> Result<[u8; 32], EnclaveError> {
+    let mut input = Vec::with_capacity(64);
+    input.extend_from_slice(prev);
+    input.extend_from_slice(digest);
+    rsgx_sha256_slice(&input).map_err(|_| invalid("hashing the audit log failed"))
+}
+
+fn message(prev_seq: u64, prev_head: &[u8; 32], seq: u64, head: &[u8; 32]) -> Vec<u8> {
+    let mut message = CHECKPOINT_TAG.to_vec();
+    message.extend_from_slice(&prev_seq.to_be_bytes());
+    message.extend_from_slice(prev_head);
+    message.extend_from_slice(&seq.to_be_bytes());
+    message.extend_from_slice(head);
+    message
+}
+
+// Handed over to the next version of the enclave, see `upgrade`.
+pub fn head() -> Option<(u64, [u8; 32])> {
+    *HEAD.lock_expect("Audit Head")
+}
+
+// The head the enclave we took over from signed last, so our first checkpoint isn't a resumption.
+pub fn resume(seq: u64, head: [u8; 32]) {
+    HEAD.lock_expect("Audit Head").get_or_insert((seq, head));
+}
+
+// `digests` are the 32 bytes SHA-256 of the entries after `prev_seq`, in order.
+pub fn checkpoint_internal(prev_seq: u64, prev_head: &[u8; 32], digests: &[u8]) -> Result<Checkpoint, EnclaveError> {
+    if digests.is_empty() || digests.len() % 32 != 0 || digests.len() / 32 > MAX_DIGESTS {
+        return Err(invalid("the audit digests must be 1 to 100000 SHA-256 hashes"));
+    }
+    let mut last = HEAD.lock_expect("Audit Head");
+    let resumed = match *last {
+        Some((seq, head)) if seq != prev_seq || &head != prev_head => {
+            return Err(invalid("the audit checkpoint doesn't extend the last one"));
+        },
+        Some(_) => false,
+        None => true,
+    };
+    let mut head = *prev_head;
+    for digest in digests.chunks(32) {
+        head = chain(&head, digest)?;
+    }
+    let seq = prev_seq + (digests.len() / 32) as u64;
+    let signature = identity::sign(&message(prev_seq, prev_head, seq, &head))?;
+    *last = Some((seq, head));
+    Ok(Checkpoint { seq, head, signature, resumed })
+}
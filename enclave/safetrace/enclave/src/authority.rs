@@ -0,0 +1,133 @@
+use crate::clock;
+use crate::data::GeolocationTime;
+use crate::settings;
+use crate::time_t;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use enigma_crypto::asymmetric::KeyPair;
+use enigma_tools_m::utils::LockExpectMutex;
+use enigma_tools_t::common::errors_t::{EnclaveError, FailedTaskError::InputError};
+use enigma_types::PubKey;
+use serde::Deserialize;
+use std::{string::{String, ToString}, sync::SgxMutex, vec::Vec};
+
+// Health authorities.
+// Anybody can submit locations, so without this anybody could mark theirs as infected and have every
+// user who was nearby told they were exposed. Once the operator configured the public keys of the health
+// authorities, locations with `testResult` set are only stored if the envelope carries a declaration
+// signed by one of them:
+//   {"nonce": ..., "timestamp": ..., "locations": [...], "declaration": {"issuedAt": 1589000000, "signature": "1b2c..."}}
+// `signature` (65 bytes, hex) is over `declaration_message`, which names the user so a declaration can't
+// be handed over to somebody else. A declaration is taken for `MAX_AGE` seconds after `issuedAt` and refused
+// when dated later than the clock tolerance of `clock`, so it can't mark new locations infected forever.
+// Without configured keys, `testResult` is taken on trust as before.
+// The keys are set once, before the first quote commits to them, so the host can't clear them after.
+
+const DECLARATION_PREFIX: &str = "safetrace:infected:";
+
+static SET: AtomicBool = AtomicBool::new(false);
+static MAX_AGE: AtomicU64 = AtomicU64::new(14 * 24 * 3600);
+
+lazy_static! {
+    static ref AUTHORITIES: SgxMutex<Vec<PubKey>> = SgxMutex::new(Vec::new());
+}
+
+// What a health authority hands over to a user who tested positive, for them to submit.
+#[derive(Deserialize, Debug)]
+pub struct Declaration {
+    #[serde(rename = "issuedAt")]
+    pub issued_at: u64,
+    pub signature: String,
+}
+
+fn invalid(message: &str) -> EnclaveError {
+    EnclaveError::FailedTaskError(InputError { message: message.to_string() })
+}
+
+// The keys are passed through the ecall as a flat array of 64 bytes public keys, like the peers of
+// `federation`. The host leaves out the ecall when it has none.
+pub fn set(keys: &[u8], max_age: u64) -> Result<(), EnclaveError> {
+    if keys.is_empty() || keys.len() % 64 != 0 {
+        return Err(invalid("health authority keys must be 64 bytes long, at least one"));
+    }
+    if max_age == 0 {
+        return Err(invalid("the declaration max age can't be 0"));
+    }
+    if settings::quoted() {
+        return Err(invalid("a quote committed to the health authorities already"));
+    }
+    if SET.swap(true, Ordering::SeqCst) {
+        return Err(invalid("the health authorities are already set"));
+    }
+    let keys = keys.chunks_exact(64).map(|chunk| {
+        let mut key = [0u8; 64];
+        key.copy_from_slice(chunk);
+        key
+    }).collect();
+    *AUTHORITIES.lock_expect("Health Authorities") = keys;
+    MAX_AGE.store(max_age, Ordering::SeqCst);
+    Ok(())
+}
+
+pub fn max_age() -> u64 {
+    MAX_AGE.load(Ordering::SeqCst)
+}
+
+// The configured keys, hex.
+pub fn keys() -> Vec<String> {
+    AUTHORITIES.lock_expect("Health Authorities").iter()
+        .map(|key| key.iter().map(|b| format!("{:02x}", b)).collect())
+        .collect()
+}
+
+pub fn declaration_message(userid: &str, issued_at: u64) -> Vec<u8> {
+    format!("{}{}:{}", DECLARATION_PREFIX, userid, issued_at).into_bytes()
+}
+
+// The enclave doesn't link rustc-hex: the signatures here and the operator keys of `quorum`
+pub(crate) fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    hex.as_bytes().chunks(2).map(|pair| {
+        let high = (pair[0] as char).to_digit(16)?;
+        let low = (pair[1] as char).to_digit(16)?;
+        Some((high << 4 | low) as u8)
+    }).collect()
+}
+
+// Refuses `locations` marking `userid` as infected unless `declaration` is signed by a configured authority.
+pub fn check(userid: &str, locations: &[GeolocationTime], declaration: Option<&Declaration>) -> Result<(), EnclaveError> {
+    let authorities = AUTHORITIES.lock_expect("Health Authorities");
+    if authorities.is_empty() || !locations.iter().any(|location| location.testResult) {
+        return Ok(());
+    }
+    let declaration = declaration.ok_or_else(|| invalid("unauthorized: testResult needs a health authority declaration"))?;
+    let now = time_t::now()?;
+    if declaration.issued_at > now.saturating_add(clock::tolerance()) {
+        return Err(invalid("unauthorized: the declaration is dated in the future"));
+    }
+    if now.saturating_sub(declaration.issued_at) > max_age() {
+        return Err(invalid("unauthorized: the declaration expired"));
+    }
+    signer(&authorities, "declaration", &declaration_message(userid, declaration.issued_at), &declaration.signature)?;
+    Ok(())
+}
+
+// The authority of `authorities` that signed `message`, `what` names it in the errors.
+fn signer(authorities: &[PubKey], what: &str, message: &[u8], signature: &str) -> Result<PubKey, EnclaveError> {
+    let sig = decode_hex(signature).filter(|sig| sig.len() == 65)
+        .ok_or_else(|| invalid(&format!("the {} signature must be 65 bytes, hex", what)))?;
+    let mut signature = [0u8; 65];
+    signature.copy_from_slice(&sig);
+    let signer = KeyPair::recover(message, signature)?;
+    if !authorities.iter().any(|authority| authority[..] == signer[..]) {
+        return Err(invalid(&format!("unauthorized: the {} isn't signed by a health authority", what)));
+    }
+    Ok(signer)
+}
+
+// The health authority that signed `message`, for the requests only they may make (`export`). Refused
+// when no authority is configured.
+pub fn authorized(what: &str, message: &[u8], signature: &str) -> Result<PubKey, EnclaveError> {
+    signer(&AUTHORITIES.lock_expect("Health Authorities"), what, message, signature)
+}
@@ -0,0 +1,28 @@
+use enigma_tools_t::common::errors_t::{EnclaveError, EnclaveSystemError::OcallError};
+use sgx_types::sgx_status_t;
+use std::string::ToString;
+
+extern "C" {
+    fn ocall_is_cancelled(retval: *mut u8) -> sgx_status_t;
+}
+
+// Cancellation of the request an ecall runs for. The host gives up on a request once its deadline passes
+// and raises a flag, which the loops that grow with the data (matching against every stored user) read
+// through `ocall_is_cancelled` between users, so an abandoned request doesn't keep its worker thread.
+// The ecall then fails with an `OcallError`, the host answers its client with a timeout. Nothing is
+// written by the loops that check it, stopping them leaves the store as it was.
+
+// Stored users compared between two checks
+pub const CHECK_EVERY: usize = 256;
+
+pub fn check() -> Result<(), EnclaveError> {
+    let mut cancelled = 0u8;
+    let status = unsafe { ocall_is_cancelled(&mut cancelled as *mut u8) };
+    if status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveError::SystemError(OcallError { command: "ocall_is_cancelled".to_string(), err: status.as_str().to_string() }));
+    }
+    if cancelled != 0 {
+        return Err(EnclaveError::SystemError(OcallError { command: "ocall_is_cancelled".to_string(), err: "the request was cancelled".to_string() }));
+    }
+    Ok(())
+}
@@ -0,0 +1,52 @@
+use crate::identity;
+use enigma_tools_t::common::errors_t::EnclaveError;
+use enigma_tools_m::utils::LockExpectMutex;
+use enigma_crypto::{asymmetric::KeyPair, CryptoError};
+use enigma_types::{DhKey, PubKey};
+use std::collections::HashMap;
+use std::{sync::SgxMutex, vec::Vec};
+
+// Node-to-node secure channels.
+// Each side generates an ephemeral ECDH key and signs it with the enclave signing key, whose address
+// is bound into the attestation report. Once the host verified the peer's report, the enclave checks
+// that the peer's ephemeral key was signed by the attested address and derives the session key.
+// The session key never leaves the enclave, so neither operator sees what's exchanged over the channel.
+lazy_static! {
+    static ref PENDING_CHANNEL_KEYS: SgxMutex<HashMap<Vec<u8>, KeyPair>> = SgxMutex::new(HashMap::new());
+    pub static ref CHANNELS: SgxMutex<HashMap<[u8; 20], DhKey>> = SgxMutex::new(HashMap::new());
+}
+
+pub(crate) fn new_channel_key_internal(channel_pubkey: &mut PubKey, sig: &mut [u8; 65]) -> Result<(), EnclaveError> {
+    let keys = KeyPair::new()?;
+    *channel_pubkey = keys.get_pubkey();
+    *sig = identity::sign(&channel_pubkey[..])?;
+    PENDING_CHANNEL_KEYS.lock_expect("Channel Keys").insert(channel_pubkey.to_vec(), keys);
+    Ok(())
+}
+
+pub(crate) fn open_channel_internal(
+    channel_pubkey: &PubKey,
+    peer_pubkey: &PubKey,
+    peer_sig: &[u8; 65],
+    peer_address: &[u8; 20]) -> Result<(), EnclaveError> {
+
+    if !identity::signed_by(&peer_pubkey[..], peer_sig, peer_address)? {
+        return Err(CryptoError::KeyError { key_type: "Peer Channel Key", err: None }.into());
+    }
+    let keys = PENDING_CHANNEL_KEYS
+        .lock_expect("Channel Keys")
+        .remove(&channel_pubkey[..])
+        .ok_or(CryptoError::MissingKeyError { key_type: "Channel Key" })?;
+    let session_key = keys.derive_key(peer_pubkey)?;
+    CHANNELS.lock_expect("Channels").insert(*peer_address, session_key);
+    Ok(())
+}
+
+pub(crate) fn get_channel_key(peer_address: &[u8; 20]) -> Result<DhKey, EnclaveError> {
+    let key = CHANNELS
+        .lock_expect("Channels")
+        .get(peer_address)
+        .cloned()
+        .ok_or(CryptoError::MissingKeyError { key_type: "Channel Session Key" })?;
+    Ok(key)
+}
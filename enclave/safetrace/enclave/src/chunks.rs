@@ -0,0 +1,81 @@
+use crate::memory;
+use core::sync::atomic::{AtomicU64, Ordering};
+use enigma_tools_m::utils::LockExpectMutex;
+use enigma_tools_t::common::errors_t::{EnclaveError, FailedTaskError::InputError};
+use std::{collections::HashMap, string::ToString, sync::SgxMutex, vec::Vec};
+
+// Chunked inputs. The inputs that grow with the data, a location history for `addPersonalData` or the
+// answers of every peer for `ecall_federated_end`, aren't passed to their ecall as one buffer: the edge
+// routines would copy all of it into the enclave at once. The host opens a context for the total length
+// instead, appends the input to it in fixed-size chunks, and passes the context id to the ecall, which
+// takes the whole input out of it. The length is reserved against the memory budget when the context is
+// opened, so an input that doesn't fit fails before any of it is copied.
+
+pub const MAX_INPUT_LEN: usize = 16 * 1024 * 1024;
+pub const MAX_CHUNK_LEN: usize = 1024 * 1024;
+// Contexts a host that died mid-upload left behind can't pile up past this
+pub const MAX_CONTEXTS: usize = 8;
+
+static NEXT_CONTEXT: AtomicU64 = AtomicU64::new(1);
+
+lazy_static! {
+    static ref CONTEXTS: SgxMutex<HashMap<u64, Context>> = SgxMutex::new(HashMap::new());
+}
+
+struct Context {
+    total_len: usize,
+    data: Vec<u8>,
+}
+
+fn invalid(message: &str) -> EnclaveError {
+    EnclaveError::FailedTaskError(InputError { message: message.to_string() })
+}
+
+// Opens a context for an input of `total_len` bytes, returns its id.
+pub fn begin(total_len: usize) -> Result<u64, EnclaveError> {
+    if total_len > MAX_INPUT_LEN {
+        return Err(invalid("the input is too large"));
+    }
+    let mut contexts = CONTEXTS.lock_expect("Chunk Contexts");
+    if contexts.len() >= MAX_CONTEXTS {
+        return Err(invalid("too many chunked inputs are open"));
+    }
+    memory::reserve(total_len as u64)?;
+    let context = NEXT_CONTEXT.fetch_add(1, Ordering::SeqCst);
+    contexts.insert(context, Context { total_len, data: Vec::with_capacity(total_len) });
+    Ok(context)
+}
+
+pub fn append(context: u64, chunk: &[u8]) -> Result<(), EnclaveError> {
+    if chunk.len() > MAX_CHUNK_LEN {
+        return Err(invalid("the chunk is too large"));
+    }
+    let mut contexts = CONTEXTS.lock_expect("Chunk Contexts");
+    let pending = contexts.get_mut(&context).ok_or_else(|| invalid("unknown chunk context"))?;
+    if pending.data.len() + chunk.len() > pending.total_len {
+        return Err(invalid("the chunks are longer than the input"));
+    }
+    pending.data.extend_from_slice(chunk);
+    Ok(())
+}
+
+// The input of a context once every chunk is in, the context is closed either way.
+pub fn take(context: u64) -> Result<Vec<u8>, EnclaveError> {
+    let pending = CONTEXTS.lock_expect("Chunk Contexts").remove(&context).ok_or_else(|| invalid("unknown chunk context"))?;
+    memory::release(pending.total_len as u64);
+    if pending.data.len() != pending.total_len {
+        return Err(invalid("the chunked input is incomplete"));
+    }
+    Ok(pending.data)
+}
+
+// Closes a context, if it's still open.
+pub fn discard(context: u64) {
+    if let Some(pending) = CONTEXTS.lock_expect("Chunk Contexts").remove(&context) {
+        memory::release(pending.total_len as u64);
+    }
+}
+
+pub fn count() -> u64 {
+    CONTEXTS.lock_expect("Chunk Contexts").len() as u64
+}
@@ -0,0 +1,75 @@
+use crate::data::GeolocationTime;
+use crate::replay::Rejection;
+use crate::settings;
+use crate::time_t;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use enigma_tools_t::common::errors_t::{EnclaveError, FailedTaskError::InputError};
+use std::string::ToString;
+
+// The clocks of the devices. Locations are stamped by the device that recorded them, whose clock may be off,
+// and some clients stamp them in local time. On ingest the enclave brings them to its own clock:
+//  - an envelope may carry `tzOffset`, the seconds east of UTC its locations are stamped in, which are
+//    taken off so every stored location is in UTC
+//  - the drift of the device is estimated from the envelope's `timestamp`, the time the device built it,
+//    against `time_t::now`: once past `MIN_DRIFT`, which latency and jitter stay under, and within the
+//    tolerance, the locations are shifted back by it, they were stamped by the same clock
+//  - locations ending more than the tolerance after our clock can't have happened yet, the submission is
+//    refused with `ClockSkew`
+// The host sets the tolerance (`enclave.max_clock_skew`), 0 turns the drift correction and the check off. It
+// does so once, before the quotes commit to it (see `settings`).
+
+// Seconds of drift left alone
+pub const MIN_DRIFT: i64 = 30;
+// UTC-12:00 to UTC+14:00
+const MIN_TZ_OFFSET: i32 = -12 * 3600;
+const MAX_TZ_OFFSET: i32 = 14 * 3600;
+
+static TOLERANCE: AtomicU64 = AtomicU64::new(300);
+static SET: AtomicBool = AtomicBool::new(false);
+
+pub fn set(tolerance: u64) -> Result<(), EnclaveError> {
+    if settings::quoted() {
+        return Err(EnclaveError::FailedTaskError(InputError { message: "a quote committed to the clock tolerance already".to_string() }));
+    }
+    if SET.swap(true, Ordering::SeqCst) {
+        return Err(EnclaveError::FailedTaskError(InputError { message: "the clock tolerance is already set".to_string() }));
+    }
+    TOLERANCE.store(tolerance, Ordering::SeqCst);
+    Ok(())
+}
+
+pub fn tolerance() -> u64 {
+    TOLERANCE.load(Ordering::SeqCst)
+}
+
+fn shift(ts: i32, by: i64) -> i32 {
+    (i64::from(ts) - by).max(0).min(i64::from(i32::max_value())) as i32
+}
+
+// Converts `locations` to UTC and the enclave's clock. `sent_at` is the `timestamp` of the envelope, none for
+// the bare list of older clients. Returns the drift it corrected, in seconds.
+pub fn normalize(locations: &mut [GeolocationTime], tz_offset: i32, sent_at: Option<u64>) -> Result<Result<i64, Rejection>, EnclaveError> {
+    if tz_offset < MIN_TZ_OFFSET || tz_offset > MAX_TZ_OFFSET {
+        return Err(EnclaveError::FailedTaskError(InputError { message: "tzOffset must be between -43200 and 50400 seconds".to_string() }));
+    }
+    let tolerance = TOLERANCE.load(Ordering::SeqCst) as i64;
+    let now = time_t::now()? as i64;
+    let drift = match sent_at {
+        Some(sent_at) if tolerance > 0 => {
+            let drift = sent_at as i64 - now;
+            if drift.abs() >= MIN_DRIFT && drift.abs() <= tolerance { drift } else { 0 }
+        },
+        _ => 0,
+    };
+    let by = i64::from(tz_offset) + drift;
+    if by != 0 {
+        for l in locations.iter_mut() {
+            l.startTS = shift(l.startTS, by);
+            l.endTS = shift(l.endTS, by);
+        }
+    }
+    if tolerance > 0 && locations.iter().any(|l| i64::from(l.endTS) > now + tolerance) {
+        return Ok(Err(Rejection::ClockSkew));
+    }
+    Ok(Ok(drift))
+}
@@ -0,0 +1,41 @@
+use enigma_tools_t::common::errors_t::{EnclaveError, FailedTaskError::InputError};
+use miniz_oxide::inflate::decompress_to_vec_with_limit;
+use std::{string::ToString, vec::Vec};
+
+// Compressed submissions. A long location history compresses well (the same places, timestamps a few
+// minutes apart), so a client may deflate (raw RFC 1951) the plaintext of `encryptedData` before it
+// encrypts it and name the algorithm in the envelope, `"compression": "deflate"`. The host passes it on as
+// a code, the enclave inflates the payload once decrypted: the host never sees the plaintext either way.
+// A few kB can inflate to gigabytes, so the output is capped at `MAX_INFLATED`: past it the submission
+// is refused without going further.
+
+pub const MAX_INFLATED: usize = 1 << 20;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    None = 0,
+    Deflate = 1,
+}
+
+fn invalid(message: &str) -> EnclaveError {
+    EnclaveError::FailedTaskError(InputError { message: message.to_string() })
+}
+
+impl Compression {
+    pub fn from_code(code: u8) -> Result<Compression, EnclaveError> {
+        match code {
+            0 => Ok(Compression::None),
+            1 => Ok(Compression::Deflate),
+            _ => Err(invalid("unknown compression")),
+        }
+    }
+
+    // The plaintext of a payload compressed with `self`.
+    pub fn inflate(self, payload: Vec<u8>) -> Result<Vec<u8>, EnclaveError> {
+        match self {
+            Compression::None => Ok(payload),
+            Compression::Deflate => decompress_to_vec_with_limit(&payload, MAX_INFLATED)
+                .map_err(|_| invalid("encryptedData isn't deflated, or inflates past 1 MB")),
+        }
+    }
+}
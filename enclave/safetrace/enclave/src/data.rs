@@ -1,6 +1,6 @@
 use enigma_tools_t::common::errors_t::{EnclaveError,  EnclaveError::*, FailedTaskError::*, EnclaveSystemError::*};
 use enigma_crypto::{symmetric::decrypt, symmetric::encrypt};
-use enigma_types::{DhKey, PubKey, EnclaveReturn};
+use enigma_types::{DhKey, PubKey};
 use std::{
     string::{String,ToString},
     vec::Vec,
@@ -9,10 +9,31 @@ use std::{
 };
 
 use serde_json::{Value, json};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
 use rmp_serde::{Deserializer, Serializer};
 
 use sgx_tseal::{SgxSealedData};
+use crate::cancel;
+use crate::clock;
+use crate::compression::Compression;
+use crate::freshness;
+use crate::records::{self, Freshness};
+use crate::stats;
+use crate::padding::{self, PaddingClass};
+use crate::decoy;
+use crate::dedup;
+use crate::matching::{self, Strategy};
+use crate::incremental;
+use crate::memory;
+use crate::params;
+use crate::quorum;
+use crate::replay::{self, Rejection};
+use crate::sealed_log;
+use crate::summaries::{self, Summaries};
+use crate::tenants::{self, Tenant};
+use crate::users;
+use crate::authority::{self, Declaration};
+use crate::zones;
 use sgx_types::marker::ContiguousMemory;
 use std::untrusted::fs::File;
 use std::io::{Read, Write, self};
@@ -29,24 +50,105 @@ pub enum Error {
     SliceError,
     UnsealError(sgx_status_t),
     SerializeError,
+    // The store was sealed with a schema version this enclave doesn't know, see `records`
+    UnsupportedVersion(u64),
+    // The store is older than the last one the enclave knows of, see `freshness`
+    RolledBack { generation: u64, floor: u64 },
+    // The monotonic counter of the store can't be read or moved
+    CounterError(sgx_status_t),
+    // The log of the store is written in a format version this enclave doesn't know, see `sealed_log`
+    UnsupportedFormat(u32),
+    // An entry of the log that isn't its last doesn't read, at `offset` bytes into the file
+    CorruptLog { offset: u64, reason: &'static str },
+    // The file of the store can't be read or written
+    WriteError(&'static str),
     Other
 }
 
 // TODO: Do proper mapping, using a generic for now
 impl From<Error> for EnclaveError {
     fn from(other: Error) -> EnclaveError {
-        EnclaveError::SystemError(MessagingError{ err: "Error unsealing data".to_string() })
+        let err = match other {
+            Error::UnsupportedVersion(version) => format!(
+                "the sealed store has schema version {}, this enclave reads up to version {}: it was written by a newer enclave",
+                version, records::SCHEMA_VERSION),
+            Error::RolledBack { generation, floor } => format!(
+                "the sealed store is at generation {}, the enclave has seen generation {}: it was rolled back", generation, floor),
+            Error::CounterError(status) => format!("the monotonic counter of the sealed store failed: {}", status.as_str()),
+            Error::UnsupportedFormat(version) => format!(
+                "the sealed store is a log of format version {}, this enclave reads up to version {}", version, sealed_log::FORMAT_VERSION),
+            Error::CorruptLog { offset, reason } => format!("the log of the sealed store is corrupt at byte {}: {}", offset, reason),
+            Error::WriteError(reason) => reason.to_string(),
+            _ => "Error unsealing data".to_string(),
+        };
+        EnclaveError::SystemError(MessagingError{ err })
     }
 }
 
 // Structs
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct GeolocationTime {
-    lat: f64,
-    lng: f64,
-    startTS: i32,
-    endTS: i32,
-    testResult: bool
+    pub(crate) lat: f64,
+    pub(crate) lng: f64,
+    pub(crate) startTS: i32,
+    pub(crate) endTS: i32,
+    pub(crate) testResult: bool
+}
+
+// The decrypted `encryptedData`: the envelope of `replay`, or the bare list of locations of older clients.
+// Only the envelope can carry the declaration of an `authority`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PersonalData {
+    Envelope {
+        nonce: String,
+        timestamp: u64,
+        locations: Vec<GeolocationTime>,
+        #[serde(default)]
+        declaration: Option<Declaration>,
+        // Seconds east of UTC the locations are stamped in, see `clock`
+        #[serde(default, rename = "tzOffset")]
+        tz_offset: i32,
+    },
+    Legacy(Vec<GeolocationTime>),
+}
+
+// The decrypted `encryptedData` of `updateUserStatus`: the new test result of a stored user, for their
+// locations ending at or after `since` (UTC seconds, all of them when missing). Marking them as infected
+// takes the declaration of an `authority`, like submitting them so.
+#[derive(Deserialize)]
+struct StatusUpdate {
+    nonce: String,
+    timestamp: u64,
+    #[serde(rename = "testResult")]
+    test_result: bool,
+    #[serde(default)]
+    since: Option<i64>,
+    #[serde(default)]
+    declaration: Option<Declaration>,
+}
+
+// Why `addPersonalData` stored nothing: a replayed or expired envelope or a full store, which the host may
+// learn, or any other error, which only the user does (see `add_personal_data_receipt`).
+pub enum SubmitError {
+    Rejected(Rejection),
+    Failed(EnclaveError),
+}
+
+// What `addPersonalData` stored: how many new locations, how many it left out as already stored (see `dedup`),
+// and the drift of the device's clock they were corrected by.
+pub struct Stored {
+    pub records: usize,
+    pub duplicates: usize,
+    pub clock_drift: i64,
+}
+
+impl From<EnclaveError> for SubmitError {
+    fn from(other: EnclaveError) -> SubmitError { SubmitError::Failed(other) }
+}
+
+impl From<Error> for SubmitError {
+    fn from(other: Error) -> SubmitError { SubmitError::Failed(other.into()) }
 }
 
 pub fn decrypt_userid(userid: &[u8], key: &DhKey) -> Result<Vec<u8>, EnclaveError> {
@@ -65,52 +167,53 @@ pub fn decrypt_data(data: &[u8], key: &DhKey) -> Result<Vec<u8>, EnclaveError> {
     }
 }
 
-//pub fn create_sealeddata_for_serializable(data: &UserLocations, sealed_log_out: &mut [u8; SEAL_LOG_SIZE]) -> enigma_types::EnclaveReturn {
-pub fn create_sealeddata_for_serializable(data: HashMap<String, Vec<GeolocationTime>>, sealed_log_out: &mut [u8; SEAL_LOG_SIZE]) -> enigma_types::EnclaveReturn {
-
-    let encoded_vec = serde_json::to_vec(&data).unwrap();
-    let encoded_slice = encoded_vec.as_slice();
-    // println!("Length of encoded slice: {}", encoded_slice.len());
-    // println!("Encoded slice: {:?}", encoded_slice);
+// Size of the sealed blob for `len` bytes of plaintext
+pub fn sealed_size(len: usize) -> u64 {
+    u64::from(SgxSealedData::<[u8]>::calc_raw_sealed_data_size(0, len as u32))
+}
 
+// Seals `plaintext` into a blob of `sealed_size`, however large it is.
+pub fn seal_to_vec(plaintext: &[u8]) -> Result<Vec<u8>, Error> {
     let aad: [u8; 0] = [0_u8; 0];
-    let result = SgxSealedData::<[u8]>::seal_data(&aad, encoded_slice);
-    let sealed_data = match result {
-        Ok(x) => x,
-        Err(ret) => { return EnclaveReturn::SgxError; },
-    };
-
-    let sealed_log = sealed_log_out.as_mut_ptr();
-
-    let opt = to_sealed_log_for_slice(&sealed_data, sealed_log, SEAL_LOG_SIZE as u32);
-    if opt.is_none() {
-        return EnclaveReturn::SgxError;
-    }
+    let sealed_data = SgxSealedData::<[u8]>::seal_data(&aad, plaintext).map_err(Error::UnsealError)?;
+    let mut sealed_log = vec![0u8; sealed_size(plaintext.len()) as usize];
+    unsafe { sealed_data.to_raw_sealed_data_t(sealed_log.as_mut_ptr() as *mut sgx_sealed_data_t, sealed_log.len() as u32) }
+        .ok_or(Error::SliceError)?;
+    Ok(sealed_log)
+}
 
-    EnclaveReturn::Success
+// The whole sealed file at `path`, `None` if there's none. The files of before `seal_to_vec` are
+// `SEAL_LOG_SIZE` bytes long, padded with zeros, and read the same.
+pub fn read_sealed_file(path: &str) -> Result<Option<Vec<u8>>, Error> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(_) => return Err(Error::WriteError("opening a sealed file failed")),
+    };
+    let mut sealed_log = Vec::new();
+    file.read_to_end(&mut sealed_log).map_err(|_| Error::WriteError("reading a sealed file failed"))?;
+    Ok(Some(sealed_log))
 }
 
-pub fn recover_sealeddata_for_serializable(sealed_log: * mut u8, sealed_log_size: u32) -> Result<HashMap<String, Vec<GeolocationTime>>, Error> {
+pub fn recover_sealeddata_for_serializable<T: DeserializeOwned>(sealed_log: * mut u8, sealed_log_size: u32) -> Result<T, Error> {
 
-    let sealed_data = from_sealed_log_for_slice::<u8>(sealed_log, sealed_log_size).ok_or(Error::SliceError)?;
-    let unsealed_data = sealed_data.unseal_data().map_err(|err| Error::UnsealError(err))?;
-    let encoded_slice = unsealed_data.get_decrypt_txt();
+    let encoded_slice = recover_sealeddata(sealed_log, sealed_log_size)?;
 
     // println!("Length of encoded slice: {}", encoded_slice.len());
     // println!("Encoded slice: {:?}", encoded_slice);
     
-    let data: HashMap<String, Vec<GeolocationTime>> = serde_json::from_slice(encoded_slice).unwrap();
+    let data: T = serde_json::from_slice(&encoded_slice).map_err(|_| Error::SerializeError)?;
 
     Ok(data)
 }
 
-
-fn to_sealed_log_for_slice<T: Copy + ContiguousMemory>(sealed_data: &SgxSealedData<[T]>, sealed_log: * mut u8, sealed_log_size: u32) -> Option<* mut sgx_sealed_data_t> {
-    unsafe {
-        sealed_data.to_raw_sealed_data_t(sealed_log as * mut sgx_sealed_data_t, sealed_log_size)
-    }
+pub fn recover_sealeddata(sealed_log: * mut u8, sealed_log_size: u32) -> Result<Vec<u8>, Error> {
+    let sealed_data = from_sealed_log_for_slice::<u8>(sealed_log, sealed_log_size).ok_or(Error::SliceError)?;
+    let unsealed_data = sealed_data.unseal_data().map_err(|err| Error::UnsealError(err))?;
+    Ok(unsealed_data.get_decrypt_txt().to_vec())
 }
 
+
 fn from_sealed_log_for_slice<'a, T: Copy + ContiguousMemory>(sealed_log: * mut u8, sealed_log_size: u32) -> Option<SgxSealedData<'a, [T]>> {
     unsafe {
         SgxSealedData::<[T]>::from_raw_sealed_data_t(sealed_log as * mut sgx_sealed_data_t, sealed_log_size)
@@ -152,65 +255,291 @@ pub fn load_sealed_data(path: &str, sealed_data: &mut [u8]) -> Result<(), String
 }
 
 pub fn unseal_data_wrapper() -> Result<HashMap<String, Vec<GeolocationTime>>, Error> {
-    let p = DATAFILE;
-    let mut sealed_log_out = [0u8; SEAL_LOG_SIZE];
-    match load_sealed_data(&p, &mut sealed_log_out) {
-        Ok(_) => {
-            let sealed_log = sealed_log_out.as_mut_ptr();
-            let mut data = recover_sealeddata_for_serializable(sealed_log, SEAL_LOG_SIZE as u32)?;
-            Ok(data)
+    unseal_store().map(|(data, _)| data)
+}
+
+// The store with the generation it was sealed at, which the caches of what it holds go by (`summaries`).
+pub fn unseal_store() -> Result<(HashMap<String, Vec<GeolocationTime>>, u64), Error> {
+    match sealed_log::read()? {
+        Some(log) => {
+            freshness::check(&log.freshness)?;
+            Ok((log.data, log.freshness.generation))
         },
-        Err(err) => {
-            let mut data = HashMap::new();
-            Ok(data)
+        // A store that was there and went missing is rolled back too
+        None => {
+            freshness::check(&Freshness::default())?;
+            Ok((HashMap::new(), 0))
         }
     }
 }
 
+// Written to the log of `sealed_log`, as a delta of what the last unseal read.
+pub fn seal_data_wrapper(data: HashMap<String, Vec<GeolocationTime>>) -> Result<(), Error> {
+    let freshness = freshness::next()?;
+    sealed_log::write(&data, &freshness)?;
+    stats::refresh(&data, sealed_size(records::encode(&data, &freshness).len()));
+    summaries::refresh(&data, freshness.generation);
+    freshness::sealed(&freshness)
+}
+
+// `safetrace-app fsck`: reads every entry of the log and reports what it holds. With `repair`, a log with a
+// torn entry or with deltas is compacted into a single snapshot, the same store at the next generation.
+pub fn check_store_internal(repair: bool) -> Result<Vec<u8>, EnclaveError> {
+    let report = match sealed_log::read()? {
+        Some(log) => {
+            freshness::check(&log.freshness)?;
+            let mut report = log.report;
+            if repair && (report.torn_bytes > 0 || report.deltas > 0 || report.format_version == 0) {
+                let freshness = freshness::next()?;
+                sealed_log::rewrite(&log.data, &freshness)?;
+                stats::refresh(&log.data, sealed_size(records::encode(&log.data, &freshness).len()));
+                freshness::sealed(&freshness)?;
+                report.generation = freshness.generation;
+                report.compacted = true;
+            }
+            report
+        },
+        None => sealed_log::Report::default(),
+    };
+    Ok(serde_json::to_vec(&report).map_err(|_| Error::SerializeError)?)
+}
+
+// Replaces the store with an empty one, returns how many users and records were dropped. Within a
+// tenant only its partition goes, see `tenants`.
+// Sealing the empty map (rather than deleting the file) keeps the stats rollups in sync. Once operators are
+// registered, only after a quorum of them approved this purge, see `quorum`.
+pub fn purge_data_internal() -> Result<(u64, u64), EnclaveError> {
+    let tenant = tenants::current()?;
+    quorum::take("Purge", tenant.0.as_ref().map(String::as_str), None)?;
+    let data = unseal_data_wrapper()?;
+    // Everything goes outside a tenant
+    let (purged, kept): (HashMap<_, _>, HashMap<_, _>) = data.into_iter()
+        .partition(|(key, _)| tenant == Tenant(None) || tenant.owns(key));
+    let records = record_count(&purged);
+    let users = purged.len() as u64;
+    seal_data_wrapper(kept)?;
+    Ok((users, records))
+}
+
 pub fn add_personal_data_internal(
     encryptedUserId: &[u8],
     encryptedData: &[u8],
+    encryptedSignature: &[u8],
     userPubKey: &PubKey,
-    dhKey: &DhKey)  -> Result<(), EnclaveError> {
+    compression: Compression,
+    dhKey: &DhKey)  -> Result<Stored, SubmitError> {
 
     println!("Add personal data inside the enclave");
 
     // Decrypt inputs using dhKey
     let decrypted_userid = decrypt_userid(encryptedUserId, dhKey)?;
-    let decrypted_data = decrypt_data(encryptedData, dhKey)?;
+    let decrypted_data = compression.inflate(decrypt_data(encryptedData, dhKey)?)?;
 
     // TODO: Should not panic, propagate error instead
     let userid = match str::from_utf8(&decrypted_userid) {
         Ok(v) => v,
         Err(e) => panic!("Invalid UTF-8 sequence: {}", e),
     }; 
+    // The user as stored, in the partition of the tenant
+    let key = tenants::current()?.scope(userid)?;
+    users::authenticate(&key, encryptedSignature, &[encryptedUserId, encryptedData, &userPubKey[..]], dhKey)?;
 
     // Deserialize decrypted input data into expected format
-    let mut inputData: Vec<GeolocationTime> = serde_json::from_slice(&decrypted_data).unwrap();
+    let payload: PersonalData = serde_json::from_slice(&decrypted_data)
+        .map_err(|_| FailedTaskError(InputError { message: "encryptedData isn't a list of locations".to_string() }))?;
+    let (mut inputData, tz_offset, sent_at) = match payload {
+        PersonalData::Envelope { nonce, timestamp, locations, declaration, tz_offset } => {
+            replay::check(&nonce, timestamp)?.map_err(SubmitError::Rejected)?;
+            authority::check(userid, &locations, declaration.as_ref())?;
+            (locations, tz_offset, Some(timestamp))
+        },
+        PersonalData::Legacy(_) if replay::required() => {
+            return Err(FailedTaskError(InputError { message: "encryptedData must carry a nonce and a timestamp".to_string() }).into());
+        },
+        PersonalData::Legacy(locations) => {
+            authority::check(userid, &locations, None)?;
+            (locations, 0, None)
+        },
+    };
+    validate_locations(&inputData)?;
+    let clock_drift = clock::normalize(&mut inputData, tz_offset, sent_at)?.map_err(SubmitError::Rejected)?;
+    let mut inputData = zones::apply(inputData)?;
+    quantize_locations(&mut inputData);
 
     let mut data = unseal_data_wrapper()?;
     //let mut data = HashMap::new();
 
-    data.insert(userid.to_string(), inputData);
+    let before = record_count(&data);
+    let merged = dedup::merge(data.entry(key).or_insert_with(Vec::new), inputData);
+    drop_expired(&mut data)?;
+    memory::check_capacity(before, record_count(&data)).map_err(SubmitError::Rejected)?;
 
     // Seal the data and store it on disk
-    let mut sealed_log_in = [0u8; SEAL_LOG_SIZE];
-    create_sealeddata_for_serializable(data, &mut sealed_log_in);
-
-    let p = DATAFILE;
-    save_sealed_data(&p, &sealed_log_in);
+    seal_data_wrapper(data)?;
 
     let mut newdata = unseal_data_wrapper()?;
     println!("This is what we got");
     println!("{:?}", newdata);
 
+    Ok(Stored { records: merged.new, duplicates: merged.duplicates, clock_drift })
+}
+
+// Sets the test result of the locations a user already stored, e.g. when they test positive days after
+// submitting them, without uploading the track again. Signed and replay protected like `addPersonalData`,
+// its receipt counts the locations it updated.
+pub fn update_user_status_internal(
+    encryptedUserId: &[u8],
+    encryptedData: &[u8],
+    encryptedSignature: &[u8],
+    userPubKey: &PubKey,
+    dhKey: &DhKey) -> Result<Stored, SubmitError> {
+
+    let decrypted_userid = decrypt_userid(encryptedUserId, dhKey)?;
+    let decrypted_data = decrypt_data(encryptedData, dhKey)?;
+    let userid = str::from_utf8(&decrypted_userid)
+        .map_err(|_| FailedTaskError(InputError { message: "encryptedUserId isn't UTF-8".to_string() }))?;
+    let key = tenants::current()?.scope(userid)?;
+    users::authenticate(&key, encryptedSignature, &[encryptedUserId, encryptedData, &userPubKey[..]], dhKey)?;
+
+    let update: StatusUpdate = serde_json::from_slice(&decrypted_data)
+        .map_err(|_| FailedTaskError(InputError { message: "encryptedData isn't a status update".to_string() }))?;
+    replay::check(&update.nonce, update.timestamp)?.map_err(SubmitError::Rejected)?;
+
+    let mut data = unseal_data_wrapper()?;
+    let since = update.since.unwrap_or(0);
+    let records = {
+        let locations = data.get_mut(&key)
+            .ok_or_else(|| FailedTaskError(InputError { message: "no locations are stored for this user".to_string() }))?;
+        // Only the locations it changes need a declaration, not those marked before `since`
+        let updated: Vec<GeolocationTime> = locations.iter()
+            .filter(|l| i64::from(l.endTS) >= since)
+            .map(|l| GeolocationTime { testResult: update.test_result, ..l.clone() })
+            .collect();
+        authority::check(userid, &updated, update.declaration.as_ref())?;
+        for l in locations.iter_mut().filter(|l| i64::from(l.endTS) >= since) {
+            l.testResult = update.test_result;
+        }
+        updated.len()
+    };
+    drop_expired(&mut data)?;
+    seal_data_wrapper(data)?;
+
+    Ok(Stored { records, duplicates: 0, clock_drift: 0 })
+}
+
+fn record_count(data: &HashMap<String, Vec<GeolocationTime>>) -> u64 {
+    data.values().map(|locations| locations.len() as u64).sum()
+}
+
+// The receipt of `addPersonalData`, encrypted for the user like the match results. Without it the host
+// decides what the user is told, and the validation errors, which describe the decrypted locations,
+// reach it in plaintext. Receipts are padded to a fixed size so a failure looks like a success.
+// `updateUserStatus` answers with the same receipt.
+const RECEIPT_SIZE: usize = 256;
+const RECEIPT_ERROR_LEN: usize = 160;
+
+pub fn add_personal_data_receipt(result: &Result<Stored, SubmitError>, dhKey: &DhKey) -> Result<Vec<u8>, EnclaveError> {
+    let receipt = match result {
+        // The drift lets the device tell its clock is off, see `clock`
+        Ok(Stored { records, duplicates, clock_drift }) => {
+            let mut receipt = json!({ "status": "Passed", "records": records });
+            if *duplicates != 0 {
+                receipt["duplicates"] = json!(duplicates);
+            }
+            if *clock_drift != 0 {
+                receipt["clockDrift"] = json!(clock_drift);
+            }
+            receipt
+        },
+        Err(SubmitError::Rejected(rejection)) => json!({ "status": "Failed", "error": rejection.name() }),
+        Err(SubmitError::Failed(e)) => json!({ "status": "Failed", "error": format!("{:?}", e).chars().take(RECEIPT_ERROR_LEN).collect::<String>() }),
+    };
+    let mut receipt = serde_json::to_vec(&receipt).map_err(|_| Error::SerializeError)?;
+    // Trailing whitespace is valid JSON
+    let size = receipt.len().max(RECEIPT_SIZE);
+    receipt.resize(size, b' ');
+    Ok(encrypt(&receipt, dhKey)?)
+}
+
+// The host can only check the envelope, the records themselves are checked once decrypted.
+fn validate_locations(locations: &[GeolocationTime]) -> Result<(), EnclaveError> {
+    for (i, l) in locations.iter().enumerate() {
+        let problem = if !(l.lat >= -90.0 && l.lat <= 90.0) {
+            "lat must be within [-90, 90]"
+        } else if !(l.lng >= -180.0 && l.lng <= 180.0) {
+            "lng must be within [-180, 180]"
+        } else if l.startTS < 0 || l.endTS < l.startTS {
+            "startTS must be positive and not after endTS"
+        } else {
+            continue;
+        };
+        return Err(FailedTaskError(InputError { message: format!("location {}: {}", i, problem) }));
+    }
     Ok(())
 }
 
+// Rounds the coordinates to the grid and widens the time span to whole buckets, so two locations that
+// overlapped still do: the start goes down to its bucket and the end up to the next one.
+fn quantize_locations(locations: &mut [GeolocationTime]) {
+    let (grid, bucket) = (params::grid(), params::time_bucket());
+    for l in locations.iter_mut() {
+        if grid > 0.0 {
+            l.lat = ((l.lat / grid).round() * grid).max(-90.0).min(90.0);
+            l.lng = ((l.lng / grid).round() * grid).max(-180.0).min(180.0);
+        }
+        if bucket > 1 {
+            l.startTS -= l.startTS.rem_euclid(bucket);
+            let rest = l.endTS.rem_euclid(bucket);
+            if rest != 0 {
+                l.endTS = l.endTS.saturating_add(bucket - rest);
+            }
+        }
+    }
+}
+
+// Drops the records past the retention period of their tenant, and the users left without any.
+fn drop_expired(data: &mut HashMap<String, Vec<GeolocationTime>>) -> Result<(), EnclaveError> {
+    for (key, locations) in data.iter_mut() {
+        if let Some(cutoff) = tenants::retention_cutoff(key)? {
+            locations.retain(|l| l.endTS as i64 >= cutoff);
+        }
+    }
+    data.retain(|_, locations| !locations.is_empty());
+    Ok(())
+}
+
+// Returns the locations of `user_locations` that overlap with an infected location stored in `data`,
+// in the partition of `tenant`, as `strategy` compares them. Only the stored users the `summaries` of `data`
+// don't rule out are compared. Stops if the host cancels the request meanwhile, see `cancel`.
+pub fn find_matches(
+    user_locations: &[GeolocationTime],
+    data: &HashMap<String, Vec<GeolocationTime>>,
+    summaries: &Summaries,
+    tenant: &Tenant,
+    strategy: &Strategy,
+    exclude: Option<&str>) -> Result<Vec<GeolocationTime>, EnclaveError> {
+
+    let mut results = Vec::new();
+    // We iterate over all values in the set, excluding the user we are looking for matches (if stored here).
+    for (i, (key, val)) in data.iter().enumerate() {
+        if i % cancel::CHECK_EVERY == 0 {
+            cancel::check()?;
+        }
+        if tenant.owns(key) && Some(key.as_str()) != exclude && summaries.may_match(key, user_locations, strategy) {
+            strategy.match_locations(user_locations, val, tenants::retention_cutoff(key)?, &mut results);
+        }
+    }
+
+    Ok(results)
+}
+
 pub fn find_match_internal(
     encryptedUserId: &[u8],
+    encryptedSignature: &[u8],
+    strategy: &[u8],
+    incremental: bool,
     userPubKey: &PubKey,
-    dhKey: &DhKey)  -> Result<Vec<u8>, EnclaveError> {
+    dhKey: &DhKey)  -> Result<(Vec<u8>, Strategy), EnclaveError> {
 
     // Decrypt inputs using dhKey
     let decrypted_userid = decrypt_userid(encryptedUserId, dhKey)?;
@@ -220,49 +549,35 @@ pub fn find_match_internal(
         Ok(v) => v,
         Err(e) => panic!("Invalid UTF-8 sequence: {}", e),
     };
+    let strategy = matching::resolve(strategy)?;
+    let tenant = tenants::current()?;
+    let key = tenant.scope(userid)?;
+    // A decoy goes through the same steps as a query, its results are dropped at the end. Decoys are never
+    // registered, their ids aren't anyone's: the registration they lack doesn't fail them.
+    let decoy = decoy::is_decoy(userid, dhKey);
+    let authenticated = users::authenticate(&key, encryptedSignature, &[encryptedUserId, &userPubKey[..]], dhKey);
+    if !decoy {
+        authenticated?;
+        decoy::charge()?;
+    }
 
-    let data = unseal_data_wrapper()?;
-
-    let mut results = Vec::new();
-
-    // This is the algorithm to find overlaps in time and space, defined in time by TOVERLAP (in seconds)
-    // and in space by DISTANCE (in meters)
-    // We iterate over all values in the set, excluding the user we are looking for matches. 
-    // For all of them, we iterate over all locations and compare them with all locations from the user
-    for (key, val) in data.iter() {
-        if key != &userid {
-            for d in data[userid].clone() {
-                for e in val.iter() {
-                    if e.testResult {
-                        // It's easier to find overlaps in time because it's a direct comparison of integers
-                        // so handle this first:
-                        // Both time intervals have to be larger than the minumum time overlap TOVERLAP
-                        // and both start times + TOVERLAP have to be smaller than the other end times
-                        if d.endTS - d.startTS > TOVERLAP &&
-                           e.endTS - e.startTS > TOVERLAP &&
-                           d.startTS + TOVERLAP < e.endTS && e.startTS + TOVERLAP < d.endTS {
-                            // We start comparing distance between latitudes. Each degree of lat is aprox
-                            // 111 kms (range varies between 110.567 km at the equator to 111.699 km at the poles)
-                            // The distance between two locations will be equal or larger than the distance between 
-                            // their latitudes (or the distance between lats will be smaller than the distance * cos(45))
-                            // Source:
-                            // https://stackoverflow.com/questions/5031268/algorithm-to-find-all-latitude-longitude-locations-within-a-certain-distance-fro
-                            if (e.lat - d.lat).abs() * 111000.0 <  DISTANCE * 0.71 {
-                                // then we can run a more computationally expensive and precise comparison
-                                if (e.lat.sin()*d.lat.sin()+e.lat.cos()*d.lat.cos()*(e.lng-d.lng).cos()).acos() * EARTH_RADIUS < DISTANCE {
-                                    results.push(d.clone());
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-        }
+    let (data, generation) = unseal_store()?;
+    // Only the stored users that changed since the previous query of the user are compared, see `incremental`
+    let user_locations = data.get(&key).cloned().unwrap_or_default();
+    let (mut prior, mut new) = incremental::find_matches(&key, &user_locations, &data, &summaries::index(&data, generation), &tenant, &strategy, !decoy)?;
+    if decoy {
+        prior.clear();
+        new.clear();
     }
 
-    let serialized_results = serde_json::to_string(&results).map_err(|err| Error::SerializeError)?;
-    let array_u8_results = serialized_results.as_bytes();
-    let encrypted_output = encrypt(array_u8_results, dhKey)?;
+    let serialized_results = if incremental {
+        serde_json::to_vec(&incremental::answer(&prior, new, generation))
+    } else {
+        serde_json::to_vec(&prior.into_iter().chain(new).collect::<Vec<_>>())
+    }.map_err(|err| Error::SerializeError)?;
+    let padded_results = padding::pad(PaddingClass::Matching, serialized_results);
+    let encrypted_output = encrypt(&padded_results, dhKey)?;
 
-    Ok(encrypted_output)
+    // The strategy that ran goes back with the results, so the response can say which one it was
+    Ok((encrypted_output, strategy))
 }
\ No newline at end of file
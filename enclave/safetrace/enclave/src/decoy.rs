@@ -0,0 +1,82 @@
+use crate::authority::decode_hex;
+use crate::time_t;
+use core::sync::atomic::{AtomicU64, Ordering};
+use enigma_tools_t::common::errors_t::{EnclaveError, FailedTaskError::InputError};
+use enigma_tools_m::utils::LockExpectMutex;
+use enigma_types::DhKey;
+use sgx_tcrypto::rsgx_sha256_slice;
+use std::{string::ToString, sync::SgxMutex, vec::Vec};
+
+// Decoy queries.
+// Clients hide their real `findMatch` requests among periodic decoys. A decoy is a regular request whose
+// user id is 16 random bytes followed by the first 16 bytes of their HMAC-SHA256 under the key of the
+// session, hex (`Session::decoy_userid` of the client). The user id is encrypted, and without the session
+// key the marker is as random as the rest of it: only the enclave tells a decoy apart. A decoy is
+// authenticated and matched like any other query, its results are dropped at the end: it answers with an
+// empty result (padded like any other, see `padding`), and isn't charged against the matching rate limit,
+// which is accounted here rather than on the host.
+const DECOY_LABEL: &[u8] = b"safetrace:decoy:";
+const NONCE_LEN: usize = 16;
+const TAG_LEN: usize = 16;
+
+const WINDOW_SECONDS: u64 = 60;
+
+// Real matching queries allowed per window, 0 means unlimited.
+static LIMIT: AtomicU64 = AtomicU64::new(0);
+
+lazy_static! {
+    // (start of the current window, queries charged in it)
+    static ref WINDOW: SgxMutex<(u64, u64)> = SgxMutex::new((0, 0));
+}
+
+// HMAC-SHA256, the SDK has none. The session keys are shorter than a block.
+fn hmac_sha256(key: &DhKey, message: &[u8]) -> Option<[u8; 32]> {
+    let mut inner = Vec::with_capacity(64 + message.len());
+    let mut outer = Vec::with_capacity(64 + 32);
+    for i in 0..64 {
+        let byte = key.get(i).cloned().unwrap_or(0);
+        inner.push(byte ^ 0x36);
+        outer.push(byte ^ 0x5c);
+    }
+    inner.extend_from_slice(message);
+    outer.extend_from_slice(&rsgx_sha256_slice(&inner).ok()?);
+    rsgx_sha256_slice(&outer).ok()
+}
+
+// Whether `userid` carries the decoy marker of the session of `key`.
+pub fn is_decoy(userid: &str, key: &DhKey) -> bool {
+    let bytes = match decode_hex(userid) {
+        Some(ref bytes) if bytes.len() == NONCE_LEN + TAG_LEN => bytes.clone(),
+        _ => return false,
+    };
+    let (nonce, tag) = bytes.split_at(NONCE_LEN);
+    let mut message = DECOY_LABEL.to_vec();
+    message.extend_from_slice(nonce);
+    match hmac_sha256(key, &message) {
+        // In constant time, the tag isn't leaked a byte at a time
+        Some(mac) => mac[..TAG_LEN].iter().zip(tag).fold(0u8, |diff, (a, b)| diff | (a ^ b)) == 0,
+        None => false,
+    }
+}
+
+pub fn set_limit(per_minute: u64) {
+    LIMIT.store(per_minute, Ordering::SeqCst);
+}
+
+// Charges one real query to the current window, fails once the limit is reached.
+pub fn charge() -> Result<(), EnclaveError> {
+    let limit = LIMIT.load(Ordering::SeqCst);
+    if limit == 0 {
+        return Ok(());
+    }
+    let now = time_t::now()?;
+    let mut window = WINDOW.lock_expect("Rate Limit Window");
+    if now >= window.0 + WINDOW_SECONDS {
+        *window = (now, 0);
+    }
+    if window.1 >= limit {
+        return Err(EnclaveError::FailedTaskError(InputError { message: "matching rate limit reached".to_string() }));
+    }
+    window.1 += 1;
+    Ok(())
+}
@@ -0,0 +1,49 @@
+use crate::data::GeolocationTime;
+use crate::params;
+use std::collections::HashSet;
+use std::vec::Vec;
+
+// Deduplication of the uploads of a user. Apps resubmit the days they already sent (a retry, a full
+// export after a reinstall), so a submission is merged into the locations the user has stored, and those
+// already there are left out. Two locations are the same when they fall in the same time buckets and the
+// same cell of the grid of `[quantization]`, the test result aside: submissions are quantized before they
+// get here, and without a grid or bucket only identical locations are. A duplicate marked infected marks
+// the stored one, a duplicate that isn't doesn't clear it (`updateUserStatus` does).
+
+// The time span and position of a location, to the bucket and the cell.
+type Key = (i32, i32, i64, i64);
+
+fn key(l: &GeolocationTime, grid: f64, bucket: i32) -> Key {
+    let bucket = bucket.max(1);
+    let cell = |degrees: f64| if grid > 0.0 { (degrees / grid).round() as i64 } else { degrees.to_bits() as i64 };
+    (l.startTS.div_euclid(bucket), l.endTS.div_euclid(bucket), cell(l.lat), cell(l.lng))
+}
+
+// How many locations of a submission were new, and how many were already stored (or twice in it).
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Merged {
+    pub new: usize,
+    pub duplicates: usize,
+}
+
+// Adds the locations of `incoming` that aren't in `stored` to it.
+pub fn merge(stored: &mut Vec<GeolocationTime>, incoming: Vec<GeolocationTime>) -> Merged {
+    let (grid, bucket) = (params::grid(), params::time_bucket());
+    let mut seen: HashSet<Key> = stored.iter().map(|l| key(l, grid, bucket)).collect();
+    let mut merged = Merged::default();
+    for l in incoming {
+        let k = key(&l, grid, bucket);
+        if seen.insert(k) {
+            stored.push(l);
+            merged.new += 1;
+            continue;
+        }
+        merged.duplicates += 1;
+        if l.testResult {
+            if let Some(same) = stored.iter_mut().find(|s| key(s, grid, bucket) == k) {
+                same.testResult = true;
+            }
+        }
+    }
+    merged
+}
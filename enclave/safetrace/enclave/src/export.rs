@@ -0,0 +1,186 @@
+use crate::aggregates::geohash;
+use crate::authority;
+use crate::data::{self, GeolocationTime};
+use crate::identity;
+use crate::settings;
+use crate::stats::EPOCH_SECONDS;
+use crate::tenants::{self, Tenant};
+use crate::time_t;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use enigma_tools_m::utils::EthereumAddress;
+use enigma_tools_t::common::errors_t::{EnclaveError, FailedTaskError::InputError};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::{string::{String, ToString}, vec::Vec};
+
+// Exports for epidemiologists. A health authority asks, with a request signed by one of the keys of
+// `authority`, for the users present and those who tested positive per geohash cell and day:
+//   {"issuedAt": 1589000000, "format": "csv", "precision": 6, "from": 18380, "to": 18400, "signature": "1b2c..."}
+// `signature` is over `request_message`, `from` and `to` are days (UTC epochs, as in `stats`). A row is
+// released only when at least `K` users were in its cell that day, and its infected count only when it's
+// 0 or at least `K` too, else it's left empty: a small count would point at the few users behind it.
+// The bundle is signed with the enclave key over `bundle_message`, the authority checks it against the
+// attested enclave like any other output. An export covers the partition of the tenant of the request.
+
+const REQUEST_PREFIX: &str = "safetrace:export:";
+const BUNDLE_PREFIX: &str = "safetrace:export-bundle:";
+// How far `issuedAt` may be from the enclave's clock, in seconds
+const MAX_REQUEST_SKEW: u64 = 600;
+pub const MAX_DAYS: i32 = 90;
+
+// The k-anonymity threshold, 0 until the host enables the exports. Set once, before the first quote
+// commits to it, so the host can't lower it after.
+static K: AtomicU64 = AtomicU64::new(0);
+static SET: AtomicBool = AtomicBool::new(false);
+
+#[derive(Deserialize, Debug)]
+pub struct ExportRequest {
+    #[serde(rename = "issuedAt")]
+    pub issued_at: u64,
+    pub format: String,
+    pub precision: u8,
+    pub from: i32,
+    pub to: i32,
+    pub signature: String,
+}
+
+#[derive(Serialize, Debug)]
+struct Row {
+    cell: String,
+    epoch: i32,
+    users: u64,
+    infected: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+struct Bundle {
+    // Address of the health authority that asked
+    authority: String,
+    #[serde(rename = "issuedAt")]
+    issued_at: u64,
+    format: String,
+    precision: u8,
+    from: i32,
+    to: i32,
+    k: u64,
+    #[serde(rename = "generatedAt")]
+    generated_at: u64,
+    rows: usize,
+    body: String,
+    signature: String,
+}
+
+fn invalid(message: &str) -> EnclaveError {
+    EnclaveError::FailedTaskError(InputError { message: message.to_string() })
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// A threshold of 0 turns the exports off, else it's at least 2: a row of a single user is that user.
+pub fn set(k: u64) -> Result<(), EnclaveError> {
+    if k == 1 {
+        return Err(invalid("the export threshold must be at least 2"));
+    }
+    if settings::quoted() {
+        return Err(invalid("a quote committed to the export policy already"));
+    }
+    if SET.swap(true, Ordering::SeqCst) {
+        return Err(invalid("the export policy is already set"));
+    }
+    K.store(k, Ordering::SeqCst);
+    Ok(())
+}
+
+pub fn k() -> u64 {
+    K.load(Ordering::SeqCst)
+}
+
+fn request_message(issued_at: u64, format: &str, precision: u8, from: i32, to: i32) -> Vec<u8> {
+    format!("{}{}:{}:{}:{}:{}", REQUEST_PREFIX, issued_at, format, precision, from, to).into_bytes()
+}
+
+fn bundle_message(bundle: &Bundle) -> Vec<u8> {
+    let mut message = format!("{}{}:{}:{}:{}:{}:{}:{}:{}:", BUNDLE_PREFIX, bundle.authority, bundle.issued_at, bundle.format,
+                              bundle.precision, bundle.from, bundle.to, bundle.k, bundle.generated_at).into_bytes();
+    message.extend_from_slice(bundle.body.as_bytes());
+    message
+}
+
+fn rows(data: &HashMap<String, Vec<GeolocationTime>>, tenant: &Tenant, precision: usize, from: i32, to: i32, k: u64) -> Vec<Row> {
+    // (cell, epoch) -> (users, infected users)
+    let mut cells: BTreeMap<(String, i32), (BTreeSet<&str>, BTreeSet<&str>)> = BTreeMap::new();
+    for (user, locations) in data.iter().filter(|(user, _)| tenant.owns(user)) {
+        for location in locations {
+            let epoch = location.startTS / EPOCH_SECONDS;
+            if epoch < from || epoch > to {
+                continue;
+            }
+            let entry = cells.entry((geohash(location.lat, location.lng, precision), epoch)).or_insert_with(Default::default);
+            entry.0.insert(user);
+            if location.testResult {
+                entry.1.insert(user);
+            }
+        }
+    }
+    cells.into_iter()
+        .filter(|(_, (users, _))| users.len() as u64 >= k)
+        .map(|((cell, epoch), (users, infected))| {
+            let infected = infected.len() as u64;
+            Row { cell, epoch, users: users.len() as u64, infected: if infected == 0 || infected >= k { Some(infected) } else { None } }
+        })
+        .collect()
+}
+
+fn csv(rows: &[Row]) -> String {
+    let mut body = "cell,epoch,users,infected\n".to_string();
+    for row in rows {
+        let infected = row.infected.map(|count| count.to_string()).unwrap_or_default();
+        body.push_str(&format!("{},{},{},{}\n", row.cell, row.epoch, row.users, infected));
+    }
+    body
+}
+
+pub fn export_statistics_internal(request: &[u8]) -> Result<Vec<u8>, EnclaveError> {
+    let k = K.load(Ordering::SeqCst);
+    if k == 0 {
+        return Err(invalid("the exports are disabled"));
+    }
+    let request: ExportRequest = serde_json::from_slice(request).map_err(|_| invalid("malformed export request"))?;
+    let message = request_message(request.issued_at, &request.format, request.precision, request.from, request.to);
+    let authority = authority::authorized("export request", &message, &request.signature)?;
+    let now = time_t::now()?;
+    if request.issued_at.saturating_add(MAX_REQUEST_SKEW) < now || request.issued_at > now.saturating_add(MAX_REQUEST_SKEW) {
+        return Err(invalid("the export request expired"));
+    }
+    if request.precision == 0 || request.precision > 12 {
+        return Err(invalid("precision must be between 1 and 12"));
+    }
+    if request.from > request.to || request.to - request.from >= MAX_DAYS {
+        return Err(invalid(&format!("an export covers from 1 to {} days", MAX_DAYS)));
+    }
+
+    let data = data::unseal_data_wrapper()?;
+    let rows = rows(&data, &tenants::current()?, request.precision as usize, request.from, request.to, k);
+    let body = match request.format.as_str() {
+        "csv" => csv(&rows),
+        "json" => serde_json::to_string(&rows).map_err(|_| data::Error::SerializeError)?,
+        _ => return Err(invalid("format must be csv or json")),
+    };
+    let mut bundle = Bundle {
+        authority: to_hex(&authority.address()),
+        issued_at: request.issued_at,
+        format: request.format,
+        precision: request.precision,
+        from: request.from,
+        to: request.to,
+        k,
+        generated_at: now,
+        rows: rows.len(),
+        body,
+        signature: String::new(),
+    };
+    bundle.signature = to_hex(&identity::sign(&bundle_message(&bundle))?);
+    Ok(serde_json::to_vec(&bundle).map_err(|_| data::Error::SerializeError)?)
+}
@@ -0,0 +1,103 @@
+use crate::channel::get_channel_key;
+use crate::padding::{pad, PaddingClass};
+use crate::matching;
+use crate::tenants;
+use crate::users;
+use crate::data::{decrypt_userid, find_matches, unseal_store, Error, GeolocationTime};
+use crate::summaries;
+use enigma_tools_t::common::errors_t::{EnclaveError, FailedTaskError::InputError};
+use enigma_tools_m::utils::LockExpectMutex;
+use enigma_crypto::{symmetric::decrypt, symmetric::encrypt, CryptoError};
+use enigma_types::{DhKey, PubKey};
+use std::collections::HashMap;
+use std::{str, string::ToString, sync::SgxMutex, vec::Vec};
+
+// Federated matching.
+// The querying node sends the user's locations to every peer, encrypted under the channel session key.
+// Each peer matches them against its own infected set and answers under the same key, and the
+// querying node combines all the answers with its local matches before encrypting them for the user.
+// Between `begin` and `end` the user's DH key and the local matches are kept here.
+struct PendingQuery {
+    io_key: DhKey,
+    results: Vec<GeolocationTime>,
+}
+
+lazy_static! { static ref PENDING_QUERIES: SgxMutex<HashMap<Vec<u8>, PendingQuery>> = SgxMutex::new(HashMap::new()); }
+
+// Returns one encrypted query per peer, in the same order as `peers`
+pub(crate) fn federated_begin_internal(
+    encrypted_userid: &[u8],
+    encrypted_signature: &[u8],
+    user_pubkey: &PubKey,
+    io_key: &DhKey,
+    peers: &[[u8; 20]]) -> Result<Vec<u8>, EnclaveError> {
+
+    let decrypted_userid = decrypt_userid(encrypted_userid, io_key)?;
+    let userid = str::from_utf8(&decrypted_userid)
+        .map_err(|_| EnclaveError::FailedTaskError(InputError { message: "encryptedUserId is not valid UTF-8".to_string() }))?;
+    let tenant = tenants::current()?;
+    let key = tenant.scope(userid)?;
+    // Signed like `findMatch`, the peers learn the user's locations
+    users::authenticate(&key, encrypted_signature, &[encrypted_userid, &user_pubkey[..]], io_key)?;
+
+    let (data, generation) = unseal_store()?;
+    let user_locations = data.get(&key).cloned().unwrap_or_default();
+    // The peers match with their own strategy, so does the local part
+    let results = find_matches(&user_locations, &data, &summaries::index(&data, generation), &tenant, &matching::resolve(&[])?, Some(&key))?;
+
+    let query = pad(PaddingClass::Federation, serde_json::to_vec(&user_locations).map_err(|_| Error::SerializeError)?);
+    let mut queries = Vec::with_capacity(peers.len());
+    for peer in peers {
+        queries.push(encrypt(&query, &get_channel_key(peer)?)?);
+    }
+
+    PENDING_QUERIES.lock_expect("Federated Queries").insert(user_pubkey.to_vec(), PendingQuery { io_key: *io_key, results });
+    Ok(serde_json::to_vec(&queries).map_err(|_| Error::SerializeError)?)
+}
+
+// Runs on the peer: matches the querying node's locations against the local dataset, the partition of
+// the tenant the querying node asks for
+pub(crate) fn federated_answer_internal(peer: &[u8; 20], encrypted_query: &[u8]) -> Result<Vec<u8>, EnclaveError> {
+    let key = get_channel_key(peer)?;
+    let locations: Vec<GeolocationTime> = serde_json::from_slice(&decrypt(encrypted_query, &key)?).map_err(|_| Error::SerializeError)?;
+
+    let (data, generation) = unseal_store()?;
+    let results = find_matches(&locations, &data, &summaries::index(&data, generation), &tenants::current()?, &matching::resolve(&[])?, None)?;
+
+    let serialized_results = serde_json::to_vec(&results).map_err(|_| Error::SerializeError)?;
+    Ok(encrypt(&pad(PaddingClass::Federation, serialized_results), &key)?)
+}
+
+// `answers` is a JSON list with one encrypted answer per peer, empty if the peer couldn't be reached
+pub(crate) fn federated_end_internal(user_pubkey: &PubKey, peers: &[[u8; 20]], answers: &[u8]) -> Result<Vec<u8>, EnclaveError> {
+    let pending = PENDING_QUERIES
+        .lock_expect("Federated Queries")
+        .remove(&user_pubkey[..])
+        .ok_or(CryptoError::MissingKeyError { key_type: "Federated Query" })?;
+    let answers: Vec<Vec<u8>> = serde_json::from_slice(answers).map_err(|_| Error::SerializeError)?;
+
+    let mut results = pending.results;
+    for (peer, answer) in peers.iter().zip(answers.iter()) {
+        if answer.is_empty() {
+            continue;
+        }
+        let mut remote: Vec<GeolocationTime> = serde_json::from_slice(&decrypt(answer, &get_channel_key(peer)?)?)
+            .map_err(|_| Error::SerializeError)?;
+        results.append(&mut remote);
+    }
+
+    let serialized_results = serde_json::to_vec(&results).map_err(|_| Error::SerializeError)?;
+    Ok(encrypt(&pad(PaddingClass::Matching, serialized_results), &pending.io_key)?)
+}
+
+// Peers are passed through the ecalls as a flat array of 20 bytes addresses
+pub(crate) fn parse_peers(peers: &[u8]) -> Result<Vec<[u8; 20]>, EnclaveError> {
+    if peers.len() % 20 != 0 {
+        return Err(EnclaveError::FailedTaskError(InputError { message: "peer addresses must be 20 bytes long".to_string() }));
+    }
+    Ok(peers.chunks_exact(20).map(|chunk| {
+        let mut address = [0u8; 20];
+        address.copy_from_slice(chunk);
+        address
+    }).collect())
+}
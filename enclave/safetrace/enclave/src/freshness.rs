@@ -0,0 +1,214 @@
+use crate::data::{read_sealed_file, recover_sealeddata, save_sealed_data, seal_to_vec, Error};
+use crate::records::Freshness;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use enigma_tools_m::utils::LockExpectMutex;
+use enigma_tools_t::common::errors_t::{EnclaveError, FailedTaskError::InputError};
+use sgx_types::{sgx_close_pse_session, sgx_create_monotonic_counter, sgx_create_pse_session, sgx_increment_monotonic_counter,
+                sgx_mc_uuid_t, sgx_read_monotonic_counter, sgx_status_t};
+use std::{string::ToString, sync::SgxMutex};
+
+// Rollback protection of the sealed store. The host can't forge a sealed store, but it can hand back an older
+// one it kept, with users that asked to be deleted or without the locations of the last days. Every seal
+// records its `generation` in the store (`records`), and the enclave refuses a store older than the newest it
+// unsealed or sealed: swapping files under a running enclave is caught.
+//
+// The enclave forgets the generation when it stops. With `enclave.monotonic_counter` the store is also bound
+// to an SGX monotonic counter of the platform services, which survives restarts: the store records the value
+// the counter gets once it's written, a store whose value is below the counter's is an old one. The store is
+// written before the counter goes up, a store one above the counter is the last write, whose increment didn't
+// happen, and the increment is done then.
+//
+// The uuid of the counter is also sealed in an anchor file of its own, written before the first file bound to
+// it: after a restart, a store that went missing or carries no counter (sealed before it, or by an enclave
+// without it) is refused once the anchor names one, as is a store of another counter. The store and its anchor
+// deleted together still look like a first start.
+//
+// The user registrations (`users`) and the operators (`quorum`) are sealed apart from the store and protected the
+// same way, with a floor and a counter of their own: `STORE`, `USERS` and `OPERATORS` track each file.
+//
+// The policy is set once: the host can't turn the counter off once the enclave started with it.
+
+static MONOTONIC: AtomicBool = AtomicBool::new(false);
+static SET: AtomicBool = AtomicBool::new(false);
+
+// The newest generation of a sealed file, and its counter once one is created, read or anchored
+pub struct Tracker {
+    floor: AtomicU64,
+    counter: SgxMutex<Option<[u8; 16]>>,
+    // The sealed file holding the uuid of the counter
+    anchor: &'static str,
+}
+
+lazy_static! {
+    pub static ref STORE: Tracker = Tracker::new("data.counter.sealed");
+    pub static ref USERS: Tracker = Tracker::new("users.counter.sealed");
+    pub static ref OPERATORS: Tracker = Tracker::new("operators.counter.sealed");
+}
+
+pub fn set(monotonic: bool) -> Result<(), EnclaveError> {
+    if SET.swap(true, Ordering::SeqCst) {
+        return Err(EnclaveError::FailedTaskError(InputError { message: "the rollback policy is already set".to_string() }));
+    }
+    MONOTONIC.store(monotonic, Ordering::SeqCst);
+    Ok(())
+}
+
+fn to_uuid(bytes: &[u8; 16]) -> sgx_mc_uuid_t {
+    let mut uuid = sgx_mc_uuid_t { counter_id: [0u8; 3], nonce: [0u8; 13] };
+    uuid.counter_id.copy_from_slice(&bytes[..3]);
+    uuid.nonce.copy_from_slice(&bytes[3..]);
+    uuid
+}
+
+fn from_uuid(uuid: &sgx_mc_uuid_t) -> [u8; 16] {
+    let mut bytes = [0u8; 16];
+    bytes[..3].copy_from_slice(&uuid.counter_id);
+    bytes[3..].copy_from_slice(&uuid.nonce);
+    bytes
+}
+
+// Runs `op` in a session with the platform services, which counters need.
+fn with_pse<T>(op: impl FnOnce() -> Result<T, Error>) -> Result<T, Error> {
+    let status = unsafe { sgx_create_pse_session() };
+    if status != sgx_status_t::SGX_SUCCESS {
+        return Err(Error::CounterError(status));
+    }
+    let result = op();
+    unsafe { sgx_close_pse_session() };
+    result
+}
+
+fn check_status(status: sgx_status_t) -> Result<(), Error> {
+    if status == sgx_status_t::SGX_SUCCESS { Ok(()) } else { Err(Error::CounterError(status)) }
+}
+
+fn read_counter(uuid: &[u8; 16]) -> Result<u32, Error> {
+    let mut value = 0u32;
+    check_status(unsafe { sgx_read_monotonic_counter(&to_uuid(uuid), &mut value) })?;
+    Ok(value)
+}
+
+fn increment_counter(uuid: &[u8; 16]) -> Result<u32, Error> {
+    let mut value = 0u32;
+    check_status(unsafe { sgx_increment_monotonic_counter(&to_uuid(uuid), &mut value) })?;
+    Ok(value)
+}
+
+impl Tracker {
+    fn new(anchor: &'static str) -> Self {
+        Tracker { floor: AtomicU64::new(0), counter: SgxMutex::new(None), anchor }
+    }
+
+    // The uuid of the anchor file, none before a counter was created.
+    fn anchored(&self) -> Result<Option<[u8; 16]>, Error> {
+        let mut sealed = match read_sealed_file(self.anchor)? {
+            Some(sealed) => sealed,
+            None => return Ok(None),
+        };
+        let bytes = recover_sealeddata(sealed.as_mut_ptr(), sealed.len() as u32)?;
+        if bytes.len() != 16 {
+            return Err(Error::SliceError);
+        }
+        let mut uuid = [0u8; 16];
+        uuid.copy_from_slice(&bytes);
+        Ok(Some(uuid))
+    }
+
+    fn anchor(&self, uuid: &[u8; 16]) -> Result<(), Error> {
+        save_sealed_data(self.anchor, &seal_to_vec(uuid)?);
+        Ok(())
+    }
+
+    fn raise_floor(&self, generation: u64) {
+        let mut floor = self.floor.load(Ordering::SeqCst);
+        while generation > floor {
+            match self.floor.compare_exchange(floor, generation, Ordering::SeqCst, Ordering::SeqCst) {
+                Ok(_) => return,
+                Err(current) => floor = current,
+            }
+        }
+    }
+
+    // Checks a file just unsealed: refused if it's older than the last one known.
+    pub fn check(&self, freshness: &Freshness) -> Result<(), Error> {
+        let floor = self.floor.load(Ordering::SeqCst);
+        if freshness.generation < floor {
+            return Err(Error::RolledBack { generation: freshness.generation, floor });
+        }
+        if MONOTONIC.load(Ordering::SeqCst) {
+            let mut counter = self.counter.lock_expect("Sealed File Counter");
+            let anchored = match *counter {
+                Some(uuid) => Some(uuid),
+                None => self.anchored()?,
+            };
+            match (anchored, freshness.counter) {
+                // Missing, or sealed without the counter since created
+                (Some(uuid), None) => {
+                    let counter = with_pse(|| read_counter(&uuid))?;
+                    return Err(Error::RolledBack { generation: freshness.generation, floor: u64::from(counter) });
+                },
+                (Some(uuid), Some((sealed, _))) if uuid != sealed => return Err(Error::CounterError(sgx_status_t::SGX_ERROR_UNEXPECTED)),
+                // A counter of before the anchors
+                (None, Some((uuid, _))) => self.anchor(&uuid)?,
+                _ => {},
+            }
+            if let Some((uuid, value)) = freshness.counter {
+                with_pse(|| {
+                    let counter = read_counter(&uuid)?;
+                    if value < counter {
+                        return Err(Error::RolledBack { generation: u64::from(value), floor: u64::from(counter) });
+                    }
+                    if value > counter + 1 {
+                        // A counter value nobody sealed yet, the file isn't of this counter
+                        return Err(Error::CounterError(sgx_status_t::SGX_ERROR_UNEXPECTED));
+                    }
+                    if value == counter + 1 {
+                        increment_counter(&uuid)?;
+                    }
+                    Ok(())
+                })?;
+                *counter = Some(uuid);
+            }
+        }
+        self.raise_floor(freshness.generation);
+        Ok(())
+    }
+
+    // The freshness of the next seal, the counter is created with the first one.
+    pub fn next(&self) -> Result<Freshness, Error> {
+        let generation = self.floor.load(Ordering::SeqCst) + 1;
+        if !MONOTONIC.load(Ordering::SeqCst) {
+            return Ok(Freshness { generation, counter: None });
+        }
+        let mut counter = self.counter.lock_expect("Sealed File Counter");
+        let uuid = match *counter {
+            Some(uuid) => uuid,
+            None => {
+                let (mut uuid, mut value) = (sgx_mc_uuid_t { counter_id: [0u8; 3], nonce: [0u8; 13] }, 0u32);
+                with_pse(|| check_status(unsafe { sgx_create_monotonic_counter(&mut uuid, &mut value) }))?;
+                // Anchored before any file is bound to it
+                self.anchor(&from_uuid(&uuid))?;
+                *counter = Some(from_uuid(&uuid));
+                from_uuid(&uuid)
+            },
+        };
+        let value = with_pse(|| read_counter(&uuid))?;
+        Ok(Freshness { generation, counter: Some((uuid, value + 1)) })
+    }
+
+    // Once the file of `freshness` is written.
+    pub fn sealed(&self, freshness: &Freshness) -> Result<(), Error> {
+        self.raise_floor(freshness.generation);
+        if let Some((uuid, _)) = freshness.counter {
+            with_pse(|| increment_counter(&uuid))?;
+        }
+        Ok(())
+    }
+}
+
+// Of the store
+pub fn check(freshness: &Freshness) -> Result<(), Error> { STORE.check(freshness) }
+
+pub fn next() -> Result<Freshness, Error> { STORE.next() }
+
+pub fn sealed(freshness: &Freshness) -> Result<(), Error> { STORE.sealed(freshness) }
@@ -0,0 +1,217 @@
+use crate::data::{read_sealed_file, recover_sealeddata, save_sealed_data, seal_to_vec};
+use crate::SIGNING_KEY;
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use enigma_crypto::asymmetric::KeyPair;
+use enigma_tools_m::utils::{EthereumAddress, LockExpectMutex};
+use enigma_tools_t::common::errors_t::{EnclaveError, EnclaveSystemError::OcallError, FailedTaskError::InputError};
+use sgx_tcrypto::{rsgx_sha256_slice, SgxEccHandle};
+use sgx_types::*;
+use std::{string::ToString, sync::SgxMutex, vec::Vec};
+
+extern "C" {
+    fn ocall_recover_p256(retval: *mut u8, hash: *const u8, signature: *const u8, pubkey: *mut u8) -> sgx_status_t;
+}
+
+// The signing identity of the enclave, on the curve the host picks before anything is signed
+// (`ecall_set_signing_curve`): secp256k1, the key of `SIGNING_KEY`, or NIST P-256, a key of the SGX crypto
+// library sealed in its own file. Either way the address is the keccak256 of the public key, the one the
+// quotes carry, and a signature is r || s || v (see `signatures` in the client). The SDK signs P-256 without
+// v: the host recovers the key of each v (`ocall_recover_p256`) and the enclave keeps the v that gives its
+// own, so a host answering wrong only spoils the signature.
+
+pub const SECP256K1: u8 = 0;
+pub const P256: u8 = 1;
+
+const P256_KEYPAIR_FILE: &str = "keypair_p256.sealed";
+// The order of P-256, big endian
+const P256_ORDER: [u8; 32] = [
+    0xff, 0xff, 0xff, 0xff, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    0xbc, 0xe6, 0xfa, 0xad, 0xa7, 0x17, 0x9e, 0x84, 0xf3, 0xb9, 0xca, 0xc2, 0xfc, 0x63, 0x25, 0x51,
+];
+
+static CURVE: AtomicU8 = AtomicU8::new(SECP256K1);
+// Whether the identity was used, its curve can't change after
+static USED: AtomicBool = AtomicBool::new(false);
+
+struct P256Key {
+    private: sgx_ec256_private_t,
+    public: sgx_ec256_public_t,
+}
+
+lazy_static! {
+    // None until read from the sealed file
+    static ref P256_KEY: SgxMutex<Option<P256Key>> = SgxMutex::new(None);
+}
+
+fn invalid(message: &str) -> EnclaveError {
+    EnclaveError::FailedTaskError(InputError { message: message.to_string() })
+}
+
+pub fn set(curve: u8) -> Result<(), EnclaveError> {
+    if curve != SECP256K1 && curve != P256 {
+        return Err(invalid("unknown signing curve"));
+    }
+    if USED.load(Ordering::SeqCst) && CURVE.load(Ordering::SeqCst) != curve {
+        return Err(invalid("the signing identity is already in use on another curve"));
+    }
+    CURVE.store(curve, Ordering::SeqCst);
+    Ok(())
+}
+
+pub fn curve() -> u8 { CURVE.load(Ordering::SeqCst) }
+
+// As the settings name it
+pub fn name() -> &'static str {
+    if curve() == P256 { "p256" } else { "secp256k1" }
+}
+
+// The SDK keeps its numbers little endian, u32 words for the signatures.
+fn reversed(bytes: &[u8]) -> [u8; 32] {
+    let mut out = [0u8; 32];
+    out.copy_from_slice(bytes);
+    out.reverse();
+    out
+}
+
+fn from_words(words: &[u32; 8]) -> [u8; 32] {
+    let mut bytes = [0u8; 32];
+    for (i, word) in words.iter().enumerate() {
+        bytes[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    bytes.reverse();
+    bytes
+}
+
+fn to_words(big_endian: &[u8]) -> [u32; 8] {
+    let bytes = reversed(big_endian);
+    let mut words = [0u32; 8];
+    for (i, word) in words.iter_mut().enumerate() {
+        let mut le = [0u8; 4];
+        le.copy_from_slice(&bytes[i * 4..i * 4 + 4]);
+        *word = u32::from_le_bytes(le);
+    }
+    words
+}
+
+fn public_bytes(public: &sgx_ec256_public_t) -> [u8; 64] {
+    let mut bytes = [0u8; 64];
+    bytes[..32].copy_from_slice(&reversed(&public.gx));
+    bytes[32..].copy_from_slice(&reversed(&public.gy));
+    bytes
+}
+
+// s or n - s, whichever is lower, as the verifiers of Ethereum and of the precompiles expect
+fn low_s(s: [u8; 32]) -> [u8; 32] {
+    let mut negated = [0u8; 32];
+    let mut borrow = 0i16;
+    for i in (0..32).rev() {
+        let digit = i16::from(P256_ORDER[i]) - i16::from(s[i]) - borrow;
+        borrow = if digit < 0 { 1 } else { 0 };
+        negated[i] = (digit + 256 * borrow) as u8;
+    }
+    if s > negated { negated } else { s }
+}
+
+// The sealed key is x || y || the private key, as the SDK has them. Generated the first time.
+fn load_p256() -> Result<P256Key, EnclaveError> {
+    if let Some(mut sealed_log) = read_sealed_file(P256_KEYPAIR_FILE)? {
+        let bytes = recover_sealeddata(sealed_log.as_mut_ptr(), sealed_log.len() as u32)?;
+        if bytes.len() != 96 {
+            return Err(invalid("the sealed P-256 key is malformed"));
+        }
+        let mut key = P256Key { private: sgx_ec256_private_t::default(), public: sgx_ec256_public_t::default() };
+        key.public.gx.copy_from_slice(&bytes[..32]);
+        key.public.gy.copy_from_slice(&bytes[32..64]);
+        key.private.r.copy_from_slice(&bytes[64..]);
+        return Ok(key);
+    }
+    let handle = SgxEccHandle::new();
+    handle.open()?;
+    let (private, public) = handle.create_key_pair()?;
+    let _ = handle.close();
+    let mut bytes = Vec::with_capacity(96);
+    bytes.extend_from_slice(&public.gx);
+    bytes.extend_from_slice(&public.gy);
+    bytes.extend_from_slice(&private.r);
+    let sealed_log = seal_to_vec(&bytes).map_err(|_| invalid("sealing the P-256 key failed"))?;
+    save_sealed_data(P256_KEYPAIR_FILE, &sealed_log);
+    Ok(P256Key { private, public })
+}
+
+fn with_p256<T, F: FnOnce(&P256Key) -> Result<T, EnclaveError>>(f: F) -> Result<T, EnclaveError> {
+    let mut key = P256_KEY.lock_expect("P-256 Key");
+    if key.is_none() {
+        *key = Some(load_p256()?);
+    }
+    f(key.as_ref().expect("P-256 key loaded"))
+}
+
+// The key the host recovers from a P-256 signature, None when there's none.
+fn recover_p256(hash: &[u8; 32], signature: &[u8; 65]) -> Result<Option<[u8; 64]>, EnclaveError> {
+    let mut recovered = 0u8;
+    let mut pubkey = [0u8; 64];
+    let status = unsafe { ocall_recover_p256(&mut recovered as *mut u8, hash.as_ptr(), signature.as_ptr(), pubkey.as_mut_ptr()) };
+    if status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveError::SystemError(OcallError { command: "ocall_recover_p256".to_string(), err: status.as_str().to_string() }));
+    }
+    Ok(if recovered != 0 { Some(pubkey) } else { None })
+}
+
+// x || y of the signing key.
+pub fn public_key() -> Result<[u8; 64], EnclaveError> {
+    USED.store(true, Ordering::SeqCst);
+    if curve() != P256 {
+        return Ok(SIGNING_KEY.get_pubkey());
+    }
+    with_p256(|key| Ok(public_bytes(&key.public)))
+}
+
+pub fn address() -> Result<[u8; 20], EnclaveError> {
+    Ok(public_key()?.address())
+}
+
+pub fn sign(message: &[u8]) -> Result<[u8; 65], EnclaveError> {
+    USED.store(true, Ordering::SeqCst);
+    if curve() != P256 {
+        return Ok(SIGNING_KEY.sign(message)?);
+    }
+    with_p256(|key| {
+        let handle = SgxEccHandle::new();
+        handle.open()?;
+        let signed = handle.ecdsa_sign_slice(message, &key.private);
+        let _ = handle.close();
+        let signed = signed?;
+        let mut signature = [0u8; 65];
+        signature[..32].copy_from_slice(&from_words(&signed.x));
+        signature[32..64].copy_from_slice(&low_s(from_words(&signed.y)));
+        let hash = rsgx_sha256_slice(message)?;
+        let own = public_bytes(&key.public);
+        for v in &[27u8, 28] {
+            signature[64] = *v;
+            if recover_p256(&hash, &signature)? == Some(own) {
+                return Ok(signature);
+            }
+        }
+        Err(invalid("no v recovers the P-256 signing key"))
+    })
+}
+
+// Whether `signature` over `message` is the one of the key at `address`, on either curve: a peer may sign on
+// another curve than this enclave. The host recovers P-256 keys, the enclave checks the signature itself.
+pub fn signed_by(message: &[u8], signature: &[u8; 65], address: &[u8; 20]) -> Result<bool, EnclaveError> {
+    if KeyPair::recover(message, *signature).map(|signer| signer.address() == *address).unwrap_or(false) {
+        return Ok(true);
+    }
+    let hash = rsgx_sha256_slice(message)?;
+    let pubkey = match recover_p256(&hash, signature)? {
+        Some(pubkey) if pubkey.address() == *address => pubkey,
+        _ => return Ok(false),
+    };
+    let public = sgx_ec256_public_t { gx: reversed(&pubkey[..32]), gy: reversed(&pubkey[32..]) };
+    let signed = sgx_ec256_signature_t { x: to_words(&signature[..32]), y: to_words(&signature[32..64]) };
+    let handle = SgxEccHandle::new();
+    handle.open()?;
+    let valid = handle.ecdsa_verify_slice(message, &public, &signed);
+    let _ = handle.close();
+    Ok(valid?)
+}
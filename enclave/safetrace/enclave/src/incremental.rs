@@ -0,0 +1,214 @@
+use crate::data::{unseal_store, Error, GeolocationTime};
+use crate::matching::Strategy;
+use crate::summaries::{self, Summaries};
+use crate::tenants::{self, Tenant};
+use crate::cancel;
+use enigma_tools_m::utils::LockExpectMutex;
+use enigma_tools_t::common::errors_t::EnclaveError;
+use serde::Serialize;
+use sgx_tcrypto::rsgx_sha256_slice;
+use std::collections::HashMap;
+use std::{string::String, sync::SgxMutex, vec::Vec};
+
+// Incremental matching. The matches of a query are kept per stored user, with the generation of the store the
+// query ran at: its watermark. The next query of the same user, with the same strategy and the same locations,
+// only compares the user with the stored users whose infected locations changed since (see `summaries`), and
+// takes the matches of the others from the previous query. A stored user with infected locations the retention
+// now skips is compared again too, the previous matches may have counted them.
+//
+// The results are the same as a full query. An `incremental` query answers
+//   {"matches": [...], "prior": {"matches": 12, "from": 1589000000, "to": 1589090000}, "watermark": 42}
+// `matches` being the ones the previous query didn't return and `prior` summing up those it did.
+//
+// The matches are kept in the enclave memory only, for up to `MAX_USERS` users: the one queried longest ago
+// makes room. A restart or a new generation of the enclave starts over with full queries.
+//
+// `precompute` brings the kept matches up to the store when infected locations arrive, in the background, so the
+// next query only looks them up. The matches it adds wait for the user's next query, which returns them as new:
+// they're queued in the enclave, the host is only told how many users it went through.
+
+const MAX_USERS: usize = 4096;
+
+struct Previous {
+    watermark: u64,
+    strategy: Strategy,
+    // SHA-256 of the user's own locations
+    locations: [u8; 32],
+    tenant: Tenant,
+    // The matches per stored user, only those with any
+    matches: HashMap<String, Vec<GeolocationTime>>,
+    // The matches the last query returned, when `precompute` changed them since
+    notified: Option<HashMap<String, Vec<GeolocationTime>>>,
+}
+
+// What a step of `precompute` did.
+#[derive(Debug, Default)]
+pub struct Precomputed {
+    pub refreshed: u64,
+    // Users still behind the store
+    pub remaining: u64,
+}
+
+// The matches the previous query returned, summed up.
+#[derive(Serialize, Debug, Default)]
+pub struct Prior {
+    pub matches: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to: Option<i32>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Incremental {
+    pub matches: Vec<GeolocationTime>,
+    pub prior: Prior,
+    pub watermark: u64,
+}
+
+lazy_static! { static ref PREVIOUS: SgxMutex<HashMap<String, Previous>> = SgxMutex::new(HashMap::new()); }
+
+fn digest(locations: &[GeolocationTime]) -> Result<[u8; 32], EnclaveError> {
+    let encoded = serde_json::to_vec(locations).map_err(|_| Error::SerializeError)?;
+    Ok(rsgx_sha256_slice(&encoded).map_err(|_| Error::Other)?)
+}
+
+fn keep(key: String, previous: Previous) {
+    let mut all = PREVIOUS.lock_expect("Previous Matches");
+    if all.len() >= MAX_USERS && !all.contains_key(&key) {
+        let oldest = all.iter().min_by_key(|(_, previous)| previous.watermark).map(|(key, _)| key.clone());
+        if let Some(oldest) = oldest {
+            all.remove(&oldest);
+        }
+    }
+    all.insert(key, previous);
+}
+
+fn same(a: &GeolocationTime, b: &GeolocationTime) -> bool {
+    a.startTS == b.startTS && a.endTS == b.endTS && a.lat == b.lat && a.lng == b.lng
+}
+
+// Splits the matches `found` with a stored user into those `before` had too and the new ones.
+fn split(found: &[GeolocationTime], before: &[GeolocationTime], prior: &mut Vec<GeolocationTime>, new: &mut Vec<GeolocationTime>) {
+    let mut before: Vec<&GeolocationTime> = before.iter().collect();
+    for l in found {
+        match before.iter().position(|b| same(b, l)) {
+            Some(i) => {
+                before.swap_remove(i);
+                prior.push(l.clone());
+            },
+            None => new.push(l.clone()),
+        }
+    }
+}
+
+// The matches of `user_locations`, the locations of the stored user `key`, per stored user as `data::find_matches`
+// finds them, taking those of the stored users that didn't change since `previous` was computed from it.
+fn refresh(
+    key: &str,
+    user_locations: &[GeolocationTime],
+    data: &HashMap<String, Vec<GeolocationTime>>,
+    summaries: &Summaries,
+    tenant: &Tenant,
+    strategy: &Strategy,
+    previous: Option<&Previous>) -> Result<HashMap<String, Vec<GeolocationTime>>, EnclaveError> {
+
+    let mut matches: HashMap<String, Vec<GeolocationTime>> = HashMap::new();
+    for (i, (stored, val)) in data.iter().enumerate() {
+        if i % cancel::CHECK_EVERY == 0 {
+            cancel::check()?;
+        }
+        if !tenant.owns(stored) || stored == key || !summaries.may_match(stored, user_locations, strategy) {
+            continue;
+        }
+        let cutoff = tenants::retention_cutoff(stored)?;
+        let unchanged = previous.map_or(false, |previous| {
+            summaries.changed(stored).map_or(false, |changed| changed <= previous.watermark) && !summaries.expiring(stored, cutoff)
+        });
+        let found = if unchanged {
+            previous.and_then(|previous| previous.matches.get(stored)).cloned().unwrap_or_default()
+        } else {
+            let mut found = Vec::new();
+            strategy.match_locations(user_locations, val, cutoff, &mut found);
+            found
+        };
+        if !found.is_empty() {
+            matches.insert(stored.clone(), found);
+        }
+    }
+    Ok(matches)
+}
+
+// The matches of `user_locations`, the locations of the stored user `key`, as `data::find_matches` finds them,
+// comparing only the stored users that changed since the previous query of `key` or its last `precompute`.
+// Returns those the previous query returned too, and the new ones.
+pub fn find_matches(
+    key: &str,
+    user_locations: &[GeolocationTime],
+    data: &HashMap<String, Vec<GeolocationTime>>,
+    summaries: &Summaries,
+    tenant: &Tenant,
+    strategy: &Strategy,
+    remember: bool) -> Result<(Vec<GeolocationTime>, Vec<GeolocationTime>), EnclaveError> {
+
+    let locations = digest(user_locations)?;
+    let previous = PREVIOUS.lock_expect("Previous Matches").remove(key)
+        .filter(|previous| previous.strategy == *strategy && previous.locations == locations && previous.tenant == *tenant);
+    let matches = refresh(key, user_locations, data, summaries, tenant, strategy, previous.as_ref())?;
+    let notified = previous.as_ref().map(|previous| previous.notified.as_ref().unwrap_or(&previous.matches));
+    let (mut prior, mut new) = (Vec::new(), Vec::new());
+    for (stored, found) in &matches {
+        let before = notified.and_then(|notified| notified.get(stored));
+        split(found, before.map_or(&[][..], |before| &before[..]), &mut prior, &mut new);
+    }
+    // A decoy's matches aren't kept, they'd make a real user's room
+    if remember {
+        keep(key.to_string(), Previous {
+            watermark: summaries.generation(), strategy: strategy.clone(), locations, tenant: tenant.clone(), matches, notified: None,
+        });
+    }
+    Ok((prior, new))
+}
+
+// Brings the matches of up to `budget` users behind the store up to it, for the background thread of the host.
+pub fn precompute(budget: u64) -> Result<Precomputed, EnclaveError> {
+    let (data, generation) = unseal_store()?;
+    let summaries = summaries::index(&data, generation);
+    let behind: Vec<String> = PREVIOUS.lock_expect("Previous Matches").iter()
+        .filter(|(_, previous)| previous.watermark < generation)
+        .map(|(key, _)| key.clone())
+        .collect();
+    let mut done = Precomputed::default();
+    for key in &behind {
+        if done.refreshed >= budget {
+            done.remaining += 1;
+            continue;
+        }
+        done.refreshed += 1;
+        // Unless a query took it meanwhile
+        let previous = match PREVIOUS.lock_expect("Previous Matches").remove(key) {
+            Some(previous) => previous,
+            None => continue,
+        };
+        // The user's own locations changed, their next query runs in full
+        let user_locations = data.get(key).cloned().unwrap_or_default();
+        if digest(&user_locations)? != previous.locations {
+            continue;
+        }
+        let matches = refresh(key, &user_locations, &data, &summaries, &previous.tenant, &previous.strategy, Some(&previous))?;
+        let Previous { strategy, locations, tenant, matches: before, notified, .. } = previous;
+        let notified = Some(notified.unwrap_or(before));
+        keep(key.clone(), Previous { watermark: generation, strategy, locations, tenant, matches, notified });
+    }
+    Ok(done)
+}
+
+// The answer of an `incremental` query.
+pub fn answer(prior: &[GeolocationTime], new: Vec<GeolocationTime>, watermark: u64) -> Incremental {
+    let summary = Prior {
+        matches: prior.len(),
+        from: prior.iter().map(|l| l.startTS).min(),
+        to: prior.iter().map(|l| l.endTS).max(),
+    };
+    Incremental { matches: new, prior: summary, watermark }
+}
@@ -0,0 +1,142 @@
+use crate::data::{decrypt_userid, unseal_data_wrapper, Error, GeolocationTime};
+use crate::decoy;
+use crate::matching::{self, Strategy};
+use crate::memory;
+use crate::padding::{self, PaddingClass};
+use crate::tenants;
+use crate::users;
+use core::mem::size_of;
+use core::sync::atomic::{AtomicU64, Ordering};
+use enigma_crypto::symmetric::encrypt;
+use enigma_tools_m::utils::LockExpectMutex;
+use enigma_tools_t::common::errors_t::{EnclaveError, FailedTaskError::InputError};
+use enigma_types::{DhKey, PubKey};
+use std::{collections::HashMap, str, string::{String, ToString}, sync::SgxMutex, vec::Vec};
+
+// Match jobs: `FindMatch` in steps, so a large store doesn't hold the enclave, and the host's state lock,
+// for the whole computation. `start` authenticates the user like `find_match_internal` and takes a
+// snapshot of the store, each `step` compares the user with the next stored users, and `finish` encrypts
+// the results once every user was compared. The host learns how many users a job goes through, which
+// is the size of the store, the same for every job at a time, decoys included.
+
+// Jobs hold a copy of the store, this bounds the memory they take
+pub const MAX_JOBS: usize = 16;
+
+static NEXT_JOB: AtomicU64 = AtomicU64::new(1);
+
+lazy_static! {
+    static ref JOBS: SgxMutex<HashMap<u64, MatchJob>> = SgxMutex::new(HashMap::new());
+}
+
+struct MatchJob {
+    dh_key: DhKey,
+    // As stored, see `tenants`
+    key: String,
+    locations: Vec<GeolocationTime>,
+    // Resolved when the job starts, a later change of the deployment's doesn't affect it
+    strategy: Strategy,
+    snapshot: Vec<(String, Vec<GeolocationTime>)>,
+    next: usize,
+    results: Vec<GeolocationTime>,
+    // Bytes of the snapshot, as reserved in `memory`
+    reserved: u64,
+    decoy: bool,
+}
+
+// About what a snapshot takes on the heap, the maps of the store aside
+fn snapshot_size(snapshot: &[(String, Vec<GeolocationTime>)]) -> u64 {
+    snapshot.iter()
+        .map(|(userid, locations)| (userid.len() + locations.len() * size_of::<GeolocationTime>()) as u64)
+        .sum::<u64>()
+        + (snapshot.len() * size_of::<(String, Vec<GeolocationTime>)>()) as u64
+}
+
+fn invalid(message: &str) -> EnclaveError {
+    EnclaveError::FailedTaskError(InputError { message: message.to_string() })
+}
+
+// Returns the id of the job, the number of users it goes through and the strategy it runs.
+pub fn start(
+    encrypted_userid: &[u8],
+    encrypted_signature: &[u8],
+    strategy: &[u8],
+    user_pubkey: &PubKey,
+    dh_key: DhKey) -> Result<(u64, u64, Strategy), EnclaveError> {
+
+    let decrypted_userid = decrypt_userid(encrypted_userid, &dh_key)?;
+    let userid = str::from_utf8(&decrypted_userid).map_err(|_| invalid("encryptedUserId is not valid UTF-8"))?;
+    let strategy = matching::resolve(strategy)?;
+    let tenant = tenants::current()?;
+    let key = tenant.scope(userid)?;
+    // As in `find_match_internal`: a decoy goes through the same steps, its results are dropped in `finish`
+    let decoy = decoy::is_decoy(userid, &dh_key);
+    let authenticated = users::authenticate(&key, encrypted_signature, &[encrypted_userid, &user_pubkey[..]], &dh_key);
+    if !decoy {
+        authenticated?;
+    }
+
+    let mut jobs = JOBS.lock_expect("Match Jobs");
+    if jobs.len() >= MAX_JOBS {
+        return Err(invalid("too many match jobs are running"));
+    }
+    let data = unseal_data_wrapper()?;
+    if !decoy {
+        decoy::charge()?;
+    }
+    let locations = data.get(&key).cloned().unwrap_or_default();
+    // Only the partition of the tenant is matched
+    let snapshot: Vec<(String, Vec<GeolocationTime>)> = data.into_iter().filter(|(stored, _)| tenant.owns(stored)).collect();
+    let total = snapshot.len() as u64;
+    let reserved = snapshot_size(&snapshot);
+    memory::reserve(reserved)?;
+    let job = NEXT_JOB.fetch_add(1, Ordering::SeqCst);
+    jobs.insert(job, MatchJob { dh_key, key, locations, strategy: strategy.clone(), snapshot, next: 0, results: Vec::new(), reserved, decoy });
+    Ok((job, total, strategy))
+}
+
+// Compares the user with up to `budget` more stored users, returns how many were compared so far.
+pub fn step(job: u64, budget: u64) -> Result<u64, EnclaveError> {
+    if budget == 0 {
+        return Err(invalid("the step budget can't be 0"));
+    }
+    let mut jobs = JOBS.lock_expect("Match Jobs");
+    let job = jobs.get_mut(&job).ok_or_else(|| invalid("unknown match job"))?;
+    let end = job.snapshot.len().min(job.next.saturating_add(budget as usize));
+    for (key, locations) in &job.snapshot[job.next..end] {
+        // The user isn't matched against themselves
+        if *key != job.key {
+            job.strategy.match_locations(&job.locations, locations, tenants::retention_cutoff(key)?, &mut job.results);
+        }
+    }
+    job.next = end;
+    Ok(end as u64)
+}
+
+// The results of a job every user was compared with, encrypted for the user like `FindMatch`'s.
+pub fn finish(job: u64) -> Result<Vec<u8>, EnclaveError> {
+    let mut jobs = JOBS.lock_expect("Match Jobs");
+    match jobs.get(&job) {
+        None => return Err(invalid("unknown match job")),
+        Some(pending) if pending.next < pending.snapshot.len() => return Err(invalid("the match job isn't done")),
+        Some(_) => {},
+    }
+    let mut job = jobs.remove(&job).expect("the job is there");
+    memory::release(job.reserved);
+    if job.decoy {
+        job.results.clear();
+    }
+    let serialized_results = serde_json::to_vec(&job.results).map_err(|_| Error::SerializeError)?;
+    let padded_results = padding::pad(PaddingClass::Matching, serialized_results);
+    Ok(encrypt(&padded_results, &job.dh_key)?)
+}
+
+// Forgets a job, done or not.
+pub fn cancel(job: u64) {
+    if let Some(job) = JOBS.lock_expect("Match Jobs").remove(&job) {
+        memory::release(job.reserved);
+    }
+}
+
+pub fn count() -> u64 {
+    JOBS.lock_expect("Match Jobs").len() as u64
+}
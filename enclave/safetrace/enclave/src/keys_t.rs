@@ -1,4 +1,5 @@
-use crate::SIGNING_KEY;
+use crate::tenants;
+use crate::identity;
 use enigma_tools_t::common::errors_t::EnclaveError;
 use enigma_tools_m::utils::LockExpectMutex;
 use enigma_crypto::asymmetric::KeyPair;
@@ -12,9 +13,9 @@ lazy_static! { pub static ref DH_KEYS: SgxMutex<HashMap<Vec<u8>, DhKey>> = SgxMu
 pub(crate) unsafe fn get_user_key_internal(sig: &mut [u8; 65], user_pubkey: &PubKey) -> Result<Vec<u8>, EnclaveError> {
     let keys = KeyPair::new()?;
     let req = UserMessage::new(keys.get_pubkey());
-    *sig = SIGNING_KEY.sign(&req.to_sign())?;
+    *sig = identity::sign(&req.to_sign())?;
     let msg = req.into_message()?;
     let enc_key = keys.derive_key(&user_pubkey)?;
-    DH_KEYS.lock_expect("DH Keys").insert(user_pubkey.to_vec(), enc_key);
+    DH_KEYS.lock_expect("DH Keys").insert(tenants::current()?.key_id(&user_pubkey[..]), enc_key);
     Ok(msg)
 }
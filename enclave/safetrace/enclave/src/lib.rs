@@ -37,8 +37,12 @@ extern crate sgx_tstd as std;
 extern crate sgx_rand;
 // extern crate sgx_trts;
 extern crate sgx_tseal;
+extern crate sgx_tse;
+extern crate sgx_tcrypto;
+extern crate sgx_tdh;
 #[macro_use]
 extern crate lazy_static;
+extern crate miniz_oxide;
 
 extern crate serde;
 // #[macro_use]
@@ -48,7 +52,8 @@ extern crate serde_json;
 // #[macro_use]
 // extern crate sgx_serialize_derive;
 
-use std::{slice};
+use core::sync::atomic::{AtomicBool, Ordering};
+use std::{slice, vec::Vec};
 
 // extern crate serde;
 // extern crate secp256k1;
@@ -62,7 +67,43 @@ use std::{slice};
 // mod macros;
 // mod errors_t;
 mod data;
+mod records;
+mod freshness;
 mod keys_t;
+mod identity;
+mod channel;
+mod federation;
+mod migration;
+mod time_t;
+mod stats;
+mod padding;
+mod decoy;
+mod params;
+mod replay;
+mod users;
+mod authority;
+mod aggregates;
+mod export;
+mod zones;
+mod jobs;
+mod chunks;
+mod memory;
+mod audit;
+mod cancel;
+mod local;
+mod upgrade;
+mod responses;
+mod tenants;
+mod matching;
+mod clock;
+mod dedup;
+mod compression;
+mod sealed_log;
+mod quorum;
+mod policy;
+mod settings;
+mod summaries;
+mod incremental;
 // // mod storage;
 // mod types;
 // mod hash;
@@ -70,7 +111,16 @@ mod keys_t;
 
 use sgx_types::*;
 use keys_t::{get_user_key_internal};
-use data::{add_personal_data_internal, find_match_internal};
+use data::{add_personal_data_internal, add_personal_data_receipt, check_store_internal, find_match_internal, purge_data_internal,
+           update_user_status_internal, SubmitError};
+use channel::{new_channel_key_internal, open_channel_internal};
+use federation::{federated_begin_internal, federated_answer_internal, federated_end_internal, parse_peers};
+use migration::{migrate_legacy_data_internal, parse_path};
+use stats::get_stats_internal;
+use aggregates::get_aggregates_internal;
+use export::export_statistics_internal;
+use padding::PaddingClass;
+use compression::Compression;
 // use storage::*;
 use enigma_types::{PubKey, DhKey, EnclaveReturn};
 use enigma_tools_t::{
@@ -78,7 +128,6 @@ use enigma_tools_t::{
     storage_t,
     quote_t,
 };
-use enigma_tools_m::utils::EthereumAddress;
 
 use enigma_tools_m::utils::{LockExpectMutex};
 use enigma_crypto::{asymmetric, CryptoError};
@@ -93,13 +142,60 @@ lazy_static! {
     pub(crate) static ref SIGNING_KEY: asymmetric::KeyPair = get_sealed_keys_wrapper();
 }
 
+// Whether `SIGNING_KEY` was read, an enclave taking over from another one must not have generated one yet
+static SIGNING_KEY_LOADED: AtomicBool = AtomicBool::new(false);
+
+pub(crate) fn signing_key_loaded() -> bool { SIGNING_KEY_LOADED.load(Ordering::SeqCst) }
+
 #[no_mangle]
 pub extern "C" fn ecall_get_registration_quote(target_info: &sgx_target_info_t, real_report: &mut sgx_report_t) -> sgx_status_t {
-    quote_t::create_report_with_data(&target_info, real_report, &SIGNING_KEY.get_pubkey().address())
+    // The signing address, then the hash of the settings in force, see `settings`
+    let digest = match settings::digest() {
+        Ok(digest) => digest,
+        Err(_) => return sgx_status_t::SGX_ERROR_UNEXPECTED,
+    };
+    let address = match identity::address() {
+        Ok(address) => address,
+        Err(_) => return sgx_status_t::SGX_ERROR_UNEXPECTED,
+    };
+    let mut report_data = [0u8; 52];
+    report_data[..20].copy_from_slice(&address);
+    report_data[20..].copy_from_slice(&digest);
+    quote_t::create_report_with_data(&target_info, real_report, &report_data)
+}
+
+// Zeros when the key can't be read, `ecall_get_signing_identity` reports why.
+#[no_mangle]
+pub extern "C" fn ecall_get_signing_address(pubkey: &mut [u8; 20]) {
+    if let Ok(address) = identity::address() {
+        pubkey.copy_from_slice(&address);
+    }
+}
+
+// Before anything is signed, see `identity`.
+#[no_mangle]
+pub extern "C" fn ecall_set_signing_curve(curve: u8) -> EnclaveReturn {
+    match identity::set(curve) {
+        Ok(()) => EnclaveReturn::Success,
+        Err(e) => e.into(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn ecall_get_signing_identity(curve: &mut u8, pubkey: &mut [u8; 64]) -> EnclaveReturn {
+    match identity::public_key() {
+        Ok(key) => {
+            *curve = identity::curve();
+            pubkey.copy_from_slice(&key);
+            EnclaveReturn::Success
+        },
+        Err(e) => e.into(),
+    }
 }
 
+// The measurement of this enclave, from a report targeted at itself (no quote or IAS round trip needed).
 #[no_mangle]
-pub extern "C" fn ecall_get_signing_address(pubkey: &mut [u8; 20]) { pubkey.copy_from_slice(&SIGNING_KEY.get_pubkey().address()); }
+pub extern "C" fn ecall_get_mr_enclave(mr_enclave: &mut [u8; 32]) { mr_enclave.copy_from_slice(&sgx_tse::rsgx_self_report().body.mr_enclave.m); }
 
 
 fn get_sealed_keys_wrapper() -> asymmetric::KeyPair {
@@ -109,6 +205,7 @@ fn get_sealed_keys_wrapper() -> asymmetric::KeyPair {
     // path_buf.push("keypair.sealed");
     // let sealed_path = path_buf.to_str().unwrap();
 
+    SIGNING_KEY_LOADED.store(true, Ordering::SeqCst);
     // TODO: Decide what to do if failed to obtain keys.
     match storage_t::get_sealed_keys("keypair.sealed") {
         Ok(key) => key,
@@ -131,9 +228,11 @@ pub unsafe extern "C" fn ecall_get_user_key(sig: &mut [u8; 65], user_pubkey: &[u
 }
 
 fn get_io_key(user_key: &PubKey) -> Result<DhKey, EnclaveError> {
+    // Handed out for the tenant of the request, see `tenants`
+    let key_id = tenants::current()?.key_id(&user_key[..]);
     let io_key = keys_t::DH_KEYS
         .lock_expect("User DH Key")
-        .remove(&user_key[..])
+        .remove(&key_id)
         .ok_or(CryptoError::MissingKeyError { key_type: "DH Key" })?;
     Ok(io_key)
 }
@@ -142,12 +241,29 @@ fn get_io_key(user_key: &PubKey) -> Result<DhKey, EnclaveError> {
 pub unsafe extern "C" fn ecall_add_personal_data(
     encryptedUserId: *const u8,
     encryptedUserId_len: usize,
-    encryptedData: *const u8,
-    encryptedData_len: usize,
-    userPubKey: &[u8; 64]) -> EnclaveReturn {
+    encryptedData_context: u64,
+    encryptedSignature: *const u8,
+    encryptedSignature_len: usize,
+    userPubKey: &[u8; 64],
+    compression: u8,
+    serialized_ptr: *mut u64,
+    rejection: &mut u8) -> EnclaveReturn {
 
+    *rejection = 0;
+    if let Err(e) = init_serialized_ptr(serialized_ptr) {
+        return e;
+    }
+    let compression = match Compression::from_code(compression) {
+        Ok(v) => v,
+        Err(e) => return e.into(),
+    };
     let encryptedUserId = slice::from_raw_parts(encryptedUserId, encryptedUserId_len);
-    let encryptedData = slice::from_raw_parts(encryptedData, encryptedData_len);
+    // A location history can be large, it comes in chunks (see `chunks`)
+    let encryptedData = match chunks::take(encryptedData_context) {
+        Ok(v) => v,
+        Err(e) => return e.into(),
+    };
+    let encryptedSignature = slice::from_raw_parts(encryptedSignature, encryptedSignature_len);
 
     let io_key;
     match get_io_key(userPubKey) {
@@ -155,20 +271,94 @@ pub unsafe extern "C" fn ecall_add_personal_data(
         Err(e) => return e.into(),
     }
 
-    let result = add_personal_data_internal(encryptedUserId, encryptedData, userPubKey, &io_key);
+    let result = add_personal_data_internal(encryptedUserId, &encryptedData, encryptedSignature, userPubKey, compression, &io_key);
+
+    // The user gets an encrypted receipt either way, the host only learns the status
+    let saved = save_output(add_personal_data_receipt(&result, &io_key), serialized_ptr);
+    match result {
+        // Replays, expired envelopes, a full store and skewed clocks are told apart, the host can see them coming anyway
+        Err(SubmitError::Rejected(r)) => {
+            *rejection = r as u8;
+            EnclaveReturn::TaskFailure
+        },
+        Err(SubmitError::Failed(e)) => e.into(),
+        Ok(_) => saved,
+    }
+}
 
-    EnclaveReturn::Success
+// A status update is a few hundred bytes, it doesn't go through `chunks`
+#[no_mangle]
+pub unsafe extern "C" fn ecall_update_user_status(
+    encryptedUserId: *const u8,
+    encryptedUserId_len: usize,
+    encryptedData: *const u8,
+    encryptedData_len: usize,
+    encryptedSignature: *const u8,
+    encryptedSignature_len: usize,
+    userPubKey: &[u8; 64],
+    serialized_ptr: *mut u64,
+    rejection: &mut u8) -> EnclaveReturn {
+
+    *rejection = 0;
+    if let Err(e) = init_serialized_ptr(serialized_ptr) {
+        return e;
+    }
+    let encryptedUserId = slice::from_raw_parts(encryptedUserId, encryptedUserId_len);
+    let encryptedData = slice::from_raw_parts(encryptedData, encryptedData_len);
+    let encryptedSignature = slice::from_raw_parts(encryptedSignature, encryptedSignature_len);
+    let io_key = match get_io_key(userPubKey) {
+        Ok(v) => v,
+        Err(e) => return e.into(),
+    };
+
+    let result = update_user_status_internal(encryptedUserId, encryptedData, encryptedSignature, userPubKey, &io_key);
+    let saved = save_output(add_personal_data_receipt(&result, &io_key), serialized_ptr);
+    match result {
+        Err(SubmitError::Rejected(r)) => {
+            *rejection = r as u8;
+            EnclaveReturn::TaskFailure
+        },
+        Err(SubmitError::Failed(e)) => e.into(),
+        Ok(_) => saved,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_register_user(
+    encryptedUserId: *const u8,
+    encryptedUserId_len: usize,
+    encryptedData: *const u8,
+    encryptedData_len: usize,
+    userPubKey: &[u8; 64]) -> EnclaveReturn {
+
+    let encryptedUserId = slice::from_raw_parts(encryptedUserId, encryptedUserId_len);
+    let encryptedData = slice::from_raw_parts(encryptedData, encryptedData_len);
+    let io_key = match get_io_key(userPubKey) {
+        Ok(v) => v,
+        Err(e) => return e.into(),
+    };
+    match users::register_user_internal(encryptedUserId, encryptedData, userPubKey, &io_key) {
+        Ok(()) => EnclaveReturn::Success,
+        Err(e) => e.into(),
+    }
 }
 
 #[no_mangle]
 pub unsafe extern "C" fn ecall_find_match(
     encryptedUserId: *const u8,
     encryptedUserId_len: usize,
+    encryptedSignature: *const u8,
+    encryptedSignature_len: usize,
+    strategy: *const u8,
+    strategy_len: usize,
+    incremental: u8,
     userPubKey: &[u8; 64],
-    serialized_ptr: *mut u64) -> EnclaveReturn {
+    serialized_ptr: *mut u64,
+    strategy_ptr: *mut u64) -> EnclaveReturn {
 
     // Initialize the pointer, in case we error out, it points somewhere,
     // otherwise we get a segmentation fault when we throw an error
+    *strategy_ptr = 0;
     let empty = [0u8];
     *serialized_ptr = match ocalls_t::save_to_untrusted_memory(&empty) {
         Ok(ptr) => ptr,
@@ -176,6 +366,8 @@ pub unsafe extern "C" fn ecall_find_match(
     };
 
     let encryptedUserId = slice::from_raw_parts(encryptedUserId, encryptedUserId_len);
+    let encryptedSignature = slice::from_raw_parts(encryptedSignature, encryptedSignature_len);
+    let strategy = slice::from_raw_parts(strategy, strategy_len);
 
     let io_key;
     match get_io_key(userPubKey) {
@@ -183,7 +375,126 @@ pub unsafe extern "C" fn ecall_find_match(
         Err(e) => return e.into(),
     }
 
-    let msg = match find_match_internal(encryptedUserId, userPubKey, &io_key) {
+    let (msg, strategy) = match find_match_internal(encryptedUserId, encryptedSignature, strategy, incremental != 0, userPubKey, &io_key) {
+        Ok(output) => output,
+        Err(e) => return e.into(),
+    };
+    if let Err(e) = save_strategy(&strategy, strategy_ptr) {
+        return e;
+    }
+    *serialized_ptr = match ocalls_t::save_to_untrusted_memory(&msg[..]) {
+        Ok(ptr) => ptr,
+        Err(e) => return e.into(),
+    };
+
+    EnclaveReturn::Success
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_start_match_job(
+    encryptedUserId: *const u8,
+    encryptedUserId_len: usize,
+    encryptedSignature: *const u8,
+    encryptedSignature_len: usize,
+    strategy: *const u8,
+    strategy_len: usize,
+    userPubKey: &[u8; 64],
+    job: &mut u64,
+    total: &mut u64,
+    strategy_ptr: *mut u64) -> EnclaveReturn {
+
+    *strategy_ptr = 0;
+    let encryptedUserId = slice::from_raw_parts(encryptedUserId, encryptedUserId_len);
+    let encryptedSignature = slice::from_raw_parts(encryptedSignature, encryptedSignature_len);
+    let strategy = slice::from_raw_parts(strategy, strategy_len);
+    let io_key = match get_io_key(userPubKey) {
+        Ok(v) => v,
+        Err(e) => return e.into(),
+    };
+    match jobs::start(encryptedUserId, encryptedSignature, strategy, userPubKey, io_key) {
+        Ok((id, users, strategy)) => {
+            *job = id;
+            *total = users;
+            match save_strategy(&strategy, strategy_ptr) {
+                Ok(()) => EnclaveReturn::Success,
+                Err(e) => e,
+            }
+        },
+        Err(e) => e.into(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_match_job_step(job: u64, budget: u64, processed: &mut u64) -> EnclaveReturn {
+    match jobs::step(job, budget) {
+        Ok(done) => {
+            *processed = done;
+            EnclaveReturn::Success
+        },
+        Err(e) => e.into(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_finish_match_job(job: u64, serialized_ptr: *mut u64) -> EnclaveReturn {
+    if let Err(e) = init_serialized_ptr(serialized_ptr) {
+        return e;
+    }
+    save_output(jobs::finish(job), serialized_ptr)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_cancel_match_job(job: u64) -> EnclaveReturn {
+    jobs::cancel(job);
+    EnclaveReturn::Success
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_precompute_matches(budget: u64, refreshed: &mut u64, remaining: &mut u64) -> EnclaveReturn {
+    match incremental::precompute(budget) {
+        Ok(done) => {
+            *refreshed = done.refreshed;
+            *remaining = done.remaining;
+            EnclaveReturn::Success
+        },
+        Err(e) => e.into(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_new_channel_key(channel_pubkey: &mut [u8; 64], sig: &mut [u8; 65]) -> EnclaveReturn {
+    match new_channel_key_internal(channel_pubkey, sig) {
+        Ok(()) => EnclaveReturn::Success,
+        Err(e) => e.into(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_open_channel(
+    channel_pubkey: &[u8; 64],
+    peer_pubkey: &[u8; 64],
+    peer_sig: &[u8; 65],
+    peer_address: &[u8; 20]) -> EnclaveReturn {
+
+    match open_channel_internal(channel_pubkey, peer_pubkey, peer_sig, peer_address) {
+        Ok(()) => EnclaveReturn::Success,
+        Err(e) => e.into(),
+    }
+}
+
+// The ecalls returning their output through `serialized_ptr` (the federated ones, `ecall_add_personal_data`)
+// initialize it first so it's always valid, like in `ecall_find_match`.
+unsafe fn init_serialized_ptr(serialized_ptr: *mut u64) -> Result<(), EnclaveReturn> {
+    let empty = [0u8];
+    *serialized_ptr = match ocalls_t::save_to_untrusted_memory(&empty) {
+        Ok(ptr) => ptr,
+        Err(e) => return Err(e.into()),
+    };
+    Ok(())
+}
+
+unsafe fn save_output(output: Result<Vec<u8>, EnclaveError>, serialized_ptr: *mut u64) -> EnclaveReturn {
+    let msg = match output {
         Ok(msg) => msg,
         Err(e) => return e.into(),
     };
@@ -191,6 +502,489 @@ pub unsafe extern "C" fn ecall_find_match(
         Ok(ptr) => ptr,
         Err(e) => return e.into(),
     };
+    EnclaveReturn::Success
+}
+
+// The strategy a query ran, as JSON, for the host to report it. `strategy_ptr` stays 0 without it.
+unsafe fn save_strategy(strategy: &matching::Strategy, strategy_ptr: *mut u64) -> Result<(), EnclaveReturn> {
+    let saved = serde_json::to_vec(strategy).map_err(|_| EnclaveError::from(data::Error::SerializeError))
+        .and_then(|strategy| ocalls_t::save_to_untrusted_memory(&strategy[..]));
+    *strategy_ptr = saved.map_err(EnclaveReturn::from)?;
+    Ok(())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_federated_begin(
+    encryptedUserId: *const u8,
+    encryptedUserId_len: usize,
+    encryptedSignature: *const u8,
+    encryptedSignature_len: usize,
+    userPubKey: &[u8; 64],
+    peers: *const u8,
+    peers_len: usize,
+    serialized_ptr: *mut u64) -> EnclaveReturn {
+
+    if let Err(e) = init_serialized_ptr(serialized_ptr) {
+        return e;
+    }
+    let encryptedUserId = slice::from_raw_parts(encryptedUserId, encryptedUserId_len);
+    let encryptedSignature = slice::from_raw_parts(encryptedSignature, encryptedSignature_len);
+    let peers = match parse_peers(slice::from_raw_parts(peers, peers_len)) {
+        Ok(v) => v,
+        Err(e) => return e.into(),
+    };
+
+    let io_key;
+    match get_io_key(userPubKey) {
+        Ok(v) => io_key = v,
+        Err(e) => return e.into(),
+    }
+
+    save_output(federated_begin_internal(encryptedUserId, encryptedSignature, userPubKey, &io_key, &peers), serialized_ptr)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_federated_answer(
+    peer: &[u8; 20],
+    query: *const u8,
+    query_len: usize,
+    serialized_ptr: *mut u64) -> EnclaveReturn {
+
+    if let Err(e) = init_serialized_ptr(serialized_ptr) {
+        return e;
+    }
+    let query = slice::from_raw_parts(query, query_len);
+    save_output(federated_answer_internal(peer, query), serialized_ptr)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_federated_end(
+    userPubKey: &[u8; 64],
+    peers: *const u8,
+    peers_len: usize,
+    answers_context: u64,
+    serialized_ptr: *mut u64) -> EnclaveReturn {
+
+    if let Err(e) = init_serialized_ptr(serialized_ptr) {
+        return e;
+    }
+    // The answers of every peer, in chunks
+    let answers = match chunks::take(answers_context) {
+        Ok(v) => v,
+        Err(e) => return e.into(),
+    };
+    let peers = match parse_peers(slice::from_raw_parts(peers, peers_len)) {
+        Ok(v) => v,
+        Err(e) => return e.into(),
+    };
+    save_output(federated_end_internal(userPubKey, &peers, &answers), serialized_ptr)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_chunk_begin(total_len: usize, context: &mut u64) -> EnclaveReturn {
+    match chunks::begin(total_len) {
+        Ok(id) => {
+            *context = id;
+            EnclaveReturn::Success
+        },
+        Err(e) => e.into(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_chunk_append(context: u64, chunk: *const u8, chunk_len: usize) -> EnclaveReturn {
+    match chunks::append(context, slice::from_raw_parts(chunk, chunk_len)) {
+        Ok(()) => EnclaveReturn::Success,
+        Err(e) => e.into(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_chunk_discard(context: u64) -> EnclaveReturn {
+    chunks::discard(context);
+    EnclaveReturn::Success
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_get_memory_usage(serialized_ptr: *mut u64) -> EnclaveReturn {
+    if let Err(e) = init_serialized_ptr(serialized_ptr) {
+        return e;
+    }
+    save_output(memory::get_memory_usage_internal(), serialized_ptr)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_audit_checkpoint(
+    prev_seq: u64,
+    prev_head: &[u8; 32],
+    digests: *const u8,
+    digests_len: usize,
+    seq: &mut u64,
+    head: &mut [u8; 32],
+    sig: &mut [u8; 65],
+    resumed: &mut u8) -> EnclaveReturn {
+
+    match audit::checkpoint_internal(prev_seq, prev_head, slice::from_raw_parts(digests, digests_len)) {
+        Ok(checkpoint) => {
+            *seq = checkpoint.seq;
+            *head = checkpoint.head;
+            *sig = checkpoint.signature;
+            *resumed = checkpoint.resumed as u8;
+            EnclaveReturn::Success
+        },
+        Err(e) => e.into(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn ecall_sign_response(
+    digest: &[u8; 32],
+    address: &mut [u8; 20],
+    boot: &mut [u8; 16],
+    counter: &mut u64,
+    sig: &mut [u8; 65]) -> EnclaveReturn {
+
+    match responses::sign_response_internal(digest) {
+        Ok(signed) => {
+            *address = signed.address;
+            *boot = signed.boot;
+            *counter = signed.counter;
+            *sig = signed.signature;
+            EnclaveReturn::Success
+        },
+        Err(e) => e.into(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn ecall_local_offer(msg1: &mut sgx_dh_msg1_t, session: &mut u64) -> EnclaveReturn {
+    match local::offer(msg1) {
+        Ok(id) => { *session = id; EnclaveReturn::Success },
+        Err(e) => e.into(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn ecall_local_join(msg1: &sgx_dh_msg1_t, msg2: &mut sgx_dh_msg2_t, session: &mut u64) -> EnclaveReturn {
+    match local::join(msg1, msg2) {
+        Ok(id) => { *session = id; EnclaveReturn::Success },
+        Err(e) => e.into(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_local_accept(session: u64, msg2: &sgx_dh_msg2_t, msg3: *mut u8, msg3_len: usize) -> EnclaveReturn {
+    match local::accept(session, msg2, slice::from_raw_parts_mut(msg3, msg3_len)) {
+        Ok(()) => EnclaveReturn::Success,
+        Err(e) => e.into(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_local_confirm(session: u64, msg3: *const u8, msg3_len: usize) -> EnclaveReturn {
+    // `from_raw_dh_msg3_t` takes a mutable pointer, it only reads
+    let mut msg3 = slice::from_raw_parts(msg3, msg3_len).to_vec();
+    match local::confirm(session, &mut msg3) {
+        Ok(()) => EnclaveReturn::Success,
+        Err(e) => e.into(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn ecall_local_close(session: u64) -> EnclaveReturn {
+    local::close(session);
+    EnclaveReturn::Success
+}
+
+#[no_mangle]
+pub extern "C" fn ecall_upgrade_export(session: u64, transfer: &mut [u8; upgrade::TRANSFER_SIZE]) -> EnclaveReturn {
+    match upgrade::export_internal(session, transfer) {
+        Ok(()) => EnclaveReturn::Success,
+        Err(e) => e.into(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn ecall_upgrade_import(session: u64, transfer: &[u8; upgrade::TRANSFER_SIZE], address: &mut [u8; 20]) -> EnclaveReturn {
+    match upgrade::import_internal(session, transfer, address) {
+        Ok(()) => EnclaveReturn::Success,
+        Err(e) => e.into(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_migrate_legacy_data(
+    legacy_path: *const u8,
+    legacy_path_len: usize,
+    migrated_users: &mut u64,
+    migrated_records: &mut u64) -> EnclaveReturn {
+
+    let legacy_path = match parse_path(slice::from_raw_parts(legacy_path, legacy_path_len)) {
+        Ok(v) => v,
+        Err(e) => return e.into(),
+    };
+    match migrate_legacy_data_internal(legacy_path) {
+        Ok(report) => {
+            *migrated_users = report.users;
+            *migrated_records = report.records;
+            EnclaveReturn::Success
+        },
+        Err(e) => e.into(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_get_stats(serialized_ptr: *mut u64) -> EnclaveReturn {
+    if let Err(e) = init_serialized_ptr(serialized_ptr) {
+        return e;
+    }
+    save_output(get_stats_internal(), serialized_ptr)
+}
 
+#[no_mangle]
+pub unsafe extern "C" fn ecall_get_settings(serialized_ptr: *mut u64) -> EnclaveReturn {
+    if let Err(e) = init_serialized_ptr(serialized_ptr) {
+        return e;
+    }
+    save_output(settings::document(), serialized_ptr)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_get_aggregates(serialized_ptr: *mut u64) -> EnclaveReturn {
+    if let Err(e) = init_serialized_ptr(serialized_ptr) {
+        return e;
+    }
+    save_output(get_aggregates_internal(), serialized_ptr)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_export_statistics(request: *const u8, request_len: usize, serialized_ptr: *mut u64) -> EnclaveReturn {
+    if let Err(e) = init_serialized_ptr(serialized_ptr) {
+        return e;
+    }
+    save_output(export_statistics_internal(slice::from_raw_parts(request, request_len)), serialized_ptr)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_set_response_padding(padding_class: u8, bucket: u64) -> EnclaveReturn {
+    match PaddingClass::from_u8(padding_class) {
+        Ok(class) => {
+            padding::set_bucket(class, bucket as usize);
+            EnclaveReturn::Success
+        },
+        Err(e) => e.into(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_set_match_rate_limit(per_minute: u64) -> EnclaveReturn {
+    decoy::set_limit(per_minute);
     EnclaveReturn::Success
-}
\ No newline at end of file
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_set_data_policy(min_overlap: i32, distance: f64, retention: u64) -> EnclaveReturn {
+    match params::set(min_overlap, distance, retention) {
+        Ok(()) => EnclaveReturn::Success,
+        Err(e) => e.into(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_set_matching_strategy(strategy: *const u8, strategy_len: usize) -> EnclaveReturn {
+    match matching::set_default(slice::from_raw_parts(strategy, strategy_len)) {
+        Ok(()) => EnclaveReturn::Success,
+        Err(e) => e.into(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_set_quantization(grid: f64, time_bucket: u64) -> EnclaveReturn {
+    match params::set_quantization(grid, time_bucket) {
+        Ok(()) => EnclaveReturn::Success,
+        Err(e) => e.into(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_set_replay_policy(window: u64, required: u8) -> EnclaveReturn {
+    match replay::set(window, required != 0) {
+        Ok(()) => EnclaveReturn::Success,
+        Err(e) => e.into(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_set_clock_policy(max_skew: u64) -> EnclaveReturn {
+    match clock::set(max_skew) {
+        Ok(()) => EnclaveReturn::Success,
+        Err(e) => e.into(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_set_auth_policy(required: u8) -> EnclaveReturn {
+    match users::set(required != 0) {
+        Ok(()) => EnclaveReturn::Success,
+        Err(e) => e.into(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_set_rollback_policy(monotonic: u8) -> EnclaveReturn {
+    match freshness::set(monotonic != 0) {
+        Ok(()) => EnclaveReturn::Success,
+        Err(e) => e.into(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_set_storage_policy(compact_after: u64) -> EnclaveReturn {
+    sealed_log::set(compact_after);
+    EnclaveReturn::Success
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_check_store(repair: u8, serialized_ptr: *mut u64) -> EnclaveReturn {
+    if let Err(e) = init_serialized_ptr(serialized_ptr) {
+        return e;
+    }
+    save_output(check_store_internal(repair != 0), serialized_ptr)
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_set_capacity(soft_records: u64, hard_records: u64) -> EnclaveReturn {
+    match memory::set_capacity(soft_records, hard_records) {
+        Ok(()) => EnclaveReturn::Success,
+        Err(e) => e.into(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_set_export_policy(k: u64) -> EnclaveReturn {
+    match export::set(k) {
+        Ok(()) => EnclaveReturn::Success,
+        Err(e) => e.into(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_set_statistics_policy(epsilon: f64, precision: u8, min_count: u64) -> EnclaveReturn {
+    match aggregates::set(epsilon, precision, min_count) {
+        Ok(()) => EnclaveReturn::Success,
+        Err(e) => e.into(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_set_health_authorities(keys: *const u8, keys_len: usize, max_age: u64) -> EnclaveReturn {
+    match authority::set(slice::from_raw_parts(keys, keys_len), max_age) {
+        Ok(()) => EnclaveReturn::Success,
+        Err(e) => e.into(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_set_exclusion_zones(zones: *const u8, zones_len: usize, count: &mut u64) -> EnclaveReturn {
+    match zones::set_zones_internal(slice::from_raw_parts(zones, zones_len)) {
+        Ok(n) => {
+            *count = n;
+            EnclaveReturn::Success
+        },
+        Err(e) => e.into(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_set_tenants(tenants: *const u8, tenants_len: usize, count: &mut u64) -> EnclaveReturn {
+    match tenants::set(slice::from_raw_parts(tenants, tenants_len)) {
+        Ok(n) => {
+            *count = n;
+            EnclaveReturn::Success
+        },
+        Err(e) => e.into(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_purge_data(purged_users: &mut u64, purged_records: &mut u64) -> EnclaveReturn {
+    match purge_data_internal() {
+        Ok((users, records)) => {
+            *purged_users = users;
+            *purged_records = records;
+            EnclaveReturn::Success
+        },
+        Err(e) => e.into(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_register_operators(operators: *const u8, operators_len: usize, state: &mut u8) -> EnclaveReturn {
+    match quorum::register(slice::from_raw_parts(operators, operators_len)) {
+        Ok(registered) => {
+            *state = registered;
+            EnclaveReturn::Success
+        },
+        Err(e) => e.into(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_authorize_admin(
+    payload: *const u8,
+    payload_len: usize,
+    signatures: *const u8,
+    signatures_len: usize,
+    approvals: &mut u64,
+) -> EnclaveReturn {
+    match quorum::authorize(slice::from_raw_parts(payload, payload_len), slice::from_raw_parts(signatures, signatures_len)) {
+        Ok(signed) => {
+            *approvals = signed;
+            EnclaveReturn::Success
+        },
+        Err(e) => e.into(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn ecall_adopt_operators(operators: &mut u64, required: &mut u32) -> EnclaveReturn {
+    match quorum::adopt() {
+        Ok(registry) => {
+            *operators = registry.operators.len() as u64;
+            *required = registry.quorum;
+            EnclaveReturn::Success
+        },
+        Err(e) => e.into(),
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn ecall_apply_policy(
+    document: *const u8,
+    document_len: usize,
+    signature: *const u8,
+    signature_len: usize,
+    version: &mut u64,
+    hash: &mut [u8; 32],
+) -> EnclaveReturn {
+    match policy::apply(slice::from_raw_parts(document, document_len), slice::from_raw_parts(signature, signature_len)) {
+        Ok(active) => {
+            *version = active.version;
+            hash.copy_from_slice(&active.hash);
+            EnclaveReturn::Success
+        },
+        Err(e) => e.into(),
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn ecall_restore_policy(version: &mut u64, hash: &mut [u8; 32]) -> EnclaveReturn {
+    match policy::restore() {
+        Ok(active) => {
+            *version = active.version;
+            hash.copy_from_slice(&active.hash);
+            EnclaveReturn::Success
+        },
+        Err(e) => e.into(),
+    }
+}
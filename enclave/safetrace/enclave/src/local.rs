@@ -0,0 +1,167 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use enigma_tools_m::utils::LockExpectMutex;
+use enigma_tools_t::common::errors_t::{EnclaveError, FailedTaskError::InputError};
+use sgx_rand::{Rng, SgxRng};
+use sgx_tcrypto::{rsgx_rijndael128GCM_decrypt, rsgx_rijndael128GCM_encrypt};
+use sgx_tdh::{SgxDhInitiator, SgxDhMsg1, SgxDhMsg2, SgxDhMsg3, SgxDhResponder};
+use sgx_types::*;
+use std::{collections::HashMap, mem, string::ToString, sync::SgxMutex, vec::Vec};
+
+// Local attestation sessions with other enclaves of the same platform: the SGX DH exchange, in which each
+// enclave gets a report of the other one, so both know who they talk to without a quote nor IAS. The
+// responder offers (msg1), the initiator joins (msg2), the responder accepts (msg3) and the initiator
+// confirms; the host only carries the messages, see `esgx::local` in the app. Both sides then hold the same
+// session key and the identity of the other enclave. Only an enclave of the same signer is accepted, and no
+// debug enclave unless this one is one: what else the peer must be (product, version, a given MRENCLAVE)
+// is up to the ecall that `take`s the session, `upgrade` or the auxiliary enclaves of a split deployment.
+
+// Without additional properties
+pub const MSG3_SIZE: usize = mem::size_of::<sgx_dh_msg3_t>();
+// IV and MAC in front of what `encrypt` returns
+pub const OVERHEAD: usize = 12 + 16;
+// Handshakes a host abandoned can't pile up past this
+pub const MAX_SESSIONS: usize = 8;
+
+static NEXT_SESSION: AtomicU64 = AtomicU64::new(1);
+
+enum State {
+    Responder(SgxDhResponder),
+    Initiator(SgxDhInitiator),
+    Established(Session),
+}
+
+pub struct Session {
+    pub key: sgx_key_128bit_t,
+    pub peer: sgx_dh_session_enclave_identity_t,
+    // Which side of the exchange this enclave was
+    pub initiator: bool,
+}
+
+lazy_static! {
+    static ref SESSIONS: SgxMutex<HashMap<u64, State>> = SgxMutex::new(HashMap::new());
+}
+
+fn invalid(message: &str) -> EnclaveError {
+    EnclaveError::FailedTaskError(InputError { message: message.to_string() })
+}
+
+fn sgx_call(result: SgxError, step: &str) -> Result<(), EnclaveError> {
+    result.map_err(|status| invalid(&format!("{} failed: {}", step, status.as_str())))
+}
+
+pub fn is_debug(flags: u64) -> bool { flags & SGX_FLAGS_DEBUG != 0 }
+
+fn check_peer(peer: &sgx_dh_session_enclave_identity_t) -> Result<(), EnclaveError> {
+    let own = sgx_tse::rsgx_self_report().body;
+    if peer.mr_signer.m != own.mr_signer.m {
+        return Err(invalid("the other enclave has another signer"));
+    }
+    if is_debug(peer.attributes.flags) && !is_debug(own.attributes.flags) {
+        return Err(invalid("the other enclave is a debug build"));
+    }
+    Ok(())
+}
+
+fn insert(state: State) -> Result<u64, EnclaveError> {
+    let mut sessions = SESSIONS.lock_expect("Local Sessions");
+    if sessions.len() >= MAX_SESSIONS {
+        return Err(invalid("too many local attestation sessions are open"));
+    }
+    let session = NEXT_SESSION.fetch_add(1, Ordering::SeqCst);
+    sessions.insert(session, state);
+    Ok(session)
+}
+
+// The responder, 1st step. Returns the id of the session.
+pub fn offer(msg1: &mut sgx_dh_msg1_t) -> Result<u64, EnclaveError> {
+    let mut responder = SgxDhResponder::init_session();
+    sgx_call(responder.gen_msg1(msg1), "gen_msg1")?;
+    insert(State::Responder(responder))
+}
+
+// The initiator, 2nd step. Returns the id of the session.
+pub fn join(msg1: &SgxDhMsg1, msg2: &mut SgxDhMsg2) -> Result<u64, EnclaveError> {
+    let mut initiator = SgxDhInitiator::init_session();
+    sgx_call(initiator.proc_msg1(msg1, msg2), "proc_msg1")?;
+    insert(State::Initiator(initiator))
+}
+
+// The responder, 3rd step: checks the initiator.
+pub fn accept(session: u64, msg2: &SgxDhMsg2, msg3: &mut [u8]) -> Result<(), EnclaveError> {
+    let mut sessions = SESSIONS.lock_expect("Local Sessions");
+    let mut responder = match sessions.remove(&session) {
+        Some(State::Responder(responder)) => responder,
+        _ => return Err(invalid("no such offered session")),
+    };
+    let mut dh_msg3 = SgxDhMsg3::new();
+    let mut key = sgx_key_128bit_t::default();
+    let mut peer = sgx_dh_session_enclave_identity_t::default();
+    sgx_call(responder.proc_msg2(msg2, &mut dh_msg3, &mut key, &mut peer), "proc_msg2")?;
+    check_peer(&peer)?;
+    if msg3.len() != MSG3_SIZE || dh_msg3.calc_raw_sealed_data_size() as usize != MSG3_SIZE {
+        return Err(invalid("unexpected msg3 size"));
+    }
+    unsafe { dh_msg3.to_raw_dh_msg3_t(msg3.as_mut_ptr() as *mut sgx_dh_msg3_t, msg3.len() as u32) }
+        .ok_or_else(|| invalid("encoding msg3 failed"))?;
+    sessions.insert(session, State::Established(Session { key, peer, initiator: false }));
+    Ok(())
+}
+
+// The initiator, 4th step: checks the responder.
+pub fn confirm(session: u64, msg3: &mut [u8]) -> Result<(), EnclaveError> {
+    let mut sessions = SESSIONS.lock_expect("Local Sessions");
+    let mut initiator = match sessions.remove(&session) {
+        Some(State::Initiator(initiator)) => initiator,
+        _ => return Err(invalid("no such joined session")),
+    };
+    let dh_msg3 = unsafe { SgxDhMsg3::from_raw_dh_msg3_t(msg3.as_mut_ptr() as *mut sgx_dh_msg3_t, msg3.len() as u32) }
+        .ok_or_else(|| invalid("malformed msg3"))?;
+    let mut key = sgx_key_128bit_t::default();
+    let mut peer = sgx_dh_session_enclave_identity_t::default();
+    sgx_call(initiator.proc_msg3(&dh_msg3, &mut key, &mut peer), "proc_msg3")?;
+    check_peer(&peer)?;
+    sessions.insert(session, State::Established(Session { key, peer, initiator: true }));
+    Ok(())
+}
+
+// An established session, for one use: it's closed either way.
+pub fn take(session: u64) -> Result<Session, EnclaveError> {
+    match SESSIONS.lock_expect("Local Sessions").remove(&session) {
+        Some(State::Established(session)) => Ok(session),
+        _ => Err(invalid("no such local attestation session")),
+    }
+}
+
+// Closes a session, if it's still open.
+pub fn close(session: u64) {
+    SESSIONS.lock_expect("Local Sessions").remove(&session);
+}
+
+// AES-GCM with the session key and a random IV, returns IV | MAC | ciphertext. `aad` says what the message
+// is for, so it can't be replayed as another one.
+pub fn encrypt(key: &sgx_key_128bit_t, plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, EnclaveError> {
+    let mut iv = [0u8; 12];
+    SgxRng::new().map_err(|_| invalid("no randomness"))?.fill_bytes(&mut iv);
+    let mut message = vec![0u8; OVERHEAD + plaintext.len()];
+    let mut mac = sgx_aes_gcm_128bit_tag_t::default();
+    {
+        let (header, ciphertext) = message.split_at_mut(OVERHEAD);
+        sgx_call(rsgx_rijndael128GCM_encrypt(key, plaintext, &iv, aad, ciphertext, &mut mac), "encrypting")?;
+        header[..12].copy_from_slice(&iv);
+        header[12..].copy_from_slice(&mac);
+    }
+    Ok(message)
+}
+
+pub fn decrypt(key: &sgx_key_128bit_t, message: &[u8], aad: &[u8]) -> Result<Vec<u8>, EnclaveError> {
+    if message.len() < OVERHEAD {
+        return Err(invalid("the message is too short"));
+    }
+    let (iv, rest) = message.split_at(12);
+    let (mac, ciphertext) = rest.split_at(16);
+    let mut tag = sgx_aes_gcm_128bit_tag_t::default();
+    tag.copy_from_slice(mac);
+    let mut plaintext = vec![0u8; ciphertext.len()];
+    sgx_call(rsgx_rijndael128GCM_decrypt(key, ciphertext, iv, aad, &tag, &mut plaintext), "decrypting")?;
+    Ok(plaintext)
+}
@@ -0,0 +1,170 @@
+use crate::data::{GeolocationTime, EARTH_RADIUS};
+use crate::params;
+use crate::policy;
+use crate::settings;
+use core::sync::atomic::{AtomicBool, Ordering};
+use enigma_tools_m::utils::LockExpectMutex;
+use enigma_tools_t::common::errors_t::{EnclaveError, FailedTaskError::InputError};
+use serde::{Deserialize, Serialize};
+use std::{string::ToString, sync::SgxMutex, vec::Vec};
+
+// The matching strategies: how a location of the user is compared with the infected locations of a stored
+// user. The host sets the one of the deployment (`[matching]`), a query may name another, e.g.
+//   {"algorithm": "radius", "minOverlap": 300, "distance": 10.0}
+// and the results of the query say which one ran with which parameters, so they can be reproduced.
+// Without a strategy set, `radius` runs with the thresholds of `params`.
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "algorithm", rename_all = "camelCase")]
+pub enum Strategy {
+    // Within `distance` meters for more than `minOverlap` seconds, the algorithm of the first releases
+    Radius {
+        #[serde(rename = "minOverlap")]
+        min_overlap: i32,
+        distance: f64,
+    },
+    // In the same cell of a grid of `cell` degrees, for more than `minOverlap` seconds
+    Grid {
+        cell: f64,
+        #[serde(rename = "minOverlap")]
+        min_overlap: i32,
+    },
+    // Within `distance` meters for at least `minDuration` seconds in all, adding up the overlaps with every
+    // infected location of the stored user: several short contacts count like a long one
+    Duration {
+        distance: f64,
+        #[serde(rename = "minDuration")]
+        min_duration: i32,
+    },
+}
+
+lazy_static! { static ref DEFAULT: SgxMutex<Option<Strategy>> = SgxMutex::new(None); }
+static SET: AtomicBool = AtomicBool::new(false);
+
+fn invalid(message: &str) -> EnclaveError {
+    EnclaveError::FailedTaskError(InputError { message: message.to_string() })
+}
+
+fn parse(strategy: &[u8]) -> Result<Strategy, EnclaveError> {
+    let strategy: Strategy = serde_json::from_slice(strategy).map_err(|_| invalid("malformed matching strategy"))?;
+    let valid_distance = |distance: f64| distance.is_finite() && distance > 0.0;
+    let valid = match strategy {
+        Strategy::Radius { min_overlap, distance } => min_overlap >= 0 && valid_distance(distance),
+        // Beyond a degree (111km) nothing would be close anymore
+        Strategy::Grid { cell, min_overlap } => cell.is_finite() && cell > 0.0 && cell <= 1.0 && min_overlap >= 0,
+        Strategy::Duration { distance, min_duration } => valid_distance(distance) && min_duration > 0,
+    };
+    if !valid {
+        return Err(invalid("invalid matching strategy parameters"));
+    }
+    Ok(strategy)
+}
+
+// The strategy of the deployment, for the queries that don't name one. Refused once a signed policy is active,
+// and set once, before the quotes commit to it (see `settings`).
+pub fn set_default(strategy: &[u8]) -> Result<(), EnclaveError> {
+    if policy::active_hash() != [0u8; 32] {
+        return Err(invalid("a signed policy sets the matching strategy"));
+    }
+    let strategy = parse(strategy)?;
+    if settings::quoted() {
+        return Err(invalid("a quote committed to the matching strategy already"));
+    }
+    if SET.swap(true, Ordering::SeqCst) {
+        return Err(invalid("the matching strategy is already set"));
+    }
+    *DEFAULT.lock_expect("Matching Strategy") = Some(strategy);
+    Ok(())
+}
+
+// The strategy of a signed policy, see `policy::enforce`.
+pub(crate) fn set_signed_default(strategy: &[u8]) -> Result<(), EnclaveError> {
+    *DEFAULT.lock_expect("Matching Strategy") = Some(parse(strategy)?);
+    Ok(())
+}
+
+// The strategy of the deployment, if one was set.
+pub fn default() -> Option<Strategy> {
+    DEFAULT.lock_expect("Matching Strategy").clone()
+}
+
+// The strategy a query named, or the one of the deployment when it named none (an empty `strategy`).
+pub fn resolve(strategy: &[u8]) -> Result<Strategy, EnclaveError> {
+    if !strategy.is_empty() {
+        return parse(strategy);
+    }
+    let default = DEFAULT.lock_expect("Matching Strategy").clone();
+    Ok(default.unwrap_or_else(|| Strategy::Radius { min_overlap: params::min_overlap(), distance: params::distance() }))
+}
+
+// Whether two locations are less than `distance` meters apart.
+fn within(d: &GeolocationTime, e: &GeolocationTime, distance: f64) -> bool {
+    // We start comparing distance between latitudes. Each degree of lat is aprox
+    // 111 kms (range varies between 110.567 km at the equator to 111.699 km at the poles)
+    // The distance between two locations will be equal or larger than the distance between
+    // their latitudes (or the distance between lats will be smaller than the distance * cos(45))
+    // Source:
+    // https://stackoverflow.com/questions/5031268/algorithm-to-find-all-latitude-longitude-locations-within-a-certain-distance-fro
+    (e.lat - d.lat).abs() * 111000.0 < distance * 0.71 &&
+        // then we can run a more computationally expensive and precise comparison
+        (e.lat.sin()*d.lat.sin()+e.lat.cos()*d.lat.cos()*(e.lng-d.lng).cos()).acos() * EARTH_RADIUS < distance
+}
+
+// It's easier to find overlaps in time because it's a direct comparison of integers
+// so handle this first:
+// Both time intervals have to be larger than the minumum time overlap TOVERLAP
+// and both start times + TOVERLAP have to be smaller than the other end times
+fn overlap(d: &GeolocationTime, e: &GeolocationTime, toverlap: i32) -> bool {
+    d.endTS - d.startTS > toverlap &&
+        e.endTS - e.startTS > toverlap &&
+        d.startTS + toverlap < e.endTS && e.startTS + toverlap < d.endTS
+}
+
+fn same_cell(d: &GeolocationTime, e: &GeolocationTime, cell: f64) -> bool {
+    (d.lat / cell).floor() == (e.lat / cell).floor() && (d.lng / cell).floor() == (e.lng / cell).floor()
+}
+
+// Seconds both locations cover, 0 if they don't overlap.
+fn overlap_duration(d: &GeolocationTime, e: &GeolocationTime) -> i64 {
+    (i64::from(d.endTS.min(e.endTS)) - i64::from(d.startTS.max(e.startTS))).max(0)
+}
+
+impl Strategy {
+    // Adds to `results` the locations of `user_locations` that overlap with the infected locations of `other`,
+    // the locations of a single stored user. Expired records are only dropped on the next write, the ones
+    // ending before `cutoff` (see `tenants::retention_cutoff`) are skipped meanwhile.
+    pub fn match_locations(&self, user_locations: &[GeolocationTime], other: &[GeolocationTime], cutoff: Option<i64>,
+                           results: &mut Vec<GeolocationTime>) {
+        let infected: Vec<&GeolocationTime> = other.iter()
+            .filter(|e| e.testResult && cutoff.map_or(true, |cutoff| e.endTS as i64 >= cutoff))
+            .collect();
+        // We iterate over all locations and compare them with all locations from the user
+        for d in user_locations.iter() {
+            match *self {
+                Strategy::Radius { min_overlap, distance } => {
+                    for e in &infected {
+                        if overlap(d, e, min_overlap) && within(d, e, distance) {
+                            results.push(d.clone());
+                        }
+                    }
+                },
+                Strategy::Grid { cell, min_overlap } => {
+                    for e in &infected {
+                        if overlap(d, e, min_overlap) && same_cell(d, e, cell) {
+                            results.push(d.clone());
+                        }
+                    }
+                },
+                Strategy::Duration { distance, min_duration } => {
+                    let exposure: i64 = infected.iter()
+                        .filter(|e| within(d, e, distance))
+                        .map(|e| overlap_duration(d, e))
+                        .sum();
+                    if exposure >= i64::from(min_duration) {
+                        results.push(d.clone());
+                    }
+                },
+            }
+        }
+    }
+}
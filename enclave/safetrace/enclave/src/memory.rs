@@ -0,0 +1,125 @@
+use crate::chunks;
+use crate::data::Error;
+use crate::jobs;
+use crate::replay::Rejection;
+use crate::stats;
+use core::sync::atomic::{AtomicU64, Ordering};
+use enigma_tools_t::common::errors_t::{EnclaveError, FailedTaskError::InputError};
+use serde::Serialize;
+use std::{string::ToString, vec::Vec};
+
+// Accounting of what the enclave keeps on its heap from one ecall to the next: the chunk contexts being
+// filled and the store snapshots of the match jobs. Both are reserved against `BUDGET` before they're
+// allocated, so too many of them fail the ecall that asks for more instead of running the enclave out
+// of EPC, which pages (slowly) and then aborts. The rest of the heap is left to the work of each ecall.
+//
+// What the work of an ecall needs grows with the store it unseals, which is bounded by the capacity limits
+// (`set_capacity`), in records: a submission taking the store past the hard limit is refused as
+// `CapacityExceeded`, past the soft one the report says the enclave is near capacity.
+
+// HeapMaxSize of Enclave.config.xml, keep them in line
+pub const HEAP_MAX_SIZE: u64 = 0x4000_0000;
+pub const BUDGET: u64 = HEAP_MAX_SIZE / 4;
+
+static IN_USE: AtomicU64 = AtomicU64::new(0);
+static PEAK: AtomicU64 = AtomicU64::new(0);
+// 0 is no limit
+static SOFT_RECORDS: AtomicU64 = AtomicU64::new(0);
+static HARD_RECORDS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MemoryUsage {
+    pub in_use: u64,
+    // The most `in_use` was since the enclave started
+    pub peak: u64,
+    pub budget: u64,
+    pub heap_max: u64,
+    pub chunk_contexts: u64,
+    pub match_jobs: u64,
+    pub users: u64,
+    pub records: u64,
+    // `in_use` and the store an ecall unseals, about what the heap holds at its fullest
+    pub heap_estimate: u64,
+    pub soft_limit: u64,
+    pub hard_limit: u64,
+    pub near_capacity: bool,
+}
+
+pub fn set_capacity(soft: u64, hard: u64) -> Result<(), EnclaveError> {
+    if hard > 0 && soft > hard {
+        return Err(EnclaveError::FailedTaskError(InputError { message: "the soft capacity limit can't be above the hard one".to_string() }));
+    }
+    SOFT_RECORDS.store(soft, Ordering::SeqCst);
+    HARD_RECORDS.store(hard, Ordering::SeqCst);
+    Ok(())
+}
+
+// Whether a write may take the store from `before` to `after` records. One that doesn't grow it always
+// may, the users can still replace or delete their locations once the store is full.
+pub fn check_capacity(before: u64, after: u64) -> Result<(), Rejection> {
+    let hard = HARD_RECORDS.load(Ordering::SeqCst);
+    if hard > 0 && after > hard && after > before {
+        return Err(Rejection::CapacityExceeded);
+    }
+    Ok(())
+}
+
+pub fn reserve(bytes: u64) -> Result<(), EnclaveError> {
+    let mut current = IN_USE.load(Ordering::SeqCst);
+    loop {
+        let next = current.saturating_add(bytes);
+        if next > BUDGET {
+            return Err(EnclaveError::FailedTaskError(InputError {
+                message: "the enclave is out of memory for inputs and jobs, try again later".to_string() }));
+        }
+        match IN_USE.compare_exchange(current, next, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => {
+                raise_peak(next);
+                return Ok(());
+            },
+            Err(actual) => current = actual,
+        }
+    }
+}
+
+fn raise_peak(bytes: u64) {
+    let mut peak = PEAK.load(Ordering::SeqCst);
+    while peak < bytes {
+        match PEAK.compare_exchange(peak, bytes, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => return,
+            Err(actual) => peak = actual,
+        }
+    }
+}
+
+pub fn release(bytes: u64) {
+    let mut current = IN_USE.load(Ordering::SeqCst);
+    loop {
+        match IN_USE.compare_exchange(current, current.saturating_sub(bytes), Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => return,
+            Err(actual) => current = actual,
+        }
+    }
+}
+
+pub fn get_memory_usage_internal() -> Result<Vec<u8>, EnclaveError> {
+    let (users, records, store_bytes) = stats::totals()?;
+    let in_use = IN_USE.load(Ordering::SeqCst);
+    let soft_limit = SOFT_RECORDS.load(Ordering::SeqCst);
+    let usage = MemoryUsage {
+        in_use,
+        peak: PEAK.load(Ordering::SeqCst),
+        budget: BUDGET,
+        heap_max: HEAP_MAX_SIZE,
+        chunk_contexts: chunks::count(),
+        match_jobs: jobs::count(),
+        users,
+        records,
+        heap_estimate: in_use.saturating_add(store_bytes),
+        soft_limit,
+        hard_limit: HARD_RECORDS.load(Ordering::SeqCst),
+        near_capacity: soft_limit > 0 && records > soft_limit,
+    };
+    Ok(serde_json::to_vec(&usage).map_err(|_| Error::SerializeError)?)
+}
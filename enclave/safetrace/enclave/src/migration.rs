@@ -0,0 +1,65 @@
+use crate::data::{load_sealed_data, recover_sealeddata_for_serializable, seal_data_wrapper, unseal_data_wrapper,
+                  Error, GeolocationTime, SEAL_LOG_SIZE};
+use enigma_tools_t::common::errors_t::{EnclaveError, FailedTaskError::InputError};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::{str, string::{String, ToString}, vec::Vec};
+
+// Location records as stored by the enigma-core based prototypes, before the test result
+// was recorded per location. Those submissions predate self-reporting, so they are migrated as negative.
+#[derive(Deserialize, Clone, Debug)]
+struct LegacyGeolocationTime {
+    lat: f64,
+    lng: f64,
+    startTS: i32,
+    endTS: i32,
+}
+
+impl From<LegacyGeolocationTime> for GeolocationTime {
+    fn from(legacy: LegacyGeolocationTime) -> Self {
+        GeolocationTime { lat: legacy.lat, lng: legacy.lng, startTS: legacy.startTS, endTS: legacy.endTS, testResult: false }
+    }
+}
+
+pub struct MigrationReport {
+    pub users: u64,
+    pub records: u64,
+}
+
+// Merges a legacy sealed data file into the current store.
+// Users that already exist in the current store are kept as they are, as their data is more recent.
+// The legacy blobs are sealed with the MRSIGNER policy, so any enclave signed with the same key can read them.
+pub(crate) fn migrate_legacy_data_internal(legacy_path: &str) -> Result<MigrationReport, EnclaveError> {
+    let mut sealed_log = [0u8; SEAL_LOG_SIZE];
+    load_sealed_data(legacy_path, &mut sealed_log)
+        .map_err(|e| EnclaveError::FailedTaskError(InputError { message: e }))?;
+    let legacy: HashMap<String, Vec<LegacyGeolocationTime>> =
+        recover_sealeddata_for_serializable(sealed_log.as_mut_ptr(), SEAL_LOG_SIZE as u32)?;
+
+    let mut data = unseal_data_wrapper()?;
+    let mut migrated = HashMap::new();
+    for (userid, locations) in legacy {
+        if !data.contains_key(&userid) {
+            let locations: Vec<GeolocationTime> = locations.into_iter().map(GeolocationTime::from).collect();
+            migrated.insert(userid.clone(), locations.len());
+            data.insert(userid, locations);
+        }
+    }
+    seal_data_wrapper(data)?;
+
+    // Verification pass: read the new store back and check every migrated user made it in full
+    let data = unseal_data_wrapper()?;
+    for (userid, count) in migrated.iter() {
+        if data.get(userid).map(Vec::len) != Some(*count) {
+            return Err(Error::Other.into());
+        }
+    }
+
+    debug_println!("Migrated {} users from {}", migrated.len(), legacy_path);
+    Ok(MigrationReport { users: migrated.len() as u64, records: migrated.values().sum::<usize>() as u64 })
+}
+
+pub(crate) fn parse_path(path: &[u8]) -> Result<&str, EnclaveError> {
+    str::from_utf8(path)
+        .map_err(|_| EnclaveError::FailedTaskError(InputError { message: "the legacy path is not valid UTF-8".to_string() }))
+}
@@ -0,0 +1,42 @@
+use core::sync::atomic::{AtomicUsize, Ordering};
+use enigma_tools_t::common::errors_t::{EnclaveError, FailedTaskError::InputError};
+use std::{string::ToString, vec::Vec};
+
+// Response padding.
+// Encrypted outputs leak their plaintext length, so e.g. an empty match result ("[]") is easy to tell
+// apart from a positive one. When a bucket size is set for a class of outputs, their plaintext is padded
+// with trailing whitespace up to the next multiple of the bucket before encryption. All the padded
+// plaintexts are JSON, which ignores trailing whitespace, so clients don't need to strip anything.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PaddingClass {
+    // Results encrypted for the user (`findMatch`, `findMatchFederated`)
+    Matching = 0,
+    // Queries and answers exchanged with federation peers
+    Federation = 1,
+}
+
+// Bucket size per class, in bytes. 0 disables padding.
+static BUCKETS: [AtomicUsize; 2] = [AtomicUsize::new(0), AtomicUsize::new(0)];
+
+impl PaddingClass {
+    pub fn from_u8(class: u8) -> Result<Self, EnclaveError> {
+        match class {
+            0 => Ok(PaddingClass::Matching),
+            1 => Ok(PaddingClass::Federation),
+            _ => Err(EnclaveError::FailedTaskError(InputError { message: "unknown padding class".to_string() })),
+        }
+    }
+}
+
+pub fn set_bucket(class: PaddingClass, bucket: usize) {
+    BUCKETS[class as usize].store(bucket, Ordering::SeqCst);
+}
+
+pub fn pad(class: PaddingClass, mut plaintext: Vec<u8>) -> Vec<u8> {
+    let bucket = BUCKETS[class as usize].load(Ordering::SeqCst);
+    if bucket > 0 {
+        let padded = ((plaintext.len() + bucket - 1) / bucket).max(1) * bucket;
+        plaintext.resize(padded, b' ');
+    }
+    plaintext
+}
@@ -0,0 +1,108 @@
+use crate::data::{DISTANCE, TOVERLAP};
+use crate::policy;
+use crate::settings;
+use crate::time_t;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use enigma_tools_t::common::errors_t::{EnclaveError, FailedTaskError::InputError};
+use std::string::ToString;
+
+// Matching thresholds and retention, set by the host from its configuration at startup.
+// They default to the constants of `data`, and retention to keeping everything. Once a signed policy is
+// active (`policy`), it alone sets the thresholds and the retention: the host's are refused. The host sets
+// them, and the quantization, once, before the quotes commit to them (see `settings`).
+
+// Minimum time overlap of two locations, in seconds.
+static MIN_OVERLAP: AtomicU64 = AtomicU64::new(TOVERLAP as u64);
+// Maximum distance between two locations, in meters, stored as the bits of an f64. 0 means `DISTANCE`.
+static MAX_DISTANCE: AtomicU64 = AtomicU64::new(0);
+// Records ending more than `RETENTION` seconds ago are dropped, 0 keeps them forever.
+static RETENTION: AtomicU64 = AtomicU64::new(0);
+// Coordinates are rounded to a grid of `GRID` degrees (the bits of an f64) on ingest, 0 keeps them as sent.
+static GRID: AtomicU64 = AtomicU64::new(0);
+// Timestamps are widened to multiples of `TIME_BUCKET` seconds on ingest, 0 keeps them as sent.
+static TIME_BUCKET: AtomicU64 = AtomicU64::new(0);
+static SET: AtomicBool = AtomicBool::new(false);
+static QUANTIZATION_SET: AtomicBool = AtomicBool::new(false);
+
+fn invalid(message: &str) -> EnclaveError {
+    EnclaveError::FailedTaskError(InputError { message: message.to_string() })
+}
+
+fn validate(min_overlap: i32, distance: f64) -> Result<(), EnclaveError> {
+    if min_overlap < 0 || !distance.is_finite() || distance <= 0.0 {
+        return Err(invalid("invalid matching thresholds"));
+    }
+    Ok(())
+}
+
+pub fn set(min_overlap: i32, distance: f64, retention: u64) -> Result<(), EnclaveError> {
+    if policy::active_hash() != [0u8; 32] {
+        return Err(invalid("a signed policy sets the matching thresholds"));
+    }
+    validate(min_overlap, distance)?;
+    if settings::quoted() {
+        return Err(invalid("a quote committed to the matching thresholds already"));
+    }
+    if SET.swap(true, Ordering::SeqCst) {
+        return Err(invalid("the matching thresholds are already set"));
+    }
+    set_signed(min_overlap, distance, retention)
+}
+
+// The thresholds of a signed policy, see `policy::enforce`.
+pub(crate) fn set_signed(min_overlap: i32, distance: f64, retention: u64) -> Result<(), EnclaveError> {
+    validate(min_overlap, distance)?;
+    MIN_OVERLAP.store(min_overlap as u64, Ordering::SeqCst);
+    MAX_DISTANCE.store(distance.to_bits(), Ordering::SeqCst);
+    RETENTION.store(retention, Ordering::SeqCst);
+    Ok(())
+}
+
+pub fn min_overlap() -> i32 {
+    MIN_OVERLAP.load(Ordering::SeqCst) as i32
+}
+
+pub fn distance() -> f64 {
+    match MAX_DISTANCE.load(Ordering::SeqCst) {
+        0 => DISTANCE,
+        bits => f64::from_bits(bits),
+    }
+}
+
+// Seconds records are retained, 0 for ever.
+pub fn retention() -> u64 {
+    RETENTION.load(Ordering::SeqCst)
+}
+
+// Oldest `endTS` still retained, if there's a retention period.
+pub fn retention_cutoff() -> Result<Option<i64>, EnclaveError> {
+    match RETENTION.load(Ordering::SeqCst) {
+        0 => Ok(None),
+        retention => Ok(Some(time_t::now()?.saturating_sub(retention) as i64)),
+    }
+}
+
+// Less precise locations are harder to tie back to a home or a workplace. Both are applied before the
+// locations are sealed, so the precise ones never reach the disk.
+pub fn set_quantization(grid: f64, time_bucket: u64) -> Result<(), EnclaveError> {
+    if !grid.is_finite() || grid < 0.0 || grid > 1.0 || time_bucket > i32::max_value() as u64 {
+        return Err(invalid("invalid quantization"));
+    }
+    if settings::quoted() {
+        return Err(invalid("a quote committed to the quantization already"));
+    }
+    if QUANTIZATION_SET.swap(true, Ordering::SeqCst) {
+        return Err(invalid("the quantization is already set"));
+    }
+    GRID.store(grid.to_bits(), Ordering::SeqCst);
+    TIME_BUCKET.store(time_bucket, Ordering::SeqCst);
+    Ok(())
+}
+
+pub fn grid() -> f64 {
+    f64::from_bits(GRID.load(Ordering::SeqCst))
+}
+
+pub fn time_bucket() -> i32 {
+    TIME_BUCKET.load(Ordering::SeqCst) as i32
+}
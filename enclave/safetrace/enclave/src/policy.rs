@@ -0,0 +1,157 @@
+use crate::authority::decode_hex;
+use crate::data::{read_sealed_file, recover_sealeddata_for_serializable, save_sealed_data, seal_to_vec, Error};
+use crate::matching;
+use crate::params;
+use enigma_crypto::asymmetric::KeyPair;
+use enigma_tools_m::utils::LockExpectMutex;
+use enigma_tools_t::common::errors_t::{EnclaveError, FailedTaskError::InputError};
+use serde::{Deserialize, Serialize};
+use sgx_tcrypto::rsgx_sha256_slice;
+use std::{collections::BTreeMap, string::{String, ToString}, sync::SgxMutex, vec::Vec};
+
+// Signed policies.
+// The matching thresholds and the retention of the configuration are the host's say. A deployment may
+// instead have them set by documents signed with a policy key, whose SHA-256 is baked into the enclave at
+// build time (`SAFETRACE_POLICY_KEY_HASH`, hex of the hash of the 64 bytes public key) and so covered by
+// MRENCLAVE:
+//   {"version": 3, "issuedAt": 1589000000, "minOverlap": 300, "distance": 10.0, "retentionDays": 14,
+//    "strategy": {"algorithm": "duration", "distance": 5.0, "minDuration": 900}, "riskWeights": {"duration": 1.0}}
+// The signature (65 bytes) is over the document as sent. The fields left out keep their current value, the
+// risk weights are only published with the policy, for the apps scoring exposures. A document must carry a
+// higher version than the active one; it's sealed and applied again over the configuration after a restart.
+// The SHA-256 of the active document is part of the settings the quotes commit to, see `settings`.
+
+pub const POLICYFILE: &str = "policy.sealed";
+const POLICY_KEY_HASH: Option<&str> = option_env!("SAFETRACE_POLICY_KEY_HASH");
+const MAX_DOCUMENT_LEN: usize = 2048;
+const MAX_WEIGHTS: usize = 16;
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Document {
+    version: u64,
+    min_overlap: Option<i32>,
+    distance: Option<f64>,
+    retention_days: Option<u64>,
+    strategy: Option<serde_json::Value>,
+    #[serde(default)]
+    risk_weights: BTreeMap<String, f64>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Signed {
+    document: String,
+    signature: Vec<u8>,
+}
+
+// The version and the hash of the active document
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Active {
+    pub version: u64,
+    pub hash: [u8; 32],
+}
+
+lazy_static! {
+    static ref ACTIVE: SgxMutex<Active> = SgxMutex::new(Active::default());
+}
+
+fn invalid(message: &str) -> EnclaveError {
+    EnclaveError::FailedTaskError(InputError { message: message.to_string() })
+}
+
+fn load_signed() -> Result<Option<Signed>, Error> {
+    match read_sealed_file(POLICYFILE)? {
+        Some(mut sealed_log) => recover_sealeddata_for_serializable(sealed_log.as_mut_ptr(), sealed_log.len() as u32).map(Some),
+        None => Ok(None),
+    }
+}
+
+fn save_signed(signed: &Signed) -> Result<(), EnclaveError> {
+    let encoded = serde_json::to_vec(signed).map_err(|_| invalid("encoding the policy failed"))?;
+    let sealed_log = seal_to_vec(&encoded).map_err(|_| invalid("sealing the policy failed"))?;
+    save_sealed_data(POLICYFILE, &sealed_log);
+    Ok(())
+}
+
+// Checks the signature is of the policy key, and the fields.
+fn verify(document: &[u8], signature: &[u8]) -> Result<Document, EnclaveError> {
+    let expected = POLICY_KEY_HASH.and_then(decode_hex).ok_or_else(|| invalid("this enclave was built without a policy key"))?;
+    if document.len() > MAX_DOCUMENT_LEN {
+        return Err(invalid("the policy document is longer than 2048 bytes"));
+    }
+    if signature.len() != 65 {
+        return Err(invalid("the policy signature must be 65 bytes long"));
+    }
+    let mut sig = [0u8; 65];
+    sig.copy_from_slice(signature);
+    let signer = KeyPair::recover(document, sig)?;
+    let hash = rsgx_sha256_slice(&signer[..]).map_err(|_| invalid("hashing the policy key failed"))?;
+    if hash[..] != expected[..] {
+        return Err(invalid("unauthorized: the policy isn't signed by the policy key"));
+    }
+    let parsed: Document = serde_json::from_slice(document).map_err(|_| invalid("malformed policy document"))?;
+    let valid = parsed.min_overlap.map_or(true, |overlap| overlap >= 0)
+        && parsed.distance.map_or(true, |distance| distance.is_finite() && distance > 0.0)
+        && parsed.retention_days.map_or(true, |days| days <= 36500)
+        && parsed.risk_weights.len() <= MAX_WEIGHTS
+        && parsed.risk_weights.iter().all(|(name, weight)| name.len() <= 32 && weight.is_finite() && *weight >= 0.0);
+    if !valid {
+        return Err(invalid("invalid policy parameters"));
+    }
+    Ok(parsed)
+}
+
+// Sets the thresholds, the retention and the strategy the document names.
+fn enforce(document: &Document) -> Result<(), EnclaveError> {
+    let retention = document.retention_days.map_or_else(params::retention, |days| days * 24 * 3600);
+    params::set_signed(document.min_overlap.unwrap_or_else(params::min_overlap), document.distance.unwrap_or_else(params::distance), retention)?;
+    if let Some(strategy) = &document.strategy {
+        let encoded = serde_json::to_vec(strategy).map_err(|_| invalid("malformed matching strategy"))?;
+        matching::set_signed_default(&encoded)?;
+    }
+    Ok(())
+}
+
+fn activate(document: &[u8], version: u64) -> Result<Active, EnclaveError> {
+    let hash = rsgx_sha256_slice(document).map_err(|_| invalid("hashing the policy failed"))?;
+    let active = Active { version, hash };
+    *ACTIVE.lock_expect("Policy") = active;
+    Ok(active)
+}
+
+// Applies a signed document and seals it, refused unless its version is above the active one.
+pub fn apply(document: &[u8], signature: &[u8]) -> Result<Active, EnclaveError> {
+    let parsed = verify(document, signature)?;
+    let current = ACTIVE.lock_expect("Policy").version;
+    let sealed = load_signed().map_err(|_| invalid("the sealed policy doesn't read"))?
+        .and_then(|signed| verify(signed.document.as_bytes(), &signed.signature).ok())
+        .map_or(0, |document| document.version);
+    if parsed.version <= current.max(sealed) {
+        return Err(invalid("the policy version must be above the active one"));
+    }
+    let text = String::from_utf8(document.to_vec()).map_err(|_| invalid("malformed policy document"))?;
+    save_signed(&Signed { document: text, signature: signature.to_vec() })?;
+    enforce(&parsed)?;
+    activate(document, parsed.version)
+}
+
+// Applies the sealed document again, after the host set the policy of its configuration. A document this
+// enclave doesn't accept (another policy key) is ignored.
+pub fn restore() -> Result<Active, EnclaveError> {
+    let signed = match load_signed().map_err(|_| invalid("the sealed policy doesn't read"))? {
+        Some(signed) => signed,
+        None => return Ok(Active::default()),
+    };
+    match verify(signed.document.as_bytes(), &signed.signature) {
+        Ok(parsed) => {
+            enforce(&parsed)?;
+            activate(signed.document.as_bytes(), parsed.version)
+        },
+        Err(_) => Ok(Active::default()),
+    }
+}
+
+// SHA-256 of the active document, zeros without one.
+pub fn active_hash() -> [u8; 32] {
+    ACTIVE.lock_expect("Policy").hash
+}
@@ -0,0 +1,244 @@
+use crate::authority::decode_hex;
+use crate::data::{read_sealed_file, recover_sealeddata, save_sealed_data, seal_to_vec, Error};
+use crate::freshness::OPERATORS;
+use crate::records::Freshness;
+use crate::time_t;
+use enigma_crypto::asymmetric::KeyPair;
+use enigma_tools_m::utils::LockExpectMutex;
+use enigma_tools_t::common::errors_t::{EnclaveError, FailedTaskError::InputError};
+use serde::{Deserialize, Serialize};
+use sgx_tcrypto::rsgx_sha256_slice;
+use std::{string::{String, ToString}, sync::SgxMutex, vec::Vec};
+
+// Operator quorum.
+// The operations that destroy the records or hand the signing key over (`Purge`, `Upgrade`) shouldn't be up to
+// a single operator. The enclave seals the operator keys it was first given, with the number of them that must
+// sign such an operation, and only runs it after `authorize` checked that many signatures over the admin
+// payload:
+//   {"operators": [{"key": "<64 bytes hex>", "tenant": null}, ...], "quorum": 2}
+// An operator with a `tenant` only counts towards purging that tenant. A host configuring other operators
+// doesn't replace the sealed ones: they wait in `PENDING` until a quorum of the sealed ones signs a
+// `SetOperators` naming their digest. Nothing is checked while no operator was ever registered.
+//
+// The sealed operators are protected against rollback like the store (`freshness`): a file older than the one
+// sealed last, or one gone missing once operators were sealed, is refused rather than read as none, which would
+// let the host register its own. Across restarts that takes `enclave.monotonic_counter`.
+//
+// This is about what the enclave does on its own say. Whoever holds the machine can still delete its files.
+
+pub const OPERATORSFILE: &str = "operators.sealed";
+pub const MAX_OPERATORS: usize = 16;
+// How far the timestamp of a payload may be from the enclave clock, and how long a grant waits for its ecall
+const MAX_SKEW: u64 = 300;
+const GRANT_TTL: u64 = 60;
+
+// What `register` did with the operators of the host
+pub const REGISTERED: u8 = 0;
+pub const UNCHANGED: u8 = 1;
+pub const PENDING_ADOPTION: u8 = 2;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Operator {
+    pub key: String,
+    pub tenant: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Default)]
+pub struct Registry {
+    pub operators: Vec<Operator>,
+    pub quorum: u32,
+}
+
+// As sealed. The operators sealed before `generation` was recorded are the bare `Registry`, at generation 0
+#[derive(Serialize, Deserialize)]
+struct Sealed {
+    generation: u64,
+    counter: Option<([u8; 16], u32)>,
+    registry: Registry,
+}
+
+#[derive(Deserialize, Debug)]
+struct Payload {
+    timestamp: u64,
+    nonce: String,
+    #[serde(default)]
+    tenant: Option<String>,
+    op: String,
+    // Of the operators a `SetOperators` adopts
+    #[serde(default)]
+    digest: Option<String>,
+}
+
+// The operation a quorum approved, for the next ecall that needs one
+struct Grant {
+    op: String,
+    tenant: Option<String>,
+    digest: Option<String>,
+    expires: u64,
+}
+
+lazy_static! {
+    // None until read from the sealed file
+    static ref REGISTRY: SgxMutex<Option<Registry>> = SgxMutex::new(None);
+    static ref PENDING: SgxMutex<Option<Registry>> = SgxMutex::new(None);
+    static ref GRANT: SgxMutex<Option<Grant>> = SgxMutex::new(None);
+    // The nonces of the payloads authorized within `MAX_SKEW`, with their timestamp
+    static ref SEEN: SgxMutex<Vec<(String, u64)>> = SgxMutex::new(Vec::new());
+}
+
+fn invalid(message: &str) -> EnclaveError {
+    EnclaveError::FailedTaskError(InputError { message: message.to_string() })
+}
+
+fn checked(freshness: &Freshness) -> Result<(), EnclaveError> {
+    OPERATORS.check(freshness).map_err(|e| match e {
+        Error::RolledBack { generation, floor } => invalid(&format!(
+            "the sealed operators are at generation {}, the enclave has seen generation {}: they were rolled back", generation, floor)),
+        e => e.into(),
+    })
+}
+
+fn load_registry() -> Result<Registry, EnclaveError> {
+    let mut sealed_log = match read_sealed_file(OPERATORSFILE)? {
+        Some(sealed_log) => sealed_log,
+        None => {
+            checked(&Freshness::default())?;
+            return Ok(Registry::default());
+        },
+    };
+    let encoded = recover_sealeddata(sealed_log.as_mut_ptr(), sealed_log.len() as u32)?;
+    let sealed = match serde_json::from_slice::<Sealed>(&encoded) {
+        Ok(sealed) => sealed,
+        Err(_) => Sealed { generation: 0, counter: None, registry: serde_json::from_slice(&encoded).map_err(|_| Error::SerializeError)? },
+    };
+    checked(&Freshness { generation: sealed.generation, counter: sealed.counter })?;
+    Ok(sealed.registry)
+}
+
+fn save_registry(registry: &Registry) -> Result<(), EnclaveError> {
+    let freshness = OPERATORS.next()?;
+    let sealed = Sealed { generation: freshness.generation, counter: freshness.counter, registry: registry.clone() };
+    let encoded = serde_json::to_vec(&sealed).map_err(|_| invalid("encoding the operators failed"))?;
+    let sealed_log = seal_to_vec(&encoded).map_err(|_| invalid("sealing the operators failed"))?;
+    save_sealed_data(OPERATORSFILE, &sealed_log);
+    Ok(OPERATORS.sealed(&freshness)?)
+}
+
+fn with_registry<T, F: FnOnce(&Registry) -> T>(f: F) -> Result<T, EnclaveError> {
+    let mut current = REGISTRY.lock_expect("Operators");
+    if current.is_none() {
+        *current = Some(load_registry()?);
+    }
+    Ok(f(current.as_ref().expect("operators loaded")))
+}
+
+fn validate(registry: &Registry) -> Result<(), EnclaveError> {
+    if registry.operators.len() > MAX_OPERATORS {
+        return Err(invalid("at most 16 operators"));
+    }
+    if registry.operators.iter().any(|operator| decode_hex(&operator.key).map(|key| key.len()) != Some(64)) {
+        return Err(invalid("operator keys must be 64 bytes, hex"));
+    }
+    if !registry.operators.is_empty() && (registry.quorum == 0 || registry.quorum as usize > registry.operators.len()) {
+        return Err(invalid("the quorum must be between 1 and the number of operators"));
+    }
+    Ok(())
+}
+
+// SHA-256 of the operators as encoded, hex: what a `SetOperators` names.
+pub fn digest(registry: &Registry) -> Result<String, EnclaveError> {
+    let encoded = serde_json::to_vec(registry).map_err(|_| invalid("encoding the operators failed"))?;
+    let hash = rsgx_sha256_slice(&encoded).map_err(|_| invalid("hashing the operators failed"))?;
+    Ok(hash.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+// The operators of the host, the JSON `Registry`: sealed if none were, else kept for `adopt` when they differ.
+pub fn register(encoded: &[u8]) -> Result<u8, EnclaveError> {
+    let proposed: Registry = serde_json::from_slice(encoded).map_err(|_| invalid("malformed operator list"))?;
+    validate(&proposed)?;
+    let sealed = with_registry(Registry::clone)?;
+    if sealed == proposed {
+        return Ok(UNCHANGED);
+    }
+    if sealed.operators.is_empty() {
+        save_registry(&proposed)?;
+        *REGISTRY.lock_expect("Operators") = Some(proposed);
+        return Ok(REGISTERED);
+    }
+    *PENDING.lock_expect("Pending Operators") = Some(proposed);
+    Ok(PENDING_ADOPTION)
+}
+
+// Checks `signatures` (65 bytes each) over the admin `payload`: signed by at least the quorum of distinct
+// operators allowed the operation, recently and only once. The operation is then granted to the next ecall
+// needing it. Returns how many operators signed.
+pub fn authorize(payload: &[u8], signatures: &[u8]) -> Result<u64, EnclaveError> {
+    let registry = with_registry(Registry::clone)?;
+    if registry.operators.is_empty() {
+        return Ok(0);
+    }
+    let request: Payload = serde_json::from_slice(payload).map_err(|_| invalid("malformed admin payload"))?;
+    let now = time_t::now()?;
+    if request.timestamp.saturating_add(MAX_SKEW) < now || request.timestamp > now + MAX_SKEW {
+        return Err(invalid("the timestamp of the admin payload is too far from the enclave clock"));
+    }
+    if signatures.is_empty() || signatures.len() % 65 != 0 {
+        return Err(invalid("the operator signatures must be 65 bytes each"));
+    }
+    let mut signers: Vec<usize> = Vec::new();
+    for chunk in signatures.chunks_exact(65) {
+        let mut signature = [0u8; 65];
+        signature.copy_from_slice(chunk);
+        let signer = KeyPair::recover(payload, signature)?;
+        let operator = registry.operators.iter().position(|operator| {
+            let allowed = match &operator.tenant {
+                None => true,
+                Some(tenant) => request.op == "Purge" && request.tenant.as_ref() == Some(tenant),
+            };
+            allowed && decode_hex(&operator.key).map_or(false, |key| key[..] == signer[..])
+        });
+        match operator {
+            Some(operator) if !signers.contains(&operator) => signers.push(operator),
+            Some(_) => return Err(invalid("an operator signed twice")),
+            None => return Err(invalid("unauthorized: a signer isn't an operator allowed this operation")),
+        }
+    }
+    if (signers.len() as u32) < registry.quorum {
+        return Err(invalid(&format!("unauthorized: {} of the {} operator signatures needed", signers.len(), registry.quorum)));
+    }
+    let mut seen = SEEN.lock_expect("Admin Nonces");
+    seen.retain(|&(_, timestamp)| timestamp.saturating_add(MAX_SKEW) >= now);
+    if seen.iter().any(|(nonce, _)| *nonce == request.nonce) {
+        return Err(invalid("the nonce of the admin payload was already used"));
+    }
+    seen.push((request.nonce, request.timestamp));
+    *GRANT.lock_expect("Operator Grant") = Some(Grant { op: request.op, tenant: request.tenant, digest: request.digest, expires: now + GRANT_TTL });
+    Ok(signers.len() as u64)
+}
+
+// Takes the grant of `op` for `tenant`, refused if a quorum didn't just approve that very operation.
+pub fn take(op: &str, tenant: Option<&str>, digest: Option<&str>) -> Result<(), EnclaveError> {
+    if with_registry(|registry| registry.operators.is_empty())? {
+        return Ok(());
+    }
+    let grant = GRANT.lock_expect("Operator Grant").take();
+    let now = time_t::now()?;
+    let granted = grant.map_or(false, |grant| {
+        grant.op == op && grant.tenant.as_ref().map(String::as_str) == tenant
+            && (digest.is_none() || grant.digest.as_ref().map(String::as_str) == digest) && now <= grant.expires
+    });
+    if !granted {
+        return Err(invalid(&format!("unauthorized: {} needs a quorum of operator signatures", op)));
+    }
+    Ok(())
+}
+
+// Seals the operators the host proposed in place of the current ones, once a quorum of those approved them.
+pub fn adopt() -> Result<Registry, EnclaveError> {
+    let pending = PENDING.lock_expect("Pending Operators").clone().ok_or_else(|| invalid("the host proposed no other operators"))?;
+    take("SetOperators", None, Some(&digest(&pending)?))?;
+    save_registry(&pending)?;
+    *REGISTRY.lock_expect("Operators") = Some(pending.clone());
+    *PENDING.lock_expect("Pending Operators") = None;
+    Ok(pending)
+}
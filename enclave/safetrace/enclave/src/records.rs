@@ -0,0 +1,268 @@
+use crate::data::{Error, GeolocationTime};
+use std::{collections::HashMap, str, string::{String, ToString}, vec::Vec};
+
+// The encoding of the sealed store: the protobuf `Store` of records.proto, with its schema version in
+// front. `decode` reads every version up to `SCHEMA_VERSION` into the records of today, so a store an
+// older enclave sealed stays readable after an upgrade, and refuses a store of a later version (an
+// enclave rolled back after a newer one sealed it) rather than misreading it. Version 1 is the JSON map
+// the enclave sealed before, told apart by its first byte: a `Store` starts with the tag of `version`.
+//
+// A schema change adds a version: `SCHEMA_VERSION` goes up, the decoder of the previous version stays,
+// converting its records the way the migration needs.
+//
+// The `Store` also carries its freshness (`freshness`), optional fields a store of before doesn't have.
+// The deltas of the log (`sealed_log`) are `Store`s too, of the users a write changed, with the ids of the
+// users it removed in `removed`.
+
+pub const SCHEMA_VERSION: u32 = 2;
+
+// Wire types
+const VARINT: u8 = 0;
+const FIXED64: u8 = 1;
+const LENGTH_DELIMITED: u8 = 2;
+const FIXED32: u8 = 5;
+
+fn put_varint(out: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn put_key(out: &mut Vec<u8>, field: u32, wire_type: u8) {
+    put_varint(out, u64::from(field << 3 | u32::from(wire_type)));
+}
+
+fn put_bytes(out: &mut Vec<u8>, field: u32, bytes: &[u8]) {
+    put_key(out, field, LENGTH_DELIMITED);
+    put_varint(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn put_double(out: &mut Vec<u8>, field: u32, value: f64) {
+    put_key(out, field, FIXED64);
+    out.extend_from_slice(&value.to_bits().to_le_bytes());
+}
+
+// int32 is sign extended to 64 bits on the wire
+fn put_int32(out: &mut Vec<u8>, field: u32, value: i32) {
+    put_key(out, field, VARINT);
+    put_varint(out, i64::from(value) as u64);
+}
+
+// Which seal of the store this is, for the rollback protection of `freshness`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Freshness {
+    // Goes up by one with every seal, 0 for the stores sealed before it was recorded
+    pub generation: u64,
+    // The SGX monotonic counter the store is bound to, by its uuid, and the value it has once the store is
+    // written
+    pub counter: Option<([u8; 16], u32)>,
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn is_empty(&self) -> bool { self.bytes.is_empty() }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if len > self.bytes.len() {
+            return Err(Error::SerializeError);
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn varint(&mut self) -> Result<u64, Error> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = self.take(1)?[0];
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(Error::SerializeError)
+    }
+
+    fn key(&mut self) -> Result<(u32, u8), Error> {
+        let key = self.varint()?;
+        if key > u64::from(u32::max_value()) {
+            return Err(Error::SerializeError);
+        }
+        Ok(((key >> 3) as u32, (key & 0x7) as u8))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], Error> {
+        let len = self.varint()?;
+        if len > self.bytes.len() as u64 {
+            return Err(Error::SerializeError);
+        }
+        self.take(len as usize)
+    }
+
+    fn double(&mut self) -> Result<f64, Error> {
+        let mut bits = [0u8; 8];
+        bits.copy_from_slice(self.take(8)?);
+        Ok(f64::from_bits(u64::from_le_bytes(bits)))
+    }
+
+    // The fields a later minor change may add are skipped, like protobuf does
+    fn skip(&mut self, wire_type: u8) -> Result<(), Error> {
+        match wire_type {
+            VARINT => self.varint().map(|_| ()),
+            FIXED64 => self.take(8).map(|_| ()),
+            LENGTH_DELIMITED => self.bytes().map(|_| ()),
+            FIXED32 => self.take(4).map(|_| ()),
+            _ => Err(Error::SerializeError),
+        }
+    }
+}
+
+fn encode_location(location: &GeolocationTime) -> Vec<u8> {
+    let mut out = Vec::with_capacity(40);
+    put_double(&mut out, 1, location.lat);
+    put_double(&mut out, 2, location.lng);
+    put_int32(&mut out, 3, location.startTS);
+    put_int32(&mut out, 4, location.endTS);
+    if location.testResult {
+        put_key(&mut out, 5, VARINT);
+        put_varint(&mut out, 1);
+    }
+    out
+}
+
+// The locations of a user, the fields of its `User` but the id.
+pub fn encode_locations(locations: &[GeolocationTime]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for location in locations {
+        put_bytes(&mut out, 2, &encode_location(location));
+    }
+    out
+}
+
+// The users are sorted, the same store always gives the same bytes.
+pub fn encode(data: &HashMap<String, Vec<GeolocationTime>>, freshness: &Freshness) -> Vec<u8> {
+    let mut users: Vec<_> = data.iter().collect();
+    users.sort_by(|a, b| a.0.cmp(b.0));
+    let mut out = Vec::new();
+    put_key(&mut out, 1, VARINT);
+    put_varint(&mut out, u64::from(SCHEMA_VERSION));
+    if freshness.generation > 0 {
+        put_key(&mut out, 3, VARINT);
+        put_varint(&mut out, freshness.generation);
+    }
+    if let Some((uuid, value)) = freshness.counter {
+        let mut counter = Vec::with_capacity(24);
+        put_bytes(&mut counter, 1, &uuid);
+        put_key(&mut counter, 2, VARINT);
+        put_varint(&mut counter, u64::from(value));
+        put_bytes(&mut out, 4, &counter);
+    }
+    for (userid, locations) in users {
+        let mut user = Vec::new();
+        put_bytes(&mut user, 1, userid.as_bytes());
+        user.extend_from_slice(&encode_locations(locations));
+        put_bytes(&mut out, 2, &user);
+    }
+    out
+}
+
+// A delta: the users of `changed` as `encode` has them, and the ids of the `removed` ones (field 5).
+pub fn encode_delta(changed: &HashMap<String, Vec<GeolocationTime>>, removed: &[String], freshness: &Freshness) -> Vec<u8> {
+    let mut out = encode(changed, freshness);
+    for userid in removed {
+        put_bytes(&mut out, 5, userid.as_bytes());
+    }
+    out
+}
+
+fn decode_location(bytes: &[u8]) -> Result<GeolocationTime, Error> {
+    let mut location = GeolocationTime { lat: 0.0, lng: 0.0, startTS: 0, endTS: 0, testResult: false };
+    let mut reader = Reader { bytes };
+    while !reader.is_empty() {
+        match reader.key()? {
+            (1, FIXED64) => location.lat = reader.double()?,
+            (2, FIXED64) => location.lng = reader.double()?,
+            (3, VARINT) => location.startTS = reader.varint()? as i32,
+            (4, VARINT) => location.endTS = reader.varint()? as i32,
+            (5, VARINT) => location.testResult = reader.varint()? != 0,
+            (_, wire_type) => reader.skip(wire_type)?,
+        }
+    }
+    Ok(location)
+}
+
+fn decode_user(bytes: &[u8]) -> Result<(String, Vec<GeolocationTime>), Error> {
+    let mut userid = String::new();
+    let mut locations = Vec::new();
+    let mut reader = Reader { bytes };
+    while !reader.is_empty() {
+        match reader.key()? {
+            (1, LENGTH_DELIMITED) => userid = str::from_utf8(reader.bytes()?).map_err(|_| Error::SerializeError)?.to_string(),
+            (2, LENGTH_DELIMITED) => locations.push(decode_location(reader.bytes()?)?),
+            (_, wire_type) => reader.skip(wire_type)?,
+        }
+    }
+    Ok((userid, locations))
+}
+
+fn decode_counter(bytes: &[u8]) -> Result<([u8; 16], u32), Error> {
+    let (mut uuid, mut value) = (None, 0);
+    let mut reader = Reader { bytes };
+    while !reader.is_empty() {
+        match reader.key()? {
+            (1, LENGTH_DELIMITED) => {
+                let bytes = reader.bytes()?;
+                if bytes.len() != 16 {
+                    return Err(Error::SerializeError);
+                }
+                let mut id = [0u8; 16];
+                id.copy_from_slice(bytes);
+                uuid = Some(id);
+            },
+            (2, VARINT) => value = reader.varint()? as u32,
+            (_, wire_type) => reader.skip(wire_type)?,
+        }
+    }
+    Ok((uuid.ok_or(Error::SerializeError)?, value))
+}
+
+// The unsealed store of any version this enclave knows.
+pub fn decode(bytes: &[u8]) -> Result<(HashMap<String, Vec<GeolocationTime>>, Freshness), Error> {
+    decode_delta(bytes).map(|(data, _, freshness)| (data, freshness))
+}
+
+// `decode`, with the ids a delta removed.
+pub fn decode_delta(bytes: &[u8]) -> Result<(HashMap<String, Vec<GeolocationTime>>, Vec<String>, Freshness), Error> {
+    // Version 1
+    if bytes.first() == Some(&b'{') {
+        let data = serde_json::from_slice(bytes).map_err(|_| Error::SerializeError)?;
+        return Ok((data, Vec::new(), Freshness::default()));
+    }
+    let mut version = 0;
+    let mut users = Vec::new();
+    let mut removed = Vec::new();
+    let mut freshness = Freshness::default();
+    let mut reader = Reader { bytes };
+    while !reader.is_empty() {
+        match reader.key()? {
+            (1, VARINT) => version = reader.varint()?,
+            (2, LENGTH_DELIMITED) => users.push(reader.bytes()?),
+            (3, VARINT) => freshness.generation = reader.varint()?,
+            (4, LENGTH_DELIMITED) => freshness.counter = Some(decode_counter(reader.bytes()?)?),
+            (5, LENGTH_DELIMITED) => removed.push(str::from_utf8(reader.bytes()?).map_err(|_| Error::SerializeError)?.to_string()),
+            (_, wire_type) => reader.skip(wire_type)?,
+        }
+    }
+    match version {
+        2 => Ok((users.into_iter().map(decode_user).collect::<Result<_, _>>()?, removed, freshness)),
+        v if v > u64::from(SCHEMA_VERSION) => Err(Error::UnsupportedVersion(v)),
+        // 1 is never a `Store`, 0 is a missing version
+        _ => Err(Error::SerializeError),
+    }
+}
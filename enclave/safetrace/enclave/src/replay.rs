@@ -0,0 +1,91 @@
+use crate::time_t;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use enigma_tools_t::common::errors_t::{EnclaveError, FailedTaskError::InputError};
+use enigma_tools_m::utils::LockExpectMutex;
+use std::{collections::HashMap, string::{String, ToString}, sync::SgxMutex};
+
+// Replay protection of `addPersonalData`.
+// The encrypted payload carries a nonce and the time the client built it:
+//   {"nonce": "0f3a...", "timestamp": 1589000000, "locations": [...]}
+// Both are encrypted with the task key, so the host can neither read nor change them. A payload older
+// (or further in the future) than `WINDOW` seconds is `Expired`, a nonce seen within the window is a
+// `Replay`. Nonces are forgotten once they're out of the window: their timestamp would be refused anyway.
+// The host sets the policy once, before the quotes commit to it (see `settings`).
+
+pub const MAX_NONCE_LEN: usize = 128;
+// Below it the clients' envelopes expire on their way, seconds
+pub const MIN_WINDOW: u64 = 60;
+
+// Seconds a payload stays valid, either side of our clock.
+static WINDOW: AtomicU64 = AtomicU64::new(300);
+// Whether the legacy payload, a bare list of locations without nonce, is refused.
+static REQUIRED: AtomicBool = AtomicBool::new(false);
+static SET: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    // nonce -> timestamp
+    static ref SEEN: SgxMutex<HashMap<String, u64>> = SgxMutex::new(HashMap::new());
+}
+
+// Told to the host (it's about the envelope, not the locations), values of `ecall_add_personal_data`'s `rejection`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum Rejection {
+    Replay = 1,
+    Expired = 2,
+    // Not about the envelope, but the host knows how full the store is anyway (see `memory`)
+    CapacityExceeded = 3,
+    // Locations later than the enclave's clock allows, see `clock`. The host learns the device's clock is off
+    ClockSkew = 4,
+}
+
+impl Rejection {
+    pub fn name(self) -> &'static str {
+        match self {
+            Rejection::Replay => "Replay",
+            Rejection::Expired => "Expired",
+            Rejection::CapacityExceeded => "CapacityExceeded",
+            Rejection::ClockSkew => "ClockSkew",
+        }
+    }
+}
+
+pub fn set(window: u64, required: bool) -> Result<(), EnclaveError> {
+    if window < MIN_WINDOW {
+        return Err(EnclaveError::FailedTaskError(InputError { message: "the replay window must be at least 60 seconds".to_string() }));
+    }
+    if SET.swap(true, Ordering::SeqCst) {
+        return Err(EnclaveError::FailedTaskError(InputError { message: "the replay policy is already set".to_string() }));
+    }
+    WINDOW.store(window, Ordering::SeqCst);
+    REQUIRED.store(required, Ordering::SeqCst);
+    Ok(())
+}
+
+pub fn required() -> bool {
+    REQUIRED.load(Ordering::SeqCst)
+}
+
+pub fn window() -> u64 {
+    WINDOW.load(Ordering::SeqCst)
+}
+
+// Accepts `nonce` once within the window. Only call it with a payload that decrypted: a nonce the host
+// made up would come with a key the enclave never gave out.
+pub fn check(nonce: &str, timestamp: u64) -> Result<Result<(), Rejection>, EnclaveError> {
+    if nonce.is_empty() || nonce.len() > MAX_NONCE_LEN {
+        return Err(EnclaveError::FailedTaskError(InputError { message: "the nonce must be between 1 and 128 characters".to_string() }));
+    }
+    let window = WINDOW.load(Ordering::SeqCst);
+    let now = time_t::now()?;
+    if timestamp.saturating_add(window) < now || timestamp > now.saturating_add(window) {
+        return Ok(Err(Rejection::Expired));
+    }
+    let mut seen = SEEN.lock_expect("Replay Nonces");
+    seen.retain(|_, &mut seen_at| seen_at.saturating_add(window) >= now);
+    if seen.contains_key(nonce) {
+        return Ok(Err(Rejection::Replay));
+    }
+    seen.insert(nonce.to_string(), timestamp);
+    Ok(Ok(()))
+}
@@ -0,0 +1,48 @@
+use crate::identity;
+use core::sync::atomic::{AtomicU64, Ordering};
+use enigma_tools_m::utils::LockExpectMutex;
+use enigma_tools_t::common::errors_t::{EnclaveError, FailedTaskError::InputError};
+use sgx_rand::{Rng, SgxRng};
+use std::{string::ToString, sync::SgxMutex};
+
+// The identity block of the IPC responses (`identity` in the client, which has the format). The host hands
+// over the digest of a response, the enclave signs it with the next value of its counter, under the boot id
+// it draws when it starts. Neither is sealed: a restarted enclave counts from 1 again, under another boot.
+
+const RESPONSE_TAG: &[u8] = b"safetrace-response";
+
+lazy_static! {
+    static ref BOOT: SgxMutex<Option<[u8; 16]>> = SgxMutex::new(None);
+}
+
+// The last counter signed
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+pub struct SignedResponse {
+    pub address: [u8; 20],
+    pub boot: [u8; 16],
+    pub counter: u64,
+    pub signature: [u8; 65],
+}
+
+fn boot() -> Result<[u8; 16], EnclaveError> {
+    let mut boot = BOOT.lock_expect("Response Boot");
+    if let Some(id) = *boot {
+        return Ok(id);
+    }
+    let mut id = [0u8; 16];
+    SgxRng::new().map_err(|_| EnclaveError::FailedTaskError(InputError { message: "no randomness".to_string() }))?.fill_bytes(&mut id);
+    *boot = Some(id);
+    Ok(id)
+}
+
+pub fn sign_response_internal(digest: &[u8; 32]) -> Result<SignedResponse, EnclaveError> {
+    let boot = boot()?;
+    let counter = COUNTER.fetch_add(1, Ordering::SeqCst) + 1;
+    let mut message = RESPONSE_TAG.to_vec();
+    message.extend_from_slice(&boot);
+    message.extend_from_slice(&counter.to_be_bytes());
+    message.extend_from_slice(digest);
+    let signature = identity::sign(&message)?;
+    Ok(SignedResponse { address: identity::address()?, boot, counter, signature })
+}
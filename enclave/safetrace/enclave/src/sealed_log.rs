@@ -0,0 +1,260 @@
+use crate::data::{recover_sealeddata, sealed_size, Error, GeolocationTime, DATAFILE, SEAL_LOG_SIZE};
+use crate::records::{self, Freshness};
+use core::sync::atomic::{AtomicU64, Ordering};
+use enigma_tools_m::utils::LockExpectMutex;
+use serde::Serialize;
+use sgx_tcrypto::rsgx_sha256_slice;
+use sgx_tseal::SgxSealedData;
+use sgx_types::sgx_sealed_data_t;
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::untrusted::fs::{self, File, OpenOptions};
+use std::{string::String, sync::SgxMutex, vec::Vec};
+
+// The file of the sealed store. Rather than sealing all of the store again on every write, `data.sealed` is
+// a log: a header naming the format, then entries appended one after the other, each sealed on its own
+//   header  "STLG", the format version (u32, little endian)
+//   entry   the length of the sealed blob (u32, little endian), the kind of entry (u8), the sealed blob
+// A snapshot holds the whole store (a `Store` of `records`), a delta the users a write changed and those it
+// removed. Reading replays the entries, each must be the generation after the one before it (`freshness`):
+// the host can't drop, reorder or splice them in without the read failing.
+//
+// Past `compact_after` deltas (`enclave.compact_after`, 0 compacts on every write) the store is written as a
+// single snapshot into `data.sealed.compact`, synced and renamed over the log: a crash leaves either log
+// whole. A crash while appending leaves a torn last entry, cut short or not unsealing, which reads skip as a
+// write that never happened and the next append cuts off; `safetrace-app fsck` reports it and compacts the
+// log without it. The single sealed blob of the enclaves before the log has no header: it's read as the
+// snapshot it is, and the next write turns it into a log.
+
+pub const MAGIC: &[u8; 4] = b"STLG";
+pub const FORMAT_VERSION: u32 = 1;
+const HEADER_LEN: usize = 8;
+const ENTRY_HEADER_LEN: usize = 5;
+const COMPACT_FILE: &str = "data.sealed.compact";
+
+const SNAPSHOT: u8 = 0;
+const DELTA: u8 = 1;
+
+static COMPACT_AFTER: AtomicU64 = AtomicU64::new(64);
+
+// Where the last read left the log, for the next write to append its delta to
+struct Tail {
+    // Bytes of the file, and where its last whole entry ends (before a torn one)
+    size: u64,
+    end: u64,
+    deltas: u64,
+    // Of the locations of each user, the delta holds the users whose digest changed
+    digests: HashMap<String, [u8; 32]>,
+}
+
+lazy_static! { static ref TAIL: SgxMutex<Option<Tail>> = SgxMutex::new(None); }
+
+pub fn set(compact_after: u64) {
+    COMPACT_AFTER.store(compact_after, Ordering::SeqCst);
+}
+
+// What `fsck` reports of the log.
+#[derive(Serialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Report {
+    // 0 for the sealed blob of before the log
+    pub format_version: u32,
+    pub entries: u64,
+    // Since the last snapshot
+    pub deltas: u64,
+    pub generation: u64,
+    pub users: u64,
+    pub records: u64,
+    pub bytes: u64,
+    // Bytes of the torn entry at the end, left by a write that didn't complete
+    pub torn_bytes: u64,
+    pub compacted: bool,
+}
+
+pub struct Log {
+    pub data: HashMap<String, Vec<GeolocationTime>>,
+    pub freshness: Freshness,
+    pub report: Report,
+}
+
+fn corrupt(offset: usize, reason: &'static str) -> Error {
+    Error::CorruptLog { offset: offset as u64, reason }
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    let mut le = [0u8; 4];
+    le.copy_from_slice(&bytes[offset..offset + 4]);
+    u32::from_le_bytes(le)
+}
+
+fn digest(locations: &[GeolocationTime]) -> Result<[u8; 32], Error> {
+    rsgx_sha256_slice(&records::encode_locations(locations)).map_err(|_| Error::Other)
+}
+
+fn digests(data: &HashMap<String, Vec<GeolocationTime>>) -> Result<HashMap<String, [u8; 32]>, Error> {
+    data.iter().map(|(userid, locations)| Ok((userid.clone(), digest(locations)?))).collect()
+}
+
+fn seal(kind: u8, plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+    let aad: [u8; 0] = [0_u8; 0];
+    let sealed = SgxSealedData::<[u8]>::seal_data(&aad, plaintext).map_err(|_| Error::Other)?;
+    let len = sealed_size(plaintext.len()) as usize;
+    let mut entry = vec![0u8; ENTRY_HEADER_LEN + len];
+    entry[..4].copy_from_slice(&(len as u32).to_le_bytes());
+    entry[4] = kind;
+    let blob = entry[ENTRY_HEADER_LEN..].as_mut_ptr() as *mut sgx_sealed_data_t;
+    unsafe { sealed.to_raw_sealed_data_t(blob, len as u32) }.ok_or(Error::SliceError)?;
+    Ok(entry)
+}
+
+fn read_file() -> Result<Option<Vec<u8>>, Error> {
+    let mut file = match File::open(DATAFILE) {
+        Ok(file) => file,
+        Err(_) => return Ok(None),
+    };
+    let mut bytes = Vec::new();
+    file.read_to_end(&mut bytes).map_err(|_| Error::WriteError("reading data.sealed failed"))?;
+    Ok(Some(bytes))
+}
+
+// The store of before the log, one sealed blob of `SEAL_LOG_SIZE` bytes.
+fn read_blob(mut bytes: Vec<u8>) -> Result<Log, Error> {
+    let size = bytes.len() as u64;
+    bytes.resize(SEAL_LOG_SIZE.max(bytes.len()), 0);
+    let (data, freshness) = records::decode(&recover_sealeddata(bytes.as_mut_ptr(), bytes.len() as u32)?)?;
+    let report = Report { entries: 1, generation: freshness.generation, bytes: size, ..Default::default() };
+    Ok(Log { data, freshness, report })
+}
+
+fn replay(bytes: &[u8]) -> Result<Log, Error> {
+    let version = u32_at(bytes, MAGIC.len());
+    if version > FORMAT_VERSION {
+        return Err(Error::UnsupportedFormat(version));
+    }
+    let mut data = HashMap::new();
+    let mut freshness = Freshness::default();
+    let mut report = Report { format_version: version, bytes: bytes.len() as u64, ..Default::default() };
+    let mut offset = HEADER_LEN;
+    while bytes.len() - offset >= ENTRY_HEADER_LEN {
+        let len = u32_at(bytes, offset) as usize;
+        let start = offset + ENTRY_HEADER_LEN;
+        if bytes.len() - start < len {
+            break;
+        }
+        let mut blob = bytes[start..start + len].to_vec();
+        let plaintext = match recover_sealeddata(blob.as_mut_ptr(), len as u32) {
+            Ok(plaintext) => plaintext,
+            // Written in full but not sealed in full, the same write cut short
+            Err(_) if start + len == bytes.len() => break,
+            Err(_) => return Err(corrupt(offset, "an entry doesn't unseal")),
+        };
+        let (users, removed, entry) = records::decode_delta(&plaintext)?;
+        if report.entries > 0 && entry.generation != freshness.generation + 1 {
+            return Err(corrupt(offset, "an entry isn't the generation after the one before it"));
+        }
+        match bytes[offset + 4] {
+            SNAPSHOT => {
+                data = users;
+                report.deltas = 0;
+            },
+            DELTA if report.entries > 0 => {
+                for userid in removed {
+                    data.remove(&userid);
+                }
+                data.extend(users);
+                report.deltas += 1;
+            },
+            DELTA => return Err(corrupt(offset, "the log doesn't start with a snapshot")),
+            _ => return Err(corrupt(offset, "unknown kind of entry")),
+        }
+        freshness = entry;
+        report.entries += 1;
+        offset = start + len;
+    }
+    report.torn_bytes = (bytes.len() - offset) as u64;
+    report.generation = freshness.generation;
+    Ok(Log { data, freshness, report })
+}
+
+// The store on disk, `None` if there's none yet. Remembers where the log ends for the next write.
+pub fn read() -> Result<Option<Log>, Error> {
+    let bytes = match read_file()? {
+        Some(bytes) => bytes,
+        None => {
+            *TAIL.lock_expect("Sealed Log") = None;
+            return Ok(None);
+        },
+    };
+    let mut log = if bytes.len() >= HEADER_LEN && &bytes[..MAGIC.len()] == MAGIC { replay(&bytes)? } else { read_blob(bytes)? };
+    log.report.users = log.data.len() as u64;
+    log.report.records = log.data.values().map(|locations| locations.len() as u64).sum();
+    // The blob of before is compacted into a log by the next write
+    let tail = match log.report.format_version {
+        0 => None,
+        _ => Some(Tail {
+            size: log.report.bytes,
+            end: log.report.bytes - log.report.torn_bytes,
+            deltas: log.report.deltas,
+            digests: digests(&log.data)?,
+        }),
+    };
+    *TAIL.lock_expect("Sealed Log") = tail;
+    Ok(Some(log))
+}
+
+fn file_size() -> Option<u64> {
+    fs::metadata(DATAFILE).ok().map(|metadata| metadata.len())
+}
+
+// Writes `data` as a log of a single snapshot, then renames it over the store.
+fn compact(data: &HashMap<String, Vec<GeolocationTime>>, freshness: &Freshness) -> Result<Tail, Error> {
+    let mut log = Vec::with_capacity(HEADER_LEN);
+    log.extend_from_slice(MAGIC);
+    log.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    log.extend_from_slice(&seal(SNAPSHOT, &records::encode(data, freshness))?);
+    let written = File::create(COMPACT_FILE)
+        .and_then(|mut file| file.write_all(&log).and_then(|_| file.sync_all()))
+        .and_then(|_| fs::rename(COMPACT_FILE, DATAFILE));
+    written.map_err(|_| Error::WriteError("writing data.sealed.compact failed"))?;
+    Ok(Tail { size: log.len() as u64, end: log.len() as u64, deltas: 0, digests: digests(data)? })
+}
+
+// Appends the changes since the last read as a delta, cutting off a torn entry first.
+fn append(tail: &Tail, data: &HashMap<String, Vec<GeolocationTime>>, freshness: &Freshness) -> Result<Tail, Error> {
+    let digests = digests(data)?;
+    let changed: HashMap<String, Vec<GeolocationTime>> = data.iter()
+        .filter(|(userid, _)| tail.digests.get(*userid) != digests.get(*userid))
+        .map(|(userid, locations)| (userid.clone(), locations.clone()))
+        .collect();
+    let mut removed: Vec<String> = tail.digests.keys().filter(|userid| !data.contains_key(*userid)).cloned().collect();
+    removed.sort();
+    let entry = seal(DELTA, &records::encode_delta(&changed, &removed, freshness))?;
+    let written = OpenOptions::new().write(true).open(DATAFILE).and_then(|mut file| {
+        file.set_len(tail.end)?;
+        file.seek(SeekFrom::Start(tail.end))?;
+        file.write_all(&entry)?;
+        file.sync_all()
+    });
+    written.map_err(|_| Error::WriteError("appending to data.sealed failed"))?;
+    let end = tail.end + entry.len() as u64;
+    Ok(Tail { size: end, end, deltas: tail.deltas + 1, digests })
+}
+
+// Writes the store the caller read and changed. The delta is against the last read: when the file changed
+// since (another worker wrote it) or there's no log yet, the store is compacted instead.
+pub fn write(data: &HashMap<String, Vec<GeolocationTime>>, freshness: &Freshness) -> Result<(), Error> {
+    let mut tail = TAIL.lock_expect("Sealed Log");
+    let next = match &*tail {
+        Some(last) if Some(last.size) == file_size() && last.deltas < COMPACT_AFTER.load(Ordering::SeqCst) => append(last, data, freshness)?,
+        _ => compact(data, freshness)?,
+    };
+    *tail = Some(next);
+    Ok(())
+}
+
+// Writes the store as a single snapshot whatever the log holds, for `fsck`.
+pub fn rewrite(data: &HashMap<String, Vec<GeolocationTime>>, freshness: &Freshness) -> Result<(), Error> {
+    let mut tail = TAIL.lock_expect("Sealed Log");
+    *tail = Some(compact(data, freshness)?);
+    Ok(())
+}
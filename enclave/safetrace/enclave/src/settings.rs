@@ -0,0 +1,75 @@
+use crate::{aggregates, authority, clock, data, export, identity, params, policy, replay, users};
+use crate::matching::{self, Strategy};
+use core::sync::atomic::{AtomicBool, Ordering};
+use enigma_tools_t::common::errors_t::EnclaveError;
+use serde::Serialize;
+use sgx_tcrypto::rsgx_sha256_slice;
+use std::{string::String, vec::Vec};
+
+// The settings the enclave enforces, whether from the configuration of the host or from a signed policy, as
+// the JSON document its quotes commit to: bytes 20 to 52 of the report data are its SHA-256 (see
+// `ecall_get_registration_quote`). A client verifying the report then knows the thresholds, the retention and
+// the rest it's trusting, not only the code. `GetEnclaveInfo` serves the document as hashed.
+
+const VERSION: u32 = 1;
+
+// Whether a quote committed to the settings already
+static QUOTED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+struct Settings {
+    version: u32,
+    // SHA-256 of the signed policy in force, see `policy`
+    policy: Option<String>,
+    min_overlap: i32,
+    distance: f64,
+    retention: u64,
+    strategy: Option<Strategy>,
+    grid: f64,
+    time_bucket: i32,
+    replay_window: u64,
+    require_replay_protection: bool,
+    max_clock_skew: u64,
+    require_registration: bool,
+    health_authorities: Vec<String>,
+    declaration_max_age: u64,
+    export_k: u64,
+    statistics_epsilon: f64,
+    // The curve of the signing key, see `identity`
+    signing_curve: &'static str,
+}
+
+pub fn document() -> Result<Vec<u8>, EnclaveError> {
+    let hash = policy::active_hash();
+    let settings = Settings {
+        version: VERSION,
+        policy: if hash == [0u8; 32] { None } else { Some(hash.iter().map(|b| format!("{:02x}", b)).collect()) },
+        min_overlap: params::min_overlap(),
+        distance: params::distance(),
+        retention: params::retention(),
+        strategy: matching::default(),
+        grid: params::grid(),
+        time_bucket: params::time_bucket(),
+        replay_window: replay::window(),
+        require_replay_protection: replay::required(),
+        max_clock_skew: clock::tolerance(),
+        require_registration: users::required(),
+        health_authorities: authority::keys(),
+        declaration_max_age: authority::max_age(),
+        export_k: export::k(),
+        statistics_epsilon: aggregates::epsilon(),
+        signing_curve: identity::name(),
+    };
+    Ok(serde_json::to_vec(&settings).map_err(|_| data::Error::SerializeError)?)
+}
+
+// SHA-256 of `document`, for the report data.
+pub fn digest() -> Result<[u8; 32], EnclaveError> {
+    QUOTED.store(true, Ordering::SeqCst);
+    Ok(rsgx_sha256_slice(&document()?).map_err(|_| data::Error::SerializeError)?)
+}
+
+pub fn quoted() -> bool {
+    QUOTED.load(Ordering::SeqCst)
+}
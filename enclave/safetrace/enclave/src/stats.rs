@@ -0,0 +1,86 @@
+use crate::aggregates;
+use crate::data::{self, GeolocationTime};
+use crate::records;
+use enigma_tools_t::common::errors_t::EnclaveError;
+use enigma_tools_m::utils::LockExpectMutex;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::{string::String, sync::SgxMutex, vec::Vec};
+
+// Records are rolled up by the epoch (UTC day) in which they start.
+pub const EPOCH_SECONDS: i32 = 86400;
+
+// Capacity planning figures. They only hold counts, never a user id or a location.
+#[derive(Serialize, Clone, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct EpochStats {
+    pub epoch: i32,
+    pub records: u64,
+    pub users: u64,
+    pub bytes: u64,
+}
+
+#[derive(Serialize, Clone, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Rollups {
+    pub epochs: Vec<EpochStats>,
+    pub records: u64,
+    pub users: u64,
+    pub bytes_sealed: u64,
+}
+
+lazy_static! {
+    // Refreshed every time the data store is sealed, so ingestion and purges both update it.
+    static ref ROLLUPS: SgxMutex<Option<Rollups>> = SgxMutex::new(None);
+}
+
+fn compute(data: &HashMap<String, Vec<GeolocationTime>>, bytes_sealed: u64) -> Rollups {
+    let mut epochs: BTreeMap<i32, (EpochStats, HashSet<&str>)> = BTreeMap::new();
+    let mut records = 0;
+    for (user, locations) in data {
+        for location in locations {
+            let epoch = location.startTS / EPOCH_SECONDS;
+            let entry = epochs.entry(epoch).or_insert_with(|| (EpochStats { epoch, ..Default::default() }, HashSet::new()));
+            entry.0.records += 1;
+            entry.0.bytes += serde_json::to_vec(location).map(|v| v.len() as u64).unwrap_or(0);
+            entry.1.insert(user);
+            records += 1;
+        }
+    }
+    let epochs = epochs.into_iter().map(|(_, (mut stats, users))| {
+        stats.users = users.len() as u64;
+        stats
+    }).collect();
+    Rollups { epochs, records, users: data.len() as u64, bytes_sealed }
+}
+
+// Called right after `data` got sealed into `bytes_sealed` bytes.
+pub fn refresh(data: &HashMap<String, Vec<GeolocationTime>>, bytes_sealed: u64) {
+    *ROLLUPS.lock_expect("Rollups") = Some(compute(data, bytes_sealed));
+    aggregates::invalidate();
+}
+
+fn rollups() -> Result<Rollups, EnclaveError> {
+    let mut rollups = ROLLUPS.lock_expect("Rollups");
+    Ok(match &*rollups {
+        Some(stats) => stats.clone(),
+        None => {
+            // Nothing was sealed since the enclave started, roll up what is on disk
+            let data = data::unseal_data_wrapper()?;
+            let stats = compute(&data, data::sealed_size(records::encode(&data, &Default::default()).len()));
+            *rollups = Some(stats.clone());
+            stats
+        },
+    })
+}
+
+// Users, records and sealed bytes of the store, for the capacity figures of `memory`.
+pub fn totals() -> Result<(u64, u64, u64), EnclaveError> {
+    let stats = rollups()?;
+    Ok((stats.users, stats.records, stats.bytes_sealed))
+}
+
+pub fn get_stats_internal() -> Result<Vec<u8>, EnclaveError> {
+    let stats = rollups()?;
+    Ok(serde_json::to_vec(&stats).map_err(|_| data::Error::SerializeError)?)
+}
@@ -0,0 +1,159 @@
+use crate::data::GeolocationTime;
+use crate::matching::Strategy;
+use crate::stats::EPOCH_SECONDS;
+use enigma_tools_m::utils::LockExpectMutex;
+use sgx_tcrypto::rsgx_sha256_slice;
+use std::collections::{BTreeMap, HashMap};
+use std::{string::String, sync::{Arc, SgxMutex}};
+
+// Summaries of the infected locations of each stored user: per UTC day (of the start of the locations), the box
+// of their coordinates and the span of their times. A query compares the user's locations with the summaries
+// first, and only runs the strategy on the stored users it may match: most infected tracks are elsewhere or
+// on other days, and no stored user without infected locations is looked at.
+//
+// The boxes only ever rule out what can't match: a stored user is skipped when no location of the user is
+// close enough, in latitude (and in grid cell for `grid`), to a box and overlaps its span. The index is built
+// for a generation of the store (`freshness`), when it's sealed and when an enclave unseals a generation it
+// didn't index, e.g. written by another worker.
+//
+// Each stored user also has the generation their infected locations last changed at, for the watermarks of
+// `incremental`: the SHA-256 of the locations is compared with the one of the previous index. Without a previous
+// index every user changed at the generation indexed.
+
+// Degrees of latitude are about 111 km apart, see `matching::within`
+const METERS_PER_DEGREE: f64 = 111000.0;
+
+#[derive(Clone, Debug)]
+struct DayBox {
+    min_lat: f64,
+    max_lat: f64,
+    min_lng: f64,
+    max_lng: f64,
+    start: i32,
+    end: i32,
+}
+
+#[derive(Clone, Debug)]
+struct Summary {
+    days: BTreeMap<i32, DayBox>,
+    // The end of the oldest infected location
+    first_end: i32,
+    digest: [u8; 32],
+    changed: u64,
+}
+
+pub struct Summaries {
+    generation: u64,
+    users: HashMap<String, Summary>,
+}
+
+lazy_static! { static ref INDEX: SgxMutex<Option<Arc<Summaries>>> = SgxMutex::new(None); }
+
+impl DayBox {
+    fn new(l: &GeolocationTime) -> Self {
+        DayBox { min_lat: l.lat, max_lat: l.lat, min_lng: l.lng, max_lng: l.lng, start: l.startTS, end: l.endTS }
+    }
+
+    fn extend(&mut self, l: &GeolocationTime) {
+        self.min_lat = self.min_lat.min(l.lat);
+        self.max_lat = self.max_lat.max(l.lat);
+        self.min_lng = self.min_lng.min(l.lng);
+        self.max_lng = self.max_lng.max(l.lng);
+        self.start = self.start.min(l.startTS);
+        self.end = self.end.max(l.endTS);
+    }
+
+    // Whether `d` may match a location of the box, as `strategy` compares them.
+    fn may_match(&self, d: &GeolocationTime, strategy: &Strategy) -> bool {
+        let (min_overlap, margin) = match *strategy {
+            Strategy::Radius { min_overlap, distance } => (min_overlap, distance / METERS_PER_DEGREE),
+            Strategy::Duration { distance, .. } => (0, distance / METERS_PER_DEGREE),
+            Strategy::Grid { cell, min_overlap } => {
+                let row = (d.lat / cell).floor();
+                let column = (d.lng / cell).floor();
+                let in_cells = (self.min_lat / cell).floor() <= row && row <= (self.max_lat / cell).floor()
+                    && (self.min_lng / cell).floor() <= column && column <= (self.max_lng / cell).floor();
+                return in_cells && self.overlaps(d, min_overlap);
+            },
+        };
+        d.lat > self.min_lat - margin && d.lat < self.max_lat + margin && self.overlaps(d, min_overlap)
+    }
+
+    fn overlaps(&self, d: &GeolocationTime, min_overlap: i32) -> bool {
+        d.startTS.saturating_add(min_overlap) < self.end && self.start.saturating_add(min_overlap) < d.endTS
+    }
+}
+
+impl Summary {
+    // The infected locations of `locations`, None without any. `changed` is left to the index.
+    fn of(locations: &[GeolocationTime]) -> Option<Summary> {
+        let infected: Vec<&GeolocationTime> = locations.iter().filter(|l| l.testResult).collect();
+        let first_end = infected.iter().map(|l| l.endTS).min()?;
+        let mut days: BTreeMap<i32, DayBox> = BTreeMap::new();
+        for l in &infected {
+            days.entry(l.startTS / EPOCH_SECONDS).and_modify(|day| day.extend(l)).or_insert_with(|| DayBox::new(l));
+        }
+        let digest = serde_json::to_vec(&infected).ok().and_then(|encoded| rsgx_sha256_slice(&encoded).ok()).unwrap_or([0u8; 32]);
+        Some(Summary { days, first_end, digest, changed: 0 })
+    }
+
+    fn may_match(&self, user_locations: &[GeolocationTime], strategy: &Strategy) -> bool {
+        user_locations.iter().any(|d| self.days.values().any(|day| day.may_match(d, strategy)))
+    }
+}
+
+impl Summaries {
+    fn build(data: &HashMap<String, Vec<GeolocationTime>>, generation: u64, previous: Option<&Summaries>) -> Self {
+        let users = data.iter().filter_map(|(key, locations)| {
+            let mut summary = Summary::of(locations)?;
+            summary.changed = previous.and_then(|previous| previous.users.get(key))
+                .filter(|before| before.digest == summary.digest)
+                .map_or(generation, |before| before.changed);
+            Some((key.clone(), summary))
+        }).collect();
+        Summaries { generation, users }
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    // The generation the infected locations of the stored user `key` last changed at, None without any.
+    pub fn changed(&self, key: &str) -> Option<u64> {
+        self.users.get(key).map(|summary| summary.changed)
+    }
+
+    // Whether some infected locations of `key` end before `cutoff`, the retention skips them until they're dropped.
+    pub fn expiring(&self, key: &str, cutoff: Option<i64>) -> bool {
+        match (self.users.get(key), cutoff) {
+            (Some(summary), Some(cutoff)) => i64::from(summary.first_end) < cutoff,
+            _ => false,
+        }
+    }
+
+    // Whether the infected locations of the stored user `key` may match one of `user_locations`.
+    pub fn may_match(&self, key: &str, user_locations: &[GeolocationTime], strategy: &Strategy) -> bool {
+        self.users.get(key).map_or(false, |summary| summary.may_match(user_locations, strategy))
+    }
+}
+
+// Called right after `data` got sealed as `generation`.
+pub fn refresh(data: &HashMap<String, Vec<GeolocationTime>>, generation: u64) {
+    let mut index = INDEX.lock_expect("Summaries");
+    let summaries = Summaries::build(data, generation, index.as_ref().map(|previous| &**previous));
+    *index = Some(Arc::new(summaries));
+}
+
+// The summaries of `data`, the store unsealed at `generation`.
+pub fn index(data: &HashMap<String, Vec<GeolocationTime>>, generation: u64) -> Arc<Summaries> {
+    let mut index = INDEX.lock_expect("Summaries");
+    if let Some(summaries) = index.as_ref().filter(|summaries| summaries.generation == generation) {
+        return summaries.clone();
+    }
+    let summaries = Arc::new(Summaries::build(data, generation, index.as_ref().map(|previous| &**previous)));
+    // An older generation read meanwhile doesn't replace the newer one
+    if index.as_ref().map_or(true, |current| current.generation < generation) {
+        *index = Some(summaries.clone());
+    }
+    summaries
+}
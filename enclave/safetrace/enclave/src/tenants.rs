@@ -0,0 +1,122 @@
+use crate::params;
+use crate::time_t;
+use enigma_tools_m::utils::LockExpectMutex;
+use enigma_tools_t::common::errors_t::{EnclaveError, EnclaveSystemError::OcallError, FailedTaskError::InputError};
+use serde::Deserialize;
+use sgx_types::sgx_status_t;
+use std::collections::HashMap;
+use std::{str, string::{String, ToString}, sync::SgxMutex, vec::Vec};
+
+extern "C" {
+    fn ocall_current_tenant(retval: *mut usize, tenant: *mut u8, tenant_len: usize) -> sgx_status_t;
+}
+
+// Tenants: the regions or health authorities one deployment serves, each with a partition of its own.
+// The host tells the tenant of the request an ecall runs for through `ocall_current_tenant`, which runs on
+// the thread that made the ecall, like `cancel`. A request without a tenant is in the default partition,
+// the one of the deployments before tenants. Each partition has its own users (their store key is
+// `<tenant>\x1f<user id>`, the default one keeps the bare id), registrations and DH keys, and is only
+// matched against itself. A tenant may keep its records for another period than `params`.
+//
+// The host picks the tenant of a request, so it can't hide one from another it serves: it's the partition
+// of the data of each health authority, not a protection against the operator.
+
+pub const MAX_TENANT_LEN: usize = 32;
+// Between the tenant and the user id in the store keys, user ids can't hold it
+const SEPARATOR: char = '\u{1f}';
+
+#[derive(Deserialize, Debug)]
+struct TenantPolicy {
+    id: String,
+    // Seconds, keeps `params`' retention when missing
+    #[serde(default)]
+    retention: Option<u64>,
+}
+
+lazy_static! {
+    // The configured tenants and their retention, 0 keeps the records forever
+    static ref TENANTS: SgxMutex<HashMap<String, Option<u64>>> = SgxMutex::new(HashMap::new());
+}
+
+fn invalid(message: &str) -> EnclaveError {
+    EnclaveError::FailedTaskError(InputError { message: message.to_string() })
+}
+
+// The partition of a request, `None` for the default one.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Tenant(pub Option<String>);
+
+// Replaces the tenants with the JSON list `[{"id": "ch-ge", "retention": 1209600}]`.
+pub fn set(tenants: &[u8]) -> Result<u64, EnclaveError> {
+    let tenants: Vec<TenantPolicy> = serde_json::from_slice(tenants).map_err(|_| invalid("malformed tenant list"))?;
+    let mut policies = HashMap::new();
+    for tenant in tenants {
+        if tenant.id.is_empty() || tenant.id.len() > MAX_TENANT_LEN || tenant.id.contains(SEPARATOR) {
+            return Err(invalid("invalid tenant id"));
+        }
+        if policies.insert(tenant.id, tenant.retention).is_some() {
+            return Err(invalid("a tenant is listed twice"));
+        }
+    }
+    let count = policies.len() as u64;
+    *TENANTS.lock_expect("Tenants") = policies;
+    Ok(count)
+}
+
+// The tenant of the request of this thread, refused if it isn't one the host configured.
+pub fn current() -> Result<Tenant, EnclaveError> {
+    let mut tenant = [0u8; MAX_TENANT_LEN];
+    let mut len = 0usize;
+    let status = unsafe { ocall_current_tenant(&mut len as *mut usize, tenant.as_mut_ptr(), tenant.len()) };
+    if status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveError::SystemError(OcallError { command: "ocall_current_tenant".to_string(), err: status.as_str().to_string() }));
+    }
+    if len == 0 {
+        return Ok(Tenant(None));
+    }
+    let id = tenant.get(..len).and_then(|id| str::from_utf8(id).ok()).ok_or_else(|| invalid("invalid tenant id"))?;
+    if !TENANTS.lock_expect("Tenants").contains_key(id) {
+        return Err(invalid("unknown tenant"));
+    }
+    Ok(Tenant(Some(id.to_string())))
+}
+
+fn tenant_of(key: &str) -> Option<&str> {
+    key.find(SEPARATOR).map(|at| &key[..at])
+}
+
+// Oldest `endTS` the records of the user stored under `key` keep.
+pub fn retention_cutoff(key: &str) -> Result<Option<i64>, EnclaveError> {
+    let retention = tenant_of(key).and_then(|tenant| TENANTS.lock_expect("Tenants").get(tenant).cloned().unwrap_or(None));
+    match retention {
+        None => params::retention_cutoff(),
+        Some(0) => Ok(None),
+        Some(retention) => Ok(Some(time_t::now()?.saturating_sub(retention) as i64)),
+    }
+}
+
+impl Tenant {
+    // The store key of `userid` in this partition.
+    pub fn scope(&self, userid: &str) -> Result<String, EnclaveError> {
+        if userid.contains(SEPARATOR) {
+            return Err(invalid("user ids can't hold U+001F"));
+        }
+        Ok(match &self.0 {
+            Some(tenant) => format!("{}{}{}", tenant, SEPARATOR, userid),
+            None => userid.to_string(),
+        })
+    }
+
+    // Whether the user stored under `key` is in this partition.
+    pub fn owns(&self, key: &str) -> bool {
+        tenant_of(key) == self.0.as_ref().map(String::as_str)
+    }
+
+    // Where the DH key of `user_pubkey` is kept, a key handed out for one tenant can't serve another.
+    pub fn key_id(&self, user_pubkey: &[u8]) -> Vec<u8> {
+        let mut id = self.0.as_ref().map(|tenant| tenant.as_bytes().to_vec()).unwrap_or_default();
+        id.push(SEPARATOR as u8);
+        id.extend_from_slice(user_pubkey);
+        id
+    }
+}
@@ -0,0 +1,31 @@
+use enigma_tools_t::common::errors_t::{EnclaveError, EnclaveSystemError::OcallError};
+use core::sync::atomic::{AtomicU64, Ordering};
+use sgx_types::sgx_status_t;
+use std::string::ToString;
+
+extern "C" {
+    fn ocall_get_time(retval: *mut u64) -> sgx_status_t;
+}
+
+// Latest time handed out inside the enclave, so it never goes backwards even if the host lies.
+static LAST_TIME: AtomicU64 = AtomicU64::new(0);
+
+// Seconds since the UNIX epoch, as supplied by the host through `ocall_get_time`.
+// The host already enforces monotonicity and reports large jumps, we enforce it again here.
+// Enclave code that needs the time (retention, deadlines, receipts) should go through this
+// instead of reading a clock on its own.
+pub fn now() -> Result<u64, EnclaveError> {
+    let mut time = 0u64;
+    let status = unsafe { ocall_get_time(&mut time as *mut u64) };
+    if status != sgx_status_t::SGX_SUCCESS {
+        return Err(EnclaveError::SystemError(OcallError { command: "ocall_get_time".to_string(), err: status.as_str().to_string() }));
+    }
+    let mut last = LAST_TIME.load(Ordering::SeqCst);
+    while time > last {
+        match LAST_TIME.compare_exchange(last, time, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => return Ok(time),
+            Err(current) => last = current,
+        }
+    }
+    Ok(last)
+}
@@ -0,0 +1,98 @@
+use crate::{audit, identity, local, quorum, SIGNING_KEY};
+use enigma_crypto::asymmetric::KeyPair;
+use enigma_tools_m::utils::EthereumAddress;
+use enigma_tools_t::common::errors_t::{EnclaveError, FailedTaskError::InputError};
+use enigma_tools_t::storage_t::{self, SecretKeyStorage};
+use sgx_types::*;
+use std::string::ToString;
+
+// Hands the state the next version of the enclave can't unseal over to it, see `upgrade` in the app.
+// The records are sealed with the MRSIGNER policy, a newer enclave of the same signer reads them as they
+// are. The signing key is sealed with the MRENCLAVE policy, and the audit head isn't sealed at all: the
+// running enclave (the responder) and the new one (the initiator) open a `local` attestation session, and
+// each checks the other is a build of the same product, the running one that the new one isn't older.
+// The running enclave then encrypts the key and the head with the session key, the new one seals them
+// as its own. Only a secp256k1 key is handed over, the upgrade of a P-256 deployment is refused.
+
+// The key, whether there's an audit head, its seq and hash
+const STATE_SIZE: usize = 32 + 1 + 8 + 32;
+pub const TRANSFER_SIZE: usize = local::OVERHEAD + STATE_SIZE;
+const KEYPAIR_FILE: &str = "keypair.sealed";
+const AAD: &[u8] = b"safetrace upgrade";
+
+fn invalid(message: &str) -> EnclaveError {
+    EnclaveError::FailedTaskError(InputError { message: message.to_string() })
+}
+
+// `newer` when checking the enclave taking over, which must be at least at our SVN.
+fn check_peer(peer: &sgx_dh_session_enclave_identity_t, newer: bool) -> Result<(), EnclaveError> {
+    let own = sgx_tse::rsgx_self_report().body;
+    if peer.isv_prod_id != own.isv_prod_id {
+        return Err(invalid("the other enclave is another product"));
+    }
+    if newer && peer.isv_svn < own.isv_svn {
+        return Err(invalid("the new enclave is older"));
+    }
+    if !newer && peer.isv_svn > own.isv_svn {
+        return Err(invalid("the running enclave is newer"));
+    }
+    Ok(())
+}
+
+// The running enclave: its signing key and audit head, for the new one only, and once operators are
+// registered only when a quorum of them approved the upgrade.
+pub fn export_internal(session: u64, transfer: &mut [u8; TRANSFER_SIZE]) -> Result<(), EnclaveError> {
+    if identity::curve() != identity::SECP256K1 {
+        return Err(invalid("only a secp256k1 signing key can be handed over"));
+    }
+    let session = local::take(session)?;
+    quorum::take("Upgrade", None, None)?;
+    if session.initiator {
+        return Err(invalid("the running enclave must be the responder"));
+    }
+    check_peer(&session.peer, true)?;
+    let mut state = [0u8; STATE_SIZE];
+    state[..32].copy_from_slice(&SIGNING_KEY.get_privkey());
+    if let Some((seq, head)) = audit::head() {
+        state[32] = 1;
+        state[33..41].copy_from_slice(&seq.to_be_bytes());
+        state[41..].copy_from_slice(&head);
+    }
+    transfer.copy_from_slice(&local::encrypt(&session.key, &state, AAD)?);
+    Ok(())
+}
+
+// The new enclave: seals the signing key it was handed, before it ever reads one.
+pub fn import_internal(session: u64, transfer: &[u8; TRANSFER_SIZE], address: &mut [u8; 20]) -> Result<(), EnclaveError> {
+    if identity::curve() != identity::SECP256K1 {
+        return Err(invalid("only a secp256k1 signing key can be handed over"));
+    }
+    let session = local::take(session)?;
+    if !session.initiator {
+        return Err(invalid("the new enclave must be the initiator"));
+    }
+    check_peer(&session.peer, false)?;
+    if crate::signing_key_loaded() {
+        return Err(invalid("this enclave already has a signing key"));
+    }
+    let state = local::decrypt(&session.key, transfer, AAD)?;
+
+    let mut privkey = [0u8; 32];
+    privkey.copy_from_slice(&state[..32]);
+    let keypair = KeyPair::from_slice(&privkey)?;
+    let mut sealed = [0u8; storage_t::SEAL_LOG_SIZE];
+    SecretKeyStorage { version: 0x1, data: privkey }.seal_key(&mut sealed);
+    storage_t::save_sealed_key(KEYPAIR_FILE, &sealed);
+    if SIGNING_KEY.get_pubkey().address() != keypair.get_pubkey().address() {
+        return Err(invalid("sealing the signing key failed"));
+    }
+    if state[32] == 1 {
+        let mut seq = [0u8; 8];
+        seq.copy_from_slice(&state[33..41]);
+        let mut head = [0u8; 32];
+        head.copy_from_slice(&state[41..]);
+        audit::resume(u64::from_be_bytes(seq), head);
+    }
+    *address = keypair.get_pubkey().address();
+    Ok(())
+}
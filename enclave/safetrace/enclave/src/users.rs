@@ -0,0 +1,166 @@
+use crate::data::{decrypt_data, decrypt_userid, read_sealed_file, recover_sealeddata, save_sealed_data, seal_to_vec, Error};
+use crate::decoy;
+use crate::freshness::USERS;
+use crate::records::Freshness;
+use crate::settings;
+use crate::tenants;
+use core::sync::atomic::{AtomicBool, Ordering};
+use enigma_crypto::{asymmetric::KeyPair, symmetric::decrypt};
+use enigma_tools_m::utils::LockExpectMutex;
+use enigma_tools_t::common::errors_t::{EnclaveError, FailedTaskError::InputError};
+use enigma_types::{DhKey, PubKey};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, str, string::{String, ToString}, sync::SgxMutex, vec::Vec};
+
+// User authentication.
+// A user registers a secp256k1 signing key for their user id with `RegisterUser`, the first key registered
+// for an id is the one that counts. From then on `addPersonalData` and `findMatch` for that id must carry
+// `encryptedSignature`: the signature of `encryptedUserId || encryptedData || userPubKey` (the raw bytes,
+// `encryptedData` only for `addPersonalData`), encrypted with the DH key like the rest of the request.
+// It's encrypted so the host can't recover the signer and link the requests of a user together.
+// Registrations are kept by store key, each tenant has its own (see `tenants`).
+// The host could drop the registrations, or hand back older ones, and sign requests for the users they don't
+// hold: they're rollback protected like the store (`freshness`), and a file that was there and went missing
+// is refused.
+
+pub const USERSFILE: &str = "users.sealed";
+
+// Whether the ids nobody registered are refused too.
+static REQUIRED: AtomicBool = AtomicBool::new(false);
+static SET: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    // Registrations are read, checked and sealed back in one go
+    static ref USERS_LOCK: SgxMutex<()> = SgxMutex::new(());
+}
+
+// Once, before the first registration quote: the quotes commit to it (see `settings`).
+pub fn set(required: bool) -> Result<(), EnclaveError> {
+    if settings::quoted() {
+        return Err(EnclaveError::FailedTaskError(InputError { message: "a quote committed to the authentication policy already".to_string() }));
+    }
+    if SET.swap(true, Ordering::SeqCst) {
+        return Err(EnclaveError::FailedTaskError(InputError { message: "the authentication policy is already set".to_string() }));
+    }
+    REQUIRED.store(required, Ordering::SeqCst);
+    Ok(())
+}
+
+pub fn required() -> bool {
+    REQUIRED.load(Ordering::SeqCst)
+}
+
+// As sealed. The registrations of before `generation` was recorded are the bare map, at generation 0
+#[derive(Serialize, Deserialize)]
+struct Registry {
+    generation: u64,
+    counter: Option<([u8; 16], u32)>,
+    users: HashMap<String, Vec<u8>>,
+}
+
+fn unauthorized(message: &str) -> EnclaveError {
+    EnclaveError::FailedTaskError(InputError { message: format!("unauthorized: {}", message) })
+}
+
+fn checked(freshness: &Freshness) -> Result<(), EnclaveError> {
+    USERS.check(freshness).map_err(|e| match e {
+        Error::RolledBack { generation, floor } => EnclaveError::FailedTaskError(InputError { message: format!(
+            "the user registrations are at generation {}, the enclave has seen generation {}: they were rolled back", generation, floor) }),
+        e => e.into(),
+    })
+}
+
+// user id -> the 64 bytes signing public key. The file is as large as the registrations are; one that doesn't
+// unseal is an error, not an empty registry.
+fn load_users() -> Result<HashMap<String, Vec<u8>>, EnclaveError> {
+    let mut sealed_log = match read_sealed_file(USERSFILE)? {
+        Some(sealed_log) => sealed_log,
+        None => {
+            checked(&Freshness::default())?;
+            return Ok(HashMap::new());
+        },
+    };
+    let encoded = recover_sealeddata(sealed_log.as_mut_ptr(), sealed_log.len() as u32)?;
+    let registry = match serde_json::from_slice::<Registry>(&encoded) {
+        Ok(registry) => registry,
+        Err(_) => Registry { generation: 0, counter: None, users: serde_json::from_slice(&encoded).map_err(|_| Error::SerializeError)? },
+    };
+    checked(&Freshness { generation: registry.generation, counter: registry.counter })?;
+    Ok(registry.users)
+}
+
+fn save_users(users: HashMap<String, Vec<u8>>) -> Result<(), EnclaveError> {
+    let freshness = USERS.next()?;
+    let registry = Registry { generation: freshness.generation, counter: freshness.counter, users };
+    let encoded = serde_json::to_vec(&registry).map_err(|_| Error::SerializeError)?;
+    save_sealed_data(USERSFILE, &seal_to_vec(&encoded)?);
+    Ok(USERS.sealed(&freshness)?)
+}
+
+fn to_userid(decrypted_userid: &[u8]) -> Result<&str, EnclaveError> {
+    str::from_utf8(decrypted_userid)
+        .map_err(|_| EnclaveError::FailedTaskError(InputError { message: "encryptedUserId is not valid UTF-8".to_string() }))
+}
+
+// `encryptedData` is the signing public key (64 bytes) followed by the signature of `userPubKey` with it
+// (65 bytes), which proves the user holds the key. Registering the same key again is a no-op.
+pub fn register_user_internal(
+    encrypted_userid: &[u8],
+    encrypted_data: &[u8],
+    user_pubkey: &PubKey,
+    io_key: &DhKey) -> Result<(), EnclaveError> {
+
+    let decrypted_userid = decrypt_userid(encrypted_userid, io_key)?;
+    let userid = to_userid(&decrypted_userid)?;
+    if decoy::is_decoy(userid, io_key) {
+        return Err(EnclaveError::FailedTaskError(InputError { message: "decoy user ids can't be registered".to_string() }));
+    }
+    let registration = decrypt_data(encrypted_data, io_key)?;
+    if registration.len() != 64 + 65 {
+        return Err(EnclaveError::FailedTaskError(InputError { message: "encryptedData must hold a 64 bytes key and a 65 bytes signature".to_string() }));
+    }
+    let (signing_key, sig) = registration.split_at(64);
+    let mut signature = [0u8; 65];
+    signature.copy_from_slice(sig);
+    if KeyPair::recover(&user_pubkey[..], signature)?[..] != *signing_key {
+        return Err(unauthorized("the signature doesn't match the signing key"));
+    }
+
+    let key = tenants::current()?.scope(userid)?;
+    let _lock = USERS_LOCK.lock_expect("Registered Users");
+    let mut users = load_users()?;
+    match users.get(&key) {
+        Some(registered) if registered[..] == *signing_key => Ok(()),
+        Some(_) => Err(unauthorized("the user id is registered with another key")),
+        None => {
+            users.insert(key, signing_key.to_vec());
+            save_users(users)
+        },
+    }
+}
+
+// Checks the request of the user stored under `key` (`Tenant::scope`) is signed by their registered key.
+// `signed` are the parts of the request the signature covers, in order.
+pub fn authenticate(key: &str, encrypted_signature: &[u8], signed: &[&[u8]], io_key: &DhKey) -> Result<(), EnclaveError> {
+    let users = load_users()?;
+    let registered = match users.get(key) {
+        Some(registered) => registered,
+        None if REQUIRED.load(Ordering::SeqCst) => return Err(unauthorized("the user id isn't registered")),
+        // Nothing to check a signature against
+        None => return Ok(()),
+    };
+    if encrypted_signature.is_empty() {
+        return Err(unauthorized("the request isn't signed"));
+    }
+    let sig = decrypt(encrypted_signature, io_key)?;
+    if sig.len() != 65 {
+        return Err(unauthorized("the signature must be 65 bytes long"));
+    }
+    let mut signature = [0u8; 65];
+    signature.copy_from_slice(&sig);
+    let signer = KeyPair::recover(&signed.concat(), signature)?;
+    if signer[..] != registered[..] {
+        return Err(unauthorized("the request isn't signed by the registered key"));
+    }
+    Ok(())
+}
@@ -0,0 +1,130 @@
+use crate::data::{read_sealed_file, recover_sealeddata_for_serializable, save_sealed_data, seal_to_vec, Error, GeolocationTime};
+use enigma_tools_m::utils::LockExpectMutex;
+use enigma_tools_t::common::errors_t::{EnclaveError, FailedTaskError::InputError};
+use serde::{Deserialize, Serialize};
+use std::{string::{String, ToString}, sync::SgxMutex, vec::Vec};
+
+// Exclusion zones.
+// Places the deployment doesn't want locations of kept at all, like hospitals or shelters: a location
+// inside a zone is dropped, or with `Mask` moved to the centre of the zone so the user still matches
+// whoever else was there without telling where in it they were. Zones are polygons of [lat, lng] vertices,
+// uploaded by an operator through the admin socket and sealed, so they're applied from the start after
+// a restart:
+//   [{"name": "St Mary", "polygon": [[51.517, -0.174], [51.518, -0.171], [51.516, -0.170]], "action": "Drop"}]
+// They're applied at ingest only: what's already in the store stays as it was submitted.
+
+pub const ZONESFILE: &str = "zones.sealed";
+pub const MAX_ZONES: usize = 64;
+pub const MAX_VERTICES: usize = 64;
+const MAX_NAME_LEN: usize = 64;
+
+lazy_static! {
+    // None until read from the sealed file
+    static ref ZONES: SgxMutex<Option<Vec<Zone>>> = SgxMutex::new(None);
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum ZoneAction {
+    Drop,
+    Mask,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Zone {
+    pub name: String,
+    pub polygon: Vec<[f64; 2]>,
+    pub action: ZoneAction,
+}
+
+fn invalid(message: String) -> EnclaveError {
+    EnclaveError::FailedTaskError(InputError { message })
+}
+
+fn load_zones() -> Result<Vec<Zone>, Error> {
+    match read_sealed_file(ZONESFILE)? {
+        Some(mut sealed_log) => recover_sealeddata_for_serializable(sealed_log.as_mut_ptr(), sealed_log.len() as u32),
+        None => Ok(Vec::new()),
+    }
+}
+
+fn save_zones(encoded: &[u8]) -> Result<(), Error> {
+    save_sealed_data(ZONESFILE, &seal_to_vec(encoded)?);
+    Ok(())
+}
+
+fn validate(zones: &[Zone]) -> Result<(), EnclaveError> {
+    if zones.len() > MAX_ZONES {
+        return Err(invalid(format!("at most {} exclusion zones", MAX_ZONES)));
+    }
+    for (i, zone) in zones.iter().enumerate() {
+        let problem = if zone.name.is_empty() || zone.name.len() > MAX_NAME_LEN {
+            "the name must be between 1 and 64 characters"
+        } else if zone.polygon.len() < 3 || zone.polygon.len() > MAX_VERTICES {
+            "the polygon must have between 3 and 64 vertices"
+        } else if zone.polygon.iter().any(|v| !(v[0] >= -90.0 && v[0] <= 90.0) || !(v[1] >= -180.0 && v[1] <= 180.0)) {
+            "the vertices must be [lat, lng] within [-90, 90] and [-180, 180]"
+        } else {
+            continue;
+        };
+        return Err(invalid(format!("zone {}: {}", i, problem)));
+    }
+    Ok(())
+}
+
+// Replaces the zones with the JSON list `encoded`, an empty list removes them. Returns how many there are.
+pub fn set_zones_internal(encoded: &[u8]) -> Result<u64, EnclaveError> {
+    let zones: Vec<Zone> = serde_json::from_slice(encoded)
+        .map_err(|e| invalid(format!("the exclusion zones aren't a list of zones: {}", e)))?;
+    validate(&zones)?;
+    let mut current = ZONES.lock_expect("Exclusion Zones");
+    save_zones(encoded).map_err(|_| invalid("sealing the exclusion zones failed".to_string()))?;
+    let count = zones.len() as u64;
+    *current = Some(zones);
+    Ok(count)
+}
+
+// Ray casting, with lng as x and lat as y. Zones are small enough for a flat map, and none crosses
+// the antimeridian.
+fn contains(polygon: &[[f64; 2]], lat: f64, lng: f64) -> bool {
+    let mut inside = false;
+    let mut j = polygon.len() - 1;
+    for i in 0..polygon.len() {
+        let (a, b) = (polygon[i], polygon[j]);
+        if (a[0] > lat) != (b[0] > lat) && lng < (b[1] - a[1]) * (lat - a[0]) / (b[0] - a[0]) + a[1] {
+            inside = !inside;
+        }
+        j = i;
+    }
+    inside
+}
+
+// The mean of the vertices, which is all a mask needs: one point for everybody in the zone.
+fn centre(polygon: &[[f64; 2]]) -> (f64, f64) {
+    let n = polygon.len() as f64;
+    let (lat, lng) = polygon.iter().fold((0.0, 0.0), |(lat, lng), v| (lat + v[0], lng + v[1]));
+    (lat / n, lng / n)
+}
+
+// Drops or masks the locations inside a zone, the first zone a location is in decides.
+pub fn apply(locations: Vec<GeolocationTime>) -> Result<Vec<GeolocationTime>, EnclaveError> {
+    let mut current = ZONES.lock_expect("Exclusion Zones");
+    if current.is_none() {
+        *current = Some(load_zones()?);
+    }
+    let zones = current.as_ref().expect("zones loaded");
+    if zones.is_empty() {
+        return Ok(locations);
+    }
+    Ok(locations.into_iter().filter_map(|mut location| {
+        match zones.iter().find(|zone| contains(&zone.polygon, location.lat, location.lng)) {
+            None => Some(location),
+            Some(zone) if zone.action == ZoneAction::Drop => None,
+            Some(zone) => {
+                let (lat, lng) = centre(&zone.polygon);
+                location.lat = lat;
+                location.lng = lng;
+                Some(location)
+            },
+        }
+    }).collect())
+}